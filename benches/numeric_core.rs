@@ -0,0 +1,113 @@
+//! Benchmarks of the numeric hot paths, so that performance regressions show up when refactoring. Run with
+//! `cargo bench --features bench`.
+
+use std::hint::black_box;
+
+use chrono::DateTime;
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use theta_surface::analytics::{
+    ButterflyCheckSettings, OptionInstrument, OptionType, SVICurveParameters, SmileGraph, calculate_black_scholes,
+    calculate_bs_implied_volatility, has_butterfly_arbitrage, svi_variance,
+};
+use theta_surface::constants::INTEREST_FREE_RATE;
+use theta_surface::helpers::{Clock, ProgressBar};
+
+const YEARS_UNTIL_EXPIRY: f64 = 60.0 * 24.0 * 60.0 * 60.0 / 31556926.0;
+
+fn get_curve() -> SVICurveParameters {
+    SVICurveParameters::new_from_values(0.03, 0.08, -0.4, 0.05, 0.15).expect("Benchmark curve must be valid")
+}
+
+/// A smile of calls priced from a known curve, as the fitting would see it.
+fn build_smile_graph() -> SmileGraph {
+    let clock = Clock::Fixed(DateTime::from_timestamp_secs(1_767_225_600).expect("Benchmark time must be valid"));
+    let expiry_seconds = 1_767_225_600 + 60 * 24 * 60 * 60;
+    let forward_price = 100.0 * (INTEREST_FREE_RATE * YEARS_UNTIL_EXPIRY).exp();
+    let curve = get_curve();
+    let mut graph = SmileGraph::new();
+
+    for strike in (12..=30).map(|x| x as f64 * 5.0) {
+        let variance = svi_variance(&curve, (strike / forward_price).ln()).expect("Benchmark variance must be valid");
+        let price = calculate_black_scholes(
+            100.0,
+            strike,
+            YEARS_UNTIL_EXPIRY,
+            INTEREST_FREE_RATE,
+            0.0,
+            (variance / YEARS_UNTIL_EXPIRY).sqrt(),
+            OptionType::Call,
+        )
+        .expect("Benchmark price must be valid");
+
+        graph
+            .try_insert_option(OptionInstrument::new(
+                price,
+                expiry_seconds,
+                strike,
+                strike.to_string().into(),
+                OptionType::Call,
+                100.0,
+                clock,
+            ))
+            .expect("Benchmark option must be valid");
+    }
+
+    graph
+}
+
+fn bench_implied_volatility(c: &mut Criterion) {
+    let price = calculate_black_scholes(100.0, 110.0, YEARS_UNTIL_EXPIRY, INTEREST_FREE_RATE, 0.0, 0.6, OptionType::Call)
+        .expect("Benchmark price must be valid");
+
+    c.bench_function("calculate_bs_implied_volatility", |b| {
+        b.iter(|| {
+            calculate_bs_implied_volatility(
+                black_box(100.0),
+                black_box(110.0),
+                YEARS_UNTIL_EXPIRY,
+                INTEREST_FREE_RATE,
+                0.0,
+                black_box(price),
+                OptionType::Call,
+            )
+        })
+    });
+}
+
+fn bench_svi_variance(c: &mut Criterion) {
+    let curve = get_curve();
+
+    c.bench_function("svi_variance", |b| b.iter(|| svi_variance(&curve, black_box(0.1))));
+}
+
+fn bench_butterfly_arbitrage(c: &mut Criterion) {
+    let curve = get_curve();
+    let settings = ButterflyCheckSettings::default();
+
+    c.bench_function("has_butterfly_arbitrage", |b| {
+        b.iter(|| has_butterfly_arbitrage(&curve, black_box(60.0), black_box(150.0), black_box(100.0), &settings))
+    });
+}
+
+fn bench_fit_smile(c: &mut Criterion) {
+    let progress = ProgressBar::new("", 1, true);
+    let mut group = c.benchmark_group("fitting");
+
+    // Each fit takes long enough that the default number of samples would make the benchmark very slow.
+    group.sample_size(10);
+    group.bench_function("fit_smile", |b| {
+        b.iter_batched(
+            build_smile_graph,
+            |mut graph| {
+                graph
+                    .fit_smile(&progress)
+                    .expect("Benchmark smile must fit")
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_implied_volatility, bench_svi_variance, bench_butterfly_arbitrage, bench_fit_smile);
+criterion_main!(benches);
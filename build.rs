@@ -0,0 +1,104 @@
+//! Makes the versions of the numerical libraries that smiles are fit with available at compile time, so that they can be
+//! saved with each surface. They are read from Cargo.lock, which is committed so that every build of this crate records
+//! the versions it was really built with. When this crate is built as a dependency of another, the lock file is the other
+//! crate's, which is found above the build's output directory. The build fails rather than recording a version it can't
+//! find.
+//!
+//! With the ffi feature it also generates the C header of src/ffi.rs as theta_surface.h in OUT_DIR, which the ffi tests
+//! check include/theta_surface.h against. With the grpc feature it generates the gRPC service and messages of
+//! proto/theta_surface.proto in OUT_DIR, with a vendored protoc so that none needs to be installed. Building never writes
+//! to the source tree.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// Each library recorded, and the environment variable its version is put in.
+const RECORDED_DEPENDENCIES: [(&str, &str); 2] = [
+    ("levenberg-marquardt", "LEVENBERG_MARQUARDT_VERSION"),
+    ("nalgebra", "NALGEBRA_VERSION"),
+];
+
+fn main() {
+    let manifest_directory = env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let out_directory = env::var("OUT_DIR").unwrap_or_default();
+    let lock_path = find_lock_file(&manifest_directory, &out_directory).unwrap_or_else(|| {
+        panic!("Cargo.lock wasn't found in {manifest_directory} or above {out_directory}, so the versions of the fitting libraries can't be recorded")
+    });
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={}", lock_path.display());
+
+    let lock = fs::read_to_string(&lock_path).unwrap_or_else(|e| panic!("Failed reading {}: {e}", lock_path.display()));
+
+    for (name, variable) in RECORDED_DEPENDENCIES {
+        let version = find_version(&lock, name)
+            .unwrap_or_else(|| panic!("{} doesn't list {name}, so its version can't be recorded", lock_path.display()));
+        println!("cargo:rustc-env={variable}={version}");
+    }
+
+    #[cfg(feature = "ffi")]
+    generate_ffi_header(&manifest_directory, &out_directory);
+
+    #[cfg(feature = "grpc")]
+    generate_grpc_service(&manifest_directory);
+}
+
+/// The lock file this build uses: this crate's own, or the nearest above the output directory (i.e. that of the workspace
+/// whose target directory it is) when this crate is built as a dependency.
+fn find_lock_file(manifest_directory: &str, out_directory: &str) -> Option<PathBuf> {
+    Path::new(manifest_directory)
+        .ancestors()
+        .chain(Path::new(out_directory).ancestors())
+        .map(|x| x.join("Cargo.lock"))
+        .find(|x| x.is_file())
+}
+
+/// Only the ffi module is read, since the crate's other public constants aren't part of the C interface.
+#[cfg(feature = "ffi")]
+fn generate_ffi_header(manifest_directory: &str, out_directory: &str) {
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+
+    cbindgen::Builder::new()
+        .with_src(Path::new(manifest_directory).join("src/ffi.rs"))
+        .with_language(cbindgen::Language::C)
+        .with_include_guard("THETA_SURFACE_H")
+        .with_autogen_warning("/* Generated from src/ffi.rs by cbindgen when building with --features ffi. Don't edit. */")
+        .with_cpp_compat(true)
+        .generate()
+        .expect("Failed generating the C header")
+        .write_to_file(Path::new(out_directory).join("theta_surface.h"));
+}
+
+#[cfg(feature = "grpc")]
+fn generate_grpc_service(manifest_directory: &str) {
+    println!("cargo:rerun-if-changed=proto/theta_surface.proto");
+
+    let proto_directory = Path::new(manifest_directory).join("proto");
+    let protoc = protoc_bin_vendored::protoc_bin_path().unwrap_or_else(|e| panic!("No vendored protoc for this platform: {e}"));
+
+    // Safe because build scripts don't start any other threads that could be reading the environment.
+    unsafe { env::set_var("PROTOC", protoc) };
+
+    tonic_prost_build::configure()
+        .compile_protos(&[proto_directory.join("theta_surface.proto")], &[proto_directory])
+        .unwrap_or_else(|e| panic!("Failed generating the gRPC service: {e}"));
+}
+
+/// Cargo.lock lists each package as a name line followed by a version line.
+fn find_version<'a>(lock: &'a str, name: &str) -> Option<&'a str> {
+    let name_line = format!("name = \"{name}\"");
+    let mut lines = lock.lines();
+
+    while let Some(line) = lines.next() {
+        if line == name_line {
+            return lines
+                .next()?
+                .strip_prefix("version = \"")?
+                .strip_suffix('"');
+        }
+    }
+
+    None
+}
@@ -0,0 +1,143 @@
+#ifndef THETA_SURFACE_H
+#define THETA_SURFACE_H
+
+/* Generated from src/ffi.rs by cbindgen when building with --features ffi. Don't edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Returned by every function that succeeded.
+ */
+#define TS_OK 0
+
+/**
+ * Returned if a function panicked, the same code the program exits with when it panics.
+ */
+#define TS_PANICKED 101
+
+/**
+ * A call option, the same value as OptionType::Call.
+ */
+#define TS_CALL 1
+
+/**
+ * A put option, the same value as OptionType::Put.
+ */
+#define TS_PUT 2
+
+/**
+ * A fitted surface loaded by ts_surface_from_json(), which must be freed with ts_surface_free().
+ */
+typedef struct TsSurface TsSurface;
+
+/**
+ * What an option's price depends on besides its volatility.
+ */
+typedef struct TsOptionInputs {
+  double spot_price;
+  double strike;
+  /**
+   * Years until the option expires (365 day year).
+   */
+  double years_until_expiry;
+  /**
+   * Continuously compounded and annualised, e.g. 0.05 for 5% per annum.
+   */
+  double risk_free_interest_rate;
+  /**
+   * Continuously compounded and annualised. Use 0 for none.
+   */
+  double dividend_yield;
+  /**
+   * TS_CALL or TS_PUT.
+   */
+  int32_t option_type;
+} TsOptionInputs;
+
+/**
+ * A point read off a surface (see analytics::interpolate_surface()).
+ */
+typedef struct TsSurfacePoint {
+  double forward_price;
+  double log_moneyness;
+  double total_variance;
+  double implied_volatility;
+  /**
+   * True if the smiles either side of the point have calendar arbitrage at its log moneyness.
+   */
+  bool has_calendar_arbitrage;
+} TsSurfacePoint;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * The Black-Scholes price of a European option.
+ *
+ * # Safety
+ *
+ * out_price must be valid to write an f64 to.
+ */
+int32_t ts_black_scholes_price(struct TsOptionInputs inputs, double volatility, double *out_price);
+
+/**
+ * The Black-Scholes implied volatility of a European option with the given price.
+ *
+ * # Safety
+ *
+ * out_volatility must be valid to write an f64 to.
+ */
+int32_t ts_implied_volatility(struct TsOptionInputs inputs,
+                              double option_price,
+                              double *out_volatility);
+
+/**
+ * Load a surface from the contents of a saved smile-graph-data.json. Times until expiry are measured from
+ * evaluation_time_ms (milliseconds since the Unix epoch) if it isn't null, and otherwise from when the surface was built.
+ *
+ * # Safety
+ *
+ * json must be a null-terminated string, evaluation_time_ms must be null or valid to read an i64 from, and out_surface
+ * must be valid to write a pointer to.
+ */
+int32_t ts_surface_from_json(const char *json,
+                             const int64_t *evaluation_time_ms,
+                             struct TsSurface **out_surface);
+
+/**
+ * Read the surface at any strike and time to expiry, interpolating between its smiles.
+ *
+ * # Safety
+ *
+ * surface must have come from ts_surface_from_json() and not yet been freed, and out_point must be valid to write a
+ * TsSurfacePoint to.
+ */
+int32_t ts_surface_evaluate(const struct TsSurface *surface,
+                            double strike,
+                            double years_until_expiry,
+                            struct TsSurfacePoint *out_point);
+
+/**
+ * Free a surface. Does nothing if it is null.
+ *
+ * # Safety
+ *
+ * surface must be null or have come from ts_surface_from_json(), and mustn't be used again.
+ */
+void ts_surface_free(struct TsSurface *surface);
+
+/**
+ * Why the last function that failed on this thread failed. The string is owned by the library and only valid until
+ * another function fails on the same thread.
+ */
+const char *ts_last_error_message(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* THETA_SURFACE_H */
@@ -0,0 +1,166 @@
+use std::thread;
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    analytics::{ButterflyCheckSettings, SmileGraph, math::norm_cdf, svi_variance, types::SVICurveParameters},
+    types::{TsError, TsErrorType::RuntimeError},
+};
+
+/// The number of strikes (or log moneynesses) each smile is checked at. Far more than are checked while fitting, so that
+/// arbitrage between the points the fit checked is found.
+const VERIFICATION_POINTS: u64 = 5000;
+
+/// How far (as a fraction of the forward price) a butterfly spread can be below zero before it counts as arbitrage, to
+/// allow for floating point error.
+const BUTTERFLY_TOLERANCE: f64 = 1e-9;
+
+/// How far (as a fraction of the forward price) a call's price can fall between expiries before it counts as arbitrage.
+const CALENDAR_TOLERANCE: f64 = 1e-12;
+
+/// What the independent check found for one fitted expiry.
+pub struct ArbitrageVerification {
+    pub expiry: DateTime<Utc>,
+    /// The strike of the most negative butterfly spread, if any are negative.
+    pub butterfly_arbitrage_strike: Option<f64>,
+    /// The log moneyness where a call is cheapest compared to the same call at the previous expiry, if any is cheaper.
+    pub calendar_arbitrage_log_moneyness: Option<f64>,
+}
+
+/// The parts of a fitted smile that are saved, copied out so that each expiry can be checked on its own thread.
+struct PublishedSmile {
+    expiry: DateTime<Utc>,
+    forward_price: f64,
+    lowest_observed_strike: f64,
+    highest_observed_strike: f64,
+    butterfly_check: ButterflyCheckSettings,
+    svi_curve_parameters: SVICurveParameters,
+}
+
+impl PublishedSmile {
+    fn new(graph: &SmileGraph) -> Result<Self, TsError> {
+        let params = &graph.svi_curve_parameters;
+
+        Ok(Self {
+            expiry: graph.get_expiration()?,
+            forward_price: graph.get_underlying_forward_price()?,
+            lowest_observed_strike: graph.lowest_observed_strike,
+            highest_observed_strike: graph.highest_observed_strike,
+            butterfly_check: graph.butterfly_check,
+            svi_curve_parameters: SVICurveParameters::new_from_values(
+                params.get_a(),
+                params.get_b(),
+                params.get_p(),
+                params.get_m(),
+                params.get_o(),
+            )?,
+        })
+    }
+
+    /// The undiscounted price of a call at the given log moneyness, as a fraction of the forward price.
+    fn get_call_price(&self, log_moneyness: f64) -> Result<f64, TsError> {
+        let total_variance = svi_variance(&self.svi_curve_parameters, log_moneyness)?;
+
+        if total_variance <= 0.0 {
+            return Err(TsError::new(RuntimeError, "Total variance must be greater than 0"));
+        }
+
+        let d1 = (-log_moneyness + 0.5 * total_variance) / total_variance.sqrt();
+        let d2 = d1 - total_variance.sqrt();
+
+        Ok(norm_cdf(d1) - log_moneyness.exp() * norm_cdf(d2))
+    }
+}
+
+/// Check the fitted smiles for arbitrage again, in a different way to the checks made while fitting, as a safeguard against
+/// mistakes in either. Rather than testing the shape of the total variance curves, this prices calls on a dense grid and
+/// checks that they are convex in strike (no butterfly arbitrage), and that they don't get cheaper with time at
+/// the same log moneyness (no calendar arbitrage). Butterflies are checked over the same strikes as when fitting, and
+/// calendars over the log moneyness both expiries observed. Each expiry is checked on its own thread. The smile graphs must
+/// be ordered by expiry.
+pub fn verify_no_arbitrage(smile_graphs: &[&SmileGraph]) -> Result<Vec<ArbitrageVerification>, TsError> {
+    let smiles = smile_graphs
+        .iter()
+        .map(|x| PublishedSmile::new(x))
+        .collect::<Result<Vec<PublishedSmile>, TsError>>()?;
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = smiles
+            .iter()
+            .enumerate()
+            .map(|(i, smile)| {
+                let previous = i.checked_sub(1).map(|x| &smiles[x]);
+
+                scope.spawn(move || -> Result<ArbitrageVerification, TsError> {
+                    Ok(ArbitrageVerification {
+                        expiry: smile.expiry,
+                        butterfly_arbitrage_strike: find_butterfly_arbitrage(smile)?,
+                        calendar_arbitrage_log_moneyness: match previous {
+                            Some(previous) => find_calendar_arbitrage(previous, smile)?,
+                            None => None,
+                        },
+                    })
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|x| {
+                x.join()
+                    .map_err(|_| TsError::new(RuntimeError, "Arbitrage verification thread panicked"))?
+            })
+            .collect()
+    })
+}
+
+/// Returns the strike of the most negative butterfly spread across the observed strikes and the wings beyond them that are
+/// checked while fitting (see ButterflyCheckSettings), if there is one.
+fn find_butterfly_arbitrage(smile: &PublishedSmile) -> Result<Option<f64>, TsError> {
+    let from_strike = smile.lowest_observed_strike * (-smile.butterfly_check.wing_log_moneyness).exp();
+    let to_strike = smile.highest_observed_strike * smile.butterfly_check.wing_log_moneyness.exp();
+    let step_size = (to_strike - from_strike) / VERIFICATION_POINTS as f64;
+    let prices = (0..=VERIFICATION_POINTS)
+        .map(|i| smile.get_call_price(((from_strike + step_size * i as f64) / smile.forward_price).ln()))
+        .collect::<Result<Vec<f64>, TsError>>()?;
+
+    let mut worst: Option<(f64, f64)> = None;
+
+    for (i, window) in prices.windows(3).enumerate() {
+        let violation = -(window[0] - 2.0 * window[1] + window[2]);
+
+        if violation > BUTTERFLY_TOLERANCE && worst.is_none_or(|x| violation > x.1) {
+            worst = Some((from_strike + step_size * (i + 1) as f64, violation));
+        }
+    }
+
+    Ok(worst.map(|x| x.0))
+}
+
+/// Returns the log moneyness where a call at the later expiry is cheapest compared to one at the earlier expiry, if it is
+/// cheaper anywhere across the log moneyness both expiries observed.
+fn find_calendar_arbitrage(earlier: &PublishedSmile, later: &PublishedSmile) -> Result<Option<f64>, TsError> {
+    let from = (earlier.lowest_observed_strike / earlier.forward_price)
+        .ln()
+        .max((later.lowest_observed_strike / later.forward_price).ln());
+    let to = (earlier.highest_observed_strike / earlier.forward_price)
+        .ln()
+        .min((later.highest_observed_strike / later.forward_price).ln());
+
+    if from > to {
+        return Ok(None);
+    }
+
+    let mut worst: Option<(f64, f64)> = None;
+
+    for i in 0..=VERIFICATION_POINTS {
+        let log_moneyness = from + (to - from) * (i as f64 / VERIFICATION_POINTS as f64);
+        let violation = earlier.get_call_price(log_moneyness)? - later.get_call_price(log_moneyness)?;
+
+        if violation > CALENDAR_TOLERANCE && worst.is_none_or(|x| violation > x.1) {
+            worst = Some((log_moneyness, violation));
+        }
+    }
+
+    Ok(worst.map(|x| x.0))
+}
@@ -0,0 +1,115 @@
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::{
+    analytics::{STANDARD_TENOR_DAYS, SmileGraph, interpolate_surface, interpolate_surface_at_delta},
+    helpers,
+    types::{TsError, TsErrorType::RuntimeError},
+};
+
+/// One asset's surface read at a standard tenor.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct AssetTenorPoint {
+    pub asset: String,
+    pub atm_implied_volatility: f64,
+    /// The implied volatility of the 25 delta call minus that of the 25 delta put. Negative when puts are more expensive.
+    pub risk_reversal_25_delta: f64,
+    /// The at the money volatility divided by that of the first asset, e.g. the ETH/BTC vol ratio.
+    pub atm_volatility_ratio: f64,
+    /// The risk reversal minus that of the first asset. Negative when the asset's puts are relatively more expensive.
+    pub risk_reversal_difference: f64,
+}
+
+/// Every asset's surface read at the same standard tenor.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct AssetTenorComparison {
+    pub tenor_days: i64,
+    /// In the same order as the assets were given.
+    pub assets: Vec<AssetTenorPoint>,
+}
+
+/// The at the money term structures and skews of several assets' surfaces, side by side at the standard tenors.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct AssetComparison {
+    pub calculated_at: DateTime<Utc>,
+    /// The asset the others are compared to.
+    pub base_asset: String,
+    /// Only the tenors that fall between the first and last expiries of every asset's surface, so that none of them is
+    /// extrapolated.
+    pub tenors: Vec<AssetTenorComparison>,
+}
+
+impl AssetComparison {
+    /// Compare the surfaces of the assets, each given as its name and its smile graphs ordered by expiry. The first asset
+    /// is the one the others are compared to.
+    pub fn calculate(surfaces: &[(String, Vec<SmileGraph>)]) -> Result<AssetComparison, TsError> {
+        let (base_asset, _) = surfaces
+            .first()
+            .ok_or(TsError::new(RuntimeError, "There are no surfaces to compare"))?;
+        let mut tenors = Vec::new();
+
+        for tenor_days in STANDARD_TENOR_DAYS {
+            let years_until_expiry = TimeDelta::days(tenor_days).num_seconds() as f64 / 31556926.0;
+
+            if !surfaces
+                .iter()
+                .all(|(_, graphs)| is_within_surface(graphs, years_until_expiry).unwrap_or(false))
+            {
+                continue;
+            }
+
+            let mut points: Vec<AssetTenorPoint> = Vec::with_capacity(surfaces.len());
+
+            for (asset, graphs) in surfaces {
+                let (atm_implied_volatility, risk_reversal_25_delta) = read_tenor(graphs, years_until_expiry)
+                    .map_err(|e| e.context(format!("Failed reading the {tenor_days} day tenor of {asset}")))?;
+                let (base_atm_implied_volatility, base_risk_reversal_25_delta) = points
+                    .first()
+                    .map(|x| (x.atm_implied_volatility, x.risk_reversal_25_delta))
+                    .unwrap_or((atm_implied_volatility, risk_reversal_25_delta));
+
+                points.push(AssetTenorPoint {
+                    asset: asset.clone(),
+                    atm_implied_volatility,
+                    risk_reversal_25_delta,
+                    atm_volatility_ratio: atm_implied_volatility / base_atm_implied_volatility,
+                    risk_reversal_difference: risk_reversal_25_delta - base_risk_reversal_25_delta,
+                });
+            }
+
+            tenors.push(AssetTenorComparison {
+                tenor_days,
+                assets: points,
+            });
+        }
+
+        Ok(AssetComparison {
+            calculated_at: helpers::get_now(),
+            base_asset: base_asset.clone(),
+            tenors,
+        })
+    }
+}
+
+/// Whether the time to expiry is between the first and last expiries of the surface.
+fn is_within_surface(smile_graphs: &[SmileGraph], years_until_expiry: f64) -> Result<bool, TsError> {
+    let (Some(first), Some(last)) = (smile_graphs.first(), smile_graphs.last()) else {
+        return Ok(false);
+    };
+
+    Ok(first.get_years_until_expiry()? <= years_until_expiry && years_until_expiry <= last.get_years_until_expiry()?)
+}
+
+/// The at the money implied volatility and 25 delta risk reversal of the surface at the time to expiry.
+fn read_tenor(smile_graphs: &[SmileGraph], years_until_expiry: f64) -> Result<(f64, f64), TsError> {
+    let first_graph = smile_graphs
+        .first()
+        .ok_or(TsError::new(RuntimeError, "The surface has no smiles"))?;
+    let forward_price =
+        interpolate_surface(smile_graphs, first_graph.get_underlying_forward_price()?, years_until_expiry)?.forward_price;
+
+    let atm_implied_volatility = interpolate_surface(smile_graphs, forward_price, years_until_expiry)?.implied_volatility;
+    let call_implied_volatility = interpolate_surface_at_delta(smile_graphs, 0.25, years_until_expiry)?.implied_volatility;
+    let put_implied_volatility = interpolate_surface_at_delta(smile_graphs, -0.25, years_until_expiry)?.implied_volatility;
+
+    Ok((atm_implied_volatility, call_implied_volatility - put_implied_volatility))
+}
@@ -0,0 +1,199 @@
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::{
+    analytics::{ImpliedVolatilitySource, OptionInstrument, SmileGraph, types::SVICurveParameters},
+    helpers::ProgressBar,
+    types::{TsError, TsErrorType::RuntimeError},
+};
+
+/// The number of strikes the implied volatility band is measured at, spread over the same range that build-graphs draws
+/// the smile over (half the observed range again beyond each end).
+const BAND_STRIKES: usize = 41;
+
+/// The seed for picking resampled residuals, fixed so that the same fit always gives the same confidence band.
+const BOOTSTRAP_SEED: u64 = 1;
+
+/// The lower and upper bounds of a confidence interval.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug)]
+pub struct ConfidenceInterval {
+    pub lower: f64,
+    pub upper: f64,
+}
+
+/// The confidence interval of a smile's implied volatility at a strike.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct StrikeConfidenceInterval {
+    pub strike: f64,
+    pub implied_volatility: ConfidenceInterval,
+}
+
+/// How uncertain a smile's fit is, from refitting it to quotes made up of the fitted curve plus resampled residuals (see
+/// bootstrap_smile()). A narrow band in the middle of the smile and a wide one in the wings means the wings are held in
+/// place by only a few quotes.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct SmileConfidenceBand {
+    /// The fraction of resampled fits each interval holds, e.g. 0.9 for the 5th to 95th percentiles.
+    pub confidence_level: f64,
+    /// The number of resampled fits the intervals come from.
+    pub resamples: usize,
+    /// Resamples that couldn't be fit, which aren't counted in the intervals.
+    pub failed_resamples: usize,
+    pub a: ConfidenceInterval,
+    pub b: ConfidenceInterval,
+    pub rho: ConfidenceInterval,
+    pub m: ConfidenceInterval,
+    pub sigma: ConfidenceInterval,
+    /// In order of strike.
+    pub strikes: Vec<StrikeConfidenceInterval>,
+}
+
+/// Find confidence intervals of a fitted smile's SVI parameters and implied volatilities by residual bootstrap. Each
+/// resample moves every option's implied volatility to the fitted curve's times the relative residual (its implied
+/// volatility over the fitted curve's) of an option picked at random (with replacement), and is refit starting from the fitted curve (see SmileGraph::refit_smile()), so it's far quicker
+/// than fitting from scratch. The spread of the refit curves shows how much the fit depends on the noise in the quotes.
+pub fn bootstrap_smile(
+    graph: &SmileGraph,
+    resamples: usize,
+    confidence_level: f64,
+    progress: &mut ProgressBar,
+) -> Result<SmileConfidenceBand, TsError> {
+    if !graph.has_been_fit {
+        return Err(TsError::new(RuntimeError, "Only a smile that has been fit can be bootstrapped"));
+    }
+    if resamples < 2 {
+        return Err(TsError::new(RuntimeError, "At least 2 resamples are needed to bootstrap a smile"));
+    }
+    if !(confidence_level > 0.0 && confidence_level < 1.0) {
+        return Err(TsError::new(RuntimeError, format!("Invalid confidence level {confidence_level}")));
+    }
+
+    let fitted_implied_volatilities = graph
+        .options
+        .iter()
+        .map(|x| graph.get_implied_volatility_at_strike(x.strike))
+        .collect::<Result<Vec<f64>, TsError>>()?;
+    // Relative, so that a large residual in a high volatility wing can't make a lower volatility negative.
+    let residuals = graph
+        .options
+        .iter()
+        .zip(&fitted_implied_volatilities)
+        .map(|(option, fitted)| Ok(option.get_implied_volatility()? / fitted))
+        .collect::<Result<Vec<f64>, TsError>>()?;
+    let band_strikes = get_band_strikes(graph);
+
+    let mut rng = StdRng::seed_from_u64(BOOTSTRAP_SEED);
+    let mut parameters: [Vec<f64>; 5] = Default::default();
+    let mut implied_volatilities = vec![Vec::with_capacity(resamples); band_strikes.len()];
+    let mut failed_resamples = 0;
+
+    for _ in 0..resamples {
+        let resampled_implied_volatilities = fitted_implied_volatilities
+            .iter()
+            .map(|x| x * residuals[rng.random_range(0..residuals.len())])
+            .collect::<Vec<f64>>();
+
+        match refit_resample(graph, &resampled_implied_volatilities) {
+            Ok(resample) => {
+                let curve = &resample.svi_curve_parameters;
+                let fitted_parameters = [curve.get_a(), curve.get_b(), curve.get_p(), curve.get_m(), curve.get_o()];
+
+                for (values, value) in parameters.iter_mut().zip(fitted_parameters) {
+                    values.push(value);
+                }
+
+                for (values, strike) in implied_volatilities.iter_mut().zip(&band_strikes) {
+                    values.push(resample.get_implied_volatility_at_strike(*strike)?);
+                }
+            }
+            Err(_) => failed_resamples += 1,
+        }
+
+        progress.increment();
+    }
+
+    if failed_resamples == resamples {
+        return Err(TsError::new(RuntimeError, "None of the resamples could be fit"));
+    }
+
+    let [a, b, rho, m, sigma] = parameters.map(|mut x| get_confidence_interval(&mut x, confidence_level));
+
+    Ok(SmileConfidenceBand {
+        confidence_level,
+        resamples,
+        failed_resamples,
+        a,
+        b,
+        rho,
+        m,
+        sigma,
+        strikes: band_strikes
+            .into_iter()
+            .zip(implied_volatilities)
+            .map(|(strike, mut values)| StrikeConfidenceInterval {
+                strike,
+                implied_volatility: get_confidence_interval(&mut values, confidence_level),
+            })
+            .collect(),
+    })
+}
+
+/// A copy of the smile with the options' implied volatilities replaced, refit from the smile's curve.
+fn refit_resample(graph: &SmileGraph, implied_volatilities: &[f64]) -> Result<SmileGraph, TsError> {
+    let options = graph
+        .options
+        .iter()
+        .zip(implied_volatilities)
+        .map(|(option, implied_volatility)| {
+            let mut option = option.clone();
+
+            match option.implied_volatility_source {
+                ImpliedVolatilitySource::Solve => option.set_price(option.get_price_at_implied_volatility(*implied_volatility)?),
+                ImpliedVolatilitySource::Exchange => option.set_exchange_implied_volatility(*implied_volatility),
+            }
+
+            Ok(option)
+        })
+        .collect::<Result<Vec<OptionInstrument>, TsError>>()?;
+    let curve = &graph.svi_curve_parameters;
+    let mut resample = graph.with_options_unconstrained(options)?;
+
+    resample.svi_curve_parameters =
+        SVICurveParameters::new_from_values(curve.get_a(), curve.get_b(), curve.get_p(), curve.get_m(), curve.get_o())?;
+    resample.has_been_fit = true;
+    resample.refit_smile()?;
+
+    Ok(resample)
+}
+
+/// Strikes evenly spread from half the observed range below the lowest observed strike to half of it above the highest,
+/// leaving out any at or below zero.
+fn get_band_strikes(graph: &SmileGraph) -> Vec<f64> {
+    let strike_range = graph.highest_observed_strike - graph.lowest_observed_strike;
+    let start = graph.lowest_observed_strike - strike_range * 0.5;
+
+    (0..BAND_STRIKES)
+        .map(|i| start + strike_range * 2.0 * i as f64 / (BAND_STRIKES - 1) as f64)
+        .filter(|x| *x > 0.0)
+        .collect()
+}
+
+/// The interval holding the middle confidence_level of the values, interpolating between them. Sorts the values.
+fn get_confidence_interval(values: &mut [f64], confidence_level: f64) -> ConfidenceInterval {
+    values.sort_by(|a, b| a.total_cmp(b));
+
+    let tail = (1.0 - confidence_level) / 2.0;
+
+    ConfidenceInterval {
+        lower: get_quantile(values, tail),
+        upper: get_quantile(values, 1.0 - tail),
+    }
+}
+
+/// The value the given fraction of the way through the sorted values, interpolating between the two nearest.
+pub fn get_quantile(sorted_values: &[f64], fraction: f64) -> f64 {
+    let position = fraction * (sorted_values.len() - 1) as f64;
+    let below = position.floor() as usize;
+    let above = position.ceil() as usize;
+
+    sorted_values[below] + (sorted_values[above] - sorted_values[below]) * (position - below as f64)
+}
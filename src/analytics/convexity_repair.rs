@@ -0,0 +1,148 @@
+use crate::{
+    analytics::{OptionInstrument, OptionType},
+    types::{TsError, TsErrorType::RuntimeError},
+};
+
+/// The most times we will go through every condition when repairing prices.
+const MAX_REPAIR_ITERATIONS: u32 = 10_000;
+
+/// We stop repairing once no price moves by more than this much (USD) in an iteration.
+const REPAIR_TOLERANCE: f64 = 0.000001;
+
+/// A change made to the price of an option when repairing convexity.
+pub struct PriceAdjustment {
+    pub instrument_id: Box<str>,
+    pub strike: f64,
+    pub option_type: OptionType,
+    pub old_price: f64,
+    pub new_price: f64,
+}
+
+/// Adjust the prices of the given options, which must all have the same expiry, so that for each option type the price is
+/// convex and monotonic in strike, as it must be for there to be no butterfly or vertical spread arbitrage. Noisy quotes
+/// often break this slightly, which makes fitting less stable.
+///
+/// The prices are moved as little as possible (by least squares) and, where there is a bid or ask, kept between them.
+pub fn repair_price_convexity(options: &mut [OptionInstrument]) -> Result<Vec<PriceAdjustment>, TsError> {
+    let mut adjustments = Vec::new();
+
+    for option_type in [OptionType::Call, OptionType::Put] {
+        let mut indexes: Vec<usize> = (0..options.len())
+            .filter(|i| options[*i].option_type == option_type)
+            .collect();
+        indexes.sort_by(|a, b| options[*a].strike.total_cmp(&options[*b].strike));
+
+        // Need at least 3 strikes for convexity to mean anything.
+        if indexes.len() < 3 {
+            continue;
+        }
+
+        let strikes: Vec<f64> = indexes.iter().map(|i| options[*i].strike).collect();
+        let bounds: Vec<(f64, f64)> = indexes
+            .iter()
+            .map(|i| {
+                let option = &options[*i];
+                (option.bid_price.unwrap_or(0.0), option.ask_price.unwrap_or(f64::MAX))
+            })
+            .collect();
+        let old_prices: Vec<f64> = indexes.iter().map(|i| options[*i].price).collect();
+        let new_prices = repair_prices(&strikes, &old_prices, &bounds, option_type)?;
+
+        for (i, index) in indexes.into_iter().enumerate() {
+            if (new_prices[i] - old_prices[i]).abs() <= REPAIR_TOLERANCE {
+                continue;
+            }
+
+            let option = &mut options[index];
+            option.price = new_prices[i];
+
+            adjustments.push(PriceAdjustment {
+                instrument_id: option.instrument_id.clone(),
+                strike: option.strike,
+                option_type,
+                old_price: old_prices[i],
+                new_price: new_prices[i],
+            });
+        }
+    }
+
+    Ok(adjustments)
+}
+
+/// Returns the prices closest (by least squares) to the given ones that are convex and monotonic in strike and within the
+/// given (lower, upper) bounds. Strikes must be ascending and unique. If the bounds make this impossible, the prices will be
+/// within the bounds but may not be entirely convex.
+///
+/// Every condition is a linear inequality, so we use Hildreth's method: repeatedly project onto each condition in turn,
+/// remembering how far each one has moved the prices so that the moves can be undone once they are no longer needed. This
+/// converges on the closest prices that meet every condition, and means only the prices around a violation are changed.
+fn repair_prices(strikes: &[f64], prices: &[f64], bounds: &[(f64, f64)], option_type: OptionType) -> Result<Vec<f64>, TsError> {
+    if strikes.windows(2).any(|x| x[1] <= x[0]) {
+        return Err(TsError::new(RuntimeError, "Strikes must be ascending and unique to repair convexity"));
+    }
+
+    let last = prices.len() - 1;
+
+    // Each condition is that the weighted sum of some prices is at least some value.
+    let mut conditions: Vec<(Vec<(usize, f64)>, f64)> = Vec::new();
+
+    // The slope between strikes must never decrease.
+    for i in 1..last {
+        let left_width = strikes[i] - strikes[i - 1];
+        let right_width = strikes[i + 1] - strikes[i];
+        conditions.push((
+            vec![
+                (i - 1, 1.0 / left_width),
+                (i, -1.0 / left_width - 1.0 / right_width),
+                (i + 1, 1.0 / right_width),
+            ],
+            0.0,
+        ));
+    }
+
+    // Call prices must fall as the strike rises and put prices must rise. Since the prices are convex, it's enough to check
+    // the slope at the end where it's steepest.
+    match option_type {
+        OptionType::Call => conditions.push((vec![(last - 1, 1.0), (last, -1.0)], 0.0)),
+        OptionType::Put => conditions.push((vec![(0, -1.0), (1, 1.0)], 0.0)),
+    }
+
+    for (i, (lower, upper)) in bounds.iter().enumerate() {
+        conditions.push((vec![(i, 1.0)], lower.max(0.0)));
+
+        if upper.is_finite() {
+            conditions.push((vec![(i, -1.0)], -upper));
+        }
+    }
+
+    let mut prices = prices.to_vec();
+    let mut moves = vec![0.0; conditions.len()];
+
+    for _ in 0..MAX_REPAIR_ITERATIONS {
+        let mut largest_change: f64 = 0.0;
+
+        for (j, (weights, minimum)) in conditions.iter().enumerate() {
+            let value: f64 = weights.iter().map(|(i, weight)| weight * prices[*i]).sum();
+            let weights_squared: f64 = weights.iter().map(|(_, weight)| weight * weight).sum();
+            let change = ((minimum - value) / weights_squared).max(-moves[j]);
+
+            moves[j] += change;
+
+            for (i, weight) in weights {
+                prices[*i] += change * weight;
+                largest_change = largest_change.max((change * weight).abs());
+            }
+        }
+
+        if largest_change <= REPAIR_TOLERANCE {
+            break;
+        }
+    }
+
+    // If the conditions couldn't all be met, make sure we at least stay within the bounds.
+    for (price, (lower, upper)) in prices.iter_mut().zip(bounds) {
+        *price = price.min(*upper).max(lower.max(0.0));
+    }
+
+    Ok(prices)
+}
@@ -0,0 +1,102 @@
+use chrono::{DateTime, Utc};
+
+use crate::{
+    analytics::{CrossValidation, OptionInstrument, SmileGraph},
+    helpers::ProgressBar,
+    types::{TsError, TsErrorType::RuntimeError},
+};
+
+/// How well a smile's fit predicts the implied volatilities of options it wasn't fit to. Errors are the fitted implied
+/// volatility minus the option's, in volatility points (0.01 = 1 point).
+pub struct SmileCrossValidation {
+    pub expiry: DateTime<Utc>,
+    pub folds: usize,
+    /// Folds whose remaining options couldn't be fit. Their options aren't counted in the out of sample errors.
+    pub failed_folds: usize,
+    /// The RMSE of the curve fit to every option, if the smile has been fit.
+    pub in_sample_rmse_vol_points: Option<f64>,
+    pub out_of_sample_rmse_vol_points: f64,
+    pub out_of_sample_max_absolute_error_vol_points: f64,
+    /// The number of options whose out of sample errors were measured.
+    pub predicted_options: usize,
+}
+
+/// Fit the smile once for each fold, with that fold's options held out, and measure how far the fitted curve is from the
+/// held out options. Folds take every n-th option by strike, so that each fold's options are spread across the smile
+/// rather than being one wing that the others would have to extrapolate to. A curve that fits the options it's given
+/// much better than the ones held out is fitting noise in the quotes rather than the shape of the smile.
+/// The folds are fit without the smile's prior or at the money anchor, which would otherwise hold them in place whatever
+/// their options.
+pub fn cross_validate_smile(
+    graph: &SmileGraph,
+    cross_validation: CrossValidation,
+    progress: &mut ProgressBar,
+) -> Result<SmileCrossValidation, TsError> {
+    let folds = match cross_validation {
+        CrossValidation::LeaveOneOut => graph.options.len(),
+        CrossValidation::KFold(folds) => folds.min(graph.options.len()),
+    };
+
+    if folds < 2 {
+        return Err(TsError::new(RuntimeError, "At least 2 options are needed to cross-validate a smile"));
+    }
+
+    let mut options_by_strike: Vec<&OptionInstrument> = graph.options.iter().collect();
+    options_by_strike.sort_by(|a, b| a.strike.total_cmp(&b.strike));
+
+    let mut squared_errors = 0.0;
+    let mut max_absolute_error: f64 = 0.0;
+    let mut predicted_options = 0;
+    let mut failed_folds = 0;
+
+    for fold in 0..folds {
+        let mut held_out = Vec::new();
+        let mut training = Vec::new();
+
+        for (i, option) in options_by_strike.iter().enumerate() {
+            match i % folds == fold {
+                true => held_out.push(*option),
+                false => training.push((*option).clone()),
+            }
+        }
+
+        let mut training_graph = graph.with_options_unconstrained(training)?;
+
+        // The fits' own progress isn't worth showing.
+        if training_graph
+            .fit_smile(&ProgressBar::new("", 1, true))
+            .is_err()
+        {
+            failed_folds += 1;
+            progress.increment();
+            continue;
+        }
+
+        for option in held_out {
+            let error = training_graph.get_implied_volatility_at_strike(option.strike)? - option.get_implied_volatility()?;
+
+            squared_errors += error * error;
+            max_absolute_error = max_absolute_error.max(error.abs());
+            predicted_options += 1;
+        }
+
+        progress.increment();
+    }
+
+    if predicted_options == 0 {
+        return Err(TsError::new(RuntimeError, "None of the folds could be fit"));
+    }
+
+    Ok(SmileCrossValidation {
+        expiry: graph.get_expiration()?,
+        folds,
+        failed_folds,
+        in_sample_rmse_vol_points: match graph.has_been_fit {
+            true => Some(100.0 * graph.get_fit_rmse()?),
+            false => None,
+        },
+        out_of_sample_rmse_vol_points: 100.0 * (squared_errors / predicted_options as f64).sqrt(),
+        out_of_sample_max_absolute_error_vol_points: 100.0 * max_absolute_error,
+        predicted_options,
+    })
+}
@@ -0,0 +1,115 @@
+use std::time::Instant;
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+/// How a differential evolution search is run.
+pub struct DifferentialEvolutionSettings {
+    /// The number of candidates in each generation.
+    pub population: usize,
+    /// The most generations that are evolved.
+    pub max_generations: u64,
+    /// How far a mutant moves along the difference between two other candidates.
+    pub mutation: f64,
+    /// The chance that each parameter of a trial is taken from the mutant rather than the candidate it might replace.
+    pub crossover: f64,
+    /// The search stops once the best loss hasn't improved (as a fraction) by this much for stagnant_generations.
+    pub required_improvement: f64,
+    pub stagnant_generations: u64,
+    /// Searches with the same seed and settings give the same result.
+    pub seed: u64,
+    /// The search stops after the generation in which this passes, if set.
+    pub deadline: Option<Instant>,
+}
+
+/// Minimise the loss within the given (start, end) bounds of each parameter with differential evolution (Storn and Price's
+/// DE/rand/1/bin), returning the best parameters found and their loss. Unlike local optimisers, this doesn't need a
+/// starting point and isn't drawn into the nearest local minimum, at the cost of many more evaluations. Invalid parameters
+/// should be given a very high loss rather than rejected, so that candidates near them can still evolve away.
+///
+/// after_generation is called after each generation, e.g. to report progress.
+pub fn minimise_with_differential_evolution<const N: usize>(
+    mut loss: impl FnMut(&[f64; N]) -> f64,
+    bounds: &[(f64, f64); N],
+    settings: &DifferentialEvolutionSettings,
+    mut after_generation: impl FnMut(u64, f64),
+) -> ([f64; N], f64) {
+    if settings.population < 4 {
+        panic!("Invalid arguments: differential evolution needs a population of at least 4");
+    }
+
+    let mut rng = StdRng::seed_from_u64(settings.seed);
+    let mut population: Vec<[f64; N]> = (0..settings.population)
+        .map(|_| bounds.map(|(start, end)| rng.random_range(start..=end)))
+        .collect();
+    let mut losses: Vec<f64> = population.iter().map(&mut loss).collect();
+    let mut best_index = find_best_index(&losses);
+    let mut stagnant_since_loss = losses[best_index];
+    let mut stagnant_generations = 0;
+
+    for generation in 1..=settings.max_generations {
+        for i in 0..settings.population {
+            let [a, b, c] = pick_three_others(&mut rng, settings.population, i);
+            let forced_parameter = rng.random_range(0..N);
+            let mut trial = population[i];
+
+            for (j, value) in trial.iter_mut().enumerate() {
+                if j == forced_parameter || rng.random::<f64>() < settings.crossover {
+                    let (start, end) = bounds[j];
+                    let mutant = population[a][j] + settings.mutation * (population[b][j] - population[c][j]);
+
+                    // Reflecting back into the bounds keeps the spread of the population better than clamping to them.
+                    *value = match mutant {
+                        x if x < start => (2.0 * start - x).min(end),
+                        x if x > end => (2.0 * end - x).max(start),
+                        x => x,
+                    };
+                }
+            }
+
+            let trial_loss = loss(&trial);
+
+            if trial_loss <= losses[i] {
+                population[i] = trial;
+                losses[i] = trial_loss;
+            }
+        }
+
+        best_index = find_best_index(&losses);
+        after_generation(generation, losses[best_index]);
+
+        if losses[best_index] < stagnant_since_loss - stagnant_since_loss.abs() * settings.required_improvement {
+            stagnant_since_loss = losses[best_index];
+            stagnant_generations = 0;
+        } else {
+            stagnant_generations += 1;
+        }
+
+        if stagnant_generations >= settings.stagnant_generations || settings.deadline.is_some_and(|x| Instant::now() >= x) {
+            break;
+        }
+    }
+
+    (population[best_index], losses[best_index])
+}
+
+fn find_best_index(losses: &[f64]) -> usize {
+    losses
+        .iter()
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(b.1))
+        .map(|x| x.0)
+        .unwrap_or(0)
+}
+
+/// Three different candidates, none of which is the one at exclude.
+fn pick_three_others(rng: &mut StdRng, population: usize, exclude: usize) -> [usize; 3] {
+    let mut picked = [exclude; 3];
+
+    for i in 0..3 {
+        while picked[..i].contains(&picked[i]) || picked[i] == exclude {
+            picked[i] = rng.random_range(0..population);
+        }
+    }
+
+    picked
+}
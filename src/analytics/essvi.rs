@@ -0,0 +1,375 @@
+use chrono::{DateTime, Utc};
+use levenberg_marquardt::{LeastSquaresProblem, LevenbergMarquardt};
+use nalgebra::{DVector, Dyn, Matrix, OMatrix, Owned, U1};
+
+use crate::{
+    analytics::{
+        SmileGraph,
+        math::{saturating_exp, saturating_ln, sigmoid},
+        types::SVICurveParameters,
+    },
+    helpers::error_unless_positive_f64,
+    types::{
+        TsError,
+        TsErrorType::{RuntimeError, UnsolvableError},
+    },
+};
+
+/// The most extreme correlation allowed, even where the no-arbitrage conditions would allow more. Exactly 1 would make the
+/// slices degenerate.
+const MAX_ABSOLUTE_RHO: f64 = 0.999;
+
+/// The largest eta can be. Every slice's psi is less than eta, and a psi of 4 or more always has butterfly arbitrage.
+const MAX_ETA: f64 = 4.0;
+
+/// The smallest eta can be. Zero would make every slice flat, which has no raw SVI equivalent.
+const MIN_ETA: f64 = 0.000001;
+
+/// The largest gamma can be. Beyond 1, psi would fall as theta rises, which is calendar arbitrage.
+const MAX_GAMMA: f64 = 1.0;
+
+/// The smallest gap left between the at the money total variances of neighbouring slices when starting the calibration.
+const MIN_STARTING_THETA_GAP: f64 = 0.00001;
+
+/// How far the log of the gap between neighbouring slices' theta can get from zero (see saturating_exp()), which stops a
+/// long optimiser step making theta overflow.
+const MAX_ABSOLUTE_LOG_THETA_GAP: f64 = 20.0;
+
+/// What the arbitrage penalty of a slice whose rho bounds cross (see SliceParameters::rho_shortfall) is scaled by, relative
+/// to the implied variance residuals.
+const ARBITRAGE_PENALTY_WEIGHT: f64 = 10.0;
+
+/// The step used to estimate the Jacobian by finite differences.
+const JACOBIAN_STEP: f64 = 0.000001;
+
+const LEVENBERG_MARQUARDT_PATIENCE: usize = 200;
+
+/// The power law used for the curvature of every slice, phi(theta) = eta / (theta^gamma * (1 + theta)^(1 - gamma)).
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize)]
+pub struct EssviPowerLaw {
+    pub eta: f64,
+    pub gamma: f64,
+}
+
+impl EssviPowerLaw {
+    pub fn phi(&self, theta: f64) -> f64 {
+        self.eta / (theta.powf(self.gamma) * (1.0 + theta).powf(1.0 - self.gamma))
+    }
+}
+
+/// One expiry of a calibrated eSSVI surface.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize)]
+pub struct EssviSlice {
+    pub expiry: DateTime<Utc>,
+    /// The at the money total implied variance.
+    pub theta: f64,
+    /// The correlation, which sets the skew of the slice.
+    pub rho: f64,
+    /// theta * phi(theta), the at the money skew scale. This must not fall between expiries.
+    pub psi: f64,
+    /// The root mean squared difference in implied volatility between the slice and its options.
+    pub rmse: f64,
+}
+
+impl EssviSlice {
+    /// The raw SVI parameters giving exactly the same total variance as this slice.
+    pub fn to_svi_curve(&self) -> Result<SVICurveParameters, TsError> {
+        let phi = self.psi / self.theta;
+
+        SVICurveParameters::new_from_values(
+            self.theta * (1.0 - self.rho * self.rho) / 2.0,
+            self.psi / 2.0,
+            self.rho,
+            -self.rho / phi,
+            (1.0 - self.rho * self.rho).sqrt() / phi,
+        )
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct EssviSurface {
+    pub power_law: EssviPowerLaw,
+    pub slices: Vec<EssviSlice>,
+}
+
+/// The eSSVI total implied variance at the given log moneyness, for a slice with the given at the money total variance,
+/// correlation and curvature.
+pub fn essvi_variance(theta: f64, rho: f64, phi: f64, log_moneyness: f64) -> f64 {
+    let x = phi * log_moneyness + rho;
+
+    theta / 2.0 * (1.0 + rho * phi * log_moneyness + (x * x + 1.0 - rho * rho).sqrt())
+}
+
+/// One slice's parameters, as set by the optimiser.
+#[derive(Clone, Copy)]
+struct SliceParameters {
+    theta: f64,
+    rho: f64,
+    psi: f64,
+    /// How far apart the bounds on rho cross, if no rho meets every no-arbitrage condition, and zero otherwise. The slice
+    /// then has the rho halfway between the bounds.
+    rho_shortfall: f64,
+}
+
+/// A point on the surface to calibrate against.
+struct CalibrationTarget {
+    slice_index: usize,
+    log_moneyness: f64,
+    total_implied_variance: f64,
+    years_until_expiry: f64,
+    /// The square root of the option's fit weight.
+    weight: f64,
+}
+
+/// Calibrate an eSSVI surface (Hendriks and Martini) to every given smile at once, replacing each smile's curve with the
+/// raw SVI equivalent of its slice. The smiles must be sorted by expiry.
+///
+/// Each slice has its own at the money total variance theta and correlation rho, and they share a power law curvature
+/// phi(theta). The parameters are optimised in a transformed space that enforces the published no-arbitrage conditions:
+/// theta and psi rise with expiry, each slice meets Gatheral and Jacquier's conditions for no butterfly arbitrage
+/// (psi(1 + |rho|) < 4 and psi * phi(1 + |rho|) <= 4), and each rho is kept within the bounds from the previous slice that
+/// Hendriks and Martini showed rule out calendar arbitrage. Parameters where no rho meets every condition are penalised
+/// by how far apart the bounds are, and rejected if the calibration ends on them.
+///
+/// Residuals are differences in implied variance rather than total variance, so that short expiries aren't swamped.
+pub fn fit_essvi_surface(smile_graphs: &mut [SmileGraph]) -> Result<EssviSurface, TsError> {
+    let targets = build_calibration_targets(smile_graphs)?;
+    let mut problem = EssviProblem {
+        x: get_starting_parameters(smile_graphs)?,
+        targets: &targets,
+        residuals: None,
+    };
+
+    let initial_x = problem.x.clone();
+    problem.set_params(&initial_x);
+
+    let (result, report) = LevenbergMarquardt::new()
+        .with_patience(LEVENBERG_MARQUARDT_PATIENCE)
+        .minimize(problem);
+
+    if !report.termination.was_successful() || result.residuals.is_none() {
+        return Err(TsError::new(
+            UnsolvableError,
+            format!("Failed computing Levenberg-Marquardt: {:#?}", report.termination),
+        ));
+    }
+
+    let (power_law, parameters) = from_unconstrained(&result.x)?;
+
+    if let Some(i) = parameters.iter().position(|x| x.rho_shortfall > 0.0) {
+        return Err(TsError::new(UnsolvableError, format!("No rho is free of arbitrage for slice {}", i + 1)));
+    }
+
+    let mut slices = Vec::with_capacity(smile_graphs.len());
+
+    for (graph, parameters) in smile_graphs.iter_mut().zip(parameters) {
+        let mut slice = EssviSlice {
+            expiry: graph.get_expiration()?,
+            theta: parameters.theta,
+            rho: parameters.rho,
+            psi: parameters.psi,
+            rmse: 0.0,
+        };
+
+        graph.svi_curve_parameters = slice.to_svi_curve()?;
+        graph.has_been_fit = true;
+        slice.rmse = graph.get_fit_rmse()?;
+        slices.push(slice);
+    }
+
+    Ok(EssviSurface { power_law, slices })
+}
+
+fn build_calibration_targets(smile_graphs: &[SmileGraph]) -> Result<Vec<CalibrationTarget>, TsError> {
+    let mut targets = Vec::new();
+
+    for (slice_index, graph) in smile_graphs.iter().enumerate() {
+        let forward_price = graph.get_underlying_forward_price()?;
+
+        for (option, weight) in graph.options.iter().zip(graph.get_fit_weights()) {
+            targets.push(CalibrationTarget {
+                slice_index,
+                log_moneyness: option.get_log_moneyness_using_custom_forward(forward_price),
+                total_implied_variance: option.get_total_implied_variance()?,
+                years_until_expiry: option.get_years_until_expiry()?,
+                weight: weight.sqrt(),
+            });
+        }
+    }
+
+    Ok(targets)
+}
+
+/// Start from each smile's at the money total variance, no skew, eta of 1 and gamma of 1/2.
+fn get_starting_parameters(smile_graphs: &[SmileGraph]) -> Result<DVector<f64>, TsError> {
+    if smile_graphs.is_empty() {
+        return Err(TsError::new(RuntimeError, "There are no smiles to calibrate to"));
+    }
+
+    let mut x = DVector::zeros(2 + 2 * smile_graphs.len());
+    let mut previous_theta = 0.0;
+
+    x[0] = to_logit((1.0 - MIN_ETA) / (MAX_ETA - MIN_ETA));
+    x[1] = to_logit(0.5 / MAX_GAMMA);
+
+    for (i, graph) in smile_graphs.iter().enumerate() {
+        let forward_price = graph.get_underlying_forward_price()?;
+        let nearest_option = graph
+            .options
+            .iter()
+            .min_by(|a, b| {
+                let a = a
+                    .get_log_moneyness_using_custom_forward(forward_price)
+                    .abs();
+                let b = b
+                    .get_log_moneyness_using_custom_forward(forward_price)
+                    .abs();
+                a.total_cmp(&b)
+            })
+            .ok_or(TsError::new(RuntimeError, "Smile graph has no options"))?;
+        let theta = nearest_option.get_total_implied_variance()?;
+        error_unless_positive_f64(theta, "theta")?;
+
+        // Theta must rise with expiry, even if the quotes near the money don't quite.
+        let gap = (theta - previous_theta).max(MIN_STARTING_THETA_GAP);
+
+        x[2 + 2 * i] = saturating_ln(gap, MAX_ABSOLUTE_LOG_THETA_GAP);
+        previous_theta += gap;
+    }
+
+    Ok(x)
+}
+
+fn to_logit(x: f64) -> f64 {
+    (x / (1.0 - x)).ln()
+}
+
+/// Convert the optimiser's parameters into the power law and each slice's parameters.
+///
+/// The parameters are the position of eta and gamma between their bounds (as logits), then for each slice the log of its
+/// rise in theta over the previous slice (see saturating_exp()) and the position of rho between its bounds (as a logit).
+fn from_unconstrained(x: &DVector<f64>) -> Result<(EssviPowerLaw, Vec<SliceParameters>), TsError> {
+    let power_law = EssviPowerLaw {
+        eta: MIN_ETA + (MAX_ETA - MIN_ETA) * sigmoid(x[0]),
+        gamma: MAX_GAMMA * sigmoid(x[1]),
+    };
+    let mut slices: Vec<SliceParameters> = Vec::with_capacity((x.len() - 2) / 2);
+    let mut theta = 0.0;
+
+    for i in 0..(x.len() - 2) / 2 {
+        theta += saturating_exp(x[2 + 2 * i], MAX_ABSOLUTE_LOG_THETA_GAP);
+        let phi = power_law.phi(theta);
+        let psi = theta * phi;
+        let max_rho = (4.0 / psi - 1.0)
+            .min(4.0 / (psi * phi) - 1.0)
+            .min(MAX_ABSOLUTE_RHO);
+
+        // |rho * psi - previous_rho * previous_psi| <= psi - previous_psi, which always allows previous_rho * previous_psi / psi.
+        let (lowest_rho, highest_rho) = match slices.last() {
+            None => (-max_rho, max_rho),
+            Some(previous) => (
+                (-1.0 + previous.psi * (1.0 + previous.rho) / psi).max(-max_rho),
+                (1.0 - previous.psi * (1.0 - previous.rho) / psi).min(max_rho),
+            ),
+        };
+
+        // Halfway between crossed bounds is where rho would be as they uncross, so the slices change smoothly either side.
+        let (rho, rho_shortfall) = match lowest_rho <= highest_rho {
+            true => (lowest_rho + (highest_rho - lowest_rho) * sigmoid(x[3 + 2 * i]), 0.0),
+            false => ((lowest_rho + highest_rho) / 2.0, lowest_rho - highest_rho),
+        };
+
+        // The limits on the parameters should stop this ever happening.
+        if !theta.is_finite() || !psi.is_finite() || !rho.is_finite() || psi <= 0.0 {
+            return Err(TsError::new(UnsolvableError, "eSSVI parameters out of range"));
+        }
+
+        slices.push(SliceParameters {
+            theta,
+            rho,
+            psi,
+            rho_shortfall,
+        });
+    }
+
+    Ok((power_law, slices))
+}
+
+/// Returns the weighted implied variance error at each target, followed by each slice's arbitrage penalty, or None if the
+/// numbers overflow.
+fn calculate_residuals(targets: &[CalibrationTarget], x: &DVector<f64>) -> Option<Vec<f64>> {
+    let (_, slices) = from_unconstrained(x).ok()?;
+
+    targets
+        .iter()
+        .map(|target| {
+            let slice = slices[target.slice_index];
+            let variance = essvi_variance(slice.theta, slice.rho, slice.psi / slice.theta, target.log_moneyness);
+
+            variance
+                .is_finite()
+                .then(|| (variance - target.total_implied_variance) / target.years_until_expiry * target.weight)
+        })
+        .chain(
+            slices
+                .iter()
+                .map(|slice| Some(ARBITRAGE_PENALTY_WEIGHT * slice.rho_shortfall)),
+        )
+        .collect()
+}
+
+struct EssviProblem<'a> {
+    /// The parameters in the optimiser's unconstrained space.
+    x: DVector<f64>,
+    targets: &'a [CalibrationTarget],
+    residuals: Option<Vec<f64>>,
+}
+
+impl LeastSquaresProblem<f64, Dyn, Dyn> for EssviProblem<'_> {
+    type ParameterStorage = Owned<f64, Dyn>;
+    type ResidualStorage = Owned<f64, Dyn>;
+    type JacobianStorage = Owned<f64, Dyn, Dyn>;
+
+    fn set_params(&mut self, x: &DVector<f64>) {
+        self.x.copy_from(x);
+        self.residuals = calculate_residuals(self.targets, &self.x);
+    }
+
+    fn params(&self) -> DVector<f64> {
+        self.x.clone()
+    }
+
+    fn residuals(&self) -> Option<Matrix<f64, Dyn, U1, Self::ResidualStorage>> {
+        // Every parameter is limited so that the residuals are finite, so this only stops the calibration if rounding
+        // breaks that.
+        Some(OMatrix::<f64, Dyn, U1>::from_row_slice(self.residuals.as_ref()?))
+    }
+
+    fn jacobian(&self) -> Option<Matrix<f64, Dyn, Dyn, Self::JacobianStorage>> {
+        let residuals = self.residuals.as_ref()?;
+        let mut jacobian = OMatrix::<f64, Dyn, Dyn>::zeros(residuals.len(), self.x.len());
+
+        // Each slice's rho bounds depend on every earlier slice, so finite differences are far simpler than the chain rule.
+        // If a step overflows, step backwards instead.
+        for column in 0..self.x.len() {
+            let mut step = JACOBIAN_STEP;
+            let mut x = self.x.clone();
+            x[column] += step;
+
+            let stepped_residuals = match calculate_residuals(self.targets, &x) {
+                Some(v) => v,
+                None => {
+                    step = -JACOBIAN_STEP;
+                    x[column] = self.x[column] + step;
+                    calculate_residuals(self.targets, &x)?
+                }
+            };
+
+            for (row, (stepped, residual)) in stepped_residuals.iter().zip(residuals).enumerate() {
+                jacobian[(row, column)] = (stepped - residual) / step;
+            }
+        }
+
+        Some(jacobian)
+    }
+}
@@ -0,0 +1,170 @@
+use chrono::{DateTime, Utc};
+
+use crate::{
+    analytics::{OptionInstrument, SmileGraph},
+    types::TsError,
+};
+
+/// How many of each smile's worst fit options are listed.
+const WORST_FIT_COUNT: usize = 5;
+
+/// How well each fitted smile matches the options it was fit to, so that bad fits can be found without reading the logs.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct FitQualityReport {
+    pub calculated_at: DateTime<Utc>,
+    pub smiles: Vec<SmileFitQuality>,
+}
+
+/// How well a single smile was fit. Residuals are the fitted implied volatility minus the option's, in volatility points
+/// (0.01 = 1 point).
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct SmileFitQuality {
+    pub expiry: DateTime<Utc>,
+    /// True if fitting stopped at a time or iteration limit, keeping the best curve found by then.
+    #[serde(default)]
+    pub budget_limited: bool,
+    pub rmse_vol_points: f64,
+    /// The largest residual of any option, ignoring its sign.
+    pub max_absolute_residual_vol_points: f64,
+    /// The share of the variation in the options' implied volatilities that the fitted curve explains. Can be negative if
+    /// the curve fits worse than a flat line through their mean.
+    pub r_squared: f64,
+    /// The options whose fitted implied volatility is below their bid's or above their ask's.
+    pub outside_bid_ask_count: usize,
+    /// The instrument ids of the options with the largest absolute residuals, the worst first.
+    pub worst_fit_instruments: Vec<Box<str>>,
+    /// How many seconds before the newest quote of the surface the smile's options were quoted, on average and at most.
+    /// Options are fetched one at a time, so a smile fit to old quotes may not match the others. Missing if the quote
+    /// times aren't known, including from reports saved by older versions.
+    #[serde(default)]
+    pub mean_quote_age_seconds: Option<f64>,
+    #[serde(default)]
+    pub max_quote_age_seconds: Option<f64>,
+    /// Every option, in the same order as the smile's options.
+    pub options: Vec<OptionResidual>,
+}
+
+/// How well the fitted curve matches a single option.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct OptionResidual {
+    pub instrument_id: Box<str>,
+    pub strike: f64,
+    pub implied_volatility: f64,
+    pub fitted_implied_volatility: f64,
+    pub residual_vol_points: f64,
+    /// The implied volatilities of the option's bid and ask, if it has them and they can be solved.
+    pub bid_implied_volatility: Option<f64>,
+    pub ask_implied_volatility: Option<f64>,
+    pub outside_bid_ask: bool,
+}
+
+impl FitQualityReport {
+    /// Report on each of the given smile graphs that has been fit. Quote ages are measured from the newest quote of any of
+    /// them.
+    pub fn calculate(smile_graphs: &[SmileGraph], calculated_at: DateTime<Utc>) -> Result<FitQualityReport, TsError> {
+        let fitted_graphs = smile_graphs.iter().filter(|x| x.has_been_fit);
+        let newest_quote_time = fitted_graphs
+            .clone()
+            .flat_map(|x| &x.options)
+            .filter_map(|x| x.quote_time)
+            .max();
+
+        let smiles = fitted_graphs
+            .map(|x| SmileFitQuality::calculate(x, newest_quote_time))
+            .collect::<Result<Vec<SmileFitQuality>, TsError>>()?;
+
+        Ok(FitQualityReport { calculated_at, smiles })
+    }
+}
+
+impl SmileFitQuality {
+    /// Report on a fitted smile, with the ages of its quotes measured from the given time, if there is one.
+    pub fn calculate(graph: &SmileGraph, newest_quote_time: Option<DateTime<Utc>>) -> Result<SmileFitQuality, TsError> {
+        let options = graph
+            .options
+            .iter()
+            .map(|x| OptionResidual::calculate(graph, x))
+            .collect::<Result<Vec<OptionResidual>, TsError>>()?;
+
+        let count = options.len() as f64;
+        let mean_implied_volatility = options.iter().map(|x| x.implied_volatility).sum::<f64>() / count;
+        let squared_residuals = options
+            .iter()
+            .map(|x| (x.fitted_implied_volatility - x.implied_volatility).powi(2))
+            .sum::<f64>();
+        let total_squares = options
+            .iter()
+            .map(|x| (x.implied_volatility - mean_implied_volatility).powi(2))
+            .sum::<f64>();
+
+        // A smile with the same implied volatility everywhere has no variation to explain.
+        let r_squared = match total_squares > 0.0 {
+            true => 1.0 - squared_residuals / total_squares,
+            false => 1.0,
+        };
+
+        let quote_ages_seconds = match newest_quote_time {
+            Some(newest) => graph
+                .options
+                .iter()
+                .filter_map(|x| x.quote_time)
+                .map(|x| (newest - x).num_milliseconds() as f64 / 1000.0)
+                .collect::<Vec<f64>>(),
+            None => Vec::new(),
+        };
+
+        let mut worst_fit: Vec<&OptionResidual> = options.iter().collect();
+        worst_fit.sort_by(|a, b| {
+            b.residual_vol_points
+                .abs()
+                .total_cmp(&a.residual_vol_points.abs())
+        });
+
+        Ok(SmileFitQuality {
+            expiry: graph.get_expiration()?,
+            budget_limited: graph.budget_limited,
+            rmse_vol_points: 100.0 * (squared_residuals / count).sqrt(),
+            max_absolute_residual_vol_points: worst_fit
+                .first()
+                .map_or(0.0, |x| x.residual_vol_points.abs()),
+            r_squared,
+            outside_bid_ask_count: options.iter().filter(|x| x.outside_bid_ask).count(),
+            worst_fit_instruments: worst_fit
+                .iter()
+                .take(WORST_FIT_COUNT)
+                .map(|x| x.instrument_id.clone())
+                .collect(),
+            mean_quote_age_seconds: (!quote_ages_seconds.is_empty())
+                .then(|| quote_ages_seconds.iter().sum::<f64>() / quote_ages_seconds.len() as f64),
+            max_quote_age_seconds: quote_ages_seconds.iter().copied().reduce(f64::max),
+            options,
+        })
+    }
+}
+
+impl OptionResidual {
+    fn calculate(graph: &SmileGraph, option: &OptionInstrument) -> Result<OptionResidual, TsError> {
+        let implied_volatility = option.get_implied_volatility()?;
+        let fitted_implied_volatility = graph.get_implied_volatility_at_strike(option.strike)?;
+
+        // A quote that can't be turned into an implied volatility doesn't bound the fit.
+        let bid_implied_volatility = option
+            .bid_price
+            .and_then(|x| option.get_implied_volatility_at_price(x).ok());
+        let ask_implied_volatility = option
+            .ask_price
+            .and_then(|x| option.get_implied_volatility_at_price(x).ok());
+
+        Ok(OptionResidual {
+            instrument_id: option.instrument_id.clone(),
+            strike: option.strike,
+            implied_volatility,
+            fitted_implied_volatility,
+            residual_vol_points: 100.0 * (fitted_implied_volatility - implied_volatility),
+            bid_implied_volatility,
+            ask_implied_volatility,
+            outside_bid_ask: bid_implied_volatility.is_some_and(|x| fitted_implied_volatility < x)
+                || ask_implied_volatility.is_some_and(|x| fitted_implied_volatility > x),
+        })
+    }
+}
@@ -0,0 +1,66 @@
+use chrono::{DateTime, Utc};
+
+use crate::helpers::error_unless_positive_f64;
+use crate::types::TsError;
+
+/// Only the most recent fits are used for predictions, so that old timings (e.g. from slower machines or older versions)
+/// age out.
+const FIT_TIME_HISTORY_LENGTH: usize = 1000;
+
+/// The number of the most similar past fits a prediction is averaged over.
+const FIT_TIME_NEIGHBOURS: usize = 5;
+
+/// How long a smile took to fit, recorded after each fit so that later builds can predict how long each smile will take.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct FitTimeRecord {
+    pub recorded_at: DateTime<Utc>,
+    pub days_until_expiry: f64,
+    /// The number of options the smile was fit to, which the time taken is roughly proportional to.
+    pub quote_count: usize,
+    pub seconds: f64,
+}
+
+/// Predicts how long a smile will take to fit from how long similar smiles (by tenor and quote count) have taken before.
+pub struct FitTimeModel {
+    records: Vec<FitTimeRecord>,
+}
+
+impl FitTimeModel {
+    /// Build a model from past fits, which must be ordered from oldest to newest.
+    pub fn new(mut records: Vec<FitTimeRecord>) -> Self {
+        records.drain(..records.len().saturating_sub(FIT_TIME_HISTORY_LENGTH));
+        records.retain(|x| x.quote_count > 0 && x.seconds.is_finite() && x.seconds >= 0.0 && x.days_until_expiry.is_finite());
+
+        Self { records }
+    }
+
+    /// Predict how many seconds a smile with the given tenor and number of options will take to fit, or None if nothing has
+    /// been fit before. The time per option of the most similar past fits is averaged, with similarity measured on log
+    /// scales so that 1 and 2 days apart counts for as much as 100 and 200.
+    pub fn predict_seconds(&self, days_until_expiry: f64, quote_count: usize) -> Result<Option<f64>, TsError> {
+        error_unless_positive_f64(days_until_expiry, "days_until_expiry")?;
+        error_unless_positive_f64(quote_count as f64, "quote_count")?;
+
+        if self.records.is_empty() {
+            return Ok(None);
+        }
+
+        let distance = |record: &FitTimeRecord| {
+            (record.days_until_expiry.max(0.0).ln_1p() - days_until_expiry.ln_1p()).abs()
+                + ((record.quote_count as f64).ln() - (quote_count as f64).ln()).abs()
+        };
+
+        // Newest first, so that the newest of equally similar fits are used.
+        let mut records = self.records.iter().rev().collect::<Vec<&FitTimeRecord>>();
+        records.sort_by(|a, b| distance(a).total_cmp(&distance(b)));
+        records.truncate(FIT_TIME_NEIGHBOURS);
+
+        let seconds_per_quote = records
+            .iter()
+            .map(|x| x.seconds / x.quote_count as f64)
+            .sum::<f64>()
+            / records.len() as f64;
+
+        Ok(Some(seconds_per_quote * quote_count as f64))
+    }
+}
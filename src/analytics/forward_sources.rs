@@ -0,0 +1,207 @@
+use std::f64::consts::E;
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    analytics::{OptionInstrument, OptionType, PricingModel},
+    constants,
+    helpers::error_unless_positive_f64,
+    types::{
+        TsError,
+        TsErrorType::{RuntimeError, UnsolvableError},
+    },
+};
+
+/// How many of the strikes closest to the money are used to find the forward price from put-call parity. The median of
+/// their forward prices is used, so that one bad quote can't move it much.
+const PUT_CALL_PARITY_STRIKES: usize = 3;
+
+/// A way of finding the forward price of the underlying at an expiry.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardSource {
+    /// The price of the future that expires at the same time.
+    Futures,
+    /// From the difference between call and put prices at the strikes closest to the money.
+    PutCallParity,
+    /// The perpetual future's price, carried forward at its current funding rate.
+    PerpetualFunding,
+    /// The spot price carried forward at the interest free rate. Always available.
+    SpotCarry,
+}
+
+/// Which sources the forward price is found from, and in what order they are tried.
+#[derive(serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardSourceSettings {
+    /// The first source in this list that is available and not stale is used.
+    pub priority: Vec<ForwardSource>,
+    /// A quote taken more than this many seconds away from the expiry's option quotes is stale.
+    pub max_quote_age_seconds: u64,
+}
+
+impl Default for ForwardSourceSettings {
+    fn default() -> Self {
+        Self {
+            priority: vec![
+                ForwardSource::Futures,
+                ForwardSource::PutCallParity,
+                ForwardSource::PerpetualFunding,
+                ForwardSource::SpotCarry,
+            ],
+            max_quote_age_seconds: 300,
+        }
+    }
+}
+
+impl ForwardSourceSettings {
+    pub fn check_valid(&self) -> Result<(), TsError> {
+        if self.priority.is_empty() {
+            return Err(TsError::new(RuntimeError, "At least one forward source must be given"));
+        }
+
+        for (i, source) in self.priority.iter().enumerate() {
+            if self.priority[..i].contains(source) {
+                return Err(TsError::new(RuntimeError, format!("Forward source {source:?} is given more than once")));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A price and when it was quoted.
+#[derive(Clone, Copy)]
+pub struct ForwardQuote {
+    pub price: f64,
+    pub time: DateTime<Utc>,
+}
+
+/// A quote for the perpetual future.
+#[derive(Clone, Copy)]
+pub struct PerpetualQuote {
+    pub price: f64,
+    /// The current funding rate, as a fraction per year.
+    pub annual_funding_rate: f64,
+    pub time: DateTime<Utc>,
+}
+
+/// The market data the forward price at one expiry can be found from.
+pub struct ForwardInputs<'a> {
+    /// The options at the expiry.
+    pub options: &'a [OptionInstrument],
+    /// When the options were quoted. Other quotes too far from this are stale. If not known, no quote is stale.
+    pub quote_time: Option<DateTime<Utc>>,
+    /// The future expiring at the same time, if there is one.
+    pub futures: Option<ForwardQuote>,
+    pub perpetual: Option<PerpetualQuote>,
+}
+
+/// Where an expiry's forward price came from.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct ForwardProvenance {
+    pub source: ForwardSource,
+    pub forward_price: f64,
+    /// Why each source tried before the one used couldn't be used.
+    pub skipped_sources: Vec<String>,
+}
+
+/// Find the forward price at an expiry from the first source in the settings' priority list that is available and not
+/// stale, recording why any sources before it were skipped.
+pub fn determine_forward_price(inputs: &ForwardInputs, settings: &ForwardSourceSettings) -> Result<ForwardProvenance, TsError> {
+    let mut skipped_sources = Vec::new();
+
+    for source in &settings.priority {
+        match get_forward_price(*source, inputs, settings.max_quote_age_seconds) {
+            Ok(forward_price) => {
+                return Ok(ForwardProvenance {
+                    source: *source,
+                    forward_price,
+                    skipped_sources,
+                });
+            }
+            Err(e) => skipped_sources.push(format!("{source:?}: {}", e.reason)),
+        }
+    }
+
+    Err(TsError::new(
+        UnsolvableError,
+        format!("No forward source could be used ({})", skipped_sources.join(", ")),
+    ))
+}
+
+fn get_forward_price(source: ForwardSource, inputs: &ForwardInputs, max_quote_age_seconds: u64) -> Result<f64, TsError> {
+    let first_option = inputs
+        .options
+        .first()
+        .ok_or(TsError::new(RuntimeError, "There are no options at this expiry"))?;
+    let years_until_expiry = first_option.get_years_until_expiry()?;
+    let check_fresh = |time: DateTime<Utc>| match inputs.quote_time {
+        Some(quote_time) if (time - quote_time).num_seconds().unsigned_abs() > max_quote_age_seconds => Err(TsError::new(
+            UnsolvableError,
+            format!("The quote is {} seconds away from the options", (time - quote_time).num_seconds()),
+        )),
+        _ => Ok(()),
+    };
+
+    let forward_price = match source {
+        ForwardSource::Futures => {
+            let quote = inputs
+                .futures
+                .ok_or(TsError::new(UnsolvableError, "No future expires at the same time"))?;
+            check_fresh(quote.time)?;
+            quote.price
+        }
+        ForwardSource::PutCallParity => find_put_call_parity_forward_price(inputs.options, years_until_expiry)?,
+        ForwardSource::PerpetualFunding => {
+            let quote = inputs
+                .perpetual
+                .ok_or(TsError::new(UnsolvableError, "There is no perpetual quote"))?;
+            check_fresh(quote.time)?;
+            quote.price * E.powf(quote.annual_funding_rate * years_until_expiry)
+        }
+        ForwardSource::SpotCarry => first_option.spot_price * E.powf(constants::INTEREST_FREE_RATE * years_until_expiry),
+    };
+
+    error_unless_positive_f64(forward_price, "forward_price")?;
+
+    Ok(forward_price)
+}
+
+/// Put-call parity says that a call minus a put at the same strike is worth the discounted forward price minus the
+/// discounted strike, or for inverse options, one coin minus the strike as a fraction of the forward price. Each strike
+/// with both a call and a put gives a forward price this way, and those closest to the money (where the call and put
+/// prices are closest) are the most reliable.
+fn find_put_call_parity_forward_price(options: &[OptionInstrument], years_until_expiry: f64) -> Result<f64, TsError> {
+    let mut estimates: Vec<(f64, f64)> = options
+        .iter()
+        .filter(|x| x.option_type == OptionType::Call)
+        .filter_map(|call| {
+            options
+                .iter()
+                .find(|x| x.option_type == OptionType::Put && x.strike == call.strike)
+                .map(|put| {
+                    let difference = call.price - put.price;
+                    let forward_price = match call.pricing_model {
+                        PricingModel::BlackScholes => {
+                            call.strike + difference * E.powf(constants::INTEREST_FREE_RATE * years_until_expiry)
+                        }
+                        // USD prices of inverse options are their coin prices at the spot price.
+                        PricingModel::Inverse => call.strike / (1.0 - difference / call.spot_price),
+                    };
+
+                    (difference.abs(), forward_price)
+                })
+        })
+        .collect();
+
+    if estimates.is_empty() {
+        return Err(TsError::new(UnsolvableError, "No strike has both a call and a put"));
+    }
+
+    estimates.sort_by(|a, b| a.0.total_cmp(&b.0));
+    estimates.truncate(PUT_CALL_PARITY_STRIKES);
+    estimates.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    Ok(estimates[estimates.len() / 2].1)
+}
@@ -0,0 +1,513 @@
+use std::f64::consts::{E, PI};
+
+use chrono::{DateTime, Utc};
+use levenberg_marquardt::{LeastSquaresProblem, LevenbergMarquardt};
+use nalgebra::{Complex, Dyn, Matrix, OMatrix, Owned, U1, U5, Vector5};
+
+use crate::{
+    analytics::{
+        OptionType, SmileGraph,
+        math::{
+            calculate_black_scholes, calculate_black_scholes_vega, calculate_bs_implied_volatility, saturating_exp, saturating_ln,
+        },
+    },
+    constants,
+    helpers::{error_unless_positive_f64, error_unless_valid_f64},
+    types::{
+        TsError,
+        TsErrorType::{RuntimeError, UnsolvableError},
+    },
+};
+
+/// The number of terms in the cosine expansion when pricing. 256 is accurate to well under a cent for typical Bitcoin
+/// options.
+const COS_TERMS: usize = 256;
+
+/// How many standard deviations of the log price either side of its mean the cosine expansion covers. This is wider than
+/// the 10 suggested by Fang and Oosterlee, since negative rho gives the log price a long left tail.
+const COS_TRUNCATION_WIDTH: f64 = 16.0;
+
+/// The number of evenly spaced strikes read off each fitted smile to calibrate against.
+const CALIBRATION_STRIKES_PER_SMILE: u64 = 15;
+
+/// How far from the money (in at the money standard deviations of the log price) calibration strikes can be. Further out,
+/// prices are too small for vega weighted errors to mean much, and the smile is mostly extrapolation anyway.
+const CALIBRATION_MAX_STANDARD_DEVIATIONS: f64 = 3.0;
+
+/// The correlations the calibration starts from. The best result is kept.
+const CALIBRATION_STARTING_RHOS: [f64; 3] = [-0.5, 0.0, 0.5];
+
+/// The step used to estimate the Jacobian by finite differences.
+const JACOBIAN_STEP: f64 = 0.000001;
+
+/// How far the logs of kappa, theta, sigma and v0 can get from zero while calibrating (see saturating_exp()). This allows
+/// far more than any real market needs, but stops a long optimiser step making them overflow.
+const MAX_ABSOLUTE_LOG_PARAMETER: f64 = 10.0;
+
+/// The most extreme correlation the calibration can reach. tanh rounds to exactly 1 for large arguments, which isn't valid.
+const MAX_ABSOLUTE_RHO: f64 = 0.999;
+
+/// The parameters of the Heston stochastic volatility model, in which variance mean-reverts and is correlated with spot.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize)]
+pub struct HestonParameters {
+    /// How quickly variance reverts to theta.
+    pub kappa: f64,
+    /// The long run variance.
+    pub theta: f64,
+    /// The volatility of variance ("vol of vol").
+    pub sigma: f64,
+    /// The correlation between spot and variance.
+    pub rho: f64,
+    /// The variance now.
+    pub v0: f64,
+}
+
+impl HestonParameters {
+    pub fn new_from_values(kappa: f64, theta: f64, sigma: f64, rho: f64, v0: f64) -> Result<Self, TsError> {
+        error_unless_positive_f64(kappa, "kappa")?;
+        error_unless_positive_f64(theta, "theta")?;
+        error_unless_positive_f64(sigma, "sigma")?;
+        error_unless_positive_f64(v0, "v0")?;
+        error_unless_valid_f64(rho, "rho")?;
+
+        if rho.abs() >= 1.0 {
+            return Err(TsError::new(UnsolvableError, format!("rho must be between -1 and 1, found {rho}")));
+        }
+
+        Ok(Self {
+            kappa,
+            theta,
+            sigma,
+            rho,
+            v0,
+        })
+    }
+
+    /// The Feller condition (2κθ > σ²) means variance can never reach zero. The model still works without it, but it's a
+    /// sign that the parameters might not be realistic.
+    pub fn satisfies_feller_condition(&self) -> bool {
+        2.0 * self.kappa * self.theta > self.sigma * self.sigma
+    }
+
+    /// The characteristic function of the log return ln(S_T/S_0), using the formulation from Albrecher et al ("The little
+    /// Heston trap") which avoids discontinuities in the complex logarithm.
+    fn characteristic_function(&self, u: f64, years_until_expiry: f64, risk_free_interest_rate: f64) -> Complex<f64> {
+        let i = Complex::new(0.0, 1.0);
+        let u = Complex::new(u, 0.0);
+        let sigma_squared = self.sigma * self.sigma;
+
+        let beta = self.kappa - i * u * self.rho * self.sigma;
+        let d = (beta * beta + sigma_squared * (i * u + u * u)).sqrt();
+        let g = (beta - d) / (beta + d);
+        let decay = (-d * years_until_expiry).exp();
+
+        let c = i * u * risk_free_interest_rate * years_until_expiry
+            + (self.kappa * self.theta / sigma_squared)
+                * ((beta - d) * years_until_expiry - 2.0 * ((1.0 - g * decay) / (1.0 - g)).ln());
+        let d_term = ((beta - d) / sigma_squared) * ((1.0 - decay) / (1.0 - g * decay));
+
+        (c + d_term * self.v0).exp()
+    }
+
+    /// The first two cumulants (mean and variance) of ln(S_T/S_0), used to choose the range the cosine expansion covers.
+    /// See Fang and Oosterlee (2008).
+    fn get_cumulants(&self, years_until_expiry: f64, risk_free_interest_rate: f64) -> (f64, f64) {
+        let (kappa, theta, sigma, rho, v0) = (self.kappa, self.theta, self.sigma, self.rho, self.v0);
+        let t = years_until_expiry;
+        let decay = (-kappa * t).exp();
+
+        let c1 = risk_free_interest_rate * t + (1.0 - decay) * (theta - v0) / (2.0 * kappa) - 0.5 * theta * t;
+        let c2 = (sigma * t * kappa * decay * (v0 - theta) * (8.0 * kappa * rho - 4.0 * sigma)
+            + kappa * rho * sigma * (1.0 - decay) * (16.0 * theta - 8.0 * v0)
+            + 2.0 * theta * kappa * t * (-4.0 * kappa * rho * sigma + sigma * sigma + 4.0 * kappa * kappa)
+            + sigma * sigma * ((theta - 2.0 * v0) * decay * decay + theta * (6.0 * decay - 7.0) + 2.0 * v0)
+            + 8.0 * kappa * kappa * (v0 - theta) * (1.0 - decay))
+            / (8.0 * kappa * kappa * kappa);
+
+        (c1, c2)
+    }
+}
+
+/// Price a European option under the Heston model using the COS method (Fang and Oosterlee, 2008), which expands the
+/// density of the log price as a cosine series. Puts are priced directly, since their payoff is bounded, and calls by
+/// put-call parity.
+///
+/// # Arguments
+///
+/// * `params` - The Heston model parameters.
+/// * `asset_spot_price` - The current spot price of the underlying asset.
+/// * `strike_price` - The strike price of the option.
+/// * `years_until_expiry` - Years until the option expires (365 day year).
+/// * `risk_free_interest_rate` - The continuously-compounded risk-free interest rate from now until expiry. Annualised.
+/// * `option_type` - The type of the option.
+pub fn calculate_heston_price(
+    params: &HestonParameters,
+    asset_spot_price: f64,
+    strike_price: f64,
+    years_until_expiry: f64,
+    risk_free_interest_rate: f64,
+    option_type: OptionType,
+) -> Result<f64, TsError> {
+    error_unless_positive_f64(asset_spot_price, "asset_spot_price")?;
+    error_unless_positive_f64(strike_price, "strike_price")?;
+    error_unless_positive_f64(years_until_expiry, "years_until_expiry")?;
+    error_unless_valid_f64(risk_free_interest_rate, "risk_free_interest_rate")?;
+
+    // x is where we are now and y (the variable the density is of) is ln(S_T/K).
+    let x = (asset_spot_price / strike_price).ln();
+    let (c1, c2) = params.get_cumulants(years_until_expiry, risk_free_interest_rate);
+    let half_width = COS_TRUNCATION_WIDTH * c2.abs().sqrt();
+    let a = x + c1 - half_width;
+    let b = x + c1 + half_width;
+
+    // The put only pays out when y < 0.
+    let payoff_end = b.min(0.0);
+    let mut put_price = 0.0;
+
+    if a < payoff_end {
+        for k in 0..COS_TERMS {
+            let u = k as f64 * PI / (b - a);
+            let coefficient = 2.0 / (b - a) * (psi(k, u, a, a, payoff_end) - chi(u, a, a, payoff_end));
+            let term = (params.characteristic_function(u, years_until_expiry, risk_free_interest_rate)
+                * Complex::new(0.0, u * (x - a)).exp())
+            .re * coefficient;
+
+            // The first term of the series is halved.
+            put_price += match k {
+                0 => 0.5 * term,
+                _ => term,
+            };
+        }
+    }
+
+    let discounted_strike = strike_price * E.powf(-risk_free_interest_rate * years_until_expiry);
+    let put_price = (discounted_strike * put_price).max(0.0);
+
+    let price = match option_type {
+        OptionType::Put => put_price,
+        OptionType::Call => put_price + asset_spot_price - discounted_strike,
+    };
+
+    error_unless_valid_f64(price, "price")?;
+
+    Ok(price.max(0.0))
+}
+
+/// The cosine series coefficient of e^y over [c, d].
+fn chi(u: f64, a: f64, c: f64, d: f64) -> f64 {
+    (1.0 / (1.0 + u * u))
+        * ((u * (d - a)).cos() * d.exp() - (u * (c - a)).cos() * c.exp() + u * (u * (d - a)).sin() * d.exp()
+            - u * (u * (c - a)).sin() * c.exp())
+}
+
+/// The cosine series coefficient of 1 over [c, d].
+fn psi(k: usize, u: f64, a: f64, c: f64, d: f64) -> f64 {
+    match k {
+        0 => d - c,
+        _ => ((u * (d - a)).sin() - (u * (c - a)).sin()) / u,
+    }
+}
+
+/// The Black-Scholes implied volatility of the out of the money option at the given strike under the Heston model.
+pub fn calculate_heston_implied_volatility(
+    params: &HestonParameters,
+    asset_spot_price: f64,
+    strike_price: f64,
+    years_until_expiry: f64,
+    risk_free_interest_rate: f64,
+) -> Result<f64, TsError> {
+    let option_type = get_out_of_the_money_type(asset_spot_price, strike_price, years_until_expiry, risk_free_interest_rate);
+    let price =
+        calculate_heston_price(params, asset_spot_price, strike_price, years_until_expiry, risk_free_interest_rate, option_type)?;
+
+    calculate_bs_implied_volatility(
+        asset_spot_price,
+        strike_price,
+        years_until_expiry,
+        risk_free_interest_rate,
+        0.0,
+        price,
+        option_type,
+    )
+}
+
+fn get_out_of_the_money_type(
+    asset_spot_price: f64,
+    strike_price: f64,
+    years_until_expiry: f64,
+    risk_free_interest_rate: f64,
+) -> OptionType {
+    match strike_price < asset_spot_price * E.powf(risk_free_interest_rate * years_until_expiry) {
+        true => OptionType::Put,
+        false => OptionType::Call,
+    }
+}
+
+/// How well the calibrated model matches one smile.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct HestonSmileFit {
+    pub expiry: DateTime<Utc>,
+    /// The root mean squared difference in implied volatility between the model and the fitted smile.
+    pub rmse: f64,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct HestonCalibration {
+    pub calibrated_at: DateTime<Utc>,
+    pub parameters: HestonParameters,
+    /// The root mean squared difference in implied volatility between the model and the fitted smiles, over every expiry.
+    pub rmse: f64,
+    pub smiles: Vec<HestonSmileFit>,
+}
+
+/// A point on the surface to calibrate against.
+struct CalibrationTarget {
+    smile_index: usize,
+    spot_price: f64,
+    strike: f64,
+    years_until_expiry: f64,
+    implied_volatility: f64,
+    option_type: OptionType,
+    price: f64,
+    vega: f64,
+}
+
+/// Calibrate the Heston model to every given smile at once, to see how well a single stochastic volatility model explains
+/// the whole surface. The model is fit to strikes evenly spaced in log moneyness near the money on each fitted smile rather than
+/// to the raw quotes, so that every expiry counts equally and noise in individual quotes has already been smoothed out.
+///
+/// Residuals are price differences divided by vega, which are close to implied volatility differences but much cheaper
+/// and smoother to calculate. The parameters are optimised in a transformed space (limited logs for the positive parameters
+/// and atanh for rho) so that the optimiser can never produce invalid parameters. A starting point whose calibration
+/// reaches parameters the pricer still can't handle is given up on.
+pub fn calibrate_heston(smile_graphs: &[SmileGraph]) -> Result<HestonCalibration, TsError> {
+    let targets = build_calibration_targets(smile_graphs)?;
+    let first_variance = get_atm_variance(smile_graphs.first())?;
+    let last_variance = get_atm_variance(smile_graphs.last())?;
+    let mut best: Option<(HestonParameters, f64)> = None;
+
+    for rho in CALIBRATION_STARTING_RHOS {
+        let initial_params = HestonParameters::new_from_values(2.0, last_variance, 1.0, rho, first_variance)?;
+        let mut problem = HestonProblem {
+            x: to_unconstrained(&initial_params),
+            targets: &targets,
+            residuals: None,
+        };
+
+        let initial_x = problem.x;
+        problem.set_params(&initial_x);
+
+        let (result, report) = LevenbergMarquardt::new()
+            .with_patience(100)
+            .minimize(problem);
+
+        if !report.termination.was_successful() || result.residuals.is_none() {
+            continue;
+        }
+
+        let params = from_unconstrained(&result.x)?;
+        let loss = report.objective_function.abs();
+
+        if best.as_ref().is_none_or(|(_, best_loss)| loss < *best_loss) {
+            best = Some((params, loss));
+        }
+    }
+
+    let (parameters, _) = best.ok_or(TsError::new(UnsolvableError, "Heston calibration failed from every starting point"))?;
+
+    measure_calibration(smile_graphs, &targets, parameters)
+}
+
+fn get_atm_variance(graph: Option<&SmileGraph>) -> Result<f64, TsError> {
+    let graph = graph.ok_or(TsError::new(RuntimeError, "There are no smiles to calibrate to"))?;
+
+    Ok(graph.get_total_variance_at_log_moneyness(0.0)? / graph.get_years_until_expiry()?)
+}
+
+fn build_calibration_targets(smile_graphs: &[SmileGraph]) -> Result<Vec<CalibrationTarget>, TsError> {
+    let rate = constants::INTEREST_FREE_RATE;
+    let mut targets = Vec::new();
+
+    for (smile_index, graph) in smile_graphs.iter().enumerate() {
+        let years_until_expiry = graph.get_years_until_expiry()?;
+        let forward_price = graph.get_underlying_forward_price()?;
+        let spot_price = forward_price * E.powf(-rate * years_until_expiry);
+        let max_log_moneyness = CALIBRATION_MAX_STANDARD_DEVIATIONS * graph.get_total_variance_at_log_moneyness(0.0)?.sqrt();
+        let lowest_log_moneyness = (graph.lowest_observed_strike / forward_price)
+            .ln()
+            .max(-max_log_moneyness);
+        let highest_log_moneyness = (graph.highest_observed_strike / forward_price)
+            .ln()
+            .min(max_log_moneyness);
+        let log_moneyness_range = highest_log_moneyness - lowest_log_moneyness;
+
+        for i in 0..CALIBRATION_STRIKES_PER_SMILE {
+            let log_moneyness =
+                lowest_log_moneyness + log_moneyness_range * (i as f64 / (CALIBRATION_STRIKES_PER_SMILE - 1) as f64);
+            let strike = forward_price * log_moneyness.exp();
+            let implied_volatility = graph.get_implied_volatility_at_strike(strike)?;
+            let option_type = get_out_of_the_money_type(spot_price, strike, years_until_expiry, rate);
+
+            targets.push(CalibrationTarget {
+                smile_index,
+                spot_price,
+                strike,
+                years_until_expiry,
+                implied_volatility,
+                option_type,
+                price: calculate_black_scholes(
+                    spot_price,
+                    strike,
+                    years_until_expiry,
+                    rate,
+                    0.0,
+                    implied_volatility,
+                    option_type,
+                )?,
+                vega: calculate_black_scholes_vega(spot_price, strike, years_until_expiry, rate, 0.0, implied_volatility)?,
+            });
+        }
+    }
+
+    Ok(targets)
+}
+
+/// Work out the implied volatility errors of the calibrated parameters, overall and for each smile.
+fn measure_calibration(
+    smile_graphs: &[SmileGraph],
+    targets: &[CalibrationTarget],
+    parameters: HestonParameters,
+) -> Result<HestonCalibration, TsError> {
+    let mut squared_errors = vec![(0.0, 0); smile_graphs.len()];
+
+    let residuals = calculate_residuals(targets, &to_unconstrained(&parameters))
+        .ok_or(TsError::new(UnsolvableError, "Calibrated Heston parameters can't price every target"))?;
+
+    for (target, residual) in targets.iter().zip(residuals) {
+        // Far from the money the Heston price can be too small to solve for implied volatility, in which case the vega
+        // weighted price error is used instead, which is the same to first order.
+        let error = calculate_heston_implied_volatility(
+            &parameters,
+            target.spot_price,
+            target.strike,
+            target.years_until_expiry,
+            constants::INTEREST_FREE_RATE,
+        )
+        .map(|x| x - target.implied_volatility)
+        .unwrap_or(residual);
+        let (sum, count) = &mut squared_errors[target.smile_index];
+
+        *sum += error * error;
+        *count += 1;
+    }
+
+    let total_squared_error: f64 = squared_errors.iter().map(|x| x.0).sum();
+    let smiles = smile_graphs
+        .iter()
+        .zip(&squared_errors)
+        .map(|(graph, (sum, count))| {
+            Ok(HestonSmileFit {
+                expiry: graph.get_expiration()?,
+                rmse: (sum / *count as f64).sqrt(),
+            })
+        })
+        .collect::<Result<Vec<_>, TsError>>()?;
+
+    Ok(HestonCalibration {
+        calibrated_at: crate::helpers::get_now(),
+        parameters,
+        rmse: (total_squared_error / targets.len() as f64).sqrt(),
+        smiles,
+    })
+}
+
+fn to_unconstrained(params: &HestonParameters) -> Vector5<f64> {
+    Vector5::new(
+        saturating_ln(params.kappa, MAX_ABSOLUTE_LOG_PARAMETER),
+        saturating_ln(params.theta, MAX_ABSOLUTE_LOG_PARAMETER),
+        saturating_ln(params.sigma, MAX_ABSOLUTE_LOG_PARAMETER),
+        (params.rho / MAX_ABSOLUTE_RHO)
+            .clamp(-1.0 + f64::EPSILON, 1.0 - f64::EPSILON)
+            .atanh(),
+        saturating_ln(params.v0, MAX_ABSOLUTE_LOG_PARAMETER),
+    )
+}
+
+fn from_unconstrained(x: &Vector5<f64>) -> Result<HestonParameters, TsError> {
+    HestonParameters::new_from_values(
+        saturating_exp(x[0], MAX_ABSOLUTE_LOG_PARAMETER),
+        saturating_exp(x[1], MAX_ABSOLUTE_LOG_PARAMETER),
+        saturating_exp(x[2], MAX_ABSOLUTE_LOG_PARAMETER),
+        MAX_ABSOLUTE_RHO * x[3].tanh(),
+        saturating_exp(x[4], MAX_ABSOLUTE_LOG_PARAMETER),
+    )
+}
+
+/// Returns the vega-weighted price error at each target, or None if the parameters can't price every target.
+fn calculate_residuals(targets: &[CalibrationTarget], x: &Vector5<f64>) -> Option<Vec<f64>> {
+    let params = from_unconstrained(x).ok()?;
+
+    targets
+        .iter()
+        .map(|target| {
+            let price = calculate_heston_price(
+                &params,
+                target.spot_price,
+                target.strike,
+                target.years_until_expiry,
+                constants::INTEREST_FREE_RATE,
+                target.option_type,
+            )
+            .ok()?;
+
+            Some((price - target.price) / target.vega)
+        })
+        .collect()
+}
+
+struct HestonProblem<'a> {
+    /// The parameters in the optimiser's unconstrained space.
+    x: Vector5<f64>,
+    targets: &'a [CalibrationTarget],
+    residuals: Option<Vec<f64>>,
+}
+
+impl LeastSquaresProblem<f64, Dyn, U5> for HestonProblem<'_> {
+    type ParameterStorage = Owned<f64, U5>;
+    type ResidualStorage = Owned<f64, Dyn>;
+    type JacobianStorage = Owned<f64, Dyn, U5>;
+
+    fn set_params(&mut self, x: &Vector5<f64>) {
+        self.x.copy_from(x);
+        self.residuals = calculate_residuals(self.targets, &self.x);
+    }
+
+    fn params(&self) -> Vector5<f64> {
+        self.x
+    }
+
+    fn residuals(&self) -> Option<Matrix<f64, Dyn, U1, Self::ResidualStorage>> {
+        // Parameters the pricer can't handle stop the calibration from this starting point, rather than giving the optimiser
+        // a made-up loss.
+        Some(OMatrix::<f64, Dyn, U1>::from_row_slice(self.residuals.as_ref()?))
+    }
+
+    fn jacobian(&self) -> Option<Matrix<f64, Dyn, U5, Self::JacobianStorage>> {
+        let residuals = self.residuals.as_ref()?;
+        let mut jacobian = OMatrix::<f64, Dyn, U5>::zeros(self.targets.len());
+
+        // There's no neat closed form for the derivatives of the COS price, so use forward differences.
+        for column in 0..5 {
+            let mut x = self.x;
+            x[column] += JACOBIAN_STEP;
+
+            let stepped_residuals = calculate_residuals(self.targets, &x)?;
+
+            for (row, (stepped, residual)) in stepped_residuals.iter().zip(residuals).enumerate() {
+                jacobian[(row, column)] = (stepped - residual) / JACOBIAN_STEP;
+            }
+        }
+
+        Some(jacobian)
+    }
+}
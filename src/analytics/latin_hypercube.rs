@@ -0,0 +1,22 @@
+use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
+
+/// Sample count points within the given (start, end) bounds of each parameter, so that each parameter's range split into
+/// count equal strata has exactly one point in each stratum. This covers every parameter's range evenly with far fewer
+/// points than a grid, and unlike a grid, no two points share a value of any parameter. Samples with the same seed are the
+/// same.
+pub fn latin_hypercube_sample<const N: usize>(bounds: &[(f64, f64); N], count: usize, seed: u64) -> Vec<[f64; N]> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut points = vec![[0.0; N]; count];
+
+    for (parameter, (start, end)) in bounds.iter().enumerate() {
+        let mut strata: Vec<usize> = (0..count).collect();
+        strata.shuffle(&mut rng);
+
+        for (point, stratum) in points.iter_mut().zip(strata) {
+            let position = (stratum as f64 + rng.random::<f64>()) / count as f64;
+            point[parameter] = start + (end - start) * position;
+        }
+    }
+
+    points
+}
@@ -1,5 +1,6 @@
 use crate::analytics::OptionType;
 use crate::analytics::types::SVICurveParameters;
+use crate::analytics::types::{ButterflyCheckSettings, ExerciseStyle, OptionGreeks, TreeSettings, TreeType};
 use crate::constants;
 use crate::helpers::error_unless_positive_f64;
 use crate::helpers::error_unless_valid_f64;
@@ -7,7 +8,7 @@ use crate::types::TsError;
 use crate::types::TsErrorType::UnsolvableError;
 use std::f64::consts::E;
 
-/// Calculate the Black-Scholes implied volatility of a dividendless option.
+/// Calculate the Black-Scholes implied volatility of an option.
 ///
 /// # Arguments
 ///
@@ -16,6 +17,8 @@ use std::f64::consts::E;
 /// * `years_until_expiry` - Years until the option expires (365 day year).
 /// * `risk_free_interest_rate` - The continuously-compounded risk-free interest rate from now until expiry. Annualised. For
 ///   example, 5% per annum is 0.05. Must use a 365 day year.
+/// * `dividend_yield` - The continuously-compounded yield paid by the underlying asset from now until expiry, e.g.
+///   dividends, or lending or staking yield. Annualised. Use 0 for none. Must use a 365 day year.
 /// * `option_price` - Current price of the option.
 /// * `option_type` - The type of the option.
 pub fn calculate_bs_implied_volatility(
@@ -23,6 +26,7 @@ pub fn calculate_bs_implied_volatility(
     strike_price: f64,
     years_until_expiry: f64,
     risk_free_interest_rate: f64,
+    dividend_yield: f64,
     option_price: f64,
     option_type: OptionType,
 ) -> Result<f64, TsError> {
@@ -30,41 +34,43 @@ pub fn calculate_bs_implied_volatility(
     error_unless_positive_f64(strike_price, "strike_price")?;
     error_unless_positive_f64(years_until_expiry, "years_until_expiry")?;
     error_unless_valid_f64(risk_free_interest_rate, "risk_free_interest_rate")?;
+    error_unless_valid_f64(dividend_yield, "dividend_yield")?;
     error_unless_positive_f64(option_price, "option_price")?;
 
-    // We'll use a simple bracketed solver to do this. Basically, we're gonna keep guessing until we get it right.
-    // There are faster methods, like using the Newton method etc, but this is fine for now. Newton also doesn't work
-    // well in some situations.
+    // We use Newton's method, which usually gets there in a few steps from a good first guess, and fall back to bisecting
+    // a bracket around the answer whenever a step would leave it (e.g. far from the money, where vega is tiny).
 
     // First check for sane bounds. If any of these are violated, then it's impossible to solve the implied volatility.
 
     // This is equal to the amount of cash you would need now in order to have the strike price at expiry (by taking into
     // account the risk-free rate).
     let strike_value_now = strike_price * E.powf((-risk_free_interest_rate) * years_until_expiry);
+    // Likewise, this is what the asset received at expiry is worth now, since we miss out on its yield until then.
+    let asset_value_now = asset_spot_price * E.powf(-dividend_yield * years_until_expiry);
 
     match option_type {
         OptionType::Call => {
-            if option_price < asset_spot_price - strike_value_now {
+            if option_price < asset_value_now - strike_value_now {
                 return Err(TsError::new(
                     UnsolvableError,
                     format!(
-                        "Call option price mathematically impossibly low ({option_price} < {asset_spot_price} - {strike_value_now}) (Is the data stale?)"
+                        "Call option price mathematically impossibly low ({option_price} < {asset_value_now} - {strike_value_now}) (Is the data stale?)"
                     ),
                 ));
             }
-            if option_price > asset_spot_price {
+            if option_price > asset_value_now {
                 return Err(TsError::new(
                     UnsolvableError,
-                    format!("Call option price too high ({option_price} > {asset_spot_price})"),
+                    format!("Call option price too high ({option_price} > {asset_value_now})"),
                 ));
             }
         }
         OptionType::Put => {
-            if option_price < strike_value_now - asset_spot_price {
+            if option_price < strike_value_now - asset_value_now {
                 return Err(TsError::new(
                     UnsolvableError,
                     format!(
-                        "Put option price mathematically impossibly low ({option_price} < {strike_value_now} - {asset_spot_price}) (Is the data stale?)"
+                        "Put option price mathematically impossibly low ({option_price} < {strike_value_now} - {asset_value_now}) (Is the data stale?)"
                     ),
                 ));
             }
@@ -77,32 +83,29 @@ pub fn calculate_bs_implied_volatility(
         }
     };
 
+    let price_at = |volatility: f64| {
+        calculate_black_scholes(
+            asset_spot_price,
+            strike_price,
+            years_until_expiry,
+            risk_free_interest_rate,
+            dividend_yield,
+            volatility,
+            option_type,
+        )
+    };
+
     // Define our bounds for the volatility. We'll use some sensible defaults.
     let mut bounds_start: f64 = 0.0;
     let mut bounds_end: f64 = 2.0;
 
-    // We've found the implied volatility when the BS calculation is equal to the actual option price. In reality, we can usually
-    // only approximate this, so we'll also accept a certain degree of error.
-
     // First we need to find the best starting value for the end bound. Option price increases with volatility, so we'll
     // keep increasing the volatility until the BS price exceeds or equals the actual price. Then we can be sure that the
     // correct volatility exists somewhere within our bounds.
     let mut iterations = 0;
 
-    loop {
-        let bs = calculate_black_scholes(
-            asset_spot_price,
-            strike_price,
-            years_until_expiry,
-            risk_free_interest_rate,
-            bounds_end,
-            option_type,
-        )?;
-
-        if bs >= option_price {
-            break;
-        }
-
+    while price_at(bounds_end)? < option_price {
+        bounds_start = bounds_end;
         bounds_end *= 2.0;
         iterations += 1;
 
@@ -112,71 +115,61 @@ pub fn calculate_bs_implied_volatility(
         }
     }
 
-    // So now the correct implied volatility is between bounds_start and bounds_end. Let's narrow it down.
-    let mut bounds_end_bs: f64;
-    let mut midpoint_bs: f64;
-    let mut midpoint: f64;
-    let mut range: f64;
-    iterations = 0;
+    let mut volatility =
+        get_implied_volatility_initial_guess(asset_value_now, strike_value_now, years_until_expiry, option_price, option_type);
 
-    range = bounds_end - bounds_start;
-    midpoint = (bounds_end + bounds_start) * 0.5;
+    if !(volatility > bounds_start && volatility < bounds_end) {
+        volatility = (bounds_start + bounds_end) * 0.5;
+    }
 
-    // Calculate BS for the end bound.
-    bounds_end_bs = calculate_black_scholes(
-        asset_spot_price,
-        strike_price,
-        years_until_expiry,
-        risk_free_interest_rate,
-        bounds_end,
-        option_type,
-    )?;
+    let mut previous_step = bounds_end - bounds_start;
+    iterations = 0;
 
     loop {
-        // Calculate BS for the midpoint (halfway between the start and end bounds).
-        midpoint_bs = calculate_black_scholes(
+        let price = price_at(volatility)?;
+
+        // Unlikely, but maybe we got it perfectly.
+        if price == option_price {
+            return Ok(volatility);
+        }
+
+        // Keep the bracket around the answer, so that we always have somewhere safe to fall back to.
+        if price > option_price {
+            bounds_end = volatility;
+        } else {
+            bounds_start = volatility;
+        }
+
+        let vega = calculate_black_scholes_vega(
             asset_spot_price,
             strike_price,
             years_until_expiry,
             risk_free_interest_rate,
-            midpoint,
-            option_type,
+            dividend_yield,
+            volatility,
         )?;
+        let newton_step = (price - option_price) / vega;
 
-        if range <= constants::IMPLIED_VOLATILITY_SOLVER_ACCURACY {
-            // We're very close. Return the midpoint.
-            return Ok(midpoint);
-        }
+        // Bisect instead if Newton would leave the bracket, or if it isn't getting there at least as quickly as bisecting
+        // would (which happens for absurdly cheap options, where the price is very flat and then very steep).
+        let next_volatility = match volatility - newton_step > bounds_start
+            && volatility - newton_step < bounds_end
+            && newton_step.abs() * 2.0 <= previous_step.abs()
+        {
+            true => volatility - newton_step,
+            false => (bounds_start + bounds_end) * 0.5,
+        };
 
-        // Unlikely, but maybe we got it perfectly.
-        if bounds_end_bs == option_price {
-            return Ok(bounds_end);
-        }
-        if midpoint_bs == option_price {
-            return Ok(midpoint);
-        }
+        previous_step = next_volatility - volatility;
 
-        if midpoint_bs > option_price {
-            // Midpoint was too high, so the answer is somewhere in the lower half.
-            bounds_end = midpoint;
-
-            // Recalculate BS for the end bound.
-            bounds_end_bs = calculate_black_scholes(
-                asset_spot_price,
-                strike_price,
-                years_until_expiry,
-                risk_free_interest_rate,
-                bounds_end,
-                option_type,
-            )?;
-        } else {
-            // Midpoint was too low, so the answer is somewhere in the top half.
-            bounds_start = midpoint;
+        // We're very close, either because the steps have become tiny or because the bracket has.
+        if (next_volatility - volatility).abs() <= constants::IMPLIED_VOLATILITY_SOLVER_ACCURACY
+            || bounds_end - bounds_start <= constants::IMPLIED_VOLATILITY_SOLVER_ACCURACY
+        {
+            return Ok(next_volatility);
         }
 
-        range = bounds_end - bounds_start;
-        midpoint = (bounds_end + bounds_start) * 0.5;
-
+        volatility = next_volatility;
         iterations += 1;
 
         // To be safe.
@@ -186,6 +179,38 @@ pub fn calculate_bs_implied_volatility(
     }
 }
 
+/// A first guess at the implied volatility for calculate_bs_implied_volatility() to improve on, from Corrado and
+/// Miller's closed form approximation (a rational function of the prices, which is accurate near the money). Where that
+/// breaks down (far from the money), the volatility at which vega is highest is used instead, from which Newton's method
+/// converges without overshooting.
+fn get_implied_volatility_initial_guess(
+    asset_value_now: f64,
+    strike_value_now: f64,
+    years_until_expiry: f64,
+    option_price: f64,
+    option_type: OptionType,
+) -> f64 {
+    // Approximate with the call of the same strike, by put-call parity.
+    let call_price = match option_type {
+        OptionType::Call => option_price,
+        OptionType::Put => option_price + asset_value_now - strike_value_now,
+    };
+    let intrinsic_half = (asset_value_now - strike_value_now) * 0.5;
+    let discriminant =
+        (call_price - intrinsic_half).powi(2) - (asset_value_now - strike_value_now).powi(2) / std::f64::consts::PI;
+
+    if discriminant >= 0.0 {
+        let guess = (2.0 * std::f64::consts::PI / years_until_expiry).sqrt() / (asset_value_now + strike_value_now)
+            * (call_price - intrinsic_half + discriminant.sqrt());
+
+        if guess.is_finite() && guess > 0.0 {
+            return guess;
+        }
+    }
+
+    (2.0 * (asset_value_now / strike_value_now).ln().abs() / years_until_expiry).sqrt()
+}
+
 /// d1 is a bit complicated. It's the number of log-space standard deviation volatility units the (risk-free interest rate
 /// forward-adjusted) spot price is from the strike, further adjusted to take into account the significance of the
 /// *in-the-moneyness* at expiry, rather than just the *probability* of being in-the-money (deeply ITM matters more than
@@ -198,11 +223,14 @@ pub fn calculate_bs_implied_volatility(
 /// * `years_until_expiry` - Years until the option expires (365 day year).
 /// * `risk_free_interest_rate` - The continuously-compounded risk-free interest rate from now until expiry. Annualised. For
 ///   example, 5% per annum is 0.05. Must use a 365 day year.
+/// * `dividend_yield` - The continuously-compounded yield paid by the underlying asset from now until expiry, e.g.
+///   dividends, or lending or staking yield. Annualised. Use 0 for none. Must use a 365 day year.
 /// * `volatility` - Annualised standard deviation of the underlying log returns. Must use a 365 day year.
 pub fn black_scholes_d1(
     asset_spot_price: f64,
     strike_price: f64,
     risk_free_interest_rate: f64,
+    dividend_yield: f64,
     volatility: f64,
     years_until_expiry: f64,
 ) -> Result<f64, TsError> {
@@ -210,6 +238,7 @@ pub fn black_scholes_d1(
     error_unless_positive_f64(strike_price, "strike_price")?;
     error_unless_positive_f64(volatility, "volatility")?;
     error_unless_valid_f64(risk_free_interest_rate, "risk_free_interest_rate")?;
+    error_unless_valid_f64(dividend_yield, "dividend_yield")?;
     error_unless_positive_f64(years_until_expiry, "years_until_expiry")?;
 
     // Uncertainty increases with time and volatility.
@@ -220,9 +249,9 @@ pub fn black_scholes_d1(
 
     // Take the natural log because that's how Black-Scholes works.
     let mut d1 = moneyness.ln();
-    // Take into account the risk-change caused by the existence of the risk-free rate, whilst also
-    // doing some logarithm-based math magic.
-    d1 += (risk_free_interest_rate + (0.5 * volatility * volatility)) * years_until_expiry;
+    // Take into account the risk-change caused by the existence of the risk-free rate (less the yield the asset pays, which
+    // holders of the option miss out on), whilst also doing some logarithm-based math magic.
+    d1 += (risk_free_interest_rate - dividend_yield + (0.5 * volatility * volatility)) * years_until_expiry;
     // The greater the uncertainty, the less the distance from the strike matters.
     Ok(d1 / uncertainty)
 }
@@ -245,7 +274,7 @@ pub fn black_scholes_d2(d1: f64, volatility: f64, years_until_expiry: f64) -> Re
     Ok(d1 - uncertainty)
 }
 
-/// Calculate the Black-Scholes price given the provided parameters. Assumes no dividends.
+/// Calculate the Black-Scholes price given the provided parameters.
 ///
 /// # Arguments
 ///
@@ -254,6 +283,8 @@ pub fn black_scholes_d2(d1: f64, volatility: f64, years_until_expiry: f64) -> Re
 /// * `years_until_expiry` - Years until the option expires (365 day year).
 /// * `risk_free_interest_rate` - The continuously-compounded risk-free interest rate from now until expiry. Annualised. For
 ///   example, 5% per annum is 0.05. Must use a 365 day year.
+/// * `dividend_yield` - The continuously-compounded yield paid by the underlying asset from now until expiry, e.g.
+///   dividends, or lending or staking yield. Annualised. Use 0 for none. Must use a 365 day year.
 /// * `volatility` - Annualised standard deviation of the underlying log returns. Must use a 365 day year.
 /// * `option_type` - The type of the option.
 pub fn calculate_black_scholes(
@@ -261,6 +292,7 @@ pub fn calculate_black_scholes(
     strike_price: f64,
     years_until_expiry: f64,
     risk_free_interest_rate: f64,
+    dividend_yield: f64,
     volatility: f64,
     option_type: OptionType,
 ) -> Result<f64, TsError> {
@@ -268,10 +300,20 @@ pub fn calculate_black_scholes(
     error_unless_positive_f64(strike_price, "strike_price")?;
     error_unless_positive_f64(years_until_expiry, "years_until_expiry")?;
     error_unless_valid_f64(risk_free_interest_rate, "risk_free_interest_rate")?;
+    error_unless_valid_f64(dividend_yield, "dividend_yield")?;
     error_unless_positive_f64(volatility, "volatility")?;
 
-    let d1 = black_scholes_d1(asset_spot_price, strike_price, risk_free_interest_rate, volatility, years_until_expiry)?;
+    let d1 = black_scholes_d1(
+        asset_spot_price,
+        strike_price,
+        risk_free_interest_rate,
+        dividend_yield,
+        volatility,
+        years_until_expiry,
+    )?;
     let d2 = black_scholes_d2(d1, volatility, years_until_expiry)?;
+    // The asset is worth less to someone who only gets it at expiry, since they miss out on its yield until then.
+    let asset_value_now = asset_spot_price * E.powf(-dividend_yield * years_until_expiry);
 
     match option_type {
         OptionType::Call => {
@@ -280,7 +322,7 @@ pub fn calculate_black_scholes(
             // How much the option price changes as spot price changes.
             let delta = norm_cdf(d1);
 
-            let current_value = asset_spot_price * delta;
+            let current_value = asset_value_now * delta;
 
             // Subtract the strike price, adjusted for the risk-free rate, from the current value.
             // This gives us the actual value.
@@ -296,7 +338,7 @@ pub fn calculate_black_scholes(
             // How much the option price changes as spot price changes.
             let negative_delta = norm_cdf(-d1);
 
-            let current_value = asset_spot_price * negative_delta;
+            let current_value = asset_value_now * negative_delta;
 
             // Same as above but other way around.
             let result = (strike_price * E.powf(-risk_free_interest_rate * years_until_expiry)) * in_money_probability;
@@ -305,66 +347,791 @@ pub fn calculate_black_scholes(
     }
 }
 
-fn norm_cdf(x: f64) -> f64 {
+/// Calculate the Black-76 price of an option on a forward or future, given the forward price rather than the spot price.
+/// This is the same as Black-Scholes with the spot price the forward price discounted at the interest rate.
+///
+/// # Arguments
+///
+/// * `forward_price` - The forward price of the underlying asset at expiry.
+/// * `strike_price` - The strike price of the option.
+/// * `years_until_expiry` - Years until the option expires (365 day year).
+/// * `discount_rate` - The continuously-compounded rate the premium is discounted at from expiry to now. Annualised. Must
+///   use a 365 day year.
+/// * `volatility` - Annualised standard deviation of the underlying log returns. Must use a 365 day year.
+/// * `option_type` - The type of the option.
+pub fn calculate_black_76(
+    forward_price: f64,
+    strike_price: f64,
+    years_until_expiry: f64,
+    discount_rate: f64,
+    volatility: f64,
+    option_type: OptionType,
+) -> Result<f64, TsError> {
+    error_unless_positive_f64(forward_price, "forward_price")?;
+    error_unless_positive_f64(strike_price, "strike_price")?;
+    error_unless_positive_f64(years_until_expiry, "years_until_expiry")?;
+    error_unless_valid_f64(discount_rate, "discount_rate")?;
+    error_unless_positive_f64(volatility, "volatility")?;
+
+    // With no interest rate, d1 only depends on the forward's moneyness and the uncertainty.
+    let d1 = black_scholes_d1(forward_price, strike_price, 0.0, 0.0, volatility, years_until_expiry)?;
+    let d2 = black_scholes_d2(d1, volatility, years_until_expiry)?;
+    let discount = E.powf(-discount_rate * years_until_expiry);
+
+    match option_type {
+        OptionType::Call => Ok(discount * (forward_price * norm_cdf(d1) - strike_price * norm_cdf(d2))),
+        OptionType::Put => Ok(discount * (strike_price * norm_cdf(-d2) - forward_price * norm_cdf(-d1))),
+    }
+}
+
+/// Calculate the price of an inverse option, which is quoted and settled in the underlying coin, as a fraction of one coin.
+/// At expiry it pays the USD payoff divided by the underlying price, so its value in coin is the undiscounted Black-76 price
+/// divided by the forward price.
+///
+/// # Arguments
+///
+/// * `forward_price` - The forward price of the underlying asset at expiry, in USD.
+/// * `strike_price` - The strike price of the option, in USD.
+/// * `years_until_expiry` - Years until the option expires (365 day year).
+/// * `volatility` - Annualised standard deviation of the underlying log returns. Must use a 365 day year.
+/// * `option_type` - The type of the option.
+pub fn calculate_inverse_option_price(
+    forward_price: f64,
+    strike_price: f64,
+    years_until_expiry: f64,
+    volatility: f64,
+    option_type: OptionType,
+) -> Result<f64, TsError> {
+    Ok(calculate_black_76(forward_price, strike_price, years_until_expiry, 0.0, volatility, option_type)? / forward_price)
+}
+
+/// Calculate the implied volatility of an inverse option from its price in the underlying coin (see
+/// calculate_inverse_option_price()).
+///
+/// # Arguments
+///
+/// * `forward_price` - The forward price of the underlying asset at expiry, in USD.
+/// * `strike_price` - The strike price of the option, in USD.
+/// * `years_until_expiry` - Years until the option expires (365 day year).
+/// * `coin_price` - The price of the option as a fraction of one coin.
+/// * `option_type` - The type of the option.
+pub fn calculate_inverse_implied_volatility(
+    forward_price: f64,
+    strike_price: f64,
+    years_until_expiry: f64,
+    coin_price: f64,
+    option_type: OptionType,
+) -> Result<f64, TsError> {
+    // Undiscounted Black-76 is Black-Scholes with the forward as the spot price and no interest rate.
+    calculate_bs_implied_volatility(
+        forward_price,
+        strike_price,
+        years_until_expiry,
+        0.0,
+        0.0,
+        coin_price * forward_price,
+        option_type,
+    )
+}
+
+/// Calculate the Black-Scholes delta, the change in option price for a change in spot.
+///
+/// # Arguments
+///
+/// * `asset_spot_price` - The current spot price of the underlying asset.
+/// * `strike_price` - The strike price of the option.
+/// * `years_until_expiry` - Years until the option expires (365 day year).
+/// * `risk_free_interest_rate` - The continuously-compounded risk-free interest rate from now until expiry. Annualised. For
+///   example, 5% per annum is 0.05. Must use a 365 day year.
+/// * `dividend_yield` - The continuously-compounded yield paid by the underlying asset from now until expiry, e.g.
+///   dividends, or lending or staking yield. Annualised. Use 0 for none. Must use a 365 day year.
+/// * `volatility` - Annualised standard deviation of the underlying log returns. Must use a 365 day year.
+/// * `option_type` - The type of the option.
+pub fn calculate_black_scholes_delta(
+    asset_spot_price: f64,
+    strike_price: f64,
+    years_until_expiry: f64,
+    risk_free_interest_rate: f64,
+    dividend_yield: f64,
+    volatility: f64,
+    option_type: OptionType,
+) -> Result<f64, TsError> {
+    let terms = get_black_scholes_terms(
+        asset_spot_price,
+        strike_price,
+        years_until_expiry,
+        risk_free_interest_rate,
+        dividend_yield,
+        volatility,
+    )?;
+
+    match option_type {
+        OptionType::Call => Ok(terms.yield_discount * norm_cdf(terms.d1)),
+        OptionType::Put => Ok(-terms.yield_discount * norm_cdf(-terms.d1)),
+    }
+}
+
+/// Calculate the Black-Scholes gamma, the change in delta for a change in spot. It is the same for calls and puts.
+///
+/// # Arguments
+///
+/// * `asset_spot_price` - The current spot price of the underlying asset.
+/// * `strike_price` - The strike price of the option.
+/// * `years_until_expiry` - Years until the option expires (365 day year).
+/// * `risk_free_interest_rate` - The continuously-compounded risk-free interest rate from now until expiry. Annualised. For
+///   example, 5% per annum is 0.05. Must use a 365 day year.
+/// * `dividend_yield` - The continuously-compounded yield paid by the underlying asset from now until expiry, e.g.
+///   dividends, or lending or staking yield. Annualised. Use 0 for none. Must use a 365 day year.
+/// * `volatility` - Annualised standard deviation of the underlying log returns. Must use a 365 day year.
+pub fn calculate_black_scholes_gamma(
+    asset_spot_price: f64,
+    strike_price: f64,
+    years_until_expiry: f64,
+    risk_free_interest_rate: f64,
+    dividend_yield: f64,
+    volatility: f64,
+) -> Result<f64, TsError> {
+    let terms = get_black_scholes_terms(
+        asset_spot_price,
+        strike_price,
+        years_until_expiry,
+        risk_free_interest_rate,
+        dividend_yield,
+        volatility,
+    )?;
+
+    Ok(terms.yield_discount * norm_pdf(terms.d1) / (asset_spot_price * terms.uncertainty))
+}
+
+/// Calculate the Black-Scholes vega, the change in option price for a change in volatility (per 1.00 of volatility, not per
+/// percentage point). It is the same for calls and puts.
+///
+/// # Arguments
+///
+/// * `asset_spot_price` - The current spot price of the underlying asset.
+/// * `strike_price` - The strike price of the option.
+/// * `years_until_expiry` - Years until the option expires (365 day year).
+/// * `risk_free_interest_rate` - The continuously-compounded risk-free interest rate from now until expiry. Annualised. For
+///   example, 5% per annum is 0.05. Must use a 365 day year.
+/// * `dividend_yield` - The continuously-compounded yield paid by the underlying asset from now until expiry, e.g.
+///   dividends, or lending or staking yield. Annualised. Use 0 for none. Must use a 365 day year.
+/// * `volatility` - Annualised standard deviation of the underlying log returns. Must use a 365 day year.
+pub fn calculate_black_scholes_vega(
+    asset_spot_price: f64,
+    strike_price: f64,
+    years_until_expiry: f64,
+    risk_free_interest_rate: f64,
+    dividend_yield: f64,
+    volatility: f64,
+) -> Result<f64, TsError> {
+    let d1 = black_scholes_d1(
+        asset_spot_price,
+        strike_price,
+        risk_free_interest_rate,
+        dividend_yield,
+        volatility,
+        years_until_expiry,
+    )?;
+
+    Ok(asset_spot_price * E.powf(-dividend_yield * years_until_expiry) * norm_pdf(d1) * years_until_expiry.sqrt())
+}
+
+/// Calculate the Black-Scholes vanna, the change in delta for a change in volatility (per 1.00 of volatility), which is
+/// also the change in vega for a change in spot. It is the same for calls and puts. This is how much a position's delta
+/// hedge is thrown off when the smile moves.
+///
+/// # Arguments
+///
+/// * `asset_spot_price` - The current spot price of the underlying asset.
+/// * `strike_price` - The strike price of the option.
+/// * `years_until_expiry` - Years until the option expires (365 day year).
+/// * `risk_free_interest_rate` - The continuously-compounded risk-free interest rate from now until expiry. Annualised. For
+///   example, 5% per annum is 0.05. Must use a 365 day year.
+/// * `dividend_yield` - The continuously-compounded yield paid by the underlying asset from now until expiry, e.g.
+///   dividends, or lending or staking yield. Annualised. Use 0 for none. Must use a 365 day year.
+/// * `volatility` - Annualised standard deviation of the underlying log returns. Must use a 365 day year.
+pub fn calculate_black_scholes_vanna(
+    asset_spot_price: f64,
+    strike_price: f64,
+    years_until_expiry: f64,
+    risk_free_interest_rate: f64,
+    dividend_yield: f64,
+    volatility: f64,
+) -> Result<f64, TsError> {
+    let terms = get_black_scholes_terms(
+        asset_spot_price,
+        strike_price,
+        years_until_expiry,
+        risk_free_interest_rate,
+        dividend_yield,
+        volatility,
+    )?;
+
+    Ok(-terms.yield_discount * norm_pdf(terms.d1) * terms.d2 / volatility)
+}
+
+/// Calculate the Black-Scholes volga (or vomma), the change in vega for a change in volatility (per 1.00 of volatility).
+/// It is the same for calls and puts. Options away from the money have the most, which is why the wings of the smile
+/// are priced higher.
+///
+/// # Arguments
+///
+/// * `asset_spot_price` - The current spot price of the underlying asset.
+/// * `strike_price` - The strike price of the option.
+/// * `years_until_expiry` - Years until the option expires (365 day year).
+/// * `risk_free_interest_rate` - The continuously-compounded risk-free interest rate from now until expiry. Annualised. For
+///   example, 5% per annum is 0.05. Must use a 365 day year.
+/// * `dividend_yield` - The continuously-compounded yield paid by the underlying asset from now until expiry, e.g.
+///   dividends, or lending or staking yield. Annualised. Use 0 for none. Must use a 365 day year.
+/// * `volatility` - Annualised standard deviation of the underlying log returns. Must use a 365 day year.
+pub fn calculate_black_scholes_volga(
+    asset_spot_price: f64,
+    strike_price: f64,
+    years_until_expiry: f64,
+    risk_free_interest_rate: f64,
+    dividend_yield: f64,
+    volatility: f64,
+) -> Result<f64, TsError> {
+    let terms = get_black_scholes_terms(
+        asset_spot_price,
+        strike_price,
+        years_until_expiry,
+        risk_free_interest_rate,
+        dividend_yield,
+        volatility,
+    )?;
+    let vega = calculate_black_scholes_vega(
+        asset_spot_price,
+        strike_price,
+        years_until_expiry,
+        risk_free_interest_rate,
+        dividend_yield,
+        volatility,
+    )?;
+
+    Ok(vega * terms.d1 * terms.d2 / volatility)
+}
+
+/// Calculate the Black-Scholes charm, the change in delta as time passes (per year, so divide by 365 for the change over
+/// a day), with everything else staying the same.
+///
+/// # Arguments
+///
+/// * `asset_spot_price` - The current spot price of the underlying asset.
+/// * `strike_price` - The strike price of the option.
+/// * `years_until_expiry` - Years until the option expires (365 day year).
+/// * `risk_free_interest_rate` - The continuously-compounded risk-free interest rate from now until expiry. Annualised. For
+///   example, 5% per annum is 0.05. Must use a 365 day year.
+/// * `dividend_yield` - The continuously-compounded yield paid by the underlying asset from now until expiry, e.g.
+///   dividends, or lending or staking yield. Annualised. Use 0 for none. Must use a 365 day year.
+/// * `volatility` - Annualised standard deviation of the underlying log returns. Must use a 365 day year.
+/// * `option_type` - The type of the option.
+pub fn calculate_black_scholes_charm(
+    asset_spot_price: f64,
+    strike_price: f64,
+    years_until_expiry: f64,
+    risk_free_interest_rate: f64,
+    dividend_yield: f64,
+    volatility: f64,
+    option_type: OptionType,
+) -> Result<f64, TsError> {
+    let terms = get_black_scholes_terms(
+        asset_spot_price,
+        strike_price,
+        years_until_expiry,
+        risk_free_interest_rate,
+        dividend_yield,
+        volatility,
+    )?;
+    let drift_term = (2.0 * (risk_free_interest_rate - dividend_yield) * years_until_expiry - terms.d2 * terms.uncertainty)
+        / (2.0 * years_until_expiry * terms.uncertainty);
+    let common = terms.yield_discount * norm_pdf(terms.d1) * drift_term;
+
+    match option_type {
+        OptionType::Call => Ok(dividend_yield * terms.yield_discount * norm_cdf(terms.d1) - common),
+        OptionType::Put => Ok(-dividend_yield * terms.yield_discount * norm_cdf(-terms.d1) - common),
+    }
+}
+
+/// Calculate the Black-Scholes speed, the change in gamma for a change in spot. It is the same for calls and puts.
+///
+/// # Arguments
+///
+/// * `asset_spot_price` - The current spot price of the underlying asset.
+/// * `strike_price` - The strike price of the option.
+/// * `years_until_expiry` - Years until the option expires (365 day year).
+/// * `risk_free_interest_rate` - The continuously-compounded risk-free interest rate from now until expiry. Annualised. For
+///   example, 5% per annum is 0.05. Must use a 365 day year.
+/// * `dividend_yield` - The continuously-compounded yield paid by the underlying asset from now until expiry, e.g.
+///   dividends, or lending or staking yield. Annualised. Use 0 for none. Must use a 365 day year.
+/// * `volatility` - Annualised standard deviation of the underlying log returns. Must use a 365 day year.
+pub fn calculate_black_scholes_speed(
+    asset_spot_price: f64,
+    strike_price: f64,
+    years_until_expiry: f64,
+    risk_free_interest_rate: f64,
+    dividend_yield: f64,
+    volatility: f64,
+) -> Result<f64, TsError> {
+    let terms = get_black_scholes_terms(
+        asset_spot_price,
+        strike_price,
+        years_until_expiry,
+        risk_free_interest_rate,
+        dividend_yield,
+        volatility,
+    )?;
+    let gamma = calculate_black_scholes_gamma(
+        asset_spot_price,
+        strike_price,
+        years_until_expiry,
+        risk_free_interest_rate,
+        dividend_yield,
+        volatility,
+    )?;
+
+    Ok(-gamma / asset_spot_price * (terms.d1 / terms.uncertainty + 1.0))
+}
+
+/// Calculate the Black-Scholes color, the change in gamma as time passes (per year, so divide by 365 for the change over a
+/// day), with everything else staying the same. It is the same for calls and puts.
+///
+/// # Arguments
+///
+/// * `asset_spot_price` - The current spot price of the underlying asset.
+/// * `strike_price` - The strike price of the option.
+/// * `years_until_expiry` - Years until the option expires (365 day year).
+/// * `risk_free_interest_rate` - The continuously-compounded risk-free interest rate from now until expiry. Annualised. For
+///   example, 5% per annum is 0.05. Must use a 365 day year.
+/// * `dividend_yield` - The continuously-compounded yield paid by the underlying asset from now until expiry, e.g.
+///   dividends, or lending or staking yield. Annualised. Use 0 for none. Must use a 365 day year.
+/// * `volatility` - Annualised standard deviation of the underlying log returns. Must use a 365 day year.
+pub fn calculate_black_scholes_color(
+    asset_spot_price: f64,
+    strike_price: f64,
+    years_until_expiry: f64,
+    risk_free_interest_rate: f64,
+    dividend_yield: f64,
+    volatility: f64,
+) -> Result<f64, TsError> {
+    let terms = get_black_scholes_terms(
+        asset_spot_price,
+        strike_price,
+        years_until_expiry,
+        risk_free_interest_rate,
+        dividend_yield,
+        volatility,
+    )?;
+    let drift_term = (2.0 * (risk_free_interest_rate - dividend_yield) * years_until_expiry - terms.d2 * terms.uncertainty)
+        / terms.uncertainty;
+
+    Ok(
+        terms.yield_discount * norm_pdf(terms.d1) / (2.0 * asset_spot_price * years_until_expiry * terms.uncertainty)
+            * (2.0 * dividend_yield * years_until_expiry + 1.0 + drift_term * terms.d1),
+    )
+}
+
+/// The parts of the Black-Scholes formula the greeks are made of.
+struct BlackScholesTerms {
+    d1: f64,
+    d2: f64,
+    /// Volatility scaled to the time until expiry.
+    uncertainty: f64,
+    /// What the asset's yield until expiry is worth now, as a fraction of it.
+    yield_discount: f64,
+}
+
+fn get_black_scholes_terms(
+    asset_spot_price: f64,
+    strike_price: f64,
+    years_until_expiry: f64,
+    risk_free_interest_rate: f64,
+    dividend_yield: f64,
+    volatility: f64,
+) -> Result<BlackScholesTerms, TsError> {
+    let d1 = black_scholes_d1(
+        asset_spot_price,
+        strike_price,
+        risk_free_interest_rate,
+        dividend_yield,
+        volatility,
+        years_until_expiry,
+    )?;
+
+    Ok(BlackScholesTerms {
+        d1,
+        d2: black_scholes_d2(d1, volatility, years_until_expiry)?,
+        uncertainty: volatility * years_until_expiry.sqrt(),
+        yield_discount: E.powf(-dividend_yield * years_until_expiry),
+    })
+}
+
+/// The nodes of the first step of a tree that has three of them, from which the greeks are read.
+struct TreeCentralStep {
+    price: f64,
+    /// The spot prices of the down, middle and up nodes.
+    spot_prices: [f64; 3],
+    /// The option values at the down, middle and up nodes.
+    values: [f64; 3],
+    /// How long after now the step is (years).
+    years_from_now: f64,
+}
+
+/// Price an option by working backwards through a tree of possible spot prices with constant volatility, which unlike
+/// Black-Scholes can price American options by checking at every node whether exercising early is worth more than holding
+/// on. Any yield the asset pays is taken from the settings.
+///
+/// # Arguments
+///
+/// * `asset_spot_price` - The current spot price of the underlying asset.
+/// * `strike_price` - The strike price of the option.
+/// * `years_until_expiry` - Years until the option expires (365 day year).
+/// * `risk_free_interest_rate` - The continuously-compounded risk-free interest rate from now until expiry. Annualised. For
+///   example, 5% per annum is 0.05. Must use a 365 day year.
+/// * `volatility` - Annualised standard deviation of the underlying log returns. Must use a 365 day year.
+/// * `option_type` - The type of the option.
+/// * `settings` - The type of tree, the number of steps, the exercise style and the asset's yield.
+pub fn calculate_tree_price(
+    asset_spot_price: f64,
+    strike_price: f64,
+    years_until_expiry: f64,
+    risk_free_interest_rate: f64,
+    volatility: f64,
+    option_type: OptionType,
+    settings: &TreeSettings,
+) -> Result<f64, TsError> {
+    let step = roll_back_tree(
+        asset_spot_price,
+        strike_price,
+        years_until_expiry,
+        risk_free_interest_rate,
+        volatility,
+        option_type,
+        settings,
+    )?;
+
+    Ok(step.price)
+}
+
+/// Price an option with a tree (see calculate_tree_price()) and work out its greeks. Delta, gamma and theta are read from
+/// the tree itself, and vega is found by repricing with volatility bumped up and down by 1%.
+pub fn calculate_tree_greeks(
+    asset_spot_price: f64,
+    strike_price: f64,
+    years_until_expiry: f64,
+    risk_free_interest_rate: f64,
+    volatility: f64,
+    option_type: OptionType,
+    settings: &TreeSettings,
+) -> Result<OptionGreeks, TsError> {
+    let step = roll_back_tree(
+        asset_spot_price,
+        strike_price,
+        years_until_expiry,
+        risk_free_interest_rate,
+        volatility,
+        option_type,
+        settings,
+    )?;
+
+    let [down_spot, middle_spot, up_spot] = step.spot_prices;
+    let [down_value, middle_value, up_value] = step.values;
+
+    let delta = (up_value - down_value) / (up_spot - down_spot);
+    let gamma = ((up_value - middle_value) / (up_spot - middle_spot) - (middle_value - down_value) / (middle_spot - down_spot))
+        / (0.5 * (up_spot - down_spot));
+    // The middle node has the same spot price as now, so the change in value there is down to time passing.
+    let theta = (middle_value - step.price) / step.years_from_now;
+
+    let volatility_bump = volatility * 0.01;
+    let price_with_volatility = |volatility| {
+        calculate_tree_price(
+            asset_spot_price,
+            strike_price,
+            years_until_expiry,
+            risk_free_interest_rate,
+            volatility,
+            option_type,
+            settings,
+        )
+    };
+    let vega = (price_with_volatility(volatility + volatility_bump)? - price_with_volatility(volatility - volatility_bump)?)
+        / (2.0 * volatility_bump);
+
+    Ok(OptionGreeks {
+        price: step.price,
+        delta,
+        gamma,
+        vega,
+        theta,
+    })
+}
+
+fn roll_back_tree(
+    asset_spot_price: f64,
+    strike_price: f64,
+    years_until_expiry: f64,
+    risk_free_interest_rate: f64,
+    volatility: f64,
+    option_type: OptionType,
+    settings: &TreeSettings,
+) -> Result<TreeCentralStep, TsError> {
+    error_unless_positive_f64(asset_spot_price, "asset_spot_price")?;
+    error_unless_positive_f64(strike_price, "strike_price")?;
+    error_unless_positive_f64(years_until_expiry, "years_until_expiry")?;
+    error_unless_valid_f64(risk_free_interest_rate, "risk_free_interest_rate")?;
+    error_unless_positive_f64(volatility, "volatility")?;
+    error_unless_valid_f64(settings.dividend_yield, "dividend_yield")?;
+
+    if settings.steps < 2 {
+        return Err(TsError::new(UnsolvableError, format!("A tree needs at least 2 steps, found {}", settings.steps)));
+    }
+
+    let steps = settings.steps as usize;
+    let step_length = years_until_expiry / steps as f64;
+    let discount = E.powf(-risk_free_interest_rate * step_length);
+    // Spot is expected to grow at the risk-free rate, less the yield paid out along the way.
+    let carry = risk_free_interest_rate - settings.dividend_yield;
+
+    // The factor spot moves by each node, the probabilities of moving to each child node (lowest first), and the first
+    // step that has three nodes.
+    let (up_factor, probabilities, central_step) = match settings.tree_type {
+        TreeType::Binomial => {
+            let up_factor = E.powf(volatility * step_length.sqrt());
+            let up_probability = (E.powf(carry * step_length) - 1.0 / up_factor) / (up_factor - 1.0 / up_factor);
+
+            (up_factor, vec![1.0 - up_probability, up_probability], 2)
+        }
+        TreeType::Trinomial => {
+            let up_factor = E.powf(volatility * (2.0 * step_length).sqrt());
+            let growth = E.powf(carry * step_length / 2.0);
+            let half_up = E.powf(volatility * (step_length / 2.0).sqrt());
+            let up_probability = ((growth - 1.0 / half_up) / (half_up - 1.0 / half_up)).powi(2);
+            let down_probability = ((half_up - growth) / (half_up - 1.0 / half_up)).powi(2);
+
+            (up_factor, vec![down_probability, 1.0 - up_probability - down_probability, up_probability], 1)
+        }
+    };
+
+    if probabilities.iter().any(|x| !(0.0..=1.0).contains(x)) {
+        return Err(TsError::new(
+            UnsolvableError,
+            "The interest rate is too high for the volatility and step length, try using more steps",
+        ));
+    }
+
+    // Each step of a binomial tree has one more node than the last, with neighbouring nodes two up factors apart. Each step
+    // of a trinomial tree has two more, one up factor apart.
+    let node_spacing = match settings.tree_type {
+        TreeType::Binomial => 2,
+        TreeType::Trinomial => 1,
+    };
+    let get_spot_price = |step: usize, node: usize| asset_spot_price * up_factor.powi(node_spacing * node as i32 - step as i32);
+    let get_intrinsic_value = |spot_price: f64| match option_type {
+        OptionType::Call => (spot_price - strike_price).max(0.0),
+        OptionType::Put => (strike_price - spot_price).max(0.0),
+    };
+    let nodes_at_step = |step: usize| (probabilities.len() - 1) * step + 1;
+
+    let mut values: Vec<f64> = (0..nodes_at_step(steps))
+        .map(|node| get_intrinsic_value(get_spot_price(steps, node)))
+        .collect();
+    let mut central_values = match steps == central_step {
+        true => values.clone(),
+        false => Vec::new(),
+    };
+
+    for step in (0..steps).rev() {
+        values = (0..nodes_at_step(step))
+            .map(|node| {
+                let held_value = discount
+                    * probabilities
+                        .iter()
+                        .enumerate()
+                        .map(|(child, probability)| probability * values[node + child])
+                        .sum::<f64>();
+
+                match settings.exercise_style {
+                    ExerciseStyle::European => held_value,
+                    ExerciseStyle::American => held_value.max(get_intrinsic_value(get_spot_price(step, node))),
+                }
+            })
+            .collect();
+
+        if step == central_step {
+            central_values = values.clone();
+        }
+    }
+
+    let price = values[0];
+    error_unless_valid_f64(price, "price")?;
+
+    Ok(TreeCentralStep {
+        price,
+        spot_prices: [0, 1, 2].map(|node| get_spot_price(central_step, node)),
+        values: [central_values[0], central_values[1], central_values[2]],
+        years_from_now: step_length * central_step as f64,
+    })
+}
+
+fn norm_pdf(x: f64) -> f64 {
+    (-0.5 * x * x).exp() / (2.0 * std::f64::consts::PI).sqrt()
+}
+
+pub fn norm_cdf(x: f64) -> f64 {
     0.5 * libm::erfc(-x * std::f64::consts::FRAC_1_SQRT_2)
 }
 
 /// Returns true if the given SVI curve has butterfly arbitrage, or an error if there was an issue with the calculation.
 ///
-/// Checks resolution spots on the given curve and checks there is no arbitrage at any point. The graph will be scanned
-/// from strikes from_strike to to_strike.
+/// Checks the curve at the points given by settings (see ButterflyCheckSettings) around the observed strikes, and checks
+/// there is no arbitrage at any of them.
 ///
 /// See https://arxiv.org/pdf/1204.0646 and https://www.ma.imperial.ac.uk/~ajacquie/IC_AMDP/IC_AMDP_Docs/Code/SSVI.pdf.
 pub fn has_butterfly_arbitrage(
     curve_params: &SVICurveParameters,
-    from_strike: u64,
-    to_strike: u64,
+    lowest_observed_strike: f64,
+    highest_observed_strike: f64,
     forward_price: f64,
-    resolution: u64,
+    settings: &ButterflyCheckSettings,
 ) -> Result<bool, TsError> {
+    // We'll test lots of points along this graph and see if we can find any invalid spots. If we find any, there is arbitrage.
+    for log_moneyness in get_butterfly_check_points(lowest_observed_strike, highest_observed_strike, forward_price, settings)? {
+        if svi_g(curve_params, log_moneyness)? < 0.0 {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// How far the SVI curve's g(k) falls below the margin, as the root of the sum of the squared shortfalls at the points
+/// has_butterfly_arbitrage() checks. It's zero if g(k) is at least the margin everywhere, and otherwise grows smoothly
+/// with the arbitrage, so the fitters can use it as a penalty that the optimiser can follow back to a valid curve.
+pub fn get_butterfly_arbitrage_shortfall(
+    curve_params: &SVICurveParameters,
+    lowest_observed_strike: f64,
+    highest_observed_strike: f64,
+    forward_price: f64,
+    settings: &ButterflyCheckSettings,
+    margin: f64,
+) -> Result<f64, TsError> {
+    let mut squared_shortfall = 0.0;
+
+    for log_moneyness in get_butterfly_check_points(lowest_observed_strike, highest_observed_strike, forward_price, settings)? {
+        let shortfall = (margin - svi_g(curve_params, log_moneyness)?).max(0.0);
+        squared_shortfall += shortfall * shortfall;
+    }
+
+    Ok(squared_shortfall.sqrt())
+}
+
+/// The log moneynesses the curve is checked for butterfly arbitrage at: evenly spaced across the observed strikes, then
+/// out into each wing.
+fn get_butterfly_check_points(
+    lowest_observed_strike: f64,
+    highest_observed_strike: f64,
+    forward_price: f64,
+    settings: &ButterflyCheckSettings,
+) -> Result<impl Iterator<Item = f64>, TsError> {
     error_unless_positive_f64(forward_price, "forward_price")?;
+    error_unless_positive_f64(lowest_observed_strike, "lowest_observed_strike")?;
 
-    let range = to_strike - from_strike;
-    let step_size = range as f64 / resolution as f64;
-    let m = curve_params.get_m();
-    let b = curve_params.get_b();
-    let o = curve_params.get_o();
-    let p = curve_params.get_p();
-    let o_squared = o * o;
-    let from_strike_f64 = from_strike as f64;
-    let mut i = 0.0;
-    let resolution_f64 = resolution as f64;
+    let lowest_log_moneyness = (lowest_observed_strike / forward_price).ln();
+    let highest_log_moneyness = (highest_observed_strike / forward_price).ln();
+    let observed_step = (highest_log_moneyness - lowest_log_moneyness) / (settings.observed_points - 1) as f64;
+    let observed_points = (0..settings.observed_points).map(move |i| lowest_log_moneyness + observed_step * i as f64);
 
-    // We'll test lots of points along this graph and see if we can find any invalid spots. If we find any, there is arbitrage.
-    loop {
-        if i > resolution_f64 {
-            return Ok(false);
-        }
+    // The wings matter less the further they are from the data, so the points get further apart (quadratically) the
+    // further out they are.
+    let wing_points = (1..=settings.wing_points).flat_map(move |i| {
+        let distance = settings.wing_log_moneyness * (i as f64 / settings.wing_points as f64).powi(2);
 
-        let strike = from_strike_f64 + (step_size * i);
-        let log_moneyness = (strike / forward_price).ln();
-        let x = log_moneyness - m;
-        let x_squared = x * x;
-        let svi_variance = svi_variance(curve_params, log_moneyness)?;
-        let svi_variance_deriv1 = b * (p + (x / (x_squared + o_squared).sqrt()));
-        let svi_variance_deriv2 = b * (o_squared / ((x_squared + o_squared).powf(1.5)));
+        [lowest_log_moneyness - distance, highest_log_moneyness + distance]
+    });
 
-        let mut part1 = 1.0 - ((log_moneyness * svi_variance_deriv1) / (2.0 * svi_variance));
-        part1 *= part1;
+    Ok(observed_points.chain(wing_points))
+}
 
-        let mut part2 = (svi_variance_deriv1 * svi_variance_deriv1) * 0.25;
-        part2 *= (1.0 / svi_variance) + 0.25;
+/// Returns the smallest value of the butterfly arbitrage condition at the points has_butterfly_arbitrage() checks.
+/// Negative values mean there is arbitrage, and the larger the value the further the curve is from having any.
+pub fn get_butterfly_arbitrage_margin(
+    curve_params: &SVICurveParameters,
+    lowest_observed_strike: f64,
+    highest_observed_strike: f64,
+    forward_price: f64,
+    settings: &ButterflyCheckSettings,
+) -> Result<f64, TsError> {
+    Ok(find_minimum_svi_g(curve_params, lowest_observed_strike, highest_observed_strike, forward_price, settings)?.1)
+}
 
-        let part3 = svi_variance_deriv2 * 0.5;
+/// Find the smallest value of svi_g() at the points has_butterfly_arbitrage() checks, so that it's measured at the same
+/// resolution the curve was fit with. Returns the strike it was found at and the value.
+pub fn find_minimum_svi_g(
+    curve_params: &SVICurveParameters,
+    lowest_observed_strike: f64,
+    highest_observed_strike: f64,
+    forward_price: f64,
+    settings: &ButterflyCheckSettings,
+) -> Result<(f64, f64), TsError> {
+    let mut minimum = (lowest_observed_strike, f64::MAX);
 
-        let result = part1 - part2 + part3;
+    for log_moneyness in get_butterfly_check_points(lowest_observed_strike, highest_observed_strike, forward_price, settings)? {
+        let g = svi_g(curve_params, log_moneyness)?;
 
-        if result < 0.0 {
-            return Ok(true);
+        if g < minimum.1 {
+            minimum = (forward_price * log_moneyness.exp(), g);
         }
-
-        i += 1.0;
     }
+
+    Ok(minimum)
+}
+
+/// Gatheral and Jacquier's g(k), calculated analytically from the SVI curve and its first two derivatives. g(k) is
+/// proportional to the density implied by the curve at log moneyness k, so the curve is free of butterfly arbitrage
+/// wherever it is not negative (Durrleman's condition), and how far above zero it stays shows how close the curve is to
+/// having any.
+///
+/// See https://arxiv.org/pdf/1204.0646.
+pub fn svi_g(curve_params: &SVICurveParameters, log_moneyness: f64) -> Result<f64, TsError> {
+    let svi_variance = svi_variance(curve_params, log_moneyness)?;
+    let svi_variance_deriv1 = svi_variance_slope(curve_params, log_moneyness)?;
+    let svi_variance_deriv2 = svi_variance_curvature(curve_params, log_moneyness)?;
+
+    let mut part1 = 1.0 - ((log_moneyness * svi_variance_deriv1) / (2.0 * svi_variance));
+    part1 *= part1;
+
+    let mut part2 = (svi_variance_deriv1 * svi_variance_deriv1) * 0.25;
+    part2 *= (1.0 / svi_variance) + 0.25;
+
+    let part3 = svi_variance_deriv2 * 0.5;
+
+    Ok(part1 - part2 + part3)
+}
+
+/// The logistic function, which maps any number into (0, 1). The fitters use it to keep bounded parameters in range while
+/// optimising over every number.
+pub fn sigmoid(x: f64) -> f64 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+/// e^x, but with x smoothly limited to within max_exponent of zero, so that a parameter the fitters optimise over every
+/// number can't overflow to infinity or underflow to zero. Well within the limit it's almost exactly e^x.
+pub fn saturating_exp(x: f64, max_exponent: f64) -> f64 {
+    (max_exponent * (x / max_exponent).tanh()).exp()
+}
+
+/// The derivative of saturating_exp() at x, for the chain rule.
+pub fn saturating_exp_derivative(x: f64, max_exponent: f64) -> f64 {
+    saturating_exp(x, max_exponent) * (1.0 - (x / max_exponent).tanh().powi(2))
+}
+
+/// The inverse of saturating_exp(). Values beyond the limit are treated as being just inside it.
+pub fn saturating_ln(y: f64, max_exponent: f64) -> f64 {
+    max_exponent
+        * (y.ln() / max_exponent)
+            .clamp(-1.0 + f64::EPSILON, 1.0 - f64::EPSILON)
+            .atanh()
 }
 
 /// Calculate total variance using the stochastic volatility inspired model equation, which produces a smile shape.
@@ -393,3 +1160,26 @@ pub fn svi_variance(svi_curve_parameters: &SVICurveParameters, log_moneyness: f6
 
     Ok(result)
 }
+
+/// The slope of the SVI total variance curve with respect to log moneyness.
+pub fn svi_variance_slope(svi_curve_parameters: &SVICurveParameters, log_moneyness: f64) -> Result<f64, TsError> {
+    error_unless_valid_f64(log_moneyness, "log_moneyness")?;
+
+    let b = svi_curve_parameters.get_b();
+    let p = svi_curve_parameters.get_p();
+    let o = svi_curve_parameters.get_o();
+    let x = log_moneyness - svi_curve_parameters.get_m();
+
+    Ok(b * (p + (x / ((x * x) + (o * o)).sqrt())))
+}
+
+/// The second derivative of the SVI total variance curve with respect to log moneyness.
+pub fn svi_variance_curvature(svi_curve_parameters: &SVICurveParameters, log_moneyness: f64) -> Result<f64, TsError> {
+    error_unless_valid_f64(log_moneyness, "log_moneyness")?;
+
+    let b = svi_curve_parameters.get_b();
+    let o = svi_curve_parameters.get_o();
+    let x = log_moneyness - svi_curve_parameters.get_m();
+
+    Ok(b * ((o * o) / ((x * x) + (o * o)).powf(1.5)))
+}
@@ -1,12 +1,112 @@
+mod arbitrage_verification;
+mod asset_comparison;
+mod bootstrap;
+mod convexity_repair;
+mod cross_validation;
+mod differential_evolution;
+mod essvi;
+mod fit_quality;
+mod fit_times;
+mod forward_sources;
+mod heston;
+mod latin_hypercube;
 mod math;
+mod montecarlo;
 mod option_instrument;
+mod put_call_parity;
+mod quote_arbitrage;
+mod quote_table;
+mod scenarios;
+mod skew_beta;
 mod smile_graph;
+mod straddle;
+mod strike_conventions;
+mod surface_grid;
+mod surface_interpolation;
+mod surface_metrics;
+mod surface_score;
+mod synthetic_smiles;
+mod term_structure;
+mod term_structure_smoothing;
 #[cfg(test)]
 mod tests;
 mod types;
+mod vol_events;
+mod vol_matrix;
 
-pub use math::svi_variance;
+pub use arbitrage_verification::{ArbitrageVerification, verify_no_arbitrage};
+pub use asset_comparison::{AssetComparison, AssetTenorComparison, AssetTenorPoint};
+pub use bootstrap::{ConfidenceInterval, SmileConfidenceBand, StrikeConfidenceInterval, bootstrap_smile, get_quantile};
+pub use convexity_repair::repair_price_convexity;
+pub use cross_validation::{SmileCrossValidation, cross_validate_smile};
+pub use differential_evolution::{DifferentialEvolutionSettings, minimise_with_differential_evolution};
+pub use essvi::{EssviPowerLaw, EssviSlice, EssviSurface, essvi_variance, fit_essvi_surface};
+pub use fit_quality::{FitQualityReport, OptionResidual, SmileFitQuality};
+pub use fit_times::{FitTimeModel, FitTimeRecord};
+pub use forward_sources::{
+    ForwardInputs, ForwardProvenance, ForwardQuote, ForwardSource, ForwardSourceSettings, PerpetualQuote, determine_forward_price,
+};
+pub use heston::{
+    HestonCalibration, HestonParameters, HestonSmileFit, calculate_heston_implied_volatility, calculate_heston_price,
+    calibrate_heston,
+};
+pub use latin_hypercube::latin_hypercube_sample;
+pub use math::{
+    calculate_black_76, calculate_black_scholes, calculate_black_scholes_charm, calculate_black_scholes_color,
+    calculate_black_scholes_delta, calculate_black_scholes_gamma, calculate_black_scholes_speed, calculate_black_scholes_vanna,
+    calculate_black_scholes_vega, calculate_black_scholes_volga, calculate_inverse_implied_volatility,
+    calculate_inverse_option_price, calculate_tree_greeks, calculate_tree_price, svi_g, svi_variance,
+};
+// The numeric internals that the benchmarks call.
+#[cfg(any(feature = "bench", feature = "ffi"))]
+pub use math::calculate_bs_implied_volatility;
+#[cfg(feature = "bench")]
+pub use math::has_butterfly_arbitrage;
+pub use montecarlo::{
+    BarrierType, Payoff, SimulatedOption, SimulationResult, SimulationSettings, VolatilityModel, calculate_local_volatility,
+    simulate_option_price,
+};
 pub use option_instrument::OptionInstrument;
-pub use smile_graph::SmileGraph;
+pub use put_call_parity::{ParityCheck, ParityViolation, apply_put_call_parity};
+pub use quote_arbitrage::{QuoteArbitrage, QuoteArbitrageLeg, QuoteArbitrageType, scan_quote_arbitrage};
+pub use quote_table::QuoteTable;
+pub use scenarios::{PortfolioPosition, PositionValuation, ScenarioShock, ShockedPoint, SpotMoveRule, value_portfolio};
+pub use skew_beta::{SkewBeta, calculate_historical_beta};
+pub use smile_graph::{SmileGraph, SviAtmAnchor, SviFitPrior, SviFitSettings};
+pub use straddle::{Straddle, find_atm_straddle};
+pub use strike_conventions::{
+    calculate_forward_delta, find_log_moneyness_at_delta, get_call_delta_at_strike, interpolate_surface_at_delta,
+    log_moneyness_to_strike, strike_to_log_moneyness,
+};
+pub use surface_grid::{SurfaceGrid, SurfaceGridRow, SurfaceGridSlice};
+pub use surface_interpolation::{CalendarArbitrage, SurfaceSlice, check_calendar_arbitrage, interpolate_surface};
+pub use surface_metrics::{ExpiryMetrics, SurfaceMetrics};
+pub use surface_score::{SliceScore, SurfaceScore};
+pub use synthetic_smiles::{STANDARD_TENOR_DAYS, SyntheticSmile, build_synthetic_smile};
+pub use term_structure::{ForwardVariance, TermStructure, TermStructurePoint};
+pub use term_structure_smoothing::{NelsonSiegelCurve, TermStructureAdjustment, smooth_term_structure};
+pub use types::ButterflyCheckSettings;
+pub use types::CrossValidation;
+pub use types::ExerciseStyle;
+pub use types::ExpiryCategory;
+pub use types::ExpiryCategoryFilter;
+pub use types::FitWeighting;
+pub use types::ImpliedVolatilitySource;
+pub use types::OptionGreeks;
 pub use types::OptionType;
+pub use types::PricingModel;
+pub use types::PutCallPairing;
+pub use types::PutCallParitySettings;
+#[cfg(any(feature = "bench", feature = "wasm", test))]
+pub use types::SVICurveParameters;
+pub use types::SmileFitLimits;
 pub use types::SmileGraphsDataContainer;
+pub use types::SurfaceBuildInfo;
+pub use types::SurfaceModel;
+pub use types::SviOptimiser;
+pub use types::TermStructureSmoothing;
+pub use types::TreeSettings;
+pub use types::TreeType;
+pub use types::WingExtrapolation;
+pub use vol_events::{EventProximity, VolEvent, find_event_proximity, get_events_before_expiry};
+pub use vol_matrix::{AT_THE_MONEY_DELTA, DeltaQuote, build_options_from_delta_quotes, parse_delta_label};
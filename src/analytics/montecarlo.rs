@@ -0,0 +1,294 @@
+use std::f64::consts::E;
+
+use rand::{SeedableRng, rngs::StdRng};
+use rand_distr::{Distribution, StandardNormal};
+
+use crate::{
+    analytics::{OptionType, SmileGraph, interpolate_surface},
+    constants,
+    helpers::{error_unless_positive_f64, error_unless_valid_f64},
+    types::{
+        TsError,
+        TsErrorType::{RuntimeError, UnsolvableError},
+    },
+};
+
+/// The number of log moneyness points local volatility is calculated at for each time step. Between them it is
+/// interpolated linearly.
+const LOCAL_VOLATILITY_GRID_POINTS: usize = 201;
+
+/// How far the local volatility grid reaches either side of the forward, in at the money standard deviations of the log
+/// price at expiry. Paths beyond it use the volatility at its edge.
+const LOCAL_VOLATILITY_GRID_WIDTH: f64 = 5.0;
+
+/// The step in log moneyness used to estimate derivatives of total variance.
+const LOG_MONEYNESS_STEP: f64 = 0.005;
+
+/// Local volatility is kept within these bounds, since it can become extreme where the surface is barely free of
+/// arbitrage.
+const MIN_LOCAL_VOLATILITY: f64 = 0.01;
+const MAX_LOCAL_VOLATILITY: f64 = 5.0;
+
+/// How the volatility of each simulated path is chosen.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum VolatilityModel {
+    /// Dupire local volatility, which depends on spot and time and reprices every European option on the surface.
+    LocalVolatility,
+    /// The surface's implied volatility at the option's strike and expiry, the same as Black-Scholes.
+    FlatImpliedVolatility,
+}
+
+impl TryFrom<&str> for VolatilityModel {
+    type Error = TsError;
+
+    fn try_from(volatility_model: &str) -> Result<Self, TsError> {
+        match volatility_model.to_ascii_lowercase().as_str() {
+            "local" => Ok(VolatilityModel::LocalVolatility),
+            "flat" => Ok(VolatilityModel::FlatImpliedVolatility),
+            _ => Err(TsError::new(RuntimeError, format!("Invalid volatility model {volatility_model}"))),
+        }
+    }
+}
+
+/// Which side of spot a barrier is on, and whether crossing it ends or starts the option.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum BarrierType {
+    UpAndOut,
+    UpAndIn,
+    DownAndOut,
+    DownAndIn,
+}
+
+impl TryFrom<&str> for BarrierType {
+    type Error = TsError;
+
+    fn try_from(barrier_type: &str) -> Result<Self, TsError> {
+        match barrier_type.to_ascii_lowercase().as_str() {
+            "up-and-out" => Ok(BarrierType::UpAndOut),
+            "up-and-in" => Ok(BarrierType::UpAndIn),
+            "down-and-out" => Ok(BarrierType::DownAndOut),
+            "down-and-in" => Ok(BarrierType::DownAndIn),
+            _ => Err(TsError::new(RuntimeError, format!("Invalid barrier type {barrier_type}"))),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Payoff {
+    /// Pays out on the spot price at expiry.
+    European,
+    /// Pays out on the average spot price at the end of each time step.
+    Asian,
+    /// A European option that only exists if spot does (in) or doesn't (out) cross the barrier at the end of any time step.
+    Barrier { barrier: f64, barrier_type: BarrierType },
+}
+
+/// An option to price by simulation.
+pub struct SimulatedOption {
+    pub strike: f64,
+    pub years_until_expiry: f64,
+    pub option_type: OptionType,
+    pub payoff: Payoff,
+}
+
+pub struct SimulationSettings {
+    pub volatility_model: VolatilityModel,
+    pub paths: u64,
+    /// The number of time steps between now and expiry.
+    pub steps: u64,
+    /// Simulations with the same seed and settings give the same result.
+    pub seed: u64,
+}
+
+pub struct SimulationResult {
+    pub price: f64,
+    /// The standard deviation of the price estimate, which falls with the square root of the number of paths.
+    pub standard_error: f64,
+}
+
+/// Local volatilities at evenly spaced log moneyness for each time step.
+struct LocalVolatilityGrid {
+    lowest_log_moneyness: f64,
+    log_moneyness_step: f64,
+    volatilities: Vec<Vec<f64>>,
+}
+
+impl LocalVolatilityGrid {
+    fn build(smile_graphs: &[SmileGraph], years_until_expiry: f64, steps: u64) -> Result<Self, TsError> {
+        let atm_total_variance =
+            interpolate_surface(smile_graphs, get_spot_price(smile_graphs)?, years_until_expiry)?.total_variance;
+        let half_width = LOCAL_VOLATILITY_GRID_WIDTH * atm_total_variance.sqrt();
+        let log_moneyness_step = 2.0 * half_width / (LOCAL_VOLATILITY_GRID_POINTS - 1) as f64;
+        let step_length = years_until_expiry / steps as f64;
+
+        let volatilities = (0..steps)
+            .map(|step| {
+                // Use the volatility half way through the step.
+                let years = (step as f64 + 0.5) * step_length;
+
+                (0..LOCAL_VOLATILITY_GRID_POINTS)
+                    .map(|i| calculate_local_volatility(smile_graphs, -half_width + log_moneyness_step * i as f64, years))
+                    .collect::<Result<Vec<f64>, TsError>>()
+            })
+            .collect::<Result<Vec<_>, TsError>>()?;
+
+        Ok(Self {
+            lowest_log_moneyness: -half_width,
+            log_moneyness_step,
+            volatilities,
+        })
+    }
+
+    fn get_volatility(&self, step: usize, log_moneyness: f64) -> f64 {
+        let volatilities = &self.volatilities[step];
+        let position = ((log_moneyness - self.lowest_log_moneyness) / self.log_moneyness_step)
+            .clamp(0.0, (LOCAL_VOLATILITY_GRID_POINTS - 1) as f64);
+        let index = (position as usize).min(LOCAL_VOLATILITY_GRID_POINTS - 2);
+        let weight = position - index as f64;
+
+        volatilities[index] + (volatilities[index + 1] - volatilities[index]) * weight
+    }
+}
+
+/// The Dupire local volatility at the given log moneyness (relative to the forward at that time) and time, found from
+/// derivatives of the surface's total variance. See Gatheral, "The Volatility Surface", equation 1.10. Where the surface
+/// is too close to having butterfly arbitrage for this to work, the implied volatility is used instead.
+pub fn calculate_local_volatility(smile_graphs: &[SmileGraph], log_moneyness: f64, years: f64) -> Result<f64, TsError> {
+    error_unless_positive_f64(years, "years")?;
+    error_unless_valid_f64(log_moneyness, "log_moneyness")?;
+
+    let spot_price = get_spot_price(smile_graphs)?;
+    let get_total_variance = |log_moneyness: f64, years: f64| -> Result<f64, TsError> {
+        let strike = spot_price * E.powf(constants::INTEREST_FREE_RATE * years + log_moneyness);
+        Ok(interpolate_surface(smile_graphs, strike, years)?.total_variance)
+    };
+
+    let y = log_moneyness;
+    let h = LOG_MONEYNESS_STEP;
+    let dt = years * 0.01;
+
+    let w = get_total_variance(y, years)?;
+    let w_up = get_total_variance(y + h, years)?;
+    let w_down = get_total_variance(y - h, years)?;
+    let dw_dy = (w_up - w_down) / (2.0 * h);
+    let d2w_dy2 = (w_up - 2.0 * w + w_down) / (h * h);
+    let dw_dt = (get_total_variance(y, years + dt)? - get_total_variance(y, years - dt)?) / (2.0 * dt);
+
+    let denominator = 1.0 - (y / w) * dw_dy + 0.25 * (-0.25 - 1.0 / w + (y * y) / (w * w)) * dw_dy * dw_dy + 0.5 * d2w_dy2;
+    let local_variance = dw_dt / denominator;
+
+    let volatility = match denominator > 0.0 && local_variance.is_finite() {
+        true => local_variance.max(0.0).sqrt(),
+        false => (w / years).sqrt(),
+    };
+
+    Ok(volatility.clamp(MIN_LOCAL_VOLATILITY, MAX_LOCAL_VOLATILITY))
+}
+
+/// Price an option by simulating spot paths with log-Euler steps and averaging the discounted payoffs. Unlike the other
+/// pricers, this can price options whose payoff depends on the path spot takes, not just where it ends up. Barriers and
+/// averages are only observed at the end of each time step.
+pub fn simulate_option_price(
+    smile_graphs: &[SmileGraph],
+    option: &SimulatedOption,
+    settings: &SimulationSettings,
+) -> Result<SimulationResult, TsError> {
+    error_unless_positive_f64(option.strike, "strike")?;
+    error_unless_positive_f64(option.years_until_expiry, "years_until_expiry")?;
+
+    if settings.paths < 2 || settings.steps < 1 {
+        return Err(TsError::new(UnsolvableError, "At least 2 paths and 1 step are needed to simulate"));
+    }
+
+    let rate = constants::INTEREST_FREE_RATE;
+    let spot_price = get_spot_price(smile_graphs)?;
+    let steps = settings.steps as usize;
+    let step_length = option.years_until_expiry / steps as f64;
+
+    let local_volatility_grid = match settings.volatility_model {
+        VolatilityModel::LocalVolatility => {
+            Some(LocalVolatilityGrid::build(smile_graphs, option.years_until_expiry, settings.steps)?)
+        }
+        VolatilityModel::FlatImpliedVolatility => None,
+    };
+    let flat_volatility = interpolate_surface(smile_graphs, option.strike, option.years_until_expiry)?.implied_volatility;
+
+    let get_intrinsic_value = |spot_price: f64| match option.option_type {
+        OptionType::Call => (spot_price - option.strike).max(0.0),
+        OptionType::Put => (option.strike - spot_price).max(0.0),
+    };
+    let has_crossed_barrier = |spot_price: f64| match option.payoff {
+        Payoff::Barrier { barrier, barrier_type } => match barrier_type {
+            BarrierType::UpAndOut | BarrierType::UpAndIn => spot_price >= barrier,
+            BarrierType::DownAndOut | BarrierType::DownAndIn => spot_price <= barrier,
+        },
+        _ => false,
+    };
+
+    let mut rng = StdRng::seed_from_u64(settings.seed);
+    let mut payoff_sum = 0.0;
+    let mut payoff_squared_sum = 0.0;
+
+    for _ in 0..settings.paths {
+        let mut log_spot_price = spot_price.ln();
+        let mut spot_price_sum = 0.0;
+        let mut crossed_barrier = has_crossed_barrier(spot_price);
+
+        for step in 0..steps {
+            let volatility = match &local_volatility_grid {
+                Some(grid) => {
+                    let log_moneyness = log_spot_price - spot_price.ln() - rate * step as f64 * step_length;
+                    grid.get_volatility(step, log_moneyness)
+                }
+                None => flat_volatility,
+            };
+            let random: f64 = StandardNormal.sample(&mut rng);
+
+            log_spot_price += (rate - 0.5 * volatility * volatility) * step_length + volatility * step_length.sqrt() * random;
+
+            let path_spot_price = log_spot_price.exp();
+            spot_price_sum += path_spot_price;
+            crossed_barrier |= has_crossed_barrier(path_spot_price);
+        }
+
+        let final_spot_price = log_spot_price.exp();
+        let payoff = match option.payoff {
+            Payoff::European => get_intrinsic_value(final_spot_price),
+            Payoff::Asian => get_intrinsic_value(spot_price_sum / steps as f64),
+            Payoff::Barrier { barrier_type, .. } => {
+                let knocked_in = match barrier_type {
+                    BarrierType::UpAndOut | BarrierType::DownAndOut => !crossed_barrier,
+                    BarrierType::UpAndIn | BarrierType::DownAndIn => crossed_barrier,
+                };
+
+                match knocked_in {
+                    true => get_intrinsic_value(final_spot_price),
+                    false => 0.0,
+                }
+            }
+        };
+
+        payoff_sum += payoff;
+        payoff_squared_sum += payoff * payoff;
+    }
+
+    let paths = settings.paths as f64;
+    let mean = payoff_sum / paths;
+    let variance = ((payoff_squared_sum - paths * mean * mean) / (paths - 1.0)).max(0.0);
+    let discount = E.powf(-rate * option.years_until_expiry);
+
+    Ok(SimulationResult {
+        price: mean * discount,
+        standard_error: (variance / paths).sqrt() * discount,
+    })
+}
+
+/// The spot price implied by the forward price of the first smile.
+fn get_spot_price(smile_graphs: &[SmileGraph]) -> Result<f64, TsError> {
+    let first_graph = smile_graphs
+        .first()
+        .ok_or(TsError::new(RuntimeError, "The surface has no smiles"))?;
+
+    Ok(first_graph.get_underlying_forward_price()?
+        * E.powf(-constants::INTEREST_FREE_RATE * first_graph.get_years_until_expiry()?))
+}
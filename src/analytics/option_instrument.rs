@@ -1,16 +1,17 @@
-use std::cell::Cell;
+use std::{f64::consts::E, sync::OnceLock};
 
 use chrono::{DateTime, Utc};
 
 use crate::{
-    analytics::{OptionType, math},
-    constants, helpers,
+    analytics::{ImpliedVolatilitySource, OptionType, PricingModel, math},
+    constants,
+    helpers::Clock,
     types::{TsError, TsErrorType::RuntimeError, TsErrorType::UnsolvableError},
 };
 
 /// In the real world we usually wouldn't use f64 for money fields etc. But since this is just for the purpose of market
 /// analysis it's probably a good idea to do it this way as it's much faster.
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct OptionInstrument {
     pub strike: f64,
     pub price: f64,
@@ -18,11 +19,45 @@ pub struct OptionInstrument {
     pub option_type: OptionType,
     pub spot_price: f64,
     pub expiry_seconds: u64,
+    /// The best bid and ask prices, if there are any. Missing from data saved by older versions.
+    #[serde(default)]
+    pub bid_price: Option<f64>,
+    #[serde(default)]
+    pub ask_price: Option<f64>,
+    /// The forward price of the underlying at expiry, if it was found from the market (see
+    /// analytics::determine_forward_price()). If not, the spot price is carried forward at the interest free rate. Missing
+    /// from data saved by older versions.
+    #[serde(default)]
+    pub forward_price: Option<f64>,
+    /// How the price is turned into an implied volatility. Missing from data saved by older versions, which used
+    /// Black-Scholes.
+    #[serde(default)]
+    pub pricing_model: PricingModel,
+    /// The exchange's mark implied volatility, if it gave one. Missing from data saved by older versions.
+    #[serde(default)]
+    pub exchange_implied_volatility: Option<f64>,
+    /// Whether the implied volatility is solved from the price or is the exchange's. Missing from data saved by older
+    /// versions, which always solved it.
+    #[serde(default)]
+    pub implied_volatility_source: ImpliedVolatilitySource,
+    /// The number of contracts open, if known. Missing from data saved by older versions.
+    #[serde(default)]
+    pub open_interest: Option<f64>,
+    /// The number of contracts traded in the last 24 hours, if known. Missing from data saved by older versions.
+    #[serde(default)]
+    pub volume: Option<f64>,
+    /// When the exchange quoted the option, if known. Options aren't all quoted at once, so some quotes can be minutes
+    /// older than others. Missing from data saved by older versions.
+    #[serde(default)]
+    pub quote_time: Option<DateTime<Utc>>,
 
+    /// What times until expiry are measured from. Not saved, so is the real clock when loaded from file.
     #[serde(skip)]
-    implied_volatility: Cell<Option<f64>>,
+    clock: Clock,
     #[serde(skip)]
-    total_implied_variance: Cell<Option<f64>>,
+    implied_volatility: OnceLock<f64>,
+    #[serde(skip)]
+    total_implied_variance: OnceLock<f64>,
 }
 
 impl OptionInstrument {
@@ -33,6 +68,7 @@ impl OptionInstrument {
         instrument_id: Box<str>,
         option_type: OptionType,
         spot_price: f64,
+        clock: Clock,
     ) -> Self {
         Self {
             expiry_seconds,
@@ -41,8 +77,18 @@ impl OptionInstrument {
             instrument_id,
             option_type,
             spot_price,
-            implied_volatility: Cell::new(None),
-            total_implied_variance: Cell::new(None),
+            bid_price: None,
+            ask_price: None,
+            forward_price: None,
+            pricing_model: PricingModel::BlackScholes,
+            exchange_implied_volatility: None,
+            implied_volatility_source: ImpliedVolatilitySource::Solve,
+            open_interest: None,
+            volume: None,
+            quote_time: None,
+            clock,
+            implied_volatility: OnceLock::new(),
+            total_implied_variance: OnceLock::new(),
         }
     }
 
@@ -52,46 +98,156 @@ impl OptionInstrument {
     }
 
     pub fn get_years_until_expiry(&self) -> Result<f64, TsError> {
-        Ok((self.get_expiration()? - helpers::get_now()).num_seconds() as f64 / 31556926.0)
+        Ok((self.get_expiration()? - self.clock.now()).num_seconds() as f64 / 31556926.0)
+    }
+
+    /// Change the option's price, forgetting anything calculated from the previous price.
+    pub fn set_price(&mut self, price: f64) {
+        self.price = price;
+        self.implied_volatility = OnceLock::new();
+        self.total_implied_variance = OnceLock::new();
+    }
+
+    /// Change the forward price of the underlying, forgetting anything calculated from the previous one.
+    pub fn set_forward_price(&mut self, forward_price: f64) {
+        self.forward_price = Some(forward_price);
+        self.implied_volatility = OnceLock::new();
+        self.total_implied_variance = OnceLock::new();
+    }
+
+    pub fn get_forward_price(&self) -> Result<f64, TsError> {
+        match self.forward_price {
+            Some(forward_price) => Ok(forward_price),
+            None => Ok(self.spot_price * E.powf(constants::INTEREST_FREE_RATE * self.get_years_until_expiry()?)),
+        }
+    }
+
+    /// The spot price that the forward price implies at the interest free rate, which is what the option is priced from.
+    /// This is the spot price itself unless the forward price was found from the market.
+    pub fn get_implied_spot_price(&self) -> Result<f64, TsError> {
+        match self.forward_price {
+            Some(forward_price) => Ok(forward_price * E.powf(-constants::INTEREST_FREE_RATE * self.get_years_until_expiry()?)),
+            None => Ok(self.spot_price),
+        }
+    }
+
+    pub fn get_clock(&self) -> Clock {
+        self.clock
+    }
+
+    /// Change what times until expiry are measured from, forgetting anything calculated with the previous clock.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+        self.implied_volatility = OnceLock::new();
+        self.total_implied_variance = OnceLock::new();
     }
 
     pub fn get_implied_volatility(&self) -> Result<f64, TsError> {
         if let Some(iv) = self.implied_volatility.get() {
-            return Ok(iv);
+            return Ok(*iv);
         };
 
-        let implied_volatility = math::calculate_bs_implied_volatility(
-            self.spot_price,
-            self.strike,
-            self.get_years_until_expiry()?,
-            constants::INTEREST_FREE_RATE,
-            self.price,
-            self.option_type,
-        )
-        .map_err(|e| {
-            TsError::new(
+        let implied_volatility = match self.implied_volatility_source {
+            ImpliedVolatilitySource::Solve => self.get_solved_implied_volatility()?,
+            ImpliedVolatilitySource::Exchange => self.exchange_implied_volatility.ok_or(TsError::new(
                 UnsolvableError,
-                format!("Failed calculating implied volatility for instrument {}: {}", &self.instrument_id, e.reason),
-            )
-        })?;
+                format!("Instrument {} has no exchange implied volatility", &self.instrument_id),
+            ))?,
+        };
 
-        self.implied_volatility.set(Some(implied_volatility));
+        // Another thread may have set it first, to the same value.
+        let _ = self.implied_volatility.set(implied_volatility);
         Ok(implied_volatility)
     }
 
+    /// The implied volatility solved from the option's price, whatever its implied volatility source.
+    pub fn get_solved_implied_volatility(&self) -> Result<f64, TsError> {
+        self.get_implied_volatility_at_price(self.price)
+            .map_err(|e| {
+                TsError::new(
+                    UnsolvableError,
+                    format!("Failed calculating implied volatility for instrument {}: {}", &self.instrument_id, e.reason),
+                )
+            })
+    }
+
+    /// The implied volatility the option would have at the given price (in USD), e.g. its bid or ask, using its pricing
+    /// model.
+    pub fn get_implied_volatility_at_price(&self, price: f64) -> Result<f64, TsError> {
+        match self.pricing_model {
+            PricingModel::BlackScholes => math::calculate_bs_implied_volatility(
+                self.get_implied_spot_price()?,
+                self.strike,
+                self.get_years_until_expiry()?,
+                constants::INTEREST_FREE_RATE,
+                // Any yield is already in the forward price the spot price is implied from.
+                0.0,
+                price,
+                self.option_type,
+            ),
+            // USD prices of inverse options are their coin prices at the spot price.
+            PricingModel::Inverse => math::calculate_inverse_implied_volatility(
+                self.get_forward_price()?,
+                self.strike,
+                self.get_years_until_expiry()?,
+                price / self.spot_price,
+                self.option_type,
+            ),
+        }
+    }
+
+    /// The price (in USD) the option would have at the given implied volatility, using its pricing model. The inverse of
+    /// get_implied_volatility_at_price().
+    pub fn get_price_at_implied_volatility(&self, implied_volatility: f64) -> Result<f64, TsError> {
+        match self.pricing_model {
+            PricingModel::BlackScholes => math::calculate_black_scholes(
+                self.get_implied_spot_price()?,
+                self.strike,
+                self.get_years_until_expiry()?,
+                constants::INTEREST_FREE_RATE,
+                0.0,
+                implied_volatility,
+                self.option_type,
+            ),
+            PricingModel::Inverse => Ok(math::calculate_inverse_option_price(
+                self.get_forward_price()?,
+                self.strike,
+                self.get_years_until_expiry()?,
+                implied_volatility,
+                self.option_type,
+            )? * self.spot_price),
+        }
+    }
+
+    /// Change the exchange's implied volatility, forgetting anything calculated from the previous one.
+    pub fn set_exchange_implied_volatility(&mut self, implied_volatility: f64) {
+        self.exchange_implied_volatility = Some(implied_volatility);
+        self.implied_volatility = OnceLock::new();
+        self.total_implied_variance = OnceLock::new();
+    }
+
     pub fn get_total_implied_variance(&self) -> Result<f64, TsError> {
         if let Some(tiv) = self.total_implied_variance.get() {
-            return Ok(tiv);
+            return Ok(*tiv);
         }
 
         let implied_volatility = self.get_implied_volatility()?;
         let total_implied_variance = (implied_volatility * implied_volatility) * self.get_years_until_expiry()?;
 
-        self.total_implied_variance
-            .set(Some(total_implied_variance));
+        let _ = self.total_implied_variance.set(total_implied_variance);
         Ok(total_implied_variance)
     }
 
+    /// Whether the option is out of the money at its forward price: a put below it, or a call at or above it.
+    pub fn is_out_of_the_money(&self) -> Result<bool, TsError> {
+        let below_forward = self.strike < self.get_forward_price()?;
+
+        Ok(match self.option_type {
+            OptionType::Put => below_forward,
+            OptionType::Call => !below_forward,
+        })
+    }
+
     pub fn get_log_moneyness_using_custom_forward(&self, forward_price: f64) -> f64 {
         (self.strike / forward_price).ln()
     }
@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    analytics::{ImpliedVolatilitySource, OptionInstrument, OptionType, PutCallPairing, PutCallParitySettings},
+    types::TsError,
+};
+
+/// A call and put at the same strike whose implied volatilities are further apart than the tolerance allows.
+pub struct ParityViolation {
+    pub strike: f64,
+    pub call_instrument_id: Box<str>,
+    pub put_instrument_id: Box<str>,
+    pub call_implied_volatility: f64,
+    pub put_implied_volatility: f64,
+}
+
+/// What apply_put_call_parity() found and changed.
+pub struct ParityCheck {
+    /// The number of strikes with both a call and a put.
+    pub pairs: usize,
+    pub violations: Vec<ParityViolation>,
+    /// The options taken out because the other option of their pair was fit to instead.
+    pub removed_instruments: Vec<Box<str>>,
+    /// The number of pairs merged into a single option.
+    pub merged_pairs: usize,
+}
+
+/// Compare the implied volatilities of each call and put at the same strike in the given options, which must all have the
+/// same expiry and their forward prices set. For each pair the put-call parity says they should be the same, so pairs
+/// further apart than the tolerance are reported. Then, depending on the pairing, either both are kept, only the out of
+/// the money one is, or the out of the money one is kept at the mean of their implied volatilities.
+///
+/// Pairs whose implied volatilities can't both be found aren't compared, and can't be merged, so only the out of the money
+/// one is kept when merging.
+pub fn apply_put_call_parity(
+    options: &mut Vec<OptionInstrument>,
+    settings: &PutCallParitySettings,
+) -> Result<ParityCheck, TsError> {
+    // Keyed by the strike's bits, so that pairs are found in strike order.
+    let mut strikes: BTreeMap<u64, (Option<usize>, Option<usize>)> = BTreeMap::new();
+
+    for (i, option) in options.iter().enumerate() {
+        let entry = strikes.entry(option.strike.to_bits()).or_default();
+
+        match option.option_type {
+            OptionType::Call => entry.0 = entry.0.or(Some(i)),
+            OptionType::Put => entry.1 = entry.1.or(Some(i)),
+        }
+    }
+
+    let mut check = ParityCheck {
+        pairs: 0,
+        violations: Vec::new(),
+        removed_instruments: Vec::new(),
+        merged_pairs: 0,
+    };
+
+    for (call, put) in strikes.into_values() {
+        let (Some(call), Some(put)) = (call, put) else {
+            continue;
+        };
+
+        check.pairs += 1;
+
+        let implied_volatilities = (options[call].get_implied_volatility(), options[put].get_implied_volatility());
+
+        if let (Ok(call_implied_volatility), Ok(put_implied_volatility)) = implied_volatilities
+            && (call_implied_volatility - put_implied_volatility).abs() > settings.tolerance
+        {
+            check.violations.push(ParityViolation {
+                strike: options[call].strike,
+                call_instrument_id: options[call].instrument_id.clone(),
+                put_instrument_id: options[put].instrument_id.clone(),
+                call_implied_volatility,
+                put_implied_volatility,
+            });
+        }
+
+        if settings.pairing == PutCallPairing::Both {
+            continue;
+        }
+
+        let (out_of_the_money, in_the_money) = match options[put].is_out_of_the_money()? {
+            true => (put, call),
+            false => (call, put),
+        };
+
+        if settings.pairing == PutCallPairing::Merge
+            && let (Ok(call_implied_volatility), Ok(put_implied_volatility)) = implied_volatilities
+        {
+            // A call and put at the same strike have the same vega, so neither's implied volatility is more sensitive to
+            // its price than the other's.
+            let merged = (call_implied_volatility + put_implied_volatility) / 2.0;
+            let option = &mut options[out_of_the_money];
+
+            match option.implied_volatility_source {
+                ImpliedVolatilitySource::Solve => option.set_price(option.get_price_at_implied_volatility(merged)?),
+                ImpliedVolatilitySource::Exchange => option.set_exchange_implied_volatility(merged),
+            }
+
+            check.merged_pairs += 1;
+        }
+
+        check
+            .removed_instruments
+            .push(options[in_the_money].instrument_id.clone());
+    }
+
+    options.retain(|x| !check.removed_instruments.contains(&x.instrument_id));
+
+    Ok(check)
+}
@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+use std::f64::consts::E;
+
+use crate::{
+    analytics::{OptionInstrument, OptionType},
+    types::TsError,
+};
+
+/// A kind of static arbitrage that can be found in the quotes of a snapshot.
+#[derive(Clone, Copy, PartialEq, Debug, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuoteArbitrageType {
+    /// Two options of the same type and expiry whose prices aren't monotonic in strike, or are further apart than the
+    /// strikes are.
+    VerticalSpread,
+    /// Three options of the same type and expiry whose prices aren't convex in strike.
+    Butterfly,
+    /// A call spread and a put spread on the same strikes, which together pay the difference between the strikes, priced
+    /// away from its discounted value.
+    Box,
+    /// Two options of the same type and strike whose nearer expiry is worth more than the later one.
+    Calendar,
+}
+
+impl QuoteArbitrageType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            QuoteArbitrageType::VerticalSpread => "vertical spread",
+            QuoteArbitrageType::Butterfly => "butterfly",
+            QuoteArbitrageType::Box => "box",
+            QuoteArbitrageType::Calendar => "calendar",
+        }
+    }
+}
+
+/// One of the trades that make up an arbitrage.
+#[derive(serde::Serialize)]
+pub struct QuoteArbitrageLeg {
+    pub instrument_id: Box<str>,
+    /// How many are bought (positive) or sold (negative), at the ask or bid respectively.
+    pub quantity: f64,
+}
+
+/// Trades that could have been made at the quoted bids and asks for a profit that doesn't depend on where the underlying
+/// goes.
+#[derive(serde::Serialize)]
+pub struct QuoteArbitrage {
+    pub arbitrage_type: QuoteArbitrageType,
+    pub legs: Vec<QuoteArbitrageLeg>,
+    /// The least the trades make, in USD at today's value, before fees.
+    pub edge: f64,
+}
+
+impl QuoteArbitrage {
+    fn new(arbitrage_type: QuoteArbitrageType, legs: &[(&OptionInstrument, f64)], edge: f64) -> QuoteArbitrage {
+        QuoteArbitrage {
+            arbitrage_type,
+            legs: legs
+                .iter()
+                .map(|(option, quantity)| QuoteArbitrageLeg {
+                    instrument_id: option.instrument_id.clone(),
+                    quantity: *quantity,
+                })
+                .collect(),
+            edge,
+        }
+    }
+}
+
+/// Find the vertical spread, butterfly, box and calendar arbitrages in the bids and asks of the options, before anything is
+/// fit, sorted by edge with the largest first. Spreads and butterflies are checked between neighbouring strikes, boxes
+/// between neighbouring strikes with both a call and a put, and calendars between neighbouring expiries at the same strike.
+/// Strike differences are discounted at the interest rate. Expired options and quotes without a bid or ask where one is
+/// needed are skipped.
+///
+/// Calendars are only checked where they hold whatever the interest rate: calls where the later expiry's forward price is
+/// at least the earlier one's, and puts where it is at most.
+pub fn scan_quote_arbitrage(options: &[OptionInstrument], interest_rate: f64) -> Result<Vec<QuoteArbitrage>, TsError> {
+    let mut expiries: BTreeMap<u64, Vec<&OptionInstrument>> = BTreeMap::new();
+
+    for option in options {
+        if option.get_years_until_expiry()? > 0.0 {
+            expiries
+                .entry(option.expiry_seconds)
+                .or_default()
+                .push(option);
+        }
+    }
+
+    for options in expiries.values_mut() {
+        options.sort_by(|a, b| a.strike.total_cmp(&b.strike));
+    }
+
+    let mut arbitrages = Vec::new();
+
+    for options in expiries.values() {
+        let discount_factor = E.powf(-interest_rate * options[0].get_years_until_expiry()?);
+
+        for option_type in [OptionType::Call, OptionType::Put] {
+            let strikes = options
+                .iter()
+                .filter(|x| x.option_type == option_type)
+                .copied()
+                .collect::<Vec<&OptionInstrument>>();
+
+            for pair in strikes.windows(2) {
+                scan_vertical_spread(pair[0], pair[1], discount_factor, &mut arbitrages);
+            }
+
+            for triple in strikes.windows(3) {
+                scan_butterfly(triple[0], triple[1], triple[2], &mut arbitrages);
+            }
+        }
+
+        let pairs = get_call_put_pairs(options);
+
+        for pair in pairs.windows(2) {
+            scan_box(pair[0], pair[1], discount_factor, &mut arbitrages);
+        }
+    }
+
+    let expiries = expiries
+        .into_values()
+        .collect::<Vec<Vec<&OptionInstrument>>>();
+
+    for pair in expiries.windows(2) {
+        for near in &pair[0] {
+            if let Some(far) = pair[1]
+                .iter()
+                .find(|x| x.option_type == near.option_type && x.strike == near.strike)
+            {
+                scan_calendar(near, far, &mut arbitrages)?;
+            }
+        }
+    }
+
+    arbitrages.sort_by(|a, b| b.edge.total_cmp(&a.edge));
+
+    Ok(arbitrages)
+}
+
+/// Options of the same type and expiry, with the lower strike first. A call spread can't be worth less than nothing or
+/// more than the discounted difference between the strikes, and likewise a put spread.
+fn scan_vertical_spread(
+    lower: &OptionInstrument,
+    upper: &OptionInstrument,
+    discount_factor: f64,
+    arbitrages: &mut Vec<QuoteArbitrage>,
+) {
+    let (cheaper, dearer) = match lower.option_type {
+        OptionType::Call => (upper, lower),
+        OptionType::Put => (lower, upper),
+    };
+    let most_spread_pays = discount_factor * (upper.strike - lower.strike);
+
+    // Selling the option that should be cheaper for more than buying the dearer one costs.
+    if let (Some(bid), Some(ask)) = (cheaper.bid_price, dearer.ask_price)
+        && bid > ask
+    {
+        arbitrages.push(QuoteArbitrage::new(
+            QuoteArbitrageType::VerticalSpread,
+            &[(dearer, 1.0), (cheaper, -1.0)],
+            bid - ask,
+        ));
+    }
+
+    // Selling the dearer option for more than buying the cheaper one costs plus the most the spread can pay out.
+    if let (Some(bid), Some(ask)) = (dearer.bid_price, cheaper.ask_price)
+        && bid - ask > most_spread_pays
+    {
+        arbitrages.push(QuoteArbitrage::new(
+            QuoteArbitrageType::VerticalSpread,
+            &[(dearer, -1.0), (cheaper, 1.0)],
+            bid - ask - most_spread_pays,
+        ));
+    }
+}
+
+/// Options of the same type and expiry, in order of strike. Buying the wings and selling the body can't pay out less than
+/// nothing, so it can't be bought for less than nothing. The wings are weighted so that uneven strikes still work.
+fn scan_butterfly(
+    lower: &OptionInstrument,
+    middle: &OptionInstrument,
+    upper: &OptionInstrument,
+    arbitrages: &mut Vec<QuoteArbitrage>,
+) {
+    let (Some(lower_ask), Some(middle_bid), Some(upper_ask)) = (lower.ask_price, middle.bid_price, upper.ask_price) else {
+        return;
+    };
+    let lower_weight = (upper.strike - middle.strike) / (upper.strike - lower.strike);
+    let upper_weight = (middle.strike - lower.strike) / (upper.strike - lower.strike);
+    let edge = middle_bid - lower_weight * lower_ask - upper_weight * upper_ask;
+
+    if edge > 0.0 {
+        arbitrages.push(QuoteArbitrage::new(
+            QuoteArbitrageType::Butterfly,
+            &[(lower, lower_weight), (middle, -1.0), (upper, upper_weight)],
+            edge,
+        ));
+    }
+}
+
+/// The (call, put) pairs of options at the same strike, in order of strike.
+fn get_call_put_pairs<'a>(options: &[&'a OptionInstrument]) -> Vec<(&'a OptionInstrument, &'a OptionInstrument)> {
+    options
+        .iter()
+        .filter(|x| x.option_type == OptionType::Call)
+        .filter_map(|call| {
+            options
+                .iter()
+                .find(|x| x.option_type == OptionType::Put && x.strike == call.strike)
+                .map(|put| (*call, *put))
+        })
+        .collect()
+}
+
+/// Calls and puts of the same expiry at a lower and upper strike. Buying the lower call and upper put and selling the
+/// others pays the difference between the strikes whatever happens, so it's worth that discounted.
+fn scan_box(
+    (lower_call, lower_put): (&OptionInstrument, &OptionInstrument),
+    (upper_call, upper_put): (&OptionInstrument, &OptionInstrument),
+    discount_factor: f64,
+    arbitrages: &mut Vec<QuoteArbitrage>,
+) {
+    let value = discount_factor * (upper_call.strike - lower_call.strike);
+
+    if let (Some(lower_call_ask), Some(upper_call_bid), Some(lower_put_bid), Some(upper_put_ask)) =
+        (lower_call.ask_price, upper_call.bid_price, lower_put.bid_price, upper_put.ask_price)
+    {
+        let cost = lower_call_ask - upper_call_bid - lower_put_bid + upper_put_ask;
+
+        if cost < value {
+            arbitrages.push(QuoteArbitrage::new(
+                QuoteArbitrageType::Box,
+                &[(lower_call, 1.0), (upper_call, -1.0), (lower_put, -1.0), (upper_put, 1.0)],
+                value - cost,
+            ));
+        }
+    }
+
+    if let (Some(lower_call_bid), Some(upper_call_ask), Some(lower_put_ask), Some(upper_put_bid)) =
+        (lower_call.bid_price, upper_call.ask_price, lower_put.ask_price, upper_put.bid_price)
+    {
+        let proceeds = lower_call_bid - upper_call_ask - lower_put_ask + upper_put_bid;
+
+        if proceeds > value {
+            arbitrages.push(QuoteArbitrage::new(
+                QuoteArbitrageType::Box,
+                &[(lower_call, -1.0), (upper_call, 1.0), (lower_put, 1.0), (upper_put, -1.0)],
+                proceeds - value,
+            ));
+        }
+    }
+}
+
+/// Options of the same type and strike, the near one expiring first. The later option can be held past the near one's
+/// expiry, so it can't be worth less, as long as its forward price doesn't make it less likely to pay out.
+fn scan_calendar(near: &OptionInstrument, far: &OptionInstrument, arbitrages: &mut Vec<QuoteArbitrage>) -> Result<(), TsError> {
+    let (near_forward_price, far_forward_price) = (near.get_forward_price()?, far.get_forward_price()?);
+    let forwards_allow = match near.option_type {
+        OptionType::Call => far_forward_price >= near_forward_price,
+        OptionType::Put => far_forward_price <= near_forward_price,
+    };
+
+    if let (true, Some(bid), Some(ask)) = (forwards_allow, near.bid_price, far.ask_price)
+        && bid > ask
+    {
+        arbitrages.push(QuoteArbitrage::new(QuoteArbitrageType::Calendar, &[(near, -1.0), (far, 1.0)], bid - ask));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use chrono::{DateTime, Utc};
+
+use crate::{
+    analytics::{ImpliedVolatilitySource, OptionInstrument, OptionType, PricingModel},
+    helpers::Clock,
+    types::TsError,
+};
+
+/// Options stored column by column rather than one struct per option. The fitting loops go over every option many times
+/// per fit, and reading contiguous columns of just the numbers they need is much kinder to the cache than striding over
+/// whole OptionInstruments, which matters for smiles with hundreds of strikes. Each row is one option, in the order the
+/// options were given.
+#[derive(Clone, Default)]
+pub struct QuoteTable {
+    pub instrument_id: Vec<Box<str>>,
+    pub option_type: Vec<OptionType>,
+    pub strike: Vec<f64>,
+    pub price: Vec<f64>,
+    pub spot_price: Vec<f64>,
+    pub expiry_seconds: Vec<u64>,
+    pub bid_price: Vec<Option<f64>>,
+    pub ask_price: Vec<Option<f64>>,
+    pub forward_price: Vec<Option<f64>>,
+    pub pricing_model: Vec<PricingModel>,
+    pub exchange_implied_volatility: Vec<Option<f64>>,
+    pub implied_volatility_source: Vec<ImpliedVolatilitySource>,
+    pub open_interest: Vec<Option<f64>>,
+    pub volume: Vec<Option<f64>>,
+    pub quote_time: Vec<Option<DateTime<Utc>>>,
+    pub clock: Vec<Clock>,
+    /// The log moneyness at the forward price the table was made with.
+    pub log_moneyness: Vec<f64>,
+    pub total_implied_variance: Vec<f64>,
+}
+
+impl QuoteTable {
+    /// Make a table of the options, with log moneyness measured from the given forward price. Fails if any option's total
+    /// implied variance can't be calculated.
+    pub fn from_options(options: &[OptionInstrument], forward_price: f64) -> Result<Self, TsError> {
+        let mut table = Self::default();
+
+        for option in options {
+            table.instrument_id.push(option.instrument_id.clone());
+            table.option_type.push(option.option_type);
+            table.strike.push(option.strike);
+            table.price.push(option.price);
+            table.spot_price.push(option.spot_price);
+            table.expiry_seconds.push(option.expiry_seconds);
+            table.bid_price.push(option.bid_price);
+            table.ask_price.push(option.ask_price);
+            table.forward_price.push(option.forward_price);
+            table.pricing_model.push(option.pricing_model);
+            table
+                .exchange_implied_volatility
+                .push(option.exchange_implied_volatility);
+            table
+                .implied_volatility_source
+                .push(option.implied_volatility_source);
+            table.open_interest.push(option.open_interest);
+            table.volume.push(option.volume);
+            table.quote_time.push(option.quote_time);
+            table.clock.push(option.get_clock());
+            table
+                .log_moneyness
+                .push(option.get_log_moneyness_using_custom_forward(forward_price));
+            table
+                .total_implied_variance
+                .push(option.get_total_implied_variance()?);
+        }
+
+        Ok(table)
+    }
+
+    /// The options the table was made from. Anything calculated from them, such as implied volatility, is calculated again
+    /// when next needed.
+    pub fn to_options(&self) -> Vec<OptionInstrument> {
+        (0..self.len())
+            .map(|i| {
+                let mut option = OptionInstrument::new(
+                    self.price[i],
+                    self.expiry_seconds[i],
+                    self.strike[i],
+                    self.instrument_id[i].clone(),
+                    self.option_type[i],
+                    self.spot_price[i],
+                    self.clock[i],
+                );
+
+                option.bid_price = self.bid_price[i];
+                option.ask_price = self.ask_price[i];
+                option.forward_price = self.forward_price[i];
+                option.pricing_model = self.pricing_model[i];
+                option.exchange_implied_volatility = self.exchange_implied_volatility[i];
+                option.implied_volatility_source = self.implied_volatility_source[i];
+                option.open_interest = self.open_interest[i];
+                option.volume = self.volume[i];
+                option.quote_time = self.quote_time[i];
+
+                option
+            })
+            .collect()
+    }
+
+    /// The number of options (rows) in the table.
+    pub fn len(&self) -> usize {
+        self.strike.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strike.is_empty()
+    }
+}
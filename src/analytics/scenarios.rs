@@ -0,0 +1,189 @@
+use std::f64::consts::E;
+
+use chrono::{DateTime, Utc};
+
+use crate::analytics::{OptionType, SmileGraph, calculate_black_scholes, interpolate_surface};
+use crate::constants;
+use crate::helpers::{error_unless_positive_f64, error_unless_valid_f64};
+use crate::types::TsError;
+use crate::types::TsErrorType::{RuntimeError, UnsolvableError};
+
+/// How implied volatilities move when the spot price moves.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpotMoveRule {
+    /// Each strike keeps its implied volatility, so the forward moves along an unchanged smile.
+    #[default]
+    StickyStrike,
+    /// Each delta (for a given expiry, each moneyness) keeps its implied volatility, so the smile moves with the forward.
+    StickyDelta,
+}
+
+/// Shocks applied to the surface together, each of which is optional.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScenarioShock {
+    pub name: String,
+    /// Added to every implied volatility, e.g. 0.05 for 5 vol points.
+    pub volatility_shift: f64,
+    /// Added to implied volatility per unit of log moneyness (from the shocked forward), turning the smile about the
+    /// forward. Negative values steepen the put skew, e.g. -0.1 adds 1 vol point 10% below the forward.
+    pub skew_twist: f64,
+    /// The relative move in the spot price, e.g. -0.2 for a 20% fall. Forward prices move by the same proportion.
+    pub spot_move: f64,
+    pub spot_move_rule: SpotMoveRule,
+    /// The number of days that pass. Options roll down the term structure, reading the surface at their shorter time to
+    /// expiry, and options that expire are worth their intrinsic value.
+    pub days_passed: f64,
+}
+
+/// The surface after a shock, read at a strike and expiry.
+pub struct ShockedPoint {
+    pub years_until_expiry: f64,
+    pub forward_price: f64,
+    pub implied_volatility: f64,
+}
+
+impl ScenarioShock {
+    pub fn check_valid(&self) -> Result<(), TsError> {
+        error_unless_valid_f64(self.volatility_shift, "volatility_shift")?;
+        error_unless_valid_f64(self.skew_twist, "skew_twist")?;
+        error_unless_valid_f64(self.spot_move, "spot_move")?;
+        error_unless_valid_f64(self.days_passed, "days_passed")?;
+
+        if self.spot_move <= -1.0 {
+            return Err(TsError::new(RuntimeError, format!("{}: spot_move must be greater than -1", self.name)));
+        }
+        if self.days_passed < 0.0 {
+            return Err(TsError::new(RuntimeError, format!("{}: days_passed can't be negative", self.name)));
+        }
+
+        Ok(())
+    }
+
+    fn get_years_passed(&self) -> f64 {
+        self.days_passed * 86400.0 / 31556926.0
+    }
+
+    /// Read the shocked surface at a strike and time to expiry, which is measured from before any days pass. The smile
+    /// graphs must be ordered by expiry.
+    pub fn get_shocked_point(
+        &self,
+        smile_graphs: &[SmileGraph],
+        strike: f64,
+        years_until_expiry: f64,
+    ) -> Result<ShockedPoint, TsError> {
+        error_unless_positive_f64(strike, "strike")?;
+
+        let remaining_years = years_until_expiry - self.get_years_passed();
+
+        if remaining_years <= 0.0 {
+            return Err(TsError::new(UnsolvableError, "The option expires during the scenario"));
+        }
+
+        let spot_multiplier = 1.0 + self.spot_move;
+        // With sticky delta, the shocked smile at a strike is the unshocked smile at the same moneyness.
+        let unshocked_strike = match self.spot_move_rule {
+            SpotMoveRule::StickyStrike => strike,
+            SpotMoveRule::StickyDelta => strike / spot_multiplier,
+        };
+        let point = interpolate_surface(smile_graphs, unshocked_strike, remaining_years)?;
+        let forward_price = point.forward_price * spot_multiplier;
+        let implied_volatility =
+            point.implied_volatility + self.volatility_shift + self.skew_twist * (strike / forward_price).ln();
+
+        if implied_volatility <= 0.0 {
+            return Err(TsError::new(
+                UnsolvableError,
+                format!("The shocked implied volatility at strike {strike} isn't positive ({implied_volatility})"),
+            ));
+        }
+
+        Ok(ShockedPoint {
+            years_until_expiry: remaining_years,
+            forward_price,
+            implied_volatility,
+        })
+    }
+
+    /// Price a European option on the shocked surface with Black-Scholes. The smile graphs must be ordered by expiry.
+    pub fn price_option(
+        &self,
+        smile_graphs: &[SmileGraph],
+        strike: f64,
+        years_until_expiry: f64,
+        option_type: OptionType,
+    ) -> Result<f64, TsError> {
+        let rate = constants::INTEREST_FREE_RATE;
+
+        if years_until_expiry <= self.get_years_passed() {
+            let spot_price = get_spot_price(smile_graphs)? * (1.0 + self.spot_move);
+
+            return Ok(match option_type {
+                OptionType::Call => (spot_price - strike).max(0.0),
+                OptionType::Put => (strike - spot_price).max(0.0),
+            });
+        }
+
+        let point = self.get_shocked_point(smile_graphs, strike, years_until_expiry)?;
+        let spot_price = point.forward_price * E.powf(-rate * point.years_until_expiry);
+
+        calculate_black_scholes(spot_price, strike, point.years_until_expiry, rate, 0.0, point.implied_volatility, option_type)
+    }
+}
+
+/// The spot price implied by the first smile's forward price.
+fn get_spot_price(smile_graphs: &[SmileGraph]) -> Result<f64, TsError> {
+    let first_graph = smile_graphs
+        .first()
+        .ok_or(TsError::new(RuntimeError, "The surface has no smiles"))?;
+
+    Ok(first_graph.get_underlying_forward_price()?
+        * E.powf(-constants::INTEREST_FREE_RATE * first_graph.get_years_until_expiry()?))
+}
+
+/// An option held in a portfolio. A negative quantity is a short position.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PortfolioPosition {
+    pub strike: f64,
+    pub expiry: DateTime<Utc>,
+    pub option_type: OptionType,
+    pub quantity: f64,
+}
+
+/// What a position is worth before and after a shock, per option and in total.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PositionValuation {
+    pub position: PortfolioPosition,
+    pub base_price: f64,
+    pub shocked_price: f64,
+    /// The change in the position's value, i.e. the quantity times the change in price.
+    pub pnl: f64,
+}
+
+/// Value each position on the surface as it is and after the shock. Times until expiry are measured from the given time.
+/// The smile graphs must be ordered by expiry.
+pub fn value_portfolio(
+    smile_graphs: &[SmileGraph],
+    positions: &[PortfolioPosition],
+    shock: &ScenarioShock,
+    now: DateTime<Utc>,
+) -> Result<Vec<PositionValuation>, TsError> {
+    let unshocked = ScenarioShock::default();
+
+    positions
+        .iter()
+        .map(|position| {
+            let years_until_expiry = (position.expiry - now).num_seconds() as f64 / 31556926.0;
+            let base_price = unshocked.price_option(smile_graphs, position.strike, years_until_expiry, position.option_type)?;
+            let shocked_price = shock.price_option(smile_graphs, position.strike, years_until_expiry, position.option_type)?;
+
+            Ok(PositionValuation {
+                position: position.clone(),
+                base_price,
+                shocked_price,
+                pnl: position.quantity * (shocked_price - base_price),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,70 @@
+use chrono::{DateTime, Utc};
+
+use crate::{
+    analytics::{SmileGraph, math::svi_variance_slope},
+    types::{TsError, TsErrorType::RuntimeError},
+};
+
+/// The at the money skew of a fitted smile, and how at the money volatility moves with spot because of it.
+pub struct SkewBeta {
+    pub expiry: DateTime<Utc>,
+    pub years_until_expiry: f64,
+    pub atm_implied_volatility: f64,
+    /// The slope of implied volatility with respect to log moneyness at the money. Negative when puts are more expensive.
+    pub atm_skew: f64,
+    /// The change in at the money volatility for a change in log spot, found by regressing the changes between historical
+    /// snapshots. Only set when there are enough snapshots.
+    pub historical_beta: Option<f64>,
+}
+
+impl SkewBeta {
+    /// Calculate the at the money skew of a fitted smile. If the smile stays fixed in strike as spot moves (sticky
+    /// strike), at the money volatility moves along the smile, so the skew is also the surface-implied beta.
+    pub fn calculate(graph: &SmileGraph) -> Result<SkewBeta, TsError> {
+        let years_until_expiry = graph.get_years_until_expiry()?;
+        let total_variance = graph.get_total_variance_at_log_moneyness(0.0)?;
+        let total_variance_slope = svi_variance_slope(&graph.svi_curve_parameters, 0.0)?;
+
+        Ok(SkewBeta {
+            expiry: graph.get_expiration()?,
+            years_until_expiry,
+            atm_implied_volatility: (total_variance / years_until_expiry).sqrt(),
+            // Since σ = √(w/T), dσ/dk = w'/(2√(wT)).
+            atm_skew: total_variance_slope / (2.0 * (total_variance * years_until_expiry).sqrt()),
+            historical_beta: None,
+        })
+    }
+
+    /// The historical beta divided by the skew. Roughly, 0 means the smile moves with spot (sticky moneyness), 1 means it
+    /// stays fixed in strike (sticky strike) and 2 is what a local volatility model predicts.
+    pub fn get_skew_stickiness_ratio(&self) -> Option<f64> {
+        self.historical_beta
+            .map(|beta| beta / self.atm_skew)
+            .filter(|x| x.is_finite())
+    }
+}
+
+/// Fit the change in at the money volatility against the change in log spot between consecutive observations, by least
+/// squares through the origin. Each observation is (spot price, at the money volatility), ordered by time.
+pub fn calculate_historical_beta(observations: &[(f64, f64)]) -> Result<f64, TsError> {
+    if observations.len() < 3 {
+        return Err(TsError::new(RuntimeError, "At least three observations are needed to calculate a historical beta"));
+    }
+
+    let mut spot_moves_squared = 0.0;
+    let mut co_moves = 0.0;
+
+    for pair in observations.windows(2) {
+        let spot_move = (pair[1].0 / pair[0].0).ln();
+        let volatility_move = pair[1].1 - pair[0].1;
+
+        spot_moves_squared += spot_move * spot_move;
+        co_moves += spot_move * volatility_move;
+    }
+
+    if spot_moves_squared <= 0.0 {
+        return Err(TsError::new(RuntimeError, "Spot never moved between observations"));
+    }
+
+    Ok(co_moves / spot_moves_squared)
+}
@@ -1,19 +1,163 @@
-use std::{cell::Cell, f64::consts::E};
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{Mutex, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
 
 use chrono::{DateTime, Utc};
 use levenberg_marquardt::{LeastSquaresProblem, LevenbergMarquardt};
 use nalgebra::{Dyn, Matrix, OMatrix, Owned, U1, U4, Vector4};
 
 use crate::{
-    analytics::{self, OptionInstrument, math::has_butterfly_arbitrage, svi_variance, types::SVICurveParameters},
+    analytics::{
+        ButterflyCheckSettings, DifferentialEvolutionSettings, ExpiryCategory, FitWeighting, ForwardProvenance, OptionInstrument,
+        QuoteTable, SmileConfidenceBand, SmileFitLimits, Straddle, SurfaceModel, SviOptimiser, WingExtrapolation,
+        differential_evolution::minimise_with_differential_evolution,
+        latin_hypercube::latin_hypercube_sample,
+        math::{
+            find_minimum_svi_g, get_butterfly_arbitrage_margin, get_butterfly_arbitrage_shortfall, has_butterfly_arbitrage,
+            saturating_exp, saturating_exp_derivative, saturating_ln, sigmoid, svi_variance_slope,
+        },
+        svi_variance,
+        types::SVICurveParameters,
+    },
     constants,
-    helpers::{F64Helpers, error_unless_positive_f64},
+    helpers::{Clock, F64Helpers, ProgressBar, error_unless_positive_f64, error_unless_valid_f64},
     types::{
         TsError,
         TsErrorType::{RuntimeError, UnsolvableError},
     },
 };
 
+/// Roger Lee's moment formula shows that total variance can't grow faster than this (in absolute log moneyness) in either
+/// wing.
+const LEE_MAXIMUM_WING_SLOPE: f64 = 2.0;
+
+/// The number of starting curves, Latin hypercube sampled from the search ranges, optimised in each search pass.
+const CURVE_SEARCH_POINTS_PER_PASS: u64 = 64;
+
+/// The seed for the first search pass's Latin hypercube sample. Each later pass adds its number, so that passes don't
+/// sample the same relative positions in their narrower ranges.
+const CURVE_SEARCH_SEED: u64 = 1;
+
+/// The number of curves timed when estimating how long a fit will take.
+const FIT_ESTIMATE_CURVES: u64 = 10;
+
+/// The number of search passes assumed when estimating how long a fit will take. Most fits stop after 2 or 3.
+const ESTIMATED_FIT_PASSES: u64 = 3;
+
+/// Levenberg-Marquardt gives up after this many times (the number of parameters + 1) evaluations. This is the library's default, but is
+/// set explicitly so that a library upgrade can't silently change the fit.
+const LEVENBERG_MARQUARDT_PATIENCE: usize = 100;
+
+/// The number of candidate curves in each generation of differential evolution. Ten per parameter is the usual rule of
+/// thumb.
+const DIFFERENTIAL_EVOLUTION_POPULATION: usize = 40;
+
+/// The most generations of differential evolution run on one smile.
+const DIFFERENTIAL_EVOLUTION_MAX_GENERATIONS: u64 = 150;
+
+/// The number of starting curves scored by a batch search.
+#[cfg(feature = "batch-search")]
+const BATCH_SEARCH_CANDIDATES: usize = 4096;
+
+/// The number of starting curves scored together by one thread in a batch search.
+#[cfg(feature = "batch-search")]
+const BATCH_SEARCH_CHUNK_SIZE: usize = 64;
+
+/// The number of the cheapest scored curves that a batch search runs Levenberg-Marquardt from.
+#[cfg(feature = "batch-search")]
+const BATCH_SEARCH_POLISHED_CURVES: usize = 16;
+
+const DIFFERENTIAL_EVOLUTION_MUTATION: f64 = 0.7;
+
+const DIFFERENTIAL_EVOLUTION_CROSSOVER: f64 = 0.9;
+
+/// Differential evolution stops once the best curve hasn't improved for this many generations.
+const DIFFERENTIAL_EVOLUTION_STAGNANT_GENERATIONS: u64 = 20;
+
+/// The seed for differential evolution's random numbers, fixed so that the same market data and settings always give the
+/// same fit.
+const DIFFERENTIAL_EVOLUTION_SEED: u64 = 1;
+
+/// How many times as much as all of a smile's options together the at the money anchor's pseudo-residual counts for (see
+/// SmileGraph::set_atm_anchor()). High enough that the fit only strays outside the tolerance when its shape can't reach.
+const ATM_ANCHOR_WEIGHT: f64 = 100.0;
+
+/// The settings smiles are fit with, saved with each surface so that it can be reproduced. The search's random numbers all
+/// come from fixed seeds, so the same market data and settings always give the same fit.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+pub struct SviFitSettings {
+    /// Older versions searched a grid, and saved its resolution as curve_search_pass_resolution.
+    pub curve_search_points_per_pass: u64,
+    /// How much better (as a fraction) a search pass must do than the previous one to keep searching.
+    pub required_improvement: f64,
+    pub levenberg_marquardt_patience: usize,
+    pub interest_free_rate: f64,
+    /// Missing from surfaces saved by older versions, which always fit each smile with raw SVI.
+    #[serde(default)]
+    pub surface_model: SurfaceModel,
+    /// Missing from surfaces saved by older versions, which always searched a grid (saved as multi_start).
+    #[serde(default)]
+    pub optimiser: SviOptimiser,
+    /// Missing from surfaces saved by older versions, which weighted every option equally.
+    #[serde(default)]
+    pub fit_weighting: FitWeighting,
+    /// Missing from surfaces saved by older versions, which checked 150 points from a strike of 1 to 1.5 times the highest
+    /// observed strike.
+    #[serde(default)]
+    pub butterfly_check: ButterflyCheckSettings,
+    /// How strongly sparse smiles were pulled towards the previous surface's fit (see SmileGraph::set_fit_prior()), if
+    /// they were. Missing from surfaces saved by older versions, which never were.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regularisation_strength: Option<f64>,
+    /// How far (as a fraction, e.g. 0.005 for half a vol point) the fits were allowed to stray from each smile's at the
+    /// money straddle (see SmileGraph::set_atm_anchor()), if they were anchored. Missing from surfaces saved by older
+    /// versions, which never were.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub atm_anchor_tolerance: Option<f64>,
+}
+
+impl Default for SviFitSettings {
+    fn default() -> Self {
+        Self {
+            curve_search_points_per_pass: CURVE_SEARCH_POINTS_PER_PASS,
+            required_improvement: constants::SVI_FITTING_REQUIRED_IMPROVEMENT,
+            levenberg_marquardt_patience: LEVENBERG_MARQUARDT_PATIENCE,
+            interest_free_rate: constants::INTEREST_FREE_RATE,
+            surface_model: SurfaceModel::Svi,
+            optimiser: SviOptimiser::MultiStart,
+            fit_weighting: FitWeighting::Equal,
+            butterfly_check: ButterflyCheckSettings::default(),
+            regularisation_strength: None,
+            atm_anchor_tolerance: None,
+        }
+    }
+}
+
+/// A curve that a fit is pulled towards, e.g. the same expiry's fit in the previous surface, so that a smile with only a
+/// few strikes can't swing to a very different curve when one of its quotes moves. See SmileGraph::set_fit_prior().
+#[derive(Clone, Copy, Debug)]
+pub struct SviFitPrior {
+    /// The b, p, m and o of the curve. a isn't pulled towards anything, as it's always solved for exactly.
+    parameters: [f64; 4],
+    /// The relative error in total variance, on every option, that costs as much as any of the parameters moving by
+    /// its own size (b or o doubling, m moving by o, or p moving by 1).
+    strength: f64,
+}
+
+/// An implied volatility that a fit has to pass within a tolerance of at a strike, e.g. that of the straddle nearest the
+/// forward price, since how well the curve gets the at the money level matters more than how well it gets the wings for
+/// most of what it's used for. See SmileGraph::set_atm_anchor().
+#[derive(Clone, Copy, Debug)]
+pub struct SviAtmAnchor {
+    strike: f64,
+    implied_volatility: f64,
+    /// How far the fitted implied volatility can be from implied_volatility without being penalised, as a fraction.
+    tolerance: f64,
+}
+
 /// A smile graph representing the change in volatility as the strike price changes for a set of options, each having the same
 /// expiry.
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -23,11 +167,60 @@ pub struct SmileGraph {
     pub lowest_observed_strike: f64,
     pub highest_observed_implied_volatility: f64,
     pub svi_curve_parameters: SVICurveParameters,
+    /// Where the forward price came from. Missing from data saved by older versions, which always carried the spot price
+    /// forward.
+    #[serde(default)]
+    pub forward_provenance: Option<ForwardProvenance>,
+    /// The quote hash (see get_quote_hash()) when the smile was fit, so that a later build can tell whether its quotes have
+    /// changed since. Saved rather than worked out again, as the prices don't always load back bit for bit. Missing from
+    /// data saved by older versions.
+    #[serde(default)]
+    pub quote_hash: Option<u64>,
+    /// True if fitting stopped at a time or iteration limit, so the curve is the best found by then rather than the best
+    /// the search would have found. Missing from data saved by older versions.
+    #[serde(default)]
+    pub budget_limited: bool,
+    /// How often expiries like this one are listed, if the exchange said. Missing from data saved by older versions.
+    #[serde(default)]
+    pub expiry_category: Option<ExpiryCategory>,
+    /// How uncertain the fit is, if it was bootstrapped (see analytics::bootstrap_smile()).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confidence_band: Option<SmileConfidenceBand>,
 
     #[serde(skip)]
     pub has_been_fit: bool,
+    /// Set from the config rather than saved, so that it can be changed without refitting.
+    #[serde(skip)]
+    pub wing_extrapolation: WingExtrapolation,
+    /// How the best curve is searched for. Set from the config before fitting.
+    #[serde(skip)]
+    pub optimiser: SviOptimiser,
+    /// The number of threads each search pass's starting curves are optimised on. Set before fitting.
+    #[serde(skip)]
+    pub search_threads: usize,
+    /// How much each option counts towards the fit. Set from the config before fitting.
+    #[serde(skip)]
+    pub fit_weighting: FitWeighting,
+    /// Where and how densely the smile is checked for butterfly arbitrage. Set from the config before fitting.
     #[serde(skip)]
-    underlying_forward_price: Cell<Option<f64>>,
+    pub butterfly_check: ButterflyCheckSettings,
+    /// How long the smile can be fit for. Set from the config before fitting.
+    #[serde(skip)]
+    pub fit_limits: SmileFitLimits,
+    /// The curve the fit is pulled towards, if any. Set before fitting.
+    #[serde(skip)]
+    pub fit_prior: Option<SviFitPrior>,
+    /// Where the fit has to pass at the money, if anywhere. Set before fitting.
+    #[serde(skip)]
+    pub atm_anchor: Option<SviAtmAnchor>,
+    #[serde(skip)]
+    underlying_forward_price: OnceLock<f64>,
+}
+
+impl Default for SmileGraph {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SmileGraph {
@@ -35,14 +228,55 @@ impl SmileGraph {
         SmileGraph {
             options: Vec::new(),
             svi_curve_parameters: SVICurveParameters::default(),
+            forward_provenance: None,
+            quote_hash: None,
+            budget_limited: false,
+            expiry_category: None,
+            confidence_band: None,
             has_been_fit: false,
-            underlying_forward_price: Cell::new(None),
+            wing_extrapolation: WingExtrapolation::default(),
+            optimiser: SviOptimiser::default(),
+            search_threads: 1,
+            fit_weighting: FitWeighting::default(),
+            butterfly_check: ButterflyCheckSettings::default(),
+            fit_limits: SmileFitLimits::default(),
+            fit_prior: None,
+            atm_anchor: None,
+            underlying_forward_price: OnceLock::new(),
             highest_observed_implied_volatility: f64::MIN,
             lowest_observed_strike: f64::MAX,
             highest_observed_strike: f64::MIN,
         }
     }
 
+    /// A copy of the fitted smile keeping only its first option, which its forward price and expiry are read from, so that
+    /// it can be read anywhere without loading every quote. Anything the quotes are needed for (refitting, reports on the
+    /// fit) can't be done with it.
+    pub fn without_quotes(&self) -> Result<SmileGraph, TsError> {
+        let curve = &self.svi_curve_parameters;
+
+        Ok(SmileGraph {
+            options: vec![self.get_first_option()?.clone()],
+            svi_curve_parameters: SVICurveParameters::new_from_values(
+                curve.get_a(),
+                curve.get_b(),
+                curve.get_p(),
+                curve.get_m(),
+                curve.get_o(),
+            )?,
+            forward_provenance: self.forward_provenance.clone(),
+            quote_hash: self.quote_hash,
+            budget_limited: self.budget_limited,
+            expiry_category: self.expiry_category,
+            highest_observed_implied_volatility: self.highest_observed_implied_volatility,
+            lowest_observed_strike: self.lowest_observed_strike,
+            highest_observed_strike: self.highest_observed_strike,
+            has_been_fit: self.has_been_fit,
+            wing_extrapolation: self.wing_extrapolation,
+            ..SmileGraph::new()
+        })
+    }
+
     /// Internal helper for getting the first option in a way that doesn't panic.
     fn get_first_option(&self) -> Result<&OptionInstrument, TsError> {
         self.options
@@ -50,19 +284,18 @@ impl SmileGraph {
             .ok_or(TsError::new(RuntimeError, "Smile graphs has no options, this should never happen"))
     }
 
-    /// Get the forward price that best represents all of the options. In reality, since we have normalised all the
-    /// options to have the same spot price, it doesn't matter much how we calculate this. The only real guess here
-    /// is the interest free rate.
+    /// Get the forward price that best represents all of the options. Every option in a graph is given the same forward
+    /// price (see analytics::determine_forward_price()), or if it has none, the same spot price carried forward at the
+    /// interest free rate, so the first option's is used.
     pub fn get_underlying_forward_price(&self) -> Result<f64, TsError> {
         if let Some(price) = self.underlying_forward_price.get() {
-            return Ok(price);
+            return Ok(*price);
         };
 
-        let option = self.get_first_option()?;
-
-        let price = option.spot_price * E.powf(constants::INTEREST_FREE_RATE * option.get_years_until_expiry()?);
+        let price = self.get_first_option()?.get_forward_price()?;
 
-        self.underlying_forward_price.set(Some(price));
+        // Another thread may have set it first, to the same price.
+        let _ = self.underlying_forward_price.set(price);
         Ok(price)
     }
 
@@ -70,15 +303,186 @@ impl SmileGraph {
         error_unless_positive_f64(strike, "strike")?;
 
         let log_moneyness = (strike / self.get_underlying_forward_price()?).ln();
-        let implied_variance = analytics::svi_variance(&self.svi_curve_parameters, log_moneyness)?;
+        let implied_variance = self.get_total_variance_at_log_moneyness(log_moneyness)?;
 
         Ok((implied_variance / self.get_years_until_expiry()?).sqrt())
     }
 
+    /// Get the total implied variance of the smile, extrapolating beyond the observed strikes as set by wing_extrapolation.
+    pub fn get_total_variance_at_log_moneyness(&self, log_moneyness: f64) -> Result<f64, TsError> {
+        let forward_price = self.get_underlying_forward_price()?;
+        let lowest_log_moneyness = (self.lowest_observed_strike / forward_price).ln();
+        let highest_log_moneyness = (self.highest_observed_strike / forward_price).ln();
+
+        // The nearest edge of the observed strikes, if we're beyond it.
+        let edge = match log_moneyness {
+            k if k < lowest_log_moneyness => lowest_log_moneyness,
+            k if k > highest_log_moneyness => highest_log_moneyness,
+            _ => return svi_variance(&self.svi_curve_parameters, log_moneyness),
+        };
+        let edge_variance = svi_variance(&self.svi_curve_parameters, edge)?;
+        let distance = log_moneyness - edge;
+
+        match self.wing_extrapolation {
+            WingExtrapolation::Svi => svi_variance(&self.svi_curve_parameters, log_moneyness),
+            WingExtrapolation::LeeBounds => Ok(svi_variance(&self.svi_curve_parameters, log_moneyness)?
+                .min(edge_variance + LEE_MAXIMUM_WING_SLOPE * distance.abs())),
+            WingExtrapolation::Flat => Ok(edge_variance),
+            WingExtrapolation::Linear => {
+                // Variance shouldn't fall as we move further into either wing.
+                let slope = svi_variance_slope(&self.svi_curve_parameters, edge)?;
+                let slope = match distance < 0.0 {
+                    true => slope.clamp(-LEE_MAXIMUM_WING_SLOPE, 0.0),
+                    false => slope.clamp(0.0, LEE_MAXIMUM_WING_SLOPE),
+                };
+
+                Ok(edge_variance + slope * distance)
+            }
+        }
+    }
+
+    /// The root mean squared difference between the implied volatility of each option and the fitted curve.
+    pub fn get_fit_rmse(&self) -> Result<f64, TsError> {
+        let mut squared_errors = 0.0;
+
+        for option in &self.options {
+            let error = self.get_implied_volatility_at_strike(option.strike)? - option.get_implied_volatility()?;
+            squared_errors += error * error;
+        }
+
+        Ok((squared_errors / self.options.len() as f64).sqrt())
+    }
+
+    /// How far the fitted curve is from having butterfly arbitrage, checked at the same points as when it was fit. See
+    /// get_butterfly_arbitrage_margin.
+    pub fn get_arbitrage_margin(&self) -> Result<f64, TsError> {
+        get_butterfly_arbitrage_margin(
+            &self.svi_curve_parameters,
+            self.lowest_observed_strike,
+            self.highest_observed_strike,
+            self.get_underlying_forward_price()?,
+            &self.butterfly_check,
+        )
+    }
+
+    /// The smallest value of g(k) at the points the curve was checked for butterfly arbitrage at when it was fit, and the
+    /// strike it's at. See find_minimum_svi_g.
+    pub fn get_minimum_g(&self) -> Result<(f64, f64), TsError> {
+        find_minimum_svi_g(
+            &self.svi_curve_parameters,
+            self.lowest_observed_strike,
+            self.highest_observed_strike,
+            self.get_underlying_forward_price()?,
+            &self.butterfly_check,
+        )
+    }
+
     pub fn get_years_until_expiry(&self) -> Result<f64, TsError> {
         self.get_first_option()?.get_years_until_expiry()
     }
 
+    /// What the options' times until expiry are measured from.
+    pub fn get_clock(&self) -> Result<Clock, TsError> {
+        Ok(self.get_first_option()?.get_clock())
+    }
+
+    /// Change what the options' times until expiry are measured from, forgetting anything calculated with the previous
+    /// clock.
+    pub fn set_clock(&mut self, clock: Clock) {
+        for option in &mut self.options {
+            option.set_clock(clock);
+        }
+
+        self.underlying_forward_price = OnceLock::new();
+    }
+
+    /// A hash of everything about the options that the smile is fit to, which changes if any option is added, removed or
+    /// requoted, or the spot or forward price moves. The hash can also change between versions of this program.
+    pub fn get_quote_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+
+        for option in &self.options {
+            option.instrument_id.hash(&mut hasher);
+            option.expiry_seconds.hash(&mut hasher);
+            (option.option_type as u8).hash(&mut hasher);
+            (option.pricing_model as u8).hash(&mut hasher);
+            (option.implied_volatility_source as u8).hash(&mut hasher);
+
+            for value in [
+                Some(option.strike),
+                Some(option.price),
+                Some(option.spot_price),
+                option.forward_price,
+                option.exchange_implied_volatility,
+                option.open_interest,
+                option.volume,
+            ] {
+                value.map(f64::to_bits).hash(&mut hasher);
+            }
+        }
+
+        hasher.finish()
+    }
+
+    /// Use another smile's fit (e.g. the same expiry's in an earlier surface) rather than fitting this one. SVI fits total
+    /// variance, which grows with time until expiry, so the curve is scaled to this smile's time until expiry to keep the
+    /// same implied volatilities.
+    pub fn reuse_fit(&mut self, other: &SmileGraph) -> Result<(), TsError> {
+        let scale = self.get_years_until_expiry()? / other.get_years_until_expiry()?;
+        let params = &other.svi_curve_parameters;
+
+        self.svi_curve_parameters = SVICurveParameters::new_from_values(
+            params.get_a() * scale,
+            params.get_b() * scale,
+            params.get_p(),
+            params.get_m(),
+            params.get_o(),
+        )?;
+        self.has_been_fit = true;
+        self.budget_limited = other.budget_limited;
+
+        Ok(())
+    }
+
+    /// Pull this smile's fit towards another smile's (e.g. the same expiry's in an earlier surface) with the given strength
+    /// (see SviFitPrior), scaled to this smile's time until expiry as in reuse_fit(). Each of the curve's b, p, m and o is
+    /// penalised for how far it moves from the other's, which keeps a smile with too few strikes to pin down every
+    /// parameter from fitting to a different curve each time its quotes change.
+    pub fn set_fit_prior(&mut self, other: &SmileGraph, strength: f64) -> Result<(), TsError> {
+        error_unless_positive_f64(strength, "strength")?;
+
+        let scale = self.get_years_until_expiry()? / other.get_years_until_expiry()?;
+        let params = &other.svi_curve_parameters;
+
+        self.fit_prior = Some(SviFitPrior {
+            parameters: [params.get_b() * scale, params.get_p(), params.get_m(), params.get_o()],
+            strength,
+        });
+
+        Ok(())
+    }
+
+    /// Make the fit pass within the tolerance (as a fraction, e.g. 0.005 for half a vol point) of the straddle's implied
+    /// volatility at its strike. Straying further is penalised by a pseudo-residual weighted ATM_ANCHOR_WEIGHT times as
+    /// heavily as all of the options together, so the fit gives up on the wings long before it gives up on the anchor.
+    pub fn set_atm_anchor(&mut self, straddle: &Straddle, tolerance: f64) -> Result<(), TsError> {
+        error_unless_positive_f64(straddle.strike, "strike")?;
+        error_unless_positive_f64(straddle.implied_volatility, "implied_volatility")?;
+        error_unless_valid_f64(tolerance, "tolerance")?;
+
+        if tolerance < 0.0 {
+            return Err(TsError::new(RuntimeError, format!("Invalid ATM anchor tolerance {tolerance}")));
+        }
+
+        self.atm_anchor = Some(SviAtmAnchor {
+            strike: straddle.strike,
+            implied_volatility: straddle.implied_volatility,
+            tolerance,
+        });
+
+        Ok(())
+    }
+
     /// Returns true if the smile graph has no options.
     fn is_empty(&self) -> bool {
         self.options.len() == 0
@@ -131,24 +535,168 @@ impl SmileGraph {
         Ok(())
     }
 
+    /// A new unfitted graph with the same settings as this one, but only the given options.
+    pub fn with_options(&self, options: Vec<OptionInstrument>) -> Result<SmileGraph, TsError> {
+        let mut graph = SmileGraph::new();
+
+        for option in options {
+            graph.try_insert_option(option)?;
+        }
+
+        graph.forward_provenance = self.forward_provenance.clone();
+        graph.wing_extrapolation = self.wing_extrapolation;
+        graph.optimiser = self.optimiser;
+        graph.search_threads = self.search_threads;
+        graph.fit_weighting = self.fit_weighting;
+        graph.butterfly_check = self.butterfly_check;
+        graph.fit_prior = self.fit_prior;
+        graph.atm_anchor = self.atm_anchor;
+
+        Ok(graph)
+    }
+
+    /// As with_options(), but without the prior the fit is pulled towards or the at the money anchor, so that the fit
+    /// follows only the given options. For refits that measure how much the fit depends on its options, which either
+    /// would hide.
+    pub fn with_options_unconstrained(&self, options: Vec<OptionInstrument>) -> Result<SmileGraph, TsError> {
+        let mut graph = self.with_options(options)?;
+        graph.fit_prior = None;
+        graph.atm_anchor = None;
+
+        Ok(graph)
+    }
+
+    /// Change the price of one of the options already in the graph, e.g. when a new quote arrives. The fitted curve isn't
+    /// changed, so call refit_smile() afterwards (once for any number of updates) to bring it up to date. If the new price
+    /// has no implied volatility the option is left as it was.
+    pub fn update_quote(&mut self, instrument_id: &str, new_price: f64) -> Result<(), TsError> {
+        let option = self
+            .options
+            .iter_mut()
+            .find(|x| x.instrument_id.as_ref() == instrument_id)
+            .ok_or(TsError::new(RuntimeError, format!("Instrument {instrument_id} isn't in the smile graph")))?;
+        let old_price = option.price;
+
+        option.set_price(new_price);
+
+        if let Err(e) = Self::check_option_valid(option) {
+            option.set_price(old_price);
+            return Err(e);
+        }
+
+        self.highest_observed_implied_volatility = self
+            .options
+            .iter()
+            .map(|x| x.get_implied_volatility())
+            .collect::<Result<Vec<f64>, TsError>>()?
+            .into_iter()
+            .fold(f64::MIN, f64::max);
+
+        Ok(())
+    }
+
+    /// Refit an already fitted smile after its quotes have changed, starting from the current curve. This only optimises
+    /// from the current curve rather than searching for the best starting point like fit_smile(), so is far quicker but
+    /// only suitable for small changes. Returns the new error. If no valid curve is found, the current curve is kept.
+    pub fn refit_smile(&mut self) -> Result<f64, TsError> {
+        if !self.has_been_fit {
+            return Err(TsError::new(RuntimeError, "Only a smile that has already been fit can be refit"));
+        }
+
+        let current = &self.svi_curve_parameters;
+        let params = SVICurveParameters::new_from_values(
+            current.get_a(),
+            current.get_b(),
+            current.get_p(),
+            current.get_m(),
+            current.get_o(),
+        )?;
+        let (curve, error) = self.optimise_svi_params(params, &self.get_fit_inputs()?)?;
+
+        self.svi_curve_parameters = curve;
+
+        Ok(error)
+    }
+
+    /// The options as a column per field, which is what the fitting loops read. Log moneyness is measured from the
+    /// smile's forward price.
+    pub fn get_quote_table(&self) -> Result<QuoteTable, TsError> {
+        QuoteTable::from_options(&self.options, self.get_underlying_forward_price()?)
+    }
+
+    /// Everything fitting needs from the options, worked out once so that it isn't on every step of the optimiser.
+    fn get_fit_inputs(&self) -> Result<FitInputs, TsError> {
+        let weights = self.get_fit_weights();
+        let quotes = self.get_quote_table()?;
+        let total_weight = weights.iter().sum::<f64>();
+
+        // Scaled so that a parameter moving by its own size costs as much as every option's total variance being off by
+        // the strength as a fraction of the average.
+        let prior = self.fit_prior.map(|prior| {
+            let mean_variance = weighted_sum(&quotes.total_implied_variance, &weights) / total_weight;
+            (prior.parameters, prior.strength * mean_variance * total_weight.sqrt())
+        });
+        let years_until_expiry = self.get_years_until_expiry()?;
+        let forward_price = self.get_underlying_forward_price()?;
+        let atm_anchor = self.atm_anchor.map(|anchor| AtmAnchor {
+            log_moneyness: (anchor.strike / forward_price).ln(),
+            lowest_variance: (anchor.implied_volatility - anchor.tolerance)
+                .max(0.0)
+                .powi(2)
+                * years_until_expiry,
+            highest_variance: (anchor.implied_volatility + anchor.tolerance).powi(2) * years_until_expiry,
+            weight_root: (ATM_ANCHOR_WEIGHT * total_weight).sqrt(),
+        });
+
+        Ok(FitInputs {
+            quotes,
+            weight_roots: weights.iter().map(|x| x.sqrt()).collect(),
+            total_weight,
+            weights,
+            forward_price,
+            prior,
+            atm_anchor,
+        })
+    }
+
+    /// How much each option counts towards the fit, in the same order as the options. The weights average 1.
+    pub fn get_fit_weights(&self) -> Vec<f64> {
+        let liquidity: Vec<f64> = self
+            .options
+            .iter()
+            .map(|x| match self.fit_weighting {
+                FitWeighting::Equal => 0.0,
+                FitWeighting::OpenInterest => x.open_interest.unwrap_or(0.0),
+                FitWeighting::Volume => x.volume.unwrap_or(0.0),
+            })
+            .collect();
+        let mean_liquidity = liquidity.iter().sum::<f64>() / liquidity.len() as f64;
+
+        // Also used when weighting by liquidity that no option has, e.g. for data saved before it was recorded.
+        if mean_liquidity <= 0.0 {
+            return vec![1.0; liquidity.len()];
+        }
+
+        liquidity
+            .iter()
+            .map(|x| (x + mean_liquidity) / (2.0 * mean_liquidity))
+            .collect()
+    }
+
     /// Optimise the given SVI curve parameters, returning optimised parameters and their loss.
-    fn optimise_svi_params(&self, params: SVICurveParameters) -> Result<(SVICurveParameters, f64), TsError> {
-        let mut problem = SVIProblem {
+    fn optimise_svi_params(&self, params: SVICurveParameters, inputs: &FitInputs) -> Result<(SVICurveParameters, f64), TsError> {
+        let mut problem = SVIProblem::new(
             // The initial guess for the SVI function.
-            p: Vector4::new(params.get_b(), params.get_p(), params.get_m(), params.get_o()),
-            smile_graph: self,
-            curve_valid: false,
-            has_arbitrage: false,
-            curve: Some(SVICurveParameters::default()),
-            residuals_buffer: vec![0.0; self.options.len()],
-        };
+            to_unconstrained_svi_params(params.get_b(), params.get_p(), params.get_m(), params.get_o()),
+            self,
+            inputs,
+        );
 
-        let initial_params = problem.p;
+        let initial_params = problem.x;
         problem.set_params(&initial_params);
 
-        // Library default for patience is 100.
         let (result, report) = LevenbergMarquardt::new()
-            .with_patience(100)
+            .with_patience(LEVENBERG_MARQUARDT_PATIENCE)
             .minimize(problem);
 
         if !report.termination.was_successful() {
@@ -158,20 +706,19 @@ impl SmileGraph {
             ));
         }
 
-        if !result.curve_valid || result.has_arbitrage {
-            return Err(TsError::new(UnsolvableError, "No mathematically valid curve found"));
+        if result.has_arbitrage {
+            return Err(TsError::new(UnsolvableError, "No curve without butterfly arbitrage found"));
         }
 
         let curve = result
             .curve
-            .ok_or(TsError::new(RuntimeError, "No curve was produced"))?;
+            .ok_or(TsError::new(UnsolvableError, "No mathematically valid curve found"))?;
 
         Ok((curve, report.objective_function.abs()))
     }
 
-    /// Using the provided options, calculate the smile shape that best represents the data with the least error.
-    /// Returns the error on success.
-    pub fn fit_smile(&mut self) -> Result<(), TsError> {
+    /// The ranges of b, p, m and o searched in the first pass when fitting, as (start, end) pairs.
+    fn get_default_search_ranges(&self) -> Result<[(f64, f64); 4], TsError> {
         let forward_price = self.get_underlying_forward_price()?;
         let option_total_implied_variances: Vec<f64> = self
             .options
@@ -202,38 +749,88 @@ impl SmileGraph {
         let log_moneyness_range = highest_log_moneyness - lowest_log_moneyness;
         let s = (highest_total_implied_variance - lowest_total_implied_variance) / log_moneyness_range.max(0.000001);
 
+        Ok([
+            // Search in the range 0.000001 -> 5s.
+            (0.00001, s * 5.0),
+            // Search in the range -0.99 -> 0.99.
+            (-0.99, 0.99),
+            (lowest_log_moneyness, highest_log_moneyness * 1.1),
+            (log_moneyness_range * 0.05, log_moneyness_range * 2.0),
+        ])
+    }
+
+    /// Estimate how long fit_smile() will take, by timing the optimisation from a few curves spread through the first
+    /// search pass and assuming the fit takes ESTIMATED_FIT_PASSES passes.
+    pub fn estimate_fit_seconds(&self) -> Result<f64, TsError> {
+        let ranges = self.get_default_search_ranges()?;
+        let inputs = self.get_fit_inputs()?;
+        let start = Instant::now();
+
+        for i in 0..FIT_ESTIMATE_CURVES {
+            // Spread the curves along the diagonal of the search space.
+            let fraction = i as f64 / (FIT_ESTIMATE_CURVES - 1) as f64;
+            let [b, p, m, o] = ranges.map(|(start, end)| start + (end - start) * fraction);
+
+            // Invalid curves are skipped quickly when fitting too, so they still count towards the average.
+            if let Ok(params) = SVICurveParameters::new_from_values(0.0, b, p, m, o) {
+                let _ = self.optimise_svi_params(params, &inputs);
+            }
+        }
+
+        let seconds_per_curve = start.elapsed().as_secs_f64() / FIT_ESTIMATE_CURVES as f64;
+
+        Ok(seconds_per_curve * (CURVE_SEARCH_POINTS_PER_PASS * ESTIMATED_FIT_PASSES) as f64)
+    }
+
+    /// Using the provided options, calculate the smile shape that best represents the data with the least error.
+    /// Progress is reported beneath the given progress bar.
+    pub fn fit_smile(&mut self, progress: &ProgressBar) -> Result<(), TsError> {
+        self.fit_smile_with_budget(progress, None).map(|_| ())
+    }
+
+    /// Like fit_smile(), but stops searching once the budget has run out, keeping the best curve found so far. The first
+    /// search pass is always finished, as it is the only one that covers the whole search space, unless the smile's own
+    /// time limit (see fit_limits) runs out during it. Returns true, and marks the smile as budget limited, if the budget
+    /// or one of the smile's limits stopped the search before it finished.
+    pub fn fit_smile_with_budget(&mut self, progress: &ProgressBar, budget: Option<Duration>) -> Result<bool, TsError> {
+        self.budget_limited = false;
+
+        match self.optimiser {
+            SviOptimiser::MultiStart => {}
+            SviOptimiser::DifferentialEvolution => return self.fit_smile_with_differential_evolution(progress, budget),
+            #[cfg(feature = "batch-search")]
+            SviOptimiser::BatchSearch => return self.fit_smile_with_batch_search(progress, budget),
+            #[cfg(not(feature = "batch-search"))]
+            SviOptimiser::BatchSearch => return SviOptimiser::BatchSearch.check_valid().map(|_| false),
+        }
+
+        let start = Instant::now();
+        let deadline = self.fit_limits.get_deadline(start);
+        let inputs = self.get_fit_inputs()?;
+
         // From testing it seems that the initial guesses when optimising the SVI function make a huge difference
         // in the overall error. So we need to try lots of different options.
-        // We're going to brute force it, but at the same time we'll focus on the range of mathematically sensible values.
-
-        // Search in the range 0.000001 -> 5s.
-        let default_b_start = 0.00001;
-        let default_b_end = s * 5.0;
+        // We start from a Latin hypercube sample of the range of mathematically sensible values, which covers each parameter
+        // evenly with far fewer points than a grid, then narrow the range around the best curve each pass.
+        let [
+            (default_b_start, default_b_end),
+            (default_p_start, default_p_end),
+            (default_m_start, default_m_end),
+            (default_o_start, default_o_end),
+        ] = self.get_default_search_ranges()?;
         let default_b_range = default_b_end - default_b_start;
-
-        // Search in the range -0.99 -> 0.99.
-        let default_p_start = -0.99;
-        let default_p_end = 0.99;
         let default_p_range = default_p_end - default_p_start;
-
-        let default_m_start = lowest_log_moneyness;
-        let default_m_end = highest_log_moneyness * 1.1;
         let default_m_range = default_m_end - default_m_start;
-
-        let default_o_start = log_moneyness_range * 0.05;
-        let default_o_end = log_moneyness_range * 2.0;
         let default_o_range = default_o_end - default_o_start;
 
-        // It's impossible for a curve to not be found because this is already a valid curve (I think).
+        // Only a placeholder until the first pass finds a curve, which best_error staying at f64::MAX shows hasn't happened.
         let mut best_curve: SVICurveParameters = SVICurveParameters::default();
         let mut best_error: f64 = f64::MAX;
         let mut pass = 1;
 
         loop {
-            println!("Starting pass {pass}...");
-
-            // Do multiple passes until we stop improving.
-            let mut improved = false;
+            let mut pass_progress = progress.new_child(format!("Pass {pass}"), CURVE_SEARCH_POINTS_PER_PASS);
+            pass_progress.message(format!("Starting pass {pass}..."));
 
             // The percentage of the total range we are about to search in this pass.
             // As the passes progress, we focus in on a particular area.
@@ -276,160 +873,301 @@ impl SmileGraph {
                 _ => (best_curve.get_o() + default_o_range * search_range_percentage * 0.5).min(default_o_end),
             };
 
-            println!("Searching in range:");
-            println!("b={b_start} => {b_end}");
-            println!("p={p_start} => {p_end}");
-            println!("m={m_start} => {m_end}");
-            println!("o={o_start} => {o_end}");
-            println!("=====================================");
-
-            let mut b = b_start;
-            let mut p = p_start;
-            let mut m = m_start;
-            let mut o = o_start;
-
-            loop {
-                // Search for a better curve throughout this range until we reach the end.
-                let result = self.search_for_better_curve(
-                    b, p, m, o, b_start, p_start, m_start, o_start, b_end, p_end, m_end, o_end, best_error,
-                );
-
-                // Reached the end.
-                if result.0 == true {
-                    break;
-                }
+            pass_progress.message("Searching in range:");
+            pass_progress.message(format!("b={b_start} => {b_end}"));
+            pass_progress.message(format!("p={p_start} => {p_end}"));
+            pass_progress.message(format!("m={m_start} => {m_end}"));
+            pass_progress.message(format!("o={o_start} => {o_end}"));
+            pass_progress.message("=====================================");
+
+            let starting_points = latin_hypercube_sample(
+                &[(b_start, b_end), (p_start, p_end), (m_start, m_end), (o_start, o_end)],
+                CURVE_SEARCH_POINTS_PER_PASS as usize,
+                CURVE_SEARCH_SEED + pass,
+            );
+            let result = self.optimise_from_starting_points(&starting_points, &inputs, &mut pass_progress, deadline)?;
+            let timed_out = deadline.is_some_and(|x| Instant::now() >= x);
+
+            pass_progress.finish();
+
+            // Do multiple passes until we stop improving. Strictly less, so that a perfect fit (e.g. of synthetic options)
+            // doesn't keep improving on itself.
+            let Some((curve, error)) =
+                result.filter(|(_, error)| *error < best_error - best_error * constants::SVI_FITTING_REQUIRED_IMPROVEMENT)
+            else {
+                // A pass cut short by the time limit might have improved had it finished.
+                self.budget_limited = timed_out;
+                break;
+            };
 
-                improved = true;
-
-                println!(
-                    "Found new best error of {} (a={}, b={}, p={}, m={}, o={})",
-                    result.1.round_to_decimal_places(9),
-                    result.2.get_a().round_to_decimal_places(9),
-                    result.2.get_b().round_to_decimal_places(9),
-                    result.2.get_p().round_to_decimal_places(9),
-                    result.2.get_m().round_to_decimal_places(9),
-                    result.2.get_o().round_to_decimal_places(9),
-                );
-
-                b = result.3;
-                p = result.4;
-                m = result.5;
-                o = result.6;
-
-                best_error = result.1;
-                best_curve = result.2;
-            }
+            progress.message(format!(
+                "Found new best error of {} (a={}, b={}, p={}, m={}, o={})",
+                error.round_to_decimal_places(9),
+                curve.get_a().round_to_decimal_places(9),
+                curve.get_b().round_to_decimal_places(9),
+                curve.get_p().round_to_decimal_places(9),
+                curve.get_m().round_to_decimal_places(9),
+                curve.get_o().round_to_decimal_places(9),
+            ));
 
-            if !improved {
+            best_error = error;
+            best_curve = curve;
+
+            if timed_out {
+                progress.message(format!("Stopping during pass {pass} as the smile's time limit has run out..."));
+                self.budget_limited = true;
+                break;
+            }
+            if self.fit_limits.max_iterations.is_some_and(|x| pass >= x) {
+                progress.message(format!("Stopping after pass {pass} as the smile's pass limit has been reached..."));
+                self.budget_limited = true;
+                break;
+            }
+            if budget.is_some_and(|x| start.elapsed() >= x) {
+                progress.message(format!("Stopping after pass {pass} as the fitting budget has run out..."));
+                self.budget_limited = true;
                 break;
             }
 
-            println!("=====================================");
+            pass_progress.message("=====================================");
             pass += 1;
         }
 
+        // Every starting point failed, or the time limit ran out before any of them finished.
+        if best_error == f64::MAX {
+            return Err(TsError::new(UnsolvableError, "No curve without butterfly arbitrage found"));
+        }
+
         self.svi_curve_parameters = best_curve;
         self.has_been_fit = true;
 
-        println!("Smile fit with error of {best_error}...");
-        println!(
+        progress.message(format!("Smile fit with error of {best_error}..."));
+        progress.message(format!(
             "Final params: a={}, b={}, p={}, m={}, o={}...",
             self.svi_curve_parameters.get_a(),
             self.svi_curve_parameters.get_b(),
             self.svi_curve_parameters.get_p(),
             self.svi_curve_parameters.get_m(),
             self.svi_curve_parameters.get_o()
-        );
+        ));
 
-        Ok(())
+        Ok(self.budget_limited)
     }
 
-    /// Search for a smile graph curve with less error than current_best_error. Begin searching from b, p, m, o.
-    /// Finish at *_end. When a loop reaches the end, start over from *_start.
-    ///
-    /// We'll return as soon as we find a better solution. The first return value is true if we reached the end of the
-    /// searchable range, or false if not. The second is the new error. The third is the new curve. The last four are
-    /// the current b, p, m, o values.
-    ///
-    /// NB that if we reached the end of the searchable range, the other parameters (other than the first) are only
-    /// placeholders.
-    fn search_for_better_curve(
-        &self,
-        mut b: f64,
-        mut p: f64,
-        mut m: f64,
-        mut o: f64,
-        b_start: f64,
-        p_start: f64,
-        m_start: f64,
-        o_start: f64,
-        b_end: f64,
-        p_end: f64,
-        m_end: f64,
-        o_end: f64,
-        current_best_error: f64,
-    ) -> (bool, f64, SVICurveParameters, f64, f64, f64, f64) {
-        // How many points we will check along each parameter range.
-        const CURVE_SEARCH_PASS_RESOLUTION: f64 = 4.0;
-
-        let b_step = (b_end - b_start) / CURVE_SEARCH_PASS_RESOLUTION;
-        let p_step = (p_end - p_start) / CURVE_SEARCH_PASS_RESOLUTION;
-        let m_step = (m_end - m_start) / CURVE_SEARCH_PASS_RESOLUTION;
-        let o_step = (o_end - o_start) / CURVE_SEARCH_PASS_RESOLUTION;
-
-        while b <= b_end {
-            // Only print at the beginning of each b loop.
-            if p == p_start && m == m_start && o == o_start {
-                // .max(0.0) to stop nonsense negative values caused by floating point imprecision.
-                let progress_percent = (((b - b_start) / (b_end - b_start)) * 100.0)
-                    .floor()
-                    .max(0.0);
-                println!("Progress: {progress_percent}%");
-            }
+    /// Like fit_smile_with_budget(), but searches the default search ranges with differential evolution rather than from a sample,
+    /// then polishes the best curve found with Levenberg-Marquardt. Each candidate curve still has its best a found
+    /// analytically, so only b, p, m and o are evolved. If the budget or the smile's time limit runs out, the generation
+    /// running at the time is the last, and the smile's iteration limit caps the number of generations.
+    fn fit_smile_with_differential_evolution(
+        &mut self,
+        progress: &ProgressBar,
+        budget: Option<Duration>,
+    ) -> Result<bool, TsError> {
+        let start = Instant::now();
+        let bounds = self.get_default_search_ranges()?;
+        let inputs = self.get_fit_inputs()?;
+        let max_generations = self
+            .fit_limits
+            .max_iterations
+            .map_or(DIFFERENTIAL_EVOLUTION_MAX_GENERATIONS, |x| x.min(DIFFERENTIAL_EVOLUTION_MAX_GENERATIONS));
+        let deadline = [budget.map(|x| start + x), self.fit_limits.get_deadline(start)]
+            .into_iter()
+            .flatten()
+            .min();
+        let settings = DifferentialEvolutionSettings {
+            population: DIFFERENTIAL_EVOLUTION_POPULATION,
+            max_generations,
+            mutation: DIFFERENTIAL_EVOLUTION_MUTATION,
+            crossover: DIFFERENTIAL_EVOLUTION_CROSSOVER,
+            required_improvement: constants::SVI_FITTING_REQUIRED_IMPROVEMENT,
+            stagnant_generations: DIFFERENTIAL_EVOLUTION_STAGNANT_GENERATIONS,
+            seed: DIFFERENTIAL_EVOLUTION_SEED,
+            deadline,
+        };
+        let mut generations_progress = progress.new_child("Differential evolution", max_generations);
+        let mut last_generation = 0;
+
+        let ([b, p, m, o], _) = minimise_with_differential_evolution(
+            |[b, p, m, o]| match self.evaluate_svi_params(Vector4::new(*b, *p, *m, *o), &inputs) {
+                Some((_, loss)) => loss,
+                None => f64::MAX,
+            },
+            &bounds,
+            &settings,
+            |generation, loss| {
+                generations_progress.increment();
+                last_generation = generation;
+
+                if generation % 10 == 0 {
+                    generations_progress.message(format!("Best error after generation {generation} is {loss}..."));
+                }
+            },
+        );
 
-            while p <= p_end {
-                while m <= m_end {
-                    while o <= o_end {
-                        let new_params = SVICurveParameters::new_from_values(0.0, b, p, m, o);
+        generations_progress.finish();
 
-                        let result = match new_params {
-                            Err(_) => {
-                                o += o_step;
-                                continue;
-                            }
-                            Ok(params) => self.optimise_svi_params(params),
-                        };
+        let evolved = self.evaluate_svi_params(Vector4::new(b, p, m, o), &inputs);
+        let polished = SVICurveParameters::new_from_values(0.0, b, p, m, o).and_then(|x| self.optimise_svi_params(x, &inputs));
+        let (curve, error) = match (evolved, polished) {
+            (Some(evolved), Ok(polished)) if evolved.1 < polished.1 => evolved,
+            (_, Ok(polished)) => polished,
+            (Some(evolved), Err(_)) => evolved,
+            (None, Err(e)) => return Err(e),
+        };
+        let timed_out = last_generation < max_generations && deadline.is_some_and(|x| Instant::now() >= x);
+        let generations_capped = max_generations < DIFFERENTIAL_EVOLUTION_MAX_GENERATIONS && last_generation >= max_generations;
 
-                        let (optimised_params, error) = match result {
-                            Err(_) => {
-                                o += o_step;
-                                continue;
-                            }
-                            Ok(v) => v,
-                        };
+        self.svi_curve_parameters = curve;
+        self.has_been_fit = true;
+        self.budget_limited = timed_out || generations_capped;
 
-                        // Set this before we return so that when we come back to this function we are not
-                        // doing the same thing twice.
-                        o += o_step;
+        progress.message(format!("Smile fit with error of {error} after {last_generation} generations..."));
 
-                        if error <= (current_best_error - (current_best_error * constants::SVI_FITTING_REQUIRED_IMPROVEMENT)) {
-                            return (false, error, optimised_params, b, p, m, o);
-                        }
-                    }
+        Ok(self.budget_limited)
+    }
 
-                    o = o_start;
-                    m += m_step;
-                }
+    /// Like fit_smile_with_budget(), but scores BATCH_SEARCH_CANDIDATES starting curves sampled from the default search
+    /// ranges, spread over every core in batches, and only runs Levenberg-Marquardt from the BATCH_SEARCH_POLISHED_CURVES
+    /// cheapest. Scoring a curve only finds its best a, so is far quicker than optimising from it. If the budget or the
+    /// smile's time limit runs out while polishing, the best curve polished so far is kept.
+    #[cfg(feature = "batch-search")]
+    fn fit_smile_with_batch_search(&mut self, progress: &ProgressBar, budget: Option<Duration>) -> Result<bool, TsError> {
+        use rayon::prelude::*;
+
+        let start = Instant::now();
+        let inputs = self.get_fit_inputs()?;
+        let deadline = [budget.map(|x| start + x), self.fit_limits.get_deadline(start)]
+            .into_iter()
+            .flatten()
+            .min();
+        let candidates = latin_hypercube_sample(&self.get_default_search_ranges()?, BATCH_SEARCH_CANDIDATES, CURVE_SEARCH_SEED);
+
+        // Invalid curves and those with arbitrage can't be started from, so are left out.
+        let mut scored = candidates
+            .par_chunks(BATCH_SEARCH_CHUNK_SIZE)
+            .flat_map_iter(|chunk| {
+                chunk.iter().filter_map(|[b, p, m, o]| {
+                    self.evaluate_svi_params(Vector4::new(*b, *p, *m, *o), &inputs)
+                        .map(|(_, loss)| ([*b, *p, *m, *o], loss))
+                })
+            })
+            .collect::<Vec<([f64; 4], f64)>>();
+
+        progress.message(format!(
+            "Scored {} starting curves, {} of them valid, in {} seconds...",
+            candidates.len(),
+            scored.len(),
+            start.elapsed().as_secs_f64().round_to_decimal_places(3)
+        ));
+
+        scored.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        let starting_points = scored
+            .into_iter()
+            .take(BATCH_SEARCH_POLISHED_CURVES)
+            .map(|x| x.0)
+            .collect::<Vec<[f64; 4]>>();
+        let mut polish_progress = progress.new_child("Polishing", starting_points.len() as u64);
+        let result = self.optimise_from_starting_points(&starting_points, &inputs, &mut polish_progress, deadline)?;
+        polish_progress.finish();
+
+        let (curve, error) = result.ok_or(TsError::new(UnsolvableError, "No curve without butterfly arbitrage found"))?;
+
+        self.svi_curve_parameters = curve;
+        self.has_been_fit = true;
+        self.budget_limited = deadline.is_some_and(|x| Instant::now() >= x);
 
-                m = m_start;
-                p += p_step;
-            }
+        progress.message(format!("Smile fit with error of {error} after polishing {} curves...", starting_points.len()));
+
+        Ok(self.budget_limited)
+    }
 
-            p = p_start;
-            b += b_step;
+    /// The curve with the given b, p, m and o and the best a, and its loss (as reported by Levenberg-Marquardt), or None if
+    /// it's invalid or has butterfly arbitrage.
+    fn evaluate_svi_params(&self, p: Vector4<f64>, inputs: &FitInputs) -> Option<(SVICurveParameters, f64)> {
+        // Differential evolution searches the raw parameters, so they might not be valid.
+        SVICurveParameters::new_from_values(0.0, p.x, p.y, p.z, p.w).ok()?;
+
+        let x = to_unconstrained_svi_params(p.x, p.y, p.z, p.w);
+        let mut problem = SVIProblem::new(x, self, inputs);
+
+        problem.set_params(&x);
+
+        if problem.has_arbitrage {
+            return None;
         }
 
-        (true, 0.0, SVICurveParameters::default(), 0.0, 0.0, 0.0, 0.0)
+        let loss = 0.5
+            * problem
+                .residuals_buffer
+                .iter()
+                .chain(&problem.penalty_residuals)
+                .map(|x| x * x)
+                .sum::<f64>();
+
+        problem.curve.map(|x| (x, loss))
+    }
+
+    /// Optimise each of the given (b, p, m, o) starting points with Levenberg-Marquardt, returning the curve with the least
+    /// error, or None if none of them led to a valid curve without arbitrage. The starting points are shared between
+    /// search_threads threads. The progress bar is incremented for each starting point. Once the deadline passes, no more
+    /// starting points are optimised.
+    fn optimise_from_starting_points(
+        &self,
+        starting_points: &[[f64; 4]],
+        inputs: &FitInputs,
+        progress: &mut ProgressBar,
+        deadline: Option<Instant>,
+    ) -> Result<Option<(SVICurveParameters, f64)>, TsError> {
+        let progress = &Mutex::new(progress);
+        let lock_error = || TsError::new(RuntimeError, "A search thread panicked while holding a lock");
+        let chunk_size = starting_points
+            .len()
+            .div_ceil(self.search_threads.max(1))
+            .max(1);
+
+        let results = thread::scope(|scope| {
+            let handles: Vec<_> = starting_points
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || -> Result<Vec<(SVICurveParameters, f64)>, TsError> {
+                        let mut results = Vec::new();
+
+                        for (i, [b, p, m, o]) in chunk.iter().enumerate() {
+                            // Each thread optimises at least one starting point, so that a curve is found even if the
+                            // deadline has already passed.
+                            if i > 0 && deadline.is_some_and(|x| Instant::now() >= x) {
+                                break;
+                            }
+
+                            // Invalid starting points can't be optimised, so just move on.
+                            if let Ok(result) = SVICurveParameters::new_from_values(0.0, *b, *p, *m, *o)
+                                .and_then(|x| self.optimise_svi_params(x, inputs))
+                            {
+                                results.push(result);
+                            }
+
+                            progress.lock().map_err(|_| lock_error())?.increment();
+                        }
+
+                        Ok(results)
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|x| {
+                    x.join()
+                        .map_err(|_| TsError::new(RuntimeError, "Search thread panicked"))?
+                })
+                .collect::<Result<Vec<Vec<(SVICurveParameters, f64)>>, TsError>>()
+        })?;
+
+        Ok(results
+            .into_iter()
+            .flatten()
+            .min_by(|a, b| a.1.total_cmp(&b.1)))
     }
 
     /// Checks if this smile graph is valid and generally safe for use. If not, a string error is returned with a reason.
@@ -446,39 +1184,276 @@ impl SmileGraph {
     }
 }
 
+/// What the optimiser fits a smile to, none of which changes during the fit. It's worked out once per fit (see
+/// SmileGraph::get_fit_inputs()) and shared by every curve tried, so that the hot loops only read plain numbers.
+struct FitInputs {
+    /// The options, including their log moneyness and total implied variance.
+    quotes: QuoteTable,
+    /// How much each option counts towards the fit (see SmileGraph::get_fit_weights()). Residuals are scaled by the square
+    /// root of these, so that the squared errors are scaled by them.
+    weights: Vec<f64>,
+    weight_roots: Vec<f64>,
+    total_weight: f64,
+    forward_price: f64,
+    /// The b, p, m and o the fit is pulled towards, and what their penalties are scaled by (see
+    /// SmileGraph::set_fit_prior()).
+    prior: Option<([f64; 4], f64)>,
+    atm_anchor: Option<AtmAnchor>,
+}
+
+/// Where the fitted curve has to pass at the money, to within a tolerance (see SmileGraph::set_atm_anchor()).
+#[derive(Clone, Copy)]
+struct AtmAnchor {
+    /// Of the straddle's strike.
+    log_moneyness: f64,
+    /// The lowest and highest total variance the curve can have there without being penalised.
+    lowest_variance: f64,
+    highest_variance: f64,
+    /// What the anchor's residual is scaled by, the square root of its weight.
+    weight_root: f64,
+}
+
+impl AtmAnchor {
+    /// How far the curve's total variance at the money is outside the tolerance, weighted.
+    fn get_residual(&self, params: [f64; 5]) -> f64 {
+        let variance = get_svi_variance(params, self.log_moneyness);
+
+        (variance - variance.clamp(self.lowest_variance, self.highest_variance)) * self.weight_root
+    }
+}
+
 /// Used to solve SVI using Levenberg-Marquardt.
 struct SVIProblem<'graph> {
-    /// Holds the current value of the parameters used in the SVI equation.
-    /// x = b
-    /// y = p
-    /// z = m
-    /// w = o
-    p: Vector4<f64>,
+    /// The parameters in the optimiser's unconstrained space (see from_unconstrained_svi_params()).
+    x: Vector4<f64>,
     smile_graph: &'graph SmileGraph,
+    inputs: &'graph FitInputs,
+    /// The curve for the current parameters, or None if it couldn't be calculated.
     curve: Option<SVICurveParameters>,
-    curve_valid: bool,
     has_arbitrage: bool,
+    /// The butterfly arbitrage residual (see get_butterfly_penalty()).
+    butterfly_penalty: f64,
     residuals_buffer: Vec<f64>,
+    /// Residuals after the options' ones: how far the curve is from the prior (one per parameter) if the fit has one,
+    /// then how far it is outside the at the money anchor if the fit has one, then the butterfly arbitrage penalty if
+    /// arbitrage is checked for.
+    penalty_residuals: Vec<f64>,
 }
 
-fn calculate_least_squares_residual(
-    params: &SVICurveParameters,
-    option: &OptionInstrument,
-    forward_price: f64,
-) -> Result<f64, TsError> {
-    let log_moneyness = option.get_log_moneyness_using_custom_forward(forward_price);
+impl<'graph> SVIProblem<'graph> {
+    /// One residual for each option, one for each parameter pulled towards the prior, one for the at the money anchor and
+    /// one for butterfly arbitrage.
+    fn get_residual_count(&self) -> usize {
+        self.inputs.quotes.len()
+            + self.inputs.prior.map_or(0, |_| 4)
+            + self.inputs.atm_anchor.map_or(0, |_| 1)
+            + constants::CHECK_FOR_ARBITRAGE as usize
+    }
+
+    /// How far the curve's g(k) falls below BUTTERFLY_PENALTY_MARGIN around the observed strikes, scaled by
+    /// BUTTERFLY_PENALTY_WEIGHT. Penalising this rather than rejecting curves with arbitrage outright lets the optimiser
+    /// see which way the nearest curve without arbitrage is.
+    fn get_butterfly_penalty(&self, curve: &SVICurveParameters) -> Result<f64, TsError> {
+        Ok(BUTTERFLY_PENALTY_WEIGHT
+            * get_butterfly_arbitrage_shortfall(
+                curve,
+                self.smile_graph.lowest_observed_strike,
+                self.smile_graph.highest_observed_strike,
+                self.inputs.forward_price,
+                &self.smile_graph.butterfly_check,
+                BUTTERFLY_PENALTY_MARGIN,
+            )?)
+    }
+
+    /// A problem starting from the given parameters, in the optimiser's unconstrained space. set_params() must be called
+    /// before it's used.
+    fn new(x: Vector4<f64>, smile_graph: &'graph SmileGraph, inputs: &'graph FitInputs) -> Self {
+        Self {
+            x,
+            smile_graph,
+            inputs,
+            curve: None,
+            has_arbitrage: false,
+            butterfly_penalty: 0.0,
+            residuals_buffer: vec![0.0; inputs.quotes.len()],
+            penalty_residuals: Vec::new(),
+        }
+    }
+}
 
-    // This uses the option's own forward price. Which would probably be wrong were it not for the fact that
-    // all options of the same expiry are given the same spot price (and therefore forward price).
-    let total_implied_variance = option.get_total_implied_variance()?;
+/// The most extreme p the optimiser can reach. tanh rounds to exactly 1 for large arguments, which isn't a valid curve.
+const MAX_ABSOLUTE_P: f64 = 0.9999;
+/// How close to zero and to Lee's bound the steeper wing can get. Rounding could otherwise put it exactly on either.
+const MIN_STEEPEST_SLOPE_FRACTION: f64 = 1e-12;
+const MAX_STEEPEST_SLOPE_FRACTION: f64 = 1.0 - 1e-12;
+/// How far m can get from the money, in log moneyness. This is far beyond any real smile, but stops a long optimiser step
+/// moving the curve so far away that its variance overflows.
+const MAX_ABSOLUTE_M: f64 = 10.0;
+/// How far ln(o) can get from zero, for the same reason (see saturating_exp()).
+const MAX_ABSOLUTE_LOG_O: f64 = 20.0;
+
+/// What the butterfly arbitrage penalty is scaled by, relative to the options' (weighted) residuals. g(k) is around 1 for
+/// a typical smile, so even a small shortfall outweighs the error of a good fit.
+const BUTTERFLY_PENALTY_WEIGHT: f64 = 1.0;
+/// g(k) is penalised for falling below this rather than below zero, so that the fit settles just clear of arbitrage rather
+/// than just over the edge of it, where it would be rejected.
+pub(super) const BUTTERFLY_PENALTY_MARGIN: f64 = 0.001;
+/// The step used to estimate how the butterfly penalty changes with each parameter by finite differences, as its
+/// derivatives have no neat closed form.
+pub(super) const BUTTERFLY_PENALTY_JACOBIAN_STEP: f64 = 0.0000001;
+
+/// Convert b, p, m and o to the optimiser's unconstrained parameters. See from_unconstrained_svi_params().
+pub fn to_unconstrained_svi_params(b: f64, p: f64, m: f64, o: f64) -> Vector4<f64> {
+    let steepest_slope =
+        (b * (1.0 + p.abs()) / LEE_MAXIMUM_WING_SLOPE).clamp(MIN_STEEPEST_SLOPE_FRACTION, MAX_STEEPEST_SLOPE_FRACTION);
+
+    Vector4::new(
+        (steepest_slope / (1.0 - steepest_slope)).ln(),
+        (p / MAX_ABSOLUTE_P)
+            .clamp(-1.0 + f64::EPSILON, 1.0 - f64::EPSILON)
+            .atanh(),
+        MAX_ABSOLUTE_M
+            * (m / MAX_ABSOLUTE_M)
+                .clamp(-1.0 + f64::EPSILON, 1.0 - f64::EPSILON)
+                .atanh(),
+        saturating_ln(o, MAX_ABSOLUTE_LOG_O),
+    )
+}
+
+/// Convert the optimiser's unconstrained parameters to b, p, m and o, so that every step the optimiser takes is a valid
+/// curve with finite variance. p = tanh(y) keeps |p| below 1, and o = e^w keeps o positive. b = 2 sigmoid(x) / (1 + |p|)
+/// keeps b positive and the steeper wing's slope, b(1 + |p|), below Lee's bound of 2. m and ln(o) are smoothly limited
+/// (with tanh) to a range far wider than any real smile needs.
+pub fn from_unconstrained_svi_params(x: &Vector4<f64>) -> [f64; 4] {
+    let p = MAX_ABSOLUTE_P * x.y.tanh();
+    let steepest_slope = sigmoid(x.x).clamp(MIN_STEEPEST_SLOPE_FRACTION, MAX_STEEPEST_SLOPE_FRACTION);
+
+    [
+        LEE_MAXIMUM_WING_SLOPE * steepest_slope / (1.0 + p.abs()),
+        p,
+        MAX_ABSOLUTE_M * (x.z / MAX_ABSOLUTE_M).tanh(),
+        saturating_exp(x.w, MAX_ABSOLUTE_LOG_O),
+    ]
+}
+
+/// How b, p, m and o change with the optimiser's unconstrained parameters (see from_unconstrained_svi_params()), for the
+/// chain rule: db/dx, db/dy, dp/dy, dm/dz and do/dw. None of them change with any other.
+pub fn get_unconstrained_svi_derivatives(x: &Vector4<f64>) -> [f64; 5] {
+    let [b, p, _, _] = from_unconstrained_svi_params(x);
+    let steepest_slope = sigmoid(x.x);
+    let dp_dy = MAX_ABSOLUTE_P * (1.0 - x.y.tanh().powi(2));
+
+    [
+        LEE_MAXIMUM_WING_SLOPE * steepest_slope * (1.0 - steepest_slope) / (1.0 + p.abs()),
+        -b / (1.0 + p.abs()) * p.signum() * dp_dy,
+        dp_dy,
+        1.0 - (x.z / MAX_ABSOLUTE_M).tanh().powi(2),
+        saturating_exp_derivative(x.w, MAX_ABSOLUTE_LOG_O),
+    ]
+}
+
+/// The curve for the optimiser's unconstrained parameters with the best a, setting each option's residual (unweighted) in
+/// residuals. None if rounding stops it being a valid curve, which the limits in from_unconstrained_svi_params() should
+/// stop ever happening.
+fn calculate_svi_curve(x: &Vector4<f64>, inputs: &FitInputs, residuals: &mut [f64]) -> Option<SVICurveParameters> {
+    let [b, p, m, o] = from_unconstrained_svi_params(x);
 
-    // Check the error even if constants::VALIDATE_SVI is false, because allowing this will probably mess with the error
+    // We're going to average the residuals and then use this to manually calculate the best value for a.
+    // This is much more efficient and accurate. a is just a vertical offset, so this is simple to do. Variance can't
+    // be negative, even if constants::VALIDATE_SVI is false, because allowing it would probably mess with the error
     // function.
-    let svi_variance = svi_variance(params, log_moneyness)?;
+    if set_svi_residuals(residuals, &inputs.quotes, [0.0, b, p, m, o]) <= 0.0 {
+        return None;
+    }
+
+    // Get "a" parameter based on average residuals. Variance must stay positive at its lowest point, and since the loss
+    // is quadratic in a, the nearest a that allows is the best one if the average doesn't.
+    let average_residual = weighted_sum(residuals, &inputs.weights) / inputs.total_weight;
+    let lowest_a = -b * o * (1.0 - p * p).sqrt();
+    let a = (-average_residual).max(lowest_a + lowest_a.abs() * 0.000001 + f64::MIN_POSITIVE);
+
+    let curve = SVICurveParameters::new_from_values(a, b, p, m, o).ok()?;
+
+    match set_svi_residuals(residuals, &inputs.quotes, [a, b, p, m, o]) > 0.0 {
+        true => Some(curve),
+        false => None,
+    }
+}
+
+/// The number of options the fitting loops work on at once. Splitting a sum into this many separate running totals breaks
+/// the dependency between one step and the next, which lets the compiler turn the loop into SIMD instructions.
+const FIT_LOOP_LANES: usize = 4;
+
+/// The SVI total variance at the log moneyness, without svi_variance()'s checks, for the fitting loops.
+#[inline]
+fn get_svi_variance([a, b, p, m, o]: [f64; 5], log_moneyness: f64) -> f64 {
+    let d = log_moneyness - m;
+
+    a + b * ((p * d) + ((d * d) + (o * o)).sqrt())
+}
+
+/// Set each option's residual, its SVI total variance less its total implied variance (unweighted), returning the lowest
+/// SVI total variance. The total implied variances use the options' own forward price. Which would probably be wrong were
+/// it not for the fact that all options of the same expiry are given the same spot price (and therefore forward price).
+fn set_svi_residuals(residuals: &mut [f64], quotes: &QuoteTable, [a, b, p, m, o]: [f64; 5]) -> f64 {
+    let mut lowest_variances = [f64::INFINITY; FIT_LOOP_LANES];
+    let mut lowest_variance = f64::INFINITY;
+
+    let mut residual_chunks = residuals.chunks_exact_mut(FIT_LOOP_LANES);
+    let mut log_moneyness_chunks = quotes.log_moneyness.chunks_exact(FIT_LOOP_LANES);
+    let mut variance_chunks = quotes.total_implied_variance.chunks_exact(FIT_LOOP_LANES);
+
+    for ((residuals, log_moneyness), total_implied_variance) in (&mut residual_chunks)
+        .zip(&mut log_moneyness_chunks)
+        .zip(&mut variance_chunks)
+    {
+        for (((residual, log_moneyness), total_implied_variance), lowest) in residuals
+            .iter_mut()
+            .zip(log_moneyness)
+            .zip(total_implied_variance)
+            .zip(&mut lowest_variances)
+        {
+            let variance = get_svi_variance([a, b, p, m, o], *log_moneyness);
+
+            *lowest = lowest.min(variance);
+            *residual = variance - total_implied_variance;
+        }
+    }
+
+    for ((residual, log_moneyness), total_implied_variance) in residual_chunks
+        .into_remainder()
+        .iter_mut()
+        .zip(log_moneyness_chunks.remainder())
+        .zip(variance_chunks.remainder())
+    {
+        let variance = get_svi_variance([a, b, p, m, o], *log_moneyness);
+
+        lowest_variance = lowest_variance.min(variance);
+        *residual = variance - total_implied_variance;
+    }
+
+    lowest_variances.into_iter().fold(lowest_variance, f64::min)
+}
+
+/// The sum of the values multiplied by their weights.
+fn weighted_sum(values: &[f64], weights: &[f64]) -> f64 {
+    let mut totals = [0.0; FIT_LOOP_LANES];
+    let value_chunks = values.chunks_exact(FIT_LOOP_LANES);
+    let weight_chunks = weights.chunks_exact(FIT_LOOP_LANES);
+    let remainder = value_chunks
+        .remainder()
+        .iter()
+        .zip(weight_chunks.remainder())
+        .map(|(value, weight)| value * weight)
+        .sum::<f64>();
+
+    for (values, weights) in value_chunks.zip(weight_chunks) {
+        for ((total, value), weight) in totals.iter_mut().zip(values).zip(weights) {
+            *total += value * weight;
+        }
+    }
 
-    // We could also add weighting to each option depending on the quality of its data.
-    // But we'll treat them all equally for now.
-    Ok(svi_variance - total_implied_variance)
+    totals.into_iter().sum::<f64>() + remainder
 }
 
 impl LeastSquaresProblem<f64, Dyn, U4> for SVIProblem<'_> {
@@ -487,176 +1462,195 @@ impl LeastSquaresProblem<f64, Dyn, U4> for SVIProblem<'_> {
     type JacobianStorage = Owned<f64, Dyn, U4>;
 
     // Common calculations for residuals and the Jacobian.
-    fn set_params(&mut self, p: &Vector4<f64>) {
-        self.p.copy_from(p);
-        let svi_params = SVICurveParameters::new_from_values(0.0, self.p.x, self.p.y, self.p.z, self.p.w);
-        let mut total_residuals = 0.0;
-
-        // Assume not valid.
+    fn set_params(&mut self, x: &Vector4<f64>) {
+        self.x.copy_from(x);
         self.has_arbitrage = false;
-        self.curve_valid = false;
+        self.butterfly_penalty = 0.0;
         self.curve = None;
 
-        // Calculate total residuals.
-        match &svi_params {
-            Ok(params) => {
-                // We're going to average the residuals and then use this to manually calculate the best value for a.
-                // This is much more efficient and accurate. a is just a vertical offset, so this is simple to do.
-                for option in &self.smile_graph.options {
-                    let residual = calculate_least_squares_residual(
-                        params,
-                        option,
-                        self.smile_graph
-                            .get_underlying_forward_price()
-                            .expect("Graph forward price must be valid"),
-                    );
-
-                    match residual {
-                        Err(_) => {
-                            // If our curve is already invalid then it's probably best to give up.
-                            return;
-                        }
-                        Ok(v) => total_residuals += v,
-                    };
-                }
-            }
-            Err(_) => return,
+        let Some(curve) = calculate_svi_curve(&self.x, self.inputs, &mut self.residuals_buffer) else {
+            return;
+        };
+        let [a, b, p, m, o] = [curve.get_a(), curve.get_b(), curve.get_p(), curve.get_m(), curve.get_o()];
+
+        // We'll save these because we'll use them again in residuals().
+        for (residual, weight_root) in self
+            .residuals_buffer
+            .iter_mut()
+            .zip(&self.inputs.weight_roots)
+        {
+            *residual *= weight_root;
         }
 
-        // Get "a" parameter based on average residuals.
-        let average_residual = total_residuals / self.smile_graph.options.len() as f64;
-        let svi_params = SVICurveParameters::new_from_values(-average_residual, self.p.x, self.p.y, self.p.z, self.p.w);
+        self.penalty_residuals.clear();
 
-        // Check these parameters are okay.
-        match svi_params {
-            Err(_) => return,
-            Ok(v) => self.curve = Some(v),
+        if let Some(([prior_b, prior_p, prior_m, prior_o], scale)) = self.inputs.prior {
+            self.penalty_residuals.extend([
+                scale * (b - prior_b) / prior_b,
+                scale * (p - prior_p),
+                scale * (m - prior_m) / prior_o,
+                scale * (o - prior_o) / prior_o,
+            ]);
         }
 
-        // Check validity by building residuals. We'll save these because we'll use them again in residuals().
-        for (n, option) in self.smile_graph.options.iter().enumerate() {
-            let residual = calculate_least_squares_residual(
-                self.curve.as_ref().unwrap(),
-                option,
-                self.smile_graph
-                    .get_underlying_forward_price()
-                    .expect("Graph forward price must be valid"),
-            );
-
-            match residual {
-                Ok(v) => self.residuals_buffer[n] = v,
-                Err(_) => return,
-            }
+        if let Some(anchor) = self.inputs.atm_anchor {
+            self.penalty_residuals
+                .push(anchor.get_residual([a, b, p, m, o]));
         }
 
         if constants::CHECK_FOR_ARBITRAGE {
-            // If there is arbitrage then this curve is mathematically invalid. Fail it.
-            let butterfly_arbitrage_found = has_butterfly_arbitrage(
-                self.curve.as_ref().unwrap(),
-                1,
-                (self.smile_graph.highest_observed_strike * 1.5).ceil() as u64,
-                self.smile_graph
-                    .get_underlying_forward_price()
-                    .expect("Graph forward price must be valid"),
-                150,
-            );
+            // A curve with arbitrage is mathematically invalid, so is penalised while fitting and rejected if it's where
+            // the fit ends up. Only a curve that g(k) comes near zero for can have any.
+            let arbitrage = self.get_butterfly_penalty(&curve).and_then(|penalty| {
+                self.butterfly_penalty = penalty;
+
+                match penalty > 0.0 {
+                    true => has_butterfly_arbitrage(
+                        &curve,
+                        self.smile_graph.lowest_observed_strike,
+                        self.smile_graph.highest_observed_strike,
+                        self.inputs.forward_price,
+                        &self.smile_graph.butterfly_check,
+                    ),
+                    false => Ok(false),
+                }
+            });
 
-            match butterfly_arbitrage_found {
+            match arbitrage {
                 Err(_) => return,
-                Ok(has_arbitrage) => {
-                    if has_arbitrage {
-                        self.has_arbitrage = true;
-                        return;
-                    }
-                }
+                Ok(has_arbitrage) => self.has_arbitrage = has_arbitrage,
             }
+            self.penalty_residuals.push(self.butterfly_penalty);
 
             // We should also be checking for calendar arbitrage, but since this software just handles discrete expiry slices,
             // we'll overlook it for now.
         }
 
-        self.curve_valid = true;
+        self.curve = Some(curve);
     }
 
     fn params(&self) -> Vector4<f64> {
-        self.p
+        self.x
     }
 
     fn residuals(&self) -> Option<Matrix<f64, Dyn, U1, Self::ResidualStorage>> {
-        let options_count = self.smile_graph.options.len();
-        let mut residuals: Vec<f64> = Vec::with_capacity(options_count);
-
-        for n in 0..options_count {
-            // These params are garbage, push a very high loss.
-            // We have already checked constants::VALIDATE_SVI by this point.
-            if !self.curve_valid || self.has_arbitrage {
-                residuals.push(constants::INVALID_FIT_PENALITY);
-                continue;
-            }
-
-            // Use the residual we saved earlier.
-            residuals.push(self.residuals_buffer[n]);
-        }
+        // Every unconstrained parameter maps to a valid curve, so this only stops the fit if rounding breaks one.
+        self.curve.as_ref()?;
 
-        Some(OMatrix::<f64, Dyn, U1>::from_row_slice(&residuals))
+        Some(OMatrix::<f64, Dyn, U1>::from_row_slice(
+            &[self.residuals_buffer.as_slice(), &self.penalty_residuals].concat(),
+        ))
     }
 
     fn jacobian(&self) -> Option<Matrix<f64, Dyn, U4, Self::JacobianStorage>> {
-        let [b, p, m, o] = [self.p.x, self.p.y, self.p.z, self.p.w];
-        let options_count = self.smile_graph.options.len();
-        let mut result = Vec::<f64>::with_capacity(options_count * 4);
-
-        if self.has_arbitrage || !self.curve_valid {
-            // Curve is rubbish so just push 0 for everything to punish the algorithm.
-            return Some(OMatrix::<f64, Dyn, U4>::from_row_slice(&vec![0.0; options_count * 4]));
-        }
-
-        // Build the Jacobians matrix.
-        for option in &self.smile_graph.options {
+        let curve = self.curve.as_ref()?;
+        let [b, p, m, o] = from_unconstrained_svi_params(&self.x);
+        let options_count = self.inputs.quotes.len();
+        let residual_count = self.get_residual_count();
+
+        let [db_dx, db_dy, dp_dy, dm_dz, do_dw] = get_unconstrained_svi_derivatives(&self.x);
+
+        // Build the Jacobian a column at a time (the matrix is column major), so that each parameter's derivatives are
+        // one contiguous slice.
+        let mut result = vec![0.0; residual_count * 4];
+        let (deriv_b, rest) = result.split_at_mut(residual_count);
+        let (deriv_p, rest) = rest.split_at_mut(residual_count);
+        let (deriv_m, deriv_o) = rest.split_at_mut(residual_count);
+        let (deriv_b, penalty_deriv_b) = deriv_b.split_at_mut(options_count);
+        let (deriv_p, penalty_deriv_p) = deriv_p.split_at_mut(options_count);
+        let (deriv_m, penalty_deriv_m) = deriv_m.split_at_mut(options_count);
+        let (deriv_o, penalty_deriv_o) = deriv_o.split_at_mut(options_count);
+
+        for ((((log_moneyness, deriv_b), deriv_p), deriv_m), deriv_o) in self
+            .inputs
+            .quotes
+            .log_moneyness
+            .iter()
+            .zip(deriv_b.iter_mut())
+            .zip(deriv_p.iter_mut())
+            .zip(deriv_m.iter_mut())
+            .zip(deriv_o.iter_mut())
+        {
             // d and s come directly from the SVI equation. By using them we make writing the derivatives below simpler.
-            let d = option.get_log_moneyness_using_custom_forward(
-                self.smile_graph
-                    .get_underlying_forward_price()
-                    .expect("Graph forward price must be valid"),
-            ) - m;
+            let d = log_moneyness - m;
             let s = ((d * d) + (o * o)).sqrt();
 
-            let deriv_b = p * d + s;
-            let deriv_p = b * d;
-            let deriv_m = b * (-p - (d / s));
-            let deriv_o = b * (o / s);
+            *deriv_b = p * d + s;
+            *deriv_p = b * d;
+            *deriv_m = b * (-p - (d / s));
+            *deriv_o = b * (o / s);
+        }
 
-            result.push(deriv_b);
-            result.push(deriv_p);
-            result.push(deriv_m);
-            result.push(deriv_o);
+        // We also need to cancel out any vertical shift that's already accounted for by the manual change in a. The means
+        // are weighted in the same way as the residuals that a is calculated from.
+        let mean_b = weighted_sum(deriv_b, &self.inputs.weights) / self.inputs.total_weight;
+        let mean_p = weighted_sum(deriv_p, &self.inputs.weights) / self.inputs.total_weight;
+        let mean_m = weighted_sum(deriv_m, &self.inputs.weights) / self.inputs.total_weight;
+        let mean_o = weighted_sum(deriv_o, &self.inputs.weights) / self.inputs.total_weight;
+
+        // This ignores a being held up to keep variance positive, which only happens far from a good fit.
+        for ((((deriv_b, deriv_p), deriv_m), deriv_o), scale) in deriv_b
+            .iter_mut()
+            .zip(deriv_p.iter_mut())
+            .zip(deriv_m.iter_mut())
+            .zip(deriv_o.iter_mut())
+            .zip(&self.inputs.weight_roots)
+        {
+            let scaled_b = (*deriv_b - mean_b) * scale;
+            let scaled_p = (*deriv_p - mean_p) * scale;
+
+            *deriv_b = scaled_b * db_dx;
+            *deriv_p = scaled_b * db_dy + scaled_p * dp_dy;
+            *deriv_m = (*deriv_m - mean_m) * scale * dm_dz;
+            *deriv_o = (*deriv_o - mean_o) * scale * do_dw;
         }
 
-        // We also need to cancel out any vertical shift that's already accounted for by the manual change in a.
-        let mut mean_b = 0.0;
-        let mut mean_p = 0.0;
-        let mut mean_m = 0.0;
-        let mut mean_o = 0.0;
+        // Each of the prior's residuals only depends on one of b, p, m and o.
+        if let Some(([prior_b, _, _, prior_o], scale)) = self.inputs.prior {
+            penalty_deriv_b[0] = scale * db_dx / prior_b;
+            penalty_deriv_p[0] = scale * db_dy / prior_b;
+            penalty_deriv_p[1] = scale * dp_dy;
+            penalty_deriv_m[2] = scale * dm_dz / prior_o;
+            penalty_deriv_o[3] = scale * do_dw / prior_o;
+        }
+
+        let anchor_row = self.inputs.prior.map_or(0, |_| 4);
+
+        // The anchor's residual is the curve's total variance at the money once it's outside the tolerance, so it moves
+        // like an option's residual there, and not at all inside it.
+        if let Some(anchor) = self.inputs.atm_anchor
+            && anchor.get_residual([curve.get_a(), b, p, m, o]) != 0.0
+        {
+            let d = anchor.log_moneyness - m;
+            let s = ((d * d) + (o * o)).sqrt();
+            let scaled_b = (p * d + s - mean_b) * anchor.weight_root;
+            let scaled_p = (b * d - mean_p) * anchor.weight_root;
 
-        for i in 0..options_count {
-            mean_b += result[i * 4];
-            mean_p += result[(i * 4) + 1];
-            mean_m += result[(i * 4) + 2];
-            mean_o += result[(i * 4) + 3];
+            penalty_deriv_b[anchor_row] = scaled_b * db_dx;
+            penalty_deriv_p[anchor_row] = scaled_b * db_dy + scaled_p * dp_dy;
+            penalty_deriv_m[anchor_row] = (b * (-p - (d / s)) - mean_m) * anchor.weight_root * dm_dz;
+            penalty_deriv_o[anchor_row] = (b * (o / s) - mean_o) * anchor.weight_root * do_dw;
         }
 
-        mean_b /= options_count as f64;
-        mean_p /= options_count as f64;
-        mean_m /= options_count as f64;
-        mean_o /= options_count as f64;
+        // The butterfly penalty is zero, and flat, unless g(k) comes near zero somewhere.
+        if constants::CHECK_FOR_ARBITRAGE && self.butterfly_penalty > 0.0 {
+            let row = anchor_row + self.inputs.atm_anchor.map_or(0, |_| 1);
+            let mut residuals = vec![0.0; options_count];
 
-        for i in 0..options_count {
-            result[i * 4] -= mean_b;
-            result[(i * 4) + 1] -= mean_p;
-            result[(i * 4) + 2] -= mean_m;
-            result[(i * 4) + 3] -= mean_o;
+            for (column, derivs) in [penalty_deriv_b, penalty_deriv_p, penalty_deriv_m, penalty_deriv_o]
+                .into_iter()
+                .enumerate()
+            {
+                let mut x = self.x;
+                x[column] += BUTTERFLY_PENALTY_JACOBIAN_STEP;
+
+                let stepped_curve = calculate_svi_curve(&x, self.inputs, &mut residuals)?;
+                let stepped_penalty = self.get_butterfly_penalty(&stepped_curve).ok()?;
+
+                derivs[row] = (stepped_penalty - self.butterfly_penalty) / BUTTERFLY_PENALTY_JACOBIAN_STEP;
+            }
         }
 
-        Some(OMatrix::<f64, Dyn, U4>::from_row_slice(&result))
+        Some(OMatrix::<f64, Dyn, U4>::from_column_slice(&result))
     }
 }
@@ -0,0 +1,101 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    analytics::{OptionInstrument, OptionType},
+    constants,
+    types::{TsError, TsErrorType::UnsolvableError},
+};
+
+/// The call and put at the strike nearest the forward price, and the single implied volatility that prices both of them
+/// together. Near the money the straddle's price hardly depends on the skew, so this is the cleanest measure of the at
+/// the money implied volatility the quotes give.
+#[derive(Clone, Copy, Debug)]
+pub struct Straddle {
+    pub strike: f64,
+    pub price: f64,
+    pub implied_volatility: f64,
+}
+
+/// Find the straddle nearest the forward price in the given options, which must all have the same expiry and their
+/// forward prices set. Returns None if no strike has both a call and a put.
+pub fn find_atm_straddle(options: &[OptionInstrument]) -> Result<Option<Straddle>, TsError> {
+    let Some(first_option) = options.first() else {
+        return Ok(None);
+    };
+    let forward_price = first_option.get_forward_price()?;
+    // Keyed by the strike's bits, as in apply_put_call_parity().
+    let mut strikes: BTreeMap<u64, (Option<&OptionInstrument>, Option<&OptionInstrument>)> = BTreeMap::new();
+
+    for option in options {
+        let entry = strikes.entry(option.strike.to_bits()).or_default();
+
+        match option.option_type {
+            OptionType::Call => entry.0 = entry.0.or(Some(option)),
+            OptionType::Put => entry.1 = entry.1.or(Some(option)),
+        }
+    }
+
+    let nearest_pair = strikes
+        .into_values()
+        .filter_map(|pair| match pair {
+            (Some(call), Some(put)) => Some((call, put)),
+            _ => None,
+        })
+        .min_by(|a, b| {
+            (a.0.strike - forward_price)
+                .abs()
+                .total_cmp(&(b.0.strike - forward_price).abs())
+        });
+
+    let Some((call, put)) = nearest_pair else {
+        return Ok(None);
+    };
+    let price = call.price + put.price;
+
+    Ok(Some(Straddle {
+        strike: call.strike,
+        price,
+        implied_volatility: solve_straddle_implied_volatility(call, put, price)?,
+    }))
+}
+
+/// The implied volatility at which the call and put together are worth the given price, by bisection. A straddle's price
+/// always rises with volatility, so this can't fail to converge once the price is bracketed.
+fn solve_straddle_implied_volatility(call: &OptionInstrument, put: &OptionInstrument, price: f64) -> Result<f64, TsError> {
+    let price_at = |volatility: f64| -> Result<f64, TsError> {
+        Ok(call.get_price_at_implied_volatility(volatility)? + put.get_price_at_implied_volatility(volatility)?)
+    };
+
+    let mut bounds_start: f64 = 0.0;
+    let mut bounds_end: f64 = 2.0;
+    let mut iterations = 0;
+
+    while price_at(bounds_end)? < price {
+        bounds_start = bounds_end;
+        bounds_end *= 2.0;
+        iterations += 1;
+
+        if iterations > 64 {
+            return Err(TsError::new(UnsolvableError, "Too many iterations when finding the straddle's bounds"));
+        }
+    }
+
+    while bounds_end - bounds_start > constants::IMPLIED_VOLATILITY_SOLVER_ACCURACY {
+        let volatility = (bounds_start + bounds_end) * 0.5;
+
+        match price_at(volatility)? < price {
+            true => bounds_start = volatility,
+            false => bounds_end = volatility,
+        }
+    }
+
+    // Only possible if the straddle is worth less than the forward price's distance from the strike, which it can't be.
+    if bounds_start == 0.0 {
+        return Err(TsError::new(
+            UnsolvableError,
+            format!("Straddle price {price} at strike {} is below its intrinsic value", call.strike),
+        ));
+    }
+
+    Ok((bounds_start + bounds_end) * 0.5)
+}
@@ -0,0 +1,100 @@
+use crate::{
+    analytics::{
+        OptionType, SmileGraph, interpolate_surface,
+        math::norm_cdf,
+        surface_interpolation::{SurfacePoint, SurfaceSlice},
+    },
+    helpers::error_unless_positive_f64,
+    types::{
+        TsError,
+        TsErrorType::{RuntimeError, UnsolvableError},
+    },
+};
+
+/// How far either side of the money (in at the money standard deviations) to search for a strike with a given delta.
+const DELTA_SEARCH_STANDARD_DEVIATIONS: f64 = 10.0;
+
+const DELTA_SEARCH_ITERATIONS: u32 = 100;
+
+pub fn strike_to_log_moneyness(strike: f64, forward_price: f64) -> f64 {
+    (strike / forward_price).ln()
+}
+
+pub fn log_moneyness_to_strike(log_moneyness: f64, forward_price: f64) -> f64 {
+    forward_price * log_moneyness.exp()
+}
+
+/// The forward (undiscounted) Black-Scholes delta of an option at the given log moneyness and total variance. Calls have
+/// deltas from 0 to 1, and puts from -1 to 0.
+pub fn calculate_forward_delta(log_moneyness: f64, total_variance: f64, option_type: OptionType) -> Result<f64, TsError> {
+    error_unless_positive_f64(total_variance, "total_variance")?;
+
+    let call_delta = norm_cdf((-log_moneyness + 0.5 * total_variance) / total_variance.sqrt());
+
+    Ok(match option_type {
+        OptionType::Call => call_delta,
+        OptionType::Put => call_delta - 1.0,
+    })
+}
+
+/// Find the log moneyness where an option has the given forward delta, using the total variance of the smile at each log
+/// moneyness tried rather than a single volatility, which is how traders quote deltas. Positive deltas are calls and
+/// negative deltas are puts, e.g. -0.25 for the 25 delta put. Call delta falls as log moneyness rises, so this is found by
+/// bisection.
+pub fn find_log_moneyness_at_delta(get_total_variance: impl Fn(f64) -> Result<f64, TsError>, delta: f64) -> Result<f64, TsError> {
+    if !(delta > -1.0 && delta < 1.0) || delta == 0.0 {
+        return Err(TsError::new(RuntimeError, format!("Delta must be between -1 and 1 and not 0, found {delta}")));
+    }
+
+    // A put's delta is its call's delta minus 1.
+    let call_delta = if delta > 0.0 { delta } else { delta + 1.0 };
+    let get_call_delta =
+        |log_moneyness: f64| calculate_forward_delta(log_moneyness, get_total_variance(log_moneyness)?, OptionType::Call);
+
+    let search_width = DELTA_SEARCH_STANDARD_DEVIATIONS * get_total_variance(0.0)?.sqrt();
+    let mut lowest = -search_width;
+    let mut highest = search_width;
+
+    if get_call_delta(lowest)? < call_delta || get_call_delta(highest)? > call_delta {
+        return Err(TsError::new(UnsolvableError, format!("No strike has a delta of {delta}")));
+    }
+
+    for _ in 0..DELTA_SEARCH_ITERATIONS {
+        let middle = (lowest + highest) / 2.0;
+
+        match get_call_delta(middle)? > call_delta {
+            true => lowest = middle,
+            false => highest = middle,
+        }
+    }
+
+    Ok((lowest + highest) / 2.0)
+}
+
+/// The forward delta of a call at the given strike on a smile, using the smile's volatility at that strike.
+pub fn get_call_delta_at_strike(graph: &SmileGraph, strike: f64) -> Result<f64, TsError> {
+    let log_moneyness = strike_to_log_moneyness(strike, graph.get_underlying_forward_price()?);
+
+    calculate_forward_delta(log_moneyness, graph.get_total_variance_at_log_moneyness(log_moneyness)?, OptionType::Call)
+}
+
+/// Read the surface at the given forward delta (see find_log_moneyness_at_delta()) and time to expiry, which doesn't have to
+/// be one of the fitted expiries. The smiles must be ordered by expiry.
+pub fn interpolate_surface_at_delta<T: SurfaceSlice>(
+    smile_graphs: &[T],
+    delta: f64,
+    years_until_expiry: f64,
+) -> Result<SurfacePoint, TsError> {
+    let first_graph = smile_graphs
+        .first()
+        .ok_or(TsError::new(RuntimeError, "The surface has no smiles"))?;
+    let forward_price =
+        interpolate_surface(smile_graphs, first_graph.get_underlying_forward_price()?, years_until_expiry)?.forward_price;
+
+    let log_moneyness = find_log_moneyness_at_delta(
+        |x| Ok(interpolate_surface(smile_graphs, log_moneyness_to_strike(x, forward_price), years_until_expiry)?.total_variance),
+        delta,
+    )?;
+
+    interpolate_surface(smile_graphs, log_moneyness_to_strike(log_moneyness, forward_price), years_until_expiry)
+}
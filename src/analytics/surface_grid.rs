@@ -0,0 +1,186 @@
+use chrono::{DateTime, Utc};
+
+use crate::{
+    analytics::{SmileGraph, SurfaceBuildInfo, SurfaceSlice, WingExtrapolation},
+    helpers::Clock,
+    types::{SchemaVersioned, TsError, TsErrorType::RuntimeError},
+};
+
+/// The log moneyness of the grid's first column. The columns are evenly spaced and the same for every surface, so that
+/// grids from different snapshots line up.
+const SURFACE_GRID_LOWEST_LOG_MONEYNESS: f64 = -1.0;
+
+/// The log moneyness of the grid's last column.
+const SURFACE_GRID_HIGHEST_LOG_MONEYNESS: f64 = 1.0;
+
+/// The number of columns in the grid. They're 0.01 apart, which is close enough that interpolating linearly between them
+/// reads the surface almost exactly as the fitted curves do.
+const SURFACE_GRID_COLUMNS: usize = 201;
+
+/// A fitted surface precomputed when it's built, for reading it quickly: the total variance of each smile on a fixed grid
+/// of log moneyness, and each smile without the quotes it was fit to, which is all that querying, pricing and scenarios
+/// need. It's a small fraction of the size of the full surface data, which most of the time to load is spent on.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct SurfaceGrid {
+    pub schema_version: u32,
+    pub build_info: Option<SurfaceBuildInfo>,
+    /// How the total variances were extrapolated beyond each smile's observed strikes.
+    pub wing_extrapolation: WingExtrapolation,
+    /// The log moneyness of each of the grid's columns.
+    pub log_moneyness: Vec<f64>,
+    /// Ordered by expiry.
+    pub rows: Vec<SurfaceGridRow>,
+}
+
+/// One smile of a surface grid.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct SurfaceGridRow {
+    pub expiry: DateTime<Utc>,
+    pub forward_price: f64,
+    /// The total variance at each of the grid's log moneyness.
+    pub total_variance: Vec<f64>,
+    /// The smile, keeping only the option its forward price and expiry are read from (see SmileGraph::without_quotes()).
+    pub smile_graph: SmileGraph,
+}
+
+/// A row of a surface grid, for reading the surface with interpolate_surface() and the like. Between the grid's columns
+/// the total variance is interpolated linearly, and beyond them it's read from the row's smile.
+pub struct SurfaceGridSlice<'a> {
+    log_moneyness: &'a [f64],
+    row: &'a SurfaceGridRow,
+}
+
+impl SurfaceGrid {
+    /// Precompute the grid of the fitted smiles, which must be ordered by expiry and all have the same wing extrapolation.
+    pub fn new(smile_graphs: &[SmileGraph], build_info: Option<SurfaceBuildInfo>) -> Result<Self, TsError> {
+        let log_moneyness: Vec<f64> = (0..SURFACE_GRID_COLUMNS)
+            .map(|i| {
+                SURFACE_GRID_LOWEST_LOG_MONEYNESS
+                    + (SURFACE_GRID_HIGHEST_LOG_MONEYNESS - SURFACE_GRID_LOWEST_LOG_MONEYNESS)
+                        * (i as f64 / (SURFACE_GRID_COLUMNS - 1) as f64)
+            })
+            .collect();
+        let mut rows = Vec::with_capacity(smile_graphs.len());
+
+        for graph in smile_graphs {
+            rows.push(SurfaceGridRow {
+                expiry: graph.get_expiration()?,
+                forward_price: graph.get_underlying_forward_price()?,
+                total_variance: log_moneyness
+                    .iter()
+                    .map(|x| graph.get_total_variance_at_log_moneyness(*x))
+                    .collect::<Result<_, _>>()?,
+                smile_graph: graph.without_quotes()?,
+            });
+        }
+
+        Ok(Self {
+            schema_version: Self::SCHEMA_VERSION,
+            build_info,
+            wing_extrapolation: smile_graphs
+                .first()
+                .map_or(WingExtrapolation::default(), |x| x.wing_extrapolation),
+            log_moneyness,
+            rows,
+        })
+    }
+
+    /// Precompute the grid again with a different wing extrapolation, from the smiles kept in its rows.
+    pub fn with_wing_extrapolation(self, wing_extrapolation: WingExtrapolation) -> Result<Self, TsError> {
+        let build_info = self.build_info.clone();
+        let mut smile_graphs = self.into_smile_graphs();
+
+        for graph in &mut smile_graphs {
+            graph.wing_extrapolation = wing_extrapolation;
+        }
+
+        Self::new(&smile_graphs, build_info)
+    }
+
+    /// The rows, ordered by expiry, for reading the surface from the grid with interpolate_surface() and the like.
+    pub fn get_slices(&self) -> Vec<SurfaceGridSlice<'_>> {
+        self.rows
+            .iter()
+            .map(|row| SurfaceGridSlice {
+                log_moneyness: &self.log_moneyness,
+                row,
+            })
+            .collect()
+    }
+
+    /// Change what the smiles' times until expiry are measured from (see SmileGraph::set_clock()).
+    pub fn set_clock(&mut self, clock: Clock) {
+        for row in &mut self.rows {
+            row.smile_graph.set_clock(clock);
+        }
+    }
+
+    /// The smiles, ordered by expiry, for anything that needs the fitted curves themselves, such as shocking them.
+    pub fn into_smile_graphs(self) -> Vec<SmileGraph> {
+        self.rows.into_iter().map(|x| x.smile_graph).collect()
+    }
+}
+
+impl SurfaceSlice for SurfaceGridSlice<'_> {
+    fn get_expiration(&self) -> Result<DateTime<Utc>, TsError> {
+        Ok(self.row.expiry)
+    }
+
+    fn get_years_until_expiry(&self) -> Result<f64, TsError> {
+        self.row.smile_graph.get_years_until_expiry()
+    }
+
+    fn get_underlying_forward_price(&self) -> Result<f64, TsError> {
+        Ok(self.row.forward_price)
+    }
+
+    fn get_total_variance_at_log_moneyness(&self, log_moneyness: f64) -> Result<f64, TsError> {
+        let columns = self.log_moneyness.len();
+
+        if columns < 2
+            || self.row.total_variance.len() != columns
+            || log_moneyness < self.log_moneyness[0]
+            || log_moneyness > self.log_moneyness[columns - 1]
+        {
+            return self
+                .row
+                .smile_graph
+                .get_total_variance_at_log_moneyness(log_moneyness);
+        }
+
+        // The first column at or above the log moneyness, and the one before it.
+        let upper = self
+            .log_moneyness
+            .partition_point(|x| *x < log_moneyness)
+            .max(1);
+        let lower = upper - 1;
+        let weight = (log_moneyness - self.log_moneyness[lower]) / (self.log_moneyness[upper] - self.log_moneyness[lower]);
+
+        Ok(self.row.total_variance[lower] + (self.row.total_variance[upper] - self.row.total_variance[lower]) * weight)
+    }
+}
+
+impl SchemaVersioned for SurfaceGrid {
+    const SCHEMA_VERSION: u32 = 2;
+
+    fn migrate(data: &mut serde_json::Value, from_version: u32) -> Result<(), TsError> {
+        match from_version {
+            // Version 1 grids didn't record the wing extrapolation they were computed with, so their columns are
+            // dropped, which has them precomputed again when they're loaded.
+            1 => {
+                data["wing_extrapolation"] = serde_json::to_value(WingExtrapolation::default())
+                    .map_err(|e| TsError::new(RuntimeError, format!("Failed migrating surface grid: {e}")).with_source(e))?;
+                data["log_moneyness"] = serde_json::Value::Array(Vec::new());
+
+                if let Some(rows) = data["rows"].as_array_mut() {
+                    for row in rows {
+                        row["total_variance"] = serde_json::Value::Array(Vec::new());
+                    }
+                }
+
+                Ok(())
+            }
+            _ => Err(TsError::new(RuntimeError, format!("No migration from schema version {from_version}"))),
+        }
+    }
+}
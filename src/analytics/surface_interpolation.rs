@@ -0,0 +1,205 @@
+use std::f64::consts::E;
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    analytics::SmileGraph,
+    constants,
+    helpers::error_unless_positive_f64,
+    types::{
+        TsError,
+        TsErrorType::{RuntimeError, UnsolvableError},
+    },
+};
+
+/// A point read off the surface at any strike and time to expiry.
+pub struct SurfacePoint {
+    pub forward_price: f64,
+    pub log_moneyness: f64,
+    pub total_variance: f64,
+    pub implied_volatility: f64,
+    /// Set if the smiles either side of this point have calendar arbitrage at this log moneyness. The total variance is
+    /// still usable, since it is held flat rather than allowed to fall.
+    pub calendar_arbitrage: Option<CalendarArbitrage>,
+}
+
+/// A smile the surface can be read between: one of the fitted smiles, or a row of a precomputed SurfaceGrid.
+pub trait SurfaceSlice {
+    fn get_expiration(&self) -> Result<DateTime<Utc>, TsError>;
+    fn get_years_until_expiry(&self) -> Result<f64, TsError>;
+    fn get_underlying_forward_price(&self) -> Result<f64, TsError>;
+    /// The total variance at the log moneyness, extrapolated beyond the observed strikes as the smile's wing extrapolation
+    /// sets.
+    fn get_total_variance_at_log_moneyness(&self, log_moneyness: f64) -> Result<f64, TsError>;
+}
+
+impl SurfaceSlice for SmileGraph {
+    fn get_expiration(&self) -> Result<DateTime<Utc>, TsError> {
+        SmileGraph::get_expiration(self)
+    }
+
+    fn get_years_until_expiry(&self) -> Result<f64, TsError> {
+        SmileGraph::get_years_until_expiry(self)
+    }
+
+    fn get_underlying_forward_price(&self) -> Result<f64, TsError> {
+        SmileGraph::get_underlying_forward_price(self)
+    }
+
+    fn get_total_variance_at_log_moneyness(&self, log_moneyness: f64) -> Result<f64, TsError> {
+        SmileGraph::get_total_variance_at_log_moneyness(self, log_moneyness)
+    }
+}
+
+/// Total variance falling between two expiries at the same log moneyness, which would allow a calendar spread to make
+/// money for nothing.
+pub struct CalendarArbitrage {
+    pub earlier_expiry: DateTime<Utc>,
+    pub later_expiry: DateTime<Utc>,
+    pub log_moneyness: f64,
+    pub earlier_total_variance: f64,
+    pub later_total_variance: f64,
+}
+
+/// Read the surface at the given strike and time to expiry, which doesn't have to be one of the fitted expiries. The smiles
+/// must be ordered by expiry.
+///
+/// Total variance is interpolated linearly in time at a fixed log moneyness, which is the same as assuming the forward
+/// variance is flat between expiries. Before the first expiry it falls linearly to zero, and after the last it keeps
+/// growing at the same implied volatility. Total variance is never allowed to fall with time, even if the smiles have
+/// calendar arbitrage.
+pub fn interpolate_surface<T: SurfaceSlice>(
+    smile_graphs: &[T],
+    strike: f64,
+    years_until_expiry: f64,
+) -> Result<SurfacePoint, TsError> {
+    error_unless_positive_f64(strike, "strike")?;
+
+    if years_until_expiry <= 0.0 {
+        return Err(TsError::new(UnsolvableError, "Expiry must be in the future"));
+    }
+
+    let first_graph = smile_graphs
+        .first()
+        .ok_or(TsError::new(RuntimeError, "The surface has no smiles"))?;
+    let forward_price = first_graph.get_underlying_forward_price()?
+        * E.powf(constants::INTEREST_FREE_RATE * (years_until_expiry - first_graph.get_years_until_expiry()?));
+    let log_moneyness = (strike / forward_price).ln();
+
+    let mut previous: Option<(&T, f64, f64)> = None;
+    let mut calendar_arbitrage = None;
+    let mut total_variance = None;
+
+    for graph in smile_graphs {
+        let graph_years_until_expiry = graph.get_years_until_expiry()?;
+        let graph_total_variance = graph.get_total_variance_at_log_moneyness(log_moneyness)?;
+
+        if graph_years_until_expiry < years_until_expiry {
+            previous = Some((graph, graph_years_until_expiry, graph_total_variance));
+            continue;
+        }
+
+        total_variance = Some(match previous {
+            None => graph_total_variance * years_until_expiry / graph_years_until_expiry,
+            Some((previous_graph, previous_years_until_expiry, previous_total_variance)) => {
+                calendar_arbitrage = find_calendar_arbitrage(previous_graph, graph, log_moneyness)?;
+
+                let weight =
+                    (years_until_expiry - previous_years_until_expiry) / (graph_years_until_expiry - previous_years_until_expiry);
+                previous_total_variance + (graph_total_variance - previous_total_variance).max(0.0) * weight
+            }
+        });
+
+        break;
+    }
+
+    let total_variance = match total_variance {
+        Some(v) => v,
+        None => {
+            // We're beyond the last expiry.
+            let (_, last_years_until_expiry, last_total_variance) =
+                previous.ok_or(TsError::new(RuntimeError, "The surface has no smiles"))?;
+
+            last_total_variance * years_until_expiry / last_years_until_expiry
+        }
+    };
+
+    Ok(SurfacePoint {
+        forward_price,
+        log_moneyness,
+        total_variance,
+        implied_volatility: (total_variance / years_until_expiry).sqrt(),
+        calendar_arbitrage,
+    })
+}
+
+/// Check each pair of neighbouring smiles for calendar arbitrage at resolution points across the log moneyness range both
+/// of them observed, returning the worst violation for each pair that has any. The smile graphs must be ordered by expiry.
+pub fn check_calendar_arbitrage(smile_graphs: &[&SmileGraph], resolution: u64) -> Result<Vec<CalendarArbitrage>, TsError> {
+    let mut violations = Vec::new();
+
+    for pair in smile_graphs.windows(2) {
+        let (earlier, later) = (pair[0], pair[1]);
+        let (earlier_lowest, earlier_highest) = get_observed_log_moneyness_range(earlier)?;
+        let (later_lowest, later_highest) = get_observed_log_moneyness_range(later)?;
+        let from = earlier_lowest.max(later_lowest);
+        let to = earlier_highest.min(later_highest);
+
+        if from > to {
+            continue;
+        }
+
+        let mut worst: Option<CalendarArbitrage> = None;
+
+        for i in 0..=resolution {
+            let log_moneyness = from + (to - from) * (i as f64 / resolution as f64);
+
+            if let Some(violation) = find_calendar_arbitrage(earlier, later, log_moneyness)?
+                && worst
+                    .as_ref()
+                    .is_none_or(|x| get_violation_size(&violation) > get_violation_size(x))
+            {
+                worst = Some(violation);
+            }
+        }
+
+        violations.extend(worst);
+    }
+
+    Ok(violations)
+}
+
+/// Returns the calendar arbitrage between the two smiles at the given log moneyness, if there is any.
+fn find_calendar_arbitrage<T: SurfaceSlice>(
+    earlier: &T,
+    later: &T,
+    log_moneyness: f64,
+) -> Result<Option<CalendarArbitrage>, TsError> {
+    let earlier_total_variance = earlier.get_total_variance_at_log_moneyness(log_moneyness)?;
+    let later_total_variance = later.get_total_variance_at_log_moneyness(log_moneyness)?;
+
+    if later_total_variance >= earlier_total_variance {
+        return Ok(None);
+    }
+
+    Ok(Some(CalendarArbitrage {
+        earlier_expiry: earlier.get_expiration()?,
+        later_expiry: later.get_expiration()?,
+        log_moneyness,
+        earlier_total_variance,
+        later_total_variance,
+    }))
+}
+
+fn get_violation_size(violation: &CalendarArbitrage) -> f64 {
+    violation.earlier_total_variance - violation.later_total_variance
+}
+
+fn get_observed_log_moneyness_range(graph: &SmileGraph) -> Result<(f64, f64), TsError> {
+    let forward_price = graph.get_underlying_forward_price()?;
+
+    Ok((
+        (graph.lowest_observed_strike / forward_price).ln(),
+        (graph.highest_observed_strike / forward_price).ln(),
+    ))
+}
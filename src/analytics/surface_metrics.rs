@@ -0,0 +1,83 @@
+use chrono::{DateTime, Utc};
+
+use crate::{
+    analytics::{
+        EventProximity, SkewBeta, SmileGraph, VolEvent, find_event_proximity, strike_conventions::find_log_moneyness_at_delta,
+    },
+    helpers,
+    types::TsError,
+};
+
+/// The standard numbers traders quote for one fitted expiry.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct ExpiryMetrics {
+    pub expiry: DateTime<Utc>,
+    pub years_until_expiry: f64,
+    pub atm_implied_volatility: f64,
+    /// The implied volatility of the 25 delta call minus that of the 25 delta put. Negative when puts are more expensive.
+    pub risk_reversal_25_delta: f64,
+    /// The average implied volatility of the 25 delta call and put, minus the at the money volatility. Measures the
+    /// curvature of the smile.
+    pub butterfly_25_delta: f64,
+    /// The slope of implied volatility with respect to log moneyness at the money.
+    pub atm_skew: f64,
+    /// The at the money volatility implied between the previous expiry and this one. Missing for the first expiry, or if at
+    /// the money total variance falls (calendar arbitrage).
+    pub atm_forward_volatility: Option<f64>,
+    /// The last of the user's events before the expiry, if any. Missing from metrics saved by older versions.
+    #[serde(default)]
+    pub nearest_event: Option<EventProximity>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct SurfaceMetrics {
+    pub calculated_at: DateTime<Utc>,
+    /// Ordered by expiry, so the at the money volatilities form the term structure.
+    pub expiries: Vec<ExpiryMetrics>,
+}
+
+impl SurfaceMetrics {
+    /// Calculate the metrics of each fitted smile. The smile graphs and events must be ordered by expiry and date.
+    pub fn calculate(smile_graphs: &[SmileGraph], events: &[VolEvent]) -> Result<SurfaceMetrics, TsError> {
+        let mut expiries: Vec<ExpiryMetrics> = Vec::with_capacity(smile_graphs.len());
+
+        for graph in smile_graphs {
+            let skew = SkewBeta::calculate(graph)?;
+            let call_implied_volatility = get_implied_volatility_at_delta(graph, 0.25)?;
+            let put_implied_volatility = get_implied_volatility_at_delta(graph, -0.25)?;
+            let total_variance = skew.atm_implied_volatility.powi(2) * skew.years_until_expiry;
+
+            let atm_forward_volatility = expiries.last().and_then(|previous| {
+                let previous_total_variance = previous.atm_implied_volatility.powi(2) * previous.years_until_expiry;
+                let forward_variance =
+                    (total_variance - previous_total_variance) / (skew.years_until_expiry - previous.years_until_expiry);
+
+                (forward_variance >= 0.0).then(|| forward_variance.sqrt())
+            });
+
+            expiries.push(ExpiryMetrics {
+                expiry: skew.expiry,
+                years_until_expiry: skew.years_until_expiry,
+                atm_implied_volatility: skew.atm_implied_volatility,
+                risk_reversal_25_delta: call_implied_volatility - put_implied_volatility,
+                butterfly_25_delta: (call_implied_volatility + put_implied_volatility) / 2.0 - skew.atm_implied_volatility,
+                atm_skew: skew.atm_skew,
+                atm_forward_volatility,
+                nearest_event: find_event_proximity(events, graph.get_clock()?.now(), skew.expiry),
+            });
+        }
+
+        Ok(SurfaceMetrics {
+            calculated_at: helpers::get_now(),
+            expiries,
+        })
+    }
+}
+
+/// Find the implied volatility at the strike where an option has the given forward delta, using the smile's own volatility
+/// at each strike tried.
+fn get_implied_volatility_at_delta(graph: &SmileGraph, delta: f64) -> Result<f64, TsError> {
+    let log_moneyness = find_log_moneyness_at_delta(|x| graph.get_total_variance_at_log_moneyness(x), delta)?;
+
+    Ok((graph.get_total_variance_at_log_moneyness(log_moneyness)? / graph.get_years_until_expiry()?).sqrt())
+}
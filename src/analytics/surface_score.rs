@@ -0,0 +1,154 @@
+use chrono::{DateTime, Utc};
+
+use crate::{
+    analytics::SmileGraph,
+    helpers::{self, F64Helpers},
+    types::{TsError, TsErrorType::RuntimeError},
+};
+
+/// How much each component contributes to the overall score. These add up to 1.
+const FIT_WEIGHT: f64 = 0.4;
+const COVERAGE_WEIGHT: f64 = 0.2;
+const ARBITRAGE_WEIGHT: f64 = 0.2;
+const FRESHNESS_WEIGHT: f64 = 0.2;
+
+/// The implied volatility RMSE at which a smile's fit score falls to about 37% (1/e). 0.05 = 5 volatility points.
+const FIT_RMSE_SCALE: f64 = 0.05;
+
+/// A smile whose arbitrage margin is at least this large gets a full arbitrage score.
+const ARBITRAGE_MARGIN_TARGET: f64 = 0.25;
+
+/// The age of the market data (seconds) at which the freshness score falls to about 37% (1/e).
+const DATA_AGE_SCALE_SECONDS: f64 = 3600.0;
+
+/// A single number from 0 to 100 summarising the quality of a built surface, so that it can be tracked over time. The
+/// components are each between 0 and 1.
+///
+/// Each smile is weighted by the length of the part of the term structure it covers (the time between its expiry and the
+/// previous one), so a cluster of near-dated expiries counts for as much as the single far-dated one that covers the same
+/// length of time.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct SurfaceScore {
+    pub calculated_at: DateTime<Utc>,
+    pub score: f64,
+    /// How closely the fitted curves match the observed implied volatilities.
+    pub fit: f64,
+    /// The (tenor-weighted) share of the smiles that could be fit.
+    pub coverage: f64,
+    /// How far the fitted curves are from having butterfly arbitrage.
+    pub arbitrage: f64,
+    /// How recently the market data was downloaded.
+    pub freshness: f64,
+    pub smiles_count: usize,
+    pub fitted_smiles_count: usize,
+    pub data_age_seconds: f64,
+    /// The quality of each fitted smile. Missing from data saved by older versions.
+    #[serde(default)]
+    pub slices: Vec<SliceScore>,
+}
+
+/// How well a single smile was fit, recorded so that problem expiries and moves in the surface can be tracked over time.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct SliceScore {
+    pub expiry: DateTime<Utc>,
+    pub years_until_expiry: f64,
+    pub fit_rmse: f64,
+    pub arbitrage_margin: f64,
+    pub atm_implied_volatility: f64,
+}
+
+impl SurfaceScore {
+    /// Score the given smile graphs, which must be ordered by expiry. Smiles that couldn't be fit count against the
+    /// coverage. data_age_seconds is the age of the oldest market data used to build the surface, as of calculated_at.
+    pub fn calculate(
+        smile_graphs: &[SmileGraph],
+        data_age_seconds: f64,
+        calculated_at: DateTime<Utc>,
+    ) -> Result<SurfaceScore, TsError> {
+        let mut previous_years_until_expiry = 0.0;
+        let mut total_weight = 0.0;
+        let mut fitted_weight = 0.0;
+        let mut fit = 0.0;
+        let mut arbitrage = 0.0;
+        let mut fitted_smiles_count = 0;
+        let mut slices = Vec::new();
+
+        for graph in smile_graphs {
+            let years_until_expiry = graph.get_years_until_expiry()?;
+            let weight = years_until_expiry - previous_years_until_expiry;
+            previous_years_until_expiry = years_until_expiry;
+
+            if weight < 0.0 {
+                return Err(TsError::new(RuntimeError, "Smile graphs must be ordered by expiry"));
+            }
+
+            total_weight += weight;
+
+            if !graph.has_been_fit {
+                continue;
+            }
+
+            let fit_rmse = graph.get_fit_rmse()?;
+            let arbitrage_margin = graph.get_arbitrage_margin()?;
+
+            fitted_smiles_count += 1;
+            fitted_weight += weight;
+            fit += weight * (-fit_rmse / FIT_RMSE_SCALE).exp();
+            arbitrage += weight * (arbitrage_margin / ARBITRAGE_MARGIN_TARGET).clamp(0.0, 1.0);
+
+            slices.push(SliceScore {
+                expiry: graph.get_expiration()?,
+                years_until_expiry,
+                fit_rmse,
+                arbitrage_margin,
+                atm_implied_volatility: (graph.get_total_variance_at_log_moneyness(0.0)? / years_until_expiry).sqrt(),
+            });
+        }
+
+        // With nothing fit, there is nothing to score the fit of.
+        let (fit, arbitrage) = match fitted_weight > 0.0 {
+            true => (fit / fitted_weight, arbitrage / fitted_weight),
+            false => (0.0, 0.0),
+        };
+        let coverage = match total_weight > 0.0 {
+            true => fitted_weight / total_weight,
+            false => 0.0,
+        };
+        let freshness = (-data_age_seconds.max(0.0) / DATA_AGE_SCALE_SECONDS).exp();
+
+        let score =
+            100.0 * (FIT_WEIGHT * fit + COVERAGE_WEIGHT * coverage + ARBITRAGE_WEIGHT * arbitrage + FRESHNESS_WEIGHT * freshness);
+
+        helpers::error_unless_valid_f64(score, "score")?;
+
+        Ok(SurfaceScore {
+            calculated_at,
+            score,
+            fit,
+            coverage,
+            arbitrage,
+            freshness,
+            smiles_count: smile_graphs.len(),
+            fitted_smiles_count,
+            data_age_seconds,
+            slices,
+        })
+    }
+}
+
+impl std::fmt::Display for SurfaceScore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Surface score: {}/100 (fit {}, coverage {}, arbitrage {}, freshness {}) from {}/{} fitted smiles and {} minute old data",
+            self.score.round_to_decimal_places(1),
+            self.fit.round_to_decimal_places(2),
+            self.coverage.round_to_decimal_places(2),
+            self.arbitrage.round_to_decimal_places(2),
+            self.freshness.round_to_decimal_places(2),
+            self.fitted_smiles_count,
+            self.smiles_count,
+            (self.data_age_seconds / 60.0).round_to_decimal_places(1),
+        )
+    }
+}
@@ -0,0 +1,432 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use levenberg_marquardt::{LeastSquaresProblem, LevenbergMarquardt};
+use nalgebra::{Dyn, Matrix, OMatrix, Owned, U1, U5, Vector4, Vector5};
+
+use crate::{
+    analytics::{
+        ButterflyCheckSettings, SmileGraph, interpolate_surface,
+        math::{
+            get_butterfly_arbitrage_shortfall, has_butterfly_arbitrage, saturating_exp, saturating_exp_derivative, saturating_ln,
+            svi_variance,
+        },
+        smile_graph::{
+            BUTTERFLY_PENALTY_JACOBIAN_STEP, BUTTERFLY_PENALTY_MARGIN, from_unconstrained_svi_params,
+            get_unconstrained_svi_derivatives, to_unconstrained_svi_params,
+        },
+        types::SVICurveParameters,
+    },
+    constants,
+    types::{
+        TsError,
+        TsErrorType::{RuntimeError, UnsolvableError},
+    },
+};
+
+/// The tenors (days) that downstream consumers expect to find on the surface.
+pub const STANDARD_TENOR_DAYS: [i64; 8] = [7, 14, 30, 60, 90, 180, 270, 365];
+
+/// A listed expiry within this fraction of a standard tenor (or a day, if more) is close enough to stand in for it.
+const STANDARD_TENOR_TOLERANCE: f64 = 0.1;
+
+/// The number of evenly spaced log moneyness points a synthetic smile is fit to.
+const SYNTHETIC_SMILE_FIT_POINTS: usize = 41;
+
+/// How heavily the fit is penalised for total variance outside that of the neighbouring smiles, relative to the error
+/// against the interpolated total variance.
+const CALENDAR_ARBITRAGE_PENALTY_WEIGHT: f64 = 1000.0;
+
+/// Total variance this far outside that of the neighbouring smiles is treated as rounding rather than calendar arbitrage.
+const CALENDAR_ARBITRAGE_TOLERANCE: f64 = 0.000001;
+
+/// A smile at a standard tenor with no listed expiry near it, interpolated from the fitted smiles either side. These are
+/// kept apart from the fitted smiles so that they can't be mistaken for smiles backed by quotes.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct SyntheticSmile {
+    pub tenor_days: i64,
+    pub expiry: DateTime<Utc>,
+    pub underlying_forward_price: f64,
+    pub svi_curve_parameters: SVICurveParameters,
+    /// The expiries of the fitted smiles this was interpolated from.
+    pub earlier_expiry: DateTime<Utc>,
+    pub later_expiry: DateTime<Utc>,
+    /// The root mean squared difference in total variance between the SVI curve and the interpolated surface.
+    pub rmse: f64,
+}
+
+/// Build a smile at the given standard tenor, if there's no listed expiry near it but there are fitted smiles either side.
+/// The smile graphs must be ordered by expiry.
+///
+/// Total variance is interpolated in time at a fixed log moneyness in the same way as interpolate_surface(), and an SVI
+/// curve without butterfly arbitrage is fit to it across the strikes observed at both neighbouring expiries. The fit is
+/// penalised for leaving the total variance of the neighbouring smiles at those strikes, and rejected if it still does
+/// (calendar arbitrage), unless the neighbouring smiles already have calendar arbitrage there.
+pub fn build_synthetic_smile(smile_graphs: &[SmileGraph], tenor_days: i64) -> Result<Option<SyntheticSmile>, TsError> {
+    let now = smile_graphs
+        .first()
+        .ok_or(TsError::new(RuntimeError, "The surface has no smiles"))?
+        .get_clock()?
+        .now();
+    let expiry = now + TimeDelta::days(tenor_days);
+    let tolerance_seconds = (tenor_days as f64 * STANDARD_TENOR_TOLERANCE).max(1.0) * 86400.0;
+
+    let mut earlier = None;
+    let mut later = None;
+
+    for graph in smile_graphs {
+        let graph_expiry = graph.get_expiration()?;
+
+        if ((graph_expiry - expiry).num_seconds() as f64).abs() <= tolerance_seconds {
+            return Ok(None);
+        }
+
+        match graph_expiry < expiry {
+            true => earlier = Some(graph),
+            false => later = later.or(Some(graph)),
+        }
+    }
+
+    let (Some(earlier), Some(later)) = (earlier, later) else {
+        return Ok(None);
+    };
+
+    let years_until_expiry = (expiry - now).num_seconds() as f64 / 31556926.0;
+    let forward_price = interpolate_surface(smile_graphs, 1.0, years_until_expiry)?.forward_price;
+    let lowest_log_moneyness =
+        get_log_moneyness(earlier, earlier.lowest_observed_strike)?.max(get_log_moneyness(later, later.lowest_observed_strike)?);
+    let highest_log_moneyness = get_log_moneyness(earlier, earlier.highest_observed_strike)?
+        .min(get_log_moneyness(later, later.highest_observed_strike)?);
+
+    if highest_log_moneyness <= lowest_log_moneyness {
+        return Err(TsError::new(UnsolvableError, "The neighbouring smiles have no observed strikes in common"));
+    }
+
+    let mut targets = Vec::with_capacity(SYNTHETIC_SMILE_FIT_POINTS);
+
+    for i in 0..SYNTHETIC_SMILE_FIT_POINTS {
+        let log_moneyness = lowest_log_moneyness
+            + (highest_log_moneyness - lowest_log_moneyness) * (i as f64 / (SYNTHETIC_SMILE_FIT_POINTS - 1) as f64);
+        let point = interpolate_surface(smile_graphs, forward_price * log_moneyness.exp(), years_until_expiry)?;
+        let earlier_total_variance = earlier.get_total_variance_at_log_moneyness(log_moneyness)?;
+        let later_total_variance = later.get_total_variance_at_log_moneyness(log_moneyness)?;
+
+        targets.push(SyntheticSmileTarget {
+            log_moneyness,
+            total_variance: point.total_variance,
+            total_variance_bounds: (earlier_total_variance <= later_total_variance)
+                .then_some((earlier_total_variance, later_total_variance)),
+        });
+    }
+
+    let strike_range = (
+        earlier
+            .lowest_observed_strike
+            .min(later.lowest_observed_strike),
+        earlier
+            .highest_observed_strike
+            .max(later.highest_observed_strike),
+    );
+    let weight = (years_until_expiry - earlier.get_years_until_expiry()?)
+        / (later.get_years_until_expiry()? - earlier.get_years_until_expiry()?);
+    let svi_curve_parameters = fit_svi_curve(&targets, (earlier, later), weight, forward_price, strike_range)?;
+
+    let mut squared_error = 0.0;
+
+    for target in &targets {
+        let synthetic_total_variance = svi_variance(&svi_curve_parameters, target.log_moneyness)?;
+
+        if target.get_calendar_arbitrage(synthetic_total_variance) > CALENDAR_ARBITRAGE_TOLERANCE {
+            return Err(TsError::new(
+                UnsolvableError,
+                format!("The synthetic smile has calendar arbitrage at log moneyness {}", target.log_moneyness),
+            ));
+        }
+
+        squared_error += (synthetic_total_variance - target.total_variance).powi(2);
+    }
+
+    Ok(Some(SyntheticSmile {
+        tenor_days,
+        expiry,
+        underlying_forward_price: forward_price,
+        svi_curve_parameters,
+        earlier_expiry: earlier.get_expiration()?,
+        later_expiry: later.get_expiration()?,
+        rmse: (squared_error / targets.len() as f64).sqrt(),
+    }))
+}
+
+fn get_log_moneyness(graph: &SmileGraph, strike: f64) -> Result<f64, TsError> {
+    Ok((strike / graph.get_underlying_forward_price()?).ln())
+}
+
+struct SyntheticSmileTarget {
+    log_moneyness: f64,
+    /// The total variance interpolated from the neighbouring smiles.
+    total_variance: f64,
+    /// The total variance of the earlier and later neighbouring smiles, unless they have calendar arbitrage here.
+    total_variance_bounds: Option<(f64, f64)>,
+}
+
+impl SyntheticSmileTarget {
+    /// How far the given total variance is outside that of the neighbouring smiles.
+    fn get_calendar_arbitrage(&self, total_variance: f64) -> f64 {
+        match self.total_variance_bounds {
+            Some((lowest, highest)) => (lowest - total_variance)
+                .max(total_variance - highest)
+                .max(0.0),
+            None => 0.0,
+        }
+    }
+}
+
+/// Fit an SVI curve without butterfly arbitrage to the targets by least squares. The fit is started from each
+/// neighbouring smile's parameters, parameters part way between them and a flat smile at the at the money total variance,
+/// skipping any that aren't valid here, and the best fit is used.
+fn fit_svi_curve(
+    targets: &[SyntheticSmileTarget],
+    neighbours: (&SmileGraph, &SmileGraph),
+    weight: f64,
+    forward_price: f64,
+    strike_range: (f64, f64),
+) -> Result<SVICurveParameters, TsError> {
+    let atm_total_variance = targets
+        .iter()
+        .min_by(|x, y| x.log_moneyness.abs().total_cmp(&y.log_moneyness.abs()))
+        .ok_or(TsError::new(UnsolvableError, "There are no points to fit the synthetic smile to"))?
+        .total_variance;
+    let to_vector = |params: &SVICurveParameters| {
+        Vector5::new(params.get_a(), params.get_b(), params.get_p(), params.get_m(), params.get_o())
+    };
+    let earlier_p = to_vector(&neighbours.0.svi_curve_parameters);
+    let later_p = to_vector(&neighbours.1.svi_curve_parameters);
+
+    let starting_points = [
+        earlier_p + (later_p - earlier_p) * weight,
+        earlier_p,
+        later_p,
+        // A small b and a large o make the smile almost flat.
+        Vector5::new(atm_total_variance - 0.01 * 0.1, 0.01, 0.0, 0.0, 0.1),
+    ];
+    let mut best: Option<(SVICurveParameters, f64)> = None;
+
+    for starting_point in starting_points {
+        let Some(x) = to_unconstrained_params(&starting_point) else {
+            continue;
+        };
+        let mut problem = SyntheticSmileProblem {
+            x,
+            targets,
+            forward_price,
+            strike_range,
+            butterfly_check: neighbours.0.butterfly_check,
+            curve: None,
+            butterfly_penalty: 0.0,
+            has_arbitrage: false,
+        };
+
+        problem.set_params(&x);
+
+        if problem.curve.is_none() {
+            continue;
+        }
+
+        let (result, report) = LevenbergMarquardt::new()
+            .with_patience(100)
+            .minimize(problem);
+        let loss = report.objective_function.abs();
+
+        if let Some(curve) = result.curve
+            && !result.has_arbitrage
+            && report.termination.was_successful()
+            && best.as_ref().is_none_or(|(_, best_loss)| loss < *best_loss)
+        {
+            best = Some((curve, loss));
+        }
+    }
+
+    best.map(|x| x.0)
+        .ok_or(TsError::new(UnsolvableError, "No valid SVI curve without butterfly arbitrage was found"))
+}
+
+/// How far the natural log of the curve's lowest total variance can get from zero (see saturating_exp()).
+const MAX_ABSOLUTE_LOG_LOWEST_VARIANCE: f64 = 20.0;
+
+/// What the butterfly arbitrage penalty is scaled by, relative to the error in total variance.
+const BUTTERFLY_PENALTY_WEIGHT: f64 = 1.0;
+
+/// Convert the SVI parameters a, b, p, m and o to the optimiser's unconstrained parameters: the natural log of the lowest
+/// total variance, followed by the smile fitting's unconstrained b, p, m and o. None if the lowest total variance isn't
+/// positive.
+fn to_unconstrained_params(params: &Vector5<f64>) -> Option<Vector5<f64>> {
+    let [a, b, p, m, o] = [params[0], params[1], params[2], params[3], params[4]];
+    let lowest_variance = a + b * o * (1.0 - p * p).sqrt();
+
+    if lowest_variance.is_nan() || lowest_variance <= 0.0 {
+        return None;
+    }
+
+    let x = to_unconstrained_svi_params(b, p, m, o);
+
+    Some(Vector5::new(saturating_ln(lowest_variance, MAX_ABSOLUTE_LOG_LOWEST_VARIANCE), x.x, x.y, x.z, x.w))
+}
+
+/// The curve for the optimiser's unconstrained parameters. Every curve they describe has positive variance, so this is
+/// only None if rounding stops it being valid.
+fn calculate_curve(x: &Vector5<f64>) -> Option<SVICurveParameters> {
+    let [b, p, m, o] = from_unconstrained_svi_params(&Vector4::new(x[1], x[2], x[3], x[4]));
+    let a = saturating_exp(x[0], MAX_ABSOLUTE_LOG_LOWEST_VARIANCE) - b * o * (1.0 - p * p).sqrt();
+
+    SVICurveParameters::new_from_values(a, b, p, m, o).ok()
+}
+
+struct SyntheticSmileProblem<'a> {
+    /// The optimiser's unconstrained parameters (see to_unconstrained_params()).
+    x: Vector5<f64>,
+    targets: &'a [SyntheticSmileTarget],
+    forward_price: f64,
+    /// The lowest and highest strikes observed in either neighbouring smile.
+    strike_range: (f64, f64),
+    butterfly_check: ButterflyCheckSettings,
+    /// None if rounding stopped the parameters being a valid SVI curve.
+    curve: Option<SVICurveParameters>,
+    /// How far the curve's g(k) falls below BUTTERFLY_PENALTY_MARGIN, scaled by BUTTERFLY_PENALTY_WEIGHT.
+    butterfly_penalty: f64,
+    /// Whether the curve has butterfly arbitrage, in which case it's rejected if it's where the fit ends up.
+    has_arbitrage: bool,
+}
+
+impl SyntheticSmileProblem<'_> {
+    fn get_butterfly_penalty(&self, curve: &SVICurveParameters) -> Result<f64, TsError> {
+        Ok(BUTTERFLY_PENALTY_WEIGHT
+            * get_butterfly_arbitrage_shortfall(
+                curve,
+                self.strike_range.0,
+                self.strike_range.1,
+                self.forward_price,
+                &self.butterfly_check,
+                BUTTERFLY_PENALTY_MARGIN,
+            )?)
+    }
+}
+
+impl LeastSquaresProblem<f64, Dyn, U5> for SyntheticSmileProblem<'_> {
+    type ParameterStorage = Owned<f64, U5>;
+    type ResidualStorage = Owned<f64, Dyn>;
+    type JacobianStorage = Owned<f64, Dyn, U5>;
+
+    fn set_params(&mut self, x: &Vector5<f64>) {
+        self.x.copy_from(x);
+        self.butterfly_penalty = 0.0;
+        self.has_arbitrage = false;
+        self.curve = None;
+
+        let Some(curve) = calculate_curve(&self.x) else {
+            return;
+        };
+
+        if constants::CHECK_FOR_ARBITRAGE {
+            // Penalising arbitrage rather than rejecting it outright lets the optimiser see which way the nearest curve
+            // without it is. Only a curve that g(k) comes near zero for can have any.
+            let arbitrage = self.get_butterfly_penalty(&curve).and_then(|penalty| {
+                self.butterfly_penalty = penalty;
+
+                match penalty > 0.0 {
+                    true => has_butterfly_arbitrage(
+                        &curve,
+                        self.strike_range.0,
+                        self.strike_range.1,
+                        self.forward_price,
+                        &self.butterfly_check,
+                    ),
+                    false => Ok(false),
+                }
+            });
+
+            match arbitrage {
+                Err(_) => return,
+                Ok(has_arbitrage) => self.has_arbitrage = has_arbitrage,
+            }
+        }
+
+        self.curve = Some(curve);
+    }
+
+    fn params(&self) -> Vector5<f64> {
+        self.x
+    }
+
+    /// The error against each target, followed by the penalty for calendar arbitrage at each target and the penalty for
+    /// butterfly arbitrage. None, which stops the fit, if the curve isn't valid.
+    fn residuals(&self) -> Option<Matrix<f64, Dyn, U1, Self::ResidualStorage>> {
+        let curve = self.curve.as_ref()?;
+        let mut residuals = vec![0.0; self.targets.len() * 2 + 1];
+
+        for (n, target) in self.targets.iter().enumerate() {
+            let total_variance = svi_variance(curve, target.log_moneyness).ok()?;
+
+            residuals[n] = total_variance - target.total_variance;
+            residuals[self.targets.len() + n] = CALENDAR_ARBITRAGE_PENALTY_WEIGHT * target.get_calendar_arbitrage(total_variance);
+        }
+
+        residuals[self.targets.len() * 2] = self.butterfly_penalty;
+
+        Some(OMatrix::<f64, Dyn, U1>::from_row_slice(&residuals))
+    }
+
+    fn jacobian(&self) -> Option<Matrix<f64, Dyn, U5, Self::JacobianStorage>> {
+        let curve = self.curve.as_ref()?;
+        let [b, p, m, o] = [curve.get_b(), curve.get_p(), curve.get_m(), curve.get_o()];
+        let [db_dx, db_dy, dp_dy, dm_dz, do_dw] =
+            get_unconstrained_svi_derivatives(&Vector4::new(self.x[1], self.x[2], self.x[3], self.x[4]));
+        let q = (1.0 - p * p).sqrt();
+        // How a changes with b, p and o, as it's set to keep the lowest variance fixed.
+        let [da_db, da_dp, da_do] = [-o * q, b * o * p / q, -b * q];
+        let dv_dx = saturating_exp_derivative(self.x[0], MAX_ABSOLUTE_LOG_LOWEST_VARIANCE);
+        let mut jacobian = OMatrix::<f64, Dyn, U5>::zeros(self.targets.len() * 2 + 1);
+
+        for (row, target) in self.targets.iter().enumerate() {
+            // d and s come directly from the SVI equation, as in the smile fitting.
+            let d = target.log_moneyness - m;
+            let s = ((d * d) + (o * o)).sqrt();
+            let [dr_da, dr_db, dr_dp, dr_dm, dr_do] = [1.0, p * d + s, b * d, b * (-p - (d / s)), b * (o / s)];
+            let dr_db = dr_db + dr_da * da_db;
+            let dr_dp = dr_dp + dr_da * da_dp;
+            let dr_do = dr_do + dr_da * da_do;
+            let derivatives = [
+                dr_da * dv_dx,
+                dr_db * db_dx,
+                dr_db * db_dy + dr_dp * dp_dy,
+                dr_dm * dm_dz,
+                dr_do * do_dw,
+            ];
+
+            // The penalty rises with total variance above the later smile's, and falls with it below the earlier smile's.
+            let total_variance = svi_variance(curve, target.log_moneyness).ok()?;
+            let penalty_sign = match target.total_variance_bounds {
+                Some((lowest, _)) if total_variance < lowest => -1.0,
+                Some((_, highest)) if total_variance > highest => 1.0,
+                _ => 0.0,
+            };
+
+            for (column, derivative) in derivatives.iter().enumerate() {
+                jacobian[(row, column)] = *derivative;
+                jacobian[(self.targets.len() + row, column)] = CALENDAR_ARBITRAGE_PENALTY_WEIGHT * penalty_sign * derivative;
+            }
+        }
+
+        // The butterfly penalty's derivatives have no neat closed form, so are estimated by finite differences.
+        if self.butterfly_penalty > 0.0 {
+            for column in 0..5 {
+                let mut x = self.x;
+                x[column] += BUTTERFLY_PENALTY_JACOBIAN_STEP;
+
+                let stepped_penalty = self.get_butterfly_penalty(&calculate_curve(&x)?).ok()?;
+
+                jacobian[(self.targets.len() * 2, column)] =
+                    (stepped_penalty - self.butterfly_penalty) / BUTTERFLY_PENALTY_JACOBIAN_STEP;
+            }
+        }
+
+        Some(jacobian)
+    }
+}
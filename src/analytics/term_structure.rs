@@ -0,0 +1,77 @@
+use chrono::{DateTime, Utc};
+
+use crate::{
+    analytics::SmileGraph,
+    types::{TsError, TsErrorType::RuntimeError},
+};
+
+/// The at the money volatility of one fitted expiry.
+pub struct TermStructurePoint {
+    pub expiry: DateTime<Utc>,
+    pub years_until_expiry: f64,
+    pub atm_implied_volatility: f64,
+    pub atm_total_variance: f64,
+}
+
+/// The at the money variance implied between two consecutive expiries, per year.
+pub struct ForwardVariance {
+    pub start_years: f64,
+    pub end_years: f64,
+    /// Negative if at the money total variance falls between the expiries (calendar arbitrage).
+    pub variance: f64,
+}
+
+impl ForwardVariance {
+    /// The forward volatility, or None if the variance is negative.
+    pub fn get_volatility(&self) -> Option<f64> {
+        (self.variance >= 0.0).then(|| self.variance.sqrt())
+    }
+}
+
+/// The at the money term structure of a surface, and the forward variance between each pair of consecutive expiries.
+pub struct TermStructure {
+    /// Ordered by expiry.
+    pub points: Vec<TermStructurePoint>,
+    /// One fewer than the points. The first is between the first and second expiries.
+    pub forward_variances: Vec<ForwardVariance>,
+}
+
+impl TermStructure {
+    /// Calculate the term structure of the fitted smiles, in any order. At the money is at the forward price.
+    pub fn calculate(smile_graphs: &[SmileGraph]) -> Result<TermStructure, TsError> {
+        if smile_graphs.is_empty() {
+            return Err(TsError::new(RuntimeError, "The surface has no smiles"));
+        }
+
+        let mut points = smile_graphs
+            .iter()
+            .map(|graph| {
+                let years_until_expiry = graph.get_years_until_expiry()?;
+                let atm_total_variance = graph.get_total_variance_at_log_moneyness(0.0)?;
+
+                Ok(TermStructurePoint {
+                    expiry: graph.get_expiration()?,
+                    years_until_expiry,
+                    atm_implied_volatility: (atm_total_variance / years_until_expiry).sqrt(),
+                    atm_total_variance,
+                })
+            })
+            .collect::<Result<Vec<TermStructurePoint>, TsError>>()?;
+        points.sort_by(|a, b| a.years_until_expiry.total_cmp(&b.years_until_expiry));
+
+        let forward_variances = points
+            .windows(2)
+            .map(|pair| ForwardVariance {
+                start_years: pair[0].years_until_expiry,
+                end_years: pair[1].years_until_expiry,
+                variance: (pair[1].atm_total_variance - pair[0].atm_total_variance)
+                    / (pair[1].years_until_expiry - pair[0].years_until_expiry),
+            })
+            .collect();
+
+        Ok(TermStructure {
+            points,
+            forward_variances,
+        })
+    }
+}
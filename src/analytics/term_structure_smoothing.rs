@@ -0,0 +1,234 @@
+use chrono::{DateTime, Utc};
+use nalgebra::{DMatrix, DVector};
+
+use crate::{
+    analytics::{OptionInstrument, SmileGraph, TermStructureSmoothing, math::has_butterfly_arbitrage, types::SVICurveParameters},
+    constants,
+    types::{TsError, TsErrorType::UnsolvableError},
+};
+
+/// The decay times (years) tried when fitting a Nelson-Siegel curve. For each one the rest of the curve is a linear fit,
+/// so the one with the least error is used.
+const NELSON_SIEGEL_DECAY_TIMES: [f64; 12] = [0.02, 0.04, 0.06, 0.08, 0.12, 0.17, 0.25, 0.35, 0.5, 0.75, 1.0, 1.5];
+
+/// A Nelson-Siegel curve of at the money volatility against time, which can slope up or down and have one hump. Borrowed
+/// from yield curve fitting.
+pub struct NelsonSiegelCurve {
+    /// The long term level.
+    pub level: f64,
+    /// How much lower (or higher if negative) the short end is than the long term level.
+    pub slope: f64,
+    /// The size of the hump in the middle.
+    pub curvature: f64,
+    /// The time (years) over which the short end decays towards the long term level.
+    pub decay_time: f64,
+}
+
+impl NelsonSiegelCurve {
+    /// Fit the curve to (years until expiry, at the money volatility) points by least squares.
+    pub fn fit(points: &[(f64, f64)]) -> Result<Self, TsError> {
+        if points.len() < 4 {
+            return Err(TsError::new(UnsolvableError, "At least four expiries are needed to fit a Nelson-Siegel curve"));
+        }
+
+        let volatilities = DVector::from_iterator(points.len(), points.iter().map(|x| x.1));
+        let mut best: Option<(Self, f64)> = None;
+
+        for decay_time in NELSON_SIEGEL_DECAY_TIMES {
+            let factors = DMatrix::from_fn(points.len(), 3, |row, column| {
+                let (slope_factor, curvature_factor) = Self::get_factors(points[row].0, decay_time);
+
+                match column {
+                    0 => 1.0,
+                    1 => slope_factor,
+                    _ => curvature_factor,
+                }
+            });
+
+            let Ok(coefficients) = factors.clone().svd(true, true).solve(&volatilities, 1e-12) else {
+                continue;
+            };
+
+            let squared_error = (&factors * &coefficients - &volatilities).norm_squared();
+
+            if best
+                .as_ref()
+                .is_none_or(|(_, best_error)| squared_error < *best_error)
+            {
+                best = Some((
+                    Self {
+                        level: coefficients[0],
+                        slope: coefficients[1],
+                        curvature: coefficients[2],
+                        decay_time,
+                    },
+                    squared_error,
+                ));
+            }
+        }
+
+        best.map(|x| x.0)
+            .ok_or(TsError::new(UnsolvableError, "Failed fitting a Nelson-Siegel curve"))
+    }
+
+    pub fn get_volatility(&self, years_until_expiry: f64) -> f64 {
+        let (slope_factor, curvature_factor) = Self::get_factors(years_until_expiry, self.decay_time);
+
+        self.level + self.slope * slope_factor + self.curvature * curvature_factor
+    }
+
+    /// The weights of the slope and curvature at the given time. The slope's weight falls from 1 to 0, and the curvature's
+    /// rises from 0 and falls back to 0.
+    fn get_factors(years_until_expiry: f64, decay_time: f64) -> (f64, f64) {
+        let x = years_until_expiry / decay_time;
+        let slope_factor = (1.0 - (-x).exp()) / x;
+
+        (slope_factor, slope_factor - (-x).exp())
+    }
+}
+
+/// How one smile was moved towards the smooth term structure.
+pub struct TermStructureAdjustment {
+    pub expiry: DateTime<Utc>,
+    pub original_atm_implied_volatility: f64,
+    /// The at the money volatility of the smooth curve at this expiry.
+    pub target_atm_implied_volatility: f64,
+    pub adjusted_atm_implied_volatility: f64,
+}
+
+/// Fit a smooth curve to the at the money volatility of every fitted smile, and shift each smile's level towards it, to
+/// remove sawtooth term structures caused by each expiry being fit separately from noisy quotes. Each smile is moved up or
+/// down as a whole by changing the SVI "a" parameter, and only as far as it can go without leaving (or going further
+/// outside) the bid and ask of the options nearest the money, so smiles with tight quotes may not move at all. Options without
+/// quotes don't limit how far smiles move.
+pub fn smooth_term_structure(
+    smile_graphs: &mut [SmileGraph],
+    smoothing: TermStructureSmoothing,
+) -> Result<Vec<TermStructureAdjustment>, TsError> {
+    let fit_curve = match smoothing {
+        TermStructureSmoothing::None => return Ok(Vec::new()),
+        TermStructureSmoothing::NelsonSiegel => NelsonSiegelCurve::fit,
+    };
+
+    let mut points = Vec::new();
+
+    for graph in smile_graphs.iter().filter(|x| x.has_been_fit) {
+        let years_until_expiry = graph.get_years_until_expiry()?;
+        let atm_implied_volatility = (graph.get_total_variance_at_log_moneyness(0.0)? / years_until_expiry).sqrt();
+
+        points.push((years_until_expiry, atm_implied_volatility));
+    }
+
+    let curve = fit_curve(&points)?;
+
+    let mut adjustments = Vec::new();
+
+    for (graph, (years_until_expiry, atm_implied_volatility)) in smile_graphs
+        .iter_mut()
+        .filter(|x| x.has_been_fit)
+        .zip(points)
+    {
+        let target_atm_implied_volatility = curve.get_volatility(years_until_expiry);
+        let wanted_shift = target_atm_implied_volatility.max(0.0).powi(2) * years_until_expiry
+            - atm_implied_volatility.powi(2) * years_until_expiry;
+        let (lowest_shift, highest_shift) = get_allowed_variance_shift(graph, years_until_expiry)?;
+        let shift = wanted_shift.clamp(lowest_shift, highest_shift);
+
+        // If the shifted smile would be invalid, it's left where it is.
+        if shift != 0.0 {
+            let _ = shift_smile_variance(graph, shift);
+        }
+
+        adjustments.push(TermStructureAdjustment {
+            expiry: graph.get_expiration()?,
+            original_atm_implied_volatility: atm_implied_volatility,
+            target_atm_implied_volatility,
+            adjusted_atm_implied_volatility: (graph.get_total_variance_at_log_moneyness(0.0)? / years_until_expiry).sqrt(),
+        });
+    }
+
+    Ok(adjustments)
+}
+
+/// The range of total variance shifts that keep the smile within the bid and ask of the options at the strikes either side
+/// of the forward price, or don't move it further outside them if it already is. Only these options are used since it's
+/// the at the money level being moved, and fits are often outside the quotes of some options further out. Always includes
+/// 0.
+fn get_allowed_variance_shift(graph: &SmileGraph, years_until_expiry: f64) -> Result<(f64, f64), TsError> {
+    let forward_price = graph.get_underlying_forward_price()?;
+    let strike_below = graph
+        .options
+        .iter()
+        .map(|x| x.strike)
+        .filter(|x| *x <= forward_price)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let strike_above = graph
+        .options
+        .iter()
+        .map(|x| x.strike)
+        .filter(|x| *x >= forward_price)
+        .fold(f64::INFINITY, f64::min);
+    let mut lowest_shift = f64::NEG_INFINITY;
+    let mut highest_shift = f64::INFINITY;
+
+    for option in graph
+        .options
+        .iter()
+        .filter(|x| x.strike == strike_below || x.strike == strike_above)
+    {
+        let variance = graph.get_total_variance_at_log_moneyness((option.strike / forward_price).ln())?;
+
+        // A quote that can't be turned into an implied volatility doesn't limit how far the smile can move.
+        let bid_variance = option
+            .bid_price
+            .and_then(|x| get_quote_total_variance(option, x, years_until_expiry));
+        let ask_variance = option
+            .ask_price
+            .and_then(|x| get_quote_total_variance(option, x, years_until_expiry));
+
+        if let Some(bid_variance) = bid_variance {
+            lowest_shift = lowest_shift.max((bid_variance - variance).min(0.0));
+        }
+
+        if let Some(ask_variance) = ask_variance {
+            highest_shift = highest_shift.min((ask_variance - variance).max(0.0));
+        }
+    }
+
+    Ok((lowest_shift, highest_shift))
+}
+
+fn get_quote_total_variance(option: &OptionInstrument, price: f64, years_until_expiry: f64) -> Option<f64> {
+    let implied_volatility = option.get_implied_volatility_at_price(price).ok()?;
+
+    Some(implied_volatility * implied_volatility * years_until_expiry)
+}
+
+/// Move the whole smile up or down by the given total variance, unless that would make it invalid or give it butterfly
+/// arbitrage.
+fn shift_smile_variance(graph: &mut SmileGraph, shift: f64) -> Result<(), TsError> {
+    let params = &graph.svi_curve_parameters;
+    let shifted_params = SVICurveParameters::new_from_values(
+        params.get_a() + shift,
+        params.get_b(),
+        params.get_p(),
+        params.get_m(),
+        params.get_o(),
+    )?;
+
+    if constants::CHECK_FOR_ARBITRAGE
+        && has_butterfly_arbitrage(
+            &shifted_params,
+            graph.lowest_observed_strike,
+            graph.highest_observed_strike,
+            graph.get_underlying_forward_price()?,
+            &graph.butterfly_check,
+        )?
+    {
+        return Err(TsError::new(UnsolvableError, "Shifting the smile would give it butterfly arbitrage"));
+    }
+
+    graph.svi_curve_parameters = shifted_params;
+
+    Ok(())
+}
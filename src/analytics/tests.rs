@@ -1,7 +1,13 @@
 #![cfg(test)]
 
 use crate::analytics::math::calculate_black_scholes;
+use crate::analytics::math::calculate_black_scholes_vega;
 use crate::analytics::math::calculate_bs_implied_volatility;
+use crate::analytics::math::{
+    calculate_black_scholes_charm, calculate_black_scholes_color, calculate_black_scholes_delta, calculate_black_scholes_gamma,
+    calculate_black_scholes_speed, calculate_black_scholes_vanna, calculate_black_scholes_volga,
+};
+use crate::helpers::{Clock, ProgressBar};
 use crate::types::TsError;
 
 use super::*;
@@ -10,120 +16,2199 @@ use super::*;
 fn test_calculate_bs_implied_volatility() {
     // Use the known-correct examples from test_calculate_black_scholes(). We'll ignore some of the examples from the other test
     // because for deeply in-the-money options etc, the math starts to be extremely precise and floating point differences
-    // can lead to different results. This is not a fault of the calculation, just an inevitable part of the maths.
-    let res =
-        calculate_bs_implied_volatility(100.0, 110.0, 90.0 / 365.0, 0.05, 1.1674, OptionType::Call).expect("Should be solveable");
-    assert_eq!(res, 0.199981689453125);
+    // can lead to different results. This is not a fault of the calculation, just an inevitable part of the maths. The
+    // solver stops once it's within constants::IMPLIED_VOLATILITY_SOLVER_ACCURACY, so we only check it got that close.
+    let res = calculate_bs_implied_volatility(100.0, 110.0, 90.0 / 365.0, 0.05, 0.0, 1.1674, OptionType::Call)
+        .expect("Should be solveable");
+    assert!((res - 0.2).abs() < 0.0001, "{res}");
 
-    let res = calculate_bs_implied_volatility(100.0, 95.0, 0.25, 0.01, 12.5279, OptionType::Call).expect("Should be solveable");
-    assert_eq!(res, 0.499969482421875);
+    let res =
+        calculate_bs_implied_volatility(100.0, 95.0, 0.25, 0.01, 0.0, 12.5279, OptionType::Call).expect("Should be solveable");
+    assert!((res - 0.5).abs() < 0.0001, "{res}");
 
-    let res = calculate_bs_implied_volatility(100.0, 105.0, 0.5, 0.05, 6.9892, OptionType::Put).expect("Should be solveable");
-    assert_eq!(res, 0.199981689453125);
+    let res =
+        calculate_bs_implied_volatility(100.0, 105.0, 0.5, 0.05, 0.0, 6.9892, OptionType::Put).expect("Should be solveable");
+    assert!((res - 0.2).abs() < 0.0001, "{res}");
 
-    let res = calculate_bs_implied_volatility(100.0, 105.0, 999.0, 0.05, 1.3112433412358892e-26, OptionType::Put)
+    let res = calculate_bs_implied_volatility(100.0, 105.0, 999.0, 0.05, 0.0, 1.3112433412358892e-26, OptionType::Put)
         .expect("Should be solveable");
-    assert_eq!(res, 0.199981689453125);
+    assert!((res - 0.2).abs() < 0.0001, "{res}");
 
-    let res = calculate_bs_implied_volatility(101.0, 100.0, 0.0001, 0.05, 1.2109840933263835e-8, OptionType::Put)
+    let res = calculate_bs_implied_volatility(101.0, 100.0, 0.0001, 0.05, 0.0, 1.2109840933263835e-8, OptionType::Put)
         .expect("Should be solveable");
-    assert_eq!(res, 0.199981689453125);
+    assert!((res - 0.2).abs() < 0.0001, "{res}");
 
-    let res = calculate_bs_implied_volatility(99.0, 100.0, 0.0001, 0.05, 9.418876667580269e-9, OptionType::Call)
+    let res = calculate_bs_implied_volatility(99.0, 100.0, 0.0001, 0.05, 0.0, 9.418876667580269e-9, OptionType::Call)
         .expect("Should be solveable");
-    assert_eq!(res, 0.199981689453125);
+    assert!((res - 0.2).abs() < 0.0001, "{res}");
 
-    let res = calculate_bs_implied_volatility(100.0, 200.0, 0.5, 0.05, 95.06198685884354, OptionType::Put)
+    let res = calculate_bs_implied_volatility(100.0, 200.0, 0.5, 0.05, 0.0, 95.06198685884354, OptionType::Put)
         .expect("Should be solveable");
-    assert_eq!(res, 0.199981689453125);
-    let res =
-        calculate_bs_implied_volatility(100.0, 200.0, 0.5, 0.1, 90.24589558405944, OptionType::Put).expect("Should be solveable");
-    assert_eq!(res, 0.199981689453125);
-    let res =
-        calculate_bs_implied_volatility(100.0, 200.0, 0.5, 0.2, 80.96753997234954, OptionType::Put).expect("Should be solveable");
-    assert_eq!(res, 0.199981689453125);
+    assert!((res - 0.2).abs() < 0.0001, "{res}");
+    let res = calculate_bs_implied_volatility(100.0, 200.0, 0.5, 0.1, 0.0, 90.24589558405944, OptionType::Put)
+        .expect("Should be solveable");
+    assert!((res - 0.2).abs() < 0.0001, "{res}");
+    let res = calculate_bs_implied_volatility(100.0, 200.0, 0.5, 0.2, 0.0, 80.96753997234954, OptionType::Put)
+        .expect("Should be solveable");
+    assert!((res - 0.2).abs() < 0.0001, "{res}");
 
-    let res = calculate_bs_implied_volatility(200.0, 100.0, 0.5, 0.05, 102.46900948834872, OptionType::Call)
+    let res = calculate_bs_implied_volatility(200.0, 100.0, 0.5, 0.05, 0.0, 102.46900948834872, OptionType::Call)
         .expect("Should be solveable");
-    assert_eq!(res, 0.199981689453125);
-    let res = calculate_bs_implied_volatility(200.0, 100.0, 0.5, 0.1, 104.87705780725437, OptionType::Call)
+    assert!((res - 0.2).abs() < 0.0001, "{res}");
+    let res = calculate_bs_implied_volatility(200.0, 100.0, 0.5, 0.1, 0.0, 104.87705780725437, OptionType::Call)
         .expect("Should be solveable");
-    assert_eq!(res, 0.199981689453125);
-    let res = calculate_bs_implied_volatility(200.0, 100.0, 0.5, 0.2, 109.51625822904599, OptionType::Call)
+    assert!((res - 0.2).abs() < 0.0001, "{res}");
+    let res = calculate_bs_implied_volatility(200.0, 100.0, 0.5, 0.2, 0.0, 109.51625822904599, OptionType::Call)
         .expect("Should be solveable");
-    assert_eq!(res, 0.199981689453125);
+    assert!((res - 0.2).abs() < 0.0001, "{res}");
 }
 
 #[test]
 fn test_calculate_black_scholes() -> Result<(), TsError> {
     // Test some known-good examples from various resources.
-    let res = calculate_black_scholes(100.0, 110.0, 90.0 / 365.0, 0.05, 0.2, OptionType::Call)?;
+    let res = calculate_black_scholes(100.0, 110.0, 90.0 / 365.0, 0.05, 0.0, 0.2, OptionType::Call)?;
     assert_eq!(res, 1.167420038028638);
 
-    let res = calculate_black_scholes(100.0, 95.0, 0.25, 0.01, 0.5, OptionType::Call)?;
+    let res = calculate_black_scholes(100.0, 95.0, 0.25, 0.01, 0.0, 0.5, OptionType::Call)?;
     assert_eq!(res, 12.527923392521458);
 
-    let res = calculate_black_scholes(100.0, 105.0, 0.5, 0.05, 0.2, OptionType::Put)?;
+    let res = calculate_black_scholes(100.0, 105.0, 0.5, 0.05, 0.0, 0.2, OptionType::Put)?;
     assert_eq!(res, 6.989220930514911);
 
     // Put becomes basically worthless 999 years from now. This is because the biggest possible payout is equal to the strike
     // price, which is worthless if received 999 years from now.
-    let res = calculate_black_scholes(100.0, 105.0, 999.0, 0.05, 0.2, OptionType::Put)?;
+    let res = calculate_black_scholes(100.0, 105.0, 999.0, 0.05, 0.0, 0.2, OptionType::Put)?;
     assert_eq!(res, 1.3112433412358892e-26);
 
     // Call value tends towards the spot price 999 years from now. This is because the option basically becomes free to buy,
     // making it more like a stock.
-    let res = calculate_black_scholes(200.0, 105.0, 999.0, 0.05, 0.2, OptionType::Call)?;
+    let res = calculate_black_scholes(200.0, 105.0, 999.0, 0.05, 0.0, 0.2, OptionType::Call)?;
     assert_eq!(res, 200.000);
 
     // An out-of-the-money put that expires very soon is basically worthless.
-    let res = calculate_black_scholes(101.0, 100.0, 0.0001, 0.05, 0.2, OptionType::Put)?;
+    let res = calculate_black_scholes(101.0, 100.0, 0.0001, 0.05, 0.0, 0.2, OptionType::Put)?;
     assert_eq!(res, 1.2109840933263835e-8);
 
     // An out-of-the-money call that expires very soon is basically worthless.
-    let res = calculate_black_scholes(99.0, 100.0, 0.0001, 0.05, 0.2, OptionType::Call)?;
+    let res = calculate_black_scholes(99.0, 100.0, 0.0001, 0.05, 0.0, 0.2, OptionType::Call)?;
     assert_eq!(res, 9.418876667580269e-9);
 
     // The value of an in-the-money call that expires very soon is basically the margin.
-    let res = calculate_black_scholes(105.0, 100.0, 0.001, 0.05, 0.2, OptionType::Call)?;
+    let res = calculate_black_scholes(105.0, 100.0, 0.001, 0.05, 0.0, 0.2, OptionType::Call)?;
     assert_eq!(res, 5.004999875002085);
 
     // The value of an in-the-money put that expires very soon is basically the margin.
-    let res = calculate_black_scholes(95.0, 100.0, 0.001, 0.05, 0.2, OptionType::Put)?;
+    let res = calculate_black_scholes(95.0, 100.0, 0.001, 0.05, 0.0, 0.2, OptionType::Put)?;
     assert_eq!(res, 4.995000124997901);
 
     // For a put, value decreases as the risk-free interest rate increases.
-    let res = calculate_black_scholes(100.0, 200.0, 0.5, 0.05, 0.2, OptionType::Put)?;
+    let res = calculate_black_scholes(100.0, 200.0, 0.5, 0.05, 0.0, 0.2, OptionType::Put)?;
     assert_eq!(res, 95.06198685884354);
-    let res = calculate_black_scholes(100.0, 200.0, 0.5, 0.1, 0.2, OptionType::Put)?;
+    let res = calculate_black_scholes(100.0, 200.0, 0.5, 0.1, 0.0, 0.2, OptionType::Put)?;
     assert_eq!(res, 90.24589558405944);
-    let res = calculate_black_scholes(100.0, 200.0, 0.5, 0.2, 0.2, OptionType::Put)?;
+    let res = calculate_black_scholes(100.0, 200.0, 0.5, 0.2, 0.0, 0.2, OptionType::Put)?;
     assert_eq!(res, 80.96753997234954);
 
     // For a call, value increases as the risk-free interest rate increases.
     // This is because interest makes buying it later (for the same price) more attractive.
-    let res = calculate_black_scholes(200.0, 100.0, 0.5, 0.05, 0.2, OptionType::Call)?;
+    let res = calculate_black_scholes(200.0, 100.0, 0.5, 0.05, 0.0, 0.2, OptionType::Call)?;
     assert_eq!(res, 102.46900948834872);
-    let res = calculate_black_scholes(200.0, 100.0, 0.5, 0.1, 0.2, OptionType::Call)?;
+    let res = calculate_black_scholes(200.0, 100.0, 0.5, 0.1, 0.0, 0.2, OptionType::Call)?;
     assert_eq!(res, 104.87705780725437);
-    let res = calculate_black_scholes(200.0, 100.0, 0.5, 0.2, 0.2, OptionType::Call)?;
+    let res = calculate_black_scholes(200.0, 100.0, 0.5, 0.2, 0.0, 0.2, OptionType::Call)?;
     assert_eq!(res, 109.51625822904599);
 
     // Volatilty increases the value of a put.
-    let res = calculate_black_scholes(100.0, 200.0, 0.5, 0.05, 0.1, OptionType::Put)?;
+    let res = calculate_black_scholes(100.0, 200.0, 0.5, 0.05, 0.0, 0.1, OptionType::Put)?;
     assert_eq!(res, 95.06198240566653);
-    let res = calculate_black_scholes(100.0, 200.0, 0.5, 0.05, 1.1, OptionType::Put)?;
+    let res = calculate_black_scholes(100.0, 200.0, 0.5, 0.05, 0.0, 1.1, OptionType::Put)?;
     assert_eq!(res, 106.29361368317517);
-    let res = calculate_black_scholes(100.0, 200.0, 0.5, 0.05, 2.1, OptionType::Put)?;
+    let res = calculate_black_scholes(100.0, 200.0, 0.5, 0.05, 0.0, 2.1, OptionType::Put)?;
     assert_eq!(res, 133.83278385809297);
 
     // Volatilty increases the value of a call.
-    let res = calculate_black_scholes(200.0, 100.0, 0.5, 0.05, 0.1, OptionType::Call)?;
+    let res = calculate_black_scholes(200.0, 100.0, 0.5, 0.05, 0.0, 0.1, OptionType::Call)?;
     assert_eq!(res, 102.46900879716674);
-    let res = calculate_black_scholes(200.0, 100.0, 0.5, 0.05, 1.1, OptionType::Call)?;
+    let res = calculate_black_scholes(200.0, 100.0, 0.5, 0.05, 0.0, 1.1, OptionType::Call)?;
     assert_eq!(res, 112.44543136855359);
-    let res = calculate_black_scholes(200.0, 100.0, 0.5, 0.05, 2.1, OptionType::Call)?;
+    let res = calculate_black_scholes(200.0, 100.0, 0.5, 0.05, 0.0, 2.1, OptionType::Call)?;
     assert_eq!(res, 139.17773534723975);
 
     Ok(())
 }
+
+#[test]
+fn test_repair_price_convexity() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let expiry_seconds = (clock.now().timestamp() + 30 * 24 * 60 * 60) as u64;
+    let call = |strike: f64, price: f64, bid: f64, ask: f64| {
+        let mut option =
+            OptionInstrument::new(price, expiry_seconds, strike, strike.to_string().into(), OptionType::Call, 100.0, clock);
+        option.bid_price = Some(bid);
+        option.ask_price = Some(ask);
+        option
+    };
+
+    // Already convex, so nothing should change.
+    let mut options = vec![
+        call(90.0, 12.0, 11.0, 13.0),
+        call(100.0, 5.0, 4.0, 6.0),
+        call(110.0, 1.5, 1.0, 2.0),
+    ];
+    assert!(repair_price_convexity(&mut options)?.is_empty());
+
+    // The middle price is too high for the prices to be convex.
+    let mut options = vec![
+        call(90.0, 12.0, 11.0, 13.0),
+        call(100.0, 7.5, 4.0, 8.0),
+        call(110.0, 2.0, 1.0, 2.5),
+        call(120.0, 0.5, 0.2, 0.8),
+    ];
+    let adjustments = repair_price_convexity(&mut options)?;
+    assert!(!adjustments.is_empty());
+
+    for (i, option) in options.iter().enumerate() {
+        assert!(option.price >= option.bid_price.unwrap() && option.price <= option.ask_price.unwrap());
+
+        if i > 0 && i < options.len() - 1 {
+            let left_slope = (option.price - options[i - 1].price) / (option.strike - options[i - 1].strike);
+            let right_slope = (options[i + 1].price - option.price) / (options[i + 1].strike - option.strike);
+            assert!(right_slope >= left_slope - 0.00001);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_interpolate_surface() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let now_seconds = clock.now().timestamp() as u64;
+
+    // Smiles with a tiny b have almost the same total variance (a) at every strike.
+    let flat_smile = |days: u64, a: f64| -> Result<SmileGraph, TsError> {
+        let mut graph = SmileGraph::new();
+        let strike = 100.0;
+        graph.options.push(OptionInstrument::new(
+            1.0,
+            now_seconds + days * 24 * 60 * 60,
+            strike,
+            "test".into(),
+            OptionType::Call,
+            100.0,
+            clock,
+        ));
+        graph.lowest_observed_strike = 90.0;
+        graph.highest_observed_strike = 110.0;
+        graph.svi_curve_parameters = types::SVICurveParameters::new_from_values(a, 0.000000001, 0.0, 0.0, 0.1)?;
+        Ok(graph)
+    };
+
+    let graphs = vec![flat_smile(30, 0.01)?, flat_smile(90, 0.03)?];
+    let years_until_expiry = (graphs[0].get_years_until_expiry()? + graphs[1].get_years_until_expiry()?) / 2.0;
+
+    let point = interpolate_surface(&graphs, 100.0, years_until_expiry)?;
+    assert!((point.total_variance - 0.02).abs() < 0.000001);
+    assert!(point.calendar_arbitrage.is_none());
+    let verifications = verify_no_arbitrage(&graphs.iter().collect::<Vec<_>>())?;
+    assert!(
+        verifications
+            .iter()
+            .all(|x| x.butterfly_arbitrage_strike.is_none())
+    );
+    assert!(
+        verifications
+            .iter()
+            .all(|x| x.calendar_arbitrage_log_moneyness.is_none())
+    );
+
+    // Before the first expiry, the implied volatility is the same as the first smile's.
+    let point = interpolate_surface(&graphs, 100.0, graphs[0].get_years_until_expiry()? / 2.0)?;
+    assert!((point.implied_volatility - graphs[0].get_implied_volatility_at_strike(100.0)?).abs() < 0.000001);
+
+    // Total variance falls between these smiles, so it should be held flat.
+    let graphs = vec![flat_smile(30, 0.03)?, flat_smile(90, 0.01)?];
+    let point = interpolate_surface(&graphs, 100.0, years_until_expiry)?;
+    assert!((point.total_variance - 0.03).abs() < 0.000001);
+    assert!(point.calendar_arbitrage.is_some());
+    assert_eq!(check_calendar_arbitrage(&graphs.iter().collect::<Vec<_>>(), 10)?.len(), 1);
+    assert!(
+        verify_no_arbitrage(&graphs.iter().collect::<Vec<_>>())?[1]
+            .calendar_arbitrage_log_moneyness
+            .is_some()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_calculate_historical_beta() -> Result<(), TsError> {
+    // Volatility falls 0.5 for every unit rise in log spot.
+    let observations: Vec<(f64, f64)> = [0.0, 0.02, -0.01, 0.03]
+        .iter()
+        .map(|x: &f64| (100.0 * x.exp(), 0.5 - 0.5 * x))
+        .collect();
+    assert!((calculate_historical_beta(&observations)? + 0.5).abs() < 0.000001);
+
+    assert!(calculate_historical_beta(&observations[..2]).is_err());
+    assert!(calculate_historical_beta(&[(100.0, 0.5), (100.0, 0.6), (100.0, 0.4)]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_calculate_heston_price() -> Result<(), TsError> {
+    // The reference example from Fang and Oosterlee (2008).
+    let params = HestonParameters::new_from_values(1.5768, 0.0398, 0.5751, -0.5711, 0.0175)?;
+    let price = calculate_heston_price(&params, 100.0, 100.0, 1.0, 0.0, OptionType::Call)?;
+    assert!((price - 5.785155450).abs() < 0.00001);
+
+    // Put-call parity.
+    let put_price = calculate_heston_price(&params, 100.0, 100.0, 1.0, 0.0, OptionType::Put)?;
+    assert!((price - put_price).abs() < 0.00001);
+
+    // With almost no volatility of variance and variance starting at its long run level, Heston is Black-Scholes.
+    let params = HestonParameters::new_from_values(2.0, 0.04, 0.0001, 0.0, 0.04)?;
+    let price = calculate_heston_price(&params, 100.0, 110.0, 0.5, 0.05, OptionType::Call)?;
+    let expected = calculate_black_scholes(100.0, 110.0, 0.5, 0.05, 0.0, 0.2, OptionType::Call)?;
+    assert!((price - expected).abs() < 0.0001);
+
+    assert!(HestonParameters::new_from_values(2.0, 0.04, 0.5, 1.0, 0.04).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_calculate_tree_price() -> Result<(), TsError> {
+    for tree_type in [TreeType::Binomial, TreeType::Trinomial] {
+        let european = TreeSettings {
+            exercise_style: ExerciseStyle::European,
+            tree_type,
+            steps: 500,
+            dividend_yield: 0.0,
+        };
+        let american = TreeSettings {
+            exercise_style: ExerciseStyle::American,
+            ..european
+        };
+
+        // European trees should converge to Black-Scholes.
+        for option_type in [OptionType::Call, OptionType::Put] {
+            let price = calculate_tree_price(100.0, 105.0, 0.5, 0.05, 0.2, option_type, &european)?;
+            let expected = calculate_black_scholes(100.0, 105.0, 0.5, 0.05, 0.0, 0.2, option_type)?;
+            assert!((price - expected).abs() < 0.01);
+        }
+
+        // Without dividends an American call is never exercised early, but an American put can be. The reference value is
+        // from Hull's "Options, Futures, and Other Derivatives".
+        let price = calculate_tree_price(50.0, 50.0, 5.0 / 12.0, 0.1, 0.4, OptionType::Call, &american)?;
+        let expected = calculate_tree_price(50.0, 50.0, 5.0 / 12.0, 0.1, 0.4, OptionType::Call, &european)?;
+        assert!((price - expected).abs() < 0.000001);
+
+        let price = calculate_tree_price(50.0, 50.0, 5.0 / 12.0, 0.1, 0.4, OptionType::Put, &american)?;
+        assert!((price - 4.28).abs() < 0.01);
+
+        // Greeks of a European option should match Black-Scholes.
+        let greeks = calculate_tree_greeks(100.0, 100.0, 1.0, 0.05, 0.2, OptionType::Call, &european)?;
+        assert!((greeks.delta - 0.6368).abs() < 0.001);
+        assert!((greeks.gamma - 0.01876).abs() < 0.0001);
+        assert!((greeks.vega - 37.52).abs() < 0.05);
+        assert!((greeks.theta + 6.414).abs() < 0.05);
+
+        // With a yield, European trees should still converge to Black-Scholes, and now an American call can be worth
+        // exercising early to collect the yield.
+        let european = TreeSettings {
+            dividend_yield: 0.08,
+            ..european
+        };
+        let american = TreeSettings {
+            dividend_yield: 0.08,
+            ..american
+        };
+        let price = calculate_tree_price(100.0, 90.0, 1.0, 0.03, 0.2, OptionType::Call, &european)?;
+        let expected = calculate_black_scholes(100.0, 90.0, 1.0, 0.03, 0.08, 0.2, OptionType::Call)?;
+        assert!((price - expected).abs() < 0.01);
+        assert!(calculate_tree_price(100.0, 90.0, 1.0, 0.03, 0.2, OptionType::Call, &american)? > price + 0.1);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_black_scholes_second_order_greeks() -> Result<(), TsError> {
+    let (spot, strike, years, rate, dividend_yield, volatility) = (100.0, 105.0, 0.75, 0.05, 0.02, 0.25);
+
+    // Check each greek against bumping and repricing with the first-order functions.
+    let close_to = |value: f64, expected: f64| (value - expected).abs() <= 0.001 * expected.abs().max(0.0001);
+    let vega = |spot: f64, volatility: f64| calculate_black_scholes_vega(spot, strike, years, rate, dividend_yield, volatility);
+
+    let vanna = calculate_black_scholes_vanna(spot, strike, years, rate, dividend_yield, volatility)?;
+    assert!(close_to(vanna, (vega(spot + 0.01, volatility)? - vega(spot - 0.01, volatility)?) / 0.02), "{vanna}");
+
+    let volga = calculate_black_scholes_volga(spot, strike, years, rate, dividend_yield, volatility)?;
+    assert!(
+        close_to(volga, (vega(spot, volatility + 0.0001)? - vega(spot, volatility - 0.0001)?) / 0.0002),
+        "{volga}"
+    );
+
+    for option_type in [OptionType::Call, OptionType::Put] {
+        let delta = |spot: f64, years: f64| -> Result<f64, TsError> {
+            let price = |spot| calculate_black_scholes(spot, strike, years, rate, dividend_yield, volatility, option_type);
+            Ok((price(spot + 0.001)? - price(spot - 0.001)?) / 0.002)
+        };
+        let gamma =
+            |spot: f64, years: f64| -> Result<f64, TsError> { Ok((delta(spot + 0.1, years)? - delta(spot - 0.1, years)?) / 0.2) };
+
+        // Time passing shortens the time until expiry.
+        let expected = delta(spot, years)?;
+        let value = calculate_black_scholes_delta(spot, strike, years, rate, dividend_yield, volatility, option_type)?;
+        assert!(close_to(value, expected), "{value}");
+
+        let expected = gamma(spot, years)?;
+        let value = calculate_black_scholes_gamma(spot, strike, years, rate, dividend_yield, volatility)?;
+        assert!(close_to(value, expected), "{value}");
+
+        let charm = calculate_black_scholes_charm(spot, strike, years, rate, dividend_yield, volatility, option_type)?;
+        assert!(close_to(charm, -(delta(spot, years + 0.001)? - delta(spot, years - 0.001)?) / 0.002), "{charm}");
+
+        let speed = calculate_black_scholes_speed(spot, strike, years, rate, dividend_yield, volatility)?;
+        assert!(close_to(speed, (gamma(spot + 0.5, years)? - gamma(spot - 0.5, years)?) / 1.0), "{speed}");
+
+        let color = calculate_black_scholes_color(spot, strike, years, rate, dividend_yield, volatility)?;
+        assert!(close_to(color, -(gamma(spot, years + 0.001)? - gamma(spot, years - 0.001)?) / 0.002), "{color}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_dividend_yield() -> Result<(), TsError> {
+    let (spot, strike, years, rate, dividend_yield) = (100.0, 105.0, 0.75, 0.05, 0.03);
+
+    // Put-call parity with a yield: C - P = S * e^(-qT) - K * e^(-rT).
+    let call = calculate_black_scholes(spot, strike, years, rate, dividend_yield, 0.3, OptionType::Call)?;
+    let put = calculate_black_scholes(spot, strike, years, rate, dividend_yield, 0.3, OptionType::Put)?;
+    let parity = spot * (-dividend_yield * years).exp() - strike * (-rate * years).exp();
+    assert!((call - put - parity).abs() < 0.000001);
+
+    // A yield lowers the forward, so it's the same as pricing with a lower spot price and no yield.
+    let lower_spot = spot * (-dividend_yield * years).exp();
+    let expected = calculate_black_scholes(lower_spot, strike, years, rate, 0.0, 0.3, OptionType::Call)?;
+    assert!((call - expected).abs() < 0.000001);
+
+    // Vega is scaled down in the same way.
+    let vega = calculate_black_scholes_vega(spot, strike, years, rate, dividend_yield, 0.3)?;
+    let expected = calculate_black_scholes_vega(lower_spot, strike, years, rate, 0.0, 0.3)?;
+    assert!((vega - expected).abs() < 0.000001);
+
+    for (option_type, price) in [(OptionType::Call, call), (OptionType::Put, put)] {
+        let res = calculate_bs_implied_volatility(spot, strike, years, rate, dividend_yield, price, option_type)?;
+        assert!((res - 0.3).abs() < 0.0001, "{res}");
+    }
+
+    // A call can't be worth more than the asset it delivers at expiry, which is worth less now with a large yield.
+    assert!(calculate_bs_implied_volatility(spot, 50.0, years, rate, 0.0, 80.0, OptionType::Call).is_ok());
+    assert!(calculate_bs_implied_volatility(spot, 50.0, years, rate, 0.5, 80.0, OptionType::Call).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_fit_nelson_siegel_curve() -> Result<(), TsError> {
+    let expected = NelsonSiegelCurve {
+        level: 0.55,
+        slope: -0.1,
+        curvature: 0.2,
+        decay_time: 0.25,
+    };
+    let points: Vec<(f64, f64)> = [0.01, 0.05, 0.1, 0.25, 0.5, 0.75, 1.0]
+        .iter()
+        .map(|x| (*x, expected.get_volatility(*x)))
+        .collect();
+
+    let curve = NelsonSiegelCurve::fit(&points)?;
+    assert!((curve.level - 0.55).abs() < 0.000001);
+    assert!((curve.slope + 0.1).abs() < 0.000001);
+    assert!((curve.curvature - 0.2).abs() < 0.000001);
+    assert_eq!(curve.decay_time, 0.25);
+
+    assert!(NelsonSiegelCurve::fit(&points[..3]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_simulate_option_price() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let now_seconds = clock.now().timestamp() as u64;
+
+    // Smiles with a flat implied volatility of 0.5, so local volatility should be 0.5 everywhere.
+    let flat_smile = |days: u64| -> Result<SmileGraph, TsError> {
+        let mut graph = SmileGraph::new();
+        graph.options.push(OptionInstrument::new(
+            1.0,
+            now_seconds + days * 24 * 60 * 60,
+            100.0,
+            "test".into(),
+            OptionType::Call,
+            100.0,
+            clock,
+        ));
+        graph.lowest_observed_strike = 50.0;
+        graph.highest_observed_strike = 150.0;
+        let years_until_expiry = graph.get_years_until_expiry()?;
+        graph.svi_curve_parameters =
+            types::SVICurveParameters::new_from_values(0.25 * years_until_expiry, 0.000000001, 0.0, 0.0, 0.1)?;
+        Ok(graph)
+    };
+
+    let graphs = vec![flat_smile(30)?, flat_smile(90)?];
+    let years_until_expiry = 60.0 / 365.2425;
+
+    for log_moneyness in [-0.3, 0.0, 0.2] {
+        assert!((calculate_local_volatility(&graphs, log_moneyness, years_until_expiry)? - 0.5).abs() < 0.001);
+    }
+
+    let settings = SimulationSettings {
+        volatility_model: VolatilityModel::LocalVolatility,
+        paths: 20000,
+        steps: 30,
+        seed: 7,
+    };
+    let option = |payoff: Payoff| SimulatedOption {
+        strike: 100.0,
+        years_until_expiry,
+        option_type: OptionType::Call,
+        payoff,
+    };
+
+    let european = simulate_option_price(&graphs, &option(Payoff::European), &settings)?;
+    let black_scholes_price = calculate_black_scholes(
+        100.0,
+        100.0,
+        years_until_expiry,
+        crate::constants::INTEREST_FREE_RATE,
+        0.0,
+        0.5,
+        OptionType::Call,
+    )?;
+    assert!((european.price - black_scholes_price).abs() < 3.0 * european.standard_error);
+
+    // Averaging lowers volatility, so an Asian option is cheaper.
+    assert!(simulate_option_price(&graphs, &option(Payoff::Asian), &settings)?.price < european.price);
+
+    // With the same paths, knock-in and knock-out options add up to the European option.
+    let barrier = |barrier_type: BarrierType| Payoff::Barrier {
+        barrier: 120.0,
+        barrier_type,
+    };
+    let up_and_in = simulate_option_price(&graphs, &option(barrier(BarrierType::UpAndIn)), &settings)?;
+    let up_and_out = simulate_option_price(&graphs, &option(barrier(BarrierType::UpAndOut)), &settings)?;
+    assert!((up_and_in.price + up_and_out.price - european.price).abs() < 0.000001);
+    assert!(up_and_out.price < european.price);
+
+    Ok(())
+}
+
+#[test]
+fn test_build_synthetic_smile() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let now_seconds = clock.now().timestamp() as u64;
+
+    // Smiles with a flat implied volatility of 0.5.
+    let flat_smile = |days: u64| -> Result<SmileGraph, TsError> {
+        let mut graph = SmileGraph::new();
+        graph.options.push(OptionInstrument::new(
+            1.0,
+            now_seconds + days * 24 * 60 * 60,
+            100.0,
+            "test".into(),
+            OptionType::Call,
+            100.0,
+            clock,
+        ));
+        graph.lowest_observed_strike = 70.0;
+        graph.highest_observed_strike = 130.0;
+        let years_until_expiry = graph.get_years_until_expiry()?;
+        graph.svi_curve_parameters =
+            types::SVICurveParameters::new_from_values(0.25 * years_until_expiry, 0.000000001, 0.0, 0.0, 0.1)?;
+        Ok(graph)
+    };
+
+    let graphs = vec![flat_smile(20)?, flat_smile(95)?];
+
+    let smile = build_synthetic_smile(&graphs, 60)?.expect("A 60 day smile should be built");
+    let years_until_expiry = (smile.expiry - clock.now()).num_seconds() as f64 / 31556926.0;
+    assert_eq!(smile.tenor_days, 60);
+    assert_eq!(smile.earlier_expiry, graphs[0].get_expiration()?);
+    assert!((svi_variance(&smile.svi_curve_parameters, 0.0)? - 0.25 * years_until_expiry).abs() < 0.00001);
+
+    // There's a listed expiry close enough to 90 days, and nothing to interpolate from at 7 or 365 days.
+    assert!(build_synthetic_smile(&graphs, 90)?.is_none());
+    assert!(build_synthetic_smile(&graphs, 7)?.is_none());
+    assert!(build_synthetic_smile(&graphs, 365)?.is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_calculate_surface_metrics() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let now_seconds = clock.now().timestamp() as u64;
+
+    let smile = |days: u64, b: f64, p: f64| -> Result<SmileGraph, TsError> {
+        let mut graph = SmileGraph::new();
+        graph.options.push(OptionInstrument::new(
+            1.0,
+            now_seconds + days * 24 * 60 * 60,
+            100.0,
+            "test".into(),
+            OptionType::Call,
+            100.0,
+            clock,
+        ));
+        graph.lowest_observed_strike = 10.0;
+        graph.highest_observed_strike = 1000.0;
+        let years_until_expiry = graph.get_years_until_expiry()?;
+        graph.svi_curve_parameters =
+            types::SVICurveParameters::new_from_values(0.25 * years_until_expiry - b * 0.1, b, p, 0.0, 0.1)?;
+        Ok(graph)
+    };
+
+    // Flat smiles at an implied volatility of 0.5 have no risk reversal or butterfly.
+    let event = |days: i64, label: &str| VolEvent {
+        date: (clock.now() + chrono::TimeDelta::days(days)).date_naive(),
+        label: label.to_string(),
+    };
+    let events = [event(-5, "Past"), event(10, "First"), event(60, "Second")];
+    let metrics = SurfaceMetrics::calculate(&[smile(30, 0.000000001, 0.0)?, smile(90, 0.000000001, 0.0)?], &events)?;
+    assert!((metrics.expiries[0].atm_implied_volatility - 0.5).abs() < 0.000001);
+    assert!(metrics.expiries[0].risk_reversal_25_delta.abs() < 0.000001);
+    assert!(metrics.expiries[0].butterfly_25_delta.abs() < 0.000001);
+    assert!(metrics.expiries[0].atm_forward_volatility.is_none());
+    assert!(
+        (metrics.expiries[1]
+            .atm_forward_volatility
+            .expect("Should have a forward volatility")
+            - 0.5)
+            .abs()
+            < 0.000001
+    );
+
+    // Each expiry is annotated with the last event before it.
+    let nearest_events = metrics
+        .expiries
+        .iter()
+        .map(|x| {
+            x.nearest_event
+                .as_ref()
+                .map(|x| (x.label.as_str(), x.days_before_expiry))
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(nearest_events, vec![Some(("First", 20.0)), Some(("Second", 30.0))]);
+    assert!(get_events_before_expiry(&events, clock.now(), clock.now() + chrono::TimeDelta::days(5)).is_empty());
+
+    // Puts are more expensive on a downward sloping smile, and both wings are above the money.
+    let metrics = SurfaceMetrics::calculate(&[smile(30, 0.05, -0.5)?], &[])?;
+    assert!(metrics.expiries[0].risk_reversal_25_delta < 0.0);
+    assert!(metrics.expiries[0].butterfly_25_delta > 0.0);
+    assert!(metrics.expiries[0].atm_skew < 0.0);
+
+    Ok(())
+}
+
+#[test]
+fn test_find_log_moneyness_at_delta() -> Result<(), TsError> {
+    let total_variance = 0.04;
+    let get_total_variance = |_: f64| Ok(total_variance);
+
+    let call_log_moneyness = find_log_moneyness_at_delta(get_total_variance, 0.25)?;
+    let put_log_moneyness = find_log_moneyness_at_delta(get_total_variance, -0.25)?;
+
+    // With a flat smile the 25 delta call is above the forward and the 25 delta put below it.
+    assert!(call_log_moneyness > 0.0);
+    assert!(put_log_moneyness < 0.0);
+    assert!((calculate_forward_delta(call_log_moneyness, total_variance, OptionType::Call)? - 0.25).abs() < 1e-9);
+    assert!((calculate_forward_delta(put_log_moneyness, total_variance, OptionType::Put)? + 0.25).abs() < 1e-9);
+
+    let strike = log_moneyness_to_strike(call_log_moneyness, 70000.0);
+    assert!((strike_to_log_moneyness(strike, 70000.0) - call_log_moneyness).abs() < 1e-12);
+
+    assert!(find_log_moneyness_at_delta(get_total_variance, 0.0).is_err());
+    assert!(find_log_moneyness_at_delta(get_total_variance, 1.0).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_update_quote() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let expiry_seconds = (clock.now().timestamp() + 60 * 24 * 60 * 60) as u64;
+    let years_until_expiry = 60.0 * 24.0 * 60.0 * 60.0 / 31556926.0;
+    let rate = crate::constants::INTEREST_FREE_RATE;
+    let forward_price = 100.0 * (rate * years_until_expiry).exp();
+    let curve = types::SVICurveParameters::new_from_values(0.03, 0.08, -0.4, 0.05, 0.15)?;
+    let volatility_at = |strike: f64| -> Result<f64, TsError> {
+        Ok((svi_variance(&curve, (strike / forward_price).ln())? / years_until_expiry).sqrt())
+    };
+
+    // Calls priced from a known curve, which the smile starts at.
+    let mut graph = SmileGraph::new();
+    for strike in [70.0, 80.0, 90.0, 100.0, 110.0, 120.0, 130.0] {
+        let volatility = volatility_at(strike)?;
+        graph.try_insert_option(OptionInstrument::new(
+            calculate_black_scholes(100.0, strike, years_until_expiry, rate, 0.0, volatility, OptionType::Call)?,
+            expiry_seconds,
+            strike,
+            strike.to_string().into(),
+            OptionType::Call,
+            100.0,
+            clock,
+        ))?;
+    }
+    graph.svi_curve_parameters = types::SVICurveParameters::new_from_values(0.03, 0.08, -0.4, 0.05, 0.15)?;
+    graph.has_been_fit = true;
+    assert!(graph.refit_smile()? < 0.000001);
+
+    // Raising the volatility of the 120 strike should raise the curve there, but not far from the money.
+    let price =
+        calculate_black_scholes(100.0, 120.0, years_until_expiry, rate, 0.0, volatility_at(120.0)? + 0.1, OptionType::Call)?;
+    graph.update_quote("120", price)?;
+    graph.refit_smile()?;
+    assert!(graph.get_implied_volatility_at_strike(120.0)? > volatility_at(120.0)? + 0.02);
+    assert!((graph.get_implied_volatility_at_strike(100.0)? - volatility_at(100.0)?).abs() < 0.03);
+
+    // Unknown instruments and prices with no implied volatility are rejected.
+    assert!(graph.update_quote("missing", price).is_err());
+    assert!(graph.update_quote("120", 1000.0).is_err());
+    assert!((graph.options[5].price - price).abs() < 0.000001);
+
+    Ok(())
+}
+
+#[test]
+fn test_determine_forward_price() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let expiry_seconds = (clock.now().timestamp() + 90 * 24 * 60 * 60) as u64;
+    let years_until_expiry = 90.0 * 24.0 * 60.0 * 60.0 / 31556926.0;
+    let rate = crate::constants::INTEREST_FREE_RATE;
+
+    // Calls and puts priced from a spot price of 100, so put-call parity should find the spot price carried forward.
+    let mut options = Vec::new();
+    for strike in [90.0, 100.0, 110.0] {
+        for option_type in [OptionType::Call, OptionType::Put] {
+            let price = calculate_black_scholes(100.0, strike, years_until_expiry, rate, 0.0, 0.5, option_type)?;
+            options.push(OptionInstrument::new(price, expiry_seconds, strike, "test".into(), option_type, 100.0, clock));
+        }
+    }
+
+    let carried_forward = 100.0 * (rate * years_until_expiry).exp();
+    let quote = |price: f64, seconds_late: i64| ForwardQuote {
+        price,
+        time: clock.now() + chrono::TimeDelta::seconds(seconds_late),
+    };
+    let mut inputs = ForwardInputs {
+        options: &options,
+        quote_time: Some(clock.now()),
+        futures: Some(quote(101.0, 10)),
+        perpetual: None,
+    };
+    let settings = ForwardSourceSettings::default();
+
+    let provenance = determine_forward_price(&inputs, &settings)?;
+    assert_eq!(provenance.source, ForwardSource::Futures);
+    assert_eq!(provenance.forward_price, 101.0);
+    assert!(provenance.skipped_sources.is_empty());
+
+    // A stale future is skipped.
+    inputs.futures = Some(quote(101.0, 3600));
+    let provenance = determine_forward_price(&inputs, &settings)?;
+    assert_eq!(provenance.source, ForwardSource::PutCallParity);
+    assert!((provenance.forward_price - carried_forward).abs() < 0.000001);
+    assert_eq!(provenance.skipped_sources.len(), 1);
+
+    // Sources are tried in the configured order, and it's an error if none can be used.
+    let settings = ForwardSourceSettings {
+        priority: vec![ForwardSource::PerpetualFunding, ForwardSource::SpotCarry],
+        ..Default::default()
+    };
+    let provenance = determine_forward_price(&inputs, &settings)?;
+    assert_eq!(provenance.source, ForwardSource::SpotCarry);
+    assert!((provenance.forward_price - carried_forward).abs() < 0.000001);
+
+    let settings = ForwardSourceSettings {
+        priority: vec![ForwardSource::PerpetualFunding],
+        ..Default::default()
+    };
+    assert!(determine_forward_price(&inputs, &settings).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_build_options_from_delta_quotes() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_700_000_000).unwrap());
+    let expiry = clock.now() + chrono::TimeDelta::days(30);
+    let quotes = [
+        DeltaQuote {
+            delta: parse_delta_label("25P")?,
+            implied_volatility: 0.6,
+        },
+        DeltaQuote {
+            delta: parse_delta_label("ATM")?,
+            implied_volatility: 0.5,
+        },
+        DeltaQuote {
+            delta: parse_delta_label("25C")?,
+            implied_volatility: 0.55,
+        },
+    ];
+    assert!(parse_delta_label("25").is_err());
+
+    let options = build_options_from_delta_quotes("1M", expiry, 100.0, &quotes, clock)?;
+
+    for (option, quote) in options.iter().zip(&quotes) {
+        // Each option is at the strike with its quoted delta, and has its quoted volatility.
+        let total_variance = quote.implied_volatility.powi(2) * option.get_years_until_expiry()?;
+        let delta = calculate_forward_delta(strike_to_log_moneyness(option.strike, 100.0), total_variance, option.option_type)?;
+        assert!((delta - quote.delta).abs() < 0.000001);
+        assert!((option.get_implied_volatility()? - quote.implied_volatility).abs() < 0.001);
+    }
+
+    assert_eq!(&*options[0].instrument_id, "1M-25P");
+    assert_eq!(&*options[1].instrument_id, "1M-ATM");
+
+    Ok(())
+}
+
+#[test]
+fn test_predict_fit_seconds() -> Result<(), TsError> {
+    let record = |days_until_expiry: f64, quote_count: usize, seconds: f64| FitTimeRecord {
+        recorded_at: chrono::Utc::now(),
+        days_until_expiry,
+        quote_count,
+        seconds,
+    };
+
+    assert_eq!(FitTimeModel::new(Vec::new()).predict_seconds(7.0, 50)?, None);
+
+    // Short-dated smiles took 0.1 seconds per option and long-dated ones 1 second.
+    let mut records = Vec::new();
+    for _ in 0..5 {
+        records.push(record(2.0, 40, 4.0));
+        records.push(record(180.0, 100, 100.0));
+    }
+    let model = FitTimeModel::new(records);
+
+    let short_dated = model.predict_seconds(3.0, 50)?.expect("Should predict");
+    assert!((short_dated - 5.0).abs() < 0.000001);
+
+    let long_dated = model.predict_seconds(200.0, 80)?.expect("Should predict");
+    assert!((long_dated - 80.0).abs() < 0.000001);
+
+    Ok(())
+}
+
+#[test]
+fn test_scenario_shocks() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let now_seconds = clock.now().timestamp() as u64;
+
+    let smile = |b: f64, p: f64| -> Result<SmileGraph, TsError> {
+        let mut graph = SmileGraph::new();
+        graph.options.push(OptionInstrument::new(
+            1.0,
+            now_seconds + 30 * 24 * 60 * 60,
+            100.0,
+            "test".into(),
+            OptionType::Call,
+            100.0,
+            clock,
+        ));
+        graph.lowest_observed_strike = 10.0;
+        graph.highest_observed_strike = 1000.0;
+        let years_until_expiry = graph.get_years_until_expiry()?;
+        graph.svi_curve_parameters =
+            types::SVICurveParameters::new_from_values(0.25 * years_until_expiry - b * 0.1, b, p, 0.0, 0.1)?;
+        Ok(graph)
+    };
+
+    let graphs = vec![smile(0.05, -0.5)?];
+    let years_until_expiry = graphs[0].get_years_until_expiry()?;
+    let forward_price = graphs[0].get_underlying_forward_price()?;
+    let unshocked = ScenarioShock::default();
+    let volatility_at = |shock: &ScenarioShock, strike: f64| -> Result<f64, TsError> {
+        Ok(shock
+            .get_shocked_point(&graphs, strike, years_until_expiry)?
+            .implied_volatility)
+    };
+
+    // Without shocks, the surface is read as it is.
+    assert!((volatility_at(&unshocked, 90.0)? - graphs[0].get_implied_volatility_at_strike(90.0)?).abs() < 0.000001);
+
+    let shock = ScenarioShock {
+        volatility_shift: 0.05,
+        ..Default::default()
+    };
+    assert!((volatility_at(&shock, 90.0)? - volatility_at(&unshocked, 90.0)? - 0.05).abs() < 0.000001);
+
+    // Sticky strike keeps each strike's volatility, and sticky delta moves the smile with the forward.
+    let spot_move = |spot_move_rule: SpotMoveRule| ScenarioShock {
+        spot_move: 0.1,
+        spot_move_rule,
+        ..Default::default()
+    };
+    assert!((volatility_at(&spot_move(SpotMoveRule::StickyStrike), 90.0)? - volatility_at(&unshocked, 90.0)?).abs() < 0.000001);
+    assert!((volatility_at(&spot_move(SpotMoveRule::StickyDelta), 99.0)? - volatility_at(&unshocked, 90.0)?).abs() < 0.000001);
+
+    // A negative twist raises the put wing and lowers the call wing, turning about the forward.
+    let shock = ScenarioShock {
+        skew_twist: -0.1,
+        ..Default::default()
+    };
+    assert!((volatility_at(&shock, forward_price)? - volatility_at(&unshocked, forward_price)?).abs() < 0.000001);
+    assert!(volatility_at(&shock, 80.0)? > volatility_at(&unshocked, 80.0)?);
+    assert!(volatility_at(&shock, 120.0)? < volatility_at(&unshocked, 120.0)?);
+
+    // Options that expire during the scenario are worth their intrinsic value at the shocked spot price.
+    let position = |option_type: OptionType| PortfolioPosition {
+        strike: 100.0,
+        expiry: graphs[0].get_expiration().unwrap(),
+        option_type,
+        quantity: -2.0,
+    };
+    let shock = ScenarioShock {
+        spot_move: -0.2,
+        days_passed: 31.0,
+        ..Default::default()
+    };
+    let valuations = value_portfolio(&graphs, &[position(OptionType::Call), position(OptionType::Put)], &shock, clock.now())?;
+    assert_eq!(valuations[0].shocked_price, 0.0);
+    assert!((valuations[1].shocked_price - 20.0).abs() < 0.000001);
+    assert!((valuations[0].pnl - 2.0 * valuations[0].base_price).abs() < 0.000001);
+    assert!(valuations[1].pnl < 0.0);
+
+    Ok(())
+}
+
+#[test]
+fn test_inverse_option_pricing() -> Result<(), TsError> {
+    // The reference value is from Hull's "Options, Futures, and Other Derivatives".
+    let price = calculate_black_76(20.0, 20.0, 4.0 / 12.0, 0.09, 0.25, OptionType::Put)?;
+    assert!((price - 1.12).abs() < 0.005);
+
+    // Black-76 is Black-Scholes on the discounted forward price.
+    let price = calculate_black_76(110.0, 105.0, 0.5, 0.05, 0.3, OptionType::Call)?;
+    let expected =
+        calculate_black_scholes(110.0 * std::f64::consts::E.powf(-0.05 * 0.5), 105.0, 0.5, 0.05, 0.0, 0.3, OptionType::Call)?;
+    assert!((price - expected).abs() < 0.000001);
+
+    // An inverse option's coin price solves back to its implied volatility, from the option's USD price at spot.
+    let now = chrono::DateTime::from_timestamp_secs(1_700_000_000).expect("Time should be valid");
+    let expiry = now + chrono::Duration::days(60);
+    let years_until_expiry = (expiry - now).num_seconds() as f64 / 31556926.0;
+
+    for (strike, option_type) in [(50_000.0, OptionType::Put), (70_000.0, OptionType::Call)] {
+        let coin_price = calculate_inverse_option_price(62_000.0, strike, years_until_expiry, 0.55, option_type)?;
+        let implied_volatility =
+            calculate_inverse_implied_volatility(62_000.0, strike, years_until_expiry, coin_price, option_type)?;
+        assert!((implied_volatility - 0.55).abs() < 0.0001);
+
+        let spot_price = 61_000.0;
+        let mut option = OptionInstrument::new(
+            coin_price * spot_price,
+            expiry.timestamp() as u64,
+            strike,
+            "test".into(),
+            option_type,
+            spot_price,
+            Clock::Fixed(now),
+        );
+        option.pricing_model = PricingModel::Inverse;
+        option.set_forward_price(62_000.0);
+        assert!((option.get_implied_volatility()? - 0.55).abs() < 0.0001);
+
+        // Black-Scholes on the same USD price discounts a premium that isn't discounted, so disagrees.
+        option.pricing_model = PricingModel::BlackScholes;
+        option.set_forward_price(62_000.0);
+        assert!((option.get_implied_volatility()? - 0.55).abs() > 0.001);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_exchange_implied_volatility_source() -> Result<(), TsError> {
+    let now = chrono::DateTime::from_timestamp_secs(1_700_000_000).expect("Time should be valid");
+    let expiry = now + chrono::Duration::days(90);
+    let years_until_expiry = (expiry - now).num_seconds() as f64 / 31556926.0;
+    let price = calculate_black_scholes(
+        60_000.0,
+        65_000.0,
+        years_until_expiry,
+        crate::constants::INTEREST_FREE_RATE,
+        0.0,
+        0.6,
+        OptionType::Call,
+    )?;
+
+    let mut option = OptionInstrument::new(
+        price,
+        expiry.timestamp() as u64,
+        65_000.0,
+        "test".into(),
+        OptionType::Call,
+        60_000.0,
+        Clock::Fixed(now),
+    );
+    option.implied_volatility_source = ImpliedVolatilitySource::Exchange;
+
+    // Without an exchange implied volatility there's nothing to use.
+    assert!(option.get_implied_volatility().is_err());
+
+    // The exchange's implied volatility is used as is, while the solved one still comes from the price.
+    option.exchange_implied_volatility = Some(0.65);
+    assert_eq!(option.get_implied_volatility()?, 0.65);
+    assert!((option.get_total_implied_variance()? - 0.65 * 0.65 * years_until_expiry).abs() < 1e-12);
+    assert!((option.get_solved_implied_volatility()? - 0.6).abs() < 0.01);
+
+    Ok(())
+}
+
+#[test]
+fn test_svi_g() -> Result<(), TsError> {
+    let curve = types::SVICurveParameters::new_from_values(0.03, 0.08, -0.4, 0.05, 0.15)?;
+
+    // The analytic curvature should match the slope's finite difference.
+    for k in [-0.5, -0.1, 0.0, 0.05, 0.3, 0.8] {
+        let expected = (math::svi_variance_slope(&curve, k + 0.0001)? - math::svi_variance_slope(&curve, k - 0.0001)?) / 0.0002;
+        let value = math::svi_variance_curvature(&curve, k)?;
+        assert!((value - expected).abs() < 0.00001, "{value}");
+    }
+
+    // This curve is free of butterfly arbitrage, so g(k) should stay above zero and agree with the yes/no check.
+    let settings = ButterflyCheckSettings::default();
+    let (strike, g) = math::find_minimum_svi_g(&curve, 50.0, 200.0, 100.0, &settings)?;
+    assert!(g > 0.0, "{g}");
+    assert!((50.0 * (-settings.wing_log_moneyness).exp()..=200.0 * settings.wing_log_moneyness.exp()).contains(&strike));
+    assert!(!math::has_butterfly_arbitrage(&curve, 50.0, 200.0, 100.0, &settings)?);
+    assert!((math::get_butterfly_arbitrage_margin(&curve, 50.0, 200.0, 100.0, &settings)? - g).abs() < 0.000001);
+
+    // Axel Vogt's curve (https://arxiv.org/pdf/1204.0646) has arbitrage to the right of the money, so the minimum should
+    // be negative and found there.
+    let curve = types::SVICurveParameters::new_from_values(-0.0410, 0.1331, 0.3060, 0.3586, 0.4153)?;
+    let (strike, g) = math::find_minimum_svi_g(&curve, 50.0, 180.0, 100.0, &settings)?;
+    assert!(g < 0.0, "{g}");
+    assert!(strike > 180.0, "{strike}");
+    assert!(math::has_butterfly_arbitrage(&curve, 50.0, 180.0, 100.0, &settings)?);
+
+    // Its arbitrage is beyond a highest observed strike of 180, so it's only found if the wings are checked.
+    let settings = ButterflyCheckSettings {
+        wing_log_moneyness: 0.0,
+        wing_points: 0,
+        ..ButterflyCheckSettings::default()
+    };
+    settings.check_valid()?;
+    assert!(!math::has_butterfly_arbitrage(&curve, 50.0, 180.0, 100.0, &settings)?);
+    assert!(
+        ButterflyCheckSettings {
+            wing_points: 0,
+            ..ButterflyCheckSettings::default()
+        }
+        .check_valid()
+        .is_err()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_fit_essvi_surface() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let rate = crate::constants::INTEREST_FREE_RATE;
+    let power_law = EssviPowerLaw { eta: 0.8, gamma: 0.4 };
+
+    // Two smiles priced from a known eSSVI surface, with the skew flattening out with expiry.
+    let smile = |days: u64, theta: f64, rho: f64| -> Result<SmileGraph, TsError> {
+        let years_until_expiry = (days * 24 * 60 * 60) as f64 / 31556926.0;
+        let forward_price = 100.0 * (rate * years_until_expiry).exp();
+        let mut graph = SmileGraph::new();
+
+        for strike in [60.0, 70.0, 80.0, 90.0, 100.0, 110.0, 120.0, 135.0, 150.0] {
+            let log_moneyness = (strike / forward_price).ln();
+            let volatility = (essvi_variance(theta, rho, power_law.phi(theta), log_moneyness) / years_until_expiry).sqrt();
+            graph.try_insert_option(OptionInstrument::new(
+                calculate_black_scholes(100.0, strike, years_until_expiry, rate, 0.0, volatility, OptionType::Call)?,
+                clock.now().timestamp() as u64 + days * 24 * 60 * 60,
+                strike,
+                strike.to_string().into(),
+                OptionType::Call,
+                100.0,
+                clock,
+            ))?;
+        }
+
+        Ok(graph)
+    };
+
+    let mut graphs = vec![smile(30, 0.02, -0.3)?, smile(120, 0.07, -0.2)?];
+    let surface = fit_essvi_surface(&mut graphs)?;
+
+    assert!((surface.power_law.eta - 0.8).abs() < 0.05);
+    assert!((surface.slices[0].theta - 0.02).abs() < 0.0005);
+    assert!((surface.slices[1].theta - 0.07).abs() < 0.0005);
+    assert!((surface.slices[0].rho + 0.3).abs() < 0.05);
+    assert!((surface.slices[1].rho + 0.2).abs() < 0.05);
+    assert!(surface.slices.iter().all(|x| x.rmse < 0.001));
+
+    // Each smile is replaced by the same curve as raw SVI, which is free of butterfly and calendar arbitrage.
+    for graph in &graphs {
+        assert!(graph.has_been_fit);
+        assert!(graph.get_minimum_g()?.1 > 0.0);
+    }
+    for k in [-1.0, -0.5, 0.0, 0.5, 1.0] {
+        assert!(graphs[1].get_total_variance_at_log_moneyness(k)? > graphs[0].get_total_variance_at_log_moneyness(k)?);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_minimise_with_differential_evolution() {
+    let settings = DifferentialEvolutionSettings {
+        population: 30,
+        max_generations: 500,
+        mutation: 0.7,
+        crossover: 0.9,
+        required_improvement: 0.000001,
+        stagnant_generations: 50,
+        seed: 1,
+        deadline: None,
+    };
+
+    // Rastrigin's function has a local minimum at every whole number, which a local optimiser started away from the
+    // global minimum at 1.5 can't escape.
+    let rastrigin = |x: &[f64; 2]| {
+        x.iter()
+            .map(|x| (x - 1.5).powi(2) - 10.0 * (2.0 * std::f64::consts::PI * (x - 1.5)).cos() + 10.0)
+            .sum::<f64>()
+    };
+    let mut generations = 0;
+    let (best, loss) =
+        minimise_with_differential_evolution(rastrigin, &[(-5.0, 5.0), (-5.0, 5.0)], &settings, |x, _| generations = x);
+
+    assert!((best[0] - 1.5).abs() < 0.001);
+    assert!((best[1] - 1.5).abs() < 0.001);
+    assert!(loss < 0.0001);
+    assert!(generations < 500);
+
+    // The same seed gives the same result.
+    assert_eq!(
+        minimise_with_differential_evolution(rastrigin, &[(-5.0, 5.0), (-5.0, 5.0)], &settings, |_, _| {}).0,
+        best
+    );
+}
+
+#[test]
+fn test_latin_hypercube_sample() {
+    let bounds = [(0.0, 1.0), (-2.0, 2.0), (10.0, 20.0)];
+    let points = latin_hypercube_sample(&bounds, 8, 1);
+
+    assert_eq!(points.len(), 8);
+
+    // Each eighth of every parameter's range has exactly one point.
+    for (parameter, (start, end)) in bounds.iter().enumerate() {
+        let mut strata: Vec<usize> = points
+            .iter()
+            .map(|x| ((x[parameter] - start) / (end - start) * 8.0) as usize)
+            .collect();
+        strata.sort();
+        assert_eq!(strata, (0..8).collect::<Vec<usize>>());
+    }
+
+    assert_eq!(latin_hypercube_sample(&bounds, 8, 1), points);
+    assert_ne!(latin_hypercube_sample(&bounds, 8, 2), points);
+}
+
+#[test]
+fn test_refit_smile_from_edge_of_valid_curves() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let expiry_seconds = (clock.now().timestamp() + 90 * 24 * 60 * 60) as u64;
+    let years_until_expiry = 90.0 * 24.0 * 60.0 * 60.0 / 31556926.0;
+    let rate = crate::constants::INTEREST_FREE_RATE;
+    let forward_price = 100.0 * (rate * years_until_expiry).exp();
+    let curve = types::SVICurveParameters::new_from_values(0.02, 0.15, -0.8, 0.02, 0.2)?;
+
+    // A steeply skewed smile, priced from a known curve.
+    let mut graph = SmileGraph::new();
+    for strike in [60.0, 70.0, 80.0, 90.0, 100.0, 110.0, 120.0, 135.0, 150.0] {
+        let volatility = (svi_variance(&curve, (strike / forward_price).ln())? / years_until_expiry).sqrt();
+        graph.try_insert_option(OptionInstrument::new(
+            calculate_black_scholes(100.0, strike, years_until_expiry, rate, 0.0, volatility, OptionType::Call)?,
+            expiry_seconds,
+            strike,
+            strike.to_string().into(),
+            OptionType::Call,
+            100.0,
+            clock,
+        ))?;
+    }
+
+    // Starting with p almost at -1 and a sharp vertex, where a step in almost any direction would leave the valid curves
+    // if the parameters weren't constrained.
+    graph.svi_curve_parameters = types::SVICurveParameters::new_from_values(0.01, 0.1, -0.99, -0.3, 0.05)?;
+    graph.has_been_fit = true;
+    assert!(graph.refit_smile()? < 0.000001);
+
+    let fitted = &graph.svi_curve_parameters;
+    assert!((fitted.get_b() - 0.15).abs() < 0.001);
+    assert!((fitted.get_p() + 0.8).abs() < 0.001);
+    assert!((fitted.get_o() - 0.2).abs() < 0.001);
+    assert!(graph.get_fit_rmse()? < 0.0001);
+
+    Ok(())
+}
+
+#[test]
+fn test_smile_fit_quality() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let expiry_seconds = (clock.now().timestamp() + 60 * 24 * 60 * 60) as u64;
+    let years_until_expiry = 60.0 * 24.0 * 60.0 * 60.0 / 31556926.0;
+    let rate = crate::constants::INTEREST_FREE_RATE;
+    let forward_price = 100.0 * (rate * years_until_expiry).exp();
+    let curve = types::SVICurveParameters::new_from_values(0.03, 0.08, -0.4, 0.05, 0.15)?;
+    let price_at = |strike: f64, volatility_shift: f64| -> Result<f64, TsError> {
+        let volatility = (svi_variance(&curve, (strike / forward_price).ln())? / years_until_expiry).sqrt();
+        calculate_black_scholes(100.0, strike, years_until_expiry, rate, 0.0, volatility + volatility_shift, OptionType::Call)
+    };
+
+    // Calls quoted 1 volatility point either side of a known curve, except the 120 strike, which is 5 points above it.
+    let mut graph = SmileGraph::new();
+    for strike in [70.0, 80.0, 90.0, 100.0, 110.0, 120.0, 130.0] {
+        let shift = if strike == 120.0 { 0.05 } else { 0.0 };
+        let mut option = OptionInstrument::new(
+            price_at(strike, shift)?,
+            expiry_seconds,
+            strike,
+            strike.to_string().into(),
+            OptionType::Call,
+            100.0,
+            clock,
+        );
+        option.bid_price = Some(price_at(strike, shift - 0.01)?);
+        option.ask_price = Some(price_at(strike, shift + 0.01)?);
+        // The 120 strike was also quoted 30 seconds before the others.
+        option.quote_time = Some(clock.now() - chrono::TimeDelta::seconds(if strike == 120.0 { 30 } else { 0 }));
+        graph.try_insert_option(option)?;
+    }
+    graph.svi_curve_parameters = types::SVICurveParameters::new_from_values(0.03, 0.08, -0.4, 0.05, 0.15)?;
+    graph.has_been_fit = true;
+
+    let quality = SmileFitQuality::calculate(&graph, Some(clock.now()))?;
+
+    assert_eq!(quality.options.len(), 7);
+    assert_eq!(quality.worst_fit_instruments[0].as_ref(), "120");
+    assert!((quality.max_absolute_residual_vol_points - 5.0).abs() < 0.001);
+    assert!((quality.options[5].residual_vol_points + 5.0).abs() < 0.001);
+    assert!((quality.rmse_vol_points - 5.0 / 7.0_f64.sqrt()).abs() < 0.001);
+    assert!(quality.options[2].residual_vol_points.abs() < 0.001);
+    assert!(quality.r_squared < 1.0);
+    assert_eq!(quality.outside_bid_ask_count, 1);
+    assert!(quality.options[5].outside_bid_ask);
+    assert!((quality.max_quote_age_seconds.unwrap() - 30.0).abs() < 0.001);
+    assert!((quality.mean_quote_age_seconds.unwrap() - 30.0 / 7.0).abs() < 0.001);
+    assert!(
+        SmileFitQuality::calculate(&graph, None)?
+            .max_quote_age_seconds
+            .is_none()
+    );
+
+    // Smiles that haven't been fit aren't reported.
+    let mut unfitted = SmileGraph::new();
+    unfitted.try_insert_option(graph.options[0].clone())?;
+    let report = FitQualityReport::calculate(&[graph, unfitted], clock.now())?;
+    assert_eq!(report.smiles.len(), 1);
+    assert!((report.smiles[0].max_quote_age_seconds.unwrap() - 30.0).abs() < 0.001);
+
+    Ok(())
+}
+
+#[test]
+fn test_cross_validate_smile() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let expiry_seconds = (clock.now().timestamp() + 60 * 24 * 60 * 60) as u64;
+    let years_until_expiry = 60.0 * 24.0 * 60.0 * 60.0 / 31556926.0;
+    let rate = crate::constants::INTEREST_FREE_RATE;
+    let forward_price = 100.0 * (rate * years_until_expiry).exp();
+    let curve = types::SVICurveParameters::new_from_values(0.03, 0.08, -0.4, 0.05, 0.15)?;
+
+    // Calls priced from a known curve, so whichever options are held out, the rest should predict them closely.
+    let mut graph = SmileGraph::new();
+    for strike in [60.0, 70.0, 80.0, 90.0, 95.0, 100.0, 105.0, 110.0, 120.0, 130.0, 145.0] {
+        let volatility = (svi_variance(&curve, (strike / forward_price).ln())? / years_until_expiry).sqrt();
+        graph.try_insert_option(OptionInstrument::new(
+            calculate_black_scholes(100.0, strike, years_until_expiry, rate, 0.0, volatility, OptionType::Call)?,
+            expiry_seconds,
+            strike,
+            strike.to_string().into(),
+            OptionType::Call,
+            100.0,
+            clock,
+        ))?;
+    }
+
+    let mut progress = ProgressBar::new("", 3, true);
+    let result = cross_validate_smile(&graph, CrossValidation::KFold(3), &mut progress)?;
+
+    assert_eq!(result.folds, 3);
+    assert_eq!(result.failed_folds, 0);
+    assert_eq!(result.predicted_options, 11);
+    assert_eq!(result.in_sample_rmse_vol_points, None);
+    assert!(result.out_of_sample_rmse_vol_points < 0.1);
+    assert!(result.out_of_sample_max_absolute_error_vol_points < 0.3);
+
+    // There can't be more folds than options.
+    let mut progress = ProgressBar::new("", 11, true);
+    assert_eq!(cross_validate_smile(&graph, CrossValidation::KFold(50), &mut progress)?.folds, 11);
+
+    assert_eq!(CrossValidation::try_from("loo")?, CrossValidation::LeaveOneOut);
+    assert_eq!(CrossValidation::try_from("5")?, CrossValidation::KFold(5));
+    assert!(CrossValidation::try_from("1").is_err());
+    assert!(CrossValidation::try_from("five").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_bootstrap_smile() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let expiry_seconds = (clock.now().timestamp() + 60 * 24 * 60 * 60) as u64;
+    let years_until_expiry = 60.0 * 24.0 * 60.0 * 60.0 / 31556926.0;
+    let rate = crate::constants::INTEREST_FREE_RATE;
+    let forward_price = 100.0 * (rate * years_until_expiry).exp();
+    let curve = types::SVICurveParameters::new_from_values(0.03, 0.08, -0.4, 0.05, 0.15)?;
+
+    // Calls priced from a known curve with some noise added, so that there are residuals to resample.
+    let mut graph = SmileGraph::new();
+    let strikes = [60.0, 70.0, 80.0, 90.0, 95.0, 100.0, 105.0, 110.0, 120.0, 130.0, 145.0];
+    for (i, strike) in strikes.into_iter().enumerate() {
+        let noise = [0.004, -0.003, 0.002, -0.005][i % 4];
+        let volatility = (svi_variance(&curve, (strike / forward_price).ln())? / years_until_expiry).sqrt() + noise;
+        graph.try_insert_option(OptionInstrument::new(
+            calculate_black_scholes(100.0, strike, years_until_expiry, rate, 0.0, volatility, OptionType::Call)?,
+            expiry_seconds,
+            strike,
+            strike.to_string().into(),
+            OptionType::Call,
+            100.0,
+            clock,
+        ))?;
+    }
+
+    let mut progress = ProgressBar::new("", 30, true);
+    assert!(bootstrap_smile(&graph, 30, 0.9, &mut progress).is_err());
+
+    graph.fit_smile(&ProgressBar::new("", 1, true))?;
+    let band = bootstrap_smile(&graph, 30, 0.9, &mut progress)?;
+
+    assert_eq!(band.resamples, 30);
+    assert!(band.failed_resamples < 30);
+    for interval in [band.a, band.b, band.rho, band.m, band.sigma] {
+        assert!(interval.lower <= interval.upper);
+    }
+
+    // The band should be somewhere around the fitted curve, and a few vol points wide at most in the middle of the smile.
+    assert!(!band.strikes.is_empty());
+    for point in &band.strikes {
+        assert!(point.strike > 0.0);
+        assert!(point.implied_volatility.lower <= point.implied_volatility.upper);
+    }
+    let middle = band
+        .strikes
+        .iter()
+        .min_by(|a, b| {
+            (a.strike - 100.0)
+                .abs()
+                .total_cmp(&(b.strike - 100.0).abs())
+        })
+        .unwrap();
+    let fitted = graph.get_implied_volatility_at_strike(middle.strike)?;
+    assert!(middle.implied_volatility.lower - 0.005 <= fitted && fitted <= middle.implied_volatility.upper + 0.005);
+    assert!(middle.implied_volatility.upper - middle.implied_volatility.lower < 0.05);
+
+    // The same fit always gives the same band.
+    let again = bootstrap_smile(&graph, 30, 0.9, &mut progress)?;
+    assert_eq!(again.b.lower, band.b.lower);
+    assert_eq!(again.b.upper, band.b.upper);
+
+    assert!(bootstrap_smile(&graph, 1, 0.9, &mut progress).is_err());
+    assert!(bootstrap_smile(&graph, 30, 1.0, &mut progress).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_fit_prior() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let expiry_seconds = (clock.now().timestamp() + 60 * 24 * 60 * 60) as u64;
+    let years_until_expiry = 60.0 * 24.0 * 60.0 * 60.0 / 31556926.0;
+    let rate = crate::constants::INTEREST_FREE_RATE;
+    let forward_price = 100.0 * (rate * years_until_expiry).exp();
+    let curve = types::SVICurveParameters::new_from_values(0.03, 0.08, -0.4, 0.05, 0.15)?;
+
+    // Only a few strikes, as in the sparse smiles a prior is for.
+    let mut graph = SmileGraph::new();
+    for strike in [75.0, 90.0, 100.0, 110.0, 130.0] {
+        let volatility = (svi_variance(&curve, (strike / forward_price).ln())? / years_until_expiry).sqrt();
+        graph.try_insert_option(OptionInstrument::new(
+            calculate_black_scholes(100.0, strike, years_until_expiry, rate, 0.0, volatility, OptionType::Call)?,
+            expiry_seconds,
+            strike,
+            strike.to_string().into(),
+            OptionType::Call,
+            100.0,
+            clock,
+        ))?;
+    }
+
+    let mut prior = graph.with_options(graph.options.clone())?;
+    prior.svi_curve_parameters = types::SVICurveParameters::new_from_values(0.02, 0.12, -0.1, 0.0, 0.2)?;
+
+    let mut unregularised = graph.with_options(graph.options.clone())?;
+    unregularised.fit_smile(&ProgressBar::new("", 1, true))?;
+
+    let mut weakly_regularised = graph.with_options(graph.options.clone())?;
+    weakly_regularised.set_fit_prior(&prior, 0.000001)?;
+    weakly_regularised.fit_smile(&ProgressBar::new("", 1, true))?;
+
+    let mut strongly_regularised = graph.with_options(graph.options.clone())?;
+    strongly_regularised.set_fit_prior(&prior, 1.0)?;
+    strongly_regularised.fit_smile(&ProgressBar::new("", 1, true))?;
+
+    // A weak prior barely changes the fit, while a strong one pulls it most of the way to the prior.
+    let distance_to_prior = |graph: &SmileGraph| {
+        let (fit, prior) = (&graph.svi_curve_parameters, &prior.svi_curve_parameters);
+        (fit.get_b() - prior.get_b()).abs() / prior.get_b() + (fit.get_p() - prior.get_p()).abs()
+    };
+    assert!(unregularised.get_fit_rmse()? < 0.001);
+    assert!((weakly_regularised.get_fit_rmse()? - unregularised.get_fit_rmse()?).abs() < 0.001);
+    assert!(distance_to_prior(&strongly_regularised) < distance_to_prior(&unregularised) * 0.5);
+
+    // Copies of the smile are pulled towards the same curve.
+    assert!(
+        strongly_regularised
+            .with_options(graph.options.clone())?
+            .fit_prior
+            .is_some()
+    );
+    // Except when the refit is meant to show how much the fit depends on the options alone.
+    assert!(
+        strongly_regularised
+            .with_options_unconstrained(graph.options.clone())?
+            .fit_prior
+            .is_none()
+    );
+
+    assert!(graph.set_fit_prior(&prior, 0.0).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_atm_anchor() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let expiry_seconds = (clock.now().timestamp() + 60 * 24 * 60 * 60) as u64;
+    let years_until_expiry = 60.0 * 24.0 * 60.0 * 60.0 / 31556926.0;
+    let rate = crate::constants::INTEREST_FREE_RATE;
+    let forward_price = 100.0 * (rate * years_until_expiry).exp();
+    let curve = types::SVICurveParameters::new_from_values(0.03, 0.08, -0.4, 0.05, 0.15)?;
+    let option = |strike: f64, option_type: OptionType, volatility: f64| -> Result<OptionInstrument, TsError> {
+        Ok(OptionInstrument::new(
+            calculate_black_scholes(100.0, strike, years_until_expiry, rate, 0.0, volatility, option_type)?,
+            expiry_seconds,
+            strike,
+            format!("{strike}-{option_type:?}").into(),
+            option_type,
+            100.0,
+            clock,
+        ))
+    };
+
+    // The straddle at 100 is 3 vol points above the curve the rest of the smile is on.
+    let straddle_volatility = (svi_variance(&curve, (100.0 / forward_price).ln())? / years_until_expiry).sqrt() + 0.03;
+    let mut options = vec![
+        option(100.0, OptionType::Call, straddle_volatility)?,
+        option(100.0, OptionType::Put, straddle_volatility)?,
+        // Makes a straddle with the call at 105, but further from the forward than the one at 100.
+        option(105.0, OptionType::Put, 0.5)?,
+    ];
+    for strike in (14..=28).map(|x| x as f64 * 5.0).filter(|x| *x != 100.0) {
+        let volatility = (svi_variance(&curve, (strike / forward_price).ln())? / years_until_expiry).sqrt();
+        options.push(option(strike, OptionType::Call, volatility)?);
+    }
+
+    let straddle = find_atm_straddle(&options)?.unwrap();
+    assert_eq!(straddle.strike, 100.0);
+    assert!((straddle.implied_volatility - straddle_volatility).abs() < 0.0002);
+    assert!(find_atm_straddle(&options[3..])?.is_none());
+
+    let mut graph = SmileGraph::new();
+    for option in options
+        .into_iter()
+        .filter(|x| x.strike != 105.0 || x.option_type == OptionType::Call)
+    {
+        graph.try_insert_option(option)?;
+    }
+
+    let mut unanchored = graph.with_options(graph.options.clone())?;
+    unanchored.fit_smile(&ProgressBar::new("", 1, true))?;
+
+    let mut anchored = graph.with_options(graph.options.clone())?;
+    anchored.set_atm_anchor(&straddle, 0.002)?;
+    anchored.fit_smile(&ProgressBar::new("", 1, true))?;
+
+    // Least squares only goes part of the way to one noisy quote, while the anchor has to be met.
+    let miss =
+        |graph: &SmileGraph| Ok::<f64, TsError>((graph.get_implied_volatility_at_strike(100.0)? - straddle_volatility).abs());
+    assert!(miss(&unanchored)? > 0.005);
+    assert!(miss(&anchored)? < 0.0025);
+
+    // Refits that measure how much the fit depends on the options aren't anchored.
+    assert!(
+        anchored
+            .with_options_unconstrained(graph.options.clone())?
+            .atm_anchor
+            .is_none()
+    );
+
+    assert!(graph.set_atm_anchor(&straddle, -0.01).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_apply_put_call_parity() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let expiry_seconds = (clock.now().timestamp() + 30 * 24 * 60 * 60) as u64;
+    let option = |strike: f64, option_type: OptionType, volatility: f64| -> Result<OptionInstrument, TsError> {
+        let mut option = OptionInstrument::new(
+            1.0,
+            expiry_seconds,
+            strike,
+            format!("{strike}-{option_type:?}").into(),
+            option_type,
+            100.0,
+            clock,
+        );
+        option.set_forward_price(100.0);
+        option.set_price(option.get_price_at_implied_volatility(volatility)?);
+        Ok(option)
+    };
+    let options = || -> Result<Vec<OptionInstrument>, TsError> {
+        Ok(vec![
+            option(90.0, OptionType::Call, 0.62)?,
+            option(90.0, OptionType::Put, 0.58)?,
+            option(110.0, OptionType::Call, 0.55)?,
+            option(110.0, OptionType::Put, 0.555)?,
+            option(120.0, OptionType::Call, 0.57)?,
+        ])
+    };
+    let settings = |pairing: PutCallPairing| PutCallParitySettings {
+        tolerance: 0.02,
+        pairing,
+    };
+
+    // Only the pair at 90 is further apart than the tolerance, and both options of each pair are kept.
+    let mut both = options()?;
+    let check = apply_put_call_parity(&mut both, &settings(PutCallPairing::Both))?;
+    assert_eq!(check.pairs, 2);
+    assert_eq!(check.violations.len(), 1);
+    assert_eq!(check.violations[0].strike, 90.0);
+    assert_eq!(both.len(), 5);
+
+    // The put is out of the money below the forward price, and the call above it.
+    let mut out_of_the_money = options()?;
+    apply_put_call_parity(&mut out_of_the_money, &settings(PutCallPairing::OutOfTheMoney))?;
+    let kept: Vec<(f64, OptionType)> = out_of_the_money
+        .iter()
+        .map(|x| (x.strike, x.option_type))
+        .collect();
+    assert_eq!(kept, vec![(90.0, OptionType::Put), (110.0, OptionType::Call), (120.0, OptionType::Call)]);
+
+    // The out of the money option is kept at the mean of the pair's implied volatilities.
+    let mut merged = options()?;
+    let check = apply_put_call_parity(&mut merged, &settings(PutCallPairing::Merge))?;
+    assert_eq!(check.merged_pairs, 2);
+    assert_eq!(merged.len(), 3);
+    assert!((merged[0].get_implied_volatility()? - 0.6).abs() < 1e-6);
+    assert!((merged[1].get_implied_volatility()? - 0.5525).abs() < 1e-6);
+    assert!((merged[2].get_implied_volatility()? - 0.57).abs() < 1e-6);
+
+    Ok(())
+}
+
+#[test]
+fn test_is_out_of_the_money() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let expiry_seconds = (clock.now().timestamp() + 30 * 24 * 60 * 60) as u64;
+    let option = |strike: f64, option_type: OptionType| {
+        let mut option = OptionInstrument::new(1.0, expiry_seconds, strike, "test".into(), option_type, 100.0, clock);
+        option.set_forward_price(105.0);
+        option
+    };
+
+    // Measured against the forward price rather than the spot price, with a call at the forward out of the money.
+    assert!(option(103.0, OptionType::Put).is_out_of_the_money()?);
+    assert!(!option(103.0, OptionType::Call).is_out_of_the_money()?);
+    assert!(option(105.0, OptionType::Call).is_out_of_the_money()?);
+    assert!(!option(105.0, OptionType::Put).is_out_of_the_money()?);
+
+    Ok(())
+}
+
+#[test]
+fn test_calculate_term_structure() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let now_seconds = clock.now().timestamp() as u64;
+
+    // A flat smile at the given at the money volatility.
+    let smile = |days: u64, volatility: f64| -> Result<SmileGraph, TsError> {
+        let mut graph = SmileGraph::new();
+        graph.options.push(OptionInstrument::new(
+            1.0,
+            now_seconds + days * 24 * 60 * 60,
+            100.0,
+            "test".into(),
+            OptionType::Call,
+            100.0,
+            clock,
+        ));
+        let years_until_expiry = graph.get_years_until_expiry()?;
+        graph.svi_curve_parameters =
+            types::SVICurveParameters::new_from_values(volatility.powi(2) * years_until_expiry, 0.000000001, 0.0, 0.0, 0.1)?;
+        Ok(graph)
+    };
+
+    // Given out of order, and in backwardation then contango.
+    let term_structure = TermStructure::calculate(&[smile(90, 0.5)?, smile(30, 0.6)?, smile(60, 0.45)?])?;
+    let days = term_structure
+        .points
+        .iter()
+        .map(|x| (x.years_until_expiry * 365.0).round())
+        .collect::<Vec<f64>>();
+    assert_eq!(days, vec![30.0, 60.0, 90.0]);
+    assert!((term_structure.points[0].atm_implied_volatility - 0.6).abs() < 0.000001);
+    assert_eq!(term_structure.forward_variances.len(), 2);
+
+    // Total variance (in volatility squared days) goes from 10.8 to 12.15 to 22.5, 30 days apart.
+    assert!((term_structure.forward_variances[0].variance - 0.045).abs() < 0.000001);
+    assert!((term_structure.forward_variances[1].variance - 0.345).abs() < 0.000001);
+    assert!(
+        (term_structure.forward_variances[1]
+            .get_volatility()
+            .expect("Should have a forward volatility")
+            - 0.345f64.sqrt())
+        .abs()
+            < 0.000001
+    );
+
+    // Falling total variance is calendar arbitrage, so has no forward volatility.
+    let term_structure = TermStructure::calculate(&[smile(30, 0.6)?, smile(60, 0.3)?])?;
+    assert!(term_structure.forward_variances[0].variance < 0.0);
+    assert!(
+        term_structure.forward_variances[0]
+            .get_volatility()
+            .is_none()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_calculate_asset_comparison() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let now_seconds = clock.now().timestamp() as u64;
+
+    // A smile at the given at the money volatility, skewed towards puts when rho is negative.
+    let smile = |days: u64, volatility: f64, b: f64, rho: f64| -> Result<SmileGraph, TsError> {
+        let mut graph = SmileGraph::new();
+        graph.options.push(OptionInstrument::new(
+            1.0,
+            now_seconds + days * 24 * 60 * 60,
+            100.0,
+            "test".into(),
+            OptionType::Call,
+            100.0,
+            clock,
+        ));
+        graph.lowest_observed_strike = 10.0;
+        graph.highest_observed_strike = 1000.0;
+        let years_until_expiry = graph.get_years_until_expiry()?;
+        graph.svi_curve_parameters =
+            types::SVICurveParameters::new_from_values(volatility.powi(2) * years_until_expiry - b * 0.1, b, rho, 0.0, 0.1)?;
+        Ok(graph)
+    };
+
+    let comparison = AssetComparison::calculate(&[
+        ("BTC".to_string(), vec![smile(7, 0.5, 0.000000001, 0.0)?, smile(100, 0.5, 0.000000001, 0.0)?]),
+        ("ETH".to_string(), vec![smile(7, 0.6, 0.05, -0.5)?, smile(60, 0.6, 0.05, -0.5)?]),
+    ])?;
+
+    // Only the tenors both surfaces cover are compared.
+    let tenor_days = comparison
+        .tenors
+        .iter()
+        .map(|x| x.tenor_days)
+        .collect::<Vec<i64>>();
+    assert_eq!(tenor_days, vec![7, 14, 30, 60]);
+    assert_eq!(comparison.base_asset, "BTC");
+
+    for tenor in &comparison.tenors {
+        let (btc, eth) = (&tenor.assets[0], &tenor.assets[1]);
+
+        assert!((btc.atm_volatility_ratio - 1.0).abs() < 0.000001);
+        assert!(btc.risk_reversal_difference.abs() < 0.000001);
+        assert!((btc.atm_implied_volatility - 0.5).abs() < 0.001);
+        assert!((eth.atm_volatility_ratio - 1.2).abs() < 0.001);
+        // Ether's puts are more expensive than Bitcoin's flat smile.
+        assert!(eth.risk_reversal_25_delta < 0.0);
+        assert!((eth.risk_reversal_difference - (eth.risk_reversal_25_delta - btc.risk_reversal_25_delta)).abs() < 0.000001);
+    }
+
+    assert!(AssetComparison::calculate(&[]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_scan_quote_arbitrage() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let now_seconds = clock.now().timestamp() as u64;
+
+    // Quotes given as (strike, call bid, call ask, put bid, put ask) for an expiry with the given forward price.
+    let quote_expiry = |days: u64, forward_price: f64, quotes: &[(f64, f64, f64, f64, f64)]| -> Vec<OptionInstrument> {
+        let mut options = Vec::new();
+
+        for (strike, call_bid, call_ask, put_bid, put_ask) in quotes {
+            for (option_type, name, bid, ask) in [
+                (OptionType::Call, "call", call_bid, call_ask),
+                (OptionType::Put, "put", put_bid, put_ask),
+            ] {
+                let mut option = OptionInstrument::new(
+                    (bid + ask) / 2.0,
+                    now_seconds + days * 24 * 60 * 60,
+                    *strike,
+                    format!("{days}-{strike}-{name}").into(),
+                    option_type,
+                    100.0,
+                    clock,
+                );
+                option.bid_price = Some(*bid);
+                option.ask_price = Some(*ask);
+                option.set_forward_price(forward_price);
+                options.push(option);
+            }
+        }
+
+        options
+    };
+    let far = quote_expiry(
+        60,
+        100.5,
+        &[
+            (90.0, 13.0, 14.0, 2.0, 2.5),
+            (100.0, 6.0, 7.0, 5.0, 6.0),
+            (110.0, 2.5, 3.0, 11.5, 12.5),
+        ],
+    );
+    let clean_near = quote_expiry(
+        30,
+        100.0,
+        &[
+            (90.0, 11.0, 12.0, 0.8, 1.2),
+            (100.0, 4.0, 5.0, 3.5, 4.5),
+            (110.0, 1.0, 1.5, 10.0, 11.0),
+        ],
+    );
+
+    let options = [clean_near, far.clone()].concat();
+    assert!(scan_quote_arbitrage(&options, 0.06)?.is_empty());
+
+    // The 110 call bid above the 100 call's ask, which it can never be worth more than.
+    let near = quote_expiry(
+        30,
+        100.0,
+        &[
+            (90.0, 11.0, 12.0, 0.8, 1.2),
+            (100.0, 4.0, 5.0, 3.5, 4.5),
+            (110.0, 5.5, 6.0, 10.0, 11.0),
+        ],
+    );
+    let options = [near, far].concat();
+    let arbitrages = scan_quote_arbitrage(&options, 0.06)?;
+    let types = arbitrages
+        .iter()
+        .map(|x| x.arbitrage_type)
+        .collect::<Vec<QuoteArbitrageType>>();
+    assert_eq!(
+        types,
+        vec![
+            QuoteArbitrageType::Box,
+            QuoteArbitrageType::Calendar,
+            QuoteArbitrageType::VerticalSpread
+        ]
+    );
+
+    // Buying the 100/110 box costs 5 - 5.5 - 3.5 + 11 = 7, but it pays 10 in 30 days.
+    let discount_factor = (-0.06 * 30.0 * 24.0 * 60.0 * 60.0 / 31556926.0_f64).exp();
+    assert!((arbitrages[0].edge - (10.0 * discount_factor - 7.0)).abs() < 0.000001);
+    assert!((arbitrages[1].edge - 2.5).abs() < 0.000001);
+    assert!((arbitrages[2].edge - 0.5).abs() < 0.000001);
+    assert_eq!(arbitrages[2].legs[0].instrument_id.as_ref(), "30-100-call");
+    assert_eq!(arbitrages[2].legs[0].quantity, 1.0);
+    assert_eq!(arbitrages[2].legs[1].instrument_id.as_ref(), "30-110-call");
+    assert_eq!(arbitrages[2].legs[1].quantity, -1.0);
+
+    Ok(())
+}
+
+#[test]
+fn test_expiry_category_filter() -> Result<(), TsError> {
+    assert_eq!(
+        ExpiryCategory::list_from_str("Weekly,monthly")?,
+        vec![ExpiryCategory::Weekly, ExpiryCategory::Monthly]
+    );
+    assert!(ExpiryCategory::list_from_str("weekly,yearly").is_err());
+
+    let args = [
+        "build-surface",
+        "--expiry-categories",
+        "weekly,quarterly",
+        "--exclude-expiry-categories",
+        "daily",
+    ]
+    .map(String::from);
+    let filter = ExpiryCategoryFilter::from_args(&args)?;
+    assert!(
+        filter
+            .get_exclusion_reason(Some(ExpiryCategory::Weekly))
+            .is_none()
+    );
+    assert!(
+        filter
+            .get_exclusion_reason(Some(ExpiryCategory::Monthly))
+            .is_some()
+    );
+    assert!(
+        filter
+            .get_exclusion_reason(Some(ExpiryCategory::Daily))
+            .is_some()
+    );
+    // Smiles saved before expiries were categorised can't be in an included category.
+    assert!(filter.get_exclusion_reason(None).is_some());
+
+    let filter = ExpiryCategoryFilter {
+        include: Vec::new(),
+        exclude: vec![ExpiryCategory::Daily],
+    };
+    assert!(
+        filter
+            .get_exclusion_reason(Some(ExpiryCategory::Daily))
+            .is_some()
+    );
+    assert!(
+        filter
+            .get_exclusion_reason(Some(ExpiryCategory::Quarterly))
+            .is_none()
+    );
+    assert!(filter.get_exclusion_reason(None).is_none());
+    assert!(ExpiryCategoryFilter::default().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_smile_quote_hash() {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let smile = |price: f64| {
+        let mut graph = SmileGraph::new();
+        graph
+            .options
+            .push(OptionInstrument::new(price, 1_769_904_000, 100.0, "test".into(), OptionType::Call, 100.0, clock));
+        graph
+    };
+
+    assert_eq!(smile(5.0).get_quote_hash(), smile(5.0).get_quote_hash());
+    assert_ne!(smile(5.0).get_quote_hash(), smile(5.5).get_quote_hash());
+
+    // Only the quotes count, not what times until expiry are measured from.
+    let mut graph = smile(5.0);
+    graph.set_clock(Clock::Real);
+    assert_eq!(graph.get_quote_hash(), smile(5.0).get_quote_hash());
+}
+
+#[test]
+fn test_reuse_smile_fit() -> Result<(), TsError> {
+    let smile = |now_seconds: i64| -> Result<SmileGraph, TsError> {
+        let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(now_seconds).unwrap());
+        let mut graph = SmileGraph::new();
+        graph
+            .options
+            .push(OptionInstrument::new(5.0, 1_769_904_000, 100.0, "test".into(), OptionType::Call, 100.0, clock));
+        graph.svi_curve_parameters = types::SVICurveParameters::new_from_values(0.02, 0.05, -0.3, 0.0, 0.1)?;
+        Ok(graph)
+    };
+
+    // Reused a week later, the smile should have the same implied volatilities with less time left.
+    let saved = smile(1_767_225_600)?;
+    let mut graph = smile(1_767_225_600 + 7 * 24 * 60 * 60)?;
+    graph.reuse_fit(&saved)?;
+
+    assert!(graph.has_been_fit);
+    for log_moneyness in [-0.5, 0.0, 0.5] {
+        let saved_volatility =
+            (saved.get_total_variance_at_log_moneyness(log_moneyness)? / saved.get_years_until_expiry()?).sqrt();
+        let volatility = (graph.get_total_variance_at_log_moneyness(log_moneyness)? / graph.get_years_until_expiry()?).sqrt();
+        assert!((volatility - saved_volatility).abs() < 0.000001);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_quote_table_round_trip() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let mut put = OptionInstrument::new(4.0, 1_769_904_000, 90.0, "put".into(), OptionType::Put, 100.0, clock);
+    put.bid_price = Some(3.9);
+    put.ask_price = Some(4.1);
+    put.open_interest = Some(12.0);
+    put.quote_time = Some(clock.now());
+    let mut call = OptionInstrument::new(5.0, 1_769_904_000, 110.0, "call".into(), OptionType::Call, 100.0, clock);
+    call.forward_price = Some(101.0);
+    call.pricing_model = PricingModel::Inverse;
+    let options = vec![put, call];
+
+    let table = QuoteTable::from_options(&options, 100.0)?;
+    assert_eq!(table.len(), 2);
+    assert!((table.log_moneyness[0] - (0.9_f64).ln()).abs() < 1e-12);
+    assert!((table.log_moneyness[1] - (1.1_f64).ln()).abs() < 1e-12);
+
+    for (option, restored) in options.iter().zip(table.to_options()) {
+        assert_eq!(restored.instrument_id, option.instrument_id);
+        assert_eq!(restored.option_type, option.option_type);
+        assert_eq!(restored.strike, option.strike);
+        assert_eq!(restored.price, option.price);
+        assert_eq!(restored.bid_price, option.bid_price);
+        assert_eq!(restored.ask_price, option.ask_price);
+        assert_eq!(restored.forward_price, option.forward_price);
+        assert_eq!(restored.pricing_model, option.pricing_model);
+        assert_eq!(restored.open_interest, option.open_interest);
+        assert_eq!(restored.quote_time, option.quote_time);
+        assert_eq!(restored.get_clock(), option.get_clock());
+        assert_eq!(restored.get_total_implied_variance()?, option.get_total_implied_variance()?);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_smile_fit_limits() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let expiry_seconds = (clock.now().timestamp() + 60 * 24 * 60 * 60) as u64;
+    let years_until_expiry = 60.0 * 24.0 * 60.0 * 60.0 / 31556926.0;
+    let rate = crate::constants::INTEREST_FREE_RATE;
+    let forward_price = 100.0 * (rate * years_until_expiry).exp();
+    let curve = types::SVICurveParameters::new_from_values(0.03, 0.08, -0.4, 0.05, 0.15)?;
+    let smile = |fit_limits: SmileFitLimits| -> Result<SmileGraph, TsError> {
+        let mut graph = SmileGraph::new();
+        graph.fit_limits = fit_limits;
+        for strike in [70.0, 80.0, 90.0, 100.0, 110.0, 120.0, 130.0] {
+            let volatility = (svi_variance(&curve, (strike / forward_price).ln())? / years_until_expiry).sqrt();
+            let price = calculate_black_scholes(100.0, strike, years_until_expiry, rate, 0.0, volatility, OptionType::Call)?;
+            graph.try_insert_option(OptionInstrument::new(
+                price,
+                expiry_seconds,
+                strike,
+                strike.to_string().into(),
+                OptionType::Call,
+                100.0,
+                clock,
+            ))?;
+        }
+        Ok(graph)
+    };
+    let progress = ProgressBar::new("test", 1, true);
+
+    // Stopped after the first pass, the curve found so far is kept.
+    let mut graph = smile(SmileFitLimits {
+        max_seconds: None,
+        max_iterations: Some(1),
+    })?;
+    assert!(graph.fit_smile_with_budget(&progress, None)?);
+    assert!(graph.has_been_fit && graph.budget_limited);
+    assert!(graph.get_fit_rmse()?.is_finite());
+
+    // A time limit that has already passed still finds a curve.
+    let mut graph = smile(SmileFitLimits {
+        max_seconds: Some(0.000001),
+        max_iterations: None,
+    })?;
+    assert!(graph.fit_smile_with_budget(&progress, None)?);
+    assert!(graph.has_been_fit && graph.budget_limited);
+
+    // Without limits the search runs to the end.
+    let mut graph = smile(SmileFitLimits::default())?;
+    assert!(!graph.fit_smile_with_budget(&progress, None)?);
+    assert!(!graph.budget_limited);
+
+    assert!(
+        SmileFitLimits {
+            max_seconds: Some(-1.0),
+            max_iterations: None
+        }
+        .check_valid()
+        .is_err()
+    );
+    assert!(
+        SmileFitLimits {
+            max_seconds: None,
+            max_iterations: Some(0)
+        }
+        .check_valid()
+        .is_err()
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "batch-search")]
+#[test]
+fn test_batch_search_fit() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let expiry_seconds = (clock.now().timestamp() + 60 * 24 * 60 * 60) as u64;
+    let years_until_expiry = 60.0 * 24.0 * 60.0 * 60.0 / 31556926.0;
+    let rate = crate::constants::INTEREST_FREE_RATE;
+    let forward_price = 100.0 * (rate * years_until_expiry).exp();
+    let curve = types::SVICurveParameters::new_from_values(0.03, 0.08, -0.4, 0.05, 0.15)?;
+    let smile = |optimiser: SviOptimiser| -> Result<SmileGraph, TsError> {
+        let mut graph = SmileGraph::new();
+        graph.optimiser = optimiser;
+        for strike in [70.0, 80.0, 90.0, 100.0, 110.0, 120.0, 130.0] {
+            let volatility = (svi_variance(&curve, (strike / forward_price).ln())? / years_until_expiry).sqrt();
+            let price = calculate_black_scholes(100.0, strike, years_until_expiry, rate, 0.0, volatility, OptionType::Call)?;
+            graph.try_insert_option(OptionInstrument::new(
+                price,
+                expiry_seconds,
+                strike,
+                strike.to_string().into(),
+                OptionType::Call,
+                100.0,
+                clock,
+            ))?;
+        }
+        Ok(graph)
+    };
+    let progress = ProgressBar::new("test", 1, true);
+
+    let mut multi_start = smile(SviOptimiser::MultiStart)?;
+    multi_start.fit_smile(&progress)?;
+    let mut batch_search = smile(SviOptimiser::BatchSearch)?;
+    assert!(!batch_search.fit_smile_with_budget(&progress, None)?);
+
+    // Polishing only the cheapest starting curves should fit the smile about as well as the full search.
+    assert!(batch_search.has_been_fit);
+    assert!(batch_search.get_fit_rmse()? < multi_start.get_fit_rmse()? + 0.001);
+
+    Ok(())
+}
+
+#[test]
+fn test_into_smile_graphs_as_of() -> Result<(), TsError> {
+    let built = chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap();
+    let later = chrono::DateTime::from_timestamp_secs(1_767_312_000).unwrap();
+    let loaded = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_139_200).unwrap());
+    let make_data = |build_info: Option<SurfaceBuildInfo>| -> Result<SmileGraphsDataContainer, TsError> {
+        let mut graph = SmileGraph::new();
+        graph.try_insert_option(OptionInstrument::new(
+            5.0,
+            (built.timestamp() + 30 * 24 * 60 * 60) as u64,
+            100.0,
+            "100".into(),
+            OptionType::Call,
+            100.0,
+            loaded,
+        ))?;
+
+        Ok(SmileGraphsDataContainer {
+            schema_version: 1,
+            smile_graphs: vec![graph],
+            surface_score: None,
+            synthetic_smiles: Vec::new(),
+            build_info,
+        })
+    };
+    let build_info = SurfaceBuildInfo::new(
+        built,
+        true,
+        SurfaceModel::default(),
+        SviOptimiser::default(),
+        FitWeighting::default(),
+        ButterflyCheckSettings::default(),
+    );
+
+    // Times are measured from when the surface was built unless another time is given.
+    let graphs = make_data(Some(build_info.clone()))?.into_smile_graphs_as_of(None)?;
+    assert_eq!(graphs[0].get_clock()?, Clock::Fixed(built));
+    let graphs = make_data(Some(build_info))?.into_smile_graphs_as_of(Some(later))?;
+    assert_eq!(graphs[0].get_clock()?, Clock::Fixed(later));
+
+    // Older surfaces don't say when they were built.
+    assert!(make_data(None)?.into_smile_graphs_as_of(None).is_err());
+    assert_eq!(make_data(None)?.into_smile_graphs_as_of(Some(later))?[0].get_clock()?, Clock::Fixed(later));
+
+    Ok(())
+}
+
+proptest::proptest! {
+    #[test]
+    fn test_black_scholes_price_is_monotone_in_volatility(
+        spot in 1.0..10000.0_f64,
+        log_moneyness in -1.0..1.0_f64,
+        years_until_expiry in 0.01..3.0_f64,
+        rate in -0.02..0.1_f64,
+        dividend_yield in 0.0..0.05_f64,
+        volatility in 0.05..3.0_f64,
+        increase in 0.001..1.0_f64,
+        is_call: bool,
+    ) {
+        let strike = spot * log_moneyness.exp();
+        let option_type = if is_call { OptionType::Call } else { OptionType::Put };
+        let price = |v| calculate_black_scholes(spot, strike, years_until_expiry, rate, dividend_yield, v, option_type).expect("Should be priceable");
+
+        proptest::prop_assert!(price(volatility + increase) >= price(volatility));
+    }
+
+    #[test]
+    fn test_implied_volatility_round_trip(
+        spot in 1.0..10000.0_f64,
+        log_moneyness in -1.0..1.0_f64,
+        years_until_expiry in 0.01..3.0_f64,
+        rate in -0.02..0.1_f64,
+        dividend_yield in 0.0..0.05_f64,
+        volatility in 0.05..3.0_f64,
+        is_call: bool,
+    ) {
+        let strike = spot * log_moneyness.exp();
+        let option_type = if is_call { OptionType::Call } else { OptionType::Put };
+        let price = calculate_black_scholes(spot, strike, years_until_expiry, rate, dividend_yield, volatility, option_type)
+            .expect("Should be priceable");
+        let vega = calculate_black_scholes_vega(spot, strike, years_until_expiry, rate, dividend_yield, volatility)
+            .expect("Should be priceable");
+
+        // Far enough from the money, the price barely changes with volatility, so it doesn't pin the volatility down.
+        proptest::prop_assume!(vega > spot * 0.0001);
+
+        let implied_volatility =
+            calculate_bs_implied_volatility(spot, strike, years_until_expiry, rate, dividend_yield, price, option_type)
+                .expect("Should be solveable");
+
+        proptest::prop_assert!((implied_volatility - volatility).abs() < 0.001, "{implied_volatility} != {volatility}");
+    }
+
+    #[test]
+    fn test_put_call_parity(
+        spot in 1.0..10000.0_f64,
+        log_moneyness in -1.0..1.0_f64,
+        years_until_expiry in 0.01..3.0_f64,
+        rate in -0.02..0.1_f64,
+        dividend_yield in 0.0..0.05_f64,
+        volatility in 0.05..3.0_f64,
+    ) {
+        let strike = spot * log_moneyness.exp();
+        let call = calculate_black_scholes(spot, strike, years_until_expiry, rate, dividend_yield, volatility, OptionType::Call)
+            .expect("Should be priceable");
+        let put = calculate_black_scholes(spot, strike, years_until_expiry, rate, dividend_yield, volatility, OptionType::Put)
+            .expect("Should be priceable");
+        let forward_value = spot * (-dividend_yield * years_until_expiry).exp() - strike * (-rate * years_until_expiry).exp();
+
+        proptest::prop_assert!((call - put - forward_value).abs() < spot * 1e-9, "{call} - {put} != {forward_value}");
+    }
+
+    #[test]
+    fn test_svi_variance_is_positive(
+        lowest_variance in 0.0001..1.0_f64,
+        steepest_slope in 0.001..1.999_f64,
+        p in -0.999..0.999_f64,
+        m in -1.0..1.0_f64,
+        o in 0.001..1.0_f64,
+        log_moneyness in -5.0..5.0_f64,
+    ) {
+        // Every valid curve: b keeps both wings below Lee's bound, and a puts the lowest variance above zero.
+        let b = steepest_slope / (1.0 + p.abs());
+        let a = lowest_variance - b * o * (1.0 - p * p).sqrt();
+        let curve = types::SVICurveParameters::new_from_values(a, b, p, m, o).expect("Curve should be valid");
+
+        proptest::prop_assert!(svi_variance(&curve, log_moneyness).expect("Variance should be positive") > 0.0);
+    }
+}
+
+#[test]
+fn test_surface_grid() -> Result<(), TsError> {
+    let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+    let now_seconds = clock.now().timestamp() as u64;
+    let smile = |days: u64, a: f64| -> Result<SmileGraph, TsError> {
+        let mut graph = SmileGraph::new();
+        for strike in [90.0, 100.0, 110.0] {
+            graph.options.push(OptionInstrument::new(
+                1.0,
+                now_seconds + days * 24 * 60 * 60,
+                strike,
+                strike.to_string().into(),
+                OptionType::Call,
+                100.0,
+                clock,
+            ));
+        }
+        graph.lowest_observed_strike = 90.0;
+        graph.highest_observed_strike = 110.0;
+        graph.svi_curve_parameters = types::SVICurveParameters::new_from_values(a, 0.1, -0.3, 0.0, 0.1)?;
+        Ok(graph)
+    };
+    let graphs = vec![smile(30, 0.01)?, smile(90, 0.03)?];
+    let grid = SurfaceGrid::new(&graphs, None)?;
+
+    assert_eq!(grid.rows.len(), 2);
+    assert!(
+        grid.rows
+            .iter()
+            .all(|x| x.total_variance.len() == grid.log_moneyness.len())
+    );
+    assert!(
+        (grid.rows[1].total_variance[100] - graphs[1].get_total_variance_at_log_moneyness(grid.log_moneyness[100])?).abs()
+            < 0.000001
+    );
+
+    // Between the columns the grid reads the surface almost exactly as the curves do, and beyond them it reads the
+    // curves.
+    let years_until_expiry = graphs[0].get_years_until_expiry()? * 2.0;
+    let expected = interpolate_surface(&graphs, 95.0, years_until_expiry)?;
+    let slices = grid.get_slices();
+
+    for strike in [95.0, 101.3, 300.0] {
+        let from_grid = interpolate_surface(&slices, strike, years_until_expiry)?;
+        let from_curves = interpolate_surface(&graphs, strike, years_until_expiry)?;
+
+        assert!((from_grid.total_variance - from_curves.total_variance).abs() < 0.0001);
+        assert_eq!(from_grid.forward_price, from_curves.forward_price);
+    }
+
+    // The smiles are read the same without their quotes.
+    let grid_graphs = grid.into_smile_graphs();
+    let point = interpolate_surface(&grid_graphs, 95.0, years_until_expiry)?;
+
+    assert!(grid_graphs.iter().all(|x| x.options.len() == 1));
+    assert!((point.total_variance - expected.total_variance).abs() < 0.000001);
+    assert!((point.forward_price - expected.forward_price).abs() < 0.000001);
+
+    Ok(())
+}
@@ -1,7 +1,11 @@
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
 use crate::{
-    analytics::SmileGraph,
+    analytics::{SmileGraph, SurfaceScore, SviFitSettings, SyntheticSmile},
     constants,
-    helpers::error_unless_valid_f64,
+    helpers::{self, Clock, error_unless_valid_f64},
     types::{TsError, TsErrorType::RuntimeError, TsErrorType::UnsolvableError},
 };
 
@@ -122,7 +126,7 @@ impl SVICurveParameters {
     }
 }
 
-#[derive(PartialEq, Copy, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone, serde::Serialize, serde::Deserialize)]
 pub enum OptionType {
     Call = 1,
     Put = 2,
@@ -140,8 +144,504 @@ impl TryFrom<&str> for OptionType {
     }
 }
 
+/// How an option's price is turned into an implied volatility.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PricingModel {
+    /// Black-Scholes on the spot price implied by the forward price, with the premium in USD discounted at the interest
+    /// free rate.
+    #[default]
+    BlackScholes,
+    /// An inverse option, quoted and settled in the underlying coin (as Deribit's BTC options are): Black-76 on the forward
+    /// price, with the premium as a fraction of the forward price. A coin premium holds its value against the forward, so
+    /// it isn't discounted.
+    Inverse,
+}
+
+/// How much each option counts towards a smile's fit.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FitWeighting {
+    /// Every option counts the same.
+    #[default]
+    Equal,
+    /// Options count in proportion to their open interest, plus the smile's average open interest so that options with
+    /// none still count for something.
+    OpenInterest,
+    /// Like OpenInterest, but by the last 24 hours' volume.
+    Volume,
+}
+
+/// What is done with a call and a put at the same strike and expiry, which should have the same implied volatility.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PutCallPairing {
+    /// Fit to both.
+    #[default]
+    Both,
+    /// Fit only to the one that is out of the money: the put if the strike is below the forward price, otherwise the
+    /// call. Out of the money options trade more, so their prices are usually better.
+    OutOfTheMoney,
+    /// Fit to a single point at the mean of their implied volatilities, in place of the out of the money one.
+    Merge,
+}
+
+/// How calls and puts at the same strike are checked against each other and fit to.
+#[derive(Debug, PartialEq, Copy, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PutCallParitySettings {
+    /// How far apart (in volatility, e.g. 0.02 for 2 points) a pair's implied volatilities can be before it's reported.
+    pub tolerance: f64,
+    pub pairing: PutCallPairing,
+}
+
+impl Default for PutCallParitySettings {
+    fn default() -> Self {
+        Self {
+            tolerance: 0.02,
+            pairing: PutCallPairing::Both,
+        }
+    }
+}
+
+impl PutCallParitySettings {
+    pub fn check_valid(&self) -> Result<(), TsError> {
+        error_unless_valid_f64(self.tolerance, "tolerance")?;
+
+        if self.tolerance < 0.0 {
+            return Err(TsError::new(RuntimeError, "The put-call parity tolerance cannot be less than zero"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Where an option's implied volatility comes from.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImpliedVolatilitySource {
+    /// Solved from the option's price using its pricing model.
+    #[default]
+    Solve,
+    /// The exchange's mark implied volatility, used as is.
+    Exchange,
+}
+
+/// How the at the money term structure is smoothed after the smiles are fit.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TermStructureSmoothing {
+    /// Leave each smile as it was fit.
+    #[default]
+    None,
+    /// Move each smile towards a Nelson-Siegel curve fit to the at the money volatilities, within the bid and ask.
+    NelsonSiegel,
+}
+
+/// How the best SVI curve is searched for when fitting a smile.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SviOptimiser {
+    /// Run Levenberg-Marquardt from a Latin hypercube sample of starting curves, narrowing the sampled ranges around the
+    /// best curve each pass. Called grid by older versions, which started from every point of a grid instead.
+    #[default]
+    #[serde(alias = "grid")]
+    MultiStart,
+    /// Search the whole space with differential evolution, then polish the best curve with Levenberg-Marquardt.
+    DifferentialEvolution,
+    /// Score a large Latin hypercube sample of starting curves in parallel batches, finding only each curve's best a, then
+    /// run Levenberg-Marquardt from just the cheapest few. Needs the batch-search feature.
+    BatchSearch,
+}
+
+impl SviOptimiser {
+    pub fn check_valid(&self) -> Result<(), TsError> {
+        if *self == SviOptimiser::BatchSearch && !cfg!(feature = "batch-search") {
+            return Err(TsError::new(
+                RuntimeError,
+                "svi_optimiser batch_search needs the batch-search feature (cargo build --release --features batch-search)",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// How the smiles are fit.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SurfaceModel {
+    /// Fit each smile on its own with raw SVI.
+    #[default]
+    Svi,
+    /// Fit every smile at once with eSSVI, which rules out butterfly and calendar arbitrage by construction but gives each
+    /// smile less freedom.
+    Essvi,
+}
+
+/// How often expiries like this one are listed, which says a lot about how they trade. Dailies have so little time value
+/// left that they need different filtering, while quarterlies are the most liquid.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpiryCategory {
+    Daily,
+    Weekly,
+    /// A monthly expiry that isn't also a quarterly one.
+    Monthly,
+    /// The monthly expiries of March, June, September and December.
+    Quarterly,
+}
+
+impl ExpiryCategory {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExpiryCategory::Daily => "daily",
+            ExpiryCategory::Weekly => "weekly",
+            ExpiryCategory::Monthly => "monthly",
+            ExpiryCategory::Quarterly => "quarterly",
+        }
+    }
+
+    /// Parse a comma separated list of categories, e.g. weekly,monthly.
+    pub fn list_from_str(list: &str) -> Result<Vec<ExpiryCategory>, TsError> {
+        list.split(',').map(ExpiryCategory::try_from).collect()
+    }
+}
+
+impl TryFrom<&str> for ExpiryCategory {
+    type Error = TsError;
+
+    fn try_from(category: &str) -> Result<Self, TsError> {
+        match category.to_ascii_lowercase().as_str() {
+            "daily" => Ok(ExpiryCategory::Daily),
+            "weekly" => Ok(ExpiryCategory::Weekly),
+            "monthly" => Ok(ExpiryCategory::Monthly),
+            "quarterly" => Ok(ExpiryCategory::Quarterly),
+            _ => Err(TsError::new(
+                RuntimeError,
+                format!("Invalid expiry category {category}, expected daily, weekly, monthly or quarterly"),
+            )),
+        }
+    }
+}
+
+/// Which categories of expiry are used, set with --expiry-categories and --exclude-expiry-categories.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ExpiryCategoryFilter {
+    /// If not empty, only expiries in one of these categories are used.
+    pub include: Vec<ExpiryCategory>,
+    /// Expiries in any of these categories are not used.
+    pub exclude: Vec<ExpiryCategory>,
+}
+
+impl ExpiryCategoryFilter {
+    pub fn from_args(args: &[String]) -> Result<Self, TsError> {
+        let parse = |flag: &str| -> Result<Vec<ExpiryCategory>, TsError> {
+            match helpers::get_flag_value(args, flag)? {
+                Some(v) => ExpiryCategory::list_from_str(v).map_err(|e| e.context(format!("Invalid {flag}"))),
+                None => Ok(Vec::new()),
+            }
+        };
+
+        Ok(Self {
+            include: parse("--expiry-categories")?,
+            exclude: parse("--exclude-expiry-categories")?,
+        })
+    }
+
+    /// Returns the reason an expiry in the category should not be used, or None if it should be. An expiry whose category
+    /// isn't known (e.g. from data saved by older versions) is only left out if categories are being included.
+    pub fn get_exclusion_reason(&self, category: Option<ExpiryCategory>) -> Option<String> {
+        match category {
+            Some(category) if !self.include.is_empty() && !self.include.contains(&category) => {
+                Some(format!("expiry is {}, which isn't in --expiry-categories", category.name()))
+            }
+            Some(category) if self.exclude.contains(&category) => {
+                Some(format!("expiry is {}, which is in --exclude-expiry-categories", category.name()))
+            }
+            None if !self.include.is_empty() => Some("expiry has no category, so can't be in --expiry-categories".to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+}
+
+/// When an option can be exercised.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ExerciseStyle {
+    /// Only at expiry.
+    European,
+    /// At any time up to expiry.
+    American,
+}
+
+impl TryFrom<&str> for ExerciseStyle {
+    type Error = TsError;
+
+    fn try_from(exercise_style: &str) -> Result<Self, TsError> {
+        match exercise_style.to_ascii_lowercase().as_str() {
+            "european" => Ok(ExerciseStyle::European),
+            "american" => Ok(ExerciseStyle::American),
+            _ => Err(TsError::new(RuntimeError, format!("Invalid exercise style {exercise_style}"))),
+        }
+    }
+}
+
+/// How a smile's options are split into the folds that are each held out of the fit in turn.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum CrossValidation {
+    /// Every option is its own fold.
+    LeaveOneOut,
+    /// The options are split into this many folds. Smiles with fewer options are validated leave-one-out.
+    KFold(usize),
+}
+
+impl TryFrom<&str> for CrossValidation {
+    type Error = TsError;
+
+    fn try_from(value: &str) -> Result<Self, TsError> {
+        if value.eq_ignore_ascii_case("loo") {
+            return Ok(CrossValidation::LeaveOneOut);
+        }
+
+        match value.parse::<usize>() {
+            Ok(folds) if folds >= 2 => Ok(CrossValidation::KFold(folds)),
+            _ => Err(TsError::new(
+                RuntimeError,
+                format!("Cross-validation must be loo or a number of folds of at least 2, found '{value}'"),
+            )),
+        }
+    }
+}
+
+/// The lattice used to price options that have no closed form.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum TreeType {
+    /// The Cox-Ross-Rubinstein binomial tree, where spot moves up or down each step.
+    Binomial,
+    /// The Boyle trinomial tree, where spot moves up, down or stays the same each step. Converges faster and more
+    /// smoothly than the binomial tree for the same number of steps.
+    Trinomial,
+}
+
+impl TryFrom<&str> for TreeType {
+    type Error = TsError;
+
+    fn try_from(tree_type: &str) -> Result<Self, TsError> {
+        match tree_type.to_ascii_lowercase().as_str() {
+            "binomial" => Ok(TreeType::Binomial),
+            "trinomial" => Ok(TreeType::Trinomial),
+            _ => Err(TsError::new(RuntimeError, format!("Invalid tree type {tree_type}"))),
+        }
+    }
+}
+
+/// How an option is priced with a tree.
+#[derive(Debug, Copy, Clone)]
+pub struct TreeSettings {
+    pub exercise_style: ExerciseStyle,
+    pub tree_type: TreeType,
+    /// The number of time steps between now and expiry. More steps are more accurate but slower.
+    pub steps: u64,
+    /// The continuously-compounded yield the underlying asset pays, e.g. dividends. Annualised. 0 for none.
+    pub dividend_yield: f64,
+}
+
+/// The price of an option and its sensitivities.
+#[derive(Debug, Copy, Clone)]
+pub struct OptionGreeks {
+    pub price: f64,
+    /// The change in price for a change in spot.
+    pub delta: f64,
+    /// The change in delta for a change in spot.
+    pub gamma: f64,
+    /// The change in price for a change in volatility (per 1.00 of volatility, not per percentage point).
+    pub vega: f64,
+    /// The change in price as a year passes (so usually negative).
+    pub theta: f64,
+}
+
+/// How a smile's implied volatility is worked out at strikes beyond the ones we observed. Far from the data, the SVI wings
+/// can rise to absurd implied volatilities.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WingExtrapolation {
+    /// Use the fitted SVI curve everywhere.
+    #[default]
+    Svi,
+    /// Use the fitted SVI curve, but don't let total variance rise faster than Roger Lee's moment bound (a slope of 2 in
+    /// log moneyness) beyond the observed strikes.
+    LeeBounds,
+    /// Keep total variance (and so implied volatility) flat beyond the observed strikes.
+    Flat,
+    /// Continue total variance in a straight line from the edge of the observed strikes, with the slope limited to Lee's
+    /// moment bound.
+    Linear,
+}
+
+/// Where and how densely smiles are checked for butterfly arbitrage. Points are spaced evenly in log moneyness across the
+/// observed strikes, and further and further apart in each wing beyond them, so the check is densest where the smile is
+/// actually used.
+#[derive(Debug, PartialEq, Copy, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ButterflyCheckSettings {
+    /// How many points are checked across the observed strikes.
+    pub observed_points: u64,
+    /// How far beyond the observed strikes each wing is checked, in log moneyness.
+    pub wing_log_moneyness: f64,
+    /// How many points are checked in each wing.
+    pub wing_points: u64,
+}
+
+impl Default for ButterflyCheckSettings {
+    fn default() -> Self {
+        Self {
+            observed_points: 100,
+            wing_log_moneyness: 0.5,
+            wing_points: 25,
+        }
+    }
+}
+
+impl ButterflyCheckSettings {
+    pub fn check_valid(&self) -> Result<(), TsError> {
+        error_unless_valid_f64(self.wing_log_moneyness, "wing_log_moneyness")?;
+
+        if self.observed_points < 2 {
+            return Err(TsError::new(RuntimeError, "At least 2 observed points must be checked for butterfly arbitrage"));
+        }
+        if self.wing_log_moneyness < 0.0 {
+            return Err(TsError::new(RuntimeError, "wing_log_moneyness cannot be less than zero"));
+        }
+        if self.wing_log_moneyness > 0.0 && self.wing_points == 0 {
+            return Err(TsError::new(RuntimeError, "At least 1 wing point must be checked if wing_log_moneyness is set"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Limits on how long any one smile is fit for with SVI, so that a smile that is hard to fit can't hold up the rest. When
+/// a limit is reached the best curve found so far is kept, and the smile is marked as budget limited.
+#[derive(Debug, PartialEq, Copy, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmileFitLimits {
+    /// The most seconds a smile is fit for. Unlimited if not set.
+    pub max_seconds: Option<f64>,
+    /// The most search passes (or generations, with differential evolution) a smile is fit for. Unlimited if not set.
+    pub max_iterations: Option<u64>,
+}
+
+impl SmileFitLimits {
+    pub fn check_valid(&self) -> Result<(), TsError> {
+        if let Some(max_seconds) = self.max_seconds
+            && (max_seconds <= 0.0 || Duration::try_from_secs_f64(max_seconds).is_err())
+        {
+            return Err(TsError::new(
+                RuntimeError,
+                format!("max_seconds must be a number of seconds above 0, found {max_seconds}"),
+            ));
+        }
+        if self.max_iterations == Some(0) {
+            return Err(TsError::new(RuntimeError, "max_iterations must be at least 1"));
+        }
+
+        Ok(())
+    }
+
+    /// When fitting that started at the given time has to stop, if there is a time limit.
+    pub fn get_deadline(&self, start: Instant) -> Option<Instant> {
+        self.max_seconds.map(|x| start + Duration::from_secs_f64(x))
+    }
+}
+
 /// Used to store the smile graph data to file.
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct SmileGraphsDataContainer {
+    pub schema_version: u32,
     pub smile_graphs: Vec<SmileGraph>,
+    /// Missing from data saved by older versions.
+    #[serde(default)]
+    pub surface_score: Option<SurfaceScore>,
+    /// Smiles interpolated at standard tenors that have no listed expiry. Missing from data saved by older versions.
+    #[serde(default)]
+    pub synthetic_smiles: Vec<SyntheticSmile>,
+    /// How the surface was built. Missing from data saved by older versions.
+    #[serde(default)]
+    pub build_info: Option<SurfaceBuildInfo>,
+}
+
+impl SmileGraphsDataContainer {
+    /// The smile graphs, with times until expiry measured from the given time, or from when the surface was built if no
+    /// time is given. Fails if neither is known, since only newer versions save when the surface was built.
+    pub fn into_smile_graphs_as_of(self, evaluation_time: Option<DateTime<Utc>>) -> Result<Vec<SmileGraph>, TsError> {
+        let evaluation_time = evaluation_time
+            .or(self.build_info.map(|x| x.evaluation_time))
+            .ok_or(TsError::new(
+                RuntimeError,
+                "The surface data doesn't say when it was built, so an evaluation time must be given",
+            ))?;
+        let mut smile_graphs = self.smile_graphs;
+
+        for graph in &mut smile_graphs {
+            graph.set_clock(Clock::Fixed(evaluation_time));
+        }
+
+        Ok(smile_graphs)
+    }
+}
+
+impl crate::types::SchemaVersioned for SmileGraphsDataContainer {
+    const SCHEMA_VERSION: u32 = 1;
+
+    fn migrate(_data: &mut serde_json::Value, from_version: u32) -> Result<(), TsError> {
+        match from_version {
+            // Every field added before schema versions has a default, so these files load as they are.
+            0 => Ok(()),
+            _ => Err(TsError::new(RuntimeError, format!("No migration from schema version {from_version}"))),
+        }
+    }
+}
+
+/// Everything needed to reproduce a built surface from the same market data.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct SurfaceBuildInfo {
+    /// The version of this program.
+    pub version: String,
+    pub levenberg_marquardt_version: String,
+    pub nalgebra_version: String,
+    /// The time that times until expiry were measured from.
+    pub evaluation_time: DateTime<Utc>,
+    /// Whether the surface was built with --deterministic, in which case nothing saved depends on when it was built.
+    pub deterministic: bool,
+    pub fit_settings: SviFitSettings,
+}
+
+impl SurfaceBuildInfo {
+    pub fn new(
+        evaluation_time: DateTime<Utc>,
+        deterministic: bool,
+        surface_model: SurfaceModel,
+        optimiser: SviOptimiser,
+        fit_weighting: FitWeighting,
+        butterfly_check: ButterflyCheckSettings,
+    ) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            levenberg_marquardt_version: env!("LEVENBERG_MARQUARDT_VERSION").to_string(),
+            nalgebra_version: env!("NALGEBRA_VERSION").to_string(),
+            evaluation_time,
+            deterministic,
+            fit_settings: SviFitSettings {
+                surface_model,
+                optimiser,
+                fit_weighting,
+                butterfly_check,
+                ..SviFitSettings::default()
+            },
+        }
+    }
 }
@@ -0,0 +1,80 @@
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+
+#[cfg(feature = "cli")]
+use crate::{
+    fileio::{self, CsvFormat},
+    helpers,
+    types::TsError,
+};
+
+/// A scheduled event that can move the market (e.g. an economic announcement), supplied by the user so that graphs and
+/// reports can show which expiries price it in.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct VolEvent {
+    pub date: NaiveDate,
+    pub label: String,
+}
+
+/// The last event before an expiry, which usually explains most of an odd-looking short-dated volatility.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct EventProximity {
+    pub date: NaiveDate,
+    pub label: String,
+    /// From the start of the event's day to the expiry.
+    pub days_before_expiry: f64,
+}
+
+impl VolEvent {
+    /// Load events from a CSV file with the columns date and label.
+    #[cfg(feature = "cli")]
+    pub fn load_from_csv(path: &str, format: CsvFormat) -> Result<Vec<VolEvent>, TsError> {
+        let table = fileio::load_csv_file(path, format)?;
+        let mut events = Vec::with_capacity(table.rows_count());
+
+        for row in 0..table.rows_count() {
+            events.push(VolEvent {
+                date: table.get_date(row, "date")?,
+                label: table.get_str(row, "label")?.to_string(),
+            });
+        }
+
+        events.sort_by_key(|x| x.date);
+
+        Ok(events)
+    }
+
+    /// Load the events file passed with --events (in the format set by the CSV format flags), or no events if there isn't
+    /// one.
+    #[cfg(feature = "cli")]
+    pub fn load_from_args(args: &[String]) -> Result<Vec<VolEvent>, TsError> {
+        match helpers::get_flag_value(args, "--events")? {
+            Some(path) => Self::load_from_csv(path, CsvFormat::from_args(args)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The start of the event's day. Events don't have a time, so one on the day of an expiry counts as before it.
+    pub fn get_time(&self) -> DateTime<Utc> {
+        self.date.and_time(NaiveTime::MIN).and_utc()
+    }
+}
+
+/// The events from the day of the given time up to the expiry, which the expiry's options price in. The events must be
+/// ordered by date.
+pub fn get_events_before_expiry(events: &[VolEvent], now: DateTime<Utc>, expiry: DateTime<Utc>) -> Vec<&VolEvent> {
+    events
+        .iter()
+        .filter(|x| x.date >= now.date_naive() && x.get_time() <= expiry)
+        .collect()
+}
+
+/// How long before the expiry the last event it prices in is, if there is one. The events must be ordered by date.
+pub fn find_event_proximity(events: &[VolEvent], now: DateTime<Utc>, expiry: DateTime<Utc>) -> Option<EventProximity> {
+    get_events_before_expiry(events, now, expiry)
+        .last()
+        .map(|event| EventProximity {
+            date: event.date,
+            label: event.label.clone(),
+            days_before_expiry: (expiry - event.get_time()).num_seconds() as f64 / 86400.0,
+        })
+}
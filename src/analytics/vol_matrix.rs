@@ -0,0 +1,109 @@
+use std::f64::consts::E;
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    analytics::{
+        OptionInstrument, OptionType, calculate_black_scholes,
+        strike_conventions::{find_log_moneyness_at_delta, log_moneyness_to_strike},
+    },
+    constants,
+    helpers::{Clock, F64Helpers, error_unless_positive_f64},
+    types::{TsError, TsErrorType::RuntimeError},
+};
+
+/// Brokers quote at the money as the delta neutral straddle, which is where the forward delta of a call is 0.5.
+pub const AT_THE_MONEY_DELTA: f64 = 0.5;
+
+/// An implied volatility quoted at a forward delta rather than a strike, as in a broker's volatility matrix. Positive deltas
+/// are calls and negative deltas are puts, e.g. -0.25 for the 25 delta put.
+#[derive(Clone, Copy, Debug)]
+pub struct DeltaQuote {
+    pub delta: f64,
+    pub implied_volatility: f64,
+}
+
+/// Read a volatility matrix column heading as a forward delta: ATM, or a number of delta followed by C or P, e.g. 25P for
+/// the 25 delta put.
+pub fn parse_delta_label(label: &str) -> Result<f64, TsError> {
+    let label = label.trim().to_ascii_uppercase();
+
+    if label == "ATM" {
+        return Ok(AT_THE_MONEY_DELTA);
+    }
+
+    let invalid = || TsError::new(RuntimeError, format!("Invalid delta {label}, expected ATM or e.g. 25C or 10P"));
+    let (number, sign) = if let Some(number) = label.strip_suffix('C') {
+        (number, 1.0)
+    } else if let Some(number) = label.strip_suffix('P') {
+        (number, -1.0)
+    } else {
+        return Err(invalid());
+    };
+    let delta = number.parse::<f64>().map_err(|_| invalid())? / 100.0;
+
+    if !(delta > 0.0 && delta < 1.0) {
+        return Err(invalid());
+    }
+
+    Ok(sign * delta)
+}
+
+/// Turn the quotes at one tenor of a volatility matrix into synthetic options, so that they can be fit and used like a
+/// listed chain. Each quote becomes an option at the strike where it has its quoted delta (measured with its own
+/// volatility), priced so that its implied volatility is the quoted one. The name is used in the options' instrument ids.
+pub fn build_options_from_delta_quotes(
+    name: &str,
+    expiry: DateTime<Utc>,
+    forward_price: f64,
+    quotes: &[DeltaQuote],
+    clock: Clock,
+) -> Result<Vec<OptionInstrument>, TsError> {
+    error_unless_positive_f64(forward_price, "forward_price")?;
+
+    let years_until_expiry = (expiry - clock.now()).num_seconds() as f64 / 31556926.0;
+    error_unless_positive_f64(years_until_expiry, "years_until_expiry")?;
+
+    // Options are priced from the spot price that the forward price implies at the interest free rate.
+    let spot_price = forward_price * E.powf(-constants::INTEREST_FREE_RATE * years_until_expiry);
+    let mut options = Vec::with_capacity(quotes.len());
+
+    for quote in quotes {
+        error_unless_positive_f64(quote.implied_volatility, "implied_volatility")?;
+
+        let total_variance = quote.implied_volatility * quote.implied_volatility * years_until_expiry;
+        let log_moneyness = find_log_moneyness_at_delta(|_| Ok(total_variance), quote.delta)?;
+        let strike = log_moneyness_to_strike(log_moneyness, forward_price);
+        let (option_type, suffix) = match quote.delta > 0.0 {
+            true => (OptionType::Call, "C"),
+            false => (OptionType::Put, "P"),
+        };
+        let price = calculate_black_scholes(
+            spot_price,
+            strike,
+            years_until_expiry,
+            constants::INTEREST_FREE_RATE,
+            0.0,
+            quote.implied_volatility,
+            option_type,
+        )?;
+        let delta_label = match quote.delta == AT_THE_MONEY_DELTA {
+            true => "ATM".to_string(),
+            false => format!("{}{suffix}", (quote.delta.abs() * 100.0).round_to_decimal_places(2)),
+        };
+
+        let mut option = OptionInstrument::new(
+            price,
+            expiry.timestamp() as u64,
+            strike,
+            format!("{name}-{delta_label}").into(),
+            option_type,
+            spot_price,
+            clock,
+        );
+        option.set_forward_price(forward_price);
+        options.push(option);
+    }
+
+    Ok(options)
+}
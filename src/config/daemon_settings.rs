@@ -0,0 +1,112 @@
+use crate::helpers::{self, CronSchedule};
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+/// A command the daemon can run as a step.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DaemonCommand {
+    FetchMarketData,
+    BuildSurface,
+    BuildGraphs,
+}
+
+impl DaemonCommand {
+    /// The name the command is run with.
+    pub fn name(&self) -> &'static str {
+        match self {
+            DaemonCommand::FetchMarketData => "fetch-market-data",
+            DaemonCommand::BuildSurface => "build-surface",
+            DaemonCommand::BuildGraphs => "build-graphs",
+        }
+    }
+
+    /// How long the command is given to finish if its step doesn't set a timeout.
+    fn default_timeout_minutes(&self) -> f64 {
+        match self {
+            DaemonCommand::FetchMarketData => 15.0,
+            DaemonCommand::BuildSurface => 60.0,
+            DaemonCommand::BuildGraphs => 15.0,
+        }
+    }
+}
+
+/// One step of a daemon run.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DaemonStep {
+    pub command: DaemonCommand,
+    /// Flags the command is run with, e.g. ["--compress"].
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// How long the command is given before it's stopped and the run fails. Defaults to 15 minutes for fetch-market-data
+    /// and build-graphs, and 60 minutes for build-surface.
+    pub timeout_minutes: Option<f64>,
+}
+
+impl DaemonStep {
+    pub fn get_timeout_minutes(&self) -> f64 {
+        self.timeout_minutes
+            .unwrap_or(self.command.default_timeout_minutes())
+    }
+}
+
+/// How the daemon command runs the surface build on a schedule.
+#[derive(serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonSettings {
+    /// When runs start, as a cron expression in UTC (minute hour day-of-month month day-of-week). Defaults to every hour
+    /// on the hour.
+    pub schedule: String,
+    /// The commands each run does, in order. A run stops at the first step that fails or times out.
+    pub steps: Vec<DaemonStep>,
+}
+
+impl Default for DaemonSettings {
+    fn default() -> Self {
+        Self {
+            schedule: "0 * * * *".to_string(),
+            steps: [
+                DaemonCommand::FetchMarketData,
+                DaemonCommand::BuildSurface,
+                DaemonCommand::BuildGraphs,
+            ]
+            .into_iter()
+            .map(|command| DaemonStep {
+                command,
+                args: Vec::new(),
+                timeout_minutes: None,
+            })
+            .collect(),
+        }
+    }
+}
+
+impl DaemonSettings {
+    pub fn check_valid(&self) -> Result<(), TsError> {
+        if self
+            .get_schedule()?
+            .next_after(helpers::get_now())
+            .is_none()
+        {
+            return Err(TsError::new(RuntimeError, format!("The daemon schedule {} never runs", self.schedule)));
+        }
+
+        if self.steps.is_empty() {
+            return Err(TsError::new(RuntimeError, "The daemon needs at least one step"));
+        }
+
+        for step in &self.steps {
+            helpers::error_unless_positive_f64(
+                step.get_timeout_minutes(),
+                &format!("Daemon {} step timeout_minutes", step.command.name()),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get_schedule(&self) -> Result<CronSchedule, TsError> {
+        CronSchedule::parse(&self.schedule)
+    }
+}
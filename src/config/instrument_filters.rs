@@ -0,0 +1,183 @@
+use chrono::NaiveDate;
+
+use crate::helpers::F64Helpers;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+/// Rules for permanently including or excluding instruments, e.g. ones that are known to have bad data. Name patterns can
+/// use * to match any number of characters, e.g. "BTC-*-C" matches all calls.
+#[derive(serde::Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct InstrumentFilters {
+    /// If not empty, only instruments whose names match at least one of these patterns are used.
+    pub include_names: Vec<String>,
+    /// Instruments whose names match any of these patterns are not used.
+    pub exclude_names: Vec<String>,
+    /// Instruments with a strike lower than this are not used.
+    pub min_strike: Option<f64>,
+    /// Instruments with a strike higher than this are not used.
+    pub max_strike: Option<f64>,
+    /// If not empty, only instruments expiring on one of these dates (YYYY-MM-DD, UTC) are used.
+    pub include_expiries: Vec<String>,
+    /// Instruments expiring on any of these dates (YYYY-MM-DD, UTC) are not used.
+    pub exclude_expiries: Vec<String>,
+    /// Instruments whose strike is less than this multiple of the forward price are not used, e.g. 0.25. Far out of the
+    /// money strikes often have nonsense marks that drag the wings of the fit around.
+    pub min_moneyness: Option<f64>,
+    /// Instruments whose strike is more than this multiple of the forward price are not used, e.g. 4.
+    pub max_moneyness: Option<f64>,
+    /// Instruments whose log moneyness (the log of the strike over the forward price) is further than this from zero are
+    /// not used, e.g. 2.
+    pub max_abs_log_moneyness: Option<f64>,
+}
+
+impl InstrumentFilters {
+    pub fn check_valid(&self) -> Result<(), TsError> {
+        if let (Some(min), Some(max)) = (self.min_strike, self.max_strike)
+            && min > max
+        {
+            return Err(TsError::new(RuntimeError, "Instrument filter min_strike cannot be greater than max_strike"));
+        }
+
+        for (name, value) in [
+            ("min_moneyness", self.min_moneyness),
+            ("max_moneyness", self.max_moneyness),
+            ("max_abs_log_moneyness", self.max_abs_log_moneyness),
+        ] {
+            if let Some(value) = value
+                && !(value.is_finite() && value > 0.0)
+            {
+                return Err(TsError::new(RuntimeError, format!("Instrument filter {name} must be above 0, found {value}")));
+            }
+        }
+
+        if let (Some(min), Some(max)) = (self.min_moneyness, self.max_moneyness)
+            && min > max
+        {
+            return Err(TsError::new(RuntimeError, "Instrument filter min_moneyness cannot be greater than max_moneyness"));
+        }
+
+        for date in self.include_expiries.iter().chain(&self.exclude_expiries) {
+            parse_date(date)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the reason the given instrument should not be used, or None if it should be. The moneyness filters are
+    /// only applied if the forward price is known.
+    pub fn get_exclusion_reason(
+        &self,
+        instrument_name: &str,
+        strike: f64,
+        expiry: NaiveDate,
+        forward_price: Option<f64>,
+    ) -> Option<String> {
+        if !self.include_names.is_empty()
+            && !self
+                .include_names
+                .iter()
+                .any(|pattern| matches_pattern(pattern, instrument_name))
+        {
+            return Some("name doesn't match any include_names pattern".to_string());
+        }
+
+        if let Some(pattern) = self
+            .exclude_names
+            .iter()
+            .find(|pattern| matches_pattern(pattern, instrument_name))
+        {
+            return Some(format!("name matches exclude_names pattern {pattern}"));
+        }
+
+        if let Some(min_strike) = self.min_strike
+            && strike < min_strike
+        {
+            return Some(format!("strike is below min_strike {min_strike}"));
+        }
+
+        if let Some(max_strike) = self.max_strike
+            && strike > max_strike
+        {
+            return Some(format!("strike is above max_strike {max_strike}"));
+        }
+
+        if !self.include_expiries.is_empty() && !Self::contains_date(&self.include_expiries, expiry) {
+            return Some("expiry isn't in include_expiries".to_string());
+        }
+
+        if Self::contains_date(&self.exclude_expiries, expiry) {
+            return Some("expiry is in exclude_expiries".to_string());
+        }
+
+        if let Some(forward_price) = forward_price {
+            return self.get_moneyness_exclusion_reason(strike / forward_price);
+        }
+
+        None
+    }
+
+    fn get_moneyness_exclusion_reason(&self, moneyness: f64) -> Option<String> {
+        let log_moneyness = moneyness.ln();
+
+        if let Some(min_moneyness) = self.min_moneyness
+            && moneyness < min_moneyness
+        {
+            return Some(format!("moneyness {} is below min_moneyness {min_moneyness}", moneyness.round_to_decimal_places(3)));
+        }
+
+        if let Some(max_moneyness) = self.max_moneyness
+            && moneyness > max_moneyness
+        {
+            return Some(format!("moneyness {} is above max_moneyness {max_moneyness}", moneyness.round_to_decimal_places(3)));
+        }
+
+        if let Some(max_abs_log_moneyness) = self.max_abs_log_moneyness
+            && log_moneyness.abs() > max_abs_log_moneyness
+        {
+            return Some(format!(
+                "log moneyness {} is further from 0 than max_abs_log_moneyness {max_abs_log_moneyness}",
+                log_moneyness.round_to_decimal_places(3)
+            ));
+        }
+
+        None
+    }
+
+    fn contains_date(dates: &[String], date: NaiveDate) -> bool {
+        dates.iter().any(|x| parse_date(x).is_ok_and(|x| x == date))
+    }
+}
+
+fn parse_date(date: &str) -> Result<NaiveDate, TsError> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|e| {
+        TsError::new(RuntimeError, format!("Invalid date {date} in instrument filters (expected YYYY-MM-DD): {e}")).with_source(e)
+    })
+}
+
+/// Returns true if the text matches the pattern, where * in the pattern matches any number of characters.
+fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // There's always at least one part, even for an empty pattern.
+    let first = parts.next().unwrap_or_default();
+
+    let Some(mut remaining) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+
+    // No wildcards, so it has to be an exact match.
+    let Some((last, middle)) = parts.split_last() else {
+        return remaining.is_empty();
+    };
+
+    for part in middle {
+        match remaining.find(part) {
+            Some(position) => remaining = &remaining[position + part.len()..],
+            None => return false,
+        }
+    }
+
+    remaining.len() >= last.len() && remaining.ends_with(last)
+}
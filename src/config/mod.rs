@@ -0,0 +1,11 @@
+mod daemon_settings;
+mod instrument_filters;
+mod report_header;
+mod settings;
+
+pub use daemon_settings::DaemonCommand;
+pub use daemon_settings::DaemonSettings;
+pub use daemon_settings::DaemonStep;
+pub use instrument_filters::InstrumentFilters;
+pub use report_header::ReportHeader;
+pub use settings::Config;
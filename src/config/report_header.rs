@@ -0,0 +1,42 @@
+use crate::helpers;
+
+/// A line of text shown at the top of generated graphs and reports, so they can be shared without editing them afterwards.
+#[derive(serde::Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReportHeader {
+    /// The name of the desk the artifacts are made for.
+    pub desk_name: Option<String>,
+    /// The environment the artifacts were made in, e.g. production or test.
+    pub environment: Option<String>,
+    /// How the header is laid out. {desk_name}, {environment}, {notes} and {date} are replaced with their values, and
+    /// anything not set is left empty. If not set, whichever of the desk name, environment and notes are set are joined
+    /// with " | ".
+    pub template: Option<String>,
+    /// Notes for this run only, set with --notes rather than in the config file.
+    #[serde(skip)]
+    pub notes: Option<String>,
+}
+
+impl ReportHeader {
+    /// The header text, or None if there is nothing to show.
+    pub fn render(&self) -> Option<String> {
+        let header = match &self.template {
+            Some(template) => template
+                .replace("{desk_name}", self.desk_name.as_deref().unwrap_or_default())
+                .replace("{environment}", self.environment.as_deref().unwrap_or_default())
+                .replace("{notes}", self.notes.as_deref().unwrap_or_default())
+                .replace("{date}", &helpers::format_time(helpers::get_now())),
+            None => [&self.desk_name, &self.environment, &self.notes]
+                .into_iter()
+                .flatten()
+                .filter(|x| !x.trim().is_empty())
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(" | "),
+        };
+
+        let header = header.trim();
+
+        (!header.is_empty()).then(|| header.to_string())
+    }
+}
@@ -0,0 +1,101 @@
+use chrono_tz::Tz;
+
+use crate::analytics::{
+    ButterflyCheckSettings, FitWeighting, ForwardSourceSettings, PutCallParitySettings, ScenarioShock, SmileFitLimits,
+    SurfaceModel, SviOptimiser, TermStructureSmoothing, WingExtrapolation,
+};
+use crate::config::{DaemonSettings, InstrumentFilters, ReportHeader};
+use crate::fileio::{self, DataPaths};
+use crate::helpers;
+use crate::integrations::DeribitSettings;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+/// The default location of the config file.
+const DEFAULT_CONFIG_PATH: &str = "./config.json";
+
+/// User configuration, loaded from a JSON file. Every setting is optional, and anything not set uses its default, so the
+/// config file doesn't need to exist at all.
+#[derive(serde::Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Rules for which instruments are used when building the surface.
+    pub instrument_filters: InstrumentFilters,
+    /// How implied volatility is extrapolated beyond the observed strikes.
+    pub wing_extrapolation: WingExtrapolation,
+    /// Whether the smiles are fit one at a time with SVI or all at once with eSSVI.
+    pub surface_model: SurfaceModel,
+    /// How the best curve is searched for when fitting each smile with SVI.
+    pub svi_optimiser: SviOptimiser,
+    /// How much each option counts towards its smile's fit.
+    pub fit_weighting: FitWeighting,
+    /// Where and how densely smiles are checked for butterfly arbitrage while fitting.
+    pub butterfly_check: ButterflyCheckSettings,
+    /// How long any one smile can be fit for. --smile-timeout and --smile-max-iterations override it.
+    pub smile_fit_limits: SmileFitLimits,
+    /// The continuously-compounded yield the underlying pays (e.g. an equity index's dividends, or lending or staking yield
+    /// on a coin), annualised, e.g. 0.02. Used when pricing options from the surface. Defaults to 0.
+    pub dividend_yield: f64,
+    /// Where the forward price at each expiry is found from.
+    pub forward_sources: ForwardSourceSettings,
+    /// How calls and puts at the same strike are checked against each other and fit to.
+    pub put_call_parity: PutCallParitySettings,
+    /// How the at the money term structure is smoothed after the smiles are fit.
+    pub term_structure_smoothing: TermStructureSmoothing,
+    /// The time zone times are shown in, e.g. Europe/London. Defaults to UTC. Saved data always uses UTC.
+    pub display_timezone: Option<Tz>,
+    /// The header shown at the top of generated graphs and reports.
+    pub report_header: ReportHeader,
+    /// The shocks the scenario command applies to the surface.
+    pub scenarios: Vec<ScenarioShock>,
+    /// When and how the daemon command runs the surface build.
+    pub daemon: DaemonSettings,
+    /// How Deribit's market data is read.
+    pub deribit: DeribitSettings,
+    /// Where data files are read from and saved to.
+    pub data_paths: DataPaths,
+}
+
+impl Config {
+    /// Load the config file given by --config, or ./config.json if not given. If no path was given and the default file
+    /// doesn't exist, the default config is used. Notes for the report header are taken from --notes.
+    pub fn load(args: &[String]) -> Result<Self, TsError> {
+        let mut config = match helpers::get_flag_value(args, "--config")? {
+            Some(path) => fileio::load_struct_from_file::<Config>(path)?,
+            None if fileio::file_exists(DEFAULT_CONFIG_PATH) => fileio::load_struct_from_file::<Config>(DEFAULT_CONFIG_PATH)?,
+            None => Config::default(),
+        };
+
+        config.instrument_filters.check_valid()?;
+        config.forward_sources.check_valid()?;
+        config.put_call_parity.check_valid()?;
+        config.butterfly_check.check_valid()?;
+        config.svi_optimiser.check_valid()?;
+        if let Some(v) = helpers::get_flag_value(args, "--smile-timeout")? {
+            config.smile_fit_limits.max_seconds = Some(v.parse::<f64>().map_err(|e| {
+                TsError::new(RuntimeError, format!("Invalid --smile-timeout {v}, expected a number of seconds: {e}"))
+                    .with_source(e)
+            })?);
+        }
+        if let Some(v) = helpers::get_flag_value(args, "--smile-max-iterations")? {
+            config.smile_fit_limits.max_iterations = Some(v.parse::<u64>().map_err(|e| {
+                TsError::new(RuntimeError, format!("Invalid --smile-max-iterations {v}, expected a whole number: {e}"))
+                    .with_source(e)
+            })?);
+        }
+        config.smile_fit_limits.check_valid()?;
+        config.daemon.check_valid()?;
+        config.data_paths.check_valid()?;
+        for scenario in &config.scenarios {
+            scenario.check_valid()?;
+        }
+        config.report_header.notes = helpers::get_flag_value(args, "--notes")?.map(String::from);
+
+        if let Some(directory) = helpers::get_flag_value(args, "--data-dir")? {
+            config.data_paths.directory = directory.to_string();
+        }
+        config.data_paths.snapshot_date = helpers::get_flag_date(args, "--snapshot-date")?;
+
+        Ok(config)
+    }
+}
@@ -1,8 +1,5 @@
 // Some constants based on assumptions. These could be refactored into program parameters.
 
-/// The penalty the fitting algorithm receives when it tries to use a mathematically invalid curve.
-pub const INVALID_FIT_PENALITY: f64 = 999.0;
-
 /// The assumed interest free rate used when calculating the forward price. In reality we would figure this out by
 /// doing things like looking at the market (e.g. from futures pricing), but that's too much work. Having looked at
 /// the futures data, it seems this is typically implied to be around 5-8%, depending on expiry. So we'll use a sensible
@@ -20,12 +17,29 @@ pub const VALIDATE_SVI: bool = true;
 /// fit of the final graph, there may be an issue with the way it is being fit.
 pub const CHECK_FOR_ARBITRAGE: bool = true;
 
-/// Only process the smile with this timestamp (seconds). Useful for debugging.
-pub const ONLY_PROCESS_SMILE_DATE: Option<u64> = None;
-
 /// If the error doesn't decrease by at least this much percent then we will treat a new curve as a non-improvement and ignore it.
 /// 0.01 = 1%.
 pub const SVI_FITTING_REQUIRED_IMPROVEMENT: f64 = 0.01;
 
-/// When solving implied volatility, we will keep searching until it's this close.
+/// When solving implied volatility, we will keep searching until it's this close. Newton's method converges quickly, so
+/// making this smaller only costs a step or two more per option.
 pub const IMPLIED_VOLATILITY_SOLVER_ACCURACY: f64 = 0.0001;
+
+/// When resuming an interrupted download, ticker data older than this (seconds) is downloaded again rather than reused.
+pub const FETCH_CHECKPOINT_MAX_AGE_SECONDS: u64 = 3600;
+
+/// The maximum number of times we will attempt a network request before giving up.
+pub const NETWORK_REQUEST_MAX_ATTEMPTS: u32 = 6;
+
+/// The delay before retrying a failed network request (milliseconds). This doubles with each failed attempt.
+pub const NETWORK_RETRY_BASE_DELAY_MILLIS: u64 = 500;
+
+/// The longest we will wait before retrying a failed network request (milliseconds).
+pub const NETWORK_RETRY_MAX_DELAY_MILLIS: u64 = 30_000;
+
+/// The sustained number of requests per second we allow ourselves to make. Deribit's documented limit for non-matching
+/// engine requests is 20 per second with bursts of up to 100.
+pub const NETWORK_REQUESTS_PER_SECOND: f64 = 20.0;
+
+/// The number of requests we can make in a burst before being limited to NETWORK_REQUESTS_PER_SECOND.
+pub const NETWORK_REQUEST_BURST: f64 = 100.0;
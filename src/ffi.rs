@@ -0,0 +1,391 @@
+//! C functions for pricing options and reading fitted surfaces, so that programs in other languages (e.g. C++ or C#) can
+//! use them. Build the shared library with `cargo rustc --release --lib --crate-type cdylib --no-default-features --features
+//! ffi`. Its header is include/theta_surface.h, which building with the ffi feature generates again in OUT_DIR, and a test
+//! checks that the two match.
+//!
+//! Every function returns TS_OK on success and writes its result through its last argument. Otherwise it returns the
+//! code the program would exit with for the error (see TsErrorType::exit_code()), or TS_PANICKED, and
+//! ts_last_error_message() says what went wrong.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char};
+use std::panic::{self, AssertUnwindSafe};
+
+use chrono::{DateTime, Utc};
+
+use crate::analytics::{self, OptionType, SmileGraph, SmileGraphsDataContainer};
+use crate::types::TsErrorType::RuntimeError;
+use crate::types::{TsError, load_versioned_struct_from_str};
+
+/// Returned by every function that succeeded.
+pub const TS_OK: i32 = 0;
+/// Returned if a function panicked, the same code the program exits with when it panics.
+pub const TS_PANICKED: i32 = 101;
+
+/// A call option, the same value as OptionType::Call.
+pub const TS_CALL: i32 = 1;
+/// A put option, the same value as OptionType::Put.
+pub const TS_PUT: i32 = 2;
+
+thread_local! {
+    /// Why the last function called on this thread failed.
+    static LAST_ERROR_MESSAGE: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// What an option's price depends on besides its volatility.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TsOptionInputs {
+    pub spot_price: f64,
+    pub strike: f64,
+    /// Years until the option expires (365 day year).
+    pub years_until_expiry: f64,
+    /// Continuously compounded and annualised, e.g. 0.05 for 5% per annum.
+    pub risk_free_interest_rate: f64,
+    /// Continuously compounded and annualised. Use 0 for none.
+    pub dividend_yield: f64,
+    /// TS_CALL or TS_PUT.
+    pub option_type: i32,
+}
+
+/// A point read off a surface (see analytics::interpolate_surface()).
+#[repr(C)]
+pub struct TsSurfacePoint {
+    pub forward_price: f64,
+    pub log_moneyness: f64,
+    pub total_variance: f64,
+    pub implied_volatility: f64,
+    /// True if the smiles either side of the point have calendar arbitrage at its log moneyness.
+    pub has_calendar_arbitrage: bool,
+}
+
+/// A fitted surface loaded by ts_surface_from_json(), which must be freed with ts_surface_free().
+pub struct TsSurface {
+    smile_graphs: Vec<SmileGraph>,
+}
+
+/// The Black-Scholes price of a European option.
+///
+/// # Safety
+///
+/// out_price must be valid to write an f64 to.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ts_black_scholes_price(inputs: TsOptionInputs, volatility: f64, out_price: *mut f64) -> i32 {
+    unsafe {
+        call(out_price, || {
+            analytics::calculate_black_scholes(
+                inputs.spot_price,
+                inputs.strike,
+                inputs.years_until_expiry,
+                inputs.risk_free_interest_rate,
+                inputs.dividend_yield,
+                volatility,
+                get_option_type(inputs.option_type)?,
+            )
+        })
+    }
+}
+
+/// The Black-Scholes implied volatility of a European option with the given price.
+///
+/// # Safety
+///
+/// out_volatility must be valid to write an f64 to.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ts_implied_volatility(inputs: TsOptionInputs, option_price: f64, out_volatility: *mut f64) -> i32 {
+    unsafe {
+        call(out_volatility, || {
+            analytics::calculate_bs_implied_volatility(
+                inputs.spot_price,
+                inputs.strike,
+                inputs.years_until_expiry,
+                inputs.risk_free_interest_rate,
+                inputs.dividend_yield,
+                option_price,
+                get_option_type(inputs.option_type)?,
+            )
+        })
+    }
+}
+
+/// Load a surface from the contents of a saved smile-graph-data.json. Times until expiry are measured from
+/// evaluation_time_ms (milliseconds since the Unix epoch) if it isn't null, and otherwise from when the surface was built.
+///
+/// # Safety
+///
+/// json must be a null-terminated string, evaluation_time_ms must be null or valid to read an i64 from, and out_surface
+/// must be valid to write a pointer to.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ts_surface_from_json(
+    json: *const c_char,
+    evaluation_time_ms: *const i64,
+    out_surface: *mut *mut TsSurface,
+) -> i32 {
+    unsafe {
+        call(out_surface, || {
+            if json.is_null() {
+                return Err(TsError::new(RuntimeError, "The surface data is null"));
+            }
+
+            let json = CStr::from_ptr(json)
+                .to_str()
+                .map_err(|e| TsError::new(RuntimeError, format!("The surface data isn't UTF-8: {e}")).with_source(e))?;
+            let data = load_versioned_struct_from_str::<SmileGraphsDataContainer>(json, "The surface data")
+                .map_err(|e| e.context("Failed reading surface data"))?;
+            let evaluation_time = match evaluation_time_ms.as_ref() {
+                Some(v) => Some(
+                    DateTime::<Utc>::from_timestamp_millis(*v)
+                        .ok_or(TsError::new(RuntimeError, format!("Invalid evaluation time {v}")))?,
+                ),
+                None => None,
+            };
+
+            Ok(Box::into_raw(Box::new(TsSurface {
+                smile_graphs: data.into_smile_graphs_as_of(evaluation_time)?,
+            })))
+        })
+    }
+}
+
+/// Read the surface at any strike and time to expiry, interpolating between its smiles.
+///
+/// # Safety
+///
+/// surface must have come from ts_surface_from_json() and not yet been freed, and out_point must be valid to write a
+/// TsSurfacePoint to.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ts_surface_evaluate(
+    surface: *const TsSurface,
+    strike: f64,
+    years_until_expiry: f64,
+    out_point: *mut TsSurfacePoint,
+) -> i32 {
+    unsafe {
+        call(out_point, || {
+            let surface = surface
+                .as_ref()
+                .ok_or(TsError::new(RuntimeError, "The surface is null"))?;
+            let point = analytics::interpolate_surface(&surface.smile_graphs, strike, years_until_expiry)?;
+
+            Ok(TsSurfacePoint {
+                forward_price: point.forward_price,
+                log_moneyness: point.log_moneyness,
+                total_variance: point.total_variance,
+                implied_volatility: point.implied_volatility,
+                has_calendar_arbitrage: point.calendar_arbitrage.is_some(),
+            })
+        })
+    }
+}
+
+/// Free a surface. Does nothing if it is null.
+///
+/// # Safety
+///
+/// surface must be null or have come from ts_surface_from_json(), and mustn't be used again.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ts_surface_free(surface: *mut TsSurface) {
+    if !surface.is_null() {
+        drop(unsafe { Box::from_raw(surface) });
+    }
+}
+
+/// Why the last function that failed on this thread failed. The string is owned by the library and only valid until
+/// another function fails on the same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn ts_last_error_message() -> *const c_char {
+    LAST_ERROR_MESSAGE.with_borrow(|x| x.as_ptr())
+}
+
+/// Run the function, writing its result to out. Errors and panics are kept as the thread's last error rather than being
+/// allowed to unwind into the caller, which isn't Rust.
+unsafe fn call<T>(out: *mut T, function: impl FnOnce() -> Result<T, TsError>) -> i32 {
+    if out.is_null() {
+        return fail(TsError::new(RuntimeError, "The output pointer is null"));
+    }
+
+    match panic::catch_unwind(AssertUnwindSafe(function)) {
+        Ok(Ok(v)) => {
+            unsafe { out.write(v) };
+            TS_OK
+        }
+        Ok(Err(e)) => fail(e),
+        // The panic has already been printed.
+        Err(_) => {
+            set_last_error_message("theta-surface panicked");
+            TS_PANICKED
+        }
+    }
+}
+
+fn fail(error: TsError) -> i32 {
+    set_last_error_message(&error.reason);
+    error.error_type.exit_code()
+}
+
+fn set_last_error_message(message: &str) {
+    // A message can't contain a null, since that would end it early.
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+
+    LAST_ERROR_MESSAGE.set(message);
+}
+
+fn get_option_type(option_type: i32) -> Result<OptionType, TsError> {
+    match option_type {
+        TS_CALL => Ok(OptionType::Call),
+        TS_PUT => Ok(OptionType::Put),
+        _ => Err(TsError::new(RuntimeError, format!("Invalid option type {option_type}, expected TS_CALL or TS_PUT"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString, c_char};
+    use std::ptr;
+
+    use super::*;
+    use crate::analytics::{OptionInstrument, SVICurveParameters};
+    use crate::helpers::Clock;
+    use crate::types::{SchemaVersioned, TsErrorType::UnsolvableError};
+
+    fn get_last_error_message() -> String {
+        unsafe { CStr::from_ptr(ts_last_error_message()) }
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn get_call_inputs() -> TsOptionInputs {
+        TsOptionInputs {
+            spot_price: 100.0,
+            strike: 100.0,
+            years_until_expiry: 0.5,
+            risk_free_interest_rate: 0.05,
+            dividend_yield: 0.0,
+            option_type: TS_CALL,
+        }
+    }
+
+    /// A surface saved before schema versions and build info were, with one smile.
+    fn get_old_surface_json() -> CString {
+        let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+        let expiry_seconds = 1_769_904_000;
+        let years_until_expiry = (expiry_seconds - 1_767_225_600) as f64 / 31556926.0;
+        let mut graph = SmileGraph::new();
+
+        for strike in [80.0, 90.0, 100.0, 110.0, 120.0] {
+            let price = analytics::calculate_black_scholes(
+                100.0,
+                strike,
+                years_until_expiry,
+                crate::constants::INTEREST_FREE_RATE,
+                0.0,
+                0.5,
+                OptionType::Call,
+            )
+            .unwrap();
+            let option =
+                OptionInstrument::new(price, expiry_seconds, strike, strike.to_string().into(), OptionType::Call, 100.0, clock);
+            graph.try_insert_option(option).unwrap();
+        }
+        graph.svi_curve_parameters = SVICurveParameters::new_from_values(0.03, 0.08, -0.4, 0.05, 0.15).unwrap();
+
+        let data = SmileGraphsDataContainer {
+            schema_version: SmileGraphsDataContainer::SCHEMA_VERSION,
+            smile_graphs: vec![graph],
+            surface_score: None,
+            synthetic_smiles: Vec::new(),
+            build_info: None,
+        };
+        let mut json = serde_json::to_value(data).unwrap();
+        json.as_object_mut().unwrap().remove("schema_version");
+
+        CString::new(json.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_pricing() {
+        let mut price = 0.0;
+        assert_eq!(unsafe { ts_black_scholes_price(get_call_inputs(), 0.5, &mut price) }, TS_OK);
+
+        let mut volatility = 0.0;
+        assert_eq!(unsafe { ts_implied_volatility(get_call_inputs(), price, &mut volatility) }, TS_OK);
+        assert!((volatility - 0.5).abs() < 0.0001);
+
+        // Errors return the code the program would exit with, and say what went wrong.
+        let inputs = TsOptionInputs {
+            option_type: 3,
+            ..get_call_inputs()
+        };
+        assert_eq!(unsafe { ts_black_scholes_price(inputs, 0.5, &mut price) }, RuntimeError.exit_code());
+        assert!(get_last_error_message().contains("Invalid option type 3"));
+
+        assert_eq!(
+            unsafe { ts_implied_volatility(get_call_inputs(), 1000.0, &mut volatility) },
+            UnsolvableError.exit_code()
+        );
+        assert!(get_last_error_message().contains("too high"));
+
+        assert_eq!(unsafe { ts_black_scholes_price(get_call_inputs(), 0.5, ptr::null_mut()) }, RuntimeError.exit_code());
+        assert_eq!(get_last_error_message(), "The output pointer is null");
+    }
+
+    #[test]
+    fn test_surface() {
+        let json = get_old_surface_json();
+        let evaluation_time_ms = 1_767_225_600_000;
+        let mut surface = ptr::null_mut();
+
+        // Surfaces from before schema versions are upgraded, as they are when loaded from file.
+        assert_eq!(unsafe { ts_surface_from_json(json.as_ptr(), &evaluation_time_ms, &mut surface) }, TS_OK);
+
+        let mut point = TsSurfacePoint {
+            forward_price: 0.0,
+            log_moneyness: 0.0,
+            total_variance: 0.0,
+            implied_volatility: 0.0,
+            has_calendar_arbitrage: false,
+        };
+        assert_eq!(unsafe { ts_surface_evaluate(surface, 100.0, 0.08, &mut point) }, TS_OK);
+        assert!(point.implied_volatility > 0.0);
+        unsafe { ts_surface_free(surface) };
+
+        let mut surface = ptr::null_mut();
+        assert_eq!(unsafe { ts_surface_from_json(ptr::null(), ptr::null(), &mut surface) }, RuntimeError.exit_code());
+        assert_eq!(get_last_error_message(), "The surface data is null");
+
+        let not_utf8 = [0xff_u8, 0xfe, 0];
+        let code = unsafe { ts_surface_from_json(not_utf8.as_ptr() as *const c_char, ptr::null(), &mut surface) };
+        assert_eq!(code, RuntimeError.exit_code());
+        assert!(get_last_error_message().starts_with("The surface data isn't UTF-8"));
+
+        let newer = CString::new(r#"{"schema_version": 1000, "smile_graphs": []}"#).unwrap();
+        assert_eq!(unsafe { ts_surface_from_json(newer.as_ptr(), ptr::null(), &mut surface) }, RuntimeError.exit_code());
+        assert!(get_last_error_message().contains("newer version"));
+        assert!(surface.is_null());
+
+        let mut point_out = point;
+        assert_eq!(unsafe { ts_surface_evaluate(ptr::null(), 100.0, 0.08, &mut point_out) }, RuntimeError.exit_code());
+        assert_eq!(get_last_error_message(), "The surface is null");
+
+        // Freeing null does nothing.
+        unsafe { ts_surface_free(ptr::null_mut()) };
+    }
+
+    #[test]
+    fn test_panic_is_caught() {
+        let mut out = 0.0;
+
+        assert_eq!(unsafe { call(&mut out, || -> Result<f64, TsError> { panic!("Test panic") }) }, TS_PANICKED);
+        assert_eq!(get_last_error_message(), "theta-surface panicked");
+    }
+
+    #[test]
+    fn test_header_is_current() {
+        assert_eq!(
+            include_str!(concat!(env!("OUT_DIR"), "/theta_surface.h")),
+            include_str!("../include/theta_surface.h"),
+            "include/theta_surface.h is out of date, copy the one generated in OUT_DIR over it"
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read};
+use std::path::{Component, Path, PathBuf};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+/// The name of the manifest inside an archive. It is always the first entry, so it can be read without decompressing the
+/// rest of the archive.
+const MANIFEST_ENTRY: &str = "manifest.json";
+
+/// Archived files are stored under this directory, so that they can never clash with the manifest.
+const FILES_DIRECTORY: &str = "files";
+
+/// What an archived file holds.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum ArchiveEntryKind {
+    /// Downloaded market data.
+    Snapshot,
+    /// A fitted surface, or something calculated from one.
+    Fit,
+    /// Anything else, e.g. graphs, exports and digests.
+    Report,
+}
+
+/// A file in an archive.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct ArchiveEntry {
+    /// Relative to the directory that was archived, using / as the separator.
+    pub path: String,
+    pub kind: ArchiveEntryKind,
+    pub modified_at: DateTime<Utc>,
+    pub size_bytes: u64,
+}
+
+/// The index of an archive, saved inside it so that an archive can be listed and checked before anything is restored.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct ArchiveManifest {
+    pub created_at: DateTime<Utc>,
+    /// The first and last days (inclusive) of files that were archived, if they were limited.
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub files: Vec<ArchiveEntry>,
+}
+
+/// Save the files in the manifest, which are relative to the given directory, to a gzip compressed tarball along with the
+/// manifest itself.
+pub fn save_archive(manifest: &ArchiveManifest, source_directory: &str, path: &str) -> Result<(), TsError> {
+    let file = File::create(path)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed creating file at path {path}: {e}")).with_source(e))?;
+    let mut builder = tar::Builder::new(GzEncoder::new(BufWriter::new(file), Compression::default()));
+    let write_error =
+        |e: std::io::Error| TsError::new(RuntimeError, format!("Failed writing archive {path}: {e}")).with_source(e);
+
+    let manifest_text = serde_json::to_vec_pretty(manifest)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed serialising object: {e}")).with_source(e))?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest_text.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.created_at.timestamp().max(0) as u64);
+    builder
+        .append_data(&mut header, MANIFEST_ENTRY, manifest_text.as_slice())
+        .map_err(write_error)?;
+
+    for entry in &manifest.files {
+        let relative_path = to_safe_relative_path(&entry.path)?;
+        builder
+            .append_path_with_name(
+                Path::new(source_directory).join(&relative_path),
+                Path::new(FILES_DIRECTORY).join(relative_path),
+            )
+            .map_err(write_error)?;
+    }
+
+    builder
+        .into_inner()
+        .and_then(|x| x.finish())
+        .map_err(write_error)?;
+
+    Ok(())
+}
+
+/// Read just the manifest of an archive saved by save_archive().
+pub fn load_archive_manifest(path: &str) -> Result<ArchiveManifest, TsError> {
+    let file = File::open(path)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed reading file at path {path}: {e}")).with_source(e))?;
+    let mut archive = tar::Archive::new(GzDecoder::new(BufReader::new(file)));
+    let read_error = |e: std::io::Error| TsError::new(RuntimeError, format!("Failed reading archive {path}: {e}")).with_source(e);
+
+    let mut entry = archive
+        .entries()
+        .map_err(read_error)?
+        .next()
+        .ok_or(TsError::new(RuntimeError, format!("Archive {path} is empty")))?
+        .map_err(read_error)?;
+
+    if entry.path().map_err(read_error)? != Path::new(MANIFEST_ENTRY) {
+        return Err(TsError::new(RuntimeError, format!("Archive {path} doesn't start with a manifest")));
+    }
+
+    let mut text = String::new();
+    entry.read_to_string(&mut text).map_err(read_error)?;
+
+    serde_json::from_str::<ArchiveManifest>(&text)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed deserialising object: {e}")).with_source(e))
+}
+
+/// Extract the files in an archive saved by save_archive() into the given directory, returning its manifest. Unless
+/// overwrite is set, nothing is extracted if any of the files already exist there.
+pub fn restore_archive(path: &str, destination_directory: &str, overwrite: bool) -> Result<ArchiveManifest, TsError> {
+    let manifest = load_archive_manifest(path)?;
+    let destination = Path::new(destination_directory);
+
+    if !overwrite {
+        let existing = manifest
+            .files
+            .iter()
+            .filter(|x| destination.join(&x.path).exists())
+            .map(|x| x.path.as_str())
+            .collect::<Vec<&str>>();
+
+        if !existing.is_empty() {
+            return Err(TsError::new(
+                RuntimeError,
+                format!("These files already exist in {destination_directory}: {}", existing.join(", ")),
+            ));
+        }
+    }
+
+    let file = File::open(path)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed reading file at path {path}: {e}")).with_source(e))?;
+    let mut archive = tar::Archive::new(GzDecoder::new(BufReader::new(file)));
+    let read_error = |e: std::io::Error| TsError::new(RuntimeError, format!("Failed reading archive {path}: {e}")).with_source(e);
+
+    for entry in archive.entries().map_err(read_error)? {
+        let mut entry = entry.map_err(read_error)?;
+        let entry_path = entry.path().map_err(read_error)?.into_owned();
+
+        // Only files are archived, and links could point outside the destination.
+        let Ok(relative_path) = entry_path.strip_prefix(FILES_DIRECTORY) else {
+            continue;
+        };
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let target = destination.join(to_safe_relative_path(&relative_path.to_string_lossy())?);
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                TsError::new(RuntimeError, format!("Failed creating directory {}: {e}", parent.display())).with_source(e)
+            })?;
+        }
+
+        entry
+            .unpack(&target)
+            .map_err(|e| TsError::new(RuntimeError, format!("Failed extracting {}: {e}", target.display())).with_source(e))?;
+    }
+
+    Ok(manifest)
+}
+
+/// Archives could come from anywhere, so paths in them must stay inside the directory they are extracted to.
+fn to_safe_relative_path(path: &str) -> Result<PathBuf, TsError> {
+    let relative_path = PathBuf::from(path);
+
+    if relative_path.as_os_str().is_empty()
+        || !relative_path
+            .components()
+            .all(|x| matches!(x, Component::Normal(_)))
+    {
+        return Err(TsError::new(RuntimeError, format!("Archived path {path} isn't a plain relative path")));
+    }
+
+    Ok(relative_path)
+}
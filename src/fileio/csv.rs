@@ -0,0 +1,245 @@
+use std::fs;
+
+use chrono::NaiveDate;
+
+use crate::helpers;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+/// Describes how values in a user-provided CSV file are formatted. Real-world spreadsheets are messy, so rather than guessing
+/// we make the user tell us.
+#[derive(Clone)]
+pub struct CsvFormat {
+    /// The character separating columns.
+    pub delimiter: char,
+    /// The character separating the whole and fractional parts of a number (e.g. '.' in 1.5 or ',' in 1,5).
+    pub decimal_separator: char,
+    /// The character used to group thousands (e.g. ',' in 1,000.5), if any.
+    pub thousands_separator: Option<char>,
+    /// The chrono format string used for dates (e.g. %Y-%m-%d or %d/%m/%Y).
+    pub date_format: String,
+}
+
+impl Default for CsvFormat {
+    fn default() -> Self {
+        Self {
+            delimiter: ',',
+            decimal_separator: '.',
+            thousands_separator: None,
+            date_format: "%Y-%m-%d".to_string(),
+        }
+    }
+}
+
+impl CsvFormat {
+    /// Build the format from the command line flags --csv-delimiter, --csv-decimal-separator, --csv-thousands-separator
+    /// and --csv-date-format, using the defaults for any that weren't passed.
+    pub fn from_args(args: &[String]) -> Result<Self, TsError> {
+        let default = Self::default();
+
+        let format = Self {
+            delimiter: helpers::get_flag_char(args, "--csv-delimiter")?.unwrap_or(default.delimiter),
+            decimal_separator: helpers::get_flag_char(args, "--csv-decimal-separator")?.unwrap_or(default.decimal_separator),
+            thousands_separator: helpers::get_flag_char(args, "--csv-thousands-separator")?.or(default.thousands_separator),
+            date_format: helpers::get_flag_value(args, "--csv-date-format")?
+                .map(|x| x.to_string())
+                .unwrap_or(default.date_format),
+        };
+
+        format.check_valid()?;
+
+        Ok(format)
+    }
+
+    /// Values containing the delimiter must be quoted, so the only thing that can't be worked out is a thousands separator
+    /// that is the same as the decimal separator.
+    pub fn check_valid(&self) -> Result<(), TsError> {
+        if self.thousands_separator == Some(self.decimal_separator) {
+            return Err(TsError::new(
+                RuntimeError,
+                "The CSV thousands separator cannot be the same as the decimal separator",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Parse a number formatted according to this format.
+    fn parse_f64(&self, value: &str) -> Result<f64, String> {
+        let mut normalised = String::with_capacity(value.len());
+
+        for c in value.trim().chars() {
+            if Some(c) == self.thousands_separator {
+                continue;
+            } else if c == self.decimal_separator {
+                normalised.push('.');
+            } else if c == '.' || c == ',' {
+                // A separator we weren't told about. Rather than guess what it means, fail.
+                return Err(format!(
+                    "unexpected separator '{c}' (decimal separator is '{}', thousands separator is {})",
+                    self.decimal_separator,
+                    self.thousands_separator
+                        .map(|x| format!("'{x}'"))
+                        .unwrap_or("not set".to_string())
+                ));
+            } else {
+                normalised.push(c);
+            }
+        }
+
+        let number = normalised
+            .parse::<f64>()
+            .map_err(|e| format!("not a valid number ({e})"))?;
+
+        if !number.is_finite() {
+            return Err("not a finite number".to_string());
+        }
+
+        Ok(number)
+    }
+}
+
+/// A CSV file loaded into memory. The first row is treated as a header.
+pub struct CsvTable {
+    path: String,
+    headers: Vec<String>,
+    rows: Vec<Vec<String>>,
+    format: CsvFormat,
+}
+
+impl CsvTable {
+    pub fn rows_count(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn get_headers(&self) -> &[String] {
+        &self.headers
+    }
+
+    pub fn has_column(&self, column: &str) -> bool {
+        self.get_column_index(column).is_ok()
+    }
+
+    fn get_column_index(&self, column: &str) -> Result<usize, TsError> {
+        self.headers
+            .iter()
+            .position(|x| x.eq_ignore_ascii_case(column))
+            .ok_or(TsError::new(RuntimeError, format!("{}: missing required column '{column}'", self.path)))
+    }
+
+    /// Get the raw text value at the given row (0 is the first row after the header) and column.
+    pub fn get_str(&self, row: usize, column: &str) -> Result<&str, TsError> {
+        let column_index = self.get_column_index(column)?;
+
+        self.rows
+            .get(row)
+            .and_then(|x| x.get(column_index))
+            .map(|x| x.trim())
+            .ok_or(TsError::new(RuntimeError, self.describe_error(row, column, "", "value is missing")))
+    }
+
+    pub fn get_f64(&self, row: usize, column: &str) -> Result<f64, TsError> {
+        let value = self.get_str(row, column)?;
+
+        self.format
+            .parse_f64(value)
+            .map_err(|e| TsError::new(RuntimeError, self.describe_error(row, column, value, &e)))
+    }
+
+    pub fn get_date(&self, row: usize, column: &str) -> Result<NaiveDate, TsError> {
+        let value = self.get_str(row, column)?;
+
+        NaiveDate::parse_from_str(value, &self.format.date_format).map_err(|e| {
+            TsError::new(
+                RuntimeError,
+                self.describe_error(row, column, value, &format!("not a date in the format {} ({e})", self.format.date_format)),
+            )
+        })
+    }
+
+    /// Build an error message pointing the user to the offending value. Row numbers are given as line numbers in the file
+    /// so they match what the user sees in their editor.
+    fn describe_error(&self, row: usize, column: &str, value: &str, problem: &str) -> String {
+        format!("{}: line {}, column '{column}', value '{value}': {problem}", self.path, row + 2)
+    }
+}
+
+/// Load a CSV file with the given format. Values may be wrapped in double quotes, which is required if they contain the
+/// delimiter. Blank lines are ignored.
+pub fn load_csv_file(path: &str, format: CsvFormat) -> Result<CsvTable, TsError> {
+    format.check_valid()?;
+
+    let data = fs::read_to_string(path)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed reading file at path {}: {}", path, e)).with_source(e))?;
+
+    let mut lines = data
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+
+    let (_, header_line) = lines
+        .next()
+        .ok_or(TsError::new(RuntimeError, format!("{path}: file is empty")))?;
+    let headers: Vec<String> = split_csv_line(header_line, format.delimiter)
+        .map_err(|e| TsError::new(RuntimeError, format!("{path}: line 1: {e}")))?
+        .into_iter()
+        .map(|x| x.trim().to_string())
+        .collect();
+
+    let mut rows = Vec::new();
+
+    for (line_number, line) in lines {
+        let row = split_csv_line(line, format.delimiter)
+            .map_err(|e| TsError::new(RuntimeError, format!("{path}: line {}: {e}", line_number + 1)))?;
+
+        if row.len() != headers.len() {
+            return Err(TsError::new(
+                RuntimeError,
+                format!(
+                    "{path}: line {}: expected {} columns but found {} (is the delimiter '{}' correct?)",
+                    line_number + 1,
+                    headers.len(),
+                    row.len(),
+                    format.delimiter
+                ),
+            ));
+        }
+
+        rows.push(row);
+    }
+
+    Ok(CsvTable {
+        path: path.to_string(),
+        headers,
+        rows,
+        format,
+    })
+}
+
+fn split_csv_line(line: &str, delimiter: char) -> Result<Vec<String>, String> {
+    let mut values = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                // An escaped quote.
+                current.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            c if c == delimiter && !in_quotes => values.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+
+    if in_quotes {
+        return Err("unterminated quoted value".to_string());
+    }
+
+    values.push(current);
+
+    Ok(values)
+}
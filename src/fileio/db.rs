@@ -0,0 +1,314 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::{Connection, params};
+
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+/// The version of the tables below, saved in the database's user_version. Increase it whenever the tables change, and
+/// upgrade older databases in SurfaceStore::open().
+const STORE_SCHEMA_VERSION: i64 = 1;
+
+const CREATE_TABLES: &str = "
+    CREATE TABLE IF NOT EXISTS quotes (
+        snapshot_time TEXT NOT NULL,
+        expiry TEXT NOT NULL,
+        instrument_name TEXT NOT NULL,
+        strike REAL NOT NULL,
+        option_type TEXT NOT NULL,
+        quote_currency TEXT NOT NULL,
+        bid_price REAL,
+        ask_price REAL,
+        mark_price REAL,
+        mark_implied_volatility REAL,
+        index_price REAL NOT NULL,
+        underlying_price REAL,
+        quoted_at TEXT NOT NULL,
+        PRIMARY KEY (snapshot_time, instrument_name)
+    );
+    CREATE INDEX IF NOT EXISTS quotes_by_expiry ON quotes (snapshot_time, expiry);
+    CREATE TABLE IF NOT EXISTS svi_fits (
+        snapshot_time TEXT NOT NULL,
+        expiry TEXT NOT NULL,
+        a REAL NOT NULL,
+        b REAL NOT NULL,
+        p REAL NOT NULL,
+        m REAL NOT NULL,
+        o REAL NOT NULL,
+        forward_price REAL NOT NULL,
+        option_count INTEGER NOT NULL,
+        PRIMARY KEY (snapshot_time, expiry)
+    );
+    CREATE TABLE IF NOT EXISTS expiry_metrics (
+        snapshot_time TEXT NOT NULL,
+        expiry TEXT NOT NULL,
+        atm_implied_volatility REAL NOT NULL,
+        risk_reversal_25_delta REAL NOT NULL,
+        butterfly_25_delta REAL NOT NULL,
+        atm_skew REAL NOT NULL,
+        atm_forward_volatility REAL,
+        PRIMARY KEY (snapshot_time, expiry)
+    );
+";
+
+/// A quote for one option in a snapshot, as downloaded. Prices are in the quote currency, which for Deribit is BTC, so
+/// they are multiplied by the index price to get USD.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuoteRecord {
+    pub instrument_name: String,
+    pub expiry: DateTime<Utc>,
+    pub strike: f64,
+    /// call or put.
+    pub option_type: String,
+    pub quote_currency: String,
+    pub bid_price: Option<f64>,
+    pub ask_price: Option<f64>,
+    pub mark_price: Option<f64>,
+    pub mark_implied_volatility: Option<f64>,
+    pub index_price: f64,
+    pub underlying_price: Option<f64>,
+    pub quoted_at: DateTime<Utc>,
+}
+
+/// The SVI parameters fitted to one expiry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SviFitRecord {
+    pub expiry: DateTime<Utc>,
+    pub a: f64,
+    pub b: f64,
+    pub p: f64,
+    pub m: f64,
+    pub o: f64,
+    pub forward_price: f64,
+    pub option_count: usize,
+}
+
+/// The summary metrics of one expiry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpiryMetricsRecord {
+    pub expiry: DateTime<Utc>,
+    pub atm_implied_volatility: f64,
+    pub risk_reversal_25_delta: f64,
+    pub butterfly_25_delta: f64,
+    pub atm_skew: f64,
+    pub atm_forward_volatility: Option<f64>,
+}
+
+/// An SQLite database of snapshots' quotes, fitted SVI parameters and surface metrics, keyed by snapshot time and expiry,
+/// so that months of history can be kept in one file and queried with SQL. Times are saved as RFC 3339 UTC text with
+/// milliseconds (e.g. 2026-02-20T13:41:49.882Z), which sorts and compares correctly as text.
+pub struct SurfaceStore {
+    connection: Connection,
+    path: String,
+}
+
+impl SurfaceStore {
+    /// Open the database at the given path, creating it and its tables if they don't exist.
+    pub fn open(path: &str) -> Result<Self, TsError> {
+        let connection = Connection::open(path)
+            .map_err(|e| TsError::new(RuntimeError, format!("Failed opening database {path}: {e}")).with_source(e))?;
+        let store = Self {
+            connection,
+            path: path.to_string(),
+        };
+
+        let version = store
+            .connection
+            .query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))
+            .map_err(|e| store.error(e))?;
+
+        if version > STORE_SCHEMA_VERSION {
+            return Err(TsError::new(
+                RuntimeError,
+                format!(
+                    "{path} was created by a newer version of this program (schema version {version}, but only up to \
+                     {STORE_SCHEMA_VERSION} can be used), please update"
+                ),
+            ));
+        }
+
+        store
+            .connection
+            .execute_batch(CREATE_TABLES)
+            .and_then(|_| {
+                store
+                    .connection
+                    .pragma_update(None, "user_version", STORE_SCHEMA_VERSION)
+            })
+            .map_err(|e| store.error(e))?;
+
+        Ok(store)
+    }
+
+    fn error(&self, e: rusqlite::Error) -> TsError {
+        TsError::new(RuntimeError, format!("Database {} failed: {e}", self.path))
+    }
+
+    /// Save the quotes of a snapshot, replacing any already saved for it.
+    pub fn save_quotes(&mut self, snapshot_time: DateTime<Utc>, quotes: &[QuoteRecord]) -> Result<(), TsError> {
+        let path = self.path.clone();
+        let error = |e: rusqlite::Error| TsError::new(RuntimeError, format!("Database {path} failed: {e}")).with_source(e);
+        let snapshot_time = format_time(snapshot_time);
+        let transaction = self.connection.transaction().map_err(error)?;
+
+        transaction
+            .execute("DELETE FROM quotes WHERE snapshot_time = ?1", params![snapshot_time])
+            .map_err(error)?;
+
+        {
+            let mut statement = transaction
+                .prepare(
+                    "INSERT INTO quotes (snapshot_time, expiry, instrument_name, strike, option_type, quote_currency, \
+                     bid_price, ask_price, mark_price, mark_implied_volatility, index_price, underlying_price, quoted_at) \
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+                )
+                .map_err(error)?;
+
+            for quote in quotes {
+                statement
+                    .execute(params![
+                        snapshot_time,
+                        format_time(quote.expiry),
+                        quote.instrument_name,
+                        quote.strike,
+                        quote.option_type,
+                        quote.quote_currency,
+                        quote.bid_price,
+                        quote.ask_price,
+                        quote.mark_price,
+                        quote.mark_implied_volatility,
+                        quote.index_price,
+                        quote.underlying_price,
+                        format_time(quote.quoted_at),
+                    ])
+                    .map_err(error)?;
+            }
+        }
+
+        transaction.commit().map_err(error)
+    }
+
+    /// Save the SVI parameters fitted to a snapshot, replacing any already saved for it.
+    pub fn save_svi_fits(&mut self, snapshot_time: DateTime<Utc>, fits: &[SviFitRecord]) -> Result<(), TsError> {
+        let path = self.path.clone();
+        let error = |e: rusqlite::Error| TsError::new(RuntimeError, format!("Database {path} failed: {e}")).with_source(e);
+        let snapshot_time = format_time(snapshot_time);
+        let transaction = self.connection.transaction().map_err(error)?;
+
+        transaction
+            .execute("DELETE FROM svi_fits WHERE snapshot_time = ?1", params![snapshot_time])
+            .map_err(error)?;
+
+        {
+            let mut statement = transaction
+                .prepare(
+                    "INSERT INTO svi_fits (snapshot_time, expiry, a, b, p, m, o, forward_price, option_count) \
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                )
+                .map_err(error)?;
+
+            for fit in fits {
+                statement
+                    .execute(params![
+                        snapshot_time,
+                        format_time(fit.expiry),
+                        fit.a,
+                        fit.b,
+                        fit.p,
+                        fit.m,
+                        fit.o,
+                        fit.forward_price,
+                        fit.option_count as i64,
+                    ])
+                    .map_err(error)?;
+            }
+        }
+
+        transaction.commit().map_err(error)
+    }
+
+    /// Save the metrics of a snapshot's surface, replacing any already saved for it.
+    pub fn save_expiry_metrics(&mut self, snapshot_time: DateTime<Utc>, metrics: &[ExpiryMetricsRecord]) -> Result<(), TsError> {
+        let path = self.path.clone();
+        let error = |e: rusqlite::Error| TsError::new(RuntimeError, format!("Database {path} failed: {e}")).with_source(e);
+        let snapshot_time = format_time(snapshot_time);
+        let transaction = self.connection.transaction().map_err(error)?;
+
+        transaction
+            .execute("DELETE FROM expiry_metrics WHERE snapshot_time = ?1", params![snapshot_time])
+            .map_err(error)?;
+
+        {
+            let mut statement = transaction
+                .prepare(
+                    "INSERT INTO expiry_metrics (snapshot_time, expiry, atm_implied_volatility, risk_reversal_25_delta, \
+                     butterfly_25_delta, atm_skew, atm_forward_volatility) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                )
+                .map_err(error)?;
+
+            for expiry in metrics {
+                statement
+                    .execute(params![
+                        snapshot_time,
+                        format_time(expiry.expiry),
+                        expiry.atm_implied_volatility,
+                        expiry.risk_reversal_25_delta,
+                        expiry.butterfly_25_delta,
+                        expiry.atm_skew,
+                        expiry.atm_forward_volatility,
+                    ])
+                    .map_err(error)?;
+            }
+        }
+
+        transaction.commit().map_err(error)
+    }
+
+    /// The SVI parameters fitted to a snapshot, ordered by expiry.
+    pub fn load_svi_fits(&self, snapshot_time: DateTime<Utc>) -> Result<Vec<SviFitRecord>, TsError> {
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT expiry, a, b, p, m, o, forward_price, option_count FROM svi_fits WHERE snapshot_time = ?1 \
+                 ORDER BY expiry",
+            )
+            .map_err(|e| self.error(e))?;
+
+        let rows = statement
+            .query_map(params![format_time(snapshot_time)], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    SviFitRecord {
+                        expiry: DateTime::UNIX_EPOCH,
+                        a: row.get(1)?,
+                        b: row.get(2)?,
+                        p: row.get(3)?,
+                        m: row.get(4)?,
+                        o: row.get(5)?,
+                        forward_price: row.get(6)?,
+                        option_count: row.get::<_, i64>(7)? as usize,
+                    },
+                ))
+            })
+            .map_err(|e| self.error(e))?;
+
+        rows.map(|row| {
+            let (expiry, fit) = row.map_err(|e| self.error(e))?;
+
+            Ok(SviFitRecord {
+                expiry: parse_time(&expiry)?,
+                ..fit
+            })
+        })
+        .collect()
+    }
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn parse_time(text: &str) -> Result<DateTime<Utc>, TsError> {
+    DateTime::parse_from_rfc3339(text)
+        .map(|x| x.to_utc())
+        .map_err(|e| TsError::new(RuntimeError, format!("Invalid time {text} in database: {e}")).with_source(e))
+}
@@ -1,32 +1,170 @@
-use std::fs;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
 
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
 use serde::{Serialize, de::DeserializeOwned};
 
 use crate::types::TsError;
 use crate::types::TsErrorType::RuntimeError;
 
+/// The extension added to data files saved with --compress.
+const COMPRESSED_EXTENSION: &str = ".zst";
+
+/// The zstd level used when compressing, which is a good balance of speed and size for JSON.
+const ZSTD_LEVEL: i32 = 9;
+
+/// How a file is compressed, which is decided by its extension.
+enum FileCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl FileCompression {
+    fn from_path(path: &str) -> Self {
+        if path.ends_with(".gz") {
+            Self::Gzip
+        } else if path.ends_with(".zst") {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+}
+
+/// Save an object as JSON. Paths ending in .zst or .gz are compressed with zstd or gzip, and aren't pretty-printed as
+/// they aren't meant to be read by hand.
 pub fn save_struct_to_file<T: Serialize>(obj: &T, path: &str) -> Result<(), TsError> {
-    let text =
-        serde_json::to_string_pretty(obj).map_err(|e| TsError::new(RuntimeError, format!("Failed serialising object: {}", e)))?;
+    let compression = FileCompression::from_path(path);
+    let text = match compression {
+        FileCompression::None => serde_json::to_vec_pretty(obj),
+        _ => serde_json::to_vec(obj),
+    }
+    .map_err(|e| TsError::new(RuntimeError, format!("Failed serialising object: {}", e)).with_source(e))?;
+
+    let data = match compression {
+        FileCompression::None => Ok(text),
+        FileCompression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&text).and_then(|_| encoder.finish())
+        }
+        FileCompression::Zstd => zstd::encode_all(text.as_slice(), ZSTD_LEVEL),
+    }
+    .map_err(|e| TsError::new(RuntimeError, format!("Failed compressing data for path {path}: {e}")).with_source(e))?;
 
-    fs::write(path, text).map_err(|e| TsError::new(RuntimeError, format!("Failed writing text to path {}: {}", path, e)))?;
+    fs::write(path, data)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed writing text to path {}: {}", path, e)).with_source(e))?;
 
     Ok(())
 }
 
+/// Save text to the file at the path, replacing any file already there in one step, so that anything reading it (e.g. a
+/// metrics collector) never sees it half written.
+pub fn save_text_to_file(text: &str, path: &str) -> Result<(), TsError> {
+    let temporary_path = format!("{path}.tmp");
+
+    fs::write(&temporary_path, text)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed writing text to path {temporary_path}: {e}")).with_source(e))?;
+    fs::rename(&temporary_path, path)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed moving {temporary_path} to {path}: {e}")).with_source(e))
+}
+
+/// Load an object saved by save_struct_to_file(), decompressing it first if the path ends in .zst or .gz. The object is
+/// parsed as the file is read, rather than the whole file being read into memory first, so that loading a big snapshot
+/// doesn't need room for both the file and the object at once.
 pub fn load_struct_from_file<T: DeserializeOwned>(path: &str) -> Result<T, TsError> {
-    let data = fs::read_to_string(path)
-        .map_err(|e| TsError::new(RuntimeError, format!("Failed reading file at path {}: {}", path, e)))?;
+    serde_json::from_reader::<_, T>(open_file_reader(path)?)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed deserialising object: {}", e)).with_source(e))
+}
+
+/// Open a file to be read through a buffer, decompressing it as it's read if the path ends in .zst or .gz.
+pub(super) fn open_file_reader(path: &str) -> Result<Box<dyn BufRead>, TsError> {
+    let file = File::open(path)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed reading file at path {}: {}", path, e)).with_source(e))?;
+
+    Ok(match FileCompression::from_path(path) {
+        FileCompression::None => Box::new(BufReader::new(file)),
+        FileCompression::Gzip => Box::new(BufReader::new(GzDecoder::new(BufReader::new(file)))),
+        FileCompression::Zstd => {
+            Box::new(BufReader::new(zstd::Decoder::new(file).map_err(|e| {
+                TsError::new(RuntimeError, format!("Failed decompressing file at path {path}: {e}")).with_source(e)
+            })?))
+        }
+    })
+}
+
+/// Save a data file that other commands find with find_data_file(), compressed with zstd (adding COMPRESSED_EXTENSION to
+/// the path) if asked. Copies saved with other compression are deleted so that they aren't loaded instead of this one.
+/// Returns the path saved to.
+pub fn save_data_file<T: Serialize>(obj: &T, path: &str, compress: bool) -> Result<String, TsError> {
+    let saved_path = match compress {
+        true => format!("{path}{COMPRESSED_EXTENSION}"),
+        false => path.to_string(),
+    };
+
+    save_struct_to_file(obj, &saved_path)?;
+
+    for other_path in get_data_file_paths(path) {
+        if other_path != saved_path {
+            delete_file(&other_path)?;
+        }
+    }
+
+    Ok(saved_path)
+}
+
+/// Find a data file which may have been saved compressed by save_data_file(), i.e. with .zst or .gz added to the path.
+/// If none exists, the uncompressed path is returned, so that loading it gives a clear error.
+pub fn find_data_file(path: &str) -> String {
+    get_data_file_paths(path)
+        .into_iter()
+        .find(|x| file_exists(x))
+        .unwrap_or(path.to_string())
+}
+
+/// Delete a data file saved by save_data_file(), whether or not it was compressed. Does nothing if it doesn't exist.
+pub fn delete_data_file(path: &str) -> Result<(), TsError> {
+    get_data_file_paths(path)
+        .iter()
+        .try_for_each(|x| delete_file(x))
+}
+
+fn get_data_file_paths(path: &str) -> [String; 3] {
+    [path.to_string(), format!("{path}.zst"), format!("{path}.gz")]
+}
+
+/// Returns true if a file exists at the given path.
+pub fn file_exists(path: &str) -> bool {
+    Path::new(path).is_file()
+}
+
+/// Create the directory at the given path, along with any missing parent directories. Does nothing if it already exists.
+pub fn create_directory(path: &str) -> Result<(), TsError> {
+    fs::create_dir_all(path)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed creating directory {path}: {e}")).with_source(e))
+}
+
+/// Delete the file at the given path. Does nothing if the file doesn't exist.
+pub fn delete_file(path: &str) -> Result<(), TsError> {
+    if !file_exists(path) {
+        return Ok(());
+    }
 
-    serde_json::from_str::<T>(&data).map_err(|e| TsError::new(RuntimeError, format!("Failed deserialising object: {}", e)))
+    fs::remove_file(path)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed to delete file at path {path}: {e}")).with_source(e))
 }
 
 /// Delete all files in the given directory except files whose name contains ignore_filter.
 pub fn clear_directory(path: &str, ignore_filter: &str) -> Result<(), TsError> {
-    let files = fs::read_dir(path).map_err(|e| TsError::new(RuntimeError, format!("Couldn't read directory {path}: {e}")))?;
+    let files = fs::read_dir(path)
+        .map_err(|e| TsError::new(RuntimeError, format!("Couldn't read directory {path}: {e}")).with_source(e))?;
 
     for file in files {
-        let file_info = file.map_err(|e| TsError::new(RuntimeError, format!("File reference was invalid: {e}")))?;
+        let file_info =
+            file.map_err(|e| TsError::new(RuntimeError, format!("File reference was invalid: {e}")).with_source(e))?;
         let path = file_info.path();
         let path_name = path.display();
         let raw_file_name = file_info.file_name();
@@ -39,8 +177,36 @@ pub fn clear_directory(path: &str, ignore_filter: &str) -> Result<(), TsError> {
         }
 
         fs::remove_file(file_info.path())
-            .map_err(|e| TsError::new(RuntimeError, format!("Failed to delete file at path {path_name}: {e}")))?;
+            .map_err(|e| TsError::new(RuntimeError, format!("Failed to delete file at path {path_name}: {e}")).with_source(e))?;
     }
 
     Ok(())
 }
+
+/// Find every file in the given directory and its subdirectories, in a consistent order.
+pub fn list_files_recursively(path: &str) -> Result<Vec<PathBuf>, TsError> {
+    let mut files = Vec::new();
+    let mut directories = vec![PathBuf::from(path)];
+
+    while let Some(directory) = directories.pop() {
+        let entries = fs::read_dir(&directory).map_err(|e| {
+            TsError::new(RuntimeError, format!("Couldn't read directory {}: {e}", directory.display())).with_source(e)
+        })?;
+
+        for entry in entries {
+            let entry_path = entry
+                .map_err(|e| TsError::new(RuntimeError, format!("File reference was invalid: {e}")).with_source(e))?
+                .path();
+
+            if entry_path.is_dir() {
+                directories.push(entry_path);
+            } else if entry_path.is_file() {
+                files.push(entry_path);
+            }
+        }
+    }
+
+    files.sort();
+
+    Ok(files)
+}
@@ -0,0 +1,248 @@
+use std::fs;
+
+use crate::fileio::report::escape_html;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+/// How a series is drawn.
+#[derive(serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HtmlGraphSeriesStyle {
+    Line,
+    DashedLine,
+    Points,
+    /// A shaded region, e.g. a confidence band, whose points go round its edge in order.
+    Area,
+}
+
+#[derive(serde::Serialize)]
+pub struct HtmlGraphPoint {
+    pub x: f64,
+    pub y: f64,
+    /// Shown above the coordinates when hovering over the point, e.g. the instrument it came from.
+    pub label: Option<String>,
+    /// The radius of the point in pixels, if it isn't the default. Only used by series drawn as points.
+    pub radius: Option<f64>,
+}
+
+#[derive(serde::Serialize)]
+pub struct HtmlGraphSeries {
+    pub name: String,
+    /// Any CSS colour.
+    pub colour: String,
+    pub style: HtmlGraphSeriesStyle,
+    pub points: Vec<HtmlGraphPoint>,
+}
+
+/// A graph that is saved as a single self-contained HTML page, so that it can be explored in a browser. Hovering shows the
+/// values of the nearest point, dragging a box zooms in, double clicking zooms back out, and clicking a series in the legend
+/// hides or shows it.
+#[derive(serde::Serialize)]
+pub struct HtmlGraph {
+    pub title: String,
+    /// Shown above the title, e.g. the desk and environment the graph was made for.
+    pub header: Option<String>,
+    pub x_label: String,
+    pub y_label: String,
+    pub series: Vec<HtmlGraphSeries>,
+}
+
+/// The page the graph is drawn on. The graph's data is put in place of __DATA__.
+const HTML_GRAPH_TEMPLATE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>__TITLE__</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+#legend span { cursor: pointer; margin-right: 1.5em; user-select: none; }
+#legend span.hidden { opacity: 0.35; }
+#legend i { display: inline-block; width: 12px; height: 12px; margin-right: 6px; }
+#tooltip { position: fixed; display: none; pointer-events: none; background: #fff; border: 1px solid #888; padding: 4px 8px;
+  white-space: pre; font-size: 13px; }
+svg { width: 100%; max-width: 1400px; user-select: none; }
+svg text { font-size: 13px; }
+</style>
+</head>
+<body>
+__HEADER__<h1>__TITLE__</h1>
+<div id="legend"></div>
+<svg id="graph" viewBox="0 0 1200 700"></svg>
+<p>Hover over a point to see its values, drag a box to zoom in and double click to zoom back out.</p>
+<div id="tooltip"></div>
+<script>
+const graph = __DATA__;
+const svg = document.getElementById("graph");
+const tooltip = document.getElementById("tooltip");
+const legend = document.getElementById("legend");
+const size = { width: 1200, height: 700, left: 80, right: 20, top: 20, bottom: 60 };
+const hidden = new Set();
+const all = graph.series.flatMap(s => s.points);
+const full = {
+  xMin: Math.min(...all.map(p => p.x)), xMax: Math.max(...all.map(p => p.x)),
+  yMin: Math.min(0, ...all.map(p => p.y)), yMax: Math.max(...all.map(p => p.y)) * 1.05,
+};
+let view = { ...full };
+let dragStart = null;
+
+const toX = x => size.left + (x - view.xMin) / (view.xMax - view.xMin) * (size.width - size.left - size.right);
+const toY = y => size.height - size.bottom - (y - view.yMin) / (view.yMax - view.yMin) * (size.height - size.top - size.bottom);
+const fromX = px => view.xMin + (px - size.left) / (size.width - size.left - size.right) * (view.xMax - view.xMin);
+const fromY = py => view.yMin + (size.height - size.bottom - py) / (size.height - size.top - size.bottom) * (view.yMax - view.yMin);
+const format = v => Math.abs(v) >= 100 ? v.toFixed(2) : v.toFixed(4);
+
+function element(name, attributes, text) {
+  const e = document.createElementNS("http://www.w3.org/2000/svg", name);
+  for (const [key, value] of Object.entries(attributes)) e.setAttribute(key, value);
+  if (text !== undefined) e.textContent = text;
+  svg.appendChild(e);
+  return e;
+}
+
+function ticks(min, max) {
+  const rough = (max - min) / 8;
+  const magnitude = Math.pow(10, Math.floor(Math.log10(rough)));
+  const step = [1, 2, 5, 10].map(x => x * magnitude).find(x => x >= rough);
+  const values = [];
+  for (let v = Math.ceil(min / step) * step; v <= max; v += step) values.push(v);
+  return values;
+}
+
+function draw() {
+  svg.replaceChildren();
+  const clip = element("clipPath", { id: "plot" });
+  clip.appendChild(element("rect", {
+    x: size.left, y: size.top, width: size.width - size.left - size.right, height: size.height - size.top - size.bottom,
+  }));
+
+  for (const x of ticks(view.xMin, view.xMax)) {
+    element("line", { x1: toX(x), x2: toX(x), y1: size.top, y2: size.height - size.bottom, stroke: "#eee" });
+    element("text", { x: toX(x), y: size.height - size.bottom + 18, "text-anchor": "middle" }, format(x));
+  }
+  for (const y of ticks(view.yMin, view.yMax)) {
+    element("line", { x1: size.left, x2: size.width - size.right, y1: toY(y), y2: toY(y), stroke: "#eee" });
+    element("text", { x: size.left - 8, y: toY(y) + 4, "text-anchor": "end" }, format(y));
+  }
+  element("rect", {
+    x: size.left, y: size.top, width: size.width - size.left - size.right, height: size.height - size.top - size.bottom,
+    fill: "none", stroke: "#000",
+  });
+  element("text", { x: (size.left + size.width - size.right) / 2, y: size.height - 15, "text-anchor": "middle" }, graph.x_label);
+  element("text", {
+    x: 0, y: 0, "text-anchor": "middle", transform: `translate(20 ${(size.top + size.height - size.bottom) / 2}) rotate(-90)`,
+  }, graph.y_label);
+
+  graph.series.forEach((series, index) => {
+    if (hidden.has(index)) return;
+    if (series.style === "points") {
+      for (const p of series.points) {
+        element("circle", { cx: toX(p.x), cy: toY(p.y), r: p.radius ?? 4, fill: series.colour, "clip-path": "url(#plot)" });
+      }
+    } else if (series.style === "area") {
+      element("polygon", {
+        points: series.points.map(p => `${toX(p.x)},${toY(p.y)}`).join(" "), fill: series.colour, "fill-opacity": 0.2,
+        stroke: "none", "clip-path": "url(#plot)",
+      });
+    } else {
+      element("polyline", {
+        points: series.points.map(p => `${toX(p.x)},${toY(p.y)}`).join(" "), fill: "none", stroke: series.colour,
+        "stroke-width": 2, "stroke-dasharray": series.style === "dashed_line" ? "6 4" : "none", "clip-path": "url(#plot)",
+      });
+    }
+  });
+}
+
+function toGraph(event) {
+  const box = svg.getBoundingClientRect();
+  return { x: (event.clientX - box.left) * size.width / box.width, y: (event.clientY - box.top) * size.height / box.height };
+}
+
+function nearest(position) {
+  let best = null;
+  graph.series.forEach((series, index) => {
+    if (hidden.has(index)) return;
+    for (const p of series.points) {
+      const distance = Math.hypot(toX(p.x) - position.x, toY(p.y) - position.y);
+      // Prefer the option points over the lines drawn through them.
+      const score = series.style === "points" ? distance - 5 : distance;
+      if (distance < 10 && (best === null || score < best.score)) best = { score, series, point: p };
+    }
+  });
+  return best;
+}
+
+svg.addEventListener("mousedown", event => { dragStart = toGraph(event); });
+svg.addEventListener("mousemove", event => {
+  const position = toGraph(event);
+  if (dragStart !== null) {
+    draw();
+    element("rect", {
+      x: Math.min(dragStart.x, position.x), y: Math.min(dragStart.y, position.y), width: Math.abs(position.x - dragStart.x),
+      height: Math.abs(position.y - dragStart.y), fill: "rgba(0, 0, 255, 0.1)", stroke: "#00f",
+    });
+    return;
+  }
+  const found = nearest(position);
+  if (found === null) {
+    tooltip.style.display = "none";
+    return;
+  }
+  const lines = [found.series.name];
+  if (found.point.label) lines.push(found.point.label);
+  lines.push(`${graph.x_label}: ${format(found.point.x)}`, `${graph.y_label}: ${format(found.point.y)}`);
+  tooltip.textContent = lines.join("\n");
+  tooltip.style.left = `${event.clientX + 12}px`;
+  tooltip.style.top = `${event.clientY + 12}px`;
+  tooltip.style.display = "block";
+});
+svg.addEventListener("mouseup", event => {
+  const position = toGraph(event);
+  if (dragStart !== null && Math.abs(position.x - dragStart.x) > 5 && Math.abs(position.y - dragStart.y) > 5) {
+    view = {
+      xMin: fromX(Math.min(dragStart.x, position.x)), xMax: fromX(Math.max(dragStart.x, position.x)),
+      yMin: fromY(Math.max(dragStart.y, position.y)), yMax: fromY(Math.min(dragStart.y, position.y)),
+    };
+  }
+  dragStart = null;
+  draw();
+});
+svg.addEventListener("mouseleave", () => { tooltip.style.display = "none"; });
+svg.addEventListener("dblclick", () => { view = { ...full }; draw(); });
+
+graph.series.forEach((series, index) => {
+  const entry = document.createElement("span");
+  const swatch = document.createElement("i");
+  swatch.style.background = series.colour;
+  entry.append(swatch, series.name);
+  entry.addEventListener("click", () => {
+    if (hidden.has(index)) hidden.delete(index); else hidden.add(index);
+    entry.classList.toggle("hidden");
+    draw();
+  });
+  legend.appendChild(entry);
+});
+
+draw();
+</script>
+</body>
+</html>
+"##;
+
+/// Save the graph as a single self-contained HTML page, which doesn't need anything else to be downloaded to view it.
+pub fn save_graph_to_html(graph: &HtmlGraph, path: &str) -> Result<(), TsError> {
+    let data = serde_json::to_string(graph)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed serialising graph: {e}")).with_source(e))?;
+    let header = match &graph.header {
+        Some(header) => format!("<p><em>{}</em></p>\n", escape_html(header)),
+        None => String::new(),
+    };
+
+    let html = HTML_GRAPH_TEMPLATE
+        .replace("__TITLE__", &escape_html(&graph.title))
+        .replace("__HEADER__", &header)
+        // Stop any text in the data from ending the script early.
+        .replace("__DATA__", &data.replace("</", "<\\/"));
+
+    fs::write(path, html)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed writing text to path {}: {}", path, e)).with_source(e))
+}
@@ -0,0 +1,28 @@
+use serde_json::Value;
+
+use super::file::{self, open_file_reader};
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+use crate::types::{SchemaVersionHeader, SchemaVersioned, upgrade_versioned_json};
+
+/// Like load_struct_from_file() (including decompressing .zst and .gz files), but upgrades files saved with older schema
+/// versions first, and gives a clear error for files saved by a newer version of the program.
+///
+/// The file is read twice: once to find its schema version, and again to load it. A file saved with the current version is
+/// parsed straight into the struct as it's read, like load_struct_from_file(). Only files that need upgrading are loaded
+/// into memory whole, as the upgrade works on their JSON.
+pub fn load_versioned_struct_from_file<T: SchemaVersioned>(path: &str) -> Result<T, TsError> {
+    let header = serde_json::from_reader::<_, SchemaVersionHeader>(open_file_reader(path)?)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed deserialising object: {}", e)).with_source(e))?;
+
+    let version = header.get_version::<T>(path)?;
+
+    if version == T::SCHEMA_VERSION {
+        return file::load_struct_from_file::<T>(path);
+    }
+
+    let data = serde_json::from_reader::<_, Value>(open_file_reader(path)?)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed deserialising object: {}", e)).with_source(e))?;
+
+    upgrade_versioned_json(data, version, path)
+}
@@ -1,5 +1,66 @@
+mod archive;
+mod csv;
+#[cfg(feature = "storage-sqlite")]
+mod db;
 mod file;
+mod html_graph;
+mod migration;
+mod ndjson;
+mod paths;
+mod report;
+mod table;
+#[cfg(test)]
+mod tests;
 
+pub use crate::types::SchemaVersioned;
+pub use archive::ArchiveEntry;
+pub use archive::ArchiveEntryKind;
+pub use archive::ArchiveManifest;
+pub use archive::load_archive_manifest;
+pub use archive::restore_archive;
+pub use archive::save_archive;
+pub use csv::CsvFormat;
+pub use csv::load_csv_file;
+#[cfg(feature = "storage-sqlite")]
+pub use db::ExpiryMetricsRecord;
+#[cfg(feature = "storage-sqlite")]
+pub use db::QuoteRecord;
+#[cfg(feature = "storage-sqlite")]
+pub use db::SurfaceStore;
+#[cfg(feature = "storage-sqlite")]
+pub use db::SviFitRecord;
 pub use file::clear_directory;
+pub use file::create_directory;
+pub use file::delete_data_file;
+pub use file::delete_file;
+pub use file::file_exists;
+pub use file::find_data_file;
+pub use file::list_files_recursively;
 pub use file::load_struct_from_file;
+pub use file::save_data_file;
 pub use file::save_struct_to_file;
+pub use file::save_text_to_file;
+pub use html_graph::HtmlGraph;
+pub use html_graph::HtmlGraphPoint;
+pub use html_graph::HtmlGraphSeries;
+pub use html_graph::HtmlGraphSeriesStyle;
+pub use html_graph::save_graph_to_html;
+pub use migration::load_versioned_struct_from_file;
+pub use ndjson::NdjsonWriter;
+pub use ndjson::read_ndjson_file;
+pub use paths::DataPaths;
+pub use paths::DataScope;
+pub use paths::get_currency;
+pub use paths::get_data_directory;
+pub use paths::get_data_path;
+pub use paths::get_market_data_path;
+pub use paths::get_market_file_name;
+pub use paths::set_data_paths;
+pub use report::Report;
+pub use report::ReportSection;
+pub use report::save_report_to_html;
+pub use report::save_report_to_markdown;
+pub use table::Table;
+pub use table::TableColumn;
+pub use table::save_table_to_csv;
+pub use table::save_table_to_parquet;
@@ -0,0 +1,74 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufWriter, Write};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use super::file::open_file_reader;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+/// Writes objects to a newline-delimited JSON file, one object per line. This means large files can be written a bit at a
+/// time rather than all at once.
+pub struct NdjsonWriter {
+    writer: BufWriter<File>,
+    path: String,
+}
+
+impl NdjsonWriter {
+    /// Create a new file at the given path, replacing any existing file.
+    pub fn create(path: &str) -> Result<Self, TsError> {
+        let file = File::create(path)
+            .map_err(|e| TsError::new(RuntimeError, format!("Failed creating file at path {path}: {e}")).with_source(e))?;
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+            path: path.to_string(),
+        })
+    }
+
+    /// Open the file at the given path so that new lines are added to the end of it, creating it if it doesn't exist.
+    pub fn append(path: &str) -> Result<Self, TsError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| TsError::new(RuntimeError, format!("Failed opening file at path {path}: {e}")).with_source(e))?;
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+            path: path.to_string(),
+        })
+    }
+
+    /// Write an object as a new line. The line is flushed to disk straight away so that nothing is lost if the program
+    /// dies.
+    pub fn write<T: Serialize>(&mut self, obj: &T) -> Result<(), TsError> {
+        serde_json::to_writer(&mut self.writer, obj)
+            .map_err(|e| TsError::new(RuntimeError, format!("Failed serialising object: {e}")).with_source(e))?;
+
+        self.writer
+            .write_all(b"\n")
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| TsError::new(RuntimeError, format!("Failed writing to path {}: {e}", self.path)).with_source(e))
+    }
+}
+
+/// Read a newline-delimited JSON file one object at a time, so that the whole file never has to be in memory. Blank lines
+/// are ignored. Files ending in .zst or .gz are decompressed as they're read.
+pub fn read_ndjson_file<T: DeserializeOwned>(path: &str) -> Result<impl Iterator<Item = Result<T, TsError>> + use<T>, TsError> {
+    let reader = open_file_reader(path)?;
+    let path = path.to_string();
+
+    Ok(reader
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(text) if text.trim().is_empty()))
+        .map(move |(line_number, line)| {
+            let line =
+                line.map_err(|e| TsError::new(RuntimeError, format!("Failed reading file at path {path}: {e}")).with_source(e))?;
+
+            serde_json::from_str::<T>(&line).map_err(|e| {
+                TsError::new(RuntimeError, format!("Failed deserialising object on line {} of {path}: {e}", line_number + 1))
+            })
+        }))
+}
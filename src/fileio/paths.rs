@@ -0,0 +1,182 @@
+use std::fs;
+use std::sync::RwLock;
+
+use chrono::NaiveDate;
+
+use crate::fileio;
+use crate::helpers;
+use crate::integrations::Currency;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+/// The exchange that market data is downloaded from. Only Deribit is supported so far.
+const EXCHANGE: &str = "deribit";
+
+/// Where data files are read from and saved to, once set by set_data_paths().
+static DATA_PATHS: RwLock<Option<DataPaths>> = RwLock::new(None);
+
+/// How data files are laid out, so that the data of different exchanges, currencies and snapshots can be kept apart
+/// rather than overwriting each other.
+#[derive(serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DataPaths {
+    /// The directory everything is kept in. --data-dir overrides it.
+    pub directory: String,
+    /// Keep each exchange's data in a subdirectory named after it, e.g. deribit.
+    pub per_exchange: bool,
+    /// Keep each currency's data in a subdirectory named after it, e.g. btc (inside the exchange's, if also set).
+    pub per_currency: bool,
+    /// Keep each snapshot's market data, surface, reports and graphs in a subdirectory named after the day it was
+    /// downloaded (YYYY-MM-DD). Histories that build up across snapshots are kept above it.
+    pub per_snapshot_date: bool,
+    /// The snapshot to use, set by resolve_snapshot_date() or --snapshot-date rather than in the config file.
+    #[serde(skip)]
+    pub snapshot_date: Option<NaiveDate>,
+    /// The currency whose data is used, set by --currency rather than in the config file.
+    #[serde(skip)]
+    pub currency: Currency,
+}
+
+impl Default for DataPaths {
+    fn default() -> Self {
+        Self {
+            directory: "./data".to_string(),
+            per_exchange: false,
+            per_currency: false,
+            per_snapshot_date: false,
+            snapshot_date: None,
+            currency: Currency::default(),
+        }
+    }
+}
+
+/// Which of the data files a file belongs with, which decides the directory it's kept in.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DataScope {
+    /// Shared by everything, e.g. the daemon's state and the last run's summary.
+    Global,
+    /// Built up across the snapshots of one exchange and currency, e.g. the surface score history.
+    Market,
+    /// Belonging to one snapshot, e.g. its market data, surface, reports and graphs.
+    Snapshot,
+}
+
+impl DataPaths {
+    pub fn check_valid(&self) -> Result<(), TsError> {
+        if self.directory.trim().is_empty() {
+            return Err(TsError::new(RuntimeError, "data_paths.directory must not be empty"));
+        }
+
+        Ok(())
+    }
+
+    /// Decide which snapshot is used if one wasn't given. A new snapshot is today's (UTC), and otherwise the latest one
+    /// saved is used, or today's if there are none yet. Does nothing unless snapshots are kept apart.
+    pub fn resolve_snapshot_date(&mut self, new_snapshot: bool) -> Result<(), TsError> {
+        if !self.per_snapshot_date || self.snapshot_date.is_some() {
+            return Ok(());
+        }
+
+        let today = helpers::get_now().date_naive();
+
+        self.snapshot_date = Some(match new_snapshot {
+            true => today,
+            false => self.find_latest_snapshot_date()?.unwrap_or(today),
+        });
+
+        Ok(())
+    }
+
+    /// The most recent of the snapshot directories saved, if any.
+    fn find_latest_snapshot_date(&self) -> Result<Option<NaiveDate>, TsError> {
+        let directory = self.get_directory(DataScope::Market);
+
+        if !fs::exists(&directory).unwrap_or(false) {
+            return Ok(None);
+        }
+
+        let entries = fs::read_dir(&directory)
+            .map_err(|e| TsError::new(RuntimeError, format!("Couldn't read directory {directory}: {e}")).with_source(e))?;
+
+        Ok(entries
+            .filter_map(Result::ok)
+            .filter(|x| x.path().is_dir())
+            .filter_map(|x| NaiveDate::parse_from_str(x.file_name().to_str()?, "%Y-%m-%d").ok())
+            .max())
+    }
+
+    /// Create the directories that data files are saved in, if they don't already exist.
+    pub fn create_directories(&self) -> Result<(), TsError> {
+        fileio::create_directory(&self.get_directory(DataScope::Snapshot))
+    }
+
+    /// The directory the files of the scope are kept in, without a trailing slash.
+    pub fn get_directory(&self, scope: DataScope) -> String {
+        let mut directory = self.directory.trim_end_matches('/').to_string();
+
+        if scope == DataScope::Global {
+            return directory;
+        }
+
+        if self.per_exchange {
+            directory = format!("{directory}/{EXCHANGE}");
+        }
+        if self.per_currency {
+            directory = format!("{directory}/{}", self.currency.get_file_code());
+        }
+
+        if scope == DataScope::Snapshot
+            && self.per_snapshot_date
+            && let Some(date) = self.snapshot_date
+        {
+            directory = format!("{directory}/{}", date.format("%Y-%m-%d"));
+        }
+
+        directory
+    }
+}
+
+/// Set where data files are read from and saved to, which is the default (everything directly in ./data) if never set.
+/// Setting it again replaces it, so that a run covering several currencies can move on from one's data to the next's.
+pub fn set_data_paths(paths: DataPaths) {
+    *DATA_PATHS.write().unwrap_or_else(|e| e.into_inner()) = Some(paths);
+}
+
+/// The directory the files of the scope are kept in, without a trailing slash.
+pub fn get_data_directory(scope: DataScope) -> String {
+    match DATA_PATHS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+    {
+        Some(paths) => paths.get_directory(scope),
+        None => DataPaths::default().get_directory(scope),
+    }
+}
+
+/// The currency whose data is being read and saved.
+pub fn get_currency() -> Currency {
+    DATA_PATHS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .map(|x| x.currency)
+        .unwrap_or_default()
+}
+
+/// The name of a file of the exchange's market data for the currency, e.g. deribit-btc-market-data.json for the name
+/// market-data.json.
+pub fn get_market_file_name(name: &str) -> String {
+    format!("{EXCHANGE}-{}-{name}", get_currency().get_file_code())
+}
+
+/// The path of a file of the exchange's market data for the currency in the scope's directory (see
+/// get_market_file_name()).
+pub fn get_market_data_path(scope: DataScope, name: &str) -> String {
+    get_data_path(scope, &get_market_file_name(name))
+}
+
+/// The path of a data file (or directory, if the name ends with /) in the scope's directory.
+pub fn get_data_path(scope: DataScope, name: &str) -> String {
+    format!("{}/{name}", get_data_directory(scope))
+}
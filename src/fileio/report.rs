@@ -0,0 +1,125 @@
+use std::fs;
+
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+/// A simple document of titled sections, each with some paragraphs and optionally a table, that can be saved as
+/// markdown or HTML for people to read.
+pub struct Report {
+    pub title: String,
+    /// Shown under the title, e.g. the desk and environment the report was made for.
+    pub header: Option<String>,
+    pub sections: Vec<ReportSection>,
+}
+
+#[derive(Default)]
+pub struct ReportSection {
+    pub heading: String,
+    pub paragraphs: Vec<String>,
+    /// The column headings, followed by the rows.
+    pub table: Option<(Vec<String>, Vec<Vec<String>>)>,
+}
+
+impl Report {
+    pub fn new(title: impl Into<String>) -> Self {
+        Self {
+            title: title.into(),
+            header: None,
+            sections: Vec::new(),
+        }
+    }
+
+    fn to_markdown(&self) -> String {
+        let mut text = format!("# {}\n", self.title);
+
+        if let Some(header) = &self.header {
+            text.push_str(&format!("\n*{header}*\n"));
+        }
+
+        for section in &self.sections {
+            text.push_str(&format!("\n## {}\n", section.heading));
+
+            for paragraph in &section.paragraphs {
+                text.push_str(&format!("\n{paragraph}\n"));
+            }
+
+            if let Some((headings, rows)) = &section.table {
+                let escape = |x: &String| x.replace('|', "\\|");
+
+                text.push_str(&format!("\n| {} |\n", headings.iter().map(escape).collect::<Vec<_>>().join(" | ")));
+                text.push_str(&format!("|{}\n", " --- |".repeat(headings.len())));
+
+                for row in rows {
+                    text.push_str(&format!("| {} |\n", row.iter().map(escape).collect::<Vec<_>>().join(" | ")));
+                }
+            }
+        }
+
+        text
+    }
+
+    fn to_html(&self) -> String {
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n<style>\
+             body {{ font-family: sans-serif; margin: 2em; }} table {{ border-collapse: collapse; }} \
+             th, td {{ border: 1px solid #ccc; padding: 4px 8px; text-align: left; }}</style>\n</head>\n<body>\n\
+             <h1>{title}</h1>\n",
+            title = escape_html(&self.title)
+        );
+
+        if let Some(header) = &self.header {
+            html.push_str(&format!("<p><em>{}</em></p>\n", escape_html(header)));
+        }
+
+        for section in &self.sections {
+            html.push_str(&format!("<h2>{}</h2>\n", escape_html(&section.heading)));
+
+            for paragraph in &section.paragraphs {
+                html.push_str(&format!("<p>{}</p>\n", escape_html(paragraph)));
+            }
+
+            if let Some((headings, rows)) = &section.table {
+                html.push_str("<table>\n<tr>");
+
+                for heading in headings {
+                    html.push_str(&format!("<th>{}</th>", escape_html(heading)));
+                }
+
+                html.push_str("</tr>\n");
+
+                for row in rows {
+                    html.push_str("<tr>");
+
+                    for value in row {
+                        html.push_str(&format!("<td>{}</td>", escape_html(value)));
+                    }
+
+                    html.push_str("</tr>\n");
+                }
+
+                html.push_str("</table>\n");
+            }
+        }
+
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+}
+
+pub(super) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn save_report_to_markdown(report: &Report, path: &str) -> Result<(), TsError> {
+    fs::write(path, report.to_markdown())
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed writing text to path {}: {}", path, e)).with_source(e))
+}
+
+/// Save the report as a single self-contained HTML page.
+pub fn save_report_to_html(report: &Report, path: &str) -> Result<(), TsError> {
+    fs::write(path, report.to_html())
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed writing text to path {}: {}", path, e)).with_source(e))
+}
@@ -0,0 +1,144 @@
+use std::fs::{self, File};
+use std::sync::Arc;
+
+use chrono::DateTime;
+use parquet::data_type::{DoubleType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+/// The values of one column in a table.
+pub enum TableColumn {
+    /// Milliseconds since the epoch (UTC).
+    Timestamp(Vec<i64>),
+    Float(Vec<f64>),
+}
+
+impl TableColumn {
+    fn len(&self) -> usize {
+        match self {
+            TableColumn::Timestamp(v) => v.len(),
+            TableColumn::Float(v) => v.len(),
+        }
+    }
+
+    fn get_parquet_field(&self, name: &str) -> String {
+        match self {
+            TableColumn::Timestamp(_) => format!("REQUIRED INT64 {name} (TIMESTAMP(MILLIS,true));"),
+            TableColumn::Float(_) => format!("REQUIRED DOUBLE {name};"),
+        }
+    }
+
+    fn format_value(&self, row: usize) -> Result<String, TsError> {
+        match self {
+            TableColumn::Timestamp(v) => DateTime::from_timestamp_millis(v[row])
+                .map(|x| x.to_rfc3339())
+                .ok_or(TsError::new(RuntimeError, format!("Invalid timestamp {}", v[row]))),
+            TableColumn::Float(v) => Ok(v[row].to_string()),
+        }
+    }
+}
+
+/// A simple table of named columns, used to export data in formats that are easy to analyse with other tools.
+#[derive(Default)]
+pub struct Table {
+    columns: Vec<(String, TableColumn)>,
+}
+
+impl Table {
+    pub fn add_column(&mut self, name: impl Into<String>, column: TableColumn) -> Result<(), TsError> {
+        let name = name.into();
+
+        if let Some((_, first)) = self.columns.first()
+            && first.len() != column.len()
+        {
+            return Err(TsError::new(
+                RuntimeError,
+                format!("Column {name} has {} rows but the table has {}", column.len(), first.len()),
+            ));
+        }
+
+        self.columns.push((name, column));
+
+        Ok(())
+    }
+
+    fn rows_count(&self) -> usize {
+        self.columns
+            .first()
+            .map(|(_, column)| column.len())
+            .unwrap_or(0)
+    }
+}
+
+/// Save the table as a CSV file with a header row. Timestamps are written in RFC 3339 format.
+pub fn save_table_to_csv(table: &Table, path: &str) -> Result<(), TsError> {
+    let mut text = table
+        .columns
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<&str>>()
+        .join(",");
+    text.push('\n');
+
+    for row in 0..table.rows_count() {
+        let values = table
+            .columns
+            .iter()
+            .map(|(_, column)| column.format_value(row))
+            .collect::<Result<Vec<String>, TsError>>()?;
+
+        text.push_str(&values.join(","));
+        text.push('\n');
+    }
+
+    fs::write(path, text)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed writing text to path {}: {}", path, e)).with_source(e))
+}
+
+/// Save the table as an Apache Parquet file with a single row group.
+pub fn save_table_to_parquet(table: &Table, path: &str) -> Result<(), TsError> {
+    let parquet_error =
+        |e: parquet::errors::ParquetError| TsError::new(RuntimeError, format!("Failed writing {path}: {e}")).with_source(e);
+
+    let fields = table
+        .columns
+        .iter()
+        .map(|(name, column)| column.get_parquet_field(name))
+        .collect::<Vec<String>>()
+        .join(" ");
+    let schema = Arc::new(parse_message_type(&format!("message table {{ {fields} }}")).map_err(parquet_error)?);
+
+    let file = File::create(path)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed creating file at path {path}: {e}")).with_source(e))?;
+    let mut writer =
+        SerializedFileWriter::new(file, schema, Arc::new(WriterProperties::builder().build())).map_err(parquet_error)?;
+    let mut row_group = writer.next_row_group().map_err(parquet_error)?;
+
+    for (_, column) in &table.columns {
+        let mut column_writer = row_group
+            .next_column()
+            .map_err(parquet_error)?
+            .ok_or(TsError::new(RuntimeError, "Parquet schema has fewer columns than the table"))?;
+
+        match column {
+            TableColumn::Timestamp(v) => column_writer
+                .typed::<Int64Type>()
+                .write_batch(v, None, None),
+            TableColumn::Float(v) => column_writer
+                .typed::<DoubleType>()
+                .write_batch(v, None, None),
+        }
+        .map_err(parquet_error)?;
+
+        column_writer.close().map_err(parquet_error)?;
+    }
+
+    row_group.close().map_err(parquet_error)?;
+    writer.close().map_err(parquet_error)?;
+
+    Ok(())
+}
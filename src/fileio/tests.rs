@@ -0,0 +1,430 @@
+#![cfg(test)]
+
+use crate::types::TsError;
+
+use super::*;
+
+fn european_format() -> CsvFormat {
+    CsvFormat {
+        delimiter: ';',
+        decimal_separator: ',',
+        thousands_separator: Some('.'),
+        date_format: "%d/%m/%Y".to_string(),
+    }
+}
+
+#[test]
+fn test_load_csv_file() {
+    let path = std::env::temp_dir().join("theta-surface-test-load-csv-file.csv");
+    let path = path.to_str().expect("Temp path should be valid");
+
+    std::fs::write(
+        path,
+        "instrument_name;price;expiry\n\n\"BTC-27MAR26-60000-C\";1.234,5;27/03/2026\nBTC-27MAR26-70000-C;12,25;x\n",
+    )
+    .expect("Should be able to write temp file");
+
+    let table = load_csv_file(path, european_format()).expect("Should load");
+
+    assert_eq!(table.rows_count(), 2);
+    assert_eq!(table.get_str(0, "instrument_name").expect("Should exist"), "BTC-27MAR26-60000-C");
+    assert_eq!(table.get_f64(0, "price").expect("Should parse"), 1234.5);
+    assert_eq!(table.get_f64(1, "price").expect("Should parse"), 12.25);
+    assert_eq!(
+        table.get_date(0, "expiry").expect("Should parse"),
+        chrono::NaiveDate::from_ymd_opt(2026, 3, 27).expect("Should be valid")
+    );
+
+    // Errors should point to the line in the file, accounting for the header and blank lines.
+    let error = table.get_date(1, "expiry").expect_err("Should fail");
+    assert!(error.reason.contains("line 3, column 'expiry', value 'x'"), "{}", error.reason);
+
+    assert!(table.get_f64(0, "missing").is_err());
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn test_csv_number_separators() {
+    let path = std::env::temp_dir().join("theta-surface-test-csv-number-separators.csv");
+    let path = path.to_str().expect("Temp path should be valid");
+
+    std::fs::write(path, "price\n1.5\n\"1,000.5\"\n").expect("Should be able to write temp file");
+
+    // Without a thousands separator, a comma is unexpected and should be an error rather than a guess.
+    let table = load_csv_file(path, CsvFormat::default()).expect("Should load");
+    assert_eq!(table.get_f64(0, "price").expect("Should parse"), 1.5);
+    assert!(table.get_f64(1, "price").is_err());
+
+    let format = CsvFormat {
+        thousands_separator: Some(','),
+        ..CsvFormat::default()
+    };
+    let table = load_csv_file(path, format).expect("Should load");
+    assert_eq!(table.get_f64(1, "price").expect("Should parse"), 1000.5);
+
+    // The thousands separator can't also be the decimal separator.
+    let format = CsvFormat {
+        thousands_separator: Some('.'),
+        ..CsvFormat::default()
+    };
+    assert!(load_csv_file(path, format).is_err());
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn test_ndjson_round_trip() {
+    let path = std::env::temp_dir().join("theta-surface-test-ndjson-round-trip.ndjson");
+    let path = path.to_str().expect("Temp path should be valid");
+
+    let mut writer = NdjsonWriter::create(path).expect("Should create");
+    writer.write(&vec![1, 2]).expect("Should write");
+    drop(writer);
+
+    // Appending should keep the existing lines.
+    let mut writer = NdjsonWriter::append(path).expect("Should open");
+    writer.write(&vec![3]).expect("Should write");
+    drop(writer);
+
+    let values = read_ndjson_file::<Vec<u32>>(path)
+        .expect("Should open")
+        .collect::<Result<Vec<Vec<u32>>, TsError>>()
+        .expect("Should read");
+    assert_eq!(values, vec![vec![1, 2], vec![3]]);
+
+    // Compressed files are decompressed as they're read.
+    let compressed_path = format!("{path}.gz");
+    save_struct_to_file(&1, &compressed_path).expect("Should save");
+    let values = read_ndjson_file::<u32>(&compressed_path)
+        .expect("Should open")
+        .collect::<Result<Vec<u32>, TsError>>()
+        .expect("Should read");
+    assert_eq!(values, vec![1]);
+
+    let _ = std::fs::remove_file(path);
+    let _ = std::fs::remove_file(compressed_path);
+}
+
+#[test]
+fn test_compressed_data_files() {
+    let path = std::env::temp_dir().join("theta-surface-test-compressed-data-files.json");
+    let path = path.to_str().expect("Temp path should be valid");
+    let data = vec![1.5, 2.5, 3.5];
+
+    // The compression is chosen by the extension.
+    for extension in [".zst", ".gz"] {
+        let compressed_path = format!("{path}{extension}");
+        save_struct_to_file(&data, &compressed_path).expect("Should save");
+        assert_ne!(std::fs::read(&compressed_path).expect("Should read")[0], b'[');
+        assert_eq!(load_struct_from_file::<Vec<f64>>(&compressed_path).expect("Should load"), data);
+    }
+
+    // Saving a data file replaces copies with other compression, so the new one is always found.
+    assert_eq!(save_data_file(&data, path, false).expect("Should save"), path);
+    assert!(!file_exists(&format!("{path}.zst")) && !file_exists(&format!("{path}.gz")));
+    assert_eq!(find_data_file(path), path);
+
+    let compressed_path = save_data_file(&data, path, true).expect("Should save");
+    assert!(!file_exists(path));
+    assert_eq!(find_data_file(path), compressed_path);
+    assert_eq!(load_struct_from_file::<Vec<f64>>(&find_data_file(path)).expect("Should load"), data);
+
+    let _ = std::fs::remove_file(compressed_path);
+}
+
+#[test]
+fn test_error_sources() {
+    use std::error::Error;
+
+    let path = std::env::temp_dir().join("theta-surface-test-error-sources-missing.json");
+    let path = path.to_str().expect("Temp path should be valid");
+
+    // The I/O error is kept as the source, and context wraps the error without losing its type or reason.
+    let error = load_struct_from_file::<Vec<f64>>(path)
+        .expect_err("Should fail")
+        .context("Loading prices");
+    assert_eq!(error.error_type, crate::types::TsErrorType::RuntimeError);
+    assert!(
+        error
+            .to_string()
+            .starts_with(&format!("Loading prices: Failed reading file at path {path}: "))
+    );
+
+    let inner = error
+        .source()
+        .and_then(|x| x.downcast_ref::<TsError>())
+        .expect("Should have the original error as its source");
+    assert!(inner.reason.starts_with("Failed reading file"));
+
+    let io_error = inner
+        .source()
+        .and_then(|x| x.downcast_ref::<std::io::Error>())
+        .expect("Should have the I/O error as its source");
+    assert_eq!(io_error.kind(), std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn test_save_table() {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    let csv_path = std::env::temp_dir().join("theta-surface-test-save-table.csv");
+    let csv_path = csv_path.to_str().expect("Temp path should be valid");
+    let parquet_path = std::env::temp_dir().join("theta-surface-test-save-table.parquet");
+    let parquet_path = parquet_path.to_str().expect("Temp path should be valid");
+
+    let mut table = Table::default();
+    table
+        .add_column("expiry", TableColumn::Timestamp(vec![0, 86_400_000]))
+        .expect("Should add");
+    table
+        .add_column("strike", TableColumn::Float(vec![50000.0, 60000.5]))
+        .expect("Should add");
+    assert!(
+        table
+            .add_column("bad", TableColumn::Float(vec![1.0]))
+            .is_err()
+    );
+
+    save_table_to_csv(&table, csv_path).expect("Should save");
+    assert_eq!(
+        std::fs::read_to_string(csv_path).expect("Should read"),
+        "expiry,strike\n1970-01-01T00:00:00+00:00,50000\n1970-01-02T00:00:00+00:00,60000.5\n"
+    );
+
+    save_table_to_parquet(&table, parquet_path).expect("Should save");
+    let reader = SerializedFileReader::new(std::fs::File::open(parquet_path).expect("Should open")).expect("Should read");
+    assert_eq!(reader.metadata().file_metadata().num_rows(), 2);
+    assert_eq!(
+        reader
+            .metadata()
+            .file_metadata()
+            .schema_descr()
+            .num_columns(),
+        2
+    );
+
+    let _ = std::fs::remove_file(csv_path);
+    let _ = std::fs::remove_file(parquet_path);
+}
+
+#[test]
+fn test_save_graph_to_html() {
+    let path = std::env::temp_dir().join("theta-surface-test-save-graph.html");
+    let path = path.to_str().expect("Temp path should be valid");
+
+    let graph = HtmlGraph {
+        title: "Smile <1>".to_string(),
+        header: Some("Desk & co".to_string()),
+        x_label: "Strike".to_string(),
+        y_label: "Implied volatility".to_string(),
+        series: vec![HtmlGraphSeries {
+            name: "Options".to_string(),
+            colour: "blue".to_string(),
+            style: HtmlGraphSeriesStyle::Points,
+            points: vec![HtmlGraphPoint {
+                x: 50000.0,
+                y: 0.5,
+                label: Some("</script>".to_string()),
+                radius: None,
+            }],
+        }],
+    };
+
+    save_graph_to_html(&graph, path).expect("Should save");
+    let html = std::fs::read_to_string(path).expect("Should read");
+
+    assert!(html.contains("<h1>Smile &lt;1&gt;</h1>"));
+    assert!(html.contains("<p><em>Desk &amp; co</em></p>"));
+    assert!(html.contains(r#""x":50000.0,"y":0.5,"label":"<\/script>""#));
+    assert_eq!(html.matches("</script>").count(), 1);
+
+    let _ = std::fs::remove_file(path);
+}
+
+/// Version 1 renamed price to mark_price, and version 2 added a currency.
+#[derive(serde::Deserialize, Debug, PartialEq)]
+struct VersionedQuote {
+    schema_version: u32,
+    mark_price: f64,
+    currency: String,
+}
+
+impl SchemaVersioned for VersionedQuote {
+    const SCHEMA_VERSION: u32 = 2;
+
+    fn migrate(data: &mut serde_json::Value, from_version: u32) -> Result<(), TsError> {
+        let object = data.as_object_mut().expect("Should be an object");
+
+        match from_version {
+            0 => {
+                let price = object.remove("price").expect("Should have a price");
+                object.insert("mark_price".to_string(), price);
+            }
+            _ => {
+                object.insert("currency".to_string(), "USD".into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_load_versioned_struct_from_file() {
+    let path = std::env::temp_dir().join("theta-surface-test-load-versioned-struct.json");
+    let path = path.to_str().expect("Temp path should be valid");
+    let expected = VersionedQuote {
+        schema_version: 2,
+        mark_price: 1.5,
+        currency: "USD".to_string(),
+    };
+
+    // Files from before schema versions are upgraded through every version.
+    std::fs::write(path, r#"{"price": 1.5}"#).expect("Should write");
+    assert_eq!(load_versioned_struct_from_file::<VersionedQuote>(path).expect("Should load"), expected);
+
+    std::fs::write(path, r#"{"schema_version": 1, "mark_price": 1.5}"#).expect("Should write");
+    assert_eq!(load_versioned_struct_from_file::<VersionedQuote>(path).expect("Should load"), expected);
+
+    // Files saved with the current version are loaded as they are, compressed or not.
+    let current = r#"{"schema_version": 2, "mark_price": 1.5, "currency": "USD"}"#;
+    std::fs::write(path, current).expect("Should write");
+    assert_eq!(load_versioned_struct_from_file::<VersionedQuote>(path).expect("Should load"), expected);
+
+    let compressed_path = format!("{path}.zst");
+    std::fs::write(&compressed_path, zstd::encode_all(current.as_bytes(), 3).expect("Should compress")).expect("Should write");
+    assert_eq!(load_versioned_struct_from_file::<VersionedQuote>(&compressed_path).expect("Should load"), expected);
+    let _ = std::fs::remove_file(compressed_path);
+
+    // Files from a newer version can't be loaded.
+    std::fs::write(path, r#"{"schema_version": 3, "mark_price": 1.5, "currency": "USD"}"#).expect("Should write");
+    let error = load_versioned_struct_from_file::<VersionedQuote>(path).expect_err("Should fail");
+    assert!(error.reason.contains("newer version"));
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn test_archive_round_trip() {
+    let root = std::env::temp_dir().join("theta-surface-test-archive-round-trip");
+    let _ = std::fs::remove_dir_all(&root);
+    let source = root.join("source");
+    let destination = root.join("destination");
+    let archive_path = root.join("archive.tar.gz");
+    let archive_path = archive_path.to_str().expect("Temp path should be valid");
+
+    std::fs::create_dir_all(source.join("graphs")).expect("Should create directory");
+    std::fs::write(source.join("smile-graph-data.json"), "{}").expect("Should write");
+    std::fs::write(source.join("graphs").join("smile.png"), [1, 2, 3]).expect("Should write");
+
+    let source_path = source.to_str().expect("Temp path should be valid");
+    let files = list_files_recursively(source_path).expect("Should list files");
+    assert_eq!(files.len(), 2);
+
+    let manifest = ArchiveManifest {
+        created_at: chrono::Utc::now(),
+        from: None,
+        to: None,
+        files: vec![
+            ArchiveEntry {
+                path: "smile-graph-data.json".to_string(),
+                kind: ArchiveEntryKind::Fit,
+                modified_at: chrono::Utc::now(),
+                size_bytes: 2,
+            },
+            ArchiveEntry {
+                path: "graphs/smile.png".to_string(),
+                kind: ArchiveEntryKind::Report,
+                modified_at: chrono::Utc::now(),
+                size_bytes: 3,
+            },
+        ],
+    };
+    save_archive(&manifest, source_path, archive_path).expect("Should save archive");
+    assert_eq!(
+        load_archive_manifest(archive_path)
+            .expect("Should load manifest")
+            .files
+            .len(),
+        2
+    );
+
+    let destination_path = destination.to_str().expect("Temp path should be valid");
+    restore_archive(archive_path, destination_path, false).expect("Should restore");
+    assert_eq!(std::fs::read(destination.join("graphs").join("smile.png")).expect("Should read"), vec![1, 2, 3]);
+    assert_eq!(std::fs::read_to_string(destination.join("smile-graph-data.json")).expect("Should read"), "{}");
+
+    // Existing files are only replaced when asked.
+    assert!(restore_archive(archive_path, destination_path, false).is_err());
+    restore_archive(archive_path, destination_path, true).expect("Should restore");
+
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[cfg(feature = "storage-sqlite")]
+#[test]
+fn test_surface_store_round_trip() {
+    let path = std::env::temp_dir().join("theta-surface-test-surface-store.sqlite");
+    let path = path.to_str().expect("Temp path should be valid");
+    let _ = std::fs::remove_file(path);
+
+    let snapshot_time = chrono::DateTime::from_timestamp_millis(1771594909882).expect("Should be valid");
+    let fit = |days: i64, a: f64| SviFitRecord {
+        expiry: snapshot_time + chrono::Duration::days(days),
+        a,
+        b: 0.1,
+        p: -0.3,
+        m: 0.02,
+        o: 0.15,
+        forward_price: 68000.0,
+        option_count: 40,
+    };
+
+    let mut store = SurfaceStore::open(path).expect("Should open");
+    store
+        .save_svi_fits(snapshot_time, &[fit(30, 0.04), fit(7, 0.02)])
+        .expect("Should save");
+    assert_eq!(store.load_svi_fits(snapshot_time).expect("Should load"), vec![fit(7, 0.02), fit(30, 0.04)]);
+
+    // Saving a snapshot again replaces it, including after reopening.
+    drop(store);
+    let mut store = SurfaceStore::open(path).expect("Should reopen");
+    store
+        .save_svi_fits(snapshot_time, &[fit(7, 0.03)])
+        .expect("Should save");
+    assert_eq!(store.load_svi_fits(snapshot_time).expect("Should load"), vec![fit(7, 0.03)]);
+    assert!(
+        store
+            .load_svi_fits(snapshot_time + chrono::Duration::days(1))
+            .expect("Should load")
+            .is_empty()
+    );
+
+    drop(store);
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn test_data_paths_get_directory() {
+    let mut paths = DataPaths {
+        directory: "/tmp/data/".to_string(),
+        ..Default::default()
+    };
+
+    assert_eq!(paths.get_directory(DataScope::Global), "/tmp/data");
+    assert_eq!(paths.get_directory(DataScope::Snapshot), "/tmp/data");
+
+    paths.per_exchange = true;
+    paths.per_currency = true;
+    paths.per_snapshot_date = true;
+    paths.snapshot_date = chrono::NaiveDate::from_ymd_opt(2026, 3, 27);
+
+    assert_eq!(paths.get_directory(DataScope::Global), "/tmp/data");
+    assert_eq!(paths.get_directory(DataScope::Market), "/tmp/data/deribit/btc");
+    assert_eq!(paths.get_directory(DataScope::Snapshot), "/tmp/data/deribit/btc/2026-03-27");
+
+    paths.currency = crate::integrations::Currency::Eth;
+
+    assert_eq!(paths.get_directory(DataScope::Market), "/tmp/data/deribit/eth");
+}
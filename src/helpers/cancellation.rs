@@ -0,0 +1,49 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "cli")]
+use crate::types::TsError;
+#[cfg(feature = "cli")]
+use crate::types::TsErrorType::RuntimeError;
+
+/// The exit code of a process stopped by a second Ctrl-C, the same as a shell gives a process killed by SIGINT.
+#[cfg(feature = "cli")]
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// Lets long-running work be asked to stop early, so that it can finish what it's in the middle of and save what it has
+/// done rather than being killed part way through. Clones share the same state, so the token can be handed to each
+/// thread doing the work.
+#[derive(Clone, Default, Debug)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// A token that is cancelled by Ctrl-C. A second Ctrl-C exits straight away, for when the work in progress is taking
+    /// too long to finish. Only one token can be made this way.
+    #[cfg(feature = "cli")]
+    pub fn from_ctrl_c() -> Result<Self, TsError> {
+        let token = Self::default();
+        let handler_token = token.clone();
+
+        ctrlc::set_handler(move || {
+            if handler_token.is_cancelled() {
+                std::process::exit(INTERRUPTED_EXIT_CODE);
+            }
+
+            println!("Stopping after the work in progress, press Ctrl-C again to stop straight away...");
+            handler_token.cancel();
+        })
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed listening for Ctrl-C: {e}")).with_source(e))?;
+
+        Ok(token)
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,68 @@
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+/// Deribit options expire at 08:00 UTC, so we assume the same when only a date is given.
+const DEFAULT_EXPIRY_TIME: NaiveTime = NaiveTime::from_hms_opt(8, 0, 0).expect("Default expiry time should be valid");
+
+/// Returns true if the given flag (e.g. --ndjson) was passed on the command line.
+pub fn has_flag(args: &[String], flag: &str) -> bool {
+    args.iter().any(|a| a == flag)
+}
+
+/// Get the value passed after the given flag (e.g. the 100 in --strike 100). Returns None if the flag wasn't passed, or an
+/// error if it was passed without a value.
+pub fn get_flag_value<'a>(args: &'a [String], flag: &str) -> Result<Option<&'a str>, TsError> {
+    let Some(position) = args.iter().position(|a| a == flag) else {
+        return Ok(None);
+    };
+
+    match args.get(position + 1) {
+        Some(value) if !value.starts_with("--") => Ok(Some(value.as_str())),
+        _ => Err(TsError::new(RuntimeError, format!("Expected a value after {flag}"))),
+    }
+}
+
+/// Like get_flag_value(), but the value must be a single character.
+pub fn get_flag_char(args: &[String], flag: &str) -> Result<Option<char>, TsError> {
+    let Some(value) = get_flag_value(args, flag)? else {
+        return Ok(None);
+    };
+
+    let mut chars = value.chars();
+
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(Some(c)),
+        _ => Err(TsError::new(RuntimeError, format!("Expected a single character after {flag}, found '{value}'"))),
+    }
+}
+
+/// Like get_flag_value(), but the value must be a day, given as YYYY-MM-DD.
+pub fn get_flag_date(args: &[String], flag: &str) -> Result<Option<NaiveDate>, TsError> {
+    get_flag_value(args, flag)?
+        .map(|v| {
+            NaiveDate::parse_from_str(v, "%Y-%m-%d")
+                .map_err(|e| TsError::new(RuntimeError, format!("Invalid {flag} {v}, expected YYYY-MM-DD: {e}")).with_source(e))
+        })
+        .transpose()
+}
+
+/// Like get_flag_value(), but the value must be an expiry, given as YYYY-MM-DD (assumed to be 08:00 UTC) or an RFC 3339
+/// timestamp.
+pub fn get_flag_expiry(args: &[String], flag: &str) -> Result<Option<DateTime<Utc>>, TsError> {
+    let Some(value) = get_flag_value(args, flag)? else {
+        return Ok(None);
+    };
+
+    let expiry = match NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        Ok(date) => date.and_time(DEFAULT_EXPIRY_TIME).and_utc(),
+        Err(_) => DateTime::parse_from_rfc3339(value)
+            .map_err(|e| {
+                TsError::new(RuntimeError, format!("Invalid expiry {value}, expected YYYY-MM-DD or RFC 3339: {e}")).with_source(e)
+            })?
+            .to_utc(),
+    };
+
+    Ok(Some(expiry))
+}
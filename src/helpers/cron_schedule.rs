@@ -0,0 +1,135 @@
+use chrono::{DateTime, Datelike, Days, Duration, DurationRound, Months, NaiveTime, Timelike, Utc};
+
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+/// How many times next_after() moves forward looking for a matching time before deciding there isn't one. Each move is
+/// at least a minute, and a whole month when the month doesn't match, so this covers years of schedule.
+const MAX_SEARCH_STEPS: usize = 100_000;
+
+/// A cron schedule with the usual five fields (minute, hour, day of month, month and day of week), in UTC. Each field can
+/// be *, a number, a range (1-5), a step (*/15 or 0-30/10) or a comma separated list of these. Days of the week run from
+/// 0 (Sunday) to 6, and 7 is also Sunday. As in cron, if both the day of month and day of week are restricted, a day
+/// matching either is used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    days_of_month_restricted: bool,
+    days_of_week_restricted: bool,
+}
+
+impl CronSchedule {
+    pub fn parse(expression: &str) -> Result<Self, TsError> {
+        let fields = expression.split_whitespace().collect::<Vec<&str>>();
+
+        let [minutes, hours, days_of_month, months, days_of_week] = fields[..] else {
+            return Err(TsError::new(
+                RuntimeError,
+                format!("Invalid schedule {expression}, expected 5 fields (minute hour day-of-month month day-of-week)"),
+            ));
+        };
+
+        let mut days_of_week_mask = parse_field(days_of_week, 0, 7, "day of week")?;
+
+        // 7 is another way of writing Sunday.
+        if days_of_week_mask & (1 << 7) != 0 {
+            days_of_week_mask = (days_of_week_mask | 1) & !(1 << 7);
+        }
+
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59, "minute")?,
+            hours: parse_field(hours, 0, 23, "hour")?,
+            days_of_month: parse_field(days_of_month, 1, 31, "day of month")?,
+            months: parse_field(months, 1, 12, "month")?,
+            days_of_week: days_of_week_mask,
+            days_of_month_restricted: !days_of_month.starts_with('*'),
+            days_of_week_restricted: !days_of_week.starts_with('*'),
+        })
+    }
+
+    /// The first time after the given one (to the minute) that the schedule matches, or None if it never does (e.g. the
+    /// 31st of February).
+    pub fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut time = time.duration_trunc(Duration::minutes(1)).ok()? + Duration::minutes(1);
+
+        for _ in 0..MAX_SEARCH_STEPS {
+            if !has_bit(self.months, time.month()) {
+                time = time.with_day(1)?.with_time(NaiveTime::MIN).single()? + Months::new(1);
+            } else if !self.matches_day(time) {
+                time = time.with_time(NaiveTime::MIN).single()? + Days::new(1);
+            } else if !has_bit(self.hours, time.hour()) {
+                time = time.with_minute(0)? + Duration::hours(1);
+            } else if !has_bit(self.minutes, time.minute()) {
+                time += Duration::minutes(1);
+            } else {
+                return Some(time);
+            }
+        }
+
+        None
+    }
+
+    fn matches_day(&self, time: DateTime<Utc>) -> bool {
+        let day_of_month = has_bit(self.days_of_month, time.day());
+        let day_of_week = has_bit(self.days_of_week, time.weekday().num_days_from_sunday());
+
+        match (self.days_of_month_restricted, self.days_of_week_restricted) {
+            (true, true) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        }
+    }
+}
+
+fn has_bit(mask: u64, value: u32) -> bool {
+    mask & (1 << value) != 0
+}
+
+/// Parse one field of a cron expression into a mask with a bit set for each value it matches.
+fn parse_field(field: &str, min: u32, max: u32, name: &str) -> Result<u64, TsError> {
+    let error = || TsError::new(RuntimeError, format!("Invalid {name} {field} in schedule, values must be {min}-{max}"));
+    let parse_value = |x: &str| {
+        x.parse::<u32>()
+            .ok()
+            .filter(|x| (min..=max).contains(x))
+            .ok_or_else(error)
+    };
+
+    let mut mask = 0;
+
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (
+                range,
+                Some(
+                    step.parse::<u32>()
+                        .ok()
+                        .filter(|x| *x > 0)
+                        .ok_or_else(error)?,
+                ),
+            ),
+            None => (item, None),
+        };
+
+        let (start, end) = match (range, range.split_once('-')) {
+            ("*", _) => (min, max),
+            (_, Some((start, end))) => (parse_value(start)?, parse_value(end)?),
+            // With a step, a single value is where the step starts from, e.g. 5/10 is 5, 15, 25...
+            (_, None) if step.is_some() => (parse_value(range)?, max),
+            (_, None) => (parse_value(range)?, parse_value(range)?),
+        };
+
+        if start > end {
+            return Err(error());
+        }
+
+        for value in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            mask |= 1 << value;
+        }
+    }
+
+    Ok(mask)
+}
@@ -1,9 +1,23 @@
+mod cancellation;
+mod cli_helpers;
+mod cron_schedule;
 mod formatting_helpers;
+mod progress;
 mod time_helpers;
 mod validation_helpers;
 
+pub use cancellation::CancellationToken;
+pub use cli_helpers::get_flag_char;
+pub use cli_helpers::get_flag_date;
+pub use cli_helpers::get_flag_expiry;
+pub use cli_helpers::get_flag_value;
+pub use cli_helpers::has_flag;
+pub use cron_schedule::CronSchedule;
 pub use formatting_helpers::F64Helpers;
+pub use progress::ProgressBar;
+pub use time_helpers::Clock;
+pub use time_helpers::format_time;
 pub use time_helpers::get_now;
-pub use time_helpers::set_now;
+pub use time_helpers::set_display_timezone;
 pub use validation_helpers::error_unless_positive_f64;
 pub use validation_helpers::error_unless_valid_f64;
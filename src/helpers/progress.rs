@@ -0,0 +1,143 @@
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+/// How often a progress bar is printed while work is ongoing.
+const PRINT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The width of the bar in characters.
+const BAR_WIDTH: u64 = 20;
+
+/// Reports progress through a long-running task, with an estimate of how long is left. Progress bars can be nested, in
+/// which case the child is indented beneath its parent. In quiet mode nothing is printed, which is useful for scripting.
+pub struct ProgressBar {
+    label: String,
+    total: u64,
+    completed: u64,
+    /// Items that were completed without doing any work (e.g. restored from a checkpoint). These aren't used to estimate
+    /// the remaining time.
+    skipped: u64,
+    started: Instant,
+    last_printed: Option<Instant>,
+    depth: usize,
+    quiet: bool,
+}
+
+impl ProgressBar {
+    pub fn new(label: impl Into<String>, total: u64, quiet: bool) -> Self {
+        Self {
+            label: label.into(),
+            total,
+            completed: 0,
+            skipped: 0,
+            started: Instant::now(),
+            last_printed: None,
+            depth: 0,
+            quiet,
+        }
+    }
+
+    /// Create a progress bar for a sub-task of this one.
+    pub fn new_child(&self, label: impl Into<String>, total: u64) -> Self {
+        Self {
+            depth: self.depth + 1,
+            ..Self::new(label, total, self.quiet)
+        }
+    }
+
+    /// Mark an item as completed.
+    pub fn increment(&mut self) {
+        self.completed += 1;
+
+        let print_due = self
+            .last_printed
+            .is_none_or(|x| x.elapsed() >= PRINT_INTERVAL);
+
+        if print_due || self.completed >= self.total {
+            self.print();
+        }
+    }
+
+    /// Mark all items as completed. Useful when the total was only an estimate.
+    pub fn finish(&mut self) {
+        if self.completed < self.total || self.last_printed.is_none() {
+            self.completed = self.total;
+            self.print();
+        }
+    }
+
+    /// Mark an item as completed without having done any work for it.
+    pub fn skip(&mut self) {
+        self.completed += 1;
+        self.skipped += 1;
+    }
+
+    /// Print a message beneath this progress bar, unless in quiet mode.
+    pub fn message(&self, message: impl Display) {
+        if self.quiet {
+            return;
+        }
+
+        println!("{}{message}", self.get_indent());
+    }
+
+    fn get_indent(&self) -> String {
+        "  ".repeat(self.depth)
+    }
+
+    /// Estimate the time remaining based on how fast items have been completed so far.
+    fn get_remaining_time(&self) -> Option<Duration> {
+        let worked = self.completed - self.skipped;
+
+        if worked == 0 {
+            return None;
+        }
+
+        let remaining = self.total.saturating_sub(self.completed);
+
+        Some(
+            self.started
+                .elapsed()
+                .mul_f64(remaining as f64 / worked as f64),
+        )
+    }
+
+    fn print(&mut self) {
+        self.last_printed = Some(Instant::now());
+
+        if self.quiet {
+            return;
+        }
+
+        // The total can be an estimate, so don't go over 100%.
+        let completed = self.completed.min(self.total);
+        let fraction = match self.total {
+            0 => 1.0,
+            total => completed as f64 / total as f64,
+        };
+        let filled = (fraction * BAR_WIDTH as f64).round() as u64;
+        let bar = format!("{}{}", "#".repeat(filled as usize), "-".repeat((BAR_WIDTH - filled) as usize));
+        let remaining = match self.get_remaining_time() {
+            Some(v) => format_duration(v),
+            None => "unknown".to_string(),
+        };
+
+        println!(
+            "{}{} [{bar}] {}% ({completed}/{}), ETA {remaining}",
+            self.get_indent(),
+            self.label,
+            (fraction * 100.0).floor(),
+            self.total
+        );
+    }
+}
+
+/// Format a duration in a short human-readable way, e.g. 1h 02m or 3m 20s.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+
+    match seconds {
+        0..60 => format!("{seconds}s"),
+        60..3600 => format!("{}m {:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {:02}m", seconds / 3600, (seconds % 3600) / 60),
+    }
+}
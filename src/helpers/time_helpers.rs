@@ -1,11 +1,46 @@
+use std::sync::OnceLock;
+
 use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+
+/// When the real clock was first read.
+static REAL_NOW: OnceLock<DateTime<Utc>> = OnceLock::new();
+
+/// The time zone times are shown in. Stored data always uses UTC.
+static DISPLAY_TIMEZONE: OnceLock<Tz> = OnceLock::new();
+
+/// Where the current time comes from. Times until expiry are measured from it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Clock {
+    /// The real time. This is read the first time it's needed and then kept for the rest of the run, so that every
+    /// calculation in a run agrees on what now is.
+    #[default]
+    Real,
+    /// Always the given time, e.g. for tests or for reading a surface as of when it was built.
+    Fixed(DateTime<Utc>),
+}
 
-static mut SAVED_NOW: Option<DateTime<Utc>> = None;
+impl Clock {
+    pub fn now(&self) -> DateTime<Utc> {
+        match self {
+            Clock::Real => *REAL_NOW.get_or_init(Utc::now),
+            Clock::Fixed(now) => *now,
+        }
+    }
+}
 
+/// The time now according to the real clock.
 pub fn get_now() -> DateTime<Utc> {
-    unsafe { SAVED_NOW.unwrap() }
+    Clock::Real.now()
+}
+
+/// Set the time zone that format_time() uses. Can only be set once, and is UTC if never set.
+pub fn set_display_timezone(timezone: Tz) {
+    let _ = DISPLAY_TIMEZONE.set(timezone);
 }
 
-pub fn set_now(now: DateTime<Utc>) {
-    unsafe { SAVED_NOW = Some(now) }
+/// Format a time for showing to the user (in the console or graphs), in the display time zone.
+pub fn format_time(time: DateTime<Utc>) -> String {
+    time.with_timezone(DISPLAY_TIMEZONE.get().unwrap_or(&Tz::UTC))
+        .to_rfc3339()
 }
@@ -1,19 +1,25 @@
+use chrono::{DateTime, Datelike, Utc};
 use rust_decimal::Decimal;
 use rust_decimal::prelude::ToPrimitive;
 
 use crate::{
-    analytics::{OptionInstrument, OptionType},
+    analytics::{ExpiryCategory, ForwardQuote, OptionInstrument, OptionType, PerpetualQuote, PricingModel},
+    fileio::SchemaVersioned,
+    helpers::{self, Clock},
     types::TsError,
-    types::TsErrorType::UnusableAPIData,
+    types::TsErrorType::{RuntimeError, UnusableAPIData},
 };
 
-#[derive(serde::Deserialize, serde::Serialize)]
+/// Prices moved by re-anchoring are rounded to this many decimal places, well below Deribit's tick sizes.
+const REANCHORED_PRICE_DECIMAL_PLACES: u32 = 8;
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct DeribitTickSizeStep {
     pub tick_size: Decimal,
     pub above_price: Decimal,
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct DeribitTickerStats {
     pub high: Option<Decimal>,
     pub low: Option<Decimal>,
@@ -22,7 +28,7 @@ pub struct DeribitTickerStats {
     pub volume_usd: Decimal,
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct DeribitTickerGreeks {
     pub theta: Decimal,
     pub delta: Decimal,
@@ -31,7 +37,7 @@ pub struct DeribitTickerGreeks {
     pub rho: Decimal,
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct DeribitTickerData {
     pub timestamp: u64,
     pub state: Box<str>,
@@ -58,7 +64,86 @@ pub struct DeribitTickerData {
     pub delivery_price: Option<Decimal>,
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
+/// A currency whose options Deribit lists. Each has its own options, futures and perpetual, which are downloaded and fit
+/// separately.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Currency {
+    #[default]
+    Btc,
+    Eth,
+}
+
+impl TryFrom<&str> for Currency {
+    type Error = TsError;
+
+    fn try_from(currency: &str) -> Result<Self, TsError> {
+        match currency.trim().to_ascii_uppercase().as_str() {
+            "BTC" => Ok(Currency::Btc),
+            "ETH" => Ok(Currency::Eth),
+            _ => Err(TsError::new(RuntimeError, format!("Unknown currency {currency}, expected BTC or ETH"))),
+        }
+    }
+}
+
+impl Currency {
+    /// The currencies passed with --currency as a comma separated list, e.g. BTC,ETH, in the order given. Empty if the flag
+    /// isn't passed.
+    pub fn list_from_args(args: &[String]) -> Result<Vec<Currency>, TsError> {
+        let Some(list) = helpers::get_flag_value(args, "--currency")? else {
+            return Ok(Vec::new());
+        };
+        let mut currencies = Vec::new();
+
+        for currency in list.split(',').map(Currency::try_from) {
+            let currency = currency?;
+
+            if currencies.contains(&currency) {
+                return Err(TsError::new(RuntimeError, format!("--currency lists {} more than once", currency.get_code())));
+            }
+
+            currencies.push(currency);
+        }
+
+        Ok(currencies)
+    }
+
+    /// The code Deribit uses for the currency, e.g. BTC.
+    pub fn get_code(&self) -> &'static str {
+        match self {
+            Currency::Btc => "BTC",
+            Currency::Eth => "ETH",
+        }
+    }
+
+    /// The code in lower case, which is how the currency is named in file and directory names, e.g. btc.
+    pub fn get_file_code(&self) -> &'static str {
+        match self {
+            Currency::Btc => "btc",
+            Currency::Eth => "eth",
+        }
+    }
+
+    /// The name of the currency, for showing to the user.
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Currency::Btc => "Bitcoin",
+            Currency::Eth => "Ether",
+        }
+    }
+}
+
+/// How Deribit's market data is read.
+#[derive(serde::Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeribitSettings {
+    /// How options quoted in their own coin (BTC for Bitcoin's, ETH for Ether's) are priced. They are inverse options, paid
+    /// for and settled in the coin, which the inverse model prices as Deribit does. Defaults to Black-Scholes, so that
+    /// surfaces stay comparable with those built by earlier versions. Options quoted in USD are always priced with
+    /// Black-Scholes.
+    pub btc_pricing_model: PricingModel,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct DeribitOptionInstrument {
     pub price_index: Box<str>,
     pub kind: Box<str>,
@@ -88,31 +173,27 @@ pub struct DeribitOptionInstrument {
 }
 
 impl DeribitOptionInstrument {
-    pub fn to_option(&self) -> Result<OptionInstrument, TsError> {
+    /// Convert to the internal format. Fails with UnusableAPIData, naming the field, if any field the option needs is
+    /// missing or invalid, so that the option can be skipped.
+    pub fn to_option(&self, settings: &DeribitSettings) -> Result<OptionInstrument, TsError> {
         let ticker_data = self
             .ticker_data
             .as_ref()
-            .ok_or(TsError::new(UnusableAPIData, "Failed getting ticker data"))?;
-        let index_price = ticker_data
-            .index_price
-            .to_f64()
-            .ok_or(TsError::new(UnusableAPIData, "Failed converting index price to f64"))?;
-        let mark_price = ticker_data
-            .mark_price
-            .to_f64()
-            .ok_or(TsError::new(UnusableAPIData, "Failed converting mark price to f64"))?;
-        let strike_price = self
-            .strike
-            .to_f64()
-            .ok_or(TsError::new(UnusableAPIData, "Failed converting strike price to f64"))?;
-        let best_ask_price = ticker_data
-            .best_ask_price
-            .to_f64()
-            .ok_or(TsError::new(UnusableAPIData, "Failed converting best ask price to f64"))?;
+            .ok_or(TsError::new(UnusableAPIData, "ticker_data is missing"))?;
+        let index_price = get_positive_f64(ticker_data.index_price, "index_price")?;
+        let mark_price = get_f64(ticker_data.mark_price, "mark_price")?;
+        let strike_price = self.get_strike()?;
+        let best_ask_price = get_f64(ticker_data.best_ask_price, "best_ask_price")?;
+        let best_bid_price = get_f64(ticker_data.best_bid_price, "best_bid_price")?;
+        let option_type = OptionType::try_from(self.option_type.as_ref())
+            .map_err(|e| TsError::new(UnusableAPIData, format!("option_type is invalid: {}", e.reason)).with_source(e))?;
+
+        // The option stores its expiry in seconds, so check the timestamp is valid first.
+        self.get_expiration()?;
 
         let price = match self.quote_currency.as_ref() {
             "USD" => mark_price,
-            "BTC" => {
+            coin if coin == self.base_currency.as_ref() => {
                 // Some very illiquid options can be missing a mark price.
                 if mark_price > 0.0 {
                     mark_price * index_price
@@ -120,22 +201,454 @@ impl DeribitOptionInstrument {
                     best_ask_price * index_price
                 }
             }
-            other => return Err(TsError::new(UnusableAPIData, format!("Unknown currency {other}"))),
+            other => return Err(TsError::new(UnusableAPIData, format!("quote_currency {other} is unknown"))),
+        };
+        let usd_multiplier = match self.quote_currency.as_ref() {
+            "USD" => 1.0,
+            _ => index_price,
         };
 
-        Ok(OptionInstrument::new(
+        let mut option = OptionInstrument::new(
             price,
             self.expiration_timestamp / 1000,
             strike_price,
             self.instrument_id.to_string().into_boxed_str(),
-            OptionType::try_from(self.option_type.as_ref())?,
+            option_type,
             index_price,
+            Clock::Real,
+        );
+
+        // A price of zero means there is no bid or ask.
+        option.bid_price = (best_bid_price > 0.0).then_some(best_bid_price * usd_multiplier);
+        option.ask_price = (best_ask_price > 0.0).then_some(best_ask_price * usd_multiplier);
+        option.pricing_model = match self.quote_currency.as_ref() {
+            "USD" => PricingModel::BlackScholes,
+            _ => settings.btc_pricing_model,
+        };
+        // Deribit gives implied volatilities as percentages.
+        option.exchange_implied_volatility = ticker_data
+            .mark_iv
+            .and_then(|x| x.to_f64())
+            .filter(|x| *x > 0.0)
+            .map(|x| x / 100.0);
+        option.open_interest = ticker_data.open_interest.to_f64();
+        option.volume = ticker_data.stats.volume.to_f64();
+        option.quote_time = DateTime::from_timestamp_millis(ticker_data.timestamp as i64);
+
+        Ok(option)
+    }
+
+    pub fn get_expiration(&self) -> Result<DateTime<Utc>, TsError> {
+        DateTime::from_timestamp_millis(self.expiration_timestamp as i64).ok_or(TsError::new(
+            UnusableAPIData,
+            format!("expiration_timestamp {} is invalid", self.expiration_timestamp),
         ))
     }
+
+    pub fn get_strike(&self) -> Result<f64, TsError> {
+        get_positive_f64(self.strike, "strike")
+    }
+
+    /// How often expiries like the option's are listed, from its settlement period. Deribit doesn't mark quarterlies, so
+    /// they are the monthlies of March, June, September and December. Missing if the settlement period isn't known.
+    pub fn get_expiry_category(&self) -> Option<ExpiryCategory> {
+        match self.settlement_period.as_ref() {
+            "day" => Some(ExpiryCategory::Daily),
+            "week" => Some(ExpiryCategory::Weekly),
+            "month" => match self.get_expiration().ok()?.month() % 3 {
+                0 => Some(ExpiryCategory::Quarterly),
+                _ => Some(ExpiryCategory::Monthly),
+            },
+            _ => None,
+        }
+    }
+
+    /// Deribit's forward price for the option's expiry (its future's, or a synthetic one if there's no future), or the
+    /// index price if it doesn't give one. Missing if there's no usable ticker data.
+    pub fn get_underlying_price(&self) -> Option<f64> {
+        let ticker_data = self.ticker_data.as_ref()?;
+
+        ticker_data
+            .underlying_price
+            .unwrap_or(ticker_data.index_price)
+            .to_f64()
+            .filter(|x| *x > 0.0)
+    }
+
+    /// When the ticker data was recorded, if there is any.
+    pub fn get_data_time(&self) -> Result<Option<DateTime<Utc>>, TsError> {
+        let Some(ticker_data) = &self.ticker_data else {
+            return Ok(None);
+        };
+
+        DateTime::from_timestamp_millis(ticker_data.timestamp as i64)
+            .map(Some)
+            .ok_or(TsError::new(UnusableAPIData, format!("ticker_data.timestamp {} is invalid", ticker_data.timestamp)))
+    }
+
+    /// The price of the future this option is priced from, if it's priced from a real future that expires at the same
+    /// time. Expiries without one are priced from a synthetic underlying (named SYN.*), which isn't a future's price.
+    pub fn get_futures_quote(&self) -> Result<Option<ForwardQuote>, TsError> {
+        let Some(ticker_data) = &self.ticker_data else {
+            return Ok(None);
+        };
+        let (Some(underlying_index), Some(underlying_price)) = (&ticker_data.underlying_index, ticker_data.underlying_price)
+        else {
+            return Ok(None);
+        };
+
+        if underlying_index.starts_with("SYN.") {
+            return Ok(None);
+        }
+
+        Ok(Some(ForwardQuote {
+            price: get_positive_f64(underlying_price, "underlying_price")?,
+            time: DateTime::from_timestamp_millis(ticker_data.timestamp as i64)
+                .ok_or(TsError::new(UnusableAPIData, format!("ticker_data.timestamp {} is invalid", ticker_data.timestamp)))?,
+        }))
+    }
+
+    /// Move the option's quote to the anchor, as if it had been quoted at the same time as the rest of the snapshot. The
+    /// index price becomes the anchor's, the underlying price moves in proportion, and the mark, bid and ask prices move
+    /// by the option's delta times the index move (a first order adjustment, keeping the quote's implied volatility).
+    /// Options without a delta only have their index price replaced, as before. The ticker timestamp is left as when the
+    /// option was actually quoted. A quote without a positive index price can't be moved, so its ticker data is cleared,
+    /// leaving the option to be discarded as unquoted. Only fails if the anchor itself is invalid.
+    pub fn reanchor(&mut self, anchor: SnapshotAnchor) -> Result<Reanchored, TsError> {
+        if anchor.index_price <= Decimal::ZERO {
+            return Err(TsError::new(
+                UnusableAPIData,
+                format!("Quotes can't be re-anchored to index price {}", anchor.index_price),
+            ));
+        }
+
+        let Some(ticker_data) = self.ticker_data.as_mut() else {
+            return Ok(Reanchored::Unchanged);
+        };
+        let quoted_index_price = ticker_data.index_price;
+
+        if quoted_index_price <= Decimal::ZERO {
+            self.ticker_data = None;
+            return Ok(Reanchored::Discarded);
+        }
+
+        ticker_data.index_price = anchor.index_price;
+
+        if quoted_index_price == anchor.index_price {
+            return Ok(Reanchored::Unchanged);
+        }
+
+        ticker_data.underlying_price = ticker_data
+            .underlying_price
+            .map(|x| (x * anchor.index_price / quoted_index_price).round_dp(REANCHORED_PRICE_DECIMAL_PLACES));
+
+        let Some(delta) = ticker_data.greeks.as_ref().map(|x| x.delta) else {
+            return Ok(Reanchored::Unchanged);
+        };
+        let index_move = anchor.index_price - quoted_index_price;
+        // Prices of options quoted in the coin are converted to USD to be moved, then back at the anchor's index price.
+        let (quoted_usd_multiplier, anchor_usd_multiplier) = match self.quote_currency.as_ref() {
+            "USD" => (Decimal::ONE, Decimal::ONE),
+            _ => (quoted_index_price, anchor.index_price),
+        };
+        let move_price = |price: Decimal| -> Decimal {
+            // A price of zero means there is no bid or ask, which stays so.
+            if price <= Decimal::ZERO {
+                return price;
+            }
+
+            ((price * quoted_usd_multiplier + delta * index_move).max(Decimal::ZERO) / anchor_usd_multiplier)
+                .round_dp(REANCHORED_PRICE_DECIMAL_PLACES)
+        };
+
+        ticker_data.mark_price = move_price(ticker_data.mark_price);
+        ticker_data.best_bid_price = move_price(ticker_data.best_bid_price);
+        ticker_data.best_ask_price = move_price(ticker_data.best_ask_price);
+
+        Ok(Reanchored::Adjusted)
+    }
+}
+
+/// What DeribitOptionInstrument::reanchor() did to an option's quote.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Reanchored {
+    /// The quote's prices were moved to the anchor.
+    Adjusted,
+    /// At most the index price was replaced, e.g. because the option has no delta or no ticker data.
+    Unchanged,
+    /// The quote had no positive index price to move it from, so its ticker data was cleared.
+    Discarded,
+}
+
+/// How many options re-anchoring adjusted and discarded.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ReanchorCounts {
+    pub adjusted: usize,
+    pub discarded: usize,
+    pub total: usize,
+}
+
+impl ReanchorCounts {
+    pub fn add(&mut self, reanchored: Reanchored) {
+        self.adjusted += (reanchored == Reanchored::Adjusted) as usize;
+        self.discarded += (reanchored == Reanchored::Discarded) as usize;
+        self.total += 1;
+    }
+}
+
+/// A trade of an option, as returned by get_last_trades_by_currency_and_time.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct DeribitTrade {
+    pub trade_id: Box<str>,
+    pub timestamp: u64,
+    pub instrument_name: Box<str>,
+    pub price: Decimal,
+    /// The option's mark price at the time of the trade.
+    pub mark_price: Decimal,
+    pub index_price: Decimal,
+    /// The implied volatility of the trade's price, in percent.
+    pub iv: Option<Decimal>,
+    pub underlying_price: Option<Decimal>,
+    pub underlying_index: Option<Box<str>>,
+}
+
+impl DeribitTrade {
+    /// The option's ticker data as it was at the time of the trade, as far as the trade records it. Anything the trade
+    /// doesn't record is zero or missing, which means there was no bid or ask.
+    pub fn to_ticker_data(&self) -> DeribitTickerData {
+        DeribitTickerData {
+            timestamp: self.timestamp,
+            state: "open".into(),
+            stats: DeribitTickerStats {
+                high: None,
+                low: None,
+                price_change: None,
+                volume: Decimal::ZERO,
+                volume_usd: Decimal::ZERO,
+            },
+            greeks: None,
+            index_price: self.index_price,
+            instrument_name: self.instrument_name.clone(),
+            last_price: Some(self.price),
+            min_price: Decimal::ZERO,
+            max_price: Decimal::ZERO,
+            open_interest: Decimal::ZERO,
+            mark_price: self.mark_price,
+            best_ask_price: Decimal::ZERO,
+            best_bid_price: Decimal::ZERO,
+            interest_rate: None,
+            mark_iv: self.iv,
+            bid_iv: None,
+            ask_iv: None,
+            underlying_price: self.underlying_price,
+            underlying_index: self.underlying_index.clone(),
+            estimated_delivery_price: self.index_price,
+            best_ask_amount: Decimal::ZERO,
+            best_bid_amount: Decimal::ZERO,
+            delivery_price: None,
+        }
+    }
+}
+
+/// A page of trades. There are more in the requested time range if has_more is set.
+#[derive(serde::Deserialize)]
+pub struct DeribitTradesPage {
+    pub trades: Vec<DeribitTrade>,
+    pub has_more: bool,
+}
+
+/// Sort options by expiry, then strike, then name, so that data saved from them is in the same order between runs.
+pub fn sort_options(options: &mut [DeribitOptionInstrument]) {
+    options.sort_by(|a, b| {
+        a.expiration_timestamp
+            .cmp(&b.expiration_timestamp)
+            .then(a.strike.cmp(&b.strike))
+            .then(a.instrument_name.cmp(&b.instrument_name))
+    });
+}
+
+/// The ticker data of the perpetual future, which is downloaded separately from the options.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct DeribitPerpetualTickerData {
+    pub timestamp: u64,
+    pub instrument_name: Box<str>,
+    pub mark_price: Decimal,
+    pub index_price: Decimal,
+    /// The funding rate over the last 8 hours.
+    pub funding_8h: Option<Decimal>,
+}
+
+impl DeribitPerpetualTickerData {
+    pub fn to_perpetual_quote(&self) -> Result<PerpetualQuote, TsError> {
+        let funding_8h = self
+            .funding_8h
+            .ok_or(TsError::new(UnusableAPIData, "Perpetual has no funding rate"))?
+            .to_f64()
+            .ok_or(TsError::new(UnusableAPIData, "Failed converting funding rate to f64"))?;
+
+        Ok(PerpetualQuote {
+            price: self
+                .mark_price
+                .to_f64()
+                .ok_or(TsError::new(UnusableAPIData, "Failed converting mark price to f64"))?,
+            // Funding is paid every 8 hours.
+            annual_funding_rate: funding_8h * 3.0 * 365.0,
+            time: DateTime::from_timestamp_millis(self.timestamp as i64)
+                .ok_or(TsError::new(UnusableAPIData, "Failed creating timestamp from ticker timestamp"))?,
+        })
+    }
+}
+
+/// A future that expires on a fixed date, as returned by get_instruments, along with its ticker data.
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct DeribitFuture {
+    pub instrument_name: Box<str>,
+    pub expiration_timestamp: u64,
+    /// "perpetual" for the perpetual future, which doesn't expire.
+    pub settlement_period: Box<str>,
+    pub ticker_data: Option<DeribitFuturesTickerData>,
+}
+
+/// The ticker data of a future.
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct DeribitFuturesTickerData {
+    pub timestamp: u64,
+    pub mark_price: Decimal,
+    pub index_price: Decimal,
+}
+
+impl DeribitFuture {
+    pub fn is_perpetual(&self) -> bool {
+        self.settlement_period.as_ref() == "perpetual"
+    }
+
+    /// The future's price, which is the forward price at its expiry. Missing if there's no ticker data.
+    pub fn get_forward_quote(&self) -> Result<Option<ForwardQuote>, TsError> {
+        let Some(ticker_data) = &self.ticker_data else {
+            return Ok(None);
+        };
+
+        Ok(Some(ForwardQuote {
+            price: get_positive_f64(ticker_data.mark_price, "mark_price")?,
+            time: DateTime::from_timestamp_millis(ticker_data.timestamp as i64)
+                .ok_or(TsError::new(UnusableAPIData, format!("ticker_data.timestamp {} is invalid", ticker_data.timestamp)))?,
+        }))
+    }
 }
 
 /// A simple place to store all the data - this will make it easy to save and load from file.
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct DeribitDataContainer {
+    pub schema_version: u32,
     pub options: Vec<DeribitOptionInstrument>,
+    /// When the most recent ticker data was captured, which times until expiry are measured from when building the
+    /// surface. Missing from data saved by older versions.
+    #[serde(default)]
+    pub snapshot_time: Option<DateTime<Utc>>,
+    /// The futures that expire on a fixed date, whose prices are the forward prices at their expiries. Missing from data
+    /// saved by older versions, whose forward prices come from the options' own underlying prices instead.
+    #[serde(default)]
+    pub futures: Vec<DeribitFuture>,
+}
+
+impl SchemaVersioned for DeribitDataContainer {
+    const SCHEMA_VERSION: u32 = 1;
+
+    fn migrate(_data: &mut serde_json::Value, from_version: u32) -> Result<(), TsError> {
+        match from_version {
+            // Every field added before schema versions has a default, so these files load as they are.
+            0 => Ok(()),
+            _ => Err(TsError::new(RuntimeError, format!("No migration from schema version {from_version}"))),
+        }
+    }
+}
+
+/// The moment a snapshot's quotes are re-anchored to: that of its most recent ticker data, and the index price then.
+#[derive(Clone, Copy, Debug)]
+pub struct SnapshotAnchor {
+    /// Milliseconds since the Unix epoch, like the ticker data's timestamps.
+    pub timestamp: u64,
+    pub index_price: Decimal,
+}
+
+impl SnapshotAnchor {
+    /// The later of the anchor and the option's ticker data, so that the anchor of a file of options can be found one
+    /// option at a time. Ticker data without a positive index price is skipped, as nothing can be anchored to it.
+    pub fn latest(anchor: Option<SnapshotAnchor>, option: &DeribitOptionInstrument) -> Option<SnapshotAnchor> {
+        let Some(ticker_data) = option
+            .ticker_data
+            .as_ref()
+            .filter(|x| x.index_price > Decimal::ZERO)
+        else {
+            return anchor;
+        };
+
+        match anchor {
+            Some(v) if v.timestamp >= ticker_data.timestamp => Some(v),
+            _ => Some(SnapshotAnchor {
+                timestamp: ticker_data.timestamp,
+                index_price: ticker_data.index_price,
+            }),
+        }
+    }
+
+    /// The anchor of the options, missing if none of them have ticker data with a positive index price.
+    pub fn find(options: &[DeribitOptionInstrument]) -> Option<SnapshotAnchor> {
+        options.iter().fold(None, SnapshotAnchor::latest)
+    }
+}
+
+/// Re-anchor every option to the anchor of the most recent quote (see DeribitOptionInstrument::reanchor()), printing how
+/// many were adjusted and discarded.
+pub fn reanchor_options(options: &mut [DeribitOptionInstrument]) -> Result<ReanchorCounts, TsError> {
+    let anchor = SnapshotAnchor::find(options)
+        .ok_or(TsError::new(RuntimeError, "None of the options have ticker data with a positive index price"))?;
+    let mut counts = ReanchorCounts::default();
+
+    for option in options.iter_mut() {
+        counts.add(option.reanchor(anchor)?);
+    }
+
+    print_reanchoring(anchor, counts);
+
+    Ok(counts)
+}
+
+pub fn print_reanchoring(anchor: SnapshotAnchor, counts: ReanchorCounts) {
+    let time =
+        DateTime::from_timestamp_millis(anchor.timestamp as i64).map_or(anchor.timestamp.to_string(), helpers::format_time);
+
+    println!(
+        "Re-anchored the quotes to index price {} at {time}, adjusting the prices of {}/{} options",
+        anchor.index_price, counts.adjusted, counts.total
+    );
+
+    if counts.discarded > 0 {
+        println!("Discarded the quotes of {} options without a positive index price", counts.discarded);
+    }
+}
+
+/// When the most recent ticker data of the options was captured, if any have ticker data.
+pub fn get_snapshot_time(options: &[DeribitOptionInstrument]) -> Option<DateTime<Utc>> {
+    options
+        .iter()
+        .filter_map(|x| x.ticker_data.as_ref())
+        .map(|x| x.timestamp)
+        .max()
+        .and_then(|x| DateTime::from_timestamp_millis(x as i64))
+}
+
+/// Convert a number from the API data, naming the field if it can't be.
+fn get_f64(value: Decimal, field: &str) -> Result<f64, TsError> {
+    value
+        .to_f64()
+        .ok_or(TsError::new(UnusableAPIData, format!("{field} {value} can't be converted to f64")))
+}
+
+/// Like get_f64(), but the number must also be above zero.
+fn get_positive_f64(value: Decimal, field: &str) -> Result<f64, TsError> {
+    match get_f64(value, field)? {
+        x if x > 0.0 => Ok(x),
+        x => Err(TsError::new(UnusableAPIData, format!("{field} must be greater than zero, found {x}"))),
+    }
 }
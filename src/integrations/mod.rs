@@ -1,5 +1,19 @@
 mod deribit;
 
+pub use deribit::Currency;
 pub use deribit::DeribitDataContainer;
+pub use deribit::DeribitFuture;
+pub use deribit::DeribitFuturesTickerData;
 pub use deribit::DeribitOptionInstrument;
+pub use deribit::DeribitPerpetualTickerData;
+pub use deribit::DeribitSettings;
 pub use deribit::DeribitTickerData;
+pub use deribit::DeribitTrade;
+pub use deribit::DeribitTradesPage;
+pub use deribit::ReanchorCounts;
+pub use deribit::Reanchored;
+pub use deribit::SnapshotAnchor;
+pub use deribit::get_snapshot_time;
+pub use deribit::print_reanchoring;
+pub use deribit::reanchor_options;
+pub use deribit::sort_options;
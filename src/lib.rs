@@ -0,0 +1,23 @@
+//! Builds implied volatility surfaces from Bitcoin option data. The analytics have no heavy dependencies, so the maths can
+//! be used on its own by turning off the default features, which leaves out the command line program (the cli feature)
+//! and everything it reads and saves. The wasm feature adds bindings for building to WebAssembly for use from JavaScript,
+//! and the ffi feature adds C functions.
+
+pub mod analytics;
+#[cfg(feature = "cli")]
+pub mod config;
+pub mod constants;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "cli")]
+pub mod fileio;
+pub mod helpers;
+#[cfg(feature = "cli")]
+pub mod integrations;
+#[cfg(feature = "network")]
+pub mod network;
+#[cfg(feature = "cli")]
+pub mod routines;
+pub mod types;
+#[cfg(feature = "wasm")]
+pub mod wasm;
@@ -1,29 +1,195 @@
-use chrono::Utc;
+use std::panic::{self, AssertUnwindSafe};
 
-use crate::helpers::set_now;
+use theta_surface::helpers::{self, CancellationToken, set_display_timezone};
+use theta_surface::integrations::Currency;
+use theta_surface::routines::RunSummary;
+use theta_surface::types::TsError;
+use theta_surface::types::TsErrorType::RuntimeError;
+use theta_surface::{config, fileio, routines};
 
-mod analytics;
-mod constants;
-mod fileio;
-mod helpers;
-mod integrations;
-mod network;
-mod routines;
-mod types;
+/// The commands, in the order they are looked for in the arguments.
+const COMMANDS: [&str; 24] = [
+    "fetch-market-data",
+    "build-surface",
+    "import-vol-matrix",
+    "build-greek-graphs",
+    "build-graphs",
+    "build-term-structure",
+    "export-history",
+    "export",
+    "merge-snapshots",
+    "query",
+    "price-option",
+    "scenario",
+    "simulate",
+    "skew-report",
+    "surface-metrics",
+    "digest",
+    "calibrate-heston",
+    "archive",
+    "restore",
+    "backfill",
+    "daemon",
+    "compare-assets",
+    "scan-arbitrage",
+    "serve-grpc",
+];
 
-#[tokio::main]
-async fn main() {
-    set_now(Utc::now());
+/// The commands that stop cleanly on Ctrl-C, saving the work they have finished. Any other command is killed by it as
+/// usual.
+const CANCELLABLE_COMMANDS: [&str; 2] = ["fetch-market-data", "build-surface"];
 
+/// The commands that can be given more than one currency with --currency. They are run for each in turn, apart from
+/// compare-assets, which reads them all at once.
+const MULTI_CURRENCY_COMMANDS: [&str; 4] = ["fetch-market-data", "build-surface", "compare-assets", "scan-arbitrage"];
+
+fn main() {
     let args: Vec<String> = std::env::args().collect();
 
-    if args.iter().any(|a| a == "fetch-market-data") {
-        routines::fetch_market_data().await
-    } else if args.iter().any(|a| a == "build-surface") {
-        routines::build_surface();
-    } else if args.iter().any(|a| a == "build-graphs") {
-        routines::build_graphs();
-    } else {
+    let Some(command) = COMMANDS.into_iter().find(|x| args.iter().any(|a| a == x)) else {
         routines::help();
+        return;
+    };
+
+    // Panics are caught so that they are recorded in the run summary too.
+    let mut run = RunSummary::new(command);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| run_command(command, &args, &mut run)));
+
+    std::process::exit(run.finish(result, helpers::has_flag(&args, "--json")));
+}
+
+fn run_command(command: &str, args: &[String], run: &mut RunSummary) -> Result<(), TsError> {
+    let mut config = config::Config::load(args).map_err(|e| e.context("Failed loading config"))?;
+
+    if let Some(timezone) = config.display_timezone {
+        set_display_timezone(timezone);
+    }
+
+    let currencies = Currency::list_from_args(args)?;
+
+    // Each currency's data is kept apart once one is chosen, so that the surfaces of several can sit side by side.
+    if !currencies.is_empty() {
+        config.data_paths.per_currency = true;
     }
+    if currencies.len() > 1 && !MULTI_CURRENCY_COMMANDS.contains(&command) {
+        return Err(TsError::new(RuntimeError, format!("{command} can only be run for one currency at a time")));
+    }
+
+    #[cfg(not(feature = "storage-sqlite"))]
+    if args.iter().any(|a| a == "--db") {
+        routines::missing_feature("--db", "storage-sqlite")?;
+    }
+
+    let cancel = match CANCELLABLE_COMMANDS.contains(&command) {
+        true => CancellationToken::from_ctrl_c()?,
+        false => CancellationToken::default(),
+    };
+    // compare-assets is only run once, with the first currency's data.
+    let run_currencies = match (command, currencies.first()) {
+        (_, None) => vec![config.data_paths.currency],
+        ("compare-assets", Some(first)) => vec![*first],
+        _ => currencies,
+    };
+    let snapshot_date = config.data_paths.snapshot_date;
+
+    for currency in run_currencies {
+        if cancel.is_cancelled() {
+            break;
+        }
+
+        config.data_paths.currency = currency;
+        config.data_paths.snapshot_date = snapshot_date;
+
+        // Downloading market data starts a new snapshot, while everything else (including fetching offline, which can
+        // only reuse a saved one) works with the latest one.
+        config
+            .data_paths
+            .resolve_snapshot_date(command == "fetch-market-data" && !helpers::has_flag(args, "--offline"))
+            .and_then(|_| config.data_paths.create_directories())
+            .map_err(|e| e.context("Failed setting up the data directory"))?;
+        fileio::set_data_paths(config.data_paths.clone());
+
+        run_currency_command(command, args, &config, run, &cancel)?;
+    }
+
+    if cancel.is_cancelled() {
+        run.cancel();
+    }
+
+    Ok(())
+}
+
+/// Run the command with the data of the currency set by fileio::set_data_paths().
+fn run_currency_command(
+    command: &str,
+    args: &[String],
+    config: &config::Config,
+    run: &mut RunSummary,
+    cancel: &CancellationToken,
+) -> Result<(), TsError> {
+    match command {
+        "fetch-market-data" => {
+            #[cfg(feature = "network")]
+            start_async_runtime()?.block_on(routines::fetch_market_data(args, run, cancel))?;
+            #[cfg(not(feature = "network"))]
+            routines::missing_feature("fetch-market-data", "network")?;
+        }
+        "build-surface" => routines::build_surface(args, config, run, cancel)?,
+        "import-vol-matrix" => routines::import_vol_matrix(args, run)?,
+        "build-greek-graphs" => {
+            #[cfg(feature = "plotting")]
+            routines::build_greek_graphs(args, config, run)?;
+            #[cfg(not(feature = "plotting"))]
+            routines::missing_feature("build-greek-graphs", "plotting")?;
+        }
+        "build-graphs" => {
+            #[cfg(feature = "plotting")]
+            routines::build_graphs(args, config, run)?;
+            #[cfg(not(feature = "plotting"))]
+            routines::missing_feature("build-graphs", "plotting")?;
+        }
+        "build-term-structure" => {
+            #[cfg(feature = "plotting")]
+            routines::build_term_structure(args, config, run)?;
+            #[cfg(not(feature = "plotting"))]
+            routines::missing_feature("build-term-structure", "plotting")?;
+        }
+        "export-history" => routines::export_history(args, run)?,
+        "export" => routines::export(args, config, run)?,
+        "merge-snapshots" => routines::merge_snapshots(args, run)?,
+        "query" => routines::query(args, config, run)?,
+        "price-option" => routines::price_option(args, config, run)?,
+        "scenario" => routines::scenario(args, config, run)?,
+        "simulate" => routines::simulate(args, config, run)?,
+        "skew-report" => routines::skew_report(args, config, run)?,
+        "surface-metrics" => routines::surface_metrics(args, config, run)?,
+        "digest" => routines::digest(args, config, run)?,
+        "calibrate-heston" => routines::calibrate_heston(config, run)?,
+        "archive" => routines::archive(args, run)?,
+        "restore" => routines::restore(args, run)?,
+        "backfill" => {
+            #[cfg(feature = "network")]
+            start_async_runtime()?.block_on(routines::backfill(args, config, run))?;
+            #[cfg(not(feature = "network"))]
+            routines::missing_feature("backfill", "network")?;
+        }
+        "daemon" => routines::daemon(args, config, run)?,
+        "compare-assets" => routines::compare_assets(args, config, run)?,
+        "scan-arbitrage" => routines::scan_arbitrage(args, config, run)?,
+        "serve-grpc" => {
+            #[cfg(feature = "grpc")]
+            routines::serve_grpc(args, config, run)?;
+            #[cfg(not(feature = "grpc"))]
+            routines::missing_feature("serve-grpc", "grpc")?;
+        }
+        _ => routines::help(),
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "network")]
+fn start_async_runtime() -> Result<tokio::runtime::Runtime, TsError> {
+    tokio::runtime::Runtime::new()
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed starting async runtime: {e}")).with_source(e))
 }
@@ -1,36 +1,238 @@
-use std::sync::OnceLock;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 use serde::de::DeserializeOwned;
 
+use crate::constants;
 use crate::types::TsError;
-use crate::types::TsErrorType::RuntimeError;
+use crate::types::TsErrorType::{ApiError, RuntimeError};
 
 static HTTP_CLIENT: OnceLock<Client> = OnceLock::new();
+static RATE_LIMITER: OnceLock<Mutex<RateLimiter>> = OnceLock::new();
 
+/// Deribit's error code for when we have exceeded the rate limit.
+const RATE_LIMIT_ERROR_CODE: i64 = 10028;
+
+/// Deribit's error codes for errors that are temporary, which means the request can be retried.
+const TEMPORARY_ERROR_CODES: [i64; 3] = [RATE_LIMIT_ERROR_CODE, 10040, 13028];
+
+/// A JSON RPC response will have either a result or an error.
 #[derive(serde::Deserialize)]
 struct JsonRpcStructure<T> {
-    result: T,
+    result: Option<T>,
+    error: Option<JsonRpcError>,
+}
+
+#[derive(serde::Deserialize)]
+struct JsonRpcError {
+    code: i64,
+    message: String,
+    data: Option<serde_json::Value>,
+}
+
+/// Controls how failed requests are retried.
+struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: constants::NETWORK_REQUEST_MAX_ATTEMPTS,
+            base_delay: Duration::from_millis(constants::NETWORK_RETRY_BASE_DELAY_MILLIS),
+            max_delay: Duration::from_millis(constants::NETWORK_RETRY_MAX_DELAY_MILLIS),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Get how long to wait before making the given attempt (the first retry is attempt 2). Uses exponential backoff with
+    /// "full jitter", meaning we wait a random amount of time between zero and the backoff. This stops lots of requests
+    /// that failed at the same time from all retrying at the same time.
+    fn get_delay_before_attempt(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(2)))
+            .min(self.max_delay);
+
+        backoff.mul_f64(get_random_fraction())
+    }
+}
+
+/// A token bucket rate limiter. Tokens refill at a constant rate up to a maximum, and each request uses up one token. If
+/// there are no tokens left, we have to wait for one to refill.
+struct RateLimiter {
+    tokens: f64,
+    max_tokens: f64,
+    tokens_per_second: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    fn new(tokens_per_second: f64, max_tokens: f64) -> Self {
+        Self {
+            tokens: max_tokens,
+            max_tokens,
+            tokens_per_second,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Try to take a token. If there are none, returns how long until one will be available.
+    fn try_take_token(&mut self) -> Result<(), Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.tokens_per_second).min(self.max_tokens);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / self.tokens_per_second))
+    }
+
+    /// Use up all the tokens. Used when the server tells us we've made too many requests, which means our idea of how
+    /// many tokens we have is wrong.
+    fn empty(&mut self) {
+        self.tokens = 0.0;
+        self.last_refill = Instant::now();
+    }
+}
+
+/// Describes why a single request attempt failed.
+struct RequestFailure {
+    error: TsError,
+    /// Whether trying the same request again might work.
+    retryable: bool,
 }
 
 fn get_http_client() -> &'static Client {
     HTTP_CLIENT.get_or_init(Client::new)
 }
 
-/// Perform an async JSON RPC request, returning the result as a T.
+fn get_rate_limiter() -> &'static Mutex<RateLimiter> {
+    RATE_LIMITER
+        .get_or_init(|| Mutex::new(RateLimiter::new(constants::NETWORK_REQUESTS_PER_SECOND, constants::NETWORK_REQUEST_BURST)))
+}
+
+/// Returns a random number between 0 and 1. This isn't high quality randomness, but it's good enough for jitter.
+fn get_random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+
+    (random >> 11) as f64 / (1_u64 << 53) as f64
+}
+
+fn lock_rate_limiter() -> Result<std::sync::MutexGuard<'static, RateLimiter>, TsError> {
+    get_rate_limiter()
+        .lock()
+        .map_err(|e| TsError::new(RuntimeError, format!("Rate limiter lock was poisoned: {e}")))
+}
+
+/// Wait until the rate limiter allows us to make another request.
+async fn wait_for_rate_limit() -> Result<(), TsError> {
+    loop {
+        let wait = lock_rate_limiter()?.try_take_token();
+
+        match wait {
+            Ok(()) => return Ok(()),
+            Err(duration) => tokio::time::sleep(duration).await,
+        }
+    }
+}
+
+/// Perform an async JSON RPC request, returning the result as a T. Failed requests are retried according to the default
+/// retry policy, and all requests are rate limited.
 pub async fn do_rpc_request_as_struct<T: DeserializeOwned>(url: &str) -> Result<T, TsError> {
+    let policy = RetryPolicy::default();
+    let mut attempt = 1;
+
+    loop {
+        wait_for_rate_limit().await?;
+
+        let failure = match do_rpc_request_attempt::<T>(url).await {
+            Ok(v) => return Ok(v),
+            Err(e) => e,
+        };
+
+        if !failure.retryable || attempt >= policy.max_attempts {
+            return Err(failure.error);
+        }
+
+        if matches!(failure.error.error_type, ApiError { code } if code == RATE_LIMIT_ERROR_CODE) {
+            lock_rate_limiter()?.empty();
+        }
+
+        attempt += 1;
+        let delay = policy.get_delay_before_attempt(attempt);
+
+        println!(
+            "Request failed ({}), retrying in {:.1} seconds (attempt {attempt} of {})...",
+            failure.error.reason,
+            delay.as_secs_f64(),
+            policy.max_attempts
+        );
+
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Make a single attempt at a JSON RPC request.
+async fn do_rpc_request_attempt<T: DeserializeOwned>(url: &str) -> Result<T, RequestFailure> {
     let response = get_http_client()
         .get(url)
         .send()
         .await
-        .map_err(|e| TsError::new(RuntimeError, format!("Failed making request to {url}: {e}")))?
-        .error_for_status()
-        .map_err(|e| TsError::new(RuntimeError, format!("Got a HTTP error when making a request to {url}: {e}")))?;
+        .map_err(|e| RequestFailure {
+            error: TsError::new(RuntimeError, format!("Failed making request to {url}: {e}")).with_source(e),
+            retryable: true,
+        })?;
 
-    let data: JsonRpcStructure<T> = response
-        .json::<JsonRpcStructure<T>>()
-        .await
-        .map_err(|e| TsError::new(RuntimeError, format!("Failed deserialising JSON after request to {url}: {e}")))?;
+    let status = response.status();
+
+    let body = response.text().await.map_err(|e| RequestFailure {
+        error: TsError::new(RuntimeError, format!("Failed reading response body after request to {url}: {e}")).with_source(e),
+        retryable: true,
+    })?;
+
+    // Errors can come with either a successful or unsuccessful HTTP status, so check for those first.
+    let data = serde_json::from_str::<JsonRpcStructure<T>>(&body);
+
+    if let Ok(JsonRpcStructure { error: Some(error), .. }) = data {
+        return Err(RequestFailure {
+            error: TsError::new(
+                ApiError { code: error.code },
+                format!(
+                    "Got API error {} ({}) when making a request to {url}{}",
+                    error.code,
+                    error.message,
+                    error.data.map(|x| format!(": {x}")).unwrap_or_default()
+                ),
+            ),
+            retryable: TEMPORARY_ERROR_CODES.contains(&error.code),
+        });
+    }
+
+    if !status.is_success() {
+        return Err(RequestFailure {
+            error: TsError::new(RuntimeError, format!("Got a HTTP error when making a request to {url}: {status}")),
+            retryable: status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error(),
+        });
+    }
+
+    let deserialisation_failure = |reason: String| RequestFailure {
+        error: TsError::new(RuntimeError, format!("Failed deserialising JSON after request to {url}: {reason}")),
+        retryable: false,
+    };
 
-    Ok(data.result)
+    data.map_err(|e| deserialisation_failure(e.to_string()))?
+        .result
+        .ok_or(deserialisation_failure("response had neither a result nor an error".to_string()))
 }
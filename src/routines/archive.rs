@@ -0,0 +1,219 @@
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, NaiveDate, Utc};
+
+use crate::fileio::{ArchiveEntry, ArchiveEntryKind, ArchiveManifest, DataScope};
+use crate::helpers::F64Helpers;
+use crate::routines::RunSummary;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+use crate::{fileio, helpers};
+
+/// Files saved from fitted surfaces, including older surfaces kept for skew-report.
+const FIT_FILE_PREFIXES: [&str; 4] = ["smile-graph-data", "surface-grid", "surface-metrics", "heston-calibration"];
+
+/// Options for archiving the data directory, set via command line flags.
+struct ArchiveOptions {
+    /// Only archive files last modified on or after this day (UTC).
+    from: Option<NaiveDate>,
+    /// Only archive files last modified on or before this day (UTC).
+    to: Option<NaiveDate>,
+    output_path: String,
+    /// The whole data directory, whichever exchange, currency or snapshot the files are for.
+    data_directory: String,
+}
+
+impl ArchiveOptions {
+    fn from_args(args: &[String]) -> Result<Self, TsError> {
+        let output_path = helpers::get_flag_value(args, "--output")?.ok_or(TsError::new(RuntimeError, "--output is required"))?;
+        let from = helpers::get_flag_date(args, "--from")?;
+        let to = helpers::get_flag_date(args, "--to")?;
+
+        if let (Some(from), Some(to)) = (from, to)
+            && from > to
+        {
+            return Err(TsError::new(RuntimeError, "--from must not be after --to"));
+        }
+
+        Ok(Self {
+            from,
+            to,
+            output_path: output_path.to_string(),
+            data_directory: fileio::get_data_directory(DataScope::Global),
+        })
+    }
+}
+
+/// Options for restoring an archive, set via command line flags.
+struct RestoreOptions {
+    archive_path: String,
+    destination_directory: String,
+    /// Replace files that already exist, rather than refusing to restore.
+    overwrite: bool,
+}
+
+impl RestoreOptions {
+    fn from_args(args: &[String]) -> Result<Self, TsError> {
+        let archive_path =
+            helpers::get_flag_value(args, "--archive")?.ok_or(TsError::new(RuntimeError, "--archive is required"))?;
+
+        Ok(Self {
+            archive_path: archive_path.to_string(),
+            destination_directory: match helpers::get_flag_value(args, "--to")? {
+                Some(v) => v.to_string(),
+                None => fileio::get_data_directory(DataScope::Global),
+            },
+            overwrite: helpers::has_flag(args, "--overwrite"),
+        })
+    }
+}
+
+/// Bundle the snapshots, fits and reports in the data directory that were last modified in a range of days into a
+/// compressed tarball, with a manifest listing what each file is, so that old data can be kept somewhere cheaper.
+pub fn archive(args: &[String], run: &mut RunSummary) -> Result<(), TsError> {
+    let archive_options = ArchiveOptions::from_args(args).map_err(|e| e.context("Invalid arguments"))?;
+
+    println!("===============================================================");
+    println!("===============================================================");
+    println!("Archiving data");
+    println!("===============================================================");
+    println!("===============================================================");
+
+    if Path::new(&archive_options.output_path).exists() {
+        return Err(TsError::new(RuntimeError, format!("{} already exists", archive_options.output_path)));
+    }
+
+    let manifest = run.step("Finding files to archive", || build_manifest(&archive_options))?;
+    run.count("files", manifest.files.len());
+
+    if manifest.files.is_empty() {
+        println!("No files to archive");
+        println!("===============================================================");
+        return Ok(());
+    }
+
+    print_manifest_summary(&manifest);
+    println!("------------------------------");
+
+    println!("Saving archive to {}...", archive_options.output_path);
+    run.step("Saving archive", || {
+        fileio::save_archive(&manifest, &archive_options.data_directory, &archive_options.output_path)
+    })?;
+    println!("Successfully saved archive");
+    println!("===============================================================");
+
+    Ok(())
+}
+
+/// Extract an archive made by the archive command into the data directory (or another directory, e.g. to move data to a
+/// new environment).
+pub fn restore(args: &[String], run: &mut RunSummary) -> Result<(), TsError> {
+    let restore_options = RestoreOptions::from_args(args).map_err(|e| e.context("Invalid arguments"))?;
+
+    println!("===============================================================");
+    println!("===============================================================");
+    println!("Restoring archived data");
+    println!("===============================================================");
+    println!("===============================================================");
+
+    println!("Restoring {} to {}...", restore_options.archive_path, restore_options.destination_directory);
+    let manifest = run.step("Restoring archive", || {
+        fileio::restore_archive(&restore_options.archive_path, &restore_options.destination_directory, restore_options.overwrite)
+    })?;
+    run.count("files", manifest.files.len());
+
+    println!("Archive created at {}", helpers::format_time(manifest.created_at));
+    print_manifest_summary(&manifest);
+    println!("Successfully restored archive");
+    println!("===============================================================");
+
+    Ok(())
+}
+
+/// List the files in the data directory that are in the range of days being archived.
+fn build_manifest(options: &ArchiveOptions) -> Result<ArchiveManifest, TsError> {
+    let mut files = Vec::new();
+
+    for path in fileio::list_files_recursively(&options.data_directory)? {
+        let relative_path = path
+            .strip_prefix(&options.data_directory)
+            .map_err(|e| {
+                TsError::new(RuntimeError, format!("Failed getting relative path of {}: {e}", path.display())).with_source(e)
+            })?
+            .components()
+            .map(|x| x.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let file_name = path
+            .file_name()
+            .map(|x| x.to_string_lossy())
+            .unwrap_or_default();
+
+        // Skip placeholders, files that are still being written and earlier archives.
+        if file_name.starts_with('.')
+            || file_name.contains(".partial.")
+            || file_name.ends_with(".swap")
+            || file_name.ends_with(".tar.gz")
+        {
+            continue;
+        }
+
+        let metadata = fs::metadata(&path)
+            .map_err(|e| TsError::new(RuntimeError, format!("Failed reading {}: {e}", path.display())).with_source(e))?;
+        let modified_at: DateTime<Utc> = metadata
+            .modified()
+            .map_err(|e| {
+                TsError::new(RuntimeError, format!("Failed reading modified time of {}: {e}", path.display())).with_source(e)
+            })?
+            .into();
+        let day = modified_at.date_naive();
+
+        if options.from.is_some_and(|x| day < x) || options.to.is_some_and(|x| day > x) {
+            continue;
+        }
+
+        files.push(ArchiveEntry {
+            path: relative_path,
+            kind: classify_file(&file_name),
+            modified_at,
+            size_bytes: metadata.len(),
+        });
+    }
+
+    Ok(ArchiveManifest {
+        created_at: helpers::get_now(),
+        from: options.from,
+        to: options.to,
+        files,
+    })
+}
+
+fn classify_file(file_name: &str) -> ArchiveEntryKind {
+    if file_name.starts_with("deribit-") {
+        ArchiveEntryKind::Snapshot
+    } else if FIT_FILE_PREFIXES.iter().any(|x| file_name.starts_with(x)) {
+        ArchiveEntryKind::Fit
+    } else {
+        ArchiveEntryKind::Report
+    }
+}
+
+fn print_manifest_summary(manifest: &ArchiveManifest) {
+    let range = match (manifest.from, manifest.to) {
+        (None, None) => "all days".to_string(),
+        (from, to) => format!(
+            "{} to {}",
+            from.map_or("the start".to_string(), |x| x.to_string()),
+            to.map_or("the end".to_string(), |x| x.to_string())
+        ),
+    };
+    println!("Files from {range}:");
+
+    for kind in [ArchiveEntryKind::Snapshot, ArchiveEntryKind::Fit, ArchiveEntryKind::Report] {
+        let files = manifest.files.iter().filter(|x| x.kind == kind);
+        let megabytes = files.clone().map(|x| x.size_bytes).sum::<u64>() as f64 / 1_000_000.0;
+
+        println!("  {:?}: {} files ({} MB)", kind, files.count(), megabytes.round_to_decimal_places(2));
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Days, NaiveDate, NaiveTime, Utc};
+
+use crate::config::Config;
+use crate::fileio::{DataScope, SchemaVersioned};
+use crate::integrations::{DeribitDataContainer, DeribitOptionInstrument, DeribitTrade, DeribitTradesPage};
+use crate::routines::RunSummary;
+use crate::routines::build_surface::build_surface_from_snapshot;
+use crate::routines::surface_file;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+use crate::{fileio, helpers, integrations, network};
+
+/// Relative to the market's data directory. Only used if snapshots aren't kept apart by date.
+const BACKFILL_DIRECTORY_NAME: &str = "backfill";
+
+/// The most trades Deribit returns in one request.
+const TRADES_PAGE_SIZE: usize = 1000;
+
+/// Options for backfilling past days, set via command line flags.
+struct BackfillOptions {
+    /// The first and last days (inclusive, UTC) to reconstruct.
+    from: NaiveDate,
+    to: NaiveDate,
+    /// Don't print fitting progress.
+    quiet: bool,
+    /// Save the snapshots and surfaces compressed with zstd.
+    compress: bool,
+    /// The number of smiles fit at once.
+    threads: usize,
+    /// An SQLite database to also save the quotes, SVI parameters and metrics to.
+    #[cfg(feature = "storage-sqlite")]
+    db_path: Option<String>,
+}
+
+impl BackfillOptions {
+    fn from_args(args: &[String]) -> Result<Self, TsError> {
+        let from = helpers::get_flag_date(args, "--from")?.ok_or(TsError::new(RuntimeError, "--from is required"))?;
+        let to = helpers::get_flag_date(args, "--to")?.ok_or(TsError::new(RuntimeError, "--to is required"))?;
+
+        if from > to {
+            return Err(TsError::new(RuntimeError, "--from must not be after --to"));
+        }
+        if to >= helpers::get_now().date_naive() {
+            return Err(TsError::new(
+                RuntimeError,
+                "--to must be before today, as only days that are over can be backfilled",
+            ));
+        }
+
+        Ok(Self {
+            from,
+            to,
+            quiet: helpers::has_flag(args, "--quiet"),
+            compress: helpers::has_flag(args, "--compress"),
+            threads: match helpers::get_flag_value(args, "--threads")? {
+                None => 1,
+                Some(v) => v
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|x| *x > 0)
+                    .ok_or(TsError::new(RuntimeError, format!("Invalid --threads {v}, expected a whole number above 0")))?,
+            },
+            #[cfg(feature = "storage-sqlite")]
+            db_path: helpers::get_flag_value(args, "--db")?.map(|x| x.to_string()),
+        })
+    }
+}
+
+/// Reconstruct the market data and surface of each day in a range from the trades made that day, so that the history of
+/// the surface can be analysed without having run fetch-market-data every day. Each option's last trade of the day stands
+/// in for its quote at the end of the day.
+pub async fn backfill(args: &[String], config: &Config, run: &mut RunSummary) -> Result<(), TsError> {
+    let backfill_options = BackfillOptions::from_args(args).map_err(|e| e.context("Invalid arguments"))?;
+
+    println!("===============================================================");
+    println!("===============================================================");
+    println!("Backfilling daily surfaces from past trades");
+    println!("===============================================================");
+    println!("===============================================================");
+
+    let instruments = run
+        .step_async("Downloading options", download_instruments())
+        .await?;
+    run.count("options", instruments.len());
+    println!("------------------------------");
+
+    let mut total_days = 0;
+    let mut backfilled_days = 0;
+
+    // A day that can't be reconstructed (e.g. because none of its options are still listed) doesn't stop the others, so
+    // it's only counted.
+    for day in backfill_options
+        .from
+        .iter_days()
+        .take_while(|x| *x <= backfill_options.to)
+    {
+        total_days += 1;
+
+        match backfill_day(day, &instruments, &backfill_options, config).await {
+            Ok(_) => backfilled_days += 1,
+            Err(e) => println!("Failed backfilling {day}: {}...", e.reason),
+        }
+        println!("------------------------------");
+    }
+
+    run.count("days", total_days);
+    run.count("backfilled_days", backfilled_days);
+    println!("Backfilled {backfilled_days} of {total_days} days");
+    println!("===============================================================");
+
+    match backfilled_days {
+        0 => Err(TsError::new(RuntimeError, "None of the days could be backfilled")),
+        _ => Ok(()),
+    }
+}
+
+/// Download every option Deribit lists, including those that have expired, keyed by name. Deribit only lists options that
+/// expired recently, so the further back a day is, the fewer of its options can be reconstructed.
+async fn download_instruments() -> Result<HashMap<Box<str>, DeribitOptionInstrument>, TsError> {
+    println!("Fetching options...");
+
+    let mut instruments = HashMap::new();
+
+    for expired in [true, false] {
+        let url = format!(
+            "https://www.deribit.com/api/v2/public/get_instruments?currency={}&kind=option&expired={expired}",
+            fileio::get_currency().get_code()
+        );
+        let options = network::do_rpc_request_as_struct::<Vec<DeribitOptionInstrument>>(&url).await?;
+
+        for option in options {
+            instruments.insert(option.instrument_name.clone(), option);
+        }
+    }
+
+    println!("Found {} options", instruments.len());
+
+    Ok(instruments)
+}
+
+/// The directory a backfilled day's files are saved in, and what's added to their names. Each day is a snapshot of its own
+/// if snapshots are kept apart by date, and otherwise every day is saved in the backfill directory, named by its date.
+fn get_backfill_location(config: &Config, day: NaiveDate) -> (String, String) {
+    match config.data_paths.per_snapshot_date {
+        true => {
+            let mut paths = config.data_paths.clone();
+            paths.snapshot_date = Some(day);
+            (paths.get_directory(DataScope::Snapshot), String::new())
+        }
+        false => (fileio::get_data_path(DataScope::Market, BACKFILL_DIRECTORY_NAME), format!("-{day}")),
+    }
+}
+
+async fn backfill_day(
+    day: NaiveDate,
+    instruments: &HashMap<Box<str>, DeribitOptionInstrument>,
+    backfill_options: &BackfillOptions,
+    config: &Config,
+) -> Result<(), TsError> {
+    println!("Backfilling {day}...");
+
+    let start = day.and_time(NaiveTime::MIN).and_utc();
+    let end = (day + Days::new(1)).and_time(NaiveTime::MIN).and_utc();
+
+    let trades = download_trades(start, end).await?;
+    let data = build_snapshot(end, &trades, instruments);
+
+    if data.options.is_empty() {
+        return Err(TsError::new(RuntimeError, "No trades of options Deribit still lists were found"));
+    }
+
+    let (directory, suffix) = get_backfill_location(config, day);
+    fileio::create_directory(&directory)?;
+
+    let path = fileio::save_data_file(
+        &data,
+        &format!("{directory}/{}", fileio::get_market_file_name(&format!("market-data{suffix}.json"))),
+        backfill_options.compress,
+    )?;
+    println!("Saved {} options to {path}", data.options.len());
+
+    #[cfg(feature = "storage-sqlite")]
+    if let Some(db_path) = &backfill_options.db_path {
+        crate::routines::storage::store_snapshot(db_path, &data)?;
+    }
+
+    let surface = build_surface_from_snapshot(data, config, backfill_options.quiet, backfill_options.threads)?;
+
+    let path =
+        surface_file::save_surface(&surface, &format!("{directory}/smile-graph-data{suffix}.json"), backfill_options.compress)?;
+    println!("Saved {} smiles to {path}", surface.smile_graphs.len());
+
+    #[cfg(feature = "storage-sqlite")]
+    if let Some(db_path) = &backfill_options.db_path {
+        store_surface(db_path, surface, config)?;
+    }
+
+    Ok(())
+}
+
+/// Save a backfilled surface's SVI parameters and metrics to the database, after its quotes.
+#[cfg(feature = "storage-sqlite")]
+fn store_surface(db_path: &str, mut surface: crate::analytics::SmileGraphsDataContainer, config: &Config) -> Result<(), TsError> {
+    for graph in &mut surface.smile_graphs {
+        graph.wing_extrapolation = config.wing_extrapolation;
+    }
+
+    let metrics = crate::analytics::SurfaceMetrics::calculate(&surface.smile_graphs, &[])?;
+    crate::routines::storage::store_surface(db_path, &surface)?;
+    crate::routines::storage::store_metrics(db_path, &surface, &metrics)?;
+    println!("Saved quotes, SVI parameters and metrics to {db_path}");
+
+    Ok(())
+}
+
+/// Download every option trade made between the given times, oldest first. Pages are requested from the time of the last
+/// trade of the previous page, as more trades could have been made at that time, so trades already seen are skipped.
+async fn download_trades(start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<DeribitTrade>, TsError> {
+    println!("Fetching trades...");
+
+    let mut trades: Vec<DeribitTrade> = Vec::new();
+    let mut seen_trade_ids: HashSet<Box<str>> = HashSet::new();
+    let mut page_start = start.timestamp_millis();
+    // The end of the range is inclusive.
+    let page_end = end.timestamp_millis() - 1;
+
+    loop {
+        let url = format!(
+            "https://www.deribit.com/api/v2/public/get_last_trades_by_currency_and_time?currency={}&kind=option\
+             &start_timestamp={page_start}&end_timestamp={page_end}&count={TRADES_PAGE_SIZE}&sorting=asc",
+            fileio::get_currency().get_code()
+        );
+        let page = network::do_rpc_request_as_struct::<DeribitTradesPage>(&url).await?;
+        let last_timestamp = page.trades.last().map(|x| x.timestamp as i64);
+        let previous_count = trades.len();
+
+        trades.extend(
+            page.trades
+                .into_iter()
+                .filter(|x| seen_trade_ids.insert(x.trade_id.clone())),
+        );
+
+        match (page.has_more, last_timestamp) {
+            // A whole page of trades at the same time as the last page's, so move past it to avoid requesting it forever.
+            (true, Some(timestamp)) if trades.len() == previous_count => page_start = timestamp + 1,
+            (true, Some(timestamp)) => page_start = timestamp,
+            _ => break,
+        }
+    }
+
+    println!("Found {} trades", trades.len());
+
+    Ok(trades)
+}
+
+/// Give each option its last trade before the given time as its ticker data. Options that hadn't traded or had already
+/// expired are left out, as are trades of options Deribit no longer lists.
+fn build_snapshot(
+    snapshot_time: DateTime<Utc>,
+    trades: &[DeribitTrade],
+    instruments: &HashMap<Box<str>, DeribitOptionInstrument>,
+) -> DeribitDataContainer {
+    let mut last_trades: HashMap<&str, &DeribitTrade> = HashMap::new();
+
+    for trade in trades {
+        let last_trade = last_trades.entry(&trade.instrument_name).or_insert(trade);
+
+        if trade.timestamp >= last_trade.timestamp {
+            *last_trade = trade;
+        }
+    }
+
+    let mut options = Vec::new();
+    let mut unlisted_options = 0;
+
+    for (instrument_name, trade) in last_trades {
+        let Some(instrument) = instruments.get(instrument_name) else {
+            unlisted_options += 1;
+            continue;
+        };
+
+        if instrument.expiration_timestamp as i64 <= snapshot_time.timestamp_millis() {
+            continue;
+        }
+
+        let mut option = instrument.clone();
+        option.ticker_data = Some(trade.to_ticker_data());
+        options.push(option);
+    }
+
+    if unlisted_options > 0 {
+        println!("Skipped {unlisted_options} traded options that Deribit no longer lists...");
+    }
+
+    integrations::sort_options(&mut options);
+
+    DeribitDataContainer {
+        schema_version: DeribitDataContainer::SCHEMA_VERSION,
+        options,
+        snapshot_time: Some(snapshot_time),
+        // Only option trades are downloaded, which record their own underlying prices.
+        futures: Vec::new(),
+    }
+}
@@ -4,38 +4,172 @@ use chrono::{DateTime, Utc};
 use plotters::element::DashedPathElement;
 use plotters::style::full_palette::GREY;
 
-use crate::analytics::{SmileGraph, SmileGraphsDataContainer};
-use crate::fileio;
-use crate::helpers::error_unless_positive_f64;
+use crate::analytics::{self, ExpiryCategoryFilter, OptionType, SmileGraph, SmileGraphsDataContainer, VolEvent};
+use crate::config::Config;
+use crate::fileio::{self, DataScope, HtmlGraph, HtmlGraphPoint, HtmlGraphSeries, HtmlGraphSeriesStyle};
+use crate::helpers::{self, F64Helpers, error_unless_positive_f64};
+use crate::routines::RunSummary;
 use crate::types::TsError;
 use crate::types::TsErrorType::RuntimeError;
 use plotters::prelude::*;
 
 type GraphLinesData = (Vec<(f64, f64)>, Vec<(f64, f64)>, Vec<(f64, f64)>, f64);
 
+/// The radius of option points (in pixels) when the options' open interest isn't known.
+const PNG_POINT_RADIUS: f64 = 5.0;
+const HTML_POINT_RADIUS: f64 = 4.0;
+
+/// The radii of the option points with the least and most open interest in a smile.
+const MIN_LIQUIDITY_POINT_RADIUS: f64 = 2.0;
+const MAX_LIQUIDITY_POINT_RADIUS: f64 = 12.0;
+
+/// The extrapolated first quarter, observed middle and extrapolated last quarter of a smile's line.
+type GraphLines = (Vec<(f64, f64)>, Vec<(f64, f64)>, Vec<(f64, f64)>);
+
+/// The strike (or call delta), lower and upper implied volatility at each point of a smile's confidence band, in order of
+/// strike. Empty if the smile wasn't bootstrapped.
+type ConfidenceBandPoints = Vec<(f64, f64, f64)>;
+
+/// What the x axis of the graphs shows.
+#[derive(Clone, Copy)]
+enum GraphXAxis {
+    Strike,
+    /// The forward delta of a call at each strike, which is how most volatility traders look at smiles.
+    Delta,
+}
+
+impl GraphXAxis {
+    fn get_description(self) -> &'static str {
+        match self {
+            GraphXAxis::Strike => "Strike Price (K)",
+            GraphXAxis::Delta => "Call Delta (Δ)",
+        }
+    }
+
+    fn get_path(self, expiry: DateTime<Utc>, extension: &str) -> String {
+        let name = match self {
+            GraphXAxis::Strike => "smile-graph",
+            GraphXAxis::Delta => "smile-delta-graph",
+        };
+
+        fileio::get_data_path(
+            DataScope::Snapshot,
+            &format!("graphs/{}-{name}-{}.{extension}", fileio::get_currency().get_file_code(), expiry.format("%Y-%m-%d")),
+        )
+    }
+}
+
+/// What the x axis of the graph overlaying every smile shows. Strikes mean different things at different expiries, so
+/// only measures of moneyness that can be compared across them are offered.
+#[derive(Clone, Copy)]
+enum OverlayXAxis {
+    /// The forward delta of a call at each strike.
+    Delta,
+    /// ln(K/F).
+    LogMoneyness,
+}
+
+impl OverlayXAxis {
+    fn get_description(self) -> &'static str {
+        match self {
+            OverlayXAxis::Delta => "Call Delta (Δ)",
+            OverlayXAxis::LogMoneyness => "Log Moneyness (ln(K/F))",
+        }
+    }
+}
+
+/// The kind of file the graphs are saved as.
+#[derive(Clone, Copy)]
+enum GraphFormat {
+    Png,
+    /// A self-contained interactive page, where each option point can be inspected.
+    Html,
+}
+
 /// A point on the graph representing data from one option.
 struct OptionGraphPoint {
-    strike: f64,
+    /// The strike, or the call delta at the strike if graphing against delta.
+    x: f64,
+    /// Which option the point is for, shown when hovering over it on interactive graphs.
+    description: String,
     smile_relative_implied_volatility: f64,
     self_relative_implied_volatility: f64,
+    /// The option's open interest as a fraction of the most open interest of any option in the smile, if known.
+    relative_open_interest: Option<f64>,
+}
+
+impl OptionGraphPoint {
+    /// The radius to draw the point with, so that its area is in proportion to its open interest. Points are drawn with
+    /// the given default radius if the smile's open interest isn't known.
+    fn get_radius(&self, default: f64) -> f64 {
+        match self.relative_open_interest {
+            Some(x) => MIN_LIQUIDITY_POINT_RADIUS + (MAX_LIQUIDITY_POINT_RADIUS - MIN_LIQUIDITY_POINT_RADIUS) * x.sqrt(),
+            None => default,
+        }
+    }
 }
 
-pub fn build_graphs() {
+pub fn build_graphs(args: &[String], config: &Config, run: &mut RunSummary) -> Result<(), TsError> {
     println!("===============================================================");
     println!("===============================================================");
-    println!("Building Bitcoin implied volatility graphs and saving to file");
+    println!("Building {} implied volatility graphs and saving to file", fileio::get_currency().get_name());
     println!("===============================================================");
     println!("===============================================================");
 
-    let graphs_data = load_api_data().unwrap_or_else(|e| panic!("Failed loading API data: {}", e.reason));
+    let (format, overlay_x_axis, category_filter) = get_graph_options(args).map_err(|e| e.context("Invalid arguments"))?;
+
+    let mut graphs_data = run.step("Loading API data", load_api_data)?;
+
+    for graph in &mut graphs_data.smile_graphs {
+        graph.wing_extrapolation = config.wing_extrapolation;
+    }
+
+    if !category_filter.is_empty() {
+        let total = graphs_data.smile_graphs.len();
+        graphs_data.smile_graphs.retain(|x| {
+            category_filter
+                .get_exclusion_reason(x.expiry_category)
+                .is_none()
+        });
+        println!("Kept {}/{total} smiles in the chosen expiry categories", graphs_data.smile_graphs.len());
+    }
+
+    if let Some(surface_score) = &graphs_data.surface_score {
+        println!("{surface_score}");
+    }
     println!("------------------------------");
 
-    delete_existing_graphs();
+    let events = run.step("Loading events", || VolEvent::load_from_args(args))?;
+
+    run.step("Deleting existing graphs", delete_existing_graphs)?;
     println!("------------------------------");
 
     println!("Creating graphs and saving to file...");
 
+    let header = config.report_header.render();
+    let x_axis = match helpers::has_flag(args, "--delta") {
+        true => GraphXAxis::Delta,
+        false => GraphXAxis::Strike,
+    };
+
+    run.optional_step("Building term structure graph", || {
+        create_term_structure_graph(&graphs_data.smile_graphs, &events, format, header.as_deref())
+    });
+
+    if let Some(overlay_x_axis) = overlay_x_axis {
+        run.optional_step("Building overlay graph", || {
+            create_overlay_graph(&graphs_data.smile_graphs, overlay_x_axis, format, header.as_deref())
+        });
+    }
+
+    let plot_g = helpers::has_flag(args, "--g-function");
+
     for graph in graphs_data.smile_graphs {
+        if plot_g {
+            let _ = create_g_function_graph(&graph, format, header.as_deref())
+                .inspect_err(|e| println!("Failed building g(k) graph: {}", e.reason));
+        }
+
         let (first_quarter_points, middle_points, last_quarter_points, highest_implied_volatility_1) =
             match build_graph_lines(&graph, 400) {
                 Ok(v) => v,
@@ -77,26 +211,90 @@ pub fn build_graphs() {
             Ok(v) => v,
         };
 
-        let _ = create_graph(
-            expiry,
-            highest_implied_volatility_1.max(highest_implied_volatility_2),
-            first_quarter_points,
-            middle_points,
-            last_quarter_points,
-            option_points,
-            (forward_price, implied_volatility_at_forward_price),
-        )
+        let event_labels = match describe_events_before_expiry(&graph, &events) {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Failed finding events before expiry: {}, skipping...", e.reason);
+                continue;
+            }
+        };
+
+        let mut lines = (first_quarter_points, middle_points, last_quarter_points);
+        let mut confidence_band = get_confidence_band_points(&graph);
+        let mut option_points = option_points;
+        let mut forward_price_point = (forward_price, implied_volatility_at_forward_price);
+        let highest_band_implied_volatility = confidence_band.iter().map(|x| x.2).fold(0.0, f64::max);
+
+        if let GraphXAxis::Delta = x_axis
+            && let Err(e) =
+                convert_to_delta(&graph, (&mut lines, &mut confidence_band), &mut option_points, &mut forward_price_point)
+        {
+            println!("Failed converting graph to delta: {}, skipping...", e.reason);
+            continue;
+        }
+
+        let _ = match format {
+            GraphFormat::Png => create_graph(
+                (expiry, x_axis),
+                highest_implied_volatility_1
+                    .max(highest_implied_volatility_2)
+                    .max(highest_band_implied_volatility),
+                (lines, confidence_band),
+                option_points,
+                forward_price_point,
+                header.as_deref(),
+                &event_labels,
+            ),
+            GraphFormat::Html => create_html_graph(
+                (expiry, x_axis),
+                (lines, confidence_band),
+                option_points,
+                forward_price_point,
+                header.as_deref(),
+                &event_labels,
+            ),
+        }
         .inspect_err(|e| println!("Failed building graph: {}", e.reason));
     }
 
     println!("Done!");
     println!("===============================================================");
+
+    Ok(())
+}
+
+/// Read the graph format, the overlay graph's x axis (if one was asked for) and the expiry categories to graph from the
+/// command line flags.
+fn get_graph_options(args: &[String]) -> Result<(GraphFormat, Option<OverlayXAxis>, ExpiryCategoryFilter), TsError> {
+    let format = match helpers::get_flag_value(args, "--format")? {
+        None | Some("png") => GraphFormat::Png,
+        Some("html") => GraphFormat::Html,
+        Some(other) => return Err(TsError::new(RuntimeError, format!("Unknown graph format {other}"))),
+    };
+    let overlay_x_axis = match helpers::get_flag_value(args, "--overlay")? {
+        None => None,
+        Some("delta") => Some(OverlayXAxis::Delta),
+        Some("log-moneyness") => Some(OverlayXAxis::LogMoneyness),
+        Some(other) => {
+            return Err(TsError::new(
+                RuntimeError,
+                format!("Unknown overlay x axis {other}, expected delta or log-moneyness"),
+            ));
+        }
+    };
+
+    Ok((format, overlay_x_axis, ExpiryCategoryFilter::from_args(args)?))
 }
 
 /// Get the points on the graphs. Also returns the highest found implied volatility as the last parameter.
 fn build_graph_points(smile_graph: &SmileGraph) -> Result<(Vec<OptionGraphPoint>, f64), TsError> {
     let mut points: Vec<OptionGraphPoint> = Vec::new();
     let mut highest_implied_volatility = f64::MIN;
+    let highest_open_interest = smile_graph
+        .options
+        .iter()
+        .filter_map(|x| x.open_interest)
+        .fold(0.0, f64::max);
 
     for option in &smile_graph.options {
         let implied_volatility = smile_graph.get_implied_volatility_at_strike(option.strike)?;
@@ -110,9 +308,19 @@ fn build_graph_points(smile_graph: &SmileGraph) -> Result<(Vec<OptionGraphPoint>
         }
 
         points.push(OptionGraphPoint {
-            strike: option.strike,
+            x: option.strike,
+            description: match option.open_interest {
+                Some(open_interest) => format!(
+                    "Instrument {} ({:?}, strike {}, open interest {open_interest})",
+                    option.instrument_id, option.option_type, option.strike
+                ),
+                None => format!("Instrument {} ({:?}, strike {})", option.instrument_id, option.option_type, option.strike),
+            },
             smile_relative_implied_volatility: implied_volatility,
             self_relative_implied_volatility: self_implied_volatility,
+            // With no open interest anywhere in the smile, sizing the points would only make them all the smallest size.
+            relative_open_interest: (highest_open_interest > 0.0)
+                .then(|| option.open_interest.unwrap_or(0.0) / highest_open_interest),
         });
     }
 
@@ -217,16 +425,66 @@ fn build_graph_lines(graph: &SmileGraph, number_of_points: u64) -> Result<GraphL
     Ok((first_quarter_points, middle_points, last_quarter_points, highest_implied_volatility))
 }
 
-fn delete_existing_graphs() {
+/// The smile's confidence band, if it was bootstrapped when the surface was built.
+fn get_confidence_band_points(graph: &SmileGraph) -> ConfidenceBandPoints {
+    graph.confidence_band.as_ref().map_or(Vec::new(), |band| {
+        band.strikes
+            .iter()
+            .map(|x| (x.strike, x.implied_volatility.lower, x.implied_volatility.upper))
+            .collect()
+    })
+}
+
+/// Replace the strikes on the x axis with the call delta at each strike. Points at or below a strike of zero have no delta,
+/// so are dropped.
+fn convert_to_delta(
+    graph: &SmileGraph,
+    (lines, confidence_band): (&mut GraphLines, &mut ConfidenceBandPoints),
+    option_points: &mut [OptionGraphPoint],
+    forward_price_point: &mut (f64, f64),
+) -> Result<(), TsError> {
+    let convert_line = |line: &mut Vec<(f64, f64)>| -> Result<(), TsError> {
+        *line = line
+            .iter()
+            .filter(|x| x.0 > 0.0)
+            .map(|x| Ok((analytics::get_call_delta_at_strike(graph, x.0)?, x.1)))
+            .collect::<Result<Vec<(f64, f64)>, TsError>>()?;
+
+        Ok(())
+    };
+
+    convert_line(&mut lines.0)?;
+    convert_line(&mut lines.1)?;
+    convert_line(&mut lines.2)?;
+
+    for point in confidence_band.iter_mut() {
+        point.0 = analytics::get_call_delta_at_strike(graph, point.0)?;
+    }
+
+    for point in option_points {
+        point.x = analytics::get_call_delta_at_strike(graph, point.x)?;
+    }
+
+    forward_price_point.0 = analytics::get_call_delta_at_strike(graph, forward_price_point.0)?;
+
+    Ok(())
+}
+
+fn delete_existing_graphs() -> Result<(), TsError> {
     println!("Deleting any existing graphs...");
-    fileio::clear_directory("./data/graphs/", "gitkeep")
-        .unwrap_or_else(|e| panic!("Failed clearing graphs directory: {}", e.reason));
+    let directory = fileio::get_data_path(DataScope::Snapshot, "graphs/");
+    fileio::create_directory(&directory).map_err(|e| e.context("Failed creating graphs directory"))?;
+    fileio::clear_directory(&directory, "gitkeep").map_err(|e| e.context("Failed clearing graphs directory"))?;
     println!("Done!");
+
+    Ok(())
 }
 
 fn load_api_data() -> Result<SmileGraphsDataContainer, TsError> {
     println!("Loading external API data...");
-    let data = fileio::load_struct_from_file::<SmileGraphsDataContainer>("./data/smile-graph-data.json")?;
+    let data = fileio::load_versioned_struct_from_file::<SmileGraphsDataContainer>(&fileio::find_data_file(
+        &fileio::get_data_path(DataScope::Snapshot, "smile-graph-data.json"),
+    ))?;
 
     let expiries = data
         .smile_graphs
@@ -245,73 +503,102 @@ fn load_api_data() -> Result<SmileGraphsDataContainer, TsError> {
     let smile_graphs_count = data.smile_graphs.len();
 
     println!("Found {smile_graphs_count} smile graphs...");
-    println!("Smile graph data ranges from {} to {}", first_expiry.to_rfc3339(), last_expiry.to_rfc3339());
+    println!(
+        "Smile graph data ranges from {} to {}",
+        helpers::format_time(*first_expiry),
+        helpers::format_time(*last_expiry)
+    );
 
     Ok(data)
 }
 
 fn create_graph(
-    expiry: DateTime<Utc>,
+    (expiry, x_axis): (DateTime<Utc>, GraphXAxis),
     y_finish: f64,
-    extrapolated_first_quarter_points: Vec<(f64, f64)>,
-    observed_data_points: Vec<(f64, f64)>,
-    extrapolated_last_quarter_points: Vec<(f64, f64)>,
+    (lines, confidence_band): (GraphLines, ConfidenceBandPoints),
     option_points: Vec<OptionGraphPoint>,
     forward_price_point: (f64, f64),
+    header: Option<&str>,
+    event_labels: &[String],
 ) -> Result<(), TsError> {
-    let path = format!("./data/graphs/btc-smile-graph-{}.png", expiry.format("%Y-%m-%d"));
+    let (extrapolated_first_quarter_points, observed_data_points, extrapolated_last_quarter_points) = lines;
+    let path = x_axis.get_path(expiry, "png");
+    let x_range = match x_axis {
+        GraphXAxis::Strike => {
+            let first_point = extrapolated_first_quarter_points
+                .first()
+                .ok_or(TsError::new(RuntimeError, "Failed getting first extrapolated quarter point"))?;
+            let last_point = extrapolated_last_quarter_points
+                .last()
+                .ok_or(TsError::new(RuntimeError, "Failed getting last extrapolated quarter point"))?;
+
+            // Keep x >= 0.
+            max(0, first_point.0 as i64) as f64..last_point.0
+        }
+        GraphXAxis::Delta => 0.0..1.0,
+    };
     let root = BitMapBackend::new(&path, (1920, 1080)).into_drawing_area();
 
     println!("Creating graph at {path}...");
 
     root.fill(&WHITE)
-        .map_err(|e| TsError::new(RuntimeError, format!("Filling graph failed: {}", e)))?;
-
-    let first_point = extrapolated_first_quarter_points
-        .first()
-        .ok_or(TsError::new(RuntimeError, "Failed getting first extrapolated quarter point"))?;
-    let last_point = extrapolated_last_quarter_points
-        .last()
-        .ok_or(TsError::new(RuntimeError, "Failed getting last extrapolated quarter point"))?;
+        .map_err(|e| TsError::new(RuntimeError, format!("Filling graph failed: {}", e)).with_source(e))?;
 
-    // Keep x >= 0.
-    let min_x = max(0, first_point.0 as i64);
+    let chart_area = match header {
+        Some(header) => root
+            .titled(header, ("sans-serif", 30))
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph header failed: {}", e)).with_source(e))?,
+        None => root.clone(),
+    };
 
-    let mut chart = ChartBuilder::on(&root)
+    let mut chart = ChartBuilder::on(&chart_area)
         .caption(
-            format!("Implied volatility of Bitcoin options at expiry {}", expiry.to_rfc3339()),
+            format!(
+                "Implied volatility of {} options at expiry {}",
+                fileio::get_currency().get_name(),
+                helpers::format_time(expiry)
+            ),
             ("sans-serif", 50).into_font(),
         )
         .margin(15)
         .x_label_area_size(50)
         .y_label_area_size(50)
-        .build_cartesian_2d(min_x as f64..last_point.0, 0.0..y_finish * 1.05)
-        .map_err(|e| TsError::new(RuntimeError, format!("Building graph failed: {}", e)))?;
+        .build_cartesian_2d(x_range, 0.0..y_finish * 1.05)
+        .map_err(|e| TsError::new(RuntimeError, format!("Building graph failed: {}", e)).with_source(e))?;
 
     chart
         .configure_mesh()
-        .x_desc("Strike Price (K)")
+        .x_desc(x_axis.get_description())
         .y_desc("Implied Volatility (σ)")
         .axis_desc_style(("sans-serif", 30))
         .draw()
-        .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph mesh failed: {}", e)))?;
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph mesh failed: {}", e)).with_source(e))?;
+
+    // Confidence band, drawn first so that the curve is on top of it.
+    if !confidence_band.is_empty() {
+        chart
+            .draw_series(std::iter::once(Polygon::new(get_band_outline(&confidence_band), RED.mix(0.2).filled())))
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing confidence band failed: {}", e)).with_source(e))?
+            .label("Fit confidence band")
+            .legend(|(x, y)| Rectangle::new([(x, y - 5), (x + 20, y + 5)], RED.mix(0.2).filled()));
+    }
 
     // Curve lines.
     chart
         .draw_series(LineSeries::new(extrapolated_first_quarter_points, GREY))
-        .map_err(|e| TsError::new(RuntimeError, format!("Drawing curve first quarter failed: {}", e)))?
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing curve first quarter failed: {}", e)).with_source(e))?
         .label("Extrapolated data")
         .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], GREY));
 
     chart
         .draw_series(LineSeries::new(observed_data_points, RED))
-        .map_err(|e| TsError::new(RuntimeError, format!("Drawing curve middle failed: {}", e)))?
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing curve middle failed: {}", e)).with_source(e))?
         .label("Observed data")
         .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], RED));
 
     chart
         .draw_series(LineSeries::new(extrapolated_last_quarter_points, GREY))
-        .map_err(|e| TsError::new(RuntimeError, format!("Drawing curve last quarter failed: {}", e)))?;
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing curve last quarter failed: {}", e)).with_source(e))?;
 
     // Forward price line.
     chart
@@ -321,44 +608,683 @@ fn create_graph(
             4,
             ShapeStyle::from(RED),
         ))
-        .map_err(|e| TsError::new(RuntimeError, format!("Drawing forward price line failed: {}", e)))?
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing forward price line failed: {}", e)).with_source(e))?
         .label("Forward price")
         .legend(|(x, y)| DashedPathElement::new(vec![(x, y), (x + 20, y)], 6, 4, RED));
 
     // Option points.
     chart
-        .draw_series(PointSeries::<_, _, Circle<_, _>, _>::new(
-            option_points
-                .iter()
-                .map(|x| (x.strike, x.smile_relative_implied_volatility)),
-            5,
-            BLUE.filled(),
-        ))
-        .map_err(|e| TsError::new(RuntimeError, format!("Drawing option points failed: {}", e)))?
+        .draw_series(option_points.iter().map(|x| {
+            Circle::new(
+                (x.x, x.smile_relative_implied_volatility),
+                x.get_radius(PNG_POINT_RADIUS).round() as u32,
+                BLUE.filled(),
+            )
+        }))
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing option points failed: {}", e)).with_source(e))?
         .label("Smile-relative implied volatility")
         .legend(|(x, y)| Circle::new((x, y), 5, BLUE.filled()));
 
     chart
-        .draw_series(PointSeries::<_, _, Circle<_, _>, _>::new(
-            option_points
-                .iter()
-                .map(|x| (x.strike, x.self_relative_implied_volatility)),
-            5,
-            GREY.filled(),
-        ))
-        .map_err(|e| TsError::new(RuntimeError, format!("Drawing option points failed: {}", e)))?
+        .draw_series(option_points.iter().map(|x| {
+            Circle::new(
+                (x.x, x.self_relative_implied_volatility),
+                x.get_radius(PNG_POINT_RADIUS).round() as u32,
+                GREY.filled(),
+            )
+        }))
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing option points failed: {}", e)).with_source(e))?
         .label("Self-relative implied volatility")
         .legend(|(x, y)| Circle::new((x, y), 5, GREY.filled()));
 
+    // Events only appear in the legend, since they can't be placed on a strike axis.
+    for label in event_labels {
+        chart
+            .draw_series(std::iter::empty::<PathElement<(f64, f64)>>())
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing event failed: {}", e)).with_source(e))?
+            .label(format!("Event: {label}"))
+            .legend(|(x, y)| DashedPathElement::new(vec![(x, y), (x + 20, y)], 6, 4, BLACK));
+    }
+
+    chart
+        .configure_series_labels()
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing series label failed: {}", e)).with_source(e))?;
+
+    root.present()
+        .map_err(|e| TsError::new(RuntimeError, format!("Finalising graph failed: {}", e)).with_source(e))?;
+
+    Ok(())
+}
+
+/// Save the same graph as create_graph() as an interactive HTML page, where hovering over an option shows its instrument.
+fn create_html_graph(
+    (expiry, x_axis): (DateTime<Utc>, GraphXAxis),
+    (lines, confidence_band): (GraphLines, ConfidenceBandPoints),
+    option_points: Vec<OptionGraphPoint>,
+    forward_price_point: (f64, f64),
+    header: Option<&str>,
+    event_labels: &[String],
+) -> Result<(), TsError> {
+    let path = x_axis.get_path(expiry, "html");
+
+    println!("Creating graph at {path}...");
+
+    // Strikes at or below zero only have placeholder values.
+    let to_points = |line: Vec<(f64, f64)>| {
+        line.into_iter()
+            .filter(|x| x.0 > 0.0)
+            .map(|(x, y)| HtmlGraphPoint {
+                x,
+                y,
+                label: None,
+                radius: None,
+            })
+            .collect()
+    };
+    let series = |name: &str, colour: &str, style: HtmlGraphSeriesStyle, points: Vec<HtmlGraphPoint>| HtmlGraphSeries {
+        name: name.to_string(),
+        colour: colour.to_string(),
+        style,
+        points,
+    };
+    let option_series = |get_implied_volatility: fn(&OptionGraphPoint) -> f64| {
+        option_points
+            .iter()
+            .map(|x| HtmlGraphPoint {
+                x: x.x,
+                y: get_implied_volatility(x),
+                label: Some(x.description.clone()),
+                radius: Some(x.get_radius(HTML_POINT_RADIUS)),
+            })
+            .collect()
+    };
+    let (extrapolated_first_quarter_points, observed_data_points, extrapolated_last_quarter_points) = lines;
+
+    let mut graph = HtmlGraph {
+        title: format!(
+            "Implied volatility of {} options at expiry {}",
+            fileio::get_currency().get_name(),
+            helpers::format_time(expiry)
+        ),
+        header: header.map(String::from),
+        x_label: x_axis.get_description().to_string(),
+        y_label: "Implied Volatility (σ)".to_string(),
+        series: vec![
+            series(
+                "Extrapolated data",
+                "grey",
+                HtmlGraphSeriesStyle::Line,
+                to_points(extrapolated_first_quarter_points),
+            ),
+            series("Observed data", "red", HtmlGraphSeriesStyle::Line, to_points(observed_data_points)),
+            series(
+                "Extrapolated data (high strikes)",
+                "grey",
+                HtmlGraphSeriesStyle::Line,
+                to_points(extrapolated_last_quarter_points),
+            ),
+            series(
+                "Forward price",
+                "red",
+                HtmlGraphSeriesStyle::DashedLine,
+                to_points(vec![forward_price_point, (forward_price_point.0, 0.0)]),
+            ),
+            series(
+                "Smile-relative implied volatility",
+                "blue",
+                HtmlGraphSeriesStyle::Points,
+                option_series(|x| x.smile_relative_implied_volatility),
+            ),
+            series(
+                "Self-relative implied volatility",
+                "grey",
+                HtmlGraphSeriesStyle::Points,
+                option_series(|x| x.self_relative_implied_volatility),
+            ),
+        ],
+    };
+
+    // Events only appear in the legend, since they can't be placed on a strike axis.
+    for label in event_labels {
+        graph
+            .series
+            .push(series(&format!("Event: {label}"), "black", HtmlGraphSeriesStyle::DashedLine, Vec::new()));
+    }
+
+    // First, so that the curve is drawn on top of it.
+    if !confidence_band.is_empty() {
+        let outline = get_band_outline(&confidence_band)
+            .into_iter()
+            .map(|(x, y)| HtmlGraphPoint {
+                x,
+                y,
+                label: None,
+                radius: None,
+            })
+            .collect();
+
+        graph
+            .series
+            .insert(0, series("Fit confidence band", "red", HtmlGraphSeriesStyle::Area, outline));
+    }
+
+    fileio::save_graph_to_html(&graph, &path)
+}
+
+/// The edge of the confidence band as a polygon: along its upper bounds, then back along its lower ones.
+fn get_band_outline(confidence_band: &[(f64, f64, f64)]) -> Vec<(f64, f64)> {
+    confidence_band
+        .iter()
+        .map(|(x, _, upper)| (*x, *upper))
+        .chain(
+            confidence_band
+                .iter()
+                .rev()
+                .map(|(x, lower, _)| (*x, *lower)),
+        )
+        .collect()
+}
+
+/// Graph g(k) (see analytics::svi_g()) against strike over the same range as the smile's graph, with a dashed line at
+/// zero, below which the smile has butterfly arbitrage. g(k) comes from the SVI curve alone, so in the wings it is the
+/// curve's own even if the smile is extrapolated some other way.
+fn create_g_function_graph(graph: &SmileGraph, format: GraphFormat, header: Option<&str>) -> Result<(), TsError> {
+    const POINTS: u64 = 400;
+
+    let expiry = graph.get_expiration()?;
+    let forward_price = graph.get_underlying_forward_price()?;
+    let strike_range = graph.highest_observed_strike - graph.lowest_observed_strike;
+    error_unless_positive_f64(strike_range, "strike_range")?;
+
+    let x_start = graph.lowest_observed_strike - (strike_range * 0.5);
+    let x_finish = graph.highest_observed_strike + (strike_range * 0.5);
+    let points = (0..=POINTS)
+        .map(|i| x_start + (x_finish - x_start) * (i as f64 / POINTS as f64))
+        .filter(|strike| *strike > 0.0)
+        .map(|strike| Ok((strike, analytics::svi_g(&graph.svi_curve_parameters, (strike / forward_price).ln())?)))
+        .collect::<Result<Vec<(f64, f64)>, TsError>>()?;
+    let x_start = points
+        .first()
+        .ok_or(TsError::new(RuntimeError, "No strikes to graph"))?
+        .0;
+    let y_start = points.iter().map(|x| x.1).fold(0.0, f64::min) * 1.05;
+    let y_finish = points.iter().map(|x| x.1).fold(0.0, f64::max) * 1.05;
+    let title = format!("g(k) of {} options at expiry {}", fileio::get_currency().get_name(), helpers::format_time(expiry));
+    let x_label = "Strike Price (K)";
+    let y_label = "g(k)";
+
+    if let GraphFormat::Html = format {
+        let path = fileio::get_data_path(
+            DataScope::Snapshot,
+            &format!(
+                "graphs/{}-g-function-graph-{}.html",
+                fileio::get_currency().get_file_code(),
+                expiry.format("%Y-%m-%d")
+            ),
+        );
+        println!("Creating graph at {path}...");
+
+        let to_points = |points: Vec<(f64, f64)>| {
+            points
+                .into_iter()
+                .map(|(x, y)| HtmlGraphPoint {
+                    x,
+                    y,
+                    label: None,
+                    radius: None,
+                })
+                .collect()
+        };
+        let graph = HtmlGraph {
+            title,
+            header: header.map(String::from),
+            x_label: x_label.to_string(),
+            y_label: y_label.to_string(),
+            series: vec![
+                HtmlGraphSeries {
+                    name: "g(k)".to_string(),
+                    colour: "blue".to_string(),
+                    style: HtmlGraphSeriesStyle::Line,
+                    points: to_points(points),
+                },
+                HtmlGraphSeries {
+                    name: "Butterfly arbitrage below".to_string(),
+                    colour: "red".to_string(),
+                    style: HtmlGraphSeriesStyle::DashedLine,
+                    points: to_points(vec![(x_start, 0.0), (x_finish, 0.0)]),
+                },
+                HtmlGraphSeries {
+                    name: "Forward price".to_string(),
+                    colour: "black".to_string(),
+                    style: HtmlGraphSeriesStyle::DashedLine,
+                    points: to_points(vec![(forward_price, y_start), (forward_price, y_finish)]),
+                },
+            ],
+        };
+
+        return fileio::save_graph_to_html(&graph, &path);
+    }
+
+    let path = fileio::get_data_path(
+        DataScope::Snapshot,
+        &format!(
+            "graphs/{}-g-function-graph-{}.png",
+            fileio::get_currency().get_file_code(),
+            expiry.format("%Y-%m-%d")
+        ),
+    );
+    let root = BitMapBackend::new(&path, (1920, 1080)).into_drawing_area();
+
+    println!("Creating graph at {path}...");
+
+    root.fill(&WHITE)
+        .map_err(|e| TsError::new(RuntimeError, format!("Filling graph failed: {}", e)).with_source(e))?;
+
+    let chart_area = match header {
+        Some(header) => root
+            .titled(header, ("sans-serif", 30))
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph header failed: {}", e)).with_source(e))?,
+        None => root.clone(),
+    };
+
+    let mut chart = ChartBuilder::on(&chart_area)
+        .caption(title, ("sans-serif", 50).into_font())
+        .margin(15)
+        .x_label_area_size(50)
+        .y_label_area_size(50)
+        .build_cartesian_2d(x_start..x_finish, y_start..y_finish)
+        .map_err(|e| TsError::new(RuntimeError, format!("Building graph failed: {}", e)).with_source(e))?;
+
+    chart
+        .configure_mesh()
+        .x_desc(x_label)
+        .y_desc(y_label)
+        .axis_desc_style(("sans-serif", 30))
+        .draw()
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph mesh failed: {}", e)).with_source(e))?;
+
+    chart
+        .draw_series(LineSeries::new(points, BLUE.stroke_width(2)))
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing g(k) failed: {}", e)).with_source(e))?
+        .label("g(k)")
+        .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], BLUE));
+
+    chart
+        .draw_series(DashedLineSeries::new(vec![(x_start, 0.0), (x_finish, 0.0)], 6, 4, ShapeStyle::from(RED)))
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing zero line failed: {}", e)).with_source(e))?
+        .label("Butterfly arbitrage below")
+        .legend(|(x, y)| DashedPathElement::new(vec![(x, y), (x + 20, y)], 6, 4, RED));
+
+    chart
+        .draw_series(DashedLineSeries::new(
+            vec![(forward_price, y_start), (forward_price, y_finish)],
+            6,
+            4,
+            ShapeStyle::from(BLACK),
+        ))
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing forward price line failed: {}", e)).with_source(e))?
+        .label("Forward price")
+        .legend(|(x, y)| DashedPathElement::new(vec![(x, y), (x + 20, y)], 6, 4, BLACK));
+
+    chart
+        .configure_series_labels()
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing series label failed: {}", e)).with_source(e))?;
+
+    root.present()
+        .map_err(|e| TsError::new(RuntimeError, format!("Finalising graph failed: {}", e)).with_source(e))?;
+
+    Ok(())
+}
+
+/// Describe each of the events that the smile's expiry prices in, for the graph's legend.
+fn describe_events_before_expiry(graph: &SmileGraph, events: &[VolEvent]) -> Result<Vec<String>, TsError> {
+    let expiry = graph.get_expiration()?;
+
+    Ok(analytics::get_events_before_expiry(events, graph.get_clock()?.now(), expiry)
+        .into_iter()
+        .map(|x| {
+            let days_before_expiry = (expiry - x.get_time()).num_seconds() as f64 / 86400.0;
+            format!("{} on {} ({} days before expiry)", x.label, x.date, days_before_expiry.round_to_decimal_places(1))
+        })
+        .collect())
+}
+
+/// Graph at the money implied volatility against days until expiry, with a dashed line at each event, so that expiries
+/// straddling events stand out.
+fn create_term_structure_graph(
+    smile_graphs: &[SmileGraph],
+    events: &[VolEvent],
+    format: GraphFormat,
+    header: Option<&str>,
+) -> Result<(), TsError> {
+    let now = smile_graphs
+        .first()
+        .ok_or(TsError::new(RuntimeError, "The surface has no smiles"))?
+        .get_clock()?
+        .now();
+    let to_days = |time: DateTime<Utc>| (time - now).num_seconds() as f64 / 86400.0;
+
+    let mut points = smile_graphs
+        .iter()
+        .map(|graph| {
+            let forward_price = graph.get_underlying_forward_price()?;
+            Ok((
+                to_days(graph.get_expiration()?),
+                graph.get_implied_volatility_at_strike(forward_price)?,
+                graph.get_expiration()?,
+            ))
+        })
+        .collect::<Result<Vec<(f64, f64, DateTime<Utc>)>, TsError>>()?;
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let last_day = points.iter().map(|x| x.0).fold(0.0, f64::max);
+    let y_finish = points.iter().map(|x| x.1).fold(0.0, f64::max) * 1.05;
+    let event_days: Vec<(&VolEvent, f64)> = events
+        .iter()
+        .map(|x| (x, to_days(x.get_time())))
+        .filter(|x| x.1 >= 0.0 && x.1 <= last_day)
+        .collect();
+    let title = &format!("At the money implied volatility term structure of {} options", fileio::get_currency().get_name());
+    let x_label = "Days Until Expiry";
+    let y_label = "At The Money Implied Volatility (σ)";
+
+    if let GraphFormat::Html = format {
+        let path = fileio::get_data_path(
+            DataScope::Snapshot,
+            &format!("graphs/{}-term-structure.html", fileio::get_currency().get_file_code()),
+        );
+        println!("Creating graph at {path}...");
+
+        let mut series = vec![HtmlGraphSeries {
+            name: "At the money implied volatility".to_string(),
+            colour: "red".to_string(),
+            style: HtmlGraphSeriesStyle::Line,
+            points: points
+                .iter()
+                .map(|(x, y, expiry)| HtmlGraphPoint {
+                    x: *x,
+                    y: *y,
+                    label: Some(format!("Expiry {}", helpers::format_time(*expiry))),
+                    radius: None,
+                })
+                .collect(),
+        }];
+
+        for (event, day) in event_days {
+            series.push(HtmlGraphSeries {
+                name: format!("Event: {} on {}", event.label, event.date),
+                colour: "black".to_string(),
+                style: HtmlGraphSeriesStyle::DashedLine,
+                points: [0.0, y_finish]
+                    .into_iter()
+                    .map(|y| HtmlGraphPoint {
+                        x: day,
+                        y,
+                        label: Some(event.label.clone()),
+                        radius: None,
+                    })
+                    .collect(),
+            });
+        }
+
+        let graph = HtmlGraph {
+            title: title.to_string(),
+            header: header.map(String::from),
+            x_label: x_label.to_string(),
+            y_label: y_label.to_string(),
+            series,
+        };
+
+        return fileio::save_graph_to_html(&graph, &path);
+    }
+
+    let path = fileio::get_data_path(
+        DataScope::Snapshot,
+        &format!("graphs/{}-term-structure.png", fileio::get_currency().get_file_code()),
+    );
+    let root = BitMapBackend::new(&path, (1920, 1080)).into_drawing_area();
+
+    println!("Creating graph at {path}...");
+
+    root.fill(&WHITE)
+        .map_err(|e| TsError::new(RuntimeError, format!("Filling graph failed: {}", e)).with_source(e))?;
+
+    let chart_area = match header {
+        Some(header) => root
+            .titled(header, ("sans-serif", 30))
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph header failed: {}", e)).with_source(e))?,
+        None => root.clone(),
+    };
+
+    let mut chart = ChartBuilder::on(&chart_area)
+        .caption(title, ("sans-serif", 50).into_font())
+        .margin(15)
+        .x_label_area_size(50)
+        .y_label_area_size(50)
+        .build_cartesian_2d(0.0..last_day * 1.05, 0.0..y_finish)
+        .map_err(|e| TsError::new(RuntimeError, format!("Building graph failed: {}", e)).with_source(e))?;
+
+    chart
+        .configure_mesh()
+        .x_desc(x_label)
+        .y_desc(y_label)
+        .axis_desc_style(("sans-serif", 30))
+        .draw()
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph mesh failed: {}", e)).with_source(e))?;
+
+    chart
+        .draw_series(LineSeries::new(points.iter().map(|x| (x.0, x.1)), RED))
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing term structure failed: {}", e)).with_source(e))?
+        .label("At the money implied volatility")
+        .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], RED));
+
+    chart
+        .draw_series(PointSeries::<_, _, Circle<_, _>, _>::new(points.iter().map(|x| (x.0, x.1)), 5, RED.filled()))
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing expiry points failed: {}", e)).with_source(e))?;
+
+    for (event, day) in event_days {
+        chart
+            .draw_series(DashedLineSeries::new(vec![(day, 0.0), (day, y_finish)], 6, 4, ShapeStyle::from(BLACK)))
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing event line failed: {}", e)).with_source(e))?;
+
+        chart
+            .draw_series(std::iter::once(Text::new(
+                event.label.clone(),
+                (day, y_finish * 0.97),
+                ("sans-serif", 20).into_font(),
+            )))
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing event label failed: {}", e)).with_source(e))?;
+    }
+
+    chart
+        .configure_series_labels()
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing series label failed: {}", e)).with_source(e))?;
+
+    root.present()
+        .map_err(|e| TsError::new(RuntimeError, format!("Finalising graph failed: {}", e)).with_source(e))?;
+
+    Ok(())
+}
+
+/// The colour of a smile on the overlay graph, from blue for the soonest expiry to red for the latest.
+fn get_overlay_colour(index: usize, count: usize) -> RGBColor {
+    let progress = match count {
+        0 | 1 => 0.0,
+        _ => index as f64 / (count - 1) as f64,
+    };
+
+    RGBColor((255.0 * progress).round() as u8, 0, (255.0 * (1.0 - progress)).round() as u8)
+}
+
+/// The smile's implied volatility across its observed strikes, against the overlay graph's x axis.
+fn build_overlay_line(graph: &SmileGraph, x_axis: OverlayXAxis, number_of_points: u64) -> Result<Vec<(f64, f64)>, TsError> {
+    let forward_price = graph.get_underlying_forward_price()?;
+    let years_until_expiry = graph.get_years_until_expiry()?;
+    error_unless_positive_f64(graph.lowest_observed_strike, "lowest_observed_strike")?;
+    error_unless_positive_f64(years_until_expiry, "years_until_expiry")?;
+
+    let lowest_log_moneyness = analytics::strike_to_log_moneyness(graph.lowest_observed_strike, forward_price);
+    let highest_log_moneyness = analytics::strike_to_log_moneyness(graph.highest_observed_strike, forward_price);
+
+    (0..=number_of_points)
+        .map(|i| {
+            let log_moneyness =
+                lowest_log_moneyness + (highest_log_moneyness - lowest_log_moneyness) * (i as f64 / number_of_points as f64);
+            let total_variance = graph.get_total_variance_at_log_moneyness(log_moneyness)?;
+            let x = match x_axis {
+                OverlayXAxis::Delta => analytics::calculate_forward_delta(log_moneyness, total_variance, OptionType::Call)?,
+                OverlayXAxis::LogMoneyness => log_moneyness,
+            };
+
+            Ok((x, (total_variance / years_until_expiry).sqrt()))
+        })
+        .collect()
+}
+
+/// Graph every expiry's smile over its observed strikes on one chart, coloured by expiry from blue (soonest) to red
+/// (latest), so that how the smile's shape changes along the term structure can be seen at once. Smiles that can't be
+/// drawn are left out rather than losing the whole graph.
+fn create_overlay_graph(
+    smile_graphs: &[SmileGraph],
+    x_axis: OverlayXAxis,
+    format: GraphFormat,
+    header: Option<&str>,
+) -> Result<(), TsError> {
+    let mut graphs = smile_graphs
+        .iter()
+        .map(|x| Ok((x.get_expiration()?, x)))
+        .collect::<Result<Vec<(DateTime<Utc>, &SmileGraph)>, TsError>>()?;
+    graphs.sort_by_key(|x| x.0);
+
+    let lines = graphs
+        .iter()
+        .filter_map(|(expiry, graph)| {
+            build_overlay_line(graph, x_axis, 200)
+                .inspect_err(|e| println!("Failed building overlay line for expiry {expiry}: {}, skipping...", e.reason))
+                .ok()
+                .map(|points| (*expiry, points))
+        })
+        .collect::<Vec<(DateTime<Utc>, Vec<(f64, f64)>)>>();
+
+    if lines.is_empty() {
+        return Err(TsError::new(RuntimeError, "No smiles to graph"));
+    }
+
+    let all_points = || lines.iter().flat_map(|x| x.1.iter());
+    let (x_start, x_finish) = match x_axis {
+        OverlayXAxis::Delta => (0.0, 1.0),
+        OverlayXAxis::LogMoneyness => (
+            all_points().map(|x| x.0).fold(f64::MAX, f64::min),
+            all_points().map(|x| x.0).fold(f64::MIN, f64::max),
+        ),
+    };
+    let y_finish = all_points().map(|x| x.1).fold(0.0, f64::max) * 1.05;
+    let title = &format!("Implied volatility smiles of {} options at every expiry", fileio::get_currency().get_name());
+    let y_label = "Implied Volatility (σ)";
+
+    if let GraphFormat::Html = format {
+        let path = fileio::get_data_path(
+            DataScope::Snapshot,
+            &format!("graphs/{}-smile-overlay-graph.html", fileio::get_currency().get_file_code()),
+        );
+        println!("Creating graph at {path}...");
+
+        let series = lines
+            .iter()
+            .enumerate()
+            .map(|(i, (expiry, points))| {
+                let RGBColor(red, green, blue) = get_overlay_colour(i, lines.len());
+
+                HtmlGraphSeries {
+                    name: format!("Expiry {}", expiry.format("%Y-%m-%d")),
+                    colour: format!("rgb({red}, {green}, {blue})"),
+                    style: HtmlGraphSeriesStyle::Line,
+                    points: points
+                        .iter()
+                        .map(|(x, y)| HtmlGraphPoint {
+                            x: *x,
+                            y: *y,
+                            label: None,
+                            radius: None,
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+
+        let graph = HtmlGraph {
+            title: title.to_string(),
+            header: header.map(String::from),
+            x_label: x_axis.get_description().to_string(),
+            y_label: y_label.to_string(),
+            series,
+        };
+
+        return fileio::save_graph_to_html(&graph, &path);
+    }
+
+    let path = fileio::get_data_path(
+        DataScope::Snapshot,
+        &format!("graphs/{}-smile-overlay-graph.png", fileio::get_currency().get_file_code()),
+    );
+    let root = BitMapBackend::new(&path, (1920, 1080)).into_drawing_area();
+
+    println!("Creating graph at {path}...");
+
+    root.fill(&WHITE)
+        .map_err(|e| TsError::new(RuntimeError, format!("Filling graph failed: {}", e)).with_source(e))?;
+
+    let chart_area = match header {
+        Some(header) => root
+            .titled(header, ("sans-serif", 30))
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph header failed: {}", e)).with_source(e))?,
+        None => root.clone(),
+    };
+
+    let mut chart = ChartBuilder::on(&chart_area)
+        .caption(title, ("sans-serif", 50).into_font())
+        .margin(15)
+        .x_label_area_size(50)
+        .y_label_area_size(50)
+        .build_cartesian_2d(x_start..x_finish, 0.0..y_finish)
+        .map_err(|e| TsError::new(RuntimeError, format!("Building graph failed: {}", e)).with_source(e))?;
+
+    chart
+        .configure_mesh()
+        .x_desc(x_axis.get_description())
+        .y_desc(y_label)
+        .axis_desc_style(("sans-serif", 30))
+        .draw()
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph mesh failed: {}", e)).with_source(e))?;
+
+    for (i, (expiry, points)) in lines.iter().enumerate() {
+        let colour = get_overlay_colour(i, lines.len());
+
+        chart
+            .draw_series(LineSeries::new(points.clone(), colour.stroke_width(2)))
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing smile failed: {}", e)).with_source(e))?
+            .label(format!("Expiry {}", expiry.format("%Y-%m-%d")))
+            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], colour));
+    }
+
     chart
         .configure_series_labels()
         .background_style(WHITE.mix(0.8))
         .border_style(BLACK)
         .draw()
-        .map_err(|e| TsError::new(RuntimeError, format!("Drawing series label failed: {}", e)))?;
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing series label failed: {}", e)).with_source(e))?;
 
     root.present()
-        .map_err(|e| TsError::new(RuntimeError, format!("Finalising graph failed: {}", e)))?;
+        .map_err(|e| TsError::new(RuntimeError, format!("Finalising graph failed: {}", e)).with_source(e))?;
 
     Ok(())
 }
@@ -0,0 +1,549 @@
+use std::f64::consts::E;
+
+use chrono::{DateTime, Utc};
+use plotters::element::DashedPathElement;
+use plotters::prelude::*;
+use plotters::style::full_palette::ORANGE;
+
+use crate::analytics::{self, OptionType, SmileGraph, SmileGraphsDataContainer};
+use crate::config::Config;
+use crate::constants;
+use crate::fileio::{self, DataScope, HtmlGraph, HtmlGraphPoint, HtmlGraphSeries, HtmlGraphSeriesStyle};
+use crate::helpers::{self, error_unless_positive_f64};
+use crate::routines::RunSummary;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+const GRAPHS_DIRECTORY: &str = "graphs/greeks/";
+
+/// How many strikes each greek is calculated at, for each expiry.
+const STRIKE_POINTS: u64 = 400;
+
+/// How many strikes each row of the heatmap is split into.
+const HEATMAP_STRIKE_POINTS: u64 = 100;
+
+/// A greek that is graphed against strike.
+#[derive(Clone, Copy)]
+enum Greek {
+    Delta,
+    Gamma,
+    Vega,
+}
+
+impl Greek {
+    fn name(self) -> &'static str {
+        match self {
+            Greek::Delta => "delta",
+            Greek::Gamma => "gamma",
+            Greek::Vega => "vega",
+        }
+    }
+
+    fn get_description(self) -> &'static str {
+        match self {
+            Greek::Delta => "Delta (Δ)",
+            Greek::Gamma => "Gamma (Γ, per $1)",
+            Greek::Vega => "Vega (per 1% vol)",
+        }
+    }
+
+    /// Format a value for an axis label. Gamma per $1 is tiny, so it's shown in scientific notation.
+    fn format_value(self, value: f64) -> String {
+        match self {
+            Greek::Gamma => format!("{value:.2e}"),
+            Greek::Delta | Greek::Vega => format!("{value:.2}"),
+        }
+    }
+}
+
+impl TryFrom<&str> for Greek {
+    type Error = TsError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "delta" => Ok(Greek::Delta),
+            "gamma" => Ok(Greek::Gamma),
+            "vega" => Ok(Greek::Vega),
+            other => Err(TsError::new(RuntimeError, format!("Unknown greek {other}, expected delta, gamma or vega"))),
+        }
+    }
+}
+
+/// The kind of file the graphs are saved as.
+#[derive(Clone, Copy)]
+enum GraphFormat {
+    Png,
+    /// A self-contained interactive page for each greek.
+    Html,
+}
+
+/// The greeks of the options at one strike, using the smile's implied volatility there.
+struct StrikeGreeks {
+    strike: f64,
+    call_delta: f64,
+    put_delta: f64,
+    gamma: f64,
+    /// Per percentage point of volatility.
+    vega: f64,
+}
+
+impl StrikeGreeks {
+    /// Work out the Black-Scholes greeks at the strike, with the smile's implied volatility there (rather than the
+    /// exchange's greeks, which only exist at listed strikes).
+    fn new(graph: &SmileGraph, strike: f64) -> Result<Self, TsError> {
+        let rate = constants::INTEREST_FREE_RATE;
+        let years_until_expiry = graph.get_years_until_expiry()?;
+        let volatility = graph.get_implied_volatility_at_strike(strike)?;
+        // Any yield is already in the forward price the spot price is implied from.
+        let spot_price = graph.get_underlying_forward_price()? * E.powf(-rate * years_until_expiry);
+        let delta = |option_type| {
+            analytics::calculate_black_scholes_delta(spot_price, strike, years_until_expiry, rate, 0.0, volatility, option_type)
+        };
+
+        Ok(Self {
+            strike,
+            call_delta: delta(OptionType::Call)?,
+            put_delta: delta(OptionType::Put)?,
+            gamma: analytics::calculate_black_scholes_gamma(spot_price, strike, years_until_expiry, rate, 0.0, volatility)?,
+            vega: analytics::calculate_black_scholes_vega(spot_price, strike, years_until_expiry, rate, 0.0, volatility)? / 100.0,
+        })
+    }
+
+    fn get(&self, greek: Greek) -> f64 {
+        match greek {
+            // The heatmap shows calls, whose delta is positive.
+            Greek::Delta => self.call_delta,
+            Greek::Gamma => self.gamma,
+            Greek::Vega => self.vega,
+        }
+    }
+}
+
+/// Graph delta, gamma and vega against strike for each expiry, calculated from the fitted smiles, and optionally a heatmap of
+/// one of them across the whole surface.
+pub fn build_greek_graphs(args: &[String], config: &Config, run: &mut RunSummary) -> Result<(), TsError> {
+    println!("===============================================================");
+    println!("===============================================================");
+    println!("Building {} option greek graphs and saving to file", fileio::get_currency().get_name());
+    println!("===============================================================");
+    println!("===============================================================");
+
+    let (format, heatmap_greek) = get_graph_options(args).map_err(|e| e.context("Invalid arguments"))?;
+
+    println!("Loading surface data...");
+    let mut data = run.step("Loading surface data", || {
+        fileio::load_versioned_struct_from_file::<SmileGraphsDataContainer>(&fileio::find_data_file(&fileio::get_data_path(
+            DataScope::Snapshot,
+            "smile-graph-data.json",
+        )))
+    })?;
+
+    for graph in &mut data.smile_graphs {
+        graph.wing_extrapolation = config.wing_extrapolation;
+    }
+    println!("Found {} smile graphs...", data.smile_graphs.len());
+
+    println!("Deleting any existing greek graphs...");
+    let directory = fileio::get_data_path(DataScope::Snapshot, GRAPHS_DIRECTORY);
+    run.step("Creating graphs directory", || fileio::create_directory(&directory))?;
+    run.step("Clearing graphs directory", || fileio::clear_directory(&directory, "gitkeep"))?;
+    println!("------------------------------");
+
+    println!("Creating graphs and saving to file...");
+
+    let header = config.report_header.render();
+
+    for graph in &data.smile_graphs {
+        let _ = build_expiry_greek_graphs(graph, format, header.as_deref())
+            .inspect_err(|e| println!("Failed building greek graphs: {}, skipping...", e.reason));
+    }
+
+    if let Some(greek) = heatmap_greek {
+        run.optional_step(&format!("Building {} heatmap", greek.name()), || {
+            create_heatmap(&data.smile_graphs, greek, header.as_deref())
+        });
+    }
+
+    println!("Done!");
+    println!("===============================================================");
+
+    Ok(())
+}
+
+/// Read the graph format and the greek to draw a heatmap of (if one was asked for) from the command line flags.
+fn get_graph_options(args: &[String]) -> Result<(GraphFormat, Option<Greek>), TsError> {
+    let format = match helpers::get_flag_value(args, "--format")? {
+        None | Some("png") => GraphFormat::Png,
+        Some("html") => GraphFormat::Html,
+        Some(other) => return Err(TsError::new(RuntimeError, format!("Unknown graph format {other}"))),
+    };
+    let heatmap_greek = helpers::get_flag_value(args, "--heatmap")?
+        .map(Greek::try_from)
+        .transpose()?;
+
+    Ok((format, heatmap_greek))
+}
+
+/// Calculate the greeks at evenly spaced strikes from the lowest strike to the highest, leaving out strikes at or below
+/// zero.
+fn get_strike_greeks(
+    graph: &SmileGraph,
+    lowest_strike: f64,
+    highest_strike: f64,
+    points: u64,
+) -> Result<Vec<StrikeGreeks>, TsError> {
+    (0..points)
+        .map(|i| lowest_strike + (highest_strike - lowest_strike) * (i as f64 / (points - 1) as f64))
+        .filter(|strike| *strike > 0.0)
+        .map(|strike| StrikeGreeks::new(graph, strike))
+        .collect()
+}
+
+/// Graph the expiry's greeks from half the observed strike range below the lowest observed strike to half above the
+/// highest, as build-graphs does for implied volatility.
+fn build_expiry_greek_graphs(graph: &SmileGraph, format: GraphFormat, header: Option<&str>) -> Result<(), TsError> {
+    let strike_range = graph.highest_observed_strike - graph.lowest_observed_strike;
+    error_unless_positive_f64(strike_range, "strike_range")?;
+
+    let greeks = get_strike_greeks(
+        graph,
+        graph.lowest_observed_strike - strike_range * 0.5,
+        graph.highest_observed_strike + strike_range * 0.5,
+        STRIKE_POINTS,
+    )?;
+    let expiry = graph.get_expiration()?;
+    let forward_price = graph.get_underlying_forward_price()?;
+
+    match format {
+        GraphFormat::Png => create_graph(expiry, &greeks, forward_price, header),
+        GraphFormat::Html => {
+            for greek in [Greek::Delta, Greek::Gamma, Greek::Vega] {
+                create_html_graph(expiry, greek, &greeks, forward_price, header)?;
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// A line drawn on a greek's graph.
+struct GreekLine {
+    name: &'static str,
+    colour: RGBColor,
+    /// The colour's name, for HTML graphs.
+    colour_name: &'static str,
+    points: Vec<(f64, f64)>,
+}
+
+/// The lines drawn on a greek's graph, with their names and colours.
+fn get_lines(greek: Greek, greeks: &[StrikeGreeks]) -> Vec<GreekLine> {
+    let line = |name, colour, colour_name, get: fn(&StrikeGreeks) -> f64| GreekLine {
+        name,
+        colour,
+        colour_name,
+        points: greeks.iter().map(|x| (x.strike, get(x))).collect(),
+    };
+
+    match greek {
+        Greek::Delta => vec![
+            line("Call delta", BLUE, "blue", |x| x.call_delta),
+            line("Put delta", RED, "red", |x| x.put_delta),
+        ],
+        Greek::Gamma => vec![line("Gamma", ORANGE, "orange", |x| x.gamma)],
+        Greek::Vega => vec![line("Vega", GREEN, "green", |x| x.vega)],
+    }
+}
+
+/// Save delta, gamma and vega as three graphs stacked in one image.
+fn create_graph(expiry: DateTime<Utc>, greeks: &[StrikeGreeks], forward_price: f64, header: Option<&str>) -> Result<(), TsError> {
+    let path = fileio::get_data_path(
+        DataScope::Snapshot,
+        &format!(
+            "{GRAPHS_DIRECTORY}{}-greeks-graph-{}.png",
+            fileio::get_currency().get_file_code(),
+            expiry.format("%Y-%m-%d")
+        ),
+    );
+    let first_strike = greeks
+        .first()
+        .ok_or(TsError::new(RuntimeError, "No strikes to graph"))?
+        .strike;
+    let last_strike = greeks
+        .last()
+        .ok_or(TsError::new(RuntimeError, "No strikes to graph"))?
+        .strike;
+    let root = BitMapBackend::new(&path, (1920, 2160)).into_drawing_area();
+
+    println!("Creating graph at {path}...");
+
+    root.fill(&WHITE)
+        .map_err(|e| TsError::new(RuntimeError, format!("Filling graph failed: {}", e)).with_source(e))?;
+
+    let root = match header {
+        Some(header) => root
+            .titled(header, ("sans-serif", 30))
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph header failed: {}", e)).with_source(e))?,
+        None => root,
+    };
+    let root = root
+        .titled(
+            &format!("Greeks of {} options at expiry {}", fileio::get_currency().get_name(), helpers::format_time(expiry)),
+            ("sans-serif", 50),
+        )
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph title failed: {}", e)).with_source(e))?;
+
+    for (area, greek) in root
+        .split_evenly((3, 1))
+        .iter()
+        .zip([Greek::Delta, Greek::Gamma, Greek::Vega])
+    {
+        let lines = get_lines(greek, greeks);
+        let values = || {
+            lines
+                .iter()
+                .flat_map(|x| x.points.iter().map(|point| point.1))
+        };
+        let y_start = values().fold(0.0, f64::min) * 1.05;
+        let y_finish = values().fold(0.0, f64::max) * 1.05;
+
+        let mut chart = ChartBuilder::on(area)
+            .margin(15)
+            .x_label_area_size(50)
+            .y_label_area_size(100)
+            .build_cartesian_2d(first_strike..last_strike, y_start..y_finish)
+            .map_err(|e| TsError::new(RuntimeError, format!("Building graph failed: {}", e)).with_source(e))?;
+
+        chart
+            .configure_mesh()
+            .x_desc("Strike Price (K)")
+            .y_desc(greek.get_description())
+            .y_label_formatter(&|y| greek.format_value(*y))
+            .axis_desc_style(("sans-serif", 30))
+            .draw()
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph mesh failed: {}", e)).with_source(e))?;
+
+        for GreekLine {
+            name, colour, points, ..
+        } in lines
+        {
+            chart
+                .draw_series(LineSeries::new(points, colour.stroke_width(2)))
+                .map_err(|e| TsError::new(RuntimeError, format!("Drawing {name} failed: {}", e)).with_source(e))?
+                .label(name)
+                .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], colour));
+        }
+
+        chart
+            .draw_series(DashedLineSeries::new(
+                vec![(forward_price, y_start), (forward_price, y_finish)],
+                6,
+                4,
+                ShapeStyle::from(BLACK),
+            ))
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing forward price line failed: {}", e)).with_source(e))?
+            .label("Forward price")
+            .legend(|(x, y)| DashedPathElement::new(vec![(x, y), (x + 20, y)], 6, 4, BLACK));
+
+        chart
+            .configure_series_labels()
+            .background_style(WHITE.mix(0.8))
+            .border_style(BLACK)
+            .draw()
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing series label failed: {}", e)).with_source(e))?;
+    }
+
+    root.present()
+        .map_err(|e| TsError::new(RuntimeError, format!("Finalising graph failed: {}", e)).with_source(e))?;
+
+    Ok(())
+}
+
+/// Save one greek's graph as an interactive HTML page.
+fn create_html_graph(
+    expiry: DateTime<Utc>,
+    greek: Greek,
+    greeks: &[StrikeGreeks],
+    forward_price: f64,
+    header: Option<&str>,
+) -> Result<(), TsError> {
+    let path = fileio::get_data_path(
+        DataScope::Snapshot,
+        &format!(
+            "{GRAPHS_DIRECTORY}{}-{}-graph-{}.html",
+            fileio::get_currency().get_file_code(),
+            greek.name(),
+            expiry.format("%Y-%m-%d")
+        ),
+    );
+
+    println!("Creating graph at {path}...");
+
+    let lines = get_lines(greek, greeks);
+    let values = || {
+        lines
+            .iter()
+            .flat_map(|x| x.points.iter().map(|point| point.1))
+    };
+    let (y_start, y_finish) = (values().fold(0.0, f64::min), values().fold(0.0, f64::max));
+    let to_points = |points: Vec<(f64, f64)>| {
+        points
+            .into_iter()
+            .map(|(x, y)| HtmlGraphPoint {
+                x,
+                y,
+                label: None,
+                radius: None,
+            })
+            .collect()
+    };
+
+    let mut series: Vec<HtmlGraphSeries> = lines
+        .iter()
+        .map(|x| HtmlGraphSeries {
+            name: x.name.to_string(),
+            colour: x.colour_name.to_string(),
+            style: HtmlGraphSeriesStyle::Line,
+            points: to_points(x.points.clone()),
+        })
+        .collect();
+    series.push(HtmlGraphSeries {
+        name: "Forward price".to_string(),
+        colour: "black".to_string(),
+        style: HtmlGraphSeriesStyle::DashedLine,
+        points: to_points(vec![(forward_price, y_start), (forward_price, y_finish)]),
+    });
+
+    let graph = HtmlGraph {
+        title: format!(
+            "{} of {} options at expiry {}",
+            greek.get_description(),
+            fileio::get_currency().get_name(),
+            helpers::format_time(expiry)
+        ),
+        header: header.map(String::from),
+        x_label: "Strike Price (K)".to_string(),
+        y_label: greek.get_description().to_string(),
+        series,
+    };
+
+    fileio::save_graph_to_html(&graph, &path)
+}
+
+/// Save a heatmap of the greek across strike and expiry, from white (zero) to red (the largest value anywhere on the
+/// surface), so that strikes and expiries where it concentrates stand out. Strikes cover the observed range of every
+/// expiry, and each expiry is a row, soonest at the top.
+fn create_heatmap(smile_graphs: &[SmileGraph], greek: Greek, header: Option<&str>) -> Result<(), TsError> {
+    let path = fileio::get_data_path(
+        DataScope::Snapshot,
+        &format!("{GRAPHS_DIRECTORY}{}-{}-heatmap.png", fileio::get_currency().get_file_code(), greek.name()),
+    );
+
+    println!("Creating heatmap at {path}...");
+
+    let mut graphs = smile_graphs
+        .iter()
+        .map(|x| Ok((x.get_expiration()?, x)))
+        .collect::<Result<Vec<(DateTime<Utc>, &SmileGraph)>, TsError>>()?;
+    graphs.sort_by_key(|x| x.0);
+
+    let lowest_strike = graphs
+        .iter()
+        .map(|x| x.1.lowest_observed_strike)
+        .fold(f64::MAX, f64::min);
+    let highest_strike = graphs
+        .iter()
+        .map(|x| x.1.highest_observed_strike)
+        .fold(0.0, f64::max);
+    error_unless_positive_f64(highest_strike - lowest_strike, "strike_range")?;
+
+    // Expiries whose greeks can't be calculated (e.g. because they've expired) are left out rather than losing the whole
+    // heatmap.
+    let rows = graphs
+        .iter()
+        .filter_map(|(expiry, graph)| {
+            get_strike_greeks(graph, lowest_strike, highest_strike, HEATMAP_STRIKE_POINTS)
+                .inspect_err(|e| println!("Failed calculating greeks for expiry {expiry}: {}, skipping...", e.reason))
+                .ok()
+                .map(|greeks| {
+                    (
+                        *expiry,
+                        greeks
+                            .iter()
+                            .map(|x| (x.strike, x.get(greek)))
+                            .collect::<Vec<(f64, f64)>>(),
+                    )
+                })
+        })
+        .collect::<Vec<(DateTime<Utc>, Vec<(f64, f64)>)>>();
+    let highest_value = rows
+        .iter()
+        .flat_map(|x| x.1.iter().map(|point| point.1.abs()))
+        .fold(0.0, f64::max);
+    error_unless_positive_f64(highest_value, "highest_value")?;
+
+    let cell_width = (highest_strike - lowest_strike) / (HEATMAP_STRIKE_POINTS - 1) as f64;
+    let root = BitMapBackend::new(&path, (1920, 1080)).into_drawing_area();
+
+    root.fill(&WHITE)
+        .map_err(|e| TsError::new(RuntimeError, format!("Filling graph failed: {}", e)).with_source(e))?;
+
+    let chart_area = match header {
+        Some(header) => root
+            .titled(header, ("sans-serif", 30))
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph header failed: {}", e)).with_source(e))?,
+        None => root.clone(),
+    };
+
+    // Each expiry is a row, with the soonest at the top.
+    let row_count = rows.len() as i32;
+    let mut chart = ChartBuilder::on(&chart_area)
+        .caption(
+            format!("{} of {} options across the surface", greek.get_description(), fileio::get_currency().get_name()),
+            ("sans-serif", 50).into_font(),
+        )
+        .margin(15)
+        .x_label_area_size(50)
+        .y_label_area_size(160)
+        .build_cartesian_2d(lowest_strike - cell_width * 0.5..highest_strike + cell_width * 0.5, (0..row_count).into_segmented())
+        .map_err(|e| TsError::new(RuntimeError, format!("Building graph failed: {}", e)).with_source(e))?;
+
+    chart
+        .configure_mesh()
+        .disable_mesh()
+        .x_desc("Strike Price (K)")
+        .y_desc("Expiry")
+        .y_labels(rows.len())
+        .y_label_formatter(&|y| match y {
+            SegmentValue::CenterOf(row) => rows
+                .get((row_count - 1 - row) as usize)
+                .map(|x| x.0.format("%Y-%m-%d").to_string())
+                .unwrap_or_default(),
+            _ => String::new(),
+        })
+        .axis_desc_style(("sans-serif", 30))
+        .draw()
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph mesh failed: {}", e)).with_source(e))?;
+
+    for (row, (_, points)) in rows.iter().enumerate() {
+        let y = row_count - 1 - row as i32;
+
+        chart
+            .draw_series(points.iter().map(|(strike, value)| {
+                let fade = (255.0 * (1.0 - value.abs() / highest_value)).round() as u8;
+
+                Rectangle::new(
+                    [
+                        (strike - cell_width * 0.5, SegmentValue::Exact(y)),
+                        (strike + cell_width * 0.5, SegmentValue::Exact(y + 1)),
+                    ],
+                    RGBColor(255, fade, fade).filled(),
+                )
+            }))
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing heatmap row failed: {}", e)).with_source(e))?;
+    }
+
+    root.present()
+        .map_err(|e| TsError::new(RuntimeError, format!("Finalising graph failed: {}", e)).with_source(e))?;
+
+    Ok(())
+}
@@ -1,15 +1,431 @@
-use std::collections::HashMap;
-use std::collections::hash_map::Entry;
-use std::time::Instant;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::analytics::{OptionInstrument, SmileGraph, SmileGraphsDataContainer};
-use crate::helpers::F64Helpers;
-use crate::integrations::DeribitDataContainer;
+use chrono::{DateTime, NaiveDate, Utc};
+
+use crate::analytics::{
+    self, CalendarArbitrage, ConfidenceInterval, CrossValidation, ExpiryCategory, ExpiryCategoryFilter, FitQualityReport,
+    FitTimeModel, FitTimeRecord, ForwardInputs, ForwardProvenance, ForwardQuote, ImpliedVolatilitySource, OptionInstrument,
+    PerpetualQuote, PutCallPairing, PutCallParitySettings, SmileConfidenceBand, SmileGraph, SmileGraphsDataContainer, Straddle,
+    SurfaceBuildInfo, SurfaceModel, SurfaceScore, SviOptimiser, SyntheticSmile, TermStructureSmoothing,
+};
+use crate::config::Config;
+use crate::fileio::{CsvFormat, DataScope, NdjsonWriter, SchemaVersioned};
+use crate::helpers::{CancellationToken, Clock, F64Helpers, ProgressBar};
+use crate::integrations::{DeribitDataContainer, DeribitFuture, DeribitOptionInstrument, DeribitPerpetualTickerData};
+use crate::routines::RunSummary;
+use crate::routines::discard_report::{DiscardReport, DiscardStage, DiscardedSmile};
+use crate::routines::quote_log;
+use crate::routines::surface_file;
 use crate::types::TsError;
-use crate::{constants, fileio};
+use crate::types::TsErrorType::{RuntimeError, UnusableAPIData};
+use crate::{constants, fileio, helpers};
+
+/// Prefixed with the exchange and currency (see fileio::get_market_data_path()).
+const MARKET_DATA_FILE_NAME: &str = "market-data.json";
+const NDJSON_MARKET_DATA_FILE_NAME: &str = "market-data.ndjson";
+const PERPETUAL_DATA_FILE_NAME: &str = "perpetual.json";
+const FIT_TIME_HISTORY_FILE_NAME: &str = "fit-time-history.ndjson";
+const FIT_QUALITY_REPORT_FILE_NAME: &str = "fit-quality-report.json";
+
+/// The options in the saved API data, which are read one at a time if the data is newline-delimited.
+type SavedOptions = Box<dyn Iterator<Item = Result<DeribitOptionInstrument, TsError>>>;
+
+/// The saved API data: its options, when it was captured (if known) and its futures.
+type SavedData = (SavedOptions, Option<DateTime<Utc>>, Vec<DeribitFuture>);
+
+/// How the saved surface was built, and its smiles by expiry.
+type SavedSmiles = (SurfaceBuildInfo, HashMap<DateTime<Utc>, SmileGraph>);
+
+/// The number of strikes per expiry that the bid and ask surfaces are compared at.
+const QUOTE_SIDE_STRIKE_POINTS: u64 = 25;
+
+/// Expiries at most this many days away are left out by --skip-dailies.
+const DAILY_MAX_DAYS_UNTIL_EXPIRY: f64 = 2.0;
+
+/// Smiles with at most this many strikes are pulled towards the saved surface's fits by --regularise. Fewer strikes than
+/// this can't pin down SVI's five parameters well enough for the fit to stay put from one build to the next.
+const REGULARISED_MAX_STRIKES: usize = 8;
+
+/// The fraction of resampled fits that the confidence bands of --bootstrap hold.
+const BOOTSTRAP_CONFIDENCE_LEVEL: f64 = 0.9;
+
+/// Options that change how the surface is built, set via command line flags.
+struct BuildSurfaceOptions {
+    /// A CSV file of prices to use instead of the downloaded ones.
+    price_overrides_path: Option<String>,
+    csv_format: CsvFormat,
+    /// Load the market data from newline-delimited JSON rather than one big JSON object.
+    ndjson: bool,
+    /// Load the market data from the quote log, taking each option's latest quote as of --as-of (or now).
+    quote_log: bool,
+    /// Don't print fitting progress.
+    quiet: bool,
+    /// Adjust prices so they are convex in strike before fitting.
+    repair_convexity: bool,
+    /// Stop before fitting, printing what would be fit.
+    dry_run: bool,
+    /// What times until expiry are measured from, instead of when the data was downloaded.
+    as_of: Option<Clock>,
+    /// Make nothing saved depend on when the surface was built, so that building from the same data always gives the
+    /// same file.
+    deterministic: bool,
+    /// Save the surface compressed with zstd.
+    compress: bool,
+    /// The number of smiles fit at once.
+    threads: usize,
+    /// The number of threads each smile's starting curves are optimised on.
+    search_threads: usize,
+    /// Whether the smiles are fit to implied volatilities solved from the prices, or to the exchange's.
+    iv_source: ImpliedVolatilitySource,
+    /// Also fit surfaces to the bid, mid and ask prices.
+    quote_sides: bool,
+    /// Before fitting, fit every smile with each SVI optimiser and print how well and how quickly each did.
+    compare_optimisers: bool,
+    /// After fitting, refit every smile with each fold of its options held out and print how well the held out options
+    /// were predicted.
+    cross_validation: Option<CrossValidation>,
+    /// Roughly how long fitting should take, shared between the smiles by how long each is predicted to take.
+    fit_budget: Option<Duration>,
+    /// Stop, rather than carrying on without them, if any options or smiles are discarded.
+    strict: bool,
+    /// Build each smile from only its out of the money options.
+    otm_only: bool,
+    /// Which expiries to build smiles for.
+    expiry_filter: ExpiryFilter,
+    /// Reuse the saved surface's fit of any smile whose quotes haven't changed by more than this many implied volatility
+    /// points, rather than fitting it again.
+    incremental_tolerance: Option<f64>,
+    /// Discard quotes taken more than this many seconds before the snapshot, since they may no longer match the rest.
+    max_quote_age_seconds: Option<f64>,
+    /// Pull the fits of smiles with few strikes towards the saved surface's fits of the same expiries, this strongly.
+    regularisation_strength: Option<f64>,
+    /// Make every fit pass within this many implied volatility points of its expiry's at the money straddle.
+    atm_anchor_tolerance: Option<f64>,
+    /// After fitting, refit every smile this many times to its residuals resampled, and save the confidence bands of its
+    /// parameters and implied volatilities with it.
+    bootstrap_resamples: Option<usize>,
+    /// An SQLite database to also save the fitted SVI parameters to.
+    #[cfg(feature = "storage-sqlite")]
+    db_path: Option<String>,
+}
+
+impl BuildSurfaceOptions {
+    fn from_args(args: &[String]) -> Result<Self, TsError> {
+        Ok(Self {
+            price_overrides_path: helpers::get_flag_value(args, "--price-overrides")?.map(|x| x.to_string()),
+            csv_format: CsvFormat::from_args(args)?,
+            ndjson: helpers::has_flag(args, "--ndjson"),
+            quote_log: helpers::has_flag(args, "--quote-log"),
+            quiet: helpers::has_flag(args, "--quiet"),
+            repair_convexity: helpers::has_flag(args, "--repair-convexity"),
+            dry_run: helpers::has_flag(args, "--dry-run"),
+            as_of: match helpers::get_flag_value(args, "--as-of")? {
+                None => None,
+                Some("now") => Some(Clock::Real),
+                Some(v) => Some(Clock::Fixed(
+                    DateTime::parse_from_rfc3339(v)
+                        .map_err(|e| {
+                            TsError::new(RuntimeError, format!("Invalid --as-of {v}, expected RFC 3339 or now: {e}"))
+                                .with_source(e)
+                        })?
+                        .to_utc(),
+                )),
+            },
+            deterministic: helpers::has_flag(args, "--deterministic"),
+            compress: helpers::has_flag(args, "--compress"),
+            threads: match helpers::get_flag_value(args, "--threads")? {
+                None => 1,
+                Some(v) => v
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|x| *x > 0)
+                    .ok_or(TsError::new(RuntimeError, format!("Invalid --threads {v}, expected a whole number above 0")))?,
+            },
+            search_threads: match helpers::get_flag_value(args, "--search-threads")? {
+                None => 1,
+                Some(v) => v
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|x| *x > 0)
+                    .ok_or(TsError::new(
+                        RuntimeError,
+                        format!("Invalid --search-threads {v}, expected a whole number above 0"),
+                    ))?,
+            },
+            iv_source: match helpers::get_flag_value(args, "--iv-source")? {
+                None | Some("solve") => ImpliedVolatilitySource::Solve,
+                Some("exchange") => ImpliedVolatilitySource::Exchange,
+                Some(v) => {
+                    return Err(TsError::new(RuntimeError, format!("Invalid --iv-source {v}, expected exchange or solve")));
+                }
+            },
+            quote_sides: helpers::has_flag(args, "--quote-sides"),
+            compare_optimisers: helpers::has_flag(args, "--compare-optimisers"),
+            cross_validation: match helpers::get_flag_value(args, "--cross-validate")? {
+                None => None,
+                Some(v) => Some(CrossValidation::try_from(v)?),
+            },
+            fit_budget: match helpers::get_flag_value(args, "--fit-budget")? {
+                None => None,
+                Some(v) => Some(
+                    v.parse::<f64>()
+                        .ok()
+                        .and_then(|x| Duration::try_from_secs_f64(x).ok())
+                        .filter(|x| !x.is_zero())
+                        .ok_or(TsError::new(RuntimeError, format!("Invalid --fit-budget {v}, expected a number of seconds")))?,
+                ),
+            },
+            strict: helpers::has_flag(args, "--strict"),
+            otm_only: helpers::has_flag(args, "--otm-only"),
+            expiry_filter: ExpiryFilter::from_args(args)?,
+            incremental_tolerance: match (
+                helpers::has_flag(args, "--incremental"),
+                helpers::get_flag_value(args, "--refit-tolerance")?,
+            ) {
+                (false, None) => None,
+                (false, Some(_)) => return Err(TsError::new(RuntimeError, "--refit-tolerance needs --incremental")),
+                (true, None) => Some(0.0),
+                (true, Some(v)) => Some(
+                    v.parse::<f64>()
+                        .ok()
+                        .filter(|x| x.is_finite() && *x >= 0.0)
+                        .ok_or(TsError::new(RuntimeError, format!("Invalid --refit-tolerance {v}, expected a number >= 0")))?,
+                ),
+            },
+            max_quote_age_seconds: match helpers::get_flag_value(args, "--max-quote-age")? {
+                None => None,
+                Some(v) => Some(
+                    v.parse::<f64>()
+                        .ok()
+                        .filter(|x| x.is_finite() && *x >= 0.0)
+                        .ok_or(TsError::new(
+                            RuntimeError,
+                            format!("Invalid --max-quote-age {v}, expected a number of seconds"),
+                        ))?,
+                ),
+            },
+            regularisation_strength: match helpers::get_flag_value(args, "--regularise")? {
+                None => None,
+                Some(v) => Some(
+                    v.parse::<f64>()
+                        .ok()
+                        .filter(|x| x.is_finite() && *x > 0.0)
+                        .ok_or(TsError::new(RuntimeError, format!("Invalid --regularise {v}, expected a number above 0")))?,
+                ),
+            },
+            atm_anchor_tolerance: match helpers::get_flag_value(args, "--anchor-atm")? {
+                None => None,
+                Some(v) => Some(
+                    v.parse::<f64>()
+                        .ok()
+                        .filter(|x| x.is_finite() && *x >= 0.0)
+                        .ok_or(TsError::new(
+                            RuntimeError,
+                            format!("Invalid --anchor-atm {v}, expected a number of vol points"),
+                        ))?,
+                ),
+            },
+            bootstrap_resamples: match helpers::get_flag_value(args, "--bootstrap")? {
+                None => None,
+                Some(v) => Some(
+                    v.parse::<usize>()
+                        .ok()
+                        .filter(|x| *x > 1)
+                        .ok_or(TsError::new(RuntimeError, format!("Invalid --bootstrap {v}, expected a whole number above 1")))?,
+                ),
+            },
+            #[cfg(feature = "storage-sqlite")]
+            db_path: helpers::get_flag_value(args, "--db")?.map(|x| x.to_string()),
+        })
+    }
+
+    fn get_fit_options(&self, cancel: &CancellationToken) -> FitOptions {
+        FitOptions {
+            quiet: self.quiet,
+            threads: self.threads,
+            search_threads: self.search_threads,
+            fit_budget: self.fit_budget,
+            cancel: cancel.clone(),
+        }
+    }
+}
+
+/// How the smiles of a surface are fit with SVI.
+struct FitOptions {
+    /// Don't print fitting progress.
+    quiet: bool,
+    /// The number of smiles fit at once.
+    threads: usize,
+    /// The number of threads each search pass's starting curves are optimised on.
+    search_threads: usize,
+    /// Roughly how long fitting should take, shared between the smiles by how long each is predicted to take.
+    fit_budget: Option<Duration>,
+    /// Once cancelled, the smiles being fit are finished but no more are started, leaving the rest unfit.
+    cancel: CancellationToken,
+}
+
+/// Which expiries smiles are built for, so that one expiry or a range of them can be looked at without the rest. Unlike
+/// the instrument filters in the config, these are for one run.
+#[derive(Default)]
+struct ExpiryFilter {
+    /// Only the expiry on this date (UTC) is used.
+    expiry: Option<NaiveDate>,
+    /// Expiries fewer than this many days away are left out.
+    min_days_until_expiry: Option<f64>,
+    /// Expiries more than this many days away are left out.
+    max_days_until_expiry: Option<f64>,
+    /// Leave out expiries at most DAILY_MAX_DAYS_UNTIL_EXPIRY days away, which have so little time value that they rarely
+    /// fit well.
+    skip_dailies: bool,
+    /// Which categories of expiry (daily, weekly, monthly or quarterly) are used.
+    categories: ExpiryCategoryFilter,
+}
+
+impl ExpiryFilter {
+    fn from_args(args: &[String]) -> Result<Self, TsError> {
+        let parse_days = |flag: &str| -> Result<Option<f64>, TsError> {
+            helpers::get_flag_value(args, flag)?
+                .map(|v| {
+                    v.parse::<f64>()
+                        .ok()
+                        .filter(|x| x.is_finite() && *x >= 0.0)
+                        .ok_or(TsError::new(RuntimeError, format!("Invalid {flag} {v}, expected a number of days >= 0")))
+                })
+                .transpose()
+        };
+
+        let filter = Self {
+            expiry: helpers::get_flag_date(args, "--expiry")?,
+            min_days_until_expiry: parse_days("--min-dte")?,
+            max_days_until_expiry: parse_days("--max-dte")?,
+            skip_dailies: helpers::has_flag(args, "--skip-dailies"),
+            categories: ExpiryCategoryFilter::from_args(args)?,
+        };
+
+        if let (Some(min), Some(max)) = (filter.min_days_until_expiry, filter.max_days_until_expiry)
+            && min > max
+        {
+            return Err(TsError::new(RuntimeError, "--min-dte cannot be greater than --max-dte"));
+        }
+
+        Ok(filter)
+    }
+
+    /// Returns the reason an option with the given expiry should not be used, or None if it should be. Days until expiry
+    /// are counted from the given time.
+    fn get_exclusion_reason(
+        &self,
+        expiry: DateTime<Utc>,
+        category: Option<ExpiryCategory>,
+        now: DateTime<Utc>,
+    ) -> Option<String> {
+        if let Some(date) = self.expiry
+            && expiry.date_naive() != date
+        {
+            return Some(format!("expiry isn't --expiry {date}"));
+        }
+
+        let days_until_expiry = (expiry - now).num_seconds() as f64 / 86400.0;
+
+        if let Some(min_days) = self.min_days_until_expiry
+            && days_until_expiry < min_days
+        {
+            return Some(format!("expiry is fewer than --min-dte {min_days} days away"));
+        }
+
+        if let Some(max_days) = self.max_days_until_expiry
+            && days_until_expiry > max_days
+        {
+            return Some(format!("expiry is more than --max-dte {max_days} days away"));
+        }
+
+        if self.skip_dailies && days_until_expiry <= DAILY_MAX_DAYS_UNTIL_EXPIRY {
+            return Some(format!("expiry is at most {DAILY_MAX_DAYS_UNTIL_EXPIRY} days away and --skip-dailies is set"));
+        }
+
+        self.categories.get_exclusion_reason(category)
+    }
+}
+
+/// A user-provided price for an instrument, used instead of the downloaded price.
+struct PriceOverride {
+    price: f64,
+    /// If provided, the override is only valid for an instrument with this expiry. This protects against accidentally
+    /// overriding the wrong instrument.
+    expiry: Option<NaiveDate>,
+}
 
-pub fn build_surface() {
+/// The options converted from the API data, and the quotes needed to find the forward price at each expiry.
+struct ConvertedData {
+    options: Vec<OptionInstrument>,
+    oldest_data_time: Option<DateTime<Utc>>,
+    newest_data_time: Option<DateTime<Utc>>,
+    /// Keyed by expiry (milliseconds), like the groups made by group_options_by_expiry().
+    expiry_quotes: HashMap<i64, ExpiryQuotes>,
+}
+
+#[derive(Default)]
+struct ExpiryQuotes {
+    /// When the most recently quoted option at the expiry was quoted.
+    quote_time: Option<DateTime<Utc>>,
+    /// The future expiring at the same time, if there is one.
+    futures: Option<ForwardQuote>,
+    /// How often expiries like this one are listed, if the exchange said.
+    category: Option<ExpiryCategory>,
+}
+
+pub fn build_surface(args: &[String], config: &Config, run: &mut RunSummary, cancel: &CancellationToken) -> Result<(), TsError> {
     let start = Instant::now();
+    let surface_options = BuildSurfaceOptions::from_args(args).map_err(|e| e.context("Invalid arguments"))?;
+    let fit_options = surface_options.get_fit_options(cancel);
+
+    if surface_options.iv_source == ImpliedVolatilitySource::Exchange
+        && (surface_options.price_overrides_path.is_some() || surface_options.repair_convexity)
+    {
+        return Err(TsError::new(
+            RuntimeError,
+            "--price-overrides and --repair-convexity change prices, which --iv-source exchange doesn't fit to",
+        ));
+    }
+    if surface_options.iv_source == ImpliedVolatilitySource::Exchange && surface_options.quote_sides {
+        return Err(TsError::new(
+            RuntimeError,
+            "--quote-sides fits to the bid and ask prices, which --iv-source exchange doesn't fit to",
+        ));
+    }
+
+    if surface_options.ndjson && surface_options.quote_log {
+        return Err(TsError::new(RuntimeError, "--ndjson can't be used with --quote-log"));
+    }
+
+    if surface_options.incremental_tolerance.is_some() && config.surface_model != SurfaceModel::Svi {
+        return Err(TsError::new(
+            RuntimeError,
+            "--incremental only works with the svi surface model, which fits smiles one at a time",
+        ));
+    }
+    if surface_options.regularisation_strength.is_some() && config.surface_model != SurfaceModel::Svi {
+        return Err(TsError::new(
+            RuntimeError,
+            "--regularise only works with the svi surface model, which fits smiles one at a time",
+        ));
+    }
+    if surface_options.atm_anchor_tolerance.is_some() && config.surface_model != SurfaceModel::Svi {
+        return Err(TsError::new(
+            RuntimeError,
+            "--anchor-atm only works with the svi surface model, which fits smiles one at a time",
+        ));
+    }
+    if surface_options.incremental_tolerance.is_some() && config.term_structure_smoothing != TermStructureSmoothing::None {
+        return Err(TsError::new(
+            RuntimeError,
+            "--incremental can't be used with term structure smoothing, as the saved smiles have already been smoothed",
+        ));
+    }
 
     println!("===============================================================");
     println!("===============================================================");
@@ -17,178 +433,2151 @@ pub fn build_surface() {
     println!("===============================================================");
     println!("===============================================================");
 
-    let raw_data = load_saved_deribit_api_data().unwrap_or_else(|e| panic!("Loading saved data failed: {}", e.reason));
+    let (raw_data, snapshot_time, futures) = run.step("Loading saved data", || -> Result<SavedData, TsError> {
+        match (surface_options.ndjson, surface_options.quote_log) {
+            (true, _) => Ok((Box::new(load_saved_deribit_api_data_streaming()?), None, Vec::new())),
+            (false, true) => {
+                let as_of = surface_options
+                    .as_of
+                    .map(|x| x.now())
+                    .unwrap_or_else(helpers::get_now);
+                let data = quote_log::load_latest_quotes(as_of)?;
+                Ok((Box::new(data.options.into_iter().map(Ok)), data.snapshot_time, data.futures))
+            }
+            (false, false) => {
+                let data = load_saved_deribit_api_data()?;
+                Ok((Box::new(data.options.into_iter().map(Ok)), data.snapshot_time, data.futures))
+            }
+        }
+    })?;
+    println!("------------------------------");
+
+    let price_overrides = match &surface_options.price_overrides_path {
+        Some(path) => {
+            let overrides =
+                run.step("Loading price overrides", || load_price_overrides(path, surface_options.csv_format.clone()))?;
+            println!("------------------------------");
+            overrides
+        }
+        None => HashMap::new(),
+    };
+
+    let mut discards = DiscardReport::default();
+    let mut converted_data = run.step("Converting data to internal format", || {
+        convert_external_data_to_internal_format(
+            raw_data,
+            &price_overrides,
+            &surface_options.expiry_filter,
+            surface_options.as_of.map(|x| x.now()).or(snapshot_time),
+            config,
+            &mut discards,
+        )
+    })?;
+    let oldest_data_time = converted_data.oldest_data_time;
+    println!("------------------------------");
+    record_discards(run, &discards, surface_options.strict)?;
+
+    if !futures.is_empty() {
+        use_futures_prices(&mut converted_data.expiry_quotes, &futures);
+        println!("------------------------------");
+    }
+
+    if surface_options.iv_source == ImpliedVolatilitySource::Exchange {
+        use_exchange_implied_volatilities(&mut converted_data.options);
+        println!("------------------------------");
+    }
+
+    let evaluation_clock = set_evaluation_time(
+        &mut converted_data.options,
+        surface_options.as_of,
+        snapshot_time.or(converted_data.newest_data_time),
+    );
+    if surface_options.deterministic && evaluation_clock == Clock::Real {
+        return Err(TsError::new(
+            RuntimeError,
+            "--deterministic needs a fixed time to measure times until expiry from, but the real time is being used",
+        ));
+    }
+    if surface_options.deterministic && surface_options.fit_budget.is_some() {
+        return Err(TsError::new(
+            RuntimeError,
+            "--fit-budget can't be used with --deterministic, as how far each fit gets depends on how fast it runs",
+        ));
+    }
+    if surface_options.deterministic && config.smile_fit_limits.max_seconds.is_some() {
+        return Err(TsError::new(
+            RuntimeError,
+            "A smile time limit can't be used with --deterministic, as how far each fit gets depends on how fast it runs",
+        ));
+    }
+    println!("------------------------------");
+
+    let perpetual = run.step("Loading saved perpetual data", load_saved_perpetual_data)?;
+    println!("------------------------------");
+
+    let mut grouped_options = run.step("Grouping options by expiry", || group_options_by_expiry(converted_data.options))?;
+    println!("------------------------------");
+
+    if let Some(max_age_seconds) = surface_options.max_quote_age_seconds {
+        let snapshot_time = snapshot_time
+            .or(converted_data.newest_data_time)
+            .ok_or(TsError::new(RuntimeError, "--max-quote-age needs to know when the data was downloaded"))?;
+        remove_stale_quotes(&mut grouped_options, snapshot_time, max_age_seconds, &mut discards);
+        println!("------------------------------");
+        record_discards(run, &discards, surface_options.strict)?;
+    }
+
+    if surface_options.repair_convexity {
+        repair_convexity(&mut grouped_options);
+        println!("------------------------------");
+    }
+
+    let forward_provenances =
+        determine_forward_prices(&mut grouped_options, &converted_data.expiry_quotes, perpetual, config, &mut discards);
+    println!("------------------------------");
+
+    // Found before the put-call pairing and --otm-only, which can leave only one option of each strike.
+    let straddles = match surface_options.atm_anchor_tolerance {
+        Some(_) => {
+            let straddles = run.step("Finding at the money straddles", || find_straddles(&grouped_options))?;
+            println!("------------------------------");
+            straddles
+        }
+        None => HashMap::new(),
+    };
+
+    let parity_violations =
+        run.step("Checking put-call parity", || check_put_call_parity(&mut grouped_options, &config.put_call_parity))?;
+    run.count("parity_violations", parity_violations);
+    println!("------------------------------");
+
+    if surface_options.otm_only {
+        let removed_options = run.step("Removing in the money options", || remove_in_the_money_options(&mut grouped_options))?;
+        run.count("removed_in_the_money_options", removed_options);
+        println!("------------------------------");
+    }
+
+    // Copied before the mark surface's smile graphs take them.
+    let quote_side_data = surface_options
+        .quote_sides
+        .then(|| (grouped_options.clone(), forward_provenances.clone()));
+
+    let mut smile_graphs = build_smile_graphs(grouped_options, forward_provenances, config, &mut discards);
+    set_expiry_categories(&mut smile_graphs, &converted_data.expiry_quotes)?;
     println!("------------------------------");
+    record_discards(run, &discards, surface_options.strict)?;
+
+    // Saved with the surface, so that the next --incremental build can tell which smiles' quotes have changed.
+    for graph in &mut smile_graphs {
+        graph.quote_hash = Some(graph.get_quote_hash());
+    }
+
+    run_optional_report(run, "Comparing implied volatility sources", || {
+        super::iv_source_comparison::save_iv_source_comparison(&smile_graphs, surface_options.iv_source)
+    });
+
+    if surface_options.dry_run {
+        run.step("Estimating fitting time", || {
+            print_dry_run(&mut smile_graphs, surface_options.threads, evaluation_clock)
+        })?;
+        println!("------------------------------");
+        println!("Dry run finished in {} seconds", start.elapsed().as_secs_f64().round_to_decimal_places(2));
+        println!("===============================================================");
+        return Ok(());
+    }
+
+    if surface_options.compare_optimisers {
+        run_optional_report(run, "Comparing optimisers", || compare_optimisers(&mut smile_graphs, surface_options.quiet));
+    }
+
+    let mut build_info = SurfaceBuildInfo::new(
+        evaluation_clock.now(),
+        surface_options.deterministic,
+        config.surface_model,
+        config.svi_optimiser,
+        config.fit_weighting,
+        config.butterfly_check,
+    );
+    build_info.fit_settings.regularisation_strength = surface_options.regularisation_strength;
+    build_info.fit_settings.atm_anchor_tolerance = surface_options.atm_anchor_tolerance.map(|x| x / 100.0);
+
+    let carried_forward_smiles = match surface_options.incremental_tolerance {
+        Some(tolerance) => {
+            let smiles = run.step("Carrying forward unchanged smiles", || {
+                take_unchanged_smiles(&mut smile_graphs, &build_info, tolerance)
+            })?;
+            run.count("carried_forward_smiles", smiles.len());
+            println!("------------------------------");
+            smiles
+        }
+        None => Vec::new(),
+    };
+
+    if let Some(strength) = surface_options.regularisation_strength {
+        run.step("Setting fit priors", || set_fit_priors(&mut smile_graphs, strength))?;
+        println!("------------------------------");
+    }
+
+    if let Some(tolerance) = surface_options.atm_anchor_tolerance {
+        run.step("Anchoring smiles at the money", || {
+            set_atm_anchors(&mut smile_graphs, &straddles, tolerance / 100.0)
+        })?;
+        println!("------------------------------");
+    }
+
+    let fit_failures = run.step("Fitting smile graphs", || {
+        fit_surface(&mut smile_graphs, config.surface_model, &fit_options, evaluation_clock)
+    })?;
+
+    if cancel.is_cancelled() {
+        let newly_fitted_smiles = smile_graphs.iter().filter(|x| x.has_been_fit).count();
+        let fitted_smiles = newly_fitted_smiles + carried_forward_smiles.len();
+        run.count("cancelled_smiles", smile_graphs.len() - newly_fitted_smiles - fit_failures.len());
+
+        // Saving a surface with nothing in it would only lose the one saved before.
+        if fitted_smiles == 0 {
+            println!("Cancelled before any smiles were fit, so leaving the saved surface as it was");
+            println!("===============================================================");
+            return Ok(());
+        }
+
+        println!("Fitting cancelled, saving the {fitted_smiles} smiles fit so far...");
+    }
 
-    let options = convert_external_data_to_internal_format(raw_data)
-        .unwrap_or_else(|e| panic!("Failed converting data to internal format: {}", e.reason));
+    discards.discarded_smiles.extend(fit_failures);
+    run.count("budget_limited_smiles", smile_graphs.iter().filter(|x| x.budget_limited).count());
     println!("------------------------------");
+    record_discards(run, &discards, surface_options.strict)?;
+
+    if !carried_forward_smiles.is_empty() {
+        smile_graphs.extend(carried_forward_smiles);
+        sort_by_expiry(&mut smile_graphs)?;
+    }
+
+    discards.print_summary();
+    run_optional_report(run, "Saving discard report", || discards.save());
 
-    let grouped_options =
-        group_options_by_expiry(options).unwrap_or_else(|e| panic!("Failed grouping options by expiry: {}", e.reason));
+    if config.term_structure_smoothing != TermStructureSmoothing::None {
+        run.optional_step("Smoothing term structure", || {
+            smooth_term_structure(&mut smile_graphs, config.term_structure_smoothing)
+        });
+        println!("------------------------------");
+    }
+
+    let calendar_arbitrage = run.step("Checking calendar arbitrage", || check_calendar_arbitrage(&smile_graphs))?;
     println!("------------------------------");
 
-    let mut smile_graphs = build_smile_graphs(grouped_options);
+    run_optional_report(run, "Reporting minimum g(k)", || report_minimum_g(&smile_graphs));
+
+    run.step("Verifying arbitrage", || verify_arbitrage(&smile_graphs, &calendar_arbitrage))
+        .map_err(|e| e.context("Refusing to save the surface"))?;
     println!("------------------------------");
 
-    fit_smile_graphs(&mut smile_graphs).unwrap_or_else(|e| panic!("Failed fitting smile graphs: {}", e.reason));
+    // Scoring against the real time would make the saved score depend on when the surface was built.
+    let scored_at = match surface_options.deterministic {
+        true => evaluation_clock.now(),
+        false => helpers::get_now(),
+    };
+    let surface_score = run_optional_report(run, "Calculating surface score", || {
+        calculate_surface_score(&smile_graphs, oldest_data_time, scored_at)
+    });
+    run_optional_report(run, "Saving fit quality report", || save_fit_quality_report(&smile_graphs, scored_at));
+
+    if let Some(cross_validation) = surface_options.cross_validation
+        && !cancel.is_cancelled()
+    {
+        run_optional_report(run, "Cross-validating smiles", || {
+            cross_validate_smiles(&smile_graphs, cross_validation, surface_options.quiet)
+        });
+    }
+
+    if let Some(resamples) = surface_options.bootstrap_resamples
+        && !cancel.is_cancelled()
+    {
+        run_optional_report(run, "Bootstrapping confidence bands", || {
+            bootstrap_smiles(&mut smile_graphs, resamples, surface_options.quiet)
+        });
+    }
+
+    let smile_graphs: Vec<SmileGraph> = smile_graphs
+        .into_iter()
+        .filter(|graph| graph.has_been_fit)
+        .collect();
+
+    let synthetic_smiles = run.step("Building synthetic smiles", || build_synthetic_smiles(&smile_graphs))?;
     println!("------------------------------");
 
-    save_data_to_file(smile_graphs).unwrap_or_else(|e| panic!("Failed saving surface data to file: {}", e.reason));
+    run.count("fitted_smiles", smile_graphs.len());
+    run.count("synthetic_smiles", synthetic_smiles.len());
+
+    if let Some(score) = &surface_score
+        && !score.slices.is_empty()
+    {
+        run.measure("surface_score", score.score);
+        run.measure("average_fit_rmse", score.slices.iter().map(|x| x.fit_rmse).sum::<f64>() / score.slices.len() as f64);
+    }
+
+    let data = SmileGraphsDataContainer {
+        schema_version: SmileGraphsDataContainer::SCHEMA_VERSION,
+        smile_graphs,
+        surface_score,
+        synthetic_smiles,
+        build_info: Some(build_info),
+    };
+
+    run.step("Saving surface data to file", || save_data_to_file(&data, surface_options.compress))?;
+
+    #[cfg(feature = "storage-sqlite")]
+    if let Some(db_path) = &surface_options.db_path {
+        println!("------------------------------");
+        println!("Saving SVI parameters to {db_path}...");
+        let count = run.step("Saving SVI parameters to database", || crate::routines::storage::store_surface(db_path, &data))?;
+        println!("Saved {count} smiles");
+    }
+
+    if let Some((grouped_options, forward_provenances)) = quote_side_data
+        && !cancel.is_cancelled()
+    {
+        println!("------------------------------");
+        run.step("Building bid, mid and ask surfaces", || {
+            build_quote_side_surfaces(
+                grouped_options,
+                forward_provenances,
+                &surface_options,
+                &fit_options,
+                evaluation_clock,
+                config,
+            )
+        })?;
+    }
 
     println!("Surface built in {} seconds", start.elapsed().as_secs_f64().round_to_decimal_places(2));
     println!("===============================================================");
+
+    Ok(())
 }
 
-fn load_saved_deribit_api_data() -> Result<DeribitDataContainer, TsError> {
-    println!("Loading external API data...");
-    let data = fileio::load_struct_from_file::<DeribitDataContainer>("./data/deribit-btc-market-data.json")?;
-    let external_data_count = data.options.len();
-    println!("Found {external_data_count} options");
+/// Run a step that only reports on the surface (or, like its score, is only kept for monitoring it), recording and
+/// printing a failure in the run summary rather than returning it, as failing to make a report shouldn't lose the
+/// surface the build has already fit.
+fn run_optional_report<T>(run: &mut RunSummary, name: &str, f: impl FnOnce() -> Result<T, TsError>) -> Option<T> {
+    let result = run.optional_step(name, f);
+    println!("------------------------------");
+    result
+}
 
-    Ok(data)
+/// Order smiles by expiry, failing if any of them has an invalid expiration rather than leaving it out of order.
+fn sort_by_expiry(smile_graphs: &mut [SmileGraph]) -> Result<(), TsError> {
+    for graph in smile_graphs.iter() {
+        graph.get_expiration()?;
+    }
+
+    // Every expiration has just been checked, so none of the keys are None.
+    smile_graphs.sort_by_cached_key(|x| x.get_expiration().ok());
+
+    Ok(())
 }
 
-/// Turn API data into our internal options type, throwing away bad data.
-fn convert_external_data_to_internal_format(data: DeribitDataContainer) -> Result<Vec<OptionInstrument>, TsError> {
-    println!("Converting options to internal format...");
+/// A price a surface can be fit to instead of the mark price.
+#[derive(Clone, Copy)]
+enum QuoteSide {
+    Bid,
+    Mid,
+    Ask,
+}
 
-    let mut discarded_options = 0;
-    let mut kept_options = 0;
-    let mut options: Vec<OptionInstrument> = Vec::new();
+impl QuoteSide {
+    fn name(&self) -> &'static str {
+        match self {
+            QuoteSide::Bid => "bid",
+            QuoteSide::Mid => "mid",
+            QuoteSide::Ask => "ask",
+        }
+    }
 
-    for api_option in data.options {
-        if let Some(date) = constants::ONLY_PROCESS_SMILE_DATE
-            && api_option.expiration_timestamp != date * 1000
-        {
-            println!("Discarding option due to ONLY_PROCESS_SMILE_DATE flag ({})...", api_option.instrument_name);
-            discarded_options += 1;
+    fn get_price(&self, option: &OptionInstrument) -> Option<f64> {
+        match self {
+            QuoteSide::Bid => option.bid_price,
+            QuoteSide::Mid => Some((option.bid_price? + option.ask_price?) / 2.0),
+            QuoteSide::Ask => option.ask_price,
+        }
+    }
+}
+
+/// Fit a surface to each of the bid, mid and ask prices, saving each next to the mark surface (e.g.
+/// smile-graph-data-bid.json), then print how far apart the bid and ask implied volatilities are at each expiry. The
+/// forward prices found for the mark surface are used, so that only the prices differ between the surfaces. A surface
+/// that fails the arbitrage checks isn't saved, but is still compared.
+fn build_quote_side_surfaces(
+    grouped_options: BTreeMap<i64, Vec<OptionInstrument>>,
+    forward_provenances: BTreeMap<i64, ForwardProvenance>,
+    surface_options: &BuildSurfaceOptions,
+    fit_options: &FitOptions,
+    evaluation_clock: Clock,
+    config: &Config,
+) -> Result<(), TsError> {
+    let mut surfaces = Vec::new();
+
+    for side in [QuoteSide::Bid, QuoteSide::Mid, QuoteSide::Ask] {
+        if fit_options.cancel.is_cancelled() {
+            println!("Cancelled, so not building the {} surface", side.name());
             continue;
         }
 
-        match api_option.to_option() {
-            Err(e) => {
-                discarded_options += 1;
-                println!("Discarding unusable option data ({}): {}...", api_option.instrument_name, e.reason);
-                continue;
-            }
-            Ok(v) => {
-                kept_options += 1;
-                options.push(v);
-            }
-        };
-    }
+        println!("Building the {} surface...", side.name());
 
-    let total_options = kept_options + discarded_options;
-    println!("Kept {kept_options}/{total_options} options");
+        let mut side_options = grouped_options.clone();
+        let mut missing_prices = 0;
 
-    Ok(options)
-}
+        for options in side_options.values_mut() {
+            options.retain_mut(|option| match side.get_price(option) {
+                Some(price) => {
+                    option.set_price(price);
+                    true
+                }
+                None => {
+                    missing_prices += 1;
+                    false
+                }
+            });
+        }
+        println!("Discarded {missing_prices} options with no {} price", side.name());
 
-fn group_options_by_expiry(options: Vec<OptionInstrument>) -> Result<HashMap<i64, Vec<OptionInstrument>>, TsError> {
-    println!("Grouping {} options by expiry...", options.len());
+        // What the mark surface discarded has already been reported.
+        let mut smile_graphs =
+            build_smile_graphs(side_options, forward_provenances.clone(), config, &mut DiscardReport::default());
 
-    let mut grouped_options: HashMap<i64, Vec<OptionInstrument>> = HashMap::new();
+        fit_surface(&mut smile_graphs, config.surface_model, fit_options, evaluation_clock)?;
 
-    for option in options {
-        let expiration = option.get_expiration()?;
-        let expiration_millis = expiration.timestamp_millis();
+        if config.term_structure_smoothing != TermStructureSmoothing::None {
+            smooth_term_structure(&mut smile_graphs, config.term_structure_smoothing)
+                .unwrap_or_else(|e| println!("Failed smoothing term structure: {}...", e.reason));
+        }
 
-        match grouped_options.entry(expiration_millis) {
-            Entry::Vacant(entry) => {
-                let formatted_expiration = expiration.to_rfc3339();
-                println!("Found a new expiry {expiration_millis} (i.e. {formatted_expiration})...");
-                let new_vector: Vec<OptionInstrument> = vec![option];
-                entry.insert(new_vector);
-            }
-            Entry::Occupied(mut entry) => {
-                entry.get_mut().push(option);
-            }
+        let arbitrage_free = verify_arbitrage(&smile_graphs, &check_calendar_arbitrage(&smile_graphs)?)
+            .inspect_err(|e| println!("Not saving the {} surface: {}", side.name(), e.reason))
+            .is_ok();
+        let smile_graphs: Vec<SmileGraph> = smile_graphs
+            .into_iter()
+            .filter(|graph| graph.has_been_fit)
+            .collect();
+
+        if arbitrage_free {
+            let data = SmileGraphsDataContainer {
+                schema_version: SmileGraphsDataContainer::SCHEMA_VERSION,
+                synthetic_smiles: build_synthetic_smiles(&smile_graphs)?,
+                smile_graphs,
+                surface_score: None,
+                build_info: Some(SurfaceBuildInfo::new(
+                    evaluation_clock.now(),
+                    surface_options.deterministic,
+                    config.surface_model,
+                    config.svi_optimiser,
+                    config.fit_weighting,
+                    config.butterfly_check,
+                )),
+            };
+            let path = fileio::save_data_file(
+                &data,
+                &fileio::get_data_path(DataScope::Snapshot, &format!("smile-graph-data-{}.json", side.name())),
+                surface_options.compress,
+            )?;
+            println!("Saved the {} surface to {path}", side.name());
+            surfaces.push((side, data.smile_graphs));
+        } else {
+            surfaces.push((side, smile_graphs));
         }
-    }
 
-    let number_of_groups = grouped_options.len();
-    println!("Put options into {number_of_groups} groups");
+        println!("------------------------------");
+    }
 
-    Ok(grouped_options)
+    print_quote_side_widths(&surfaces)
 }
 
-fn build_smile_graphs(grouped_options: HashMap<i64, Vec<OptionInstrument>>) -> Vec<SmileGraph> {
-    println!("Building smile graphs based on data...");
-    let mut smiles: Vec<SmileGraph> = Vec::new();
-    let initial_groups_count = grouped_options.len();
+/// Print the at the money implied volatility of each surface at each expiry, and how far apart the bid and ask implied
+/// volatilities are, at the money and on average over the strikes both smiles were fit to.
+fn print_quote_side_widths(surfaces: &[(QuoteSide, Vec<SmileGraph>)]) -> Result<(), TsError> {
+    // Each surface's smile graphs by expiry, as an expiry may have been fit on one side but not another.
+    let mut graphs_by_side = HashMap::new();
 
-    for (_, options) in grouped_options {
-        let mut smile_graph = SmileGraph::new();
+    for (side, graphs) in surfaces {
+        let mut graphs_by_expiry = HashMap::new();
 
-        for option in options {
-            match smile_graph.try_insert_option(option) {
-                Ok(_) => {}
-                Err(e) => {
-                    println!("Discarding an invalid option: {}...", e.reason);
-                }
-            }
+        for graph in graphs {
+            graphs_by_expiry.insert(graph.get_expiration()?, graph);
         }
 
-        match smile_graph.error_unless_valid() {
-            Ok(_) => smiles.push(smile_graph),
-            Err(e) => println!("Discarding an invalid smile graph: {e}..."),
-        };
+        graphs_by_side.insert(side.name(), graphs_by_expiry);
     }
 
-    println!("Built {} out of {} smile graphs", smiles.len(), initial_groups_count);
-
-    smiles
-}
+    let (Some(bid_graphs), Some(ask_graphs)) = (graphs_by_side.get("bid"), graphs_by_side.get("ask")) else {
+        println!("The bid and ask surfaces weren't both built, so their widths can't be compared");
+        return Ok(());
+    };
 
-fn fit_smile_graphs(smile_graphs: &mut [SmileGraph]) -> Result<(), TsError> {
-    println!("Fitting smile graphs...");
+    println!("Comparing the bid and ask surfaces (implied volatilities in vol points)...");
+    println!("Expiry                     | Bid ATM | Mid ATM | Ask ATM | ATM width | Mean width");
 
-    let mut succeeded_smiles = 0;
-    let mut failed_smiles = 0;
+    let mut expiries = bid_graphs.keys().collect::<Vec<_>>();
+    expiries.sort();
 
-    smile_graphs.sort_by_key(|x| {
-        x.get_expiration()
-            .expect("Smile graph had invalid expiration")
-            .timestamp()
-    });
+    for expiry in expiries {
+        let (bid_graph, Some(ask_graph)) = (bid_graphs[expiry], ask_graphs.get(expiry)) else {
+            continue;
+        };
+        let forward_price = bid_graph.get_underlying_forward_price()?;
+        let bid_atm = bid_graph.get_implied_volatility_at_strike(forward_price)?;
+        let ask_atm = ask_graph.get_implied_volatility_at_strike(forward_price)?;
+        let mid_atm = match graphs_by_side.get("mid").and_then(|x| x.get(expiry)) {
+            Some(mid_graph) => (mid_graph.get_implied_volatility_at_strike(forward_price)? * 100.0)
+                .round_to_decimal_places(2)
+                .to_string(),
+            None => "-".to_string(),
+        };
 
-    for graph in smile_graphs.iter_mut() {
-        let current_smile = succeeded_smiles + failed_smiles + 1;
-        println!();
-        println!("Fitting smile {current_smile} ({})...", graph.get_expiration()?.to_rfc3339());
-        println!("=====================================");
+        // Compared only where both smiles were fit to quotes, as their extrapolated wings say little about the market.
+        let lowest_strike = bid_graph
+            .lowest_observed_strike
+            .max(ask_graph.lowest_observed_strike);
+        let highest_strike = bid_graph
+            .highest_observed_strike
+            .min(ask_graph.highest_observed_strike);
+        let mut total_width = 0.0;
 
-        match graph.fit_smile() {
-            Err(e) => {
-                failed_smiles += 1;
-                let reason = e.reason;
-                println!("Failed fitting smile: {reason}...");
-            }
-            Ok(()) => {
-                succeeded_smiles += 1;
-            }
+        for i in 0..QUOTE_SIDE_STRIKE_POINTS {
+            let strike = lowest_strike + (highest_strike - lowest_strike) * (i as f64 / (QUOTE_SIDE_STRIKE_POINTS - 1) as f64);
+            total_width +=
+                ask_graph.get_implied_volatility_at_strike(strike)? - bid_graph.get_implied_volatility_at_strike(strike)?;
         }
-    }
 
-    println!("Successfully fit {}/{} smiles...", succeeded_smiles, smile_graphs.len());
+        println!(
+            "{:<26} | {:<7} | {:<7} | {:<7} | {:<9} | {}",
+            helpers::format_time(*expiry),
+            (bid_atm * 100.0).round_to_decimal_places(2),
+            mid_atm,
+            (ask_atm * 100.0).round_to_decimal_places(2),
+            ((ask_atm - bid_atm) * 100.0).round_to_decimal_places(2),
+            (total_width / QUOTE_SIDE_STRIKE_POINTS as f64 * 100.0).round_to_decimal_places(2),
+        );
+    }
 
     Ok(())
 }
 
-fn save_data_to_file(smiles: Vec<SmileGraph>) -> Result<(), TsError> {
-    println!("Saving data to file...");
+/// Build a surface from a snapshot the way build_surface() does without any flags, measuring times until expiry from the
+/// snapshot's time. For routines that build many surfaces in one go (e.g. backfill), so nothing is saved and a bad surface
+/// is an error rather than a panic. No forward price comes from the perpetual, as only the latest one is downloaded, but
+/// the snapshot's futures are used.
+#[cfg(feature = "network")]
+pub(super) fn build_surface_from_snapshot(
+    data: DeribitDataContainer,
+    config: &Config,
+    quiet: bool,
+    threads: usize,
+) -> Result<SmileGraphsDataContainer, TsError> {
+    let snapshot_time = data
+        .snapshot_time
+        .ok_or(TsError::new(RuntimeError, "The snapshot has no time to measure times until expiry from"))?;
 
-    let data = SmileGraphsDataContainer {
-        smile_graphs: smiles
-            .into_iter()
-            .filter(|graph| graph.has_been_fit)
-            .collect(),
+    let mut discards = DiscardReport::default();
+    let mut converted_data = convert_external_data_to_internal_format(
+        data.options.into_iter().map(Ok),
+        &HashMap::new(),
+        &ExpiryFilter::default(),
+        Some(snapshot_time),
+        config,
+        &mut discards,
+    )?;
+    use_futures_prices(&mut converted_data.expiry_quotes, &data.futures);
+    let evaluation_clock = set_evaluation_time(&mut converted_data.options, None, Some(snapshot_time));
+    let mut grouped_options = group_options_by_expiry(converted_data.options)?;
+    let forward_provenances =
+        determine_forward_prices(&mut grouped_options, &converted_data.expiry_quotes, None, config, &mut discards);
+    check_put_call_parity(&mut grouped_options, &config.put_call_parity)?;
+    let mut smile_graphs = build_smile_graphs(grouped_options, forward_provenances, config, &mut discards);
+    set_expiry_categories(&mut smile_graphs, &converted_data.expiry_quotes)?;
+
+    let fit_options = FitOptions {
+        quiet,
+        threads,
+        search_threads: 1,
+        fit_budget: None,
+        cancel: CancellationToken::default(),
     };
+    fit_surface(&mut smile_graphs, config.surface_model, &fit_options, evaluation_clock)?;
+
+    if config.term_structure_smoothing != TermStructureSmoothing::None {
+        smooth_term_structure(&mut smile_graphs, config.term_structure_smoothing)
+            .unwrap_or_else(|e| println!("Failed smoothing term structure: {}...", e.reason));
+    }
+
+    verify_arbitrage(&smile_graphs, &check_calendar_arbitrage(&smile_graphs)?)?;
+
+    let smile_graphs: Vec<SmileGraph> = smile_graphs
+        .into_iter()
+        .filter(|graph| graph.has_been_fit)
+        .collect();
+    let synthetic_smiles = build_synthetic_smiles(&smile_graphs)?;
+
+    Ok(SmileGraphsDataContainer {
+        schema_version: SmileGraphsDataContainer::SCHEMA_VERSION,
+        smile_graphs,
+        surface_score: None,
+        synthetic_smiles,
+        build_info: Some(SurfaceBuildInfo::new(
+            evaluation_clock.now(),
+            false,
+            config.surface_model,
+            config.svi_optimiser,
+            config.fit_weighting,
+            config.butterfly_check,
+        )),
+    })
+}
+
+fn load_saved_deribit_api_data() -> Result<DeribitDataContainer, TsError> {
+    println!("Loading external API data...");
+    let data = fileio::load_versioned_struct_from_file::<DeribitDataContainer>(&fileio::find_data_file(
+        &fileio::get_market_data_path(DataScope::Snapshot, MARKET_DATA_FILE_NAME),
+    ))?;
+    let external_data_count = data.options.len();
+    println!("Found {external_data_count} options");
+
+    Ok(data)
+}
+
+/// Load the perpetual future's quote, if it was downloaded with the options. Older downloads don't have it, and the
+/// forward price can be found without it, so it being missing or unusable isn't an error.
+fn load_saved_perpetual_data() -> Result<Option<PerpetualQuote>, TsError> {
+    println!("Loading perpetual data...");
+
+    let path = fileio::get_market_data_path(DataScope::Snapshot, PERPETUAL_DATA_FILE_NAME);
+
+    if !fileio::file_exists(&path) {
+        println!("No perpetual data found");
+        return Ok(None);
+    }
+
+    let data = fileio::load_struct_from_file::<DeribitPerpetualTickerData>(&path)?;
+
+    match data.to_perpetual_quote() {
+        Ok(quote) => {
+            println!(
+                "Found {} at {} with a funding rate of {}% a year",
+                data.instrument_name,
+                quote.price,
+                quote.annual_funding_rate * 100.0
+            );
+            Ok(Some(quote))
+        }
+        Err(e) => {
+            println!("Ignoring unusable perpetual data: {}", e.reason);
+            Ok(None)
+        }
+    }
+}
+
+/// Use the price of each downloaded future as the futures quote of the expiry it expires at, rather than the underlying
+/// price Deribit gave with that expiry's options. Futures expiring when no options do are ignored, as are unusable ones.
+fn use_futures_prices(expiry_quotes: &mut HashMap<i64, ExpiryQuotes>, futures: &[DeribitFuture]) {
+    println!("Matching futures to expiries...");
+
+    let mut matched_futures = 0;
+
+    for future in futures {
+        let Some(quotes) = expiry_quotes.get_mut(&(future.expiration_timestamp as i64)) else {
+            continue;
+        };
+
+        match future.get_forward_quote() {
+            Ok(Some(quote)) => {
+                quotes.futures = Some(quote);
+                matched_futures += 1;
+            }
+            Ok(None) => {}
+            Err(e) => println!("Ignoring unusable futures data ({}): {}...", future.instrument_name, e.reason),
+        }
+    }
+
+    println!("Matched {matched_futures}/{} futures to an expiry", futures.len());
+}
+
+/// Tag each smile with the category of its expiry, found from its options when they were converted.
+fn set_expiry_categories(smile_graphs: &mut [SmileGraph], expiry_quotes: &HashMap<i64, ExpiryQuotes>) -> Result<(), TsError> {
+    for graph in smile_graphs {
+        graph.expiry_category = expiry_quotes
+            .get(&graph.get_expiration()?.timestamp_millis())
+            .and_then(|x| x.category);
+    }
+
+    Ok(())
+}
+
+/// Open the saved newline-delimited JSON data. Options are read one at a time as they are converted, so the whole file
+/// never has to be in memory.
+fn load_saved_deribit_api_data_streaming() -> Result<impl Iterator<Item = Result<DeribitOptionInstrument, TsError>>, TsError> {
+    println!("Opening external API data for streaming...");
+
+    fileio::read_ndjson_file::<DeribitOptionInstrument>(&fileio::get_market_data_path(
+        DataScope::Snapshot,
+        NDJSON_MARKET_DATA_FILE_NAME,
+    ))
+}
+
+/// Load user-provided prices from a CSV file with the columns instrument_name, price and (optionally) expiry. Prices are in
+/// USD.
+fn load_price_overrides(path: &str, format: CsvFormat) -> Result<HashMap<Box<str>, PriceOverride>, TsError> {
+    println!("Loading price overrides from {path}...");
+
+    let table = fileio::load_csv_file(path, format)?;
+    let has_expiry = table.has_column("expiry");
+    let mut overrides = HashMap::new();
+
+    for row in 0..table.rows_count() {
+        let instrument_name: Box<str> = table.get_str(row, "instrument_name")?.into();
+        let price_override = PriceOverride {
+            price: table.get_f64(row, "price")?,
+            expiry: match has_expiry {
+                true => Some(table.get_date(row, "expiry")?),
+                false => None,
+            },
+        };
+
+        if price_override.price <= 0.0 {
+            return Err(TsError::new(RuntimeError, format!("Override price for {instrument_name} must be greater than 0")));
+        }
+
+        if overrides
+            .insert(instrument_name.clone(), price_override)
+            .is_some()
+        {
+            return Err(TsError::new(RuntimeError, format!("Found more than one price override for {instrument_name}")));
+        }
+    }
+
+    println!("Found {} price overrides", overrides.len());
+
+    Ok(overrides)
+}
+
+/// Turn API data into our internal options type, throwing away bad data. Days until expiry are counted from filter_time
+/// for the expiry filter, or if it isn't known (e.g. for newline-delimited data) from when each option was quoted.
+fn convert_external_data_to_internal_format(
+    data: impl Iterator<Item = Result<DeribitOptionInstrument, TsError>>,
+    price_overrides: &HashMap<Box<str>, PriceOverride>,
+    expiry_filter: &ExpiryFilter,
+    filter_time: Option<DateTime<Utc>>,
+    config: &Config,
+    discards: &mut DiscardReport,
+) -> Result<ConvertedData, TsError> {
+    println!("Converting options to internal format...");
+
+    let mut discarded_options = 0;
+    let mut kept_options = 0;
+    let mut overridden_options = 0;
+    let mut options: Vec<OptionInstrument> = Vec::new();
+    let mut oldest_data_time: Option<DateTime<Utc>> = None;
+    let mut newest_data_time: Option<DateTime<Utc>> = None;
+    let mut expiry_quotes: HashMap<i64, ExpiryQuotes> = HashMap::new();
+
+    for api_option in data {
+        let api_option = api_option?;
+
+        // Bad data for one option shouldn't stop the rest being used.
+        let (expiry, strike, data_time) = match (api_option.get_expiration(), api_option.get_strike(), api_option.get_data_time())
+        {
+            (Ok(expiry), Ok(strike), Ok(data_time)) => (expiry, strike, data_time),
+            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+                discarded_options += 1;
+                println!("Discarding unusable option data ({}): {}...", api_option.instrument_name, e.reason);
+                discards.discard_option(&api_option.instrument_name, DiscardStage::Conversion, e.reason);
+                continue;
+            }
+        };
+
+        if let Some(reason) = config.instrument_filters.get_exclusion_reason(
+            &api_option.instrument_name,
+            strike,
+            expiry.date_naive(),
+            api_option.get_underlying_price(),
+        ) {
+            println!("Discarding option due to instrument filters ({}): {reason}...", api_option.instrument_name);
+            discards.filtered_options += 1;
+            discarded_options += 1;
+            continue;
+        }
+
+        let now = filter_time.or(data_time).unwrap_or_else(helpers::get_now);
+
+        let category = api_option.get_expiry_category();
+
+        if let Some(reason) = expiry_filter.get_exclusion_reason(expiry, category, now) {
+            println!("Discarding option due to expiry filter ({}): {reason}...", api_option.instrument_name);
+            discards.filtered_options += 1;
+            discarded_options += 1;
+            continue;
+        }
+
+        match api_option.to_option(&config.deribit) {
+            Err(e) => {
+                discarded_options += 1;
+                println!("Discarding unusable option data ({}): {}...", api_option.instrument_name, e.reason);
+                discards.discard_option(&api_option.instrument_name, DiscardStage::Conversion, e.reason);
+                continue;
+            }
+            Ok(mut v) => {
+                if let Some(price_override) = price_overrides.get(&api_option.instrument_name) {
+                    if let Some(expiry) = price_override.expiry
+                        && expiry != v.get_expiration()?.date_naive()
+                    {
+                        return Err(TsError::new(
+                            RuntimeError,
+                            format!("Price override for {} has the wrong expiry {expiry}", api_option.instrument_name),
+                        ));
+                    }
+
+                    v.price = price_override.price;
+                    overridden_options += 1;
+                }
+
+                let quotes = expiry_quotes
+                    .entry(v.get_expiration()?.timestamp_millis())
+                    .or_default();
+
+                if let Some(data_time) = data_time {
+                    oldest_data_time = Some(oldest_data_time.map_or(data_time, |x| x.min(data_time)));
+                    newest_data_time = Some(newest_data_time.map_or(data_time, |x| x.max(data_time)));
+                    quotes.quote_time = Some(quotes.quote_time.map_or(data_time, |x| x.max(data_time)));
+                }
+
+                // The option is still usable without its future's price.
+                if quotes.futures.is_none() {
+                    quotes.futures = api_option.get_futures_quote().unwrap_or_else(|e| {
+                        println!("Ignoring the futures quote of {}: {}...", api_option.instrument_name, e.reason);
+                        None
+                    });
+                }
+
+                quotes.category = quotes.category.or(category);
+                kept_options += 1;
+                options.push(v);
+            }
+        };
+    }
+
+    let total_options = kept_options + discarded_options;
+    println!("Kept {kept_options}/{total_options} options");
+
+    discards.total_options = total_options;
+    discards.total_smiles = expiry_quotes.len();
+
+    if !price_overrides.is_empty() {
+        println!("Overrode the price of {overridden_options} options");
+    }
+
+    Ok(ConvertedData {
+        options,
+        oldest_data_time,
+        newest_data_time,
+        expiry_quotes,
+    })
+}
+
+/// Fit the options to the exchange's implied volatilities rather than solving them from the prices. Options without one
+/// are discarded when the smile graphs are built.
+fn use_exchange_implied_volatilities(options: &mut [OptionInstrument]) {
+    println!("Using the exchange's implied volatilities...");
+
+    for option in options.iter_mut() {
+        option.implied_volatility_source = ImpliedVolatilitySource::Exchange;
+    }
+
+    let missing = options
+        .iter()
+        .filter(|x| x.exchange_implied_volatility.is_none())
+        .count();
+    println!("{missing}/{} options have no exchange implied volatility", options.len());
+}
+
+/// Measure the options' times until expiry from when the data was downloaded, so that building the surface from the same
+/// data gives the same surface however long afterwards it's done. --as-of overrides this. Data saved by older versions
+/// doesn't record when it was downloaded, so the time of its most recent ticker data is used.
+fn set_evaluation_time(options: &mut [OptionInstrument], as_of: Option<Clock>, snapshot_time: Option<DateTime<Utc>>) -> Clock {
+    let clock = match (as_of, snapshot_time) {
+        (Some(clock), _) => {
+            println!("Measuring times until expiry from {} (set by --as-of)...", helpers::format_time(clock.now()));
+            clock
+        }
+        (None, Some(snapshot_time)) => {
+            println!(
+                "Measuring times until expiry from {}, when the data was downloaded...",
+                helpers::format_time(snapshot_time)
+            );
+            Clock::Fixed(snapshot_time)
+        }
+        (None, None) => {
+            println!("The data doesn't record when it was downloaded, so measuring times until expiry from now...");
+            Clock::Real
+        }
+    };
+
+    for option in options {
+        option.set_clock(clock);
+    }
+
+    clock
+}
+
+/// Group the options by expiry. Groups are ordered by expiry and the options in each group are ordered by strike, so that
+/// everything downstream (logs, fitting and saved files) happens in the same order between runs on the same data.
+fn group_options_by_expiry(options: Vec<OptionInstrument>) -> Result<BTreeMap<i64, Vec<OptionInstrument>>, TsError> {
+    println!("Grouping {} options by expiry...", options.len());
+
+    let mut grouped_options: BTreeMap<i64, Vec<OptionInstrument>> = BTreeMap::new();
+
+    for option in options {
+        let expiration = option.get_expiration()?;
+        let expiration_millis = expiration.timestamp_millis();
+
+        match grouped_options.entry(expiration_millis) {
+            Entry::Vacant(entry) => {
+                let formatted_expiration = helpers::format_time(expiration);
+                println!("Found a new expiry {expiration_millis} (i.e. {formatted_expiration})...");
+                let new_vector: Vec<OptionInstrument> = vec![option];
+                entry.insert(new_vector);
+            }
+            Entry::Occupied(mut entry) => {
+                entry.get_mut().push(option);
+            }
+        }
+    }
+
+    for options in grouped_options.values_mut() {
+        options.sort_by(|a, b| {
+            a.strike
+                .total_cmp(&b.strike)
+                .then(a.option_type.cmp(&b.option_type))
+                .then(a.instrument_id.cmp(&b.instrument_id))
+        });
+    }
+
+    let number_of_groups = grouped_options.len();
+    println!("Put options into {number_of_groups} groups");
+
+    Ok(grouped_options)
+}
+
+/// Discard the options quoted more than max_age_seconds before the snapshot, and any expiry left without options. Options
+/// fetched one at a time may be quoted minutes apart, so in a fast market the oldest ones no longer fit with the rest.
+/// Options whose quote time isn't known are kept.
+fn remove_stale_quotes(
+    grouped_options: &mut BTreeMap<i64, Vec<OptionInstrument>>,
+    snapshot_time: DateTime<Utc>,
+    max_age_seconds: f64,
+    discards: &mut DiscardReport,
+) {
+    println!(
+        "Removing quotes more than {max_age_seconds} seconds older than {}...",
+        helpers::format_time(snapshot_time)
+    );
+
+    let mut removed_options = 0;
+
+    for (expiry, options) in grouped_options.iter_mut() {
+        let option_count = options.len();
+
+        options.retain(|option| {
+            let Some(quote_time) = option.quote_time else {
+                return true;
+            };
+            let age_seconds = (snapshot_time - quote_time).num_milliseconds() as f64 / 1000.0;
+
+            if age_seconds <= max_age_seconds {
+                return true;
+            }
+
+            discards.discard_option(
+                &option.instrument_id,
+                DiscardStage::Staleness,
+                format!("Quoted {} seconds before the snapshot", age_seconds.round_to_decimal_places(1)),
+            );
+            false
+        });
+
+        removed_options += option_count - options.len();
+
+        if options.is_empty() {
+            discards.discard_smile(*expiry, option_count, DiscardStage::Staleness, "All of its quotes are stale");
+        }
+    }
+
+    grouped_options.retain(|_, options| !options.is_empty());
+    println!("Removed {removed_options} stale quotes");
+}
+
+/// Adjust the prices in each expiry group so that they are convex in strike, logging every adjustment.
+fn repair_convexity(grouped_options: &mut BTreeMap<i64, Vec<OptionInstrument>>) {
+    println!("Repairing price convexity...");
+
+    let mut adjusted_options = 0;
+
+    for (expiry, options) in grouped_options.iter_mut() {
+        match analytics::repair_price_convexity(options) {
+            Err(e) => println!("Failed repairing convexity for expiry {expiry}, leaving prices unchanged: {}...", e.reason),
+            Ok(adjustments) => {
+                for adjustment in &adjustments {
+                    println!(
+                        "Adjusted {:?} price at strike {} ({}) by {} from {} to {}...",
+                        adjustment.option_type,
+                        adjustment.strike,
+                        adjustment.instrument_id,
+                        (adjustment.new_price - adjustment.old_price).round_to_decimal_places(4),
+                        adjustment.old_price.round_to_decimal_places(4),
+                        adjustment.new_price.round_to_decimal_places(4),
+                    );
+                }
+
+                adjusted_options += adjustments.len();
+            }
+        }
+    }
+
+    println!("Adjusted the prices of {adjusted_options} options");
+}
+
+/// Find the forward price at each expiry from the configured sources (see analytics::determine_forward_price()), and give
+/// it to the expiry's options so their implied volatilities are found from it. Expiries with no usable source are
+/// discarded.
+fn determine_forward_prices(
+    grouped_options: &mut BTreeMap<i64, Vec<OptionInstrument>>,
+    expiry_quotes: &HashMap<i64, ExpiryQuotes>,
+    perpetual: Option<PerpetualQuote>,
+    config: &Config,
+    discards: &mut DiscardReport,
+) -> BTreeMap<i64, ForwardProvenance> {
+    println!("Determining forward prices...");
+
+    let mut forward_provenances = BTreeMap::new();
+
+    grouped_options.retain(|expiry, options| {
+        let quotes = expiry_quotes.get(expiry);
+        let inputs = ForwardInputs {
+            options,
+            quote_time: quotes.and_then(|x| x.quote_time),
+            futures: quotes.and_then(|x| x.futures),
+            perpetual,
+        };
+
+        match analytics::determine_forward_price(&inputs, &config.forward_sources) {
+            Err(e) => {
+                println!("Discarding expiry {expiry}: {}...", e.reason);
+                discards.discard_smile(*expiry, options.len(), DiscardStage::ForwardPrice, e.reason);
+                false
+            }
+            Ok(provenance) => {
+                println!(
+                    "Expiry {expiry} has a forward price of {} from {:?}{}...",
+                    provenance.forward_price.round_to_decimal_places(2),
+                    provenance.source,
+                    match provenance.skipped_sources.is_empty() {
+                        true => String::new(),
+                        false => format!(" (skipped {})", provenance.skipped_sources.join(", ")),
+                    }
+                );
+
+                for option in options.iter_mut() {
+                    option.set_forward_price(provenance.forward_price);
+                }
+
+                forward_provenances.insert(*expiry, provenance);
+                true
+            }
+        }
+    });
+
+    println!("Found forward prices for {} expiries", forward_provenances.len());
+
+    forward_provenances
+}
+
+/// Compare the implied volatilities of the calls and puts at the same strike in each expiry, printing the pairs that differ
+/// by more than the tolerance, then keep both, one or a merge of each pair as configured (see
+/// analytics::apply_put_call_parity()). Returns the number of pairs that differ by more than the tolerance.
+fn check_put_call_parity(
+    grouped_options: &mut BTreeMap<i64, Vec<OptionInstrument>>,
+    settings: &PutCallParitySettings,
+) -> Result<usize, TsError> {
+    println!("Checking put-call parity...");
+
+    let mut pairs = 0;
+    let mut violations = 0;
+    let mut removed_options = 0;
+    let mut merged_pairs = 0;
+
+    for (expiry, options) in grouped_options.iter_mut() {
+        let check = analytics::apply_put_call_parity(options, settings)?;
+
+        for violation in &check.violations {
+            println!(
+                "Expiry {expiry} at strike {}: {} has an implied volatility of {} but {} has {}...",
+                violation.strike,
+                violation.call_instrument_id,
+                violation.call_implied_volatility.round_to_decimal_places(4),
+                violation.put_instrument_id,
+                violation.put_implied_volatility.round_to_decimal_places(4),
+            );
+        }
+
+        pairs += check.pairs;
+        violations += check.violations.len();
+        removed_options += check.removed_instruments.len();
+        merged_pairs += check.merged_pairs;
+    }
+
+    println!(
+        "{violations}/{pairs} put-call pairs differ by more than {} in implied volatility",
+        settings.tolerance
+    );
+
+    match settings.pairing {
+        PutCallPairing::Both => {}
+        PutCallPairing::OutOfTheMoney => println!("Kept only the out of the money option of {removed_options} pairs"),
+        PutCallPairing::Merge => println!(
+            "Merged {merged_pairs} pairs, and kept only the out of the money option of {} pairs that couldn't be merged",
+            removed_options - merged_pairs
+        ),
+    }
+
+    Ok(violations)
+}
+
+/// Keep only the out of the money options of each expiry (puts below the forward price, calls at or above it). In the
+/// money options trade little, so their mark prices, and the implied volatilities solved from them, are often poor.
+/// Returns the number of options removed.
+fn remove_in_the_money_options(grouped_options: &mut BTreeMap<i64, Vec<OptionInstrument>>) -> Result<usize, TsError> {
+    println!("Removing in the money options...");
+
+    let mut removed_options = 0;
+
+    for options in grouped_options.values_mut() {
+        let mut out_of_the_money = options
+            .iter()
+            .map(|x| x.is_out_of_the_money())
+            .collect::<Result<Vec<bool>, TsError>>()?
+            .into_iter();
+        let count = options.len();
+
+        options.retain(|_| out_of_the_money.next().unwrap_or(true));
+        removed_options += count - options.len();
+    }
+
+    println!("Removed {removed_options} options");
+
+    Ok(removed_options)
+}
+
+fn build_smile_graphs(
+    grouped_options: BTreeMap<i64, Vec<OptionInstrument>>,
+    mut forward_provenances: BTreeMap<i64, ForwardProvenance>,
+    config: &Config,
+    discards: &mut DiscardReport,
+) -> Vec<SmileGraph> {
+    println!("Building smile graphs based on data...");
+    let mut smiles: Vec<SmileGraph> = Vec::new();
+    let initial_groups_count = grouped_options.len();
+
+    for (expiry, options) in grouped_options {
+        let mut smile_graph = SmileGraph::new();
+        smile_graph.forward_provenance = forward_provenances.remove(&expiry);
+        smile_graph.optimiser = config.svi_optimiser;
+        smile_graph.fit_weighting = config.fit_weighting;
+        smile_graph.butterfly_check = config.butterfly_check;
+        smile_graph.fit_limits = config.smile_fit_limits;
+
+        for option in options {
+            let instrument_id = option.instrument_id.clone();
+
+            match smile_graph.try_insert_option(option) {
+                Ok(_) => {}
+                Err(e) => {
+                    println!("Discarding an invalid option: {}...", e.reason);
+                    discards.discard_option(&instrument_id, DiscardStage::SmileGraph, e.reason);
+                }
+            }
+        }
+
+        match smile_graph.error_unless_valid() {
+            Ok(_) => smiles.push(smile_graph),
+            Err(e) => {
+                println!("Discarding an invalid smile graph: {e}...");
+                discards.discard_smile(expiry, smile_graph.options.len(), DiscardStage::SmileGraph, e);
+            }
+        };
+    }
+
+    println!("Built {} out of {} smile graphs", smiles.len(), initial_groups_count);
+
+    smiles
+}
+
+/// Count what has been discarded so far in the run summary. With --strict, stop building the surface as soon as anything
+/// has been discarded, saying what was and why.
+fn record_discards(run: &mut RunSummary, discards: &DiscardReport, strict: bool) -> Result<(), TsError> {
+    run.count("options", discards.total_options);
+    run.count("filtered_options", discards.filtered_options);
+    run.count("discarded_options", discards.discarded_options.len());
+    run.count("smiles", discards.total_smiles);
+    run.count("discarded_smiles", discards.discarded_smiles.len());
+
+    if !strict || discards.is_empty() {
+        return Ok(());
+    }
+
+    discards.print_summary();
+    discards
+        .save()
+        .unwrap_or_else(|e| println!("Failed saving discard report: {}...", e.reason));
+
+    Err(TsError::new(
+        UnusableAPIData,
+        format!("Discarded {} with --strict given, so not building the surface", discards.describe()),
+    ))
+}
+
+/// Print the smiles that would be fit and roughly how long each would take, without fitting them.
+fn print_dry_run(smile_graphs: &mut [SmileGraph], threads: usize, clock: Clock) -> Result<(), TsError> {
+    println!("Estimating fitting time (dry run, nothing will be fit or saved)...");
+
+    sort_by_expiry(smile_graphs)?;
+
+    let predicted_seconds = predict_fit_seconds(smile_graphs, &load_fit_time_model()?, clock)?;
+
+    for (graph, (seconds, from_history)) in smile_graphs.iter().zip(&predicted_seconds) {
+        let quoted_options = graph
+            .options
+            .iter()
+            .filter(|x| x.bid_price.is_some() && x.ask_price.is_some())
+            .count();
+
+        println!(
+            "{} ({}): {} options ({} with a bid and ask), strikes {} to {}, about {} seconds to fit ({})...",
+            helpers::format_time(graph.get_expiration()?),
+            graph.expiry_category.map_or("uncategorised", |x| x.name()),
+            graph.options.len(),
+            quoted_options,
+            graph.lowest_observed_strike,
+            graph.highest_observed_strike,
+            seconds.round_to_decimal_places(1),
+            match from_history {
+                true => "from past fits",
+                false => "timed",
+            },
+        );
+    }
+
+    let seconds = predicted_seconds.iter().map(|x| x.0).collect::<Vec<f64>>();
+
+    println!(
+        "Would fit {} smiles from {} options in about {} minutes using {threads} thread(s)",
+        smile_graphs.len(),
+        smile_graphs.iter().map(|x| x.options.len()).sum::<usize>(),
+        (estimate_wall_clock_seconds(&seconds, threads) / 60.0).round_to_decimal_places(1),
+    );
+
+    Ok(())
+}
+
+/// Load how long past fits took, so that fitting times can be predicted. Having no history isn't an error.
+fn load_fit_time_model() -> Result<FitTimeModel, TsError> {
+    let path = fileio::get_data_path(DataScope::Market, FIT_TIME_HISTORY_FILE_NAME);
+
+    if !fileio::file_exists(&path) {
+        return Ok(FitTimeModel::new(Vec::new()));
+    }
+
+    let records = fileio::read_ndjson_file::<FitTimeRecord>(&path)?.collect::<Result<Vec<FitTimeRecord>, TsError>>()?;
+
+    Ok(FitTimeModel::new(records))
+}
+
+fn get_days_until_expiry(graph: &SmileGraph, clock: Clock) -> Result<f64, TsError> {
+    Ok((graph.get_expiration()? - clock.now()).num_seconds() as f64 / 86400.0)
+}
+
+/// Predict how many seconds each smile will take to fit, from how long similar smiles took before, or by timing part of
+/// the fit if nothing has been fit before. The second value is true if the prediction came from past fits.
+fn predict_fit_seconds(smile_graphs: &[SmileGraph], model: &FitTimeModel, clock: Clock) -> Result<Vec<(f64, bool)>, TsError> {
+    smile_graphs
+        .iter()
+        .map(|graph| match model.predict_seconds(get_days_until_expiry(graph, clock)?, graph.options.len())? {
+            Some(seconds) => Ok((seconds, true)),
+            None => Ok((graph.estimate_fit_seconds()?, false)),
+        })
+        .collect()
+}
+
+/// How long fitting smiles that take the given times will take when they are fit longest first on the given number of
+/// threads, with each thread taking the next smile as soon as it is free.
+fn estimate_wall_clock_seconds(seconds: &[f64], threads: usize) -> f64 {
+    let mut seconds = seconds.to_vec();
+    seconds.sort_by(|a, b| b.total_cmp(a));
+
+    let mut thread_seconds = vec![0.0_f64; threads];
+
+    for x in seconds {
+        if let Some(least_busy) = thread_seconds.iter_mut().min_by(|a, b| a.total_cmp(b)) {
+            *least_busy += x;
+        }
+    }
+
+    thread_seconds.into_iter().fold(0.0, f64::max)
+}
+
+/// Share the fitting budget between the smiles in proportion to how long each is predicted to take. Each thread has the
+/// whole budget, so between them the smiles get the budget times the number of threads.
+fn allocate_fit_budgets(predicted_seconds: &[f64], threads: usize, fit_budget: Option<Duration>) -> Vec<Option<Duration>> {
+    let total_seconds = predicted_seconds.iter().sum::<f64>();
+
+    predicted_seconds
+        .iter()
+        .map(|seconds| {
+            let share = match total_seconds > 0.0 {
+                true => seconds / total_seconds,
+                false => 1.0 / predicted_seconds.len() as f64,
+            };
+
+            fit_budget.map(|x| x.mul_f64(threads as f64 * share))
+        })
+        .collect()
+}
+
+/// How fitting a smile went on one of the fitting threads.
+struct SmileFit {
+    index: usize,
+    /// True if the fitting budget or one of the smile's limits stopped the fit before it finished.
+    result: Result<bool, TsError>,
+    seconds: f64,
+}
+
+/// Fit the smiles on the given number of threads, longest first, recording how long each took so that later builds can
+/// predict it.
+/// Fit every smile with each SVI optimiser in turn, printing the implied volatility error of each fit and how long it took.
+/// The smiles are left unfit, so that the fit afterwards is the same as without the comparison.
+fn compare_optimisers(smile_graphs: &mut [SmileGraph], quiet: bool) -> Result<(), TsError> {
+    let optimisers = [SviOptimiser::MultiStart, SviOptimiser::DifferentialEvolution];
+    let mut totals = [(0.0, 0.0); 2];
+
+    println!("Comparing optimisers...");
+    println!("Expiry                     | Multi-start RMSE | Multi-start seconds | DE RMSE | DE seconds");
+
+    for graph in smile_graphs.iter_mut() {
+        let configured_optimiser = graph.optimiser;
+        let mut results = [(f64::NAN, 0.0); 2];
+
+        for (optimiser, result) in optimisers.iter().zip(&mut results) {
+            let progress = ProgressBar::new(format!("{optimiser:?}"), 1, quiet);
+            let start = Instant::now();
+
+            graph.optimiser = *optimiser;
+            if let Err(e) = graph.fit_smile(&progress) {
+                println!("Failed fitting with {optimiser:?}: {}...", e.reason);
+                continue;
+            }
+
+            *result = (graph.get_fit_rmse()?, start.elapsed().as_secs_f64());
+        }
+
+        graph.optimiser = configured_optimiser;
+        graph.has_been_fit = false;
+
+        println!(
+            "{:<26} | {:<16} | {:<19} | {:<7} | {}",
+            helpers::format_time(graph.get_expiration()?),
+            results[0].0.round_to_decimal_places(4),
+            results[0].1.round_to_decimal_places(2),
+            results[1].0.round_to_decimal_places(4),
+            results[1].1.round_to_decimal_places(2),
+        );
+
+        for (total, result) in totals.iter_mut().zip(results) {
+            total.0 += result.0;
+            total.1 += result.1;
+        }
+    }
+
+    let count = smile_graphs.len() as f64;
+
+    println!(
+        "{:<26} | {:<16} | {:<19} | {:<7} | {}",
+        "Average RMSE, total time",
+        (totals[0].0 / count).round_to_decimal_places(4),
+        totals[0].1.round_to_decimal_places(2),
+        (totals[1].0 / count).round_to_decimal_places(4),
+        totals[1].1.round_to_decimal_places(2),
+    );
+
+    Ok(())
+}
+
+/// Fit the smiles with the given model. See fit_smile_graphs() and fit_essvi_surface().
+/// Take out the smiles whose quotes are the same as when the saved surface was built, or whose options' implied
+/// volatilities are all within the tolerance (in volatility points) of what they were, giving each the saved surface's fit
+/// so that it doesn't have to be fit again. The saved surface is only used if it was fit with the same settings.
+fn take_unchanged_smiles(
+    smile_graphs: &mut Vec<SmileGraph>,
+    build_info: &SurfaceBuildInfo,
+    tolerance: f64,
+) -> Result<Vec<SmileGraph>, TsError> {
+    println!("Comparing quotes with the saved surface...");
+
+    let Some((saved_build_info, mut saved_smiles)) = load_saved_smiles()? else {
+        println!("Fitting every smile...");
+        return Ok(Vec::new());
+    };
+    if saved_build_info.fit_settings != build_info.fit_settings {
+        println!("The saved surface was fit with different settings, so fitting every smile...");
+        return Ok(Vec::new());
+    }
+
+    let mut unchanged = Vec::new();
+    let mut changed = Vec::new();
+
+    for mut graph in smile_graphs.drain(..) {
+        let expiry = graph.get_expiration()?;
+
+        let Some(saved_graph) = saved_smiles.remove(&expiry) else {
+            changed.push(graph);
+            continue;
+        };
+
+        // A fit that was cut short might be bettered now.
+        if saved_graph.budget_limited || !is_smile_unchanged(&graph, &saved_graph, tolerance)? {
+            changed.push(graph);
+            continue;
+        }
+
+        // Scaling to a different time until expiry can break the limits SVI's parameters have to stay within.
+        match graph.reuse_fit(&saved_graph) {
+            Ok(()) => {
+                println!("Reusing the saved fit of {}...", helpers::format_time(expiry));
+                unchanged.push(graph);
+            }
+            Err(e) => {
+                println!("Can't reuse the saved fit of {}, fitting it again: {}...", helpers::format_time(expiry), e.reason);
+                changed.push(graph);
+            }
+        }
+    }
+
+    *smile_graphs = changed;
+    println!("Reusing {} saved fits, leaving {} smiles to fit", unchanged.len(), smile_graphs.len());
+
+    Ok(unchanged)
+}
+
+/// The saved surface's smiles by expiry, with times until expiry measured from when it was built, and how it was built.
+/// None if there's no saved surface or it doesn't record how it was built, saying which.
+fn load_saved_smiles() -> Result<Option<SavedSmiles>, TsError> {
+    let path = fileio::find_data_file(&fileio::get_data_path(DataScope::Snapshot, "smile-graph-data.json"));
+
+    if !fileio::file_exists(&path) {
+        println!("No saved surface found...");
+        return Ok(None);
+    }
+
+    let saved = fileio::load_versioned_struct_from_file::<SmileGraphsDataContainer>(&path)?;
+
+    let Some(saved_build_info) = saved.build_info else {
+        println!("The saved surface doesn't record how it was built...");
+        return Ok(None);
+    };
+
+    let saved_smiles = saved
+        .smile_graphs
+        .into_iter()
+        .map(|mut graph| {
+            graph.set_clock(Clock::Fixed(saved_build_info.evaluation_time));
+            Ok((graph.get_expiration()?, graph))
+        })
+        .collect::<Result<HashMap<DateTime<Utc>, SmileGraph>, TsError>>()?;
+
+    Ok(Some((saved_build_info, saved_smiles)))
+}
+
+/// Pull the fit of each smile with at most REGULARISED_MAX_STRIKES strikes towards the saved surface's fit of the same
+/// expiry, so that it doesn't swing to a different curve from one build to the next. See SmileGraph::set_fit_prior().
+fn set_fit_priors(smile_graphs: &mut [SmileGraph], strength: f64) -> Result<(), TsError> {
+    println!("Pulling smiles with at most {REGULARISED_MAX_STRIKES} strikes towards the saved surface's fits...");
+
+    let Some((_, saved_smiles)) = load_saved_smiles()? else {
+        println!("Fitting every smile without a prior...");
+        return Ok(());
+    };
+    let mut regularised_smiles = 0;
+
+    for graph in smile_graphs {
+        let mut strikes = graph.options.iter().map(|x| x.strike).collect::<Vec<f64>>();
+        strikes.sort_by(|a, b| a.total_cmp(b));
+        strikes.dedup();
+
+        if strikes.len() > REGULARISED_MAX_STRIKES {
+            continue;
+        }
+
+        let expiry = graph.get_expiration()?;
+
+        match saved_smiles.get(&expiry) {
+            Some(saved_graph) => {
+                graph.set_fit_prior(saved_graph, strength)?;
+                regularised_smiles += 1;
+                println!("Regularising {} ({} strikes)...", helpers::format_time(expiry), strikes.len());
+            }
+            None => println!(
+                "The saved surface has no fit of {} ({} strikes), so fitting it without a prior...",
+                helpers::format_time(expiry),
+                strikes.len()
+            ),
+        }
+    }
+
+    println!("Regularising {regularised_smiles} smiles");
+
+    Ok(())
+}
+
+/// Find the straddle nearest the forward price of each expiry (see analytics::find_atm_straddle()), printing them.
+fn find_straddles(grouped_options: &BTreeMap<i64, Vec<OptionInstrument>>) -> Result<HashMap<DateTime<Utc>, Straddle>, TsError> {
+    println!("Finding at the money straddles...");
+    println!("Expiry                     | Strike     | Price      | Implied volatility");
+
+    let mut straddles = HashMap::new();
+
+    for options in grouped_options.values() {
+        let Some(first_option) = options.first() else {
+            continue;
+        };
+        let expiry = first_option.get_expiration()?;
+
+        match analytics::find_atm_straddle(options) {
+            Ok(Some(straddle)) => {
+                println!(
+                    "{:<26} | {:<10} | {:<10} | {}",
+                    helpers::format_time(expiry),
+                    straddle.strike,
+                    straddle.price.round_to_decimal_places(2),
+                    straddle.implied_volatility.round_to_decimal_places(4)
+                );
+                straddles.insert(expiry, straddle);
+            }
+            Ok(None) => println!("{:<26} | No strike has both a call and a put", helpers::format_time(expiry)),
+            Err(e) => println!("{:<26} | Failed solving the straddle: {}", helpers::format_time(expiry), e.reason),
+        }
+    }
+
+    Ok(straddles)
+}
+
+/// Make each smile's fit pass within the tolerance (as a fraction) of its expiry's straddle. See
+/// SmileGraph::set_atm_anchor().
+fn set_atm_anchors(
+    smile_graphs: &mut [SmileGraph],
+    straddles: &HashMap<DateTime<Utc>, Straddle>,
+    tolerance: f64,
+) -> Result<(), TsError> {
+    println!("Anchoring smiles within {} vol points of their straddles...", tolerance * 100.0);
+
+    let mut anchored_smiles = 0;
+
+    for graph in smile_graphs {
+        let expiry = graph.get_expiration()?;
+
+        match straddles.get(&expiry) {
+            Some(straddle) => {
+                graph.set_atm_anchor(straddle, tolerance)?;
+                anchored_smiles += 1;
+            }
+            None => println!("{} has no straddle, so fitting it without an anchor...", helpers::format_time(expiry)),
+        }
+    }
+
+    println!("Anchored {anchored_smiles} smiles");
+
+    Ok(())
+}
+
+/// Returns true if the smile has the same options as the saved one, each with the same quote or an implied volatility
+/// within the tolerance of the saved one.
+fn is_smile_unchanged(graph: &SmileGraph, saved_graph: &SmileGraph, tolerance: f64) -> Result<bool, TsError> {
+    if saved_graph.quote_hash == graph.quote_hash {
+        return Ok(true);
+    }
+
+    let saved_implied_volatilities = saved_graph
+        .options
+        .iter()
+        .map(|x| Ok((&x.instrument_id, x.get_implied_volatility()?)))
+        .collect::<Result<HashMap<&Box<str>, f64>, TsError>>()?;
+
+    if graph.options.len() != saved_implied_volatilities.len() {
+        return Ok(false);
+    }
+
+    for option in &graph.options {
+        let Some(saved_implied_volatility) = saved_implied_volatilities.get(&option.instrument_id) else {
+            return Ok(false);
+        };
+
+        if (option.get_implied_volatility()? - saved_implied_volatility).abs() * 100.0 > tolerance {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+fn fit_surface(
+    smile_graphs: &mut [SmileGraph],
+    surface_model: SurfaceModel,
+    fit_options: &FitOptions,
+    clock: Clock,
+) -> Result<Vec<DiscardedSmile>, TsError> {
+    match surface_model {
+        SurfaceModel::Svi => fit_smile_graphs(smile_graphs, fit_options, clock),
+        SurfaceModel::Essvi => fit_essvi_surface(smile_graphs),
+    }
+}
+
+/// Fit every smile at once with eSSVI, which is quick enough that the thread count and fitting budget aren't needed.
+fn fit_essvi_surface(smile_graphs: &mut [SmileGraph]) -> Result<Vec<DiscardedSmile>, TsError> {
+    println!("Fitting an eSSVI surface to {} smiles...", smile_graphs.len());
+
+    sort_by_expiry(smile_graphs)?;
+
+    let surface = analytics::fit_essvi_surface(smile_graphs)?;
+
+    println!(
+        "eta={}, gamma={}",
+        surface.power_law.eta.round_to_decimal_places(4),
+        surface.power_law.gamma.round_to_decimal_places(4)
+    );
+    println!("Expiry                     | Theta      | Rho     | Psi        | RMSE");
+
+    for slice in &surface.slices {
+        println!(
+            "{:<26} | {:<10} | {:<7} | {:<10} | {}",
+            helpers::format_time(slice.expiry),
+            slice.theta.round_to_decimal_places(6),
+            slice.rho.round_to_decimal_places(4),
+            slice.psi.round_to_decimal_places(6),
+            slice.rmse.round_to_decimal_places(4),
+        );
+    }
+
+    let failures = smile_graphs
+        .iter()
+        .filter(|x| !x.has_been_fit)
+        .map(|x| DiscardedSmile::new(x, DiscardStage::Fit, "The eSSVI surface has no slice for it"))
+        .collect::<Result<Vec<DiscardedSmile>, TsError>>()?;
+
+    println!("Successfully fit {}/{} smiles...", surface.slices.len(), smile_graphs.len());
+
+    Ok(failures)
+}
+
+fn fit_smile_graphs(
+    smile_graphs: &mut [SmileGraph],
+    fit_options: &FitOptions,
+    clock: Clock,
+) -> Result<Vec<DiscardedSmile>, TsError> {
+    let FitOptions {
+        quiet,
+        threads,
+        search_threads,
+        fit_budget,
+        ref cancel,
+    } = *fit_options;
+
+    println!("Fitting smile graphs...");
+
+    for graph in smile_graphs.iter_mut() {
+        graph.search_threads = search_threads;
+    }
+
+    sort_by_expiry(smile_graphs)?;
+
+    let predicted_seconds = predict_fit_seconds(smile_graphs, &load_fit_time_model()?, clock)?
+        .into_iter()
+        .map(|x| x.0)
+        .collect::<Vec<f64>>();
+    let budgets = allocate_fit_budgets(&predicted_seconds, threads, fit_budget);
+    let days_until_expiry = smile_graphs
+        .iter()
+        .map(|x| get_days_until_expiry(x, clock))
+        .collect::<Result<Vec<f64>, TsError>>()?;
+    let total = smile_graphs.len();
+
+    println!(
+        "Fitting should take about {} seconds using {threads} thread(s)...",
+        estimate_wall_clock_seconds(&predicted_seconds, threads).round_to_decimal_places(1)
+    );
+    if let Some(budget) = fit_budget {
+        println!("Sharing the {} second fitting budget between the smiles...", budget.as_secs_f64());
+    }
+
+    // Longest last, as they are taken from the end. Fitting the longest first means the smiles left when the threads run
+    // out of work are short ones, rather than one thread fitting a long smile alone at the end.
+    let mut queue = smile_graphs
+        .iter_mut()
+        .enumerate()
+        .collect::<Vec<(usize, &mut SmileGraph)>>();
+    queue.sort_by(|a, b| predicted_seconds[a.0].total_cmp(&predicted_seconds[b.0]));
+
+    let queue = Mutex::new(queue);
+    let progress = Mutex::new(ProgressBar::new("Fitting smiles", total as u64, quiet));
+    let lock_error = || TsError::new(RuntimeError, "A fitting thread panicked while holding a lock");
+
+    let fits = thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads.min(total))
+            .map(|_| {
+                scope.spawn(|| -> Result<Vec<SmileFit>, TsError> {
+                    let mut fits = Vec::new();
+
+                    // Once cancelled, the smiles being fit are finished but no more are started.
+                    while !cancel.is_cancelled()
+                        && let Some((index, graph)) = queue.lock().map_err(|_| lock_error())?.pop()
+                    {
+                        // Each smile gets its own progress bar, so that threads don't have to share one while fitting.
+                        let smile_progress = ProgressBar::new(format!("Smile {}", index + 1), 1, quiet);
+                        smile_progress.message("");
+                        smile_progress.message(format!(
+                            "Fitting smile {} ({}), predicted to take {} seconds...",
+                            index + 1,
+                            helpers::format_time(graph.get_expiration()?),
+                            predicted_seconds[index].round_to_decimal_places(1)
+                        ));
+                        smile_progress.message("=====================================");
+
+                        let start = Instant::now();
+                        let result = graph.fit_smile_with_budget(&smile_progress, budgets[index]);
+
+                        if let Err(e) = &result {
+                            println!("Failed fitting smile {}: {}...", index + 1, e.reason);
+                        }
+
+                        fits.push(SmileFit {
+                            index,
+                            result,
+                            seconds: start.elapsed().as_secs_f64(),
+                        });
+                        progress.lock().map_err(|_| lock_error())?.increment();
+                    }
+
+                    Ok(fits)
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|x| {
+                x.join()
+                    .map_err(|_| TsError::new(RuntimeError, "Fitting thread panicked"))?
+            })
+            .collect::<Result<Vec<Vec<SmileFit>>, TsError>>()
+    })?;
+
+    let unstarted_smiles = queue.into_inner().map_err(|_| lock_error())?.len();
+    if unstarted_smiles > 0 {
+        println!("Fitting cancelled, leaving {unstarted_smiles} smiles unfit...");
+    }
+
+    let mut succeeded_smiles = 0;
+    let mut failures = Vec::new();
+    let mut history = NdjsonWriter::append(&fileio::get_data_path(DataScope::Market, FIT_TIME_HISTORY_FILE_NAME))?;
+
+    for fit in fits.into_iter().flatten() {
+        let budget_ran_out = match fit.result {
+            Ok(v) => v,
+            Err(e) => {
+                failures.push(DiscardedSmile::new(&smile_graphs[fit.index], DiscardStage::Fit, e.reason)?);
+                continue;
+            }
+        };
+
+        succeeded_smiles += 1;
+
+        // A fit cut short by the budget doesn't show how long a whole fit takes.
+        if budget_ran_out {
+            println!(
+                "Smile {} ({}) is budget limited, keeping the best curve found in {} seconds...",
+                fit.index + 1,
+                helpers::format_time(smile_graphs[fit.index].get_expiration()?),
+                fit.seconds.round_to_decimal_places(1)
+            );
+        } else {
+            history.write(&FitTimeRecord {
+                recorded_at: helpers::get_now(),
+                days_until_expiry: days_until_expiry[fit.index],
+                quote_count: smile_graphs[fit.index].options.len(),
+                seconds: fit.seconds,
+            })?;
+        }
+    }
+
+    println!("Successfully fit {}/{} smiles...", succeeded_smiles, total);
+
+    Ok(failures)
+}
+
+fn smooth_term_structure(smile_graphs: &mut [SmileGraph], smoothing: TermStructureSmoothing) -> Result<(), TsError> {
+    println!("Smoothing at the money term structure ({smoothing:?})...");
+
+    for adjustment in analytics::smooth_term_structure(smile_graphs, smoothing)? {
+        println!(
+            "{}: at the money volatility {} -> {} (target {})...",
+            helpers::format_time(adjustment.expiry),
+            adjustment
+                .original_atm_implied_volatility
+                .round_to_decimal_places(4),
+            adjustment
+                .adjusted_atm_implied_volatility
+                .round_to_decimal_places(4),
+            adjustment
+                .target_atm_implied_volatility
+                .round_to_decimal_places(4),
+        );
+    }
+
+    println!("Done!");
+
+    Ok(())
+}
+
+/// Log any calendar arbitrage between the fitted smiles. This doesn't stop the surface being built, since each smile is
+/// still usable on its own and interpolating between them never lets total variance fall.
+fn check_calendar_arbitrage(smile_graphs: &[SmileGraph]) -> Result<Vec<CalendarArbitrage>, TsError> {
+    println!("Checking for calendar arbitrage...");
+
+    let fitted_graphs: Vec<&SmileGraph> = smile_graphs.iter().filter(|x| x.has_been_fit).collect();
+    let violations = analytics::check_calendar_arbitrage(&fitted_graphs, 100)?;
+
+    for violation in &violations {
+        println!(
+            "Total variance falls from {} at {} to {} at {} (log moneyness {})...",
+            violation.earlier_total_variance.round_to_decimal_places(6),
+            helpers::format_time(violation.earlier_expiry),
+            violation.later_total_variance.round_to_decimal_places(6),
+            helpers::format_time(violation.later_expiry),
+            violation.log_moneyness.round_to_decimal_places(4),
+        );
+    }
+
+    println!("Found calendar arbitrage between {} pairs of expiries", violations.len());
+
+    Ok(violations)
+}
+
+/// Print the smallest value of g(k) across the observed strikes of each fitted smile, and the strike it's at. g(k) is
+/// negative wherever there is butterfly arbitrage, so this shows how close each fit is to having any rather than just
+/// whether it does.
+fn report_minimum_g(smile_graphs: &[SmileGraph]) -> Result<(), TsError> {
+    println!("Finding the minimum g(k) of each smile...");
+    println!("Expiry                     | Minimum g(k) | At strike");
+
+    for graph in smile_graphs.iter().filter(|x| x.has_been_fit) {
+        let (strike, g) = graph.get_minimum_g()?;
+
+        println!(
+            "{:<26} | {:<12} | {}{}",
+            helpers::format_time(graph.get_expiration()?),
+            g.round_to_decimal_places(6),
+            strike.round_to_decimal_places(2),
+            if g < 0.0 { " (butterfly arbitrage)" } else { "" },
+        );
+    }
+
+    Ok(())
+}
+
+/// Check the fitted smiles for arbitrage again in a different way (see analytics::verify_no_arbitrage()), and fail if it
+/// finds arbitrage that the checks made while fitting didn't: butterfly arbitrage in any smile, or calendar arbitrage
+/// between expiries that weren't already logged as having it. A disagreement means one of the checks is wrong, so the
+/// surface can't be trusted.
+fn verify_arbitrage(smile_graphs: &[SmileGraph], calendar_arbitrage: &[CalendarArbitrage]) -> Result<(), TsError> {
+    println!("Verifying the surface is free of arbitrage...");
+
+    let fitted_graphs: Vec<&SmileGraph> = smile_graphs.iter().filter(|x| x.has_been_fit).collect();
+    let mut disagreements = Vec::new();
+
+    for (i, verification) in analytics::verify_no_arbitrage(&fitted_graphs)?
+        .iter()
+        .enumerate()
+    {
+        let expiry = helpers::format_time(verification.expiry);
+
+        if constants::CHECK_FOR_ARBITRAGE
+            && let Some(strike) = verification.butterfly_arbitrage_strike
+        {
+            disagreements.push(format!("{expiry} has butterfly arbitrage near strike {}", strike.round_to_decimal_places(2)));
+        }
+
+        let already_found = calendar_arbitrage
+            .iter()
+            .any(|x| x.later_expiry == verification.expiry);
+
+        if let Some(log_moneyness) = verification.calendar_arbitrage_log_moneyness
+            && !already_found
+        {
+            disagreements.push(format!(
+                "{expiry} has calendar arbitrage against {} at log moneyness {}",
+                helpers::format_time(fitted_graphs[i - 1].get_expiration()?),
+                log_moneyness.round_to_decimal_places(4)
+            ));
+        }
+    }
+
+    for disagreement in &disagreements {
+        println!("{disagreement}...");
+    }
+
+    if !disagreements.is_empty() {
+        return Err(TsError::new(
+            RuntimeError,
+            format!("Verification found {} cases of arbitrage that fitting didn't", disagreements.len()),
+        ));
+    }
+
+    println!("Done!");
+
+    Ok(())
+}
+
+/// Score the quality of the surface, saving the score to the history file so that it can be tracked over time.
+fn calculate_surface_score(
+    smile_graphs: &[SmileGraph],
+    oldest_data_time: Option<DateTime<Utc>>,
+    scored_at: DateTime<Utc>,
+) -> Result<SurfaceScore, TsError> {
+    println!("Calculating surface score...");
+
+    let oldest_data_time = oldest_data_time.ok_or(TsError::new(RuntimeError, "No market data timestamps were found"))?;
+    let data_age_seconds = (scored_at - oldest_data_time).num_milliseconds() as f64 / 1000.0;
+    let score = SurfaceScore::calculate(smile_graphs, data_age_seconds, scored_at)?;
+
+    fileio::NdjsonWriter::append(&fileio::get_data_path(DataScope::Market, "surface-score-history.ndjson"))?.write(&score)?;
+
+    println!("{score}");
+
+    Ok(score)
+}
+
+/// Report how well each smile was fit, printing a summary and saving the full report.
+fn save_fit_quality_report(smile_graphs: &[SmileGraph], calculated_at: DateTime<Utc>) -> Result<(), TsError> {
+    println!("Reporting fit quality...");
+
+    let report = FitQualityReport::calculate(smile_graphs, calculated_at)?;
+
+    println!(
+        "Expiry                     | RMSE (vol pts) | Max (vol pts) | R²      | Outside bid/ask | Max quote age (s) | Worst fit"
+    );
+
+    for smile in &report.smiles {
+        println!(
+            "{:<26} | {:<14} | {:<13} | {:<7} | {:<15} | {:<17} | {}",
+            helpers::format_time(smile.expiry),
+            smile.rmse_vol_points.round_to_decimal_places(2),
+            smile
+                .max_absolute_residual_vol_points
+                .round_to_decimal_places(2),
+            smile.r_squared.round_to_decimal_places(4),
+            format!("{}/{}", smile.outside_bid_ask_count, smile.options.len()),
+            smile
+                .max_quote_age_seconds
+                .map_or("-".to_string(), |x| x.round_to_decimal_places(1).to_string()),
+            smile.worst_fit_instruments.first().map_or("", |x| x),
+        );
+    }
+
+    let path = fileio::get_data_path(DataScope::Snapshot, FIT_QUALITY_REPORT_FILE_NAME);
+    fileio::save_struct_to_file(&report, &path)?;
+
+    println!("Saved the per-option residuals to {path}");
+
+    Ok(())
+}
+
+/// Refit each fitted smile with each fold of its options held out, printing how well the held out options' implied
+/// volatilities were predicted next to how well the whole smile was fit. See analytics::cross_validate_smile().
+fn cross_validate_smiles(smile_graphs: &[SmileGraph], cross_validation: CrossValidation, quiet: bool) -> Result<(), TsError> {
+    println!("Cross-validating smiles ({cross_validation:?})...");
+
+    let fitted_graphs: Vec<&SmileGraph> = smile_graphs.iter().filter(|x| x.has_been_fit).collect();
+    let total_folds = fitted_graphs
+        .iter()
+        .map(|x| match cross_validation {
+            CrossValidation::LeaveOneOut => x.options.len(),
+            CrossValidation::KFold(folds) => folds.min(x.options.len()),
+        })
+        .sum::<usize>();
+    let mut progress = ProgressBar::new("Fitting folds", total_folds as u64, quiet);
+    let mut results = Vec::new();
+
+    for graph in fitted_graphs {
+        match analytics::cross_validate_smile(graph, cross_validation, &mut progress) {
+            Ok(result) => results.push(result),
+            Err(e) => println!("Failed cross-validating {}: {}...", helpers::format_time(graph.get_expiration()?), e.reason),
+        }
+    }
+
+    println!("Expiry                     | Folds | In sample RMSE (vol pts) | Out of sample RMSE (vol pts) | Out of sample max");
+
+    for result in &results {
+        println!(
+            "{:<26} | {:<5} | {:<24} | {:<28} | {}{}",
+            helpers::format_time(result.expiry),
+            result.folds,
+            result
+                .in_sample_rmse_vol_points
+                .map_or("-".to_string(), |x| x.round_to_decimal_places(2).to_string()),
+            result
+                .out_of_sample_rmse_vol_points
+                .round_to_decimal_places(2),
+            result
+                .out_of_sample_max_absolute_error_vol_points
+                .round_to_decimal_places(2),
+            match result.failed_folds {
+                0 => String::new(),
+                n => format!(" ({n} folds couldn't be fit)"),
+            },
+        );
+    }
+
+    // A fit that predicts the held out options much worse than it fits them all is following the noise in the quotes.
+    let predicted_options = results.iter().map(|x| x.predicted_options).sum::<usize>();
+    if predicted_options > 0 {
+        let squared_errors = results
+            .iter()
+            .map(|x| x.out_of_sample_rmse_vol_points.powi(2) * x.predicted_options as f64)
+            .sum::<f64>();
+
+        println!(
+            "Out of sample RMSE across all {predicted_options} predicted options: {} vol points",
+            (squared_errors / predicted_options as f64)
+                .sqrt()
+                .round_to_decimal_places(2)
+        );
+    }
+
+    Ok(())
+}
+
+/// Refit each fitted smile to its resampled residuals and save the confidence bands found with it, printing how wide the
+/// band of each smile's implied volatility is at the money and at its lowest and highest observed strikes. See
+/// analytics::bootstrap_smile().
+fn bootstrap_smiles(smile_graphs: &mut [SmileGraph], resamples: usize, quiet: bool) -> Result<(), TsError> {
+    println!(
+        "Bootstrapping confidence bands ({resamples} resamples, {}% confidence)...",
+        BOOTSTRAP_CONFIDENCE_LEVEL * 100.0
+    );
+
+    let fitted_graphs = smile_graphs
+        .iter_mut()
+        .filter(|x| x.has_been_fit)
+        .collect::<Vec<&mut SmileGraph>>();
+    let mut progress = ProgressBar::new("Fitting resamples", (fitted_graphs.len() * resamples) as u64, quiet);
+    let mut bootstrapped_graphs = Vec::new();
+
+    for graph in fitted_graphs {
+        match analytics::bootstrap_smile(graph, resamples, BOOTSTRAP_CONFIDENCE_LEVEL, &mut progress) {
+            Ok(band) => {
+                graph.confidence_band = Some(band);
+                bootstrapped_graphs.push(&*graph);
+            }
+            Err(e) => println!("Failed bootstrapping {}: {}...", helpers::format_time(graph.get_expiration()?), e.reason),
+        }
+    }
+
+    println!("Expiry                     | Band width at lowest / ATM / highest strike (vol pts) | b             | rho");
+
+    for graph in bootstrapped_graphs {
+        let Some(band) = &graph.confidence_band else {
+            continue;
+        };
+        let band_widths = [
+            graph.lowest_observed_strike,
+            graph.get_underlying_forward_price()?,
+            graph.highest_observed_strike,
+        ]
+        .map(|strike| get_band_width_vol_points(band, strike));
+
+        println!(
+            "{:<26} | {:<53} | {:<13} | {}{}",
+            helpers::format_time(graph.get_expiration()?),
+            band_widths
+                .map(|x| x.map_or("-".to_string(), |x| x.round_to_decimal_places(2).to_string()))
+                .join(" / "),
+            format_interval(band.b),
+            format_interval(band.rho),
+            match band.failed_resamples {
+                0 => String::new(),
+                n => format!(" ({n} resamples couldn't be fit)"),
+            },
+        );
+    }
+
+    Ok(())
+}
+
+fn format_interval(interval: ConfidenceInterval) -> String {
+    format!("{} - {}", interval.lower.round_to_decimal_places(3), interval.upper.round_to_decimal_places(3))
+}
+
+/// The width of the band's implied volatility interval at the band strike nearest the given one, in vol points.
+fn get_band_width_vol_points(band: &SmileConfidenceBand, strike: f64) -> Option<f64> {
+    band.strikes
+        .iter()
+        .min_by(|a, b| {
+            (a.strike - strike)
+                .abs()
+                .total_cmp(&(b.strike - strike).abs())
+        })
+        .map(|x| (x.implied_volatility.upper - x.implied_volatility.lower) * 100.0)
+}
+
+/// Interpolate a smile at each standard tenor that has no listed expiry near it, so that consumers of the saved surface
+/// can always find them. A tenor that can't be interpolated without arbitrage is left out rather than stopping the build.
+fn build_synthetic_smiles(smile_graphs: &[SmileGraph]) -> Result<Vec<SyntheticSmile>, TsError> {
+    println!("Building synthetic smiles at missing standard tenors...");
+
+    let mut synthetic_smiles = Vec::new();
+
+    for tenor_days in analytics::STANDARD_TENOR_DAYS {
+        match analytics::build_synthetic_smile(smile_graphs, tenor_days) {
+            Ok(None) => {}
+            Ok(Some(smile)) => {
+                println!(
+                    "Built a {tenor_days} day smile from {} and {} (rmse {})...",
+                    helpers::format_time(smile.earlier_expiry),
+                    helpers::format_time(smile.later_expiry),
+                    smile.rmse.round_to_decimal_places(8)
+                );
+                synthetic_smiles.push(smile);
+            }
+            Err(e) => println!("Failed building a {tenor_days} day smile: {}...", e.reason),
+        }
+    }
+
+    println!("Built {} synthetic smiles", synthetic_smiles.len());
+
+    Ok(synthetic_smiles)
+}
+
+fn save_data_to_file(data: &SmileGraphsDataContainer, compress: bool) -> Result<(), TsError> {
+    println!("Saving data to file...");
 
-    fileio::save_struct_to_file(&data, "./data/smile-graph-data.json")?;
+    let path = surface_file::save_surface(data, &fileio::get_data_path(DataScope::Snapshot, "smile-graph-data.json"), compress)?;
 
-    println!("Successfully saved to file");
+    println!("Successfully saved to {path}");
 
     Ok(())
 }
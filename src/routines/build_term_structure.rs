@@ -0,0 +1,291 @@
+use plotters::prelude::*;
+use plotters::style::full_palette::ORANGE;
+
+use crate::analytics::{SmileGraphsDataContainer, TermStructure};
+use crate::config::Config;
+use crate::fileio::{self, DataScope, HtmlGraph, HtmlGraphPoint, HtmlGraphSeries, HtmlGraphSeriesStyle};
+use crate::helpers::{self, F64Helpers, error_unless_positive_f64};
+use crate::routines::RunSummary;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+const GRAPHS_DIRECTORY: &str = "graphs/term-structure/";
+
+const X_LABEL: &str = "Days Until Expiry";
+const ATM_VOLATILITY_LABEL: &str = "At The Money Implied Volatility (σ)";
+const FORWARD_VARIANCE_LABEL: &str = "Forward Variance (σ² per year)";
+
+/// The kind of file the graphs are saved as.
+#[derive(Clone, Copy)]
+enum GraphFormat {
+    Png,
+    /// A self-contained interactive page for each graph.
+    Html,
+}
+
+/// Graph at the money implied volatility, and the forward variance between each pair of consecutive expiries, against
+/// time until expiry, so that whether volatility is in contango or backwardation can be seen at a glance.
+pub fn build_term_structure(args: &[String], config: &Config, run: &mut RunSummary) -> Result<(), TsError> {
+    println!("===============================================================");
+    println!("===============================================================");
+    println!(
+        "Building the {} volatility term structure graph and saving to file",
+        fileio::get_currency().get_name()
+    );
+    println!("===============================================================");
+    println!("===============================================================");
+
+    let format = match helpers::get_flag_value(args, "--format").map_err(|e| e.context("Invalid arguments"))? {
+        None | Some("png") => GraphFormat::Png,
+        Some("html") => GraphFormat::Html,
+        Some(other) => return Err(TsError::new(RuntimeError, format!("Invalid arguments: Unknown graph format {other}"))),
+    };
+
+    println!("Loading surface data...");
+    let mut data = run.step("Loading surface data", || {
+        fileio::load_versioned_struct_from_file::<SmileGraphsDataContainer>(&fileio::find_data_file(&fileio::get_data_path(
+            DataScope::Snapshot,
+            "smile-graph-data.json",
+        )))
+    })?;
+
+    for graph in &mut data.smile_graphs {
+        graph.wing_extrapolation = config.wing_extrapolation;
+    }
+    println!("Found {} smile graphs...", data.smile_graphs.len());
+
+    let term_structure = run.step("Calculating term structure", || TermStructure::calculate(&data.smile_graphs))?;
+    println!("------------------------------");
+
+    print_term_structure(&term_structure);
+    println!("------------------------------");
+
+    println!("Deleting any existing term structure graphs...");
+    let directory = fileio::get_data_path(DataScope::Snapshot, GRAPHS_DIRECTORY);
+    run.step("Creating graphs directory", || fileio::create_directory(&directory))?;
+    run.step("Clearing graphs directory", || fileio::clear_directory(&directory, "gitkeep"))?;
+
+    let header = config.report_header.render();
+    run.step("Building term structure graph", || match format {
+        GraphFormat::Png => create_graph(&term_structure, header.as_deref()),
+        GraphFormat::Html => create_html_graphs(&term_structure, header.as_deref()),
+    })?;
+
+    println!("Done!");
+    println!("===============================================================");
+
+    Ok(())
+}
+
+fn print_term_structure(term_structure: &TermStructure) {
+    println!("Expiry                     | Days   | ATM vol | Fwd variance | Fwd vol | Shape");
+
+    for (i, point) in term_structure.points.iter().enumerate() {
+        // Each expiry's forward variance is from the expiry before it.
+        let forward_variance = i
+            .checked_sub(1)
+            .and_then(|i| term_structure.forward_variances.get(i));
+        let shape = match i.checked_sub(1).map(|i| &term_structure.points[i]) {
+            Some(previous) if point.atm_implied_volatility > previous.atm_implied_volatility => "contango",
+            Some(previous) if point.atm_implied_volatility < previous.atm_implied_volatility => "backwardation",
+            Some(_) => "flat",
+            None => "-",
+        };
+
+        println!(
+            "{:<26} | {:<6} | {:<7} | {:<12} | {:<7} | {}",
+            helpers::format_time(point.expiry),
+            (point.years_until_expiry * 365.0).round_to_decimal_places(1),
+            point.atm_implied_volatility.round_to_decimal_places(4),
+            forward_variance
+                .map(|x| x.variance.round_to_decimal_places(4).to_string())
+                .unwrap_or("-".into()),
+            forward_variance
+                .and_then(|x| x.get_volatility())
+                .map(|x| x.round_to_decimal_places(4).to_string())
+                .unwrap_or("-".into()),
+            shape,
+        );
+    }
+}
+
+/// The at the money volatility of each expiry, as (days until expiry, volatility).
+fn get_atm_volatility_points(term_structure: &TermStructure) -> Vec<(f64, f64)> {
+    term_structure
+        .points
+        .iter()
+        .map(|x| (x.years_until_expiry * 365.0, x.atm_implied_volatility))
+        .collect()
+}
+
+/// The forward variance as a step line, flat between each pair of consecutive expiries, as (days until expiry, variance).
+fn get_forward_variance_points(term_structure: &TermStructure) -> Vec<(f64, f64)> {
+    term_structure
+        .forward_variances
+        .iter()
+        .flat_map(|x| [(x.start_years * 365.0, x.variance), (x.end_years * 365.0, x.variance)])
+        .collect()
+}
+
+/// Save at the money volatility and forward variance as two graphs stacked in one image.
+fn create_graph(term_structure: &TermStructure, header: Option<&str>) -> Result<(), TsError> {
+    let path = fileio::get_data_path(
+        DataScope::Snapshot,
+        &format!("{GRAPHS_DIRECTORY}{}-term-structure.png", fileio::get_currency().get_file_code()),
+    );
+    let atm_volatility_points = get_atm_volatility_points(term_structure);
+    let forward_variance_points = get_forward_variance_points(term_structure);
+    let last_day = atm_volatility_points
+        .last()
+        .ok_or(TsError::new(RuntimeError, "No expiries to graph"))?
+        .0;
+    error_unless_positive_f64(last_day, "last_day")?;
+
+    let root = BitMapBackend::new(&path, (1920, 2160)).into_drawing_area();
+
+    println!("Creating graph at {path}...");
+
+    root.fill(&WHITE)
+        .map_err(|e| TsError::new(RuntimeError, format!("Filling graph failed: {}", e)).with_source(e))?;
+
+    let root = match header {
+        Some(header) => root
+            .titled(header, ("sans-serif", 30))
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph header failed: {}", e)).with_source(e))?,
+        None => root,
+    };
+    let root = root
+        .titled(
+            &format!("Volatility term structure of {} options", fileio::get_currency().get_name()),
+            ("sans-serif", 50),
+        )
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph title failed: {}", e)).with_source(e))?;
+    let areas = root.split_evenly((2, 1));
+
+    let y_finish = atm_volatility_points
+        .iter()
+        .map(|x| x.1)
+        .fold(0.0, f64::max)
+        * 1.05;
+    let mut chart = ChartBuilder::on(&areas[0])
+        .margin(15)
+        .x_label_area_size(50)
+        .y_label_area_size(100)
+        .build_cartesian_2d(0.0..last_day * 1.05, 0.0..y_finish)
+        .map_err(|e| TsError::new(RuntimeError, format!("Building graph failed: {}", e)).with_source(e))?;
+
+    chart
+        .configure_mesh()
+        .x_desc(X_LABEL)
+        .y_desc(ATM_VOLATILITY_LABEL)
+        .axis_desc_style(("sans-serif", 30))
+        .draw()
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph mesh failed: {}", e)).with_source(e))?;
+
+    chart
+        .draw_series(LineSeries::new(atm_volatility_points.clone(), RED.stroke_width(2)))
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing term structure failed: {}", e)).with_source(e))?;
+
+    chart
+        .draw_series(PointSeries::<_, _, Circle<_, _>, _>::new(atm_volatility_points, 5, RED.filled()))
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing expiry points failed: {}", e)).with_source(e))?;
+
+    // Forward variance is negative between expiries with calendar arbitrage, so the axis goes below zero if needed.
+    let variances = || forward_variance_points.iter().map(|x| x.1);
+    let y_start = variances().fold(0.0, f64::min) * 1.05;
+    let y_finish = variances().fold(0.0, f64::max) * 1.05;
+    let mut chart = ChartBuilder::on(&areas[1])
+        .margin(15)
+        .x_label_area_size(50)
+        .y_label_area_size(100)
+        .build_cartesian_2d(0.0..last_day * 1.05, y_start..y_finish)
+        .map_err(|e| TsError::new(RuntimeError, format!("Building graph failed: {}", e)).with_source(e))?;
+
+    chart
+        .configure_mesh()
+        .x_desc(X_LABEL)
+        .y_desc(FORWARD_VARIANCE_LABEL)
+        .axis_desc_style(("sans-serif", 30))
+        .draw()
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph mesh failed: {}", e)).with_source(e))?;
+
+    chart
+        .draw_series(LineSeries::new(forward_variance_points, ORANGE.stroke_width(2)))
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing forward variance failed: {}", e)).with_source(e))?;
+
+    chart
+        .draw_series(DashedLineSeries::new(vec![(0.0, 0.0), (last_day * 1.05, 0.0)], 6, 4, ShapeStyle::from(BLACK)))
+        .map_err(|e| TsError::new(RuntimeError, format!("Drawing zero line failed: {}", e)).with_source(e))?;
+
+    root.present()
+        .map_err(|e| TsError::new(RuntimeError, format!("Finalising graph failed: {}", e)).with_source(e))?;
+
+    Ok(())
+}
+
+/// Save at the money volatility and forward variance as an interactive HTML page each.
+fn create_html_graphs(term_structure: &TermStructure, header: Option<&str>) -> Result<(), TsError> {
+    let atm_volatility_series = HtmlGraphSeries {
+        name: "At the money implied volatility".to_string(),
+        colour: "red".to_string(),
+        style: HtmlGraphSeriesStyle::Line,
+        points: term_structure
+            .points
+            .iter()
+            .map(|x| HtmlGraphPoint {
+                x: x.years_until_expiry * 365.0,
+                y: x.atm_implied_volatility,
+                label: Some(format!("Expiry {}", helpers::format_time(x.expiry))),
+                radius: None,
+            })
+            .collect(),
+    };
+    let forward_variance_series = HtmlGraphSeries {
+        name: "Forward variance".to_string(),
+        colour: "orange".to_string(),
+        style: HtmlGraphSeriesStyle::Line,
+        points: get_forward_variance_points(term_structure)
+            .into_iter()
+            .map(|(x, y)| HtmlGraphPoint {
+                x,
+                y,
+                label: None,
+                radius: None,
+            })
+            .collect(),
+    };
+
+    for (name, title, y_label, series) in [
+        (
+            "atm-volatility",
+            format!("At the money implied volatility term structure of {} options", fileio::get_currency().get_name()),
+            ATM_VOLATILITY_LABEL,
+            atm_volatility_series,
+        ),
+        (
+            "forward-variance",
+            format!("Forward variance between expiries of {} options", fileio::get_currency().get_name()),
+            FORWARD_VARIANCE_LABEL,
+            forward_variance_series,
+        ),
+    ] {
+        let path = fileio::get_data_path(
+            DataScope::Snapshot,
+            &format!("{GRAPHS_DIRECTORY}{}-{name}-term-structure.html", fileio::get_currency().get_file_code()),
+        );
+
+        println!("Creating graph at {path}...");
+
+        let graph = HtmlGraph {
+            title: title.to_string(),
+            header: header.map(String::from),
+            x_label: X_LABEL.to_string(),
+            y_label: y_label.to_string(),
+            series: vec![series],
+        };
+
+        fileio::save_graph_to_html(&graph, &path)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,231 @@
+use crate::analytics::{self, HestonCalibration, SmileGraphsDataContainer};
+use crate::config::Config;
+use crate::fileio::DataScope;
+use crate::helpers::F64Helpers;
+use crate::routines::RunSummary;
+use crate::types::TsError;
+use crate::{fileio, helpers};
+
+const CALIBRATION_FILE_NAME: &str = "heston-calibration.json";
+
+pub fn calibrate_heston(config: &Config, run: &mut RunSummary) -> Result<(), TsError> {
+    println!("===============================================================");
+    println!("===============================================================");
+    println!("Calibrating the Heston model to the whole volatility surface");
+    println!("===============================================================");
+    println!("===============================================================");
+
+    println!("Loading surface data...");
+    let mut data = run.step("Loading surface data", || {
+        fileio::load_versioned_struct_from_file::<SmileGraphsDataContainer>(&fileio::find_data_file(&fileio::get_data_path(
+            DataScope::Snapshot,
+            "smile-graph-data.json",
+        )))
+    })?;
+
+    for graph in &mut data.smile_graphs {
+        graph.wing_extrapolation = config.wing_extrapolation;
+    }
+
+    // Only fitted smiles are saved.
+    let smile_graphs = data.smile_graphs;
+    println!("Found {} fitted smile graphs...", smile_graphs.len());
+    println!("------------------------------");
+
+    println!("Calibrating...");
+    let calibration = run.step("Calibrating Heston", || analytics::calibrate_heston(&smile_graphs))?;
+    print_calibration(&calibration);
+    println!("------------------------------");
+
+    let calibration_path = fileio::get_data_path(DataScope::Snapshot, CALIBRATION_FILE_NAME);
+    println!("Saving {calibration_path}...");
+    run.step("Saving Heston calibration", || fileio::save_struct_to_file(&calibration, &calibration_path))?;
+    println!("------------------------------");
+
+    #[cfg(feature = "plotting")]
+    {
+        println!("Creating graphs and saving to file...");
+
+        run.step("Creating graphs directory", || {
+            fileio::create_directory(&fileio::get_data_path(DataScope::Snapshot, "graphs/"))
+        })?;
+
+        let header = config.report_header.render();
+
+        for graph in &smile_graphs {
+            let _ = graphs::create_graph(graph, &calibration, header.as_deref())
+                .inspect_err(|e| println!("Failed building graph: {}", e.reason));
+        }
+    }
+    #[cfg(not(feature = "plotting"))]
+    println!("Not creating graphs because this build does not have the \"plotting\" feature");
+
+    println!("Done!");
+    println!("===============================================================");
+
+    Ok(())
+}
+
+fn print_calibration(calibration: &HestonCalibration) {
+    let params = &calibration.parameters;
+
+    println!("kappa: {}", params.kappa.round_to_decimal_places(4));
+    println!(
+        "theta: {} (long run volatility {})",
+        params.theta.round_to_decimal_places(4),
+        params.theta.sqrt().round_to_decimal_places(4)
+    );
+    println!("sigma: {}", params.sigma.round_to_decimal_places(4));
+    println!("rho: {}", params.rho.round_to_decimal_places(4));
+    println!(
+        "v0: {} (volatility now {})",
+        params.v0.round_to_decimal_places(4),
+        params.v0.sqrt().round_to_decimal_places(4)
+    );
+    println!("Feller condition satisfied: {}", params.satisfies_feller_condition());
+    println!("Overall RMSE: {} volatility points", (calibration.rmse * 100.0).round_to_decimal_places(2));
+    println!("------------------------------");
+    println!("Expiry                     | RMSE (vol points)");
+
+    for smile in &calibration.smiles {
+        println!("{:<26} | {}", helpers::format_time(smile.expiry), (smile.rmse * 100.0).round_to_decimal_places(2));
+    }
+}
+
+#[cfg(feature = "plotting")]
+mod graphs {
+    use plotters::prelude::*;
+
+    use crate::analytics::{self, HestonCalibration, SmileGraph};
+    use crate::fileio::{self, DataScope};
+    use crate::types::TsError;
+    use crate::types::TsErrorType::RuntimeError;
+    use crate::{constants, helpers};
+
+    /// The SVI line, followed by the Heston line.
+    type ComparisonLines = (Vec<(f64, f64)>, Vec<(f64, f64)>);
+
+    /// Plot the Heston smile against the SVI smile and the observed options, across the observed strikes.
+    pub fn create_graph(graph: &SmileGraph, calibration: &HestonCalibration, header: Option<&str>) -> Result<(), TsError> {
+        let expiry = graph.get_expiration()?;
+        let (svi_points, heston_points) = build_comparison_lines(graph, calibration, 200)?;
+        let option_points = graph
+            .options
+            .iter()
+            .map(|x| Ok((x.strike, x.get_implied_volatility()?)))
+            .collect::<Result<Vec<(f64, f64)>, TsError>>()?;
+
+        let y_finish = svi_points
+            .iter()
+            .chain(&heston_points)
+            .chain(&option_points)
+            .map(|x| x.1)
+            .fold(0.0, f64::max);
+
+        let path = fileio::get_data_path(
+            DataScope::Snapshot,
+            &format!("graphs/{}-heston-graph-{}.png", fileio::get_currency().get_file_code(), expiry.format("%Y-%m-%d")),
+        );
+        let root = BitMapBackend::new(&path, (1920, 1080)).into_drawing_area();
+
+        println!("Creating graph at {path}...");
+
+        root.fill(&WHITE)
+            .map_err(|e| TsError::new(RuntimeError, format!("Filling graph failed: {}", e)).with_source(e))?;
+
+        let chart_area = match header {
+            Some(header) => root
+                .titled(header, ("sans-serif", 30))
+                .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph header failed: {}", e)).with_source(e))?,
+            None => root.clone(),
+        };
+
+        let mut chart = ChartBuilder::on(&chart_area)
+            .caption(
+                format!(
+                    "Heston and SVI implied volatility of {} options at expiry {}",
+                    fileio::get_currency().get_name(),
+                    helpers::format_time(expiry)
+                ),
+                ("sans-serif", 50).into_font(),
+            )
+            .margin(15)
+            .x_label_area_size(50)
+            .y_label_area_size(50)
+            .build_cartesian_2d(graph.lowest_observed_strike..graph.highest_observed_strike, 0.0..y_finish * 1.05)
+            .map_err(|e| TsError::new(RuntimeError, format!("Building graph failed: {}", e)).with_source(e))?;
+
+        chart
+            .configure_mesh()
+            .x_desc("Strike Price (K)")
+            .y_desc("Implied Volatility (σ)")
+            .axis_desc_style(("sans-serif", 30))
+            .draw()
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing graph mesh failed: {}", e)).with_source(e))?;
+
+        chart
+            .draw_series(LineSeries::new(svi_points, RED))
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing SVI curve failed: {}", e)).with_source(e))?
+            .label("SVI")
+            .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], RED));
+
+        chart
+            .draw_series(LineSeries::new(heston_points, GREEN))
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing Heston curve failed: {}", e)).with_source(e))?
+            .label("Heston")
+            .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], GREEN));
+
+        chart
+            .draw_series(PointSeries::<_, _, Circle<_, _>, _>::new(option_points, 5, BLUE.filled()))
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing option points failed: {}", e)).with_source(e))?
+            .label("Self-relative implied volatility")
+            .legend(|(x, y)| Circle::new((x, y), 5, BLUE.filled()));
+
+        chart
+            .configure_series_labels()
+            .background_style(WHITE.mix(0.8))
+            .border_style(BLACK)
+            .draw()
+            .map_err(|e| TsError::new(RuntimeError, format!("Drawing series label failed: {}", e)).with_source(e))?;
+
+        root.present()
+            .map_err(|e| TsError::new(RuntimeError, format!("Finalising graph failed: {}", e)).with_source(e))?;
+
+        Ok(())
+    }
+
+    /// The Heston and SVI implied volatilities at evenly spaced strikes across the observed range of a smile.
+    fn build_comparison_lines(
+        graph: &SmileGraph,
+        calibration: &HestonCalibration,
+        number_of_points: u64,
+    ) -> Result<ComparisonLines, TsError> {
+        let strike_range = graph.highest_observed_strike - graph.lowest_observed_strike;
+        let years_until_expiry = graph.get_years_until_expiry()?;
+        let spot_price = graph
+            .options
+            .first()
+            .ok_or(TsError::new(RuntimeError, "Smile graph has no options"))?
+            .spot_price;
+        let mut svi_points = Vec::new();
+        let mut heston_points = Vec::new();
+
+        for i in 0..=number_of_points {
+            let strike = graph.lowest_observed_strike + strike_range * (i as f64 / number_of_points as f64);
+
+            svi_points.push((strike, graph.get_implied_volatility_at_strike(strike)?));
+            // Far from the money the Heston price can be too small to solve for implied volatility, so leave a gap.
+            if let Ok(implied_volatility) = analytics::calculate_heston_implied_volatility(
+                &calibration.parameters,
+                spot_price,
+                strike,
+                years_until_expiry,
+                constants::INTEREST_FREE_RATE,
+            ) {
+                heston_points.push((strike, implied_volatility));
+            }
+        }
+
+        Ok((svi_points, heston_points))
+    }
+}
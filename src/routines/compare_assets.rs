@@ -0,0 +1,96 @@
+use crate::analytics::{AssetComparison, SmileGraph, SmileGraphsDataContainer};
+use crate::config::Config;
+use crate::fileio;
+use crate::fileio::DataScope;
+use crate::helpers::F64Helpers;
+use crate::integrations::Currency;
+use crate::routines::RunSummary;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+const COMPARISON_FILE_NAME: &str = "asset-comparison.json";
+
+pub fn compare_assets(args: &[String], config: &Config, run: &mut RunSummary) -> Result<(), TsError> {
+    println!("===============================================================");
+    println!("===============================================================");
+    println!("Comparing the surfaces of several assets");
+    println!("===============================================================");
+    println!("===============================================================");
+
+    let currencies = Currency::list_from_args(args).map_err(|e| e.context("Invalid arguments"))?;
+
+    if currencies.len() < 2 {
+        return Err(TsError::new(
+            RuntimeError,
+            "Invalid arguments: compare-assets needs at least two currencies, e.g. --currency BTC,ETH",
+        ));
+    }
+
+    let surfaces = run.step("Loading surface data", || {
+        currencies
+            .iter()
+            .map(|currency| Ok((currency.get_code().to_string(), load_surface(*currency, config)?)))
+            .collect::<Result<Vec<(String, Vec<SmileGraph>)>, TsError>>()
+    })?;
+
+    let comparison = run.step("Comparing assets", || AssetComparison::calculate(&surfaces))?;
+    println!("------------------------------");
+
+    print_comparison(&comparison);
+    println!("------------------------------");
+
+    // The comparison isn't any one currency's, so it's kept with the data of all of them.
+    let comparison_path = fileio::get_data_path(DataScope::Global, COMPARISON_FILE_NAME);
+    println!("Saving {comparison_path}...");
+    run.step("Saving comparison", || fileio::save_struct_to_file(&comparison, &comparison_path))?;
+    println!("===============================================================");
+
+    Ok(())
+}
+
+/// Load the currency's latest surface (or the one of --snapshot-date), from the directory its data is kept in.
+fn load_surface(currency: Currency, config: &Config) -> Result<Vec<SmileGraph>, TsError> {
+    let mut paths = config.data_paths.clone();
+    paths.currency = currency;
+    paths.resolve_snapshot_date(false)?;
+
+    let path = format!("{}/smile-graph-data.json", paths.get_directory(DataScope::Snapshot));
+    println!("Loading {path}...");
+
+    let mut data = fileio::load_versioned_struct_from_file::<SmileGraphsDataContainer>(&fileio::find_data_file(&path))?;
+
+    if data.smile_graphs.is_empty() {
+        return Err(TsError::new(RuntimeError, format!("The {} surface has no smiles", currency.get_code())));
+    }
+
+    for graph in &mut data.smile_graphs {
+        graph.wing_extrapolation = config.wing_extrapolation;
+    }
+
+    Ok(data.smile_graphs)
+}
+
+fn print_comparison(comparison: &AssetComparison) {
+    println!(
+        "Tenor | Asset | ATM vol | 25d RR  | Vol ratio to {base} | RR minus {base}",
+        base = comparison.base_asset
+    );
+
+    for tenor in &comparison.tenors {
+        for point in &tenor.assets {
+            println!(
+                "{:<5} | {:<5} | {:<7} | {:<7} | {:<16} | {}",
+                format!("{}d", tenor.tenor_days),
+                point.asset,
+                point.atm_implied_volatility.round_to_decimal_places(4),
+                point.risk_reversal_25_delta.round_to_decimal_places(4),
+                point.atm_volatility_ratio.round_to_decimal_places(4),
+                point.risk_reversal_difference.round_to_decimal_places(4),
+            );
+        }
+    }
+
+    if comparison.tenors.is_empty() {
+        println!("No standard tenor falls within every surface's expiries");
+    }
+}
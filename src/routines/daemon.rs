@@ -0,0 +1,473 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+use crate::config::{Config, DaemonCommand, DaemonSettings, DaemonStep};
+use crate::fileio::DataScope;
+use crate::helpers::CronSchedule;
+use crate::routines::RunSummary;
+use crate::types::TsError;
+use crate::types::TsErrorType::{ApiError, RuntimeError};
+use crate::{fileio, helpers};
+
+const STATE_FILE_NAME: &str = "daemon-state.json";
+
+/// The metrics file, in the Prometheus text format, for e.g. node_exporter's textfile collector to pick up.
+const METRICS_FILE_NAME: &str = "daemon-metrics.prom";
+
+/// The file each command saves its run summary to (see RunSummary).
+const LAST_RUN_FILE_NAME: &str = "last-run.json";
+
+/// How often a running step is checked to see if it has finished.
+const STEP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Options for the daemon, set via command line flags.
+struct DaemonOptions {
+    /// Start a run straight away rather than waiting for the schedule.
+    run_now: bool,
+    /// The config file given by --config, which each step is also run with.
+    config_path: Option<String>,
+    /// The data directory given by --data-dir, which each step is also run with. Each step finds its own snapshot, so
+    /// --snapshot-date isn't passed on.
+    data_directory: Option<String>,
+}
+
+impl DaemonOptions {
+    fn from_args(args: &[String]) -> Result<Self, TsError> {
+        Ok(Self {
+            run_now: helpers::has_flag(args, "--now"),
+            config_path: helpers::get_flag_value(args, "--config")?.map(|x| x.to_string()),
+            data_directory: helpers::get_flag_value(args, "--data-dir")?.map(|x| x.to_string()),
+        })
+    }
+}
+
+/// What the daemon has done, saved after every step so that a restarted daemon carries on where it left off.
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+struct DaemonState {
+    /// The run in progress, if the daemon stopped part way through one.
+    current_run: Option<DaemonRun>,
+    /// The last run that finished, whether or not it succeeded.
+    last_run: Option<DaemonRun>,
+    /// What the metrics file reports, kept here so that its totals carry on when the daemon is restarted. Missing from
+    /// state saved by older versions.
+    #[serde(default)]
+    metrics: DaemonMetrics,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct DaemonRun {
+    /// The time the run was scheduled for.
+    scheduled_time: DateTime<Utc>,
+    started_time: DateTime<Utc>,
+    finished_time: Option<DateTime<Utc>>,
+    /// The steps that have finished successfully, which are always the first steps of the run.
+    completed_steps: Vec<DaemonCommand>,
+    /// Why the run stopped early, if it did.
+    error: Option<String>,
+}
+
+impl DaemonState {
+    /// Load the saved state, or start afresh if there is none or it can't be read.
+    fn load() -> Self {
+        let path = fileio::get_data_path(DataScope::Global, STATE_FILE_NAME);
+
+        if !fileio::file_exists(&path) {
+            return Self::default();
+        }
+
+        fileio::load_struct_from_file::<Self>(&path).unwrap_or_else(|e| {
+            println!("Failed loading {path}, starting afresh: {}...", e.reason);
+            Self::default()
+        })
+    }
+
+    /// Save the state, along with the metrics file. The metrics are only for monitoring, so failing to save them doesn't
+    /// stop the daemon.
+    fn save(&self) -> Result<(), TsError> {
+        fileio::save_text_to_file(
+            &self.metrics.to_prometheus_text(),
+            &fileio::get_data_path(DataScope::Global, METRICS_FILE_NAME),
+        )
+        .unwrap_or_else(|e| println!("Failed saving daemon metrics: {}...", e.reason));
+
+        fileio::save_struct_to_file(self, &fileio::get_data_path(DataScope::Global, STATE_FILE_NAME))
+    }
+
+    /// Do the steps of the current run that haven't been done yet, in order, stopping at the first that fails.
+    fn run(&mut self, settings: &DaemonSettings, daemon_options: &DaemonOptions) -> Result<(), TsError> {
+        for (index, step) in settings.steps.iter().enumerate() {
+            let run = self
+                .current_run
+                .as_mut()
+                .ok_or(TsError::new(RuntimeError, "There is no run in progress"))?;
+
+            if index < run.completed_steps.len() {
+                println!("Skipping {}, which already finished in this run...", step.command.name());
+                continue;
+            }
+
+            let started_time = Utc::now();
+            let started = Instant::now();
+            let result = run_step(step, daemon_options);
+
+            self.metrics
+                .record_step(step.command, started_time, started.elapsed(), result.is_ok());
+
+            match result {
+                Ok(_) => run.completed_steps.push(step.command),
+                Err(e) => {
+                    println!("Stopping the run: {}", e.reason);
+                    run.error = Some(e.reason);
+                    break;
+                }
+            }
+
+            self.save()?;
+        }
+
+        let mut run = self
+            .current_run
+            .take()
+            .ok_or(TsError::new(RuntimeError, "There is no run in progress"))?;
+        run.finished_time = Some(Utc::now());
+        self.metrics.runs += 1;
+        self.metrics.failed_runs += run.error.is_some() as u64;
+
+        println!(
+            "Run for {} finished {}",
+            helpers::format_time(run.scheduled_time),
+            match run.error {
+                None => "successfully",
+                Some(_) => "with errors",
+            }
+        );
+
+        self.last_run = Some(run);
+        self.save()
+    }
+}
+
+/// What the daemon reports in its metrics file.
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+struct DaemonMetrics {
+    runs: u64,
+    failed_runs: u64,
+    /// Steps that failed because the exchange's API returned an error.
+    api_failures: u64,
+    /// When build-surface last finished successfully.
+    last_successful_surface_time: Option<DateTime<Utc>>,
+    /// By command name.
+    steps: BTreeMap<String, StepMetrics>,
+}
+
+/// What a command did the last time the daemon ran it, from the run summary it saved.
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+struct StepMetrics {
+    failures: u64,
+    succeeded: bool,
+    duration_seconds: f64,
+    /// See RunSummary::counts, e.g. the smiles fitted and discarded by build-surface.
+    counts: BTreeMap<String, usize>,
+    /// See RunSummary::measurements, e.g. build-surface's average fit error.
+    measurements: BTreeMap<String, f64>,
+}
+
+/// The parts of a command's run summary (see RunSummary) that the metrics report.
+#[derive(serde::Deserialize)]
+struct StepSummary {
+    command: String,
+    started_at: DateTime<Utc>,
+    duration_seconds: f64,
+    exit_code: i32,
+    #[serde(default)]
+    counts: BTreeMap<String, usize>,
+    #[serde(default)]
+    measurements: BTreeMap<String, f64>,
+}
+
+impl StepSummary {
+    /// Load the run summary saved by the command, if it saved one after the given time. A command that was stopped for
+    /// taking too long, or crashed outright, won't have.
+    fn load(command: DaemonCommand, started_time: DateTime<Utc>) -> Option<Self> {
+        fileio::load_struct_from_file::<Self>(&fileio::get_data_path(DataScope::Global, LAST_RUN_FILE_NAME))
+            .ok()
+            .filter(|x| x.command == command.name() && x.started_at >= started_time)
+    }
+}
+
+impl DaemonMetrics {
+    /// Record how a step that has just finished went.
+    fn record_step(&mut self, command: DaemonCommand, started_time: DateTime<Utc>, duration: Duration, succeeded: bool) {
+        let summary = StepSummary::load(command, started_time);
+        let step = self.steps.entry(command.name().to_string()).or_default();
+
+        step.failures += !succeeded as u64;
+        step.succeeded = succeeded;
+        step.duration_seconds = duration.as_secs_f64();
+
+        match summary {
+            Some(summary) => {
+                // Every API error exits with the same code, whatever the code the API gave.
+                self.api_failures += (summary.exit_code == ApiError { code: 0 }.exit_code()) as u64;
+                step.duration_seconds = summary.duration_seconds;
+                step.counts = summary.counts;
+                step.measurements = summary.measurements;
+            }
+            None => {
+                step.counts.clear();
+                step.measurements.clear();
+            }
+        }
+
+        if succeeded && command == DaemonCommand::BuildSurface {
+            self.last_successful_surface_time = Some(Utc::now());
+        }
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    fn to_prometheus_text(&self) -> String {
+        let mut text = String::new();
+        let mut write_metric = |name: &str, metric_type: &str, help: &str, samples: Vec<(String, f64)>| {
+            let _ = writeln!(text, "# HELP theta_surface_daemon_{name} {help}");
+            let _ = writeln!(text, "# TYPE theta_surface_daemon_{name} {metric_type}");
+
+            for (labels, value) in samples {
+                let _ = writeln!(text, "theta_surface_daemon_{name}{labels} {value}");
+            }
+        };
+        let step_samples = |value: &dyn Fn(&StepMetrics) -> f64| -> Vec<(String, f64)> {
+            self.steps
+                .iter()
+                .map(|(name, step)| (format!("{{step=\"{name}\"}}"), value(step)))
+                .collect()
+        };
+
+        write_metric("runs_total", "counter", "Runs finished.", vec![(String::new(), self.runs as f64)]);
+        write_metric(
+            "failed_runs_total",
+            "counter",
+            "Runs stopped by a step failing.",
+            vec![(String::new(), self.failed_runs as f64)],
+        );
+        write_metric(
+            "api_failures_total",
+            "counter",
+            "Steps that failed because the exchange's API returned an error.",
+            vec![(String::new(), self.api_failures as f64)],
+        );
+        write_metric(
+            "step_failures_total",
+            "counter",
+            "Times each step has failed.",
+            step_samples(&|x| x.failures as f64),
+        );
+        write_metric(
+            "step_succeeded",
+            "gauge",
+            "Whether each step succeeded the last time it ran.",
+            step_samples(&|x| x.succeeded as u8 as f64),
+        );
+        write_metric(
+            "step_duration_seconds",
+            "gauge",
+            "How long each step took the last time it ran.",
+            step_samples(&|x| x.duration_seconds),
+        );
+        write_metric(
+            "step_count",
+            "gauge",
+            "How many of each thing each step worked with the last time it ran, e.g. smiles fitted and discarded.",
+            self.steps
+                .iter()
+                .flat_map(|(name, step)| {
+                    step.counts
+                        .iter()
+                        .map(move |(count, value)| (format!("{{step=\"{name}\",count=\"{count}\"}}"), *value as f64))
+                })
+                .collect(),
+        );
+        write_metric(
+            "step_measurement",
+            "gauge",
+            "How well each step did the last time it ran, e.g. the surface's average fit error.",
+            self.steps
+                .iter()
+                .flat_map(|(name, step)| {
+                    step.measurements
+                        .iter()
+                        .map(move |(measurement, value)| (format!("{{step=\"{name}\",measurement=\"{measurement}\"}}"), *value))
+                })
+                .collect(),
+        );
+
+        if let Some(time) = self.last_successful_surface_time {
+            write_metric(
+                "last_successful_surface_timestamp_seconds",
+                "gauge",
+                "When build-surface last succeeded, in seconds since the Unix epoch.",
+                vec![(String::new(), time.timestamp() as f64)],
+            );
+        }
+
+        text
+    }
+}
+
+/// Run the configured steps (by default fetch-market-data, build-surface and build-graphs) on the configured schedule,
+/// forever. Each step runs as a separate process, so that it can be stopped if it takes too long and a failure doesn't
+/// stop the daemon. Progress is saved after every step, so a daemon that is restarted finishes any interrupted run, and
+/// makes up for a missed run straight away.
+///
+/// It only returns if it fails, e.g. because its state can't be saved. The run summary counts the runs it has done and how
+/// many of them failed.
+pub fn daemon(args: &[String], config: &Config, run_summary: &mut RunSummary) -> Result<(), TsError> {
+    let daemon_options = DaemonOptions::from_args(args).map_err(|e| e.context("Invalid arguments"))?;
+    let settings = &config.daemon;
+    let schedule = settings
+        .get_schedule()
+        .map_err(|e| e.context("Invalid schedule"))?;
+
+    println!("===============================================================");
+    println!("===============================================================");
+    println!("Running the daemon on the schedule {}", settings.schedule);
+    println!("===============================================================");
+    println!("===============================================================");
+
+    let mut state = DaemonState::load();
+
+    if let Some(run) = &state.current_run {
+        println!(
+            "Resuming the run for {}, which was interrupted after {} of {} steps...",
+            helpers::format_time(run.scheduled_time),
+            run.completed_steps.len(),
+            settings.steps.len()
+        );
+        run_daemon_run(&mut state, settings, &daemon_options, run_summary)?;
+        println!("------------------------------");
+    }
+
+    let mut run_now = daemon_options.run_now;
+
+    loop {
+        // Not helpers::get_now(), as that stays the same for the whole of a run, and the daemon runs indefinitely.
+        let now = Utc::now();
+        let scheduled_time = match run_now {
+            true => now,
+            false => get_next_run_time(&schedule, state.last_run.as_ref(), now)
+                .ok_or(TsError::new(RuntimeError, format!("The schedule {} never runs", settings.schedule)))?,
+        };
+        run_now = false;
+
+        if scheduled_time > now {
+            println!("Waiting until {} for the next run...", helpers::format_time(scheduled_time));
+            thread::sleep((scheduled_time - now).to_std().unwrap_or_default());
+        } else if scheduled_time < now {
+            println!("Missed the run scheduled for {}, running it now...", helpers::format_time(scheduled_time));
+        }
+
+        println!("------------------------------");
+        println!("Starting the run for {}...", helpers::format_time(scheduled_time));
+
+        state.current_run = Some(DaemonRun {
+            scheduled_time,
+            started_time: Utc::now(),
+            finished_time: None,
+            completed_steps: Vec::new(),
+            error: None,
+        });
+        run_daemon_run(&mut state, settings, &daemon_options, run_summary)?;
+        println!("------------------------------");
+    }
+}
+
+/// Finish the current run, counting it in the run summary.
+fn run_daemon_run(
+    state: &mut DaemonState,
+    settings: &DaemonSettings,
+    daemon_options: &DaemonOptions,
+    run_summary: &mut RunSummary,
+) -> Result<(), TsError> {
+    state
+        .run(settings, daemon_options)
+        .map_err(|e| e.context("Failed saving daemon state"))?;
+
+    let failed = state.last_run.as_ref().is_some_and(|x| x.error.is_some());
+    run_summary.add_to_count("runs", 1);
+    run_summary.add_to_count("failed_runs", failed as usize);
+
+    Ok(())
+}
+
+/// The time of the next run. If any scheduled times were missed since the last run, this is the latest of them (earlier
+/// ones are skipped, as running them would only repeat the same work).
+fn get_next_run_time(schedule: &CronSchedule, last_run: Option<&DaemonRun>, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let Some(last_run) = last_run else {
+        return schedule.next_after(now);
+    };
+
+    let mut next_time = schedule.next_after(last_run.scheduled_time)?;
+
+    while let Some(time) = schedule.next_after(next_time)
+        && time <= now
+    {
+        next_time = time;
+    }
+
+    Some(next_time)
+}
+
+/// Run a step's command as a child process of this program, stopping it if it runs for longer than its timeout.
+fn run_step(step: &DaemonStep, daemon_options: &DaemonOptions) -> Result<(), TsError> {
+    let name = step.command.name();
+    let timeout = Duration::from_secs_f64(step.get_timeout_minutes() * 60.0);
+
+    println!("Running {name}...");
+
+    let executable = std::env::current_exe()
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed finding this program: {e}")).with_source(e))?;
+    let mut command = Command::new(executable);
+    command.arg(name).args(&step.args);
+
+    if let Some(config_path) = &daemon_options.config_path {
+        command.args(["--config", config_path]);
+    }
+
+    if let Some(data_directory) = &daemon_options.data_directory {
+        command.args(["--data-dir", data_directory]);
+    }
+
+    let started = Instant::now();
+    let mut child = command
+        .spawn()
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed starting {name}: {e}")).with_source(e))?;
+
+    loop {
+        let status = child
+            .try_wait()
+            .map_err(|e| TsError::new(RuntimeError, format!("Failed checking on {name}: {e}")).with_source(e))?;
+
+        match status {
+            Some(status) if status.success() => {
+                println!("{name} finished in {} seconds", started.elapsed().as_secs());
+                return Ok(());
+            }
+            Some(status) => return Err(TsError::new(RuntimeError, format!("{name} failed ({status})"))),
+            None if started.elapsed() >= timeout => {
+                // It may have finished since, but either way it's gone once it's been waited on.
+                let _ = child.kill();
+                let _ = child.wait();
+
+                return Err(TsError::new(
+                    RuntimeError,
+                    format!("{name} was stopped as it didn't finish within {} minutes", step.get_timeout_minutes()),
+                ));
+            }
+            None => thread::sleep(STEP_POLL_INTERVAL),
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::analytics::{SliceScore, SurfaceScore};
+use crate::config::Config;
+use crate::fileio::{DataScope, Report, ReportSection};
+use crate::helpers::F64Helpers;
+use crate::routines::RunSummary;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+use crate::{fileio, helpers};
+
+const SCORE_HISTORY_FILE_NAME: &str = "surface-score-history.ndjson";
+const MARKDOWN_DIGEST_FILE_NAME: &str = "digest.md";
+const HTML_DIGEST_FILE_NAME: &str = "digest.html";
+
+/// The default number of days covered by the digest.
+const DEFAULT_DAYS: i64 = 7;
+
+/// How many of the worst slices and biggest moves to list.
+const TOP_COUNT: usize = 5;
+
+/// A slice fit with an implied volatility RMSE above this counts as an alert. 0.05 = 5 volatility points.
+const FIT_RMSE_ALERT: f64 = 0.05;
+
+/// A run using market data older than this (seconds) counts as an alert.
+const DATA_AGE_ALERT_SECONDS: f64 = 3600.0;
+
+/// Reads one number from a run's score.
+type ScoreMetric = fn(&SurfaceScore) -> f64;
+
+/// Options that change how the digest is made, set via command line flags.
+struct DigestOptions {
+    days: i64,
+    markdown: bool,
+    html: bool,
+}
+
+impl DigestOptions {
+    fn from_args(args: &[String]) -> Result<Self, TsError> {
+        let days = match helpers::get_flag_value(args, "--days")? {
+            None => DEFAULT_DAYS,
+            Some(v) => v
+                .parse::<i64>()
+                .ok()
+                .filter(|x| *x > 0)
+                .ok_or(TsError::new(RuntimeError, format!("--days must be a whole number > 0, found {v}")))?,
+        };
+
+        let (markdown, html) = match helpers::get_flag_value(args, "--format")? {
+            None => (true, true),
+            Some("markdown") => (true, false),
+            Some("html") => (false, true),
+            Some(other) => return Err(TsError::new(RuntimeError, format!("Unknown digest format {other}"))),
+        };
+
+        Ok(Self { days, markdown, html })
+    }
+}
+
+pub fn digest(args: &[String], config: &Config, run: &mut RunSummary) -> Result<(), TsError> {
+    println!("===============================================================");
+    println!("===============================================================");
+    println!("Summarising recent runs");
+    println!("===============================================================");
+    println!("===============================================================");
+
+    let digest_options = DigestOptions::from_args(args).map_err(|e| e.context("Invalid arguments"))?;
+
+    let scores = run.step("Loading surface score history", || load_recent_scores(digest_options.days))?;
+    println!("------------------------------");
+
+    let mut report = build_digest(&scores, digest_options.days);
+    report.header = config.report_header.render();
+
+    if digest_options.markdown {
+        let path = fileio::get_data_path(DataScope::Market, MARKDOWN_DIGEST_FILE_NAME);
+        println!("Saving {path}...");
+        run.step("Saving Markdown digest", || fileio::save_report_to_markdown(&report, &path))?;
+    }
+
+    if digest_options.html {
+        let path = fileio::get_data_path(DataScope::Market, HTML_DIGEST_FILE_NAME);
+        println!("Saving {path}...");
+        run.step("Saving HTML digest", || fileio::save_report_to_html(&report, &path))?;
+    }
+
+    println!("Done!");
+    println!("===============================================================");
+
+    Ok(())
+}
+
+/// Load the scores of every run in the last given number of days, ordered by time.
+fn load_recent_scores(days: i64) -> Result<Vec<SurfaceScore>, TsError> {
+    let score_history_path = fileio::get_data_path(DataScope::Market, SCORE_HISTORY_FILE_NAME);
+    println!("Reading {score_history_path}...");
+
+    let since = helpers::get_now() - Duration::days(days);
+    let mut scores = Vec::new();
+
+    for score in fileio::read_ndjson_file::<SurfaceScore>(&score_history_path)? {
+        let score = score?;
+
+        if score.calculated_at >= since {
+            scores.push(score);
+        }
+    }
+
+    if scores.is_empty() {
+        return Err(TsError::new(RuntimeError, format!("No runs were recorded in the last {days} days")));
+    }
+
+    scores.sort_by_key(|x| x.calculated_at);
+
+    println!("Found {} runs in the last {days} days", scores.len());
+
+    Ok(scores)
+}
+
+fn build_digest(scores: &[SurfaceScore], days: i64) -> Report {
+    let mut report = Report::new(format!("Surface digest for the {days} days to {}", helpers::format_time(helpers::get_now())));
+
+    report.sections.push(build_summary_section(scores));
+    report.sections.push(build_worst_slices_section(scores));
+    report.sections.push(build_biggest_moves_section(scores));
+    report.sections.push(build_alerts_section(scores));
+
+    report
+}
+
+/// The average of each score component, and how it changed between the first and second half of the period.
+fn build_summary_section(scores: &[SurfaceScore]) -> ReportSection {
+    let (first_half, second_half) = scores.split_at(scores.len() / 2);
+    let metrics: [(&str, ScoreMetric); 6] = [
+        ("Score", |x| x.score),
+        ("Fit", |x| x.fit),
+        ("Coverage", |x| x.coverage),
+        ("Arbitrage", |x| x.arbitrage),
+        ("Freshness", |x| x.freshness),
+        ("Data age (minutes)", |x| x.data_age_seconds / 60.0),
+    ];
+
+    let rows = metrics
+        .iter()
+        .map(|(name, metric)| {
+            let average = |scores: &[SurfaceScore]| scores.iter().map(metric).sum::<f64>() / scores.len() as f64;
+            let (first_average, second_average) = match first_half.is_empty() {
+                true => (average(second_half), average(second_half)),
+                false => (average(first_half), average(second_half)),
+            };
+
+            vec![
+                name.to_string(),
+                format_number(average(scores)),
+                format_number(first_average),
+                format_number(second_average),
+                format_number(second_average - first_average),
+            ]
+        })
+        .collect();
+
+    ReportSection {
+        heading: "Summary".into(),
+        paragraphs: vec![format!(
+            "{} runs from {} to {}. The trend compares the first half of the runs with the second half.",
+            scores.len(),
+            format_run_time(scores.first()),
+            format_run_time(scores.last()),
+        )],
+        table: Some((
+            ["Metric", "Average", "First half", "Second half", "Trend"]
+                .map(String::from)
+                .to_vec(),
+            rows,
+        )),
+    }
+}
+
+fn build_worst_slices_section(scores: &[SurfaceScore]) -> ReportSection {
+    let mut slices: Vec<(&SurfaceScore, &SliceScore)> = scores
+        .iter()
+        .flat_map(|score| score.slices.iter().map(move |slice| (score, slice)))
+        .collect();
+    slices.sort_by(|a, b| b.1.fit_rmse.total_cmp(&a.1.fit_rmse));
+
+    let rows = slices
+        .iter()
+        .take(TOP_COUNT)
+        .map(|(score, slice)| {
+            vec![
+                helpers::format_time(score.calculated_at),
+                helpers::format_time(slice.expiry),
+                format_number(slice.fit_rmse * 100.0),
+                format_number(slice.arbitrage_margin),
+            ]
+        })
+        .collect();
+
+    ReportSection {
+        heading: "Worst fit slices".into(),
+        paragraphs: vec![format!(
+            "The {TOP_COUNT} slices whose fits were furthest from the observed implied volatilities."
+        )],
+        table: Some((
+            ["Run", "Expiry", "Fit RMSE (vol points)", "Arbitrage margin"]
+                .map(String::from)
+                .to_vec(),
+            rows,
+        )),
+    }
+}
+
+/// The largest changes in at the money volatility of the same expiry between consecutive runs that fit it.
+fn build_biggest_moves_section(scores: &[SurfaceScore]) -> ReportSection {
+    let mut moves = Vec::new();
+    // The most recent run that fit each expiry, so that runs where it couldn't be fit are skipped over.
+    let mut last_seen: HashMap<DateTime<Utc>, (&SurfaceScore, &SliceScore)> = HashMap::new();
+
+    for score in scores {
+        for slice in &score.slices {
+            if let Some((previous_score, previous_slice)) = last_seen.insert(slice.expiry, (score, slice)) {
+                moves.push((previous_score, score, previous_slice, slice));
+            }
+        }
+    }
+
+    let get_change = |x: &(_, _, &SliceScore, &SliceScore)| x.3.atm_implied_volatility - x.2.atm_implied_volatility;
+    moves.sort_by(|a, b| get_change(b).abs().total_cmp(&get_change(a).abs()));
+
+    let rows = moves
+        .iter()
+        .take(TOP_COUNT)
+        .map(|x| {
+            vec![
+                helpers::format_time(x.0.calculated_at),
+                helpers::format_time(x.1.calculated_at),
+                helpers::format_time(x.3.expiry),
+                format_number(x.2.atm_implied_volatility),
+                format_number(x.3.atm_implied_volatility),
+                format_number(get_change(x) * 100.0),
+            ]
+        })
+        .collect();
+
+    ReportSection {
+        heading: "Biggest surface moves".into(),
+        paragraphs: vec![format!(
+            "The {TOP_COUNT} largest changes in at the money implied volatility of an expiry between consecutive runs."
+        )],
+        table: Some((
+            [
+                "From run",
+                "To run",
+                "Expiry",
+                "ATM vol before",
+                "ATM vol after",
+                "Change (vol points)",
+            ]
+            .map(String::from)
+            .to_vec(),
+            rows,
+        )),
+    }
+}
+
+fn build_alerts_section(scores: &[SurfaceScore]) -> ReportSection {
+    let slices = || scores.iter().flat_map(|x| &x.slices);
+    let alerts = [
+        (
+            "Runs where some smiles couldn't be fit",
+            scores
+                .iter()
+                .filter(|x| x.fitted_smiles_count < x.smiles_count)
+                .count(),
+        ),
+        (
+            "Runs using data more than an hour old",
+            scores
+                .iter()
+                .filter(|x| x.data_age_seconds > DATA_AGE_ALERT_SECONDS)
+                .count(),
+        ),
+        ("Slices with butterfly arbitrage", slices().filter(|x| x.arbitrage_margin < 0.0).count()),
+        (
+            "Slices with a fit RMSE over 5 volatility points",
+            slices().filter(|x| x.fit_rmse > FIT_RMSE_ALERT).count(),
+        ),
+    ];
+
+    ReportSection {
+        heading: "Alerts".into(),
+        paragraphs: Vec::new(),
+        table: Some((
+            ["Alert", "Count"].map(String::from).to_vec(),
+            alerts
+                .iter()
+                .map(|(name, count)| vec![name.to_string(), count.to_string()])
+                .collect(),
+        )),
+    }
+}
+
+fn format_number(value: f64) -> String {
+    value.round_to_decimal_places(4).to_string()
+}
+
+fn format_run_time(score: Option<&SurfaceScore>) -> String {
+    score
+        .map(|x| helpers::format_time(x.calculated_at))
+        .unwrap_or_default()
+}
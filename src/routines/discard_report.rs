@@ -0,0 +1,143 @@
+use chrono::{DateTime, Utc};
+
+use crate::analytics::SmileGraph;
+use crate::fileio::{self, DataScope};
+use crate::helpers;
+use crate::types::TsError;
+
+const DISCARD_REPORT_FILE_NAME: &str = "discard-report.json";
+
+/// Where in building the surface something was discarded.
+#[derive(Clone, Copy, PartialEq, Debug, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum DiscardStage {
+    /// The option's API data couldn't be turned into an option.
+    Conversion,
+    /// The option was quoted too long before the snapshot (see --max-quote-age).
+    Staleness,
+    /// No forward price could be found for the expiry.
+    ForwardPrice,
+    /// The option couldn't be added to its smile, or the smile had too few usable options.
+    SmileGraph,
+    /// The smile couldn't be fit.
+    Fit,
+}
+
+#[derive(serde::Serialize)]
+pub(super) struct DiscardedOption {
+    pub instrument_name: Box<str>,
+    pub stage: DiscardStage,
+    pub reason: String,
+}
+
+/// A whole expiry that was left out of the surface, along with all of its options.
+#[derive(serde::Serialize)]
+pub(super) struct DiscardedSmile {
+    pub expiry: DateTime<Utc>,
+    pub option_count: usize,
+    pub stage: DiscardStage,
+    pub reason: String,
+}
+
+/// Everything that was discarded while building a surface, and why, so that a surface built from nearly all of the data
+/// can be told apart from one built from half of it.
+#[derive(Default, serde::Serialize)]
+pub(super) struct DiscardReport {
+    /// The options in the downloaded data.
+    pub total_options: usize,
+    /// Options left out on purpose by the instrument filters or the expiry filter flags. They aren't a problem with the
+    /// data, so aren't counted as discarded.
+    pub filtered_options: usize,
+    pub discarded_options: Vec<DiscardedOption>,
+    /// The expiries that had at least one option once the options were converted.
+    pub total_smiles: usize,
+    pub discarded_smiles: Vec<DiscardedSmile>,
+}
+
+impl DiscardedSmile {
+    pub fn new(graph: &SmileGraph, stage: DiscardStage, reason: impl Into<String>) -> Result<DiscardedSmile, TsError> {
+        Ok(DiscardedSmile {
+            expiry: graph.get_expiration()?,
+            option_count: graph.options.len(),
+            stage,
+            reason: reason.into(),
+        })
+    }
+}
+
+impl DiscardReport {
+    pub fn discard_option(&mut self, instrument_name: &str, stage: DiscardStage, reason: impl Into<String>) {
+        self.discarded_options.push(DiscardedOption {
+            instrument_name: instrument_name.into(),
+            stage,
+            reason: reason.into(),
+        });
+    }
+
+    /// Record a smile as discarded. Expiries are in milliseconds, like the groups made by group_options_by_expiry().
+    pub fn discard_smile(&mut self, expiry_millis: i64, option_count: usize, stage: DiscardStage, reason: impl Into<String>) {
+        self.discarded_smiles.push(DiscardedSmile {
+            expiry: DateTime::from_timestamp_millis(expiry_millis).expect("Expiry should have come from a valid time"),
+            option_count,
+            stage,
+            reason: reason.into(),
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.discarded_options.is_empty() && self.discarded_smiles.is_empty()
+    }
+
+    /// A one line description of how much was discarded, e.g. "3/250 options and 1/12 smiles".
+    pub fn describe(&self) -> String {
+        format!(
+            "{}/{} options and {}/{} smiles",
+            self.discarded_options.len(),
+            self.total_options - self.filtered_options,
+            self.discarded_smiles.len(),
+            self.total_smiles
+        )
+    }
+
+    pub fn print_summary(&self) {
+        println!("Discarded {}", self.describe());
+
+        for stage in [
+            DiscardStage::Conversion,
+            DiscardStage::Staleness,
+            DiscardStage::ForwardPrice,
+            DiscardStage::SmileGraph,
+            DiscardStage::Fit,
+        ] {
+            let options = self
+                .discarded_options
+                .iter()
+                .filter(|x| x.stage == stage)
+                .count();
+            let smiles = self
+                .discarded_smiles
+                .iter()
+                .filter(|x| x.stage == stage)
+                .collect::<Vec<&DiscardedSmile>>();
+
+            if options == 0 && smiles.is_empty() {
+                continue;
+            }
+
+            println!("{stage:?}: {options} options and {} smiles", smiles.len());
+
+            for smile in smiles {
+                println!("    {} ({} options): {}", helpers::format_time(smile.expiry), smile.option_count, smile.reason);
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<(), TsError> {
+        let path = fileio::get_data_path(DataScope::Snapshot, DISCARD_REPORT_FILE_NAME);
+        fileio::save_struct_to_file(self, &path)?;
+
+        println!("Saved the discard report to {path}");
+
+        Ok(())
+    }
+}
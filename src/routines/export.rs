@@ -0,0 +1,156 @@
+use crate::analytics::SmileGraphsDataContainer;
+use crate::config::Config;
+use crate::fileio::{DataScope, Table, TableColumn};
+use crate::routines::RunSummary;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+use crate::{fileio, helpers};
+
+const CSV_EXPORT_FILE_NAME: &str = "surface-export.csv";
+const PARQUET_EXPORT_FILE_NAME: &str = "surface-export.parquet";
+
+/// The default number of strikes exported per expiry.
+const DEFAULT_STRIKE_POINTS: u64 = 100;
+
+/// Options that change how the surface is exported, set via command line flags.
+struct ExportOptions {
+    csv: bool,
+    parquet: bool,
+    /// The number of evenly spaced strikes to export for each expiry, covering the observed strike range.
+    strike_points: u64,
+}
+
+impl ExportOptions {
+    fn from_args(args: &[String]) -> Result<Self, TsError> {
+        let (csv, parquet) = match helpers::get_flag_value(args, "--format")? {
+            None => (true, true),
+            Some("csv") => (true, false),
+            Some("parquet") => (false, true),
+            Some(other) => return Err(TsError::new(RuntimeError, format!("Unknown export format {other}"))),
+        };
+
+        let strike_points = match helpers::get_flag_value(args, "--strike-points")? {
+            None => DEFAULT_STRIKE_POINTS,
+            Some(v) => v
+                .parse::<u64>()
+                .ok()
+                .filter(|x| *x >= 2)
+                .ok_or(TsError::new(RuntimeError, format!("--strike-points must be a whole number >= 2, found {v}")))?,
+        };
+
+        Ok(Self {
+            csv,
+            parquet,
+            strike_points,
+        })
+    }
+}
+
+pub fn export(args: &[String], config: &Config, run: &mut RunSummary) -> Result<(), TsError> {
+    println!("===============================================================");
+    println!("===============================================================");
+    println!("Exporting fitted surface to file");
+    println!("===============================================================");
+    println!("===============================================================");
+
+    let export_options = ExportOptions::from_args(args).map_err(|e| e.context("Invalid arguments"))?;
+
+    println!("Loading surface data...");
+    let mut data = run.step("Loading surface data", || {
+        fileio::load_versioned_struct_from_file::<SmileGraphsDataContainer>(&fileio::find_data_file(&fileio::get_data_path(
+            DataScope::Snapshot,
+            "smile-graph-data.json",
+        )))
+    })?;
+
+    for graph in &mut data.smile_graphs {
+        graph.wing_extrapolation = config.wing_extrapolation;
+    }
+    run.count("smiles", data.smile_graphs.len());
+    println!("------------------------------");
+
+    let table = run.step("Building surface table", || build_surface_table(&data, export_options.strike_points))?;
+    println!("------------------------------");
+
+    if export_options.csv {
+        let path = fileio::get_data_path(DataScope::Snapshot, CSV_EXPORT_FILE_NAME);
+        println!("Saving {path}...");
+        run.step("Exporting CSV", || fileio::save_table_to_csv(&table, &path))?;
+    }
+
+    if export_options.parquet {
+        let path = fileio::get_data_path(DataScope::Snapshot, PARQUET_EXPORT_FILE_NAME);
+        println!("Saving {path}...");
+        run.step("Exporting Parquet", || fileio::save_table_to_parquet(&table, &path))?;
+    }
+
+    println!("Done!");
+    println!("===============================================================");
+
+    Ok(())
+}
+
+/// Evaluate each fitted smile on a grid of strikes, producing one row per expiry and strike. The SVI parameters are
+/// repeated on every row so that each row can be used on its own.
+fn build_surface_table(data: &SmileGraphsDataContainer, strike_points: u64) -> Result<Table, TsError> {
+    println!("Evaluating {} smiles at {strike_points} strikes each...", data.smile_graphs.len());
+
+    let mut expiries = Vec::new();
+    let mut years_until_expiry = Vec::new();
+    let mut forward_prices = Vec::new();
+    let mut strikes = Vec::new();
+    let mut log_moneynesses = Vec::new();
+    let mut implied_volatilities = Vec::new();
+    let mut total_variances = Vec::new();
+    let mut svi_params: [Vec<f64>; 5] = Default::default();
+
+    for graph in &data.smile_graphs {
+        let expiry = graph.get_expiration()?.timestamp_millis();
+        let years = graph.get_years_until_expiry()?;
+        let forward_price = graph.get_underlying_forward_price()?;
+        let params = &graph.svi_curve_parameters;
+        let strike_range = graph.highest_observed_strike - graph.lowest_observed_strike;
+
+        for i in 0..strike_points {
+            let strike = graph.lowest_observed_strike + strike_range * (i as f64 / (strike_points - 1) as f64);
+            let log_moneyness = (strike / forward_price).ln();
+
+            expiries.push(expiry);
+            years_until_expiry.push(years);
+            forward_prices.push(forward_price);
+            strikes.push(strike);
+            log_moneynesses.push(log_moneyness);
+            implied_volatilities.push(graph.get_implied_volatility_at_strike(strike)?);
+            total_variances.push(graph.get_total_variance_at_log_moneyness(log_moneyness)?);
+
+            for (column, value) in
+                svi_params
+                    .iter_mut()
+                    .zip([params.get_a(), params.get_b(), params.get_p(), params.get_m(), params.get_o()])
+            {
+                column.push(value);
+            }
+        }
+    }
+
+    let rows_count = strikes.len();
+    let [a, b, p, m, o] = svi_params;
+    let mut table = Table::default();
+
+    table.add_column("expiry", TableColumn::Timestamp(expiries))?;
+    table.add_column("years_until_expiry", TableColumn::Float(years_until_expiry))?;
+    table.add_column("forward_price", TableColumn::Float(forward_prices))?;
+    table.add_column("strike", TableColumn::Float(strikes))?;
+    table.add_column("log_moneyness", TableColumn::Float(log_moneynesses))?;
+    table.add_column("implied_volatility", TableColumn::Float(implied_volatilities))?;
+    table.add_column("total_variance", TableColumn::Float(total_variances))?;
+    table.add_column("svi_a", TableColumn::Float(a))?;
+    table.add_column("svi_b", TableColumn::Float(b))?;
+    table.add_column("svi_rho", TableColumn::Float(p))?;
+    table.add_column("svi_m", TableColumn::Float(m))?;
+    table.add_column("svi_sigma", TableColumn::Float(o))?;
+
+    println!("Built a table with {rows_count} rows");
+
+    Ok(table)
+}
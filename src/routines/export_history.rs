@@ -0,0 +1,289 @@
+use chrono::{DateTime, Utc};
+use serde::de::IgnoredAny;
+
+use crate::analytics::SurfaceScore;
+use crate::fileio::{DataScope, Table, TableColumn};
+use crate::helpers::ProgressBar;
+use crate::routines::RunSummary;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+use crate::{fileio, helpers};
+
+/// Relative to the market's data directory, since the history covers every snapshot.
+const SCORE_HISTORY_FILE_NAME: &str = "surface-score-history.ndjson";
+const EXPORT_DIRECTORY_NAME: &str = "history-export/";
+const CURSOR_FILE_NAME: &str = "history-export/cursor.json";
+const CURSOR_SWAP_FILE_NAME: &str = "history-export/cursor.json.swap";
+
+/// The default number of runs written to each chunk.
+const DEFAULT_CHUNK_SIZE: u64 = 1000;
+
+/// Options that change how the history is exported, set via command line flags.
+struct ExportHistoryOptions {
+    csv: bool,
+    parquet: bool,
+    chunk_size: u64,
+    resume: bool,
+    quiet: bool,
+}
+
+impl ExportHistoryOptions {
+    fn from_args(args: &[String]) -> Result<Self, TsError> {
+        let (csv, parquet) = match helpers::get_flag_value(args, "--format")? {
+            None => (true, true),
+            Some("csv") => (true, false),
+            Some("parquet") => (false, true),
+            Some(other) => return Err(TsError::new(RuntimeError, format!("Unknown export format {other}"))),
+        };
+
+        let chunk_size = match helpers::get_flag_value(args, "--chunk-size")? {
+            None => DEFAULT_CHUNK_SIZE,
+            Some(v) => v
+                .parse::<u64>()
+                .ok()
+                .filter(|x| *x > 0)
+                .ok_or(TsError::new(RuntimeError, format!("--chunk-size must be a whole number > 0, found {v}")))?,
+        };
+
+        Ok(Self {
+            csv,
+            parquet,
+            chunk_size,
+            resume: helpers::has_flag(args, "--resume"),
+            quiet: helpers::has_flag(args, "--quiet"),
+        })
+    }
+}
+
+/// How far an export has got, saved after every chunk so that an interrupted export can carry on where it stopped. The
+/// format and chunk size are kept so that a resumed export writes the same kind of files.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct HistoryExportCursor {
+    csv: bool,
+    parquet: bool,
+    chunk_size: u64,
+    /// The number of runs from the start of the history file that have been exported.
+    runs_exported: u64,
+    chunks_written: u64,
+    /// When the last exported run was recorded, used to check that the history file hasn't been replaced since.
+    last_calculated_at: Option<DateTime<Utc>>,
+}
+
+pub fn export_history(args: &[String], run: &mut RunSummary) -> Result<(), TsError> {
+    println!("===============================================================");
+    println!("===============================================================");
+    println!("Exporting surface score history to file");
+    println!("===============================================================");
+    println!("===============================================================");
+
+    let options = ExportHistoryOptions::from_args(args).map_err(|e| e.context("Invalid arguments"))?;
+
+    let mut cursor = run.step("Preparing export", || load_cursor(&options))?;
+    println!("------------------------------");
+
+    let total_runs = run.step("Reading surface score history", count_runs)?;
+    println!("Found {total_runs} runs, of which {} have already been exported", cursor.runs_exported);
+    println!("------------------------------");
+
+    run.step("Exporting surface score history", || export_runs(&mut cursor, total_runs, options.quiet))?;
+    run.count("exported_runs", cursor.runs_exported as usize);
+    println!("------------------------------");
+
+    println!(
+        "Exported {} runs in {} chunks to {}",
+        cursor.runs_exported,
+        cursor.chunks_written,
+        get_path(EXPORT_DIRECTORY_NAME)
+    );
+    println!("Done!");
+    println!("===============================================================");
+
+    Ok(())
+}
+
+fn get_path(name: &str) -> String {
+    fileio::get_data_path(DataScope::Market, name)
+}
+
+/// Load the cursor of the previous export if resuming, otherwise clear out any previous export and start a new one.
+fn load_cursor(options: &ExportHistoryOptions) -> Result<HistoryExportCursor, TsError> {
+    let cursor_path = get_path(CURSOR_FILE_NAME);
+    fileio::create_directory(&get_path(EXPORT_DIRECTORY_NAME))?;
+
+    if options.resume && fileio::file_exists(&cursor_path) {
+        let cursor = fileio::load_struct_from_file::<HistoryExportCursor>(&cursor_path)?;
+
+        println!(
+            "Resuming the previous export after {} runs, using its chunk size ({}) and formats...",
+            cursor.runs_exported, cursor.chunk_size
+        );
+
+        return Ok(cursor);
+    }
+
+    if options.resume {
+        println!("No previous export was found, starting a new one...");
+    } else {
+        println!("Deleting any previous export...");
+        fileio::clear_directory(&get_path(EXPORT_DIRECTORY_NAME), "gitkeep")?;
+    }
+
+    Ok(HistoryExportCursor {
+        csv: options.csv,
+        parquet: options.parquet,
+        chunk_size: options.chunk_size,
+        runs_exported: 0,
+        chunks_written: 0,
+        last_calculated_at: None,
+    })
+}
+
+/// Count the runs in the history file without fully reading them.
+fn count_runs() -> Result<u64, TsError> {
+    let mut count = 0;
+
+    for run in fileio::read_ndjson_file::<IgnoredAny>(&get_path(SCORE_HISTORY_FILE_NAME))? {
+        run?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Read the history one run at a time, skipping the runs already exported, and write the rest in chunks. The cursor is
+/// saved after each chunk, so at most one chunk is redone if the export is interrupted.
+fn export_runs(cursor: &mut HistoryExportCursor, total_runs: u64, quiet: bool) -> Result<(), TsError> {
+    if cursor.runs_exported > total_runs {
+        return Err(TsError::new(
+            RuntimeError,
+            format!(
+                "The previous export covered {} runs but the history only has {total_runs}, so it has been replaced since",
+                cursor.runs_exported
+            ),
+        ));
+    }
+
+    let mut progress = ProgressBar::new("Exporting runs", total_runs, quiet);
+    let mut chunk = Vec::new();
+
+    for (index, run) in fileio::read_ndjson_file::<SurfaceScore>(&get_path(SCORE_HISTORY_FILE_NAME))?.enumerate() {
+        let run = run?;
+
+        if (index as u64) < cursor.runs_exported {
+            if index as u64 == cursor.runs_exported - 1 && cursor.last_calculated_at != Some(run.calculated_at) {
+                return Err(TsError::new(
+                    RuntimeError,
+                    "The history doesn't match the previous export, so it has been replaced since",
+                ));
+            }
+
+            progress.skip();
+            continue;
+        }
+
+        chunk.push(run);
+        progress.increment();
+
+        if chunk.len() as u64 >= cursor.chunk_size {
+            write_chunk(cursor, &chunk, &progress)?;
+            chunk.clear();
+        }
+    }
+
+    if !chunk.is_empty() {
+        write_chunk(cursor, &chunk, &progress)?;
+    }
+
+    progress.finish();
+
+    Ok(())
+}
+
+/// Write one chunk of runs to its own files, then move the cursor past it.
+fn write_chunk(cursor: &mut HistoryExportCursor, runs: &[SurfaceScore], progress: &ProgressBar) -> Result<(), TsError> {
+    let chunk_number = cursor.chunks_written + 1;
+    let tables = [("runs", build_runs_table(runs)?), ("slices", build_slices_table(runs)?)];
+
+    for (name, table) in &tables {
+        let path = get_path(&format!("{EXPORT_DIRECTORY_NAME}{name}-{chunk_number:05}"));
+
+        if cursor.csv {
+            fileio::save_table_to_csv(table, &format!("{path}.csv"))?;
+        }
+
+        if cursor.parquet {
+            fileio::save_table_to_parquet(table, &format!("{path}.parquet"))?;
+        }
+    }
+
+    cursor.runs_exported += runs.len() as u64;
+    cursor.chunks_written = chunk_number;
+    cursor.last_calculated_at = runs.last().map(|x| x.calculated_at);
+
+    // Write the cursor to a separate file first, so that a half-written cursor is never left behind.
+    let swap_path = get_path(CURSOR_SWAP_FILE_NAME);
+    fileio::save_struct_to_file(cursor, &swap_path)?;
+    std::fs::rename(&swap_path, get_path(CURSOR_FILE_NAME))
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed replacing cursor file: {e}")).with_source(e))?;
+
+    progress.message(format!("Wrote chunk {chunk_number} ({} runs)", runs.len()));
+
+    Ok(())
+}
+
+/// One row per run, with its overall scores.
+fn build_runs_table(runs: &[SurfaceScore]) -> Result<Table, TsError> {
+    let float_column = |value: fn(&SurfaceScore) -> f64| TableColumn::Float(runs.iter().map(value).collect());
+    let mut table = Table::default();
+
+    table.add_column(
+        "calculated_at",
+        TableColumn::Timestamp(
+            runs.iter()
+                .map(|x| x.calculated_at.timestamp_millis())
+                .collect(),
+        ),
+    )?;
+    table.add_column("score", float_column(|x| x.score))?;
+    table.add_column("fit", float_column(|x| x.fit))?;
+    table.add_column("coverage", float_column(|x| x.coverage))?;
+    table.add_column("arbitrage", float_column(|x| x.arbitrage))?;
+    table.add_column("freshness", float_column(|x| x.freshness))?;
+    table.add_column("smiles_count", float_column(|x| x.smiles_count as f64))?;
+    table.add_column("fitted_smiles_count", float_column(|x| x.fitted_smiles_count as f64))?;
+    table.add_column("data_age_seconds", float_column(|x| x.data_age_seconds))?;
+
+    Ok(table)
+}
+
+/// One row per fitted smile in each run. Runs saved by older versions have no slices, so add no rows.
+fn build_slices_table(runs: &[SurfaceScore]) -> Result<Table, TsError> {
+    let mut calculated_at = Vec::new();
+    let mut expiries = Vec::new();
+    let mut years_until_expiry = Vec::new();
+    let mut fit_rmses = Vec::new();
+    let mut arbitrage_margins = Vec::new();
+    let mut atm_implied_volatilities = Vec::new();
+
+    for run in runs {
+        for slice in &run.slices {
+            calculated_at.push(run.calculated_at.timestamp_millis());
+            expiries.push(slice.expiry.timestamp_millis());
+            years_until_expiry.push(slice.years_until_expiry);
+            fit_rmses.push(slice.fit_rmse);
+            arbitrage_margins.push(slice.arbitrage_margin);
+            atm_implied_volatilities.push(slice.atm_implied_volatility);
+        }
+    }
+
+    let mut table = Table::default();
+
+    table.add_column("calculated_at", TableColumn::Timestamp(calculated_at))?;
+    table.add_column("expiry", TableColumn::Timestamp(expiries))?;
+    table.add_column("years_until_expiry", TableColumn::Float(years_until_expiry))?;
+    table.add_column("fit_rmse", TableColumn::Float(fit_rmses))?;
+    table.add_column("arbitrage_margin", TableColumn::Float(arbitrage_margins))?;
+    table.add_column("atm_implied_volatility", TableColumn::Float(atm_implied_volatilities))?;
+
+    Ok(table)
+}
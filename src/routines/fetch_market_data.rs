@@ -1,93 +1,514 @@
-use crate::fileio;
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+
+use crate::fileio::{DataScope, NdjsonWriter, SchemaVersioned};
+use crate::helpers::{CancellationToken, F64Helpers, ProgressBar};
 use crate::integrations::DeribitDataContainer;
+use crate::integrations::DeribitFuture;
+use crate::integrations::DeribitFuturesTickerData;
 use crate::integrations::DeribitOptionInstrument;
+use crate::integrations::DeribitPerpetualTickerData;
 use crate::integrations::DeribitTickerData;
-use crate::network;
+use crate::integrations::{ReanchorCounts, SnapshotAnchor};
+use crate::routines::RunSummary;
+use crate::routines::quote_log::QuoteLogWriter;
 use crate::types::TsError;
 use crate::types::TsErrorType::RuntimeError;
+use crate::{constants, fileio, helpers, integrations, network};
+
+/// The names of the files, which are prefixed with the exchange and currency (see fileio::get_market_data_path()).
+const MARKET_DATA_FILE_NAME: &str = "market-data.json";
+const NDJSON_MARKET_DATA_FILE_NAME: &str = "market-data.ndjson";
+const PERPETUAL_DATA_FILE_NAME: &str = "perpetual.json";
+
+/// The checkpoint is kept with the market's data rather than the snapshot's, so that a download interrupted just before
+/// midnight can still be resumed just after.
+const CHECKPOINT_FILE_NAME: &str = "market-data.partial.ndjson";
+const CHECKPOINT_SWAP_FILE_NAME: &str = "market-data.partial.ndjson.swap";
+
+/// Options that change how market data is fetched, set via command line flags.
+struct FetchMarketDataOptions {
+    /// Save the data as newline-delimited JSON rather than one big JSON object.
+    ndjson: bool,
+    /// Don't print progress.
+    quiet: bool,
+    /// Save the data compressed with zstd.
+    compress: bool,
+    /// Never download anything, using the saved snapshot instead and failing if there isn't one.
+    offline: bool,
+    /// Reuse the saved snapshot rather than downloading a new one if its data is at most this many minutes old.
+    max_age_minutes: Option<u64>,
+    /// Append each quote to the quote log instead of saving a snapshot.
+    quote_log: bool,
+    /// An SQLite database to also save the quotes to.
+    #[cfg(feature = "storage-sqlite")]
+    db_path: Option<String>,
+}
+
+impl FetchMarketDataOptions {
+    fn from_args(args: &[String]) -> Result<Self, TsError> {
+        let options = Self {
+            ndjson: helpers::has_flag(args, "--ndjson"),
+            quiet: helpers::has_flag(args, "--quiet"),
+            compress: helpers::has_flag(args, "--compress"),
+            offline: helpers::has_flag(args, "--offline"),
+            max_age_minutes: match helpers::get_flag_value(args, "--max-age")? {
+                None => None,
+                Some(v) => Some(v.parse::<u64>().map_err(|e| {
+                    TsError::new(RuntimeError, format!("--max-age must be a whole number of minutes, found {v}")).with_source(e)
+                })?),
+            },
+            quote_log: helpers::has_flag(args, "--quote-log"),
+            #[cfg(feature = "storage-sqlite")]
+            db_path: helpers::get_flag_value(args, "--db")?.map(|x| x.to_string()),
+        };
+
+        if options.ndjson && options.compress {
+            return Err(TsError::new(RuntimeError, "--compress can't be used with --ndjson"));
+        }
+        #[cfg(feature = "storage-sqlite")]
+        if options.ndjson && options.db_path.is_some() {
+            return Err(TsError::new(RuntimeError, "--db can't be used with --ndjson"));
+        }
+        if options.quote_log && (options.ndjson || options.compress || options.offline || options.max_age_minutes.is_some()) {
+            return Err(TsError::new(
+                RuntimeError,
+                "--quote-log doesn't save a snapshot, so can't be used with --ndjson, --compress, --offline or --max-age",
+            ));
+        }
+        #[cfg(feature = "storage-sqlite")]
+        if options.quote_log && options.db_path.is_some() {
+            return Err(TsError::new(RuntimeError, "--db can't be used with --quote-log"));
+        }
+
+        Ok(options)
+    }
+}
+
+pub async fn fetch_market_data(args: &[String], run: &mut RunSummary, cancel: &CancellationToken) -> Result<(), TsError> {
+    let fetch_options = FetchMarketDataOptions::from_args(args).map_err(|e| e.context("Invalid arguments"))?;
 
-pub async fn fetch_market_data() {
     println!("===============================================================");
     println!("===============================================================");
-    println!("Fetching Bitcoin market data and saving to file");
+    println!("Fetching {} market data and saving to file", fileio::get_currency().get_name());
     println!("===============================================================");
     println!("===============================================================");
 
-    let mut options = download_options()
-        .await
-        .unwrap_or_else(|e| panic!("Failed downloading options: {}", e.reason));
+    if fetch_options.offline || fetch_options.max_age_minutes.is_some() {
+        let reused = run.step("Reusing saved snapshot", || reuse_saved_snapshot(&fetch_options))?;
+        println!("------------------------------");
+
+        if reused {
+            println!("Done!");
+            println!("===============================================================");
+            return Ok(());
+        }
+    }
+
+    let downloaded = run
+        .step_async("Downloading options", download_options(&fetch_options, cancel))
+        .await?;
+    run.count("options", downloaded.total);
+    run.count("downloaded_options", downloaded.downloaded);
+    run.count("resumed_options", downloaded.resumed);
     println!("------------------------------");
 
-    normalise_data(&mut options).unwrap_or_else(|e| panic!("Failed normalising API data: {}", e.reason));
+    // Every quote is in the quote log as soon as it's fetched, so there's nothing to resume or save.
+    if fetch_options.quote_log && !downloaded.is_finished() {
+        println!("Download cancelled, the quotes fetched so far are in the quote log");
+        println!("===============================================================");
+        return Ok(());
+    }
+
+    // What has been downloaded is already in the checkpoint, so the next run carries on from it.
+    if !downloaded.is_finished() {
+        println!("Download cancelled, run fetch-market-data again to carry on from where it stopped");
+        println!("===============================================================");
+        return Ok(());
+    }
+
+    run.step_async("Downloading perpetual", download_perpetual())
+        .await?;
+    println!("------------------------------");
+
+    if fetch_options.quote_log {
+        println!("Done!");
+        println!("===============================================================");
+        return Ok(());
+    }
+
+    // Newline-delimited snapshots only have room for options.
+    let futures = match fetch_options.ndjson {
+        true => Vec::new(),
+        false => {
+            let futures = run
+                .step_async("Downloading futures", download_futures())
+                .await?;
+            run.count("futures", futures.len());
+            println!("------------------------------");
+            futures
+        }
+    };
+
+    let saved_options = run.step("Normalising and saving data", || normalise_and_save_data(&fetch_options, futures))?;
+    run.count("saved_options", saved_options.total);
+    run.count("reanchored_options", saved_options.adjusted);
+    run.count("unanchored_options", saved_options.discarded);
     println!("------------------------------");
 
-    save_data(options).unwrap_or_else(|e| panic!("Failed saving API data to file: {}", e.reason));
+    run.step("Deleting download checkpoint", delete_checkpoint)?;
     println!("===============================================================");
+
+    Ok(())
+}
+
+/// Decide whether the saved snapshot can be used instead of downloading a new one, so that repeatedly running the pipeline
+/// doesn't download the same data again and again. Offline, not being able to use it is an error rather than a reason to
+/// download.
+fn reuse_saved_snapshot(fetch_options: &FetchMarketDataOptions) -> Result<bool, TsError> {
+    println!("Checking for a saved snapshot...");
+
+    let Some((path, snapshot_time)) = find_saved_snapshot(fetch_options.ndjson)? else {
+        if fetch_options.offline {
+            return Err(TsError::new(RuntimeError, "No saved snapshot was found, and --offline stops one being downloaded"));
+        }
+
+        println!("No saved snapshot found, downloading a new one");
+        return Ok(false);
+    };
+
+    let age_minutes = (helpers::get_now() - snapshot_time).num_seconds() as f64 / 60.0;
+    println!("Found {path}, captured {} minutes ago", age_minutes.round_to_decimal_places(1));
+
+    match fetch_options.max_age_minutes {
+        Some(max_age_minutes) if age_minutes > max_age_minutes as f64 => match fetch_options.offline {
+            true => Err(TsError::new(
+                RuntimeError,
+                format!(
+                    "The saved snapshot is older than --max-age {max_age_minutes}, and --offline stops a new one being downloaded"
+                ),
+            )),
+            false => {
+                println!("The saved snapshot is older than --max-age {max_age_minutes}, downloading a new one");
+                Ok(false)
+            }
+        },
+        _ => {
+            println!("Using the saved snapshot");
+            Ok(true)
+        }
+    }
+}
+
+/// The path of the saved snapshot in the format being fetched, and when its data was captured, if there is one.
+fn find_saved_snapshot(ndjson: bool) -> Result<Option<(String, DateTime<Utc>)>, TsError> {
+    let (path, snapshot_time) = match ndjson {
+        true => {
+            let path = fileio::get_market_data_path(DataScope::Snapshot, NDJSON_MARKET_DATA_FILE_NAME);
+
+            if !fileio::file_exists(&path) {
+                return Ok(None);
+            }
+
+            // Streamed, since saving as NDJSON is for option chains too big to load in one go.
+            let mut latest_timestamp = None;
+
+            for option in fileio::read_ndjson_file::<DeribitOptionInstrument>(&path)? {
+                if let Some(ticker_data) = option?.ticker_data {
+                    latest_timestamp = latest_timestamp.max(Some(ticker_data.timestamp));
+                }
+            }
+
+            (path, latest_timestamp.and_then(|x| DateTime::from_timestamp_millis(x as i64)))
+        }
+        false => {
+            let path = fileio::find_data_file(&fileio::get_market_data_path(DataScope::Snapshot, MARKET_DATA_FILE_NAME));
+
+            if !fileio::file_exists(&path) {
+                return Ok(None);
+            }
+
+            let data = fileio::load_versioned_struct_from_file::<DeribitDataContainer>(&path)?;
+            let snapshot_time = data
+                .snapshot_time
+                .or_else(|| integrations::get_snapshot_time(&data.options));
+
+            (path, snapshot_time)
+        }
+    };
+
+    let snapshot_time = snapshot_time
+        .ok_or(TsError::new(RuntimeError, format!("{path} has no ticker data, so when it was captured is unknown")))?;
+
+    Ok(Some((path, snapshot_time)))
 }
 
+/// Download every option along with its ticker data. Each option is written to the checkpoint file as soon as it is
+/// downloaded, so if the download is interrupted it can be resumed, or to the quote log with --quote-log. If cancelled, it
+/// stops before the next option.
+///
 /// Deribit rate limits seem quite strict, so there's not much we can do to make this faster...
-async fn download_options() -> Result<Vec<DeribitOptionInstrument>, TsError> {
+async fn download_options(
+    fetch_options: &FetchMarketDataOptions,
+    cancel: &CancellationToken,
+) -> Result<DownloadedOptions, TsError> {
     println!("Fetching options...");
-    let mut options = network::do_rpc_request_as_struct::<Vec<DeribitOptionInstrument>>(
-        "https://www.deribit.com/api/v2/public/get_instruments?currency=BTC&kind=option&expired=false",
-    )
+    let mut options = network::do_rpc_request_as_struct::<Vec<DeribitOptionInstrument>>(&format!(
+        "https://www.deribit.com/api/v2/public/get_instruments?currency={}&kind=option&expired=false",
+        fileio::get_currency().get_code()
+    ))
     .await
-    .map_err(|e| TsError::new(RuntimeError, format!("Failed downloading options: {:?}", e)))?;
+    .map_err(|e| e.context("Failed downloading options"))?;
+
+    // The API doesn't guarantee an order, so sort them to keep the output consistent between runs.
+    integrations::sort_options(&mut options);
+
+    let (already_downloaded, mut destination) = match fetch_options.quote_log {
+        true => (HashSet::new(), OptionDestination::QuoteLog(QuoteLogWriter::open()?)),
+        false => (
+            restore_checkpoint()?,
+            OptionDestination::Checkpoint(NdjsonWriter::append(&fileio::get_market_data_path(
+                DataScope::Market,
+                CHECKPOINT_FILE_NAME,
+            ))?),
+        ),
+    };
+    let mut progress = ProgressBar::new("Fetching ticker data", options.len() as u64, fetch_options.quiet);
 
-    let mut i: usize = 0;
+    let mut downloaded = DownloadedOptions {
+        total: options.len(),
+        downloaded: 0,
+        resumed: 0,
+    };
 
-    loop {
-        if i == options.len() {
-            break;
+    for mut option in options {
+        if cancel.is_cancelled() {
+            println!("Stopped after fetching ticker data for {}/{} options", downloaded.downloaded, downloaded.total);
+            return Ok(downloaded);
         }
+        if already_downloaded.contains(&option.instrument_name) {
+            downloaded.downloaded += 1;
+            downloaded.resumed += 1;
+            progress.skip();
+            continue;
+        }
+
+        let url = format!("https://www.deribit.com/api/v2/public/ticker?instrument_name={}", option.instrument_name);
+
+        let ticker_data = network::do_rpc_request_as_struct::<DeribitTickerData>(&url)
+            .await
+            .map_err(|e| e.context(format!("Failed fetching ticker data for {}", option.instrument_name)))?;
+
+        option.ticker_data = Some(ticker_data);
+        match &mut destination {
+            OptionDestination::Checkpoint(writer) => writer.write(&option)?,
+            OptionDestination::QuoteLog(writer) => writer.write(&option)?,
+        }
+        downloaded.downloaded += 1;
+        progress.increment();
+    }
+
+    Ok(downloaded)
+}
+
+/// How far downloading the options got.
+struct DownloadedOptions {
+    total: usize,
+    /// Including those resumed from the checkpoint.
+    downloaded: usize,
+    resumed: usize,
+}
+
+impl DownloadedOptions {
+    fn is_finished(&self) -> bool {
+        self.downloaded == self.total
+    }
+}
+
+/// Where downloaded options are written.
+enum OptionDestination {
+    Checkpoint(NdjsonWriter),
+    QuoteLog(QuoteLogWriter),
+}
+
+/// Download the perpetual future's ticker data, which is one of the sources the forward price can be found from when
+/// building the surface. It's always downloaded again, even when resuming, so that it's as close as possible to the time
+/// the options finished downloading.
+async fn download_perpetual() -> Result<(), TsError> {
+    println!("Fetching perpetual...");
+
+    let ticker_data = network::do_rpc_request_as_struct::<DeribitPerpetualTickerData>(&format!(
+        "https://www.deribit.com/api/v2/public/ticker?instrument_name={}-PERPETUAL",
+        fileio::get_currency().get_code()
+    ))
+    .await
+    .map_err(|e| e.context("Failed fetching perpetual ticker data"))?;
+
+    fileio::save_struct_to_file(&ticker_data, &fileio::get_market_data_path(DataScope::Snapshot, PERPETUAL_DATA_FILE_NAME))?;
+    println!("Successfully saved to file");
+
+    Ok(())
+}
+
+/// Download the futures that expire on a fixed date, with their ticker data, so that each expiry's forward price can be
+/// its future's price. Like the perpetual, they are always downloaded after the options, so that their prices are as close
+/// as possible to the time the options finished downloading.
+async fn download_futures() -> Result<Vec<DeribitFuture>, TsError> {
+    println!("Fetching futures...");
+
+    let mut futures = network::do_rpc_request_as_struct::<Vec<DeribitFuture>>(&format!(
+        "https://www.deribit.com/api/v2/public/get_instruments?currency={}&kind=future&expired=false",
+        fileio::get_currency().get_code()
+    ))
+    .await
+    .map_err(|e| e.context("Failed downloading futures"))?;
+
+    // The perpetual is downloaded separately, since it doesn't expire.
+    futures.retain(|x| !x.is_perpetual());
+    futures.sort_by(|a, b| {
+        a.expiration_timestamp
+            .cmp(&b.expiration_timestamp)
+            .then(a.instrument_name.cmp(&b.instrument_name))
+    });
+
+    for future in &mut futures {
+        let url = format!("https://www.deribit.com/api/v2/public/ticker?instrument_name={}", future.instrument_name);
+
+        future.ticker_data = Some(
+            network::do_rpc_request_as_struct::<DeribitFuturesTickerData>(&url)
+                .await
+                .map_err(|e| e.context(format!("Failed fetching ticker data for {}", future.instrument_name)))?,
+        );
+    }
+
+    println!("Fetched ticker data for {} futures", futures.len());
 
-        println!("Fetching ticker data for option ({} of {})...", i + 1, options.len());
-        let url = format!("https://www.deribit.com/api/v2/public/ticker?instrument_name={}", options[i].instrument_name);
-        let ticker_request = network::do_rpc_request_as_struct::<DeribitTickerData>(&url);
+    Ok(futures)
+}
+
+/// If a previous download was interrupted, find out which options it managed to download so that we don't have to
+/// download them again. Options whose ticker data is too old are removed from the checkpoint, since mixing them with
+/// fresh data would damage the quality of the snapshot.
+fn restore_checkpoint() -> Result<HashSet<Box<str>>, TsError> {
+    let mut already_downloaded = HashSet::new();
+    let checkpoint_path = fileio::get_market_data_path(DataScope::Market, CHECKPOINT_FILE_NAME);
+    let checkpoint_swap_path = fileio::get_market_data_path(DataScope::Market, CHECKPOINT_SWAP_FILE_NAME);
+
+    if !fileio::file_exists(&checkpoint_path) {
+        return Ok(already_downloaded);
+    }
 
-        match ticker_request.await {
-            Err(_) => {
-                println!("Request failed, trying again...");
+    println!("Found a checkpoint from a previous download, resuming...");
+
+    let oldest_allowed_millis = (helpers::get_now().timestamp() - constants::FETCH_CHECKPOINT_MAX_AGE_SECONDS as i64) * 1000;
+    let mut fresh_checkpoint = NdjsonWriter::create(&checkpoint_swap_path)?;
+
+    for option in fileio::read_ndjson_file::<DeribitOptionInstrument>(&checkpoint_path)? {
+        let option = match option {
+            Ok(v) => v,
+            Err(e) => {
+                // Most likely the program died halfway through writing this line.
+                println!("Ignoring unreadable checkpoint data: {}...", e.reason);
                 continue;
             }
-            Ok(v) => {
-                options[i].ticker_data = Some(v);
-                i += 1;
-            }
         };
+
+        let is_fresh = option
+            .ticker_data
+            .as_ref()
+            .is_some_and(|ticker| ticker.timestamp as i64 >= oldest_allowed_millis);
+
+        if is_fresh && already_downloaded.insert(option.instrument_name.clone()) {
+            fresh_checkpoint.write(&option)?;
+        }
     }
 
-    Ok(options)
+    drop(fresh_checkpoint);
+
+    std::fs::rename(&checkpoint_swap_path, &checkpoint_path)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed replacing checkpoint file: {e}")).with_source(e))?;
+
+    println!("Restored ticker data for {} options", already_downloaded.len());
+
+    Ok(already_downloaded)
+}
+
+fn delete_checkpoint() -> Result<(), TsError> {
+    println!("Deleting download checkpoint...");
+
+    fileio::delete_file(&fileio::get_market_data_path(DataScope::Market, CHECKPOINT_FILE_NAME))
 }
 
-/// The data has some anomalies because we can't download it all in one go. For example, the spot prices will be different
-/// for no reason. We can improve the quality of the data by normalising that.
-fn normalise_data(options: &mut Vec<DeribitOptionInstrument>) -> Result<(), TsError> {
-    println!("Normalising data...");
+/// The data has some anomalies because we can't download it all in one go: each option is quoted at a different time, so
+/// the index has moved between them. We can improve the quality of the data by re-anchoring every quote to the most recent
+/// one (see DeribitOptionInstrument::reanchor()). The downloaded options are read back from the checkpoint twice, once to
+/// find the most recent quote and once to normalise them and save them to the final file.
+fn normalise_and_save_data(
+    fetch_options: &FetchMarketDataOptions,
+    futures: Vec<DeribitFuture>,
+) -> Result<ReanchorCounts, TsError> {
+    println!("Normalising data and saving to file...");
 
-    let spot_price = options[0]
-        .ticker_data
-        .as_ref()
-        .ok_or(TsError::new(RuntimeError, "Failed getting ticker data reference"))?
-        .index_price;
+    let checkpoint_path = fileio::get_market_data_path(DataScope::Market, CHECKPOINT_FILE_NAME);
+    let mut anchor = None;
 
-    for option in options {
-        option
-            .ticker_data
-            .as_mut()
-            .ok_or(TsError::new(RuntimeError, "Failed getting ticker data mutable reference"))?
-            .index_price = spot_price;
+    for option in fileio::read_ndjson_file::<DeribitOptionInstrument>(&checkpoint_path)? {
+        anchor = SnapshotAnchor::latest(anchor, &option?);
     }
 
-    Ok(())
-}
+    let anchor = anchor.ok_or(TsError::new(
+        RuntimeError,
+        "None of the downloaded options have ticker data with a positive index price",
+    ))?;
+    let mut counts = ReanchorCounts::default();
+    let mut ndjson_writer = match fetch_options.ndjson {
+        true => Some(NdjsonWriter::create(&fileio::get_market_data_path(
+            DataScope::Snapshot,
+            NDJSON_MARKET_DATA_FILE_NAME,
+        ))?),
+        false => None,
+    };
+    let mut options = Vec::new();
 
-fn save_data(options: Vec<DeribitOptionInstrument>) -> Result<(), TsError> {
-    println!("Saving data to file...");
+    for option in fileio::read_ndjson_file::<DeribitOptionInstrument>(&checkpoint_path)? {
+        let mut option = option?;
 
-    let data = DeribitDataContainer { options };
+        counts.add(option.reanchor(anchor)?);
 
-    fileio::save_struct_to_file(&data, "./data/deribit-btc-market-data.json")?;
+        match ndjson_writer.as_mut() {
+            Some(writer) => writer.write(&option)?,
+            None => options.push(option),
+        }
+    }
 
-    Ok(())
+    integrations::print_reanchoring(anchor, counts);
+
+    if !fetch_options.ndjson {
+        // Resumed downloads will be out of order.
+        integrations::sort_options(&mut options);
+        let snapshot_time = integrations::get_snapshot_time(&options);
+        let data = DeribitDataContainer {
+            schema_version: DeribitDataContainer::SCHEMA_VERSION,
+            options,
+            snapshot_time,
+            futures,
+        };
+        fileio::save_data_file(
+            &data,
+            &fileio::get_market_data_path(DataScope::Snapshot, MARKET_DATA_FILE_NAME),
+            fetch_options.compress,
+        )?;
+
+        #[cfg(feature = "storage-sqlite")]
+        if let Some(db_path) = &fetch_options.db_path {
+            println!("Saving quotes to {db_path}...");
+            let count = crate::routines::storage::store_snapshot(db_path, &data)?;
+            println!("Saved {count} quotes");
+        }
+    }
+
+    println!("Successfully saved to file");
+
+    Ok(counts)
 }
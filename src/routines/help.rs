@@ -1,11 +1,248 @@
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
 pub fn help() {
     print!(
         "===== COMMANDS =====,
 
 help:               Show this screen,
 fetch-market-data:  Download the latest market data for analysis, saving the results in /data.
+  --ndjson                          Save the data as newline-delimited JSON (one option per line).
+  --quiet                           Don't print download progress.
+  --compress                        Save the data compressed with zstd (.json.zst), which is many times smaller.
+  --db <path>                       Also save the quotes to this SQLite database (needs the storage-sqlite feature).
+  --max-age <minutes>               Keep the saved snapshot rather than downloading a new one if its data is at most
+                                    this many minutes old.
+  --offline                         Never download anything. Keeps the saved snapshot, failing if there isn't one (or,
+                                    with --max-age, if it's too old).
+  --quote-log                       Append each quote to the day's file in quote-log/ (one quote per line) instead of
+                                    saving a snapshot, for collecting continuously. Can't be used with the flags above
+                                    that save or reuse a snapshot.
+merge-snapshots <paths...>:
+                    Combine several market data files (e.g. from interrupted or overlapping downloads) into one, keeping
+                    the most recent data for each option and saving the results in /data.
+  --ndjson                          Save the data as newline-delimited JSON. Files ending in .ndjson are always read as such.
+  --compress                        Save the data compressed with zstd (.json.zst).
 build-surface:      Build the volatility surface by analysing the downloaded data, saving the results in /data.
+  --ndjson                          Read the data saved by fetch-market-data --ndjson, one option at a time.
+  --quote-log                       Read the data from the quote log saved by fetch-market-data --quote-log, taking
+                                    each option's latest quote as of --as-of (or now) from the day before it.
+  --quiet                           Don't print fitting progress.
+  --repair-convexity                Before fitting, adjust prices as little as possible (within the bid and ask) so that
+                                    they are convex in strike, logging each adjustment.
+  --dry-run                         Load, clean and group the data and print the smiles that would be fit, with their
+                                    option counts and estimated fitting times, without fitting or saving anything.
+  --as-of <time|now>                Measure times until expiry from this time (RFC 3339), or from now, rather than from
+                                    when the data was downloaded.
+  --deterministic                   Make nothing saved depend on when the surface is built, so building from the same
+                                    data always gives a byte-identical file. Needs a fixed --as-of or downloaded data.
+  --compress                        Save the surface compressed with zstd (.json.zst).
+  --db <path>                       Also save the fitted SVI parameters to this SQLite database (needs the
+                                    storage-sqlite feature).
+  --threads <n>                     The number of smiles fit at once (default 1). Smiles predicted to take longest (from
+                                    how long similar smiles took in past builds) are fit first.
+  --fit-budget <seconds>            Roughly how long fitting should take, shared between the smiles by how long each is
+                                    predicted to take. A smile whose share runs out stops searching after its current
+                                    pass, keeping the best curve found. Can't be used with --deterministic.
+  --smile-timeout <seconds>         The most any one smile is fit for, overriding the config's smile_fit_limits. A smile
+                                    that runs out keeps the best curve found and is marked budget limited. Can't be used
+                                    with --deterministic.
+  --smile-max-iterations <n>        The most search passes (or differential evolution generations) any one smile is fit
+                                    for, overriding the config's smile_fit_limits.
+  --price-overrides <path>          Use the prices in this CSV file (columns: instrument_name, price, optional expiry)
+                                    instead of the downloaded ones. Prices are in USD.
+  --quote-sides                     Also fit surfaces to the bid, mid and ask prices (with the mark surface's forward
+                                    prices), saving them as /data/smile-graph-data-<side>.json, and print the bid-ask
+                                    width of implied volatilities at each expiry.
+  --iv-source <exchange|solve>      Fit the smiles to the exchange's mark implied volatilities, or to those solved from
+                                    the prices (default solve). Either way, the two are compared in
+                                    /data/iv-source-comparison.md. Can't be used with --price-overrides or
+                                    --repair-convexity.
+  --cross-validate <folds|loo>      After fitting, refit each smile with each of this many folds of its options (or each
+                                    option, for loo) held out, and print how well the held out options were predicted.
+  --bootstrap <resamples>           After fitting, refit each smile this many times to its residuals resampled, and save
+                                    90% confidence bands of its SVI parameters and implied volatilities, which
+                                    build-graphs shades around the curve.
+  --otm-only                        Build each smile from only its out of the money options (puts below the forward
+                                    price, calls at or above it), leaving out the illiquid in the money ones.
+  --expiry <YYYY-MM-DD>             Only build the smile expiring on this date (UTC).
+  --min-dte <days>                  Leave out expiries fewer than this many days away.
+  --max-dte <days>                  Leave out expiries more than this many days away.
+  --skip-dailies                    Leave out expiries at most 2 days away, which rarely fit well.
+  --expiry-categories <list>        Only build smiles for expiries in these categories, a comma separated list of daily,
+                                    weekly, monthly and quarterly (the March, June, September and December monthlies).
+  --exclude-expiry-categories <list>
+                                    Leave out expiries in these categories, e.g. daily.
+  --incremental                     Reuse the saved surface's fit of each smile whose quotes haven't changed since, only
+                                    fitting the rest. Needs the svi surface model and no term structure smoothing.
+  --refit-tolerance <vol points>    With --incremental, also reuse the fit of a smile whose options' implied
+                                    volatilities have all moved by at most this many volatility points (default 0).
+  --regularise <strength>           Pull the fit of each smile with at most 8 strikes towards the saved surface's fit
+                                    of the same expiry, so that it stays stable between builds. The strength is the
+                                    relative total variance error on every option that a parameter moving by its own
+                                    size costs as much as (e.g. 0.01). Needs the svi surface model.
+  --anchor-atm <vol points>         Make each smile's fit pass within this many volatility points of the implied
+                                    volatility of its straddle nearest the forward price (e.g. 0.5). Needs the svi
+                                    surface model.
+  --max-quote-age <seconds>         Discard options quoted more than this many seconds before the snapshot, which may
+                                    no longer match the rest of their smile.
+  --strict                          Stop without saving anything if any option or smile is discarded, printing what was
+                                    and why. Without it they are left out, and listed in /data/discard-report.json.
+  --csv-delimiter <char>            The character separating CSV columns (default ,).
+  --csv-decimal-separator <char>    The decimal separator used in CSV numbers (default .).
+  --csv-thousands-separator <char>  The thousands separator used in CSV numbers (default none).
+  --csv-date-format <format>        The format of CSV dates, e.g. %d/%m/%Y (default %Y-%m-%d).
+import-vol-matrix:  Fit smiles to a broker-style volatility matrix (a CSV of implied volatilities in percent, with a tenor
+                    column, e.g. 3M, and a column for each delta, e.g. 25P, ATM or 10C), saving them as the surface in /data.
+  --matrix <path>                   The volatility matrix CSV file. An optional forward column gives forward prices.
+  --spot <price>                    The spot price in USD, carried forward for tenors without a forward price.
+  --as-of <time|now>                When the matrix was marked (RFC 3339), which tenors are measured from (default now).
+  --quiet                           Don't print fitting progress.
+  --compress                        Save the surface compressed with zstd (.json.zst).
+  --csv-*                           The CSV format flags, as for build-surface.
 build-graphs:       Create graphs showing the implied volatility against strike price for each option expiry, saving the results in /data/graphs.
+  --delta                           Plot implied volatility against call delta instead of strike.
+  --format <png|html>               Save the graphs as images (the default) or interactive HTML pages, where hovering
+                                    over an option shows its instrument, strike and implied volatility.
+  --events <path>                   A CSV file of events (columns: date, label). Each is marked on the term structure
+                                    graph, and listed on the smile graph of each expiry after it. The CSV format flags
+                                    work as for build-surface.
+  --overlay <delta|log-moneyness>   Also draw every expiry's smile on one graph, coloured from blue (soonest) to red
+                                    (latest), against call delta or log moneyness.
+  --expiry-categories <list>        Only graph expiries in these categories, as for build-surface.
+  --exclude-expiry-categories <list>
+                                    Leave out expiries in these categories, as for build-surface.
+build-term-structure:
+                    Graph at the money implied volatility, and the forward variance between each pair of consecutive
+                    expiries, against days until expiry, saving the results in /data/graphs/term-structure.
+  --format <png|html>               Save the graphs as an image (the default) or interactive HTML pages.
+export:             Export the fitted surface (strike grid, implied volatility, total variance and SVI parameters) to
+                    /data/surface-export.csv and /data/surface-export.parquet.
+  --format <csv|parquet>            Only write one of the formats.
+  --strike-points <n>               The number of strikes evaluated per expiry (default 100).
+query:              Print the implied volatility, total variance and forward price at any strike and expiry, using the
+                    fitted surface. Between fitted expiries, forward variance is assumed to be flat.
+  --strike <price>                  The strike price in USD.
+  --delta <delta>                   Instead of a strike, the forward delta, e.g. 0.25 for the 25 delta call or -0.25
+                                    for the 25 delta put.
+  --expiry <date>                   The expiry, as YYYY-MM-DD (assumed to be 08:00 UTC) or an RFC 3339 timestamp.
+price-option:       Price an option at any strike and expiry with a binomial or trinomial tree, using the implied volatility
+                    from the fitted surface, and print its greeks. Unlike Black-Scholes, trees can price American options.
+                    Second-order greeks (vanna, volga, charm, speed and color) are from Black-Scholes.
+  --strike <price>                  The strike price in USD.
+  --expiry <date>                   The expiry, as YYYY-MM-DD (assumed to be 08:00 UTC) or an RFC 3339 timestamp.
+  --type <call|put>                 The type of option.
+  --exercise <european|american>    When the option can be exercised (default european).
+  --tree <binomial|trinomial>       The type of tree (default binomial).
+  --steps <n>                       The number of time steps in the tree (default 1000).
+simulate:           Price an option by Monte Carlo simulation, printing the price and its standard error. Spot follows
+                    the local volatility implied by the fitted surface, so path-dependent options can be priced.
+  --strike <price>                  The strike price in USD.
+  --expiry <date>                   The expiry, as YYYY-MM-DD (assumed to be 08:00 UTC) or an RFC 3339 timestamp.
+  --type <call|put>                 The type of option.
+  --payoff <european|asian|barrier> The payoff (default european). Asian options pay on the average daily price.
+  --barrier <price>                 The barrier price in USD, for barrier options.
+  --barrier-type <type>             up-and-out, up-and-in, down-and-out or down-and-in, for barrier options.
+  --model <local|flat>              Use Dupire local volatility, or the implied volatility at the strike and expiry
+                                    (default local).
+  --paths <n>                       The number of simulated paths (default 100000).
+  --steps <n>                       The number of time steps (default one per day).
+  --seed <n>                        The random seed, so results can be reproduced (default 1).
+scenario:           Apply the shocks of each scenario in the config to the surface, printing the P&L of a portfolio
+                    under each and saving the results, including the shocked surface, in /data/scenario-results.json.
+  --portfolio <path>                A CSV file of options held (columns: strike, expiry, type, quantity). Prices are
+                                    in USD, and a negative quantity is a short position.
+  --name <name>                     Only run the scenario with this name.
+  --spot-move <fraction>            Also run a scenario with these shocks: a relative spot move, e.g. -0.2 for a 20% fall,
+  --vol-shift <vol>                 a parallel shift in implied volatility, e.g. 0.05 for 5 vol points,
+  --skew-twist <vol>                implied volatility added per unit of log moneyness,
+  --days <n>                        and days passed.
+  --sticky-delta                    Move the smile with spot in that scenario, rather than keeping each strike's volatility.
+  --strike-points <n>               The number of strikes the shocked surface is saved at per expiry (default 25).
+  --csv-*                           The CSV format flags, as for build-surface.
+skew-report [paths...]:
+                    Print the at the money volatility and skew of each fitted expiry, saving the results in
+                    /data/skew-beta-report.csv. If earlier saved surfaces are given, also regress the changes in at the
+                    money volatility at each tenor against the changes in spot, giving a historical beta and the skew
+                    stickiness ratio.
+surface-metrics:    Print the at the money volatility, 25 delta risk reversal and butterfly, at the money skew and
+                    forward volatility of each fitted expiry, saving the results in /data/surface-metrics.json.
+  --events <path>                   A CSV file of events (columns: date, label). The last event before each expiry,
+                                    and how long before it, is included.
+  --db <path>                       Also save the metrics to this SQLite database (needs the storage-sqlite feature).
+digest:             Summarise the recent runs of build-surface (average scores and their trends, the worst fit slices,
+                    the biggest moves in at the money volatility and alert counts), saving the results in
+                    /data/digest.md and /data/digest.html.
+  --days <n>                        The number of days to cover (default 7).
+  --format <markdown|html>          Only write one of the formats.
+export-history:     Export the scores recorded by every run of build-surface, and the fit of each of their smiles, to CSV
+                    and Parquet files in /data/history-export, a chunk of runs at a time.
+  --format <csv|parquet>            Only write one of the formats.
+  --chunk-size <n>                  The number of runs written to each file (default 1000).
+  --resume                          Carry on from where the last export stopped, including any runs recorded since it
+                                    finished. The last export's format and chunk size are used.
+  --quiet                           Don't print export progress.
+calibrate-heston:   Calibrate the Heston stochastic volatility model to every fitted expiry at once, printing the
+                    parameters and how well they match each smile and saving the results in /data/heston-calibration.json.
+                    Also creates graphs comparing the Heston and SVI smiles in /data/graphs.
+archive:            Bundle the snapshots, fits and reports in /data into a compressed tarball, with a manifest listing
+                    each file, when it was last modified and whether it is a snapshot, fit or report.
+  --output <path>                   Where to save the archive, e.g. ./btc-2026-01.tar.gz.
+  --from <date>                     Only include files last modified on or after this day (YYYY-MM-DD, UTC).
+  --to <date>                       Only include files last modified on or before this day (YYYY-MM-DD, UTC).
+restore:            Extract an archive made by archive, refusing if any of its files already exist.
+  --archive <path>                  The archive to restore.
+  --to <path>                       The directory to restore to (default ./data).
+  --overwrite                       Replace files that already exist.
+backfill:           Reconstruct the market data and surface of each past day from the trades made that day (each
+                    option's last trade standing in for its quote at the end of the day), saving the results in
+                    /data/backfill.
+  --from <date>                     The first day to backfill (YYYY-MM-DD, UTC).
+  --to <date>                       The last day to backfill, which must be before today.
+  --quiet                           Don't print fitting progress.
+  --compress                        Save the results compressed with zstd (.json.zst).
+  --threads <n>                     The number of smiles fit at once (default 1).
+  --db <path>                       Also save the quotes, SVI parameters and metrics to this SQLite database (needs the
+                                    storage-sqlite feature).
+daemon:             Run fetch-market-data, build-surface and build-graphs (or the steps set in the config) on the schedule
+                    set in the config, forever. Each step is stopped if it takes longer than its timeout. Progress is
+                    saved in /data/daemon-state.json, so a restarted daemon finishes any interrupted run and makes up for
+                    a missed run.
+  --now                             Start a run straight away rather than waiting for the schedule.
+compare-assets:     Compare the latest surfaces of the currencies given with --currency (e.g. BTC,ETH) at each standard
+                    tenor they all cover, printing each one's at the money volatility and 25 delta risk reversal, and
+                    their ratio and difference to the first currency's, and saving the results in
+                    /data/asset-comparison.json.
+scan-arbitrage:     Scan the downloaded bids and asks, before anything is fit, for vertical spread, butterfly, box and
+                    calendar arbitrages, printing the trades of each with its edge in USD and saving them in
+                    /data/quote-arbitrage-report.json.
+  --min-edge <usd>                  Only report arbitrages with at least this much edge (default 0).
+serve-grpc:         Serve the latest surface over gRPC (see proto/theta_surface.proto), streaming each new one to the
+                    clients watching as soon as build-surface or the daemon saves it, and answering queries of any
+                    snapshot's surface, forever. Query latencies are saved in /data/grpc-metrics.prom. Needs the grpc
+                    feature.
+  --address <host:port>             The address to listen on (default 127.0.0.1:50051).
+  --poll-seconds <n>                How often to check for a new surface (default 5).
+  --preload                         Load the latest surface before serving, and each new one as soon as it's saved,
+                                    rather than when it's first needed.
+
+All commands accept --config <path> to use a config file other than ./config.json, --notes <text> to add notes
+for this run to the header of any graphs and reports made, --data-dir <path> to keep data files somewhere other than
+./data, and --snapshot-date <YYYY-MM-DD> to use an older snapshot if the config keeps snapshots apart by date (the
+latest is used otherwise). --currency <BTC|ETH> uses that currency's options (Bitcoin's by default), keeping its data in
+its own directory, e.g. /data/eth. fetch-market-data and build-surface can be given several, e.g. --currency BTC,ETH,
+and are run for each in turn. Paths below are given for the default layout. Every command saves a summary of how it went to
+/data/last-run.json, and --json prints it too. A failed command exits with 1 for an unexpected error, 2 for unusable
+market data, 3 for unsolvable maths, 4 for an API error, or 101 for a panic. Ctrl-C stops fetch-market-data and
+build-surface once they finish the option or smiles in progress, saving what they have done, and they exit with 130.
 "
     )
 }
+
+/// Called in place of a command that needs a cargo feature this build doesn't have.
+pub fn missing_feature(command: &str, feature: &str) -> Result<(), TsError> {
+    Err(TsError::new(
+        RuntimeError,
+        format!("{command} is not available because this build does not have the \"{feature}\" feature"),
+    ))
+}
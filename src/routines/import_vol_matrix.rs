@@ -0,0 +1,225 @@
+use std::f64::consts::E;
+use std::time::Instant;
+
+use chrono::{DateTime, Duration, Months, Utc};
+
+use crate::analytics::{
+    self, ButterflyCheckSettings, DeltaQuote, FitWeighting, SmileGraph, SmileGraphsDataContainer, SurfaceBuildInfo, SurfaceModel,
+    SviOptimiser,
+};
+use crate::fileio::{CsvFormat, DataScope, SchemaVersioned};
+use crate::helpers::{Clock, F64Helpers, ProgressBar};
+use crate::routines::{RunSummary, surface_file};
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+use crate::{constants, fileio, helpers};
+
+/// Options for importing a volatility matrix, set via command line flags.
+struct ImportVolMatrixOptions {
+    /// A CSV file with a tenor column and a column of implied volatilities for each delta.
+    matrix_path: String,
+    csv_format: CsvFormat,
+    /// The spot price of the underlying, carried forward at the interest free rate for tenors without a forward column.
+    spot_price: f64,
+    /// When the matrix was marked. Tenors are measured from this time, as are times until expiry.
+    as_of: Clock,
+    /// Don't print fitting progress.
+    quiet: bool,
+    /// Save the surface compressed with zstd.
+    compress: bool,
+}
+
+impl ImportVolMatrixOptions {
+    fn from_args(args: &[String]) -> Result<Self, TsError> {
+        let matrix_path = helpers::get_flag_value(args, "--matrix")?.ok_or(TsError::new(RuntimeError, "--matrix is required"))?;
+
+        let spot_price = helpers::get_flag_value(args, "--spot")?.ok_or(TsError::new(RuntimeError, "--spot is required"))?;
+        let spot_price = spot_price
+            .parse::<f64>()
+            .map_err(|e| TsError::new(RuntimeError, format!("Invalid spot price {spot_price}: {e}")).with_source(e))?;
+        helpers::error_unless_positive_f64(spot_price, "spot")?;
+
+        Ok(Self {
+            matrix_path: matrix_path.to_string(),
+            csv_format: CsvFormat::from_args(args)?,
+            spot_price,
+            as_of: match helpers::get_flag_value(args, "--as-of")? {
+                None | Some("now") => Clock::Real,
+                Some(v) => Clock::Fixed(
+                    DateTime::parse_from_rfc3339(v)
+                        .map_err(|e| {
+                            TsError::new(RuntimeError, format!("Invalid --as-of {v}, expected RFC 3339 or now: {e}"))
+                                .with_source(e)
+                        })?
+                        .to_utc(),
+                ),
+            },
+            quiet: helpers::has_flag(args, "--quiet"),
+            compress: helpers::has_flag(args, "--compress"),
+        })
+    }
+}
+
+/// Import a broker-style volatility matrix (implied volatilities on a grid of tenors and deltas), fitting a smile to each
+/// tenor and saving them as the surface, so that OTC marks can be graphed, queried and exported like an exchange chain.
+pub fn import_vol_matrix(args: &[String], run: &mut RunSummary) -> Result<(), TsError> {
+    let start = Instant::now();
+    let import_options = ImportVolMatrixOptions::from_args(args).map_err(|e| e.context("Invalid arguments"))?;
+
+    println!("===============================================================");
+    println!("===============================================================");
+    println!("Importing volatility matrix and saving to file");
+    println!("===============================================================");
+    println!("===============================================================");
+
+    let mut smile_graphs = run.step("Loading volatility matrix", || {
+        load_vol_matrix(
+            &import_options.matrix_path,
+            import_options.csv_format,
+            import_options.spot_price,
+            import_options.as_of,
+        )
+    })?;
+    run.count("tenors", smile_graphs.len());
+    println!("------------------------------");
+
+    fit_smile_graphs(&mut smile_graphs, import_options.quiet);
+    run.count("fitted_smiles", smile_graphs.len());
+    println!("------------------------------");
+
+    let data = SmileGraphsDataContainer {
+        schema_version: SmileGraphsDataContainer::SCHEMA_VERSION,
+        smile_graphs,
+        surface_score: None,
+        synthetic_smiles: Vec::new(),
+        build_info: Some(SurfaceBuildInfo::new(
+            import_options.as_of.now(),
+            false,
+            SurfaceModel::Svi,
+            SviOptimiser::MultiStart,
+            FitWeighting::Equal,
+            ButterflyCheckSettings::default(),
+        )),
+    };
+
+    println!("Saving data to file...");
+    let path = run.step("Saving surface data to file", || {
+        surface_file::save_surface(
+            &data,
+            &fileio::get_data_path(DataScope::Snapshot, "smile-graph-data.json"),
+            import_options.compress,
+        )
+    })?;
+    println!("Successfully saved to {path}");
+
+    println!("Volatility matrix imported in {} seconds", start.elapsed().as_secs_f64().round_to_decimal_places(2));
+    println!("===============================================================");
+
+    Ok(())
+}
+
+/// Load the matrix, turning each tenor (row) into a smile graph of synthetic options. Columns other than tenor and the
+/// optional forward are deltas (see analytics::parse_delta_label()), and volatilities are in percent, e.g. 55.2.
+fn load_vol_matrix(path: &str, format: CsvFormat, spot_price: f64, clock: Clock) -> Result<Vec<SmileGraph>, TsError> {
+    println!("Loading volatility matrix from {path}...");
+
+    let table = fileio::load_csv_file(path, format)?;
+    let has_forward = table.has_column("forward");
+    let delta_columns = table
+        .get_headers()
+        .iter()
+        .filter(|x| !x.eq_ignore_ascii_case("tenor") && !x.eq_ignore_ascii_case("forward"))
+        .map(|x| Ok((x.as_str(), analytics::parse_delta_label(x)?)))
+        .collect::<Result<Vec<(&str, f64)>, TsError>>()?;
+    let as_of = clock.now();
+    let mut smile_graphs: Vec<SmileGraph> = Vec::new();
+
+    for row in 0..table.rows_count() {
+        let tenor = table.get_str(row, "tenor")?;
+        let expiry = add_tenor(as_of, tenor)?;
+        let years_until_expiry = (expiry - as_of).num_seconds() as f64 / 31556926.0;
+        let forward_price = match has_forward {
+            true => table.get_f64(row, "forward")?,
+            false => spot_price * E.powf(constants::INTEREST_FREE_RATE * years_until_expiry),
+        };
+        let quotes = delta_columns
+            .iter()
+            .map(|(column, delta)| {
+                Ok(DeltaQuote {
+                    delta: *delta,
+                    implied_volatility: table.get_f64(row, column)? / 100.0,
+                })
+            })
+            .collect::<Result<Vec<DeltaQuote>, TsError>>()?;
+
+        if smile_graphs
+            .iter()
+            .any(|x| x.get_expiration().is_ok_and(|x| x == expiry))
+        {
+            return Err(TsError::new(RuntimeError, format!("Tenor {tenor} is given more than once")));
+        }
+
+        let mut smile_graph = SmileGraph::new();
+
+        for option in analytics::build_options_from_delta_quotes(tenor, expiry, forward_price, &quotes, clock)
+            .map_err(|e| e.context(format!("Tenor {tenor}")))?
+        {
+            smile_graph.try_insert_option(option)?;
+        }
+
+        smile_graph
+            .error_unless_valid()
+            .map_err(|e| TsError::new(RuntimeError, format!("Tenor {tenor}: {e}")))?;
+        smile_graphs.push(smile_graph);
+    }
+
+    // Every smile was checked to be valid above, so none of the keys are None.
+    smile_graphs.sort_by_cached_key(|x| x.get_expiration().ok());
+
+    println!("Loaded {} tenors with {} deltas each", smile_graphs.len(), delta_columns.len());
+
+    Ok(smile_graphs)
+}
+
+/// The time a tenor (a number followed by D, W, M or Y, e.g. 3M) after the given time.
+fn add_tenor(time: DateTime<Utc>, tenor: &str) -> Result<DateTime<Utc>, TsError> {
+    let invalid = || TsError::new(RuntimeError, format!("Invalid tenor {tenor}, expected e.g. 1D, 2W, 3M or 1Y"));
+    let unit = tenor.chars().last().ok_or_else(invalid)?;
+    let count = tenor[..tenor.len() - unit.len_utf8()]
+        .parse::<u32>()
+        .ok()
+        .filter(|x| *x > 0)
+        .ok_or_else(invalid)?;
+
+    match unit.to_ascii_uppercase() {
+        'D' => time.checked_add_signed(Duration::days(count as i64)),
+        'W' => time.checked_add_signed(Duration::weeks(count as i64)),
+        'M' => time.checked_add_months(Months::new(count)),
+        'Y' => time.checked_add_months(Months::new(count * 12)),
+        _ => None,
+    }
+    .ok_or_else(invalid)
+}
+
+/// Fit each smile, keeping the ones that fail out of the saved surface.
+fn fit_smile_graphs(smile_graphs: &mut Vec<SmileGraph>, quiet: bool) {
+    println!("Fitting smile graphs...");
+
+    let total = smile_graphs.len();
+    let mut progress = ProgressBar::new("Fitting smiles", total as u64, quiet);
+
+    smile_graphs.retain_mut(|graph| {
+        let result = graph.fit_smile(&progress);
+        progress.increment();
+
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                println!("Failed fitting a smile: {}...", e.reason);
+                false
+            }
+        }
+    });
+
+    println!("Successfully fit {}/{} smiles...", smile_graphs.len(), total);
+}
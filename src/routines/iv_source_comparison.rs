@@ -0,0 +1,198 @@
+use crate::analytics::{ImpliedVolatilitySource, OptionInstrument, OptionType, SmileGraph};
+use crate::fileio::{DataScope, Report, ReportSection};
+use crate::helpers::F64Helpers;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+use crate::{fileio, helpers};
+
+const REPORT_FILE_NAME: &str = "iv-source-comparison.md";
+
+/// How many of the options whose implied volatilities differ the most are listed.
+const TOP_COUNT: usize = 10;
+
+/// An option's implied volatility from both sources.
+struct ComparedOption<'a> {
+    option: &'a OptionInstrument,
+    solved: f64,
+    exchange: f64,
+}
+
+impl ComparedOption<'_> {
+    fn get_difference(&self) -> f64 {
+        self.exchange - self.solved
+    }
+}
+
+/// Compare the implied volatilities solved from the options' prices with the exchange's mark implied volatilities,
+/// printing a summary of each expiry and saving a report of the differences.
+pub(super) fn save_iv_source_comparison(smile_graphs: &[SmileGraph], source: ImpliedVolatilitySource) -> Result<(), TsError> {
+    println!("Comparing solved and exchange implied volatilities...");
+
+    let mut report = Report::new("Solved and exchange implied volatilities");
+    let mut expiry_rows = Vec::new();
+    let mut compared = Vec::new();
+    let mut missing_exchange = 0;
+    let mut unsolvable = 0;
+
+    for graph in smile_graphs {
+        let mut expiry_compared = Vec::new();
+
+        for option in &graph.options {
+            let Some(exchange) = option.exchange_implied_volatility else {
+                missing_exchange += 1;
+                continue;
+            };
+            let Ok(solved) = option.get_solved_implied_volatility() else {
+                unsolvable += 1;
+                continue;
+            };
+
+            expiry_compared.push(ComparedOption {
+                option,
+                solved,
+                exchange,
+            });
+        }
+
+        if expiry_compared.is_empty() {
+            continue;
+        }
+
+        let count = expiry_compared.len() as f64;
+        let mean_solved = expiry_compared.iter().map(|x| x.solved).sum::<f64>() / count;
+        let mean_exchange = expiry_compared.iter().map(|x| x.exchange).sum::<f64>() / count;
+        let mean_difference = expiry_compared
+            .iter()
+            .map(|x| x.get_difference())
+            .sum::<f64>()
+            / count;
+        let mean_absolute_difference = expiry_compared
+            .iter()
+            .map(|x| x.get_difference().abs())
+            .sum::<f64>()
+            / count;
+        let max_absolute_difference = expiry_compared
+            .iter()
+            .map(|x| x.get_difference().abs())
+            .fold(0.0, f64::max);
+        let expiry = helpers::format_time(graph.get_expiration()?);
+
+        println!(
+            "{expiry}: {} options, exchange minus solved is {} vol points on average ({} absolute, {} at most)...",
+            expiry_compared.len(),
+            format_vol_points(mean_difference),
+            format_vol_points(mean_absolute_difference),
+            format_vol_points(max_absolute_difference),
+        );
+
+        expiry_rows.push(vec![
+            expiry,
+            expiry_compared.len().to_string(),
+            format_vol_points(mean_solved),
+            format_vol_points(mean_exchange),
+            format_vol_points(mean_difference),
+            format_vol_points(mean_absolute_difference),
+            format_vol_points(max_absolute_difference),
+        ]);
+        compared.append(&mut expiry_compared);
+    }
+
+    if compared.is_empty() {
+        return Err(TsError::new(RuntimeError, "No options have both a solved and an exchange implied volatility"));
+    }
+
+    let mean_absolute_difference = compared
+        .iter()
+        .map(|x| x.get_difference().abs())
+        .sum::<f64>()
+        / compared.len() as f64;
+
+    report.sections.push(ReportSection {
+        heading: "Summary".into(),
+        paragraphs: vec![
+            format!(
+                "The surface was fit to {} implied volatilities. {} options had both, {} had no exchange implied \
+                 volatility and {} couldn't be solved.",
+                match source {
+                    ImpliedVolatilitySource::Solve => "solved",
+                    ImpliedVolatilitySource::Exchange => "exchange",
+                },
+                compared.len(),
+                missing_exchange,
+                unsolvable
+            ),
+            format!(
+                "On average the exchange's implied volatility was {} vol points from the solved one. Differences are \
+                 exchange minus solved.",
+                format_vol_points(mean_absolute_difference)
+            ),
+        ],
+        table: None,
+    });
+    report.sections.push(ReportSection {
+        heading: "By expiry".into(),
+        paragraphs: vec!["Implied volatilities and differences are in vol points.".into()],
+        table: Some((
+            [
+                "Expiry",
+                "Options",
+                "Mean solved",
+                "Mean exchange",
+                "Mean difference",
+                "Mean absolute difference",
+                "Max absolute difference",
+            ]
+            .map(String::from)
+            .to_vec(),
+            expiry_rows,
+        )),
+    });
+
+    compared.sort_by(|a, b| {
+        b.get_difference()
+            .abs()
+            .total_cmp(&a.get_difference().abs())
+    });
+
+    let mut largest_rows = Vec::new();
+
+    for x in compared.iter().take(TOP_COUNT) {
+        largest_rows.push(vec![
+            helpers::format_time(x.option.get_expiration()?),
+            x.option.strike.to_string(),
+            match x.option.option_type {
+                OptionType::Call => "call".into(),
+                OptionType::Put => "put".into(),
+            },
+            format_vol_points(x.solved),
+            format_vol_points(x.exchange),
+            format_vol_points(x.get_difference()),
+        ]);
+    }
+
+    report.sections.push(ReportSection {
+        heading: "Largest differences".into(),
+        paragraphs: vec![format!(
+            "The {TOP_COUNT} options whose implied volatilities differed the most."
+        )],
+        table: Some((
+            ["Expiry", "Strike", "Type", "Solved", "Exchange", "Difference"]
+                .map(String::from)
+                .to_vec(),
+            largest_rows,
+        )),
+    });
+
+    println!(
+        "On average the exchange's implied volatility is {} vol points from the solved one",
+        format_vol_points(mean_absolute_difference)
+    );
+    let report_path = fileio::get_data_path(DataScope::Snapshot, REPORT_FILE_NAME);
+    println!("Saving {report_path}...");
+
+    fileio::save_report_to_markdown(&report, &report_path)
+}
+
+fn format_vol_points(volatility: f64) -> String {
+    (volatility * 100.0).round_to_decimal_places(2).to_string()
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+
+use crate::fileio::{DataScope, NdjsonWriter, SchemaVersioned};
+use crate::integrations::{DeribitDataContainer, DeribitOptionInstrument};
+use crate::routines::RunSummary;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+use crate::{fileio, helpers, integrations};
+
+/// Prefixed with the exchange and currency (see fileio::get_market_data_path()).
+const MARKET_DATA_FILE_NAME: &str = "market-data.json";
+const NDJSON_MARKET_DATA_FILE_NAME: &str = "market-data.ndjson";
+
+/// Options that change how snapshots are merged, set via command line arguments.
+struct MergeSnapshotsOptions {
+    /// The snapshot files to merge. Files ending in .ndjson are read as newline-delimited JSON.
+    paths: Vec<String>,
+    /// Save the merged data as newline-delimited JSON rather than one big JSON object.
+    ndjson: bool,
+    /// Save the merged data compressed with zstd.
+    compress: bool,
+}
+
+impl MergeSnapshotsOptions {
+    fn from_args(args: &[String]) -> Result<Self, TsError> {
+        let mut paths = Vec::new();
+        let mut remaining_args = args.iter().skip_while(|a| *a != "merge-snapshots").skip(1);
+
+        while let Some(arg) = remaining_args.next() {
+            match arg.as_str() {
+                // Skip the values of flags that take one, which aren't snapshots.
+                "--config" | "--data-dir" | "--snapshot-date" => {
+                    remaining_args.next();
+                }
+                flag if flag.starts_with("--") => {}
+                path => paths.push(path.to_string()),
+            }
+        }
+
+        if paths.len() < 2 {
+            return Err(TsError::new(RuntimeError, "At least two snapshot files are needed to merge"));
+        }
+
+        let ndjson = helpers::has_flag(args, "--ndjson");
+        let compress = helpers::has_flag(args, "--compress");
+
+        if ndjson && compress {
+            return Err(TsError::new(RuntimeError, "--compress can't be used with --ndjson"));
+        }
+
+        Ok(Self { paths, ndjson, compress })
+    }
+}
+
+pub fn merge_snapshots(args: &[String], run: &mut RunSummary) -> Result<(), TsError> {
+    println!("===============================================================");
+    println!("===============================================================");
+    println!("Merging market data snapshots and saving to file");
+    println!("===============================================================");
+    println!("===============================================================");
+
+    let merge_options = MergeSnapshotsOptions::from_args(args).map_err(|e| e.context("Invalid arguments"))?;
+
+    let mut options = run.step("Merging snapshots", || merge_freshest_options(&merge_options.paths))?;
+    run.count("options", options.len());
+    println!("------------------------------");
+
+    run.step("Normalising merged data", || normalise_options(&mut options))?;
+    println!("------------------------------");
+
+    run.step("Saving merged data", || save_options(options, merge_options.ndjson, merge_options.compress))?;
+    println!("===============================================================");
+
+    Ok(())
+}
+
+/// Read every snapshot, keeping the option with the most recent ticker data for each instrument.
+fn merge_freshest_options(paths: &[String]) -> Result<Vec<DeribitOptionInstrument>, TsError> {
+    let mut freshest: HashMap<Box<str>, DeribitOptionInstrument> = HashMap::new();
+
+    for path in paths {
+        println!("Reading {path}...");
+
+        let snapshot: Box<dyn Iterator<Item = Result<DeribitOptionInstrument, TsError>>> = match path.ends_with(".ndjson") {
+            true => Box::new(fileio::read_ndjson_file::<DeribitOptionInstrument>(path)?),
+            false => Box::new(
+                fileio::load_versioned_struct_from_file::<DeribitDataContainer>(path)?
+                    .options
+                    .into_iter()
+                    .map(Ok),
+            ),
+        };
+
+        let mut read_options = 0;
+        let mut replaced_options = 0;
+
+        for option in snapshot {
+            let option = option?;
+            read_options += 1;
+
+            let Some(existing) = freshest.get(&option.instrument_name) else {
+                freshest.insert(option.instrument_name.clone(), option);
+                continue;
+            };
+
+            if get_ticker_timestamp(&option) > get_ticker_timestamp(existing) {
+                replaced_options += 1;
+                freshest.insert(option.instrument_name.clone(), option);
+            }
+        }
+
+        println!("Read {read_options} options, of which {replaced_options} replaced older data");
+    }
+
+    println!("Merged into {} options", freshest.len());
+
+    Ok(freshest.into_values().collect())
+}
+
+/// The time the option's ticker data was captured, if it has any.
+fn get_ticker_timestamp(option: &DeribitOptionInstrument) -> Option<u64> {
+    option.ticker_data.as_ref().map(|x| x.timestamp)
+}
+
+/// Re-anchor every option's quote to the most recent one, as fetch-market-data does. Since the snapshots were captured at
+/// different times, the quotes may be further apart than in any one of them.
+fn normalise_options(options: &mut [DeribitOptionInstrument]) -> Result<(), TsError> {
+    println!("Normalising data...");
+
+    integrations::reanchor_options(options)?;
+
+    Ok(())
+}
+
+fn save_options(mut options: Vec<DeribitOptionInstrument>, ndjson: bool, compress: bool) -> Result<(), TsError> {
+    integrations::sort_options(&mut options);
+
+    match ndjson {
+        true => {
+            let path = fileio::get_market_data_path(DataScope::Snapshot, NDJSON_MARKET_DATA_FILE_NAME);
+            println!("Saving {path}...");
+            let mut writer = NdjsonWriter::create(&path)?;
+
+            for option in &options {
+                writer.write(option)?;
+            }
+        }
+        false => {
+            let path = fileio::get_market_data_path(DataScope::Snapshot, MARKET_DATA_FILE_NAME);
+            println!("Saving {path}...");
+            let snapshot_time = integrations::get_snapshot_time(&options);
+            fileio::save_data_file(
+                &DeribitDataContainer {
+                    schema_version: DeribitDataContainer::SCHEMA_VERSION,
+                    options,
+                    snapshot_time,
+                    // Futures aren't merged, so forward prices come from the options' own underlying prices, as with
+                    // snapshots saved by older versions.
+                    futures: Vec::new(),
+                },
+                &path,
+                compress,
+            )?;
+        }
+    }
+
+    println!("Successfully saved to file");
+
+    Ok(())
+}
@@ -1,9 +1,73 @@
+mod archive;
+#[cfg(feature = "network")]
+mod backfill;
+#[cfg(feature = "plotting")]
 mod build_graphs;
+#[cfg(feature = "plotting")]
+mod build_greek_graphs;
 mod build_surface;
+#[cfg(feature = "plotting")]
+mod build_term_structure;
+mod calibrate_heston;
+mod compare_assets;
+mod daemon;
+mod digest;
+mod discard_report;
+mod export;
+mod export_history;
+#[cfg(feature = "network")]
 mod fetch_market_data;
 mod help;
+mod import_vol_matrix;
+mod iv_source_comparison;
+mod merge_snapshots;
+mod price_option;
+mod query;
+mod quote_log;
+mod run_summary;
+mod scan_arbitrage;
+mod scenario;
+#[cfg(feature = "grpc")]
+mod serve_grpc;
+mod simulate;
+mod skew_report;
+#[cfg(feature = "storage-sqlite")]
+mod storage;
+mod surface_file;
+mod surface_metrics;
 
+pub use archive::archive;
+pub use archive::restore;
+#[cfg(feature = "network")]
+pub use backfill::backfill;
+#[cfg(feature = "plotting")]
 pub use build_graphs::build_graphs;
+#[cfg(feature = "plotting")]
+pub use build_greek_graphs::build_greek_graphs;
 pub use build_surface::build_surface;
+#[cfg(feature = "plotting")]
+pub use build_term_structure::build_term_structure;
+pub use calibrate_heston::calibrate_heston;
+pub use compare_assets::compare_assets;
+pub use daemon::daemon;
+pub use digest::digest;
+pub use export::export;
+pub use export_history::export_history;
+#[cfg(feature = "network")]
 pub use fetch_market_data::fetch_market_data;
 pub use help::help;
+pub use help::missing_feature;
+pub use import_vol_matrix::import_vol_matrix;
+pub use merge_snapshots::merge_snapshots;
+pub use price_option::price_option;
+pub use query::query;
+pub use run_summary::RunStatus;
+pub use run_summary::RunStep;
+pub use run_summary::RunSummary;
+pub use scan_arbitrage::scan_arbitrage;
+pub use scenario::scenario;
+#[cfg(feature = "grpc")]
+pub use serve_grpc::serve_grpc;
+pub use simulate::simulate;
+pub use skew_report::skew_report;
+pub use surface_metrics::surface_metrics;
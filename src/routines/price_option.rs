@@ -0,0 +1,201 @@
+use std::f64::consts::E;
+
+use chrono::{DateTime, Utc};
+
+use crate::analytics::{self, ExerciseStyle, OptionType, TreeSettings, TreeType};
+use crate::config::Config;
+use crate::helpers::F64Helpers;
+use crate::routines::{RunSummary, surface_file};
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+use crate::{constants, helpers};
+
+/// The default number of tree steps. Enough for prices to be accurate to around a cent on a $1000 option.
+const DEFAULT_STEPS: u64 = 1000;
+
+/// The option to price, set via command line flags.
+struct PriceOptionOptions {
+    strike: f64,
+    expiry: DateTime<Utc>,
+    option_type: OptionType,
+    tree_settings: TreeSettings,
+}
+
+impl PriceOptionOptions {
+    fn from_args(args: &[String], config: &Config) -> Result<Self, TsError> {
+        let strike = helpers::get_flag_value(args, "--strike")?.ok_or(TsError::new(RuntimeError, "--strike is required"))?;
+        let strike = strike
+            .parse::<f64>()
+            .map_err(|e| TsError::new(RuntimeError, format!("Invalid strike {strike}: {e}")).with_source(e))?;
+        helpers::error_unless_positive_f64(strike, "strike")?;
+
+        let expiry = helpers::get_flag_expiry(args, "--expiry")?.ok_or(TsError::new(RuntimeError, "--expiry is required"))?;
+
+        let option_type = OptionType::try_from(
+            helpers::get_flag_value(args, "--type")?.ok_or(TsError::new(RuntimeError, "--type is required"))?,
+        )?;
+
+        let exercise_style = match helpers::get_flag_value(args, "--exercise")? {
+            None => ExerciseStyle::European,
+            Some(v) => ExerciseStyle::try_from(v)?,
+        };
+
+        let tree_type = match helpers::get_flag_value(args, "--tree")? {
+            None => TreeType::Binomial,
+            Some(v) => TreeType::try_from(v)?,
+        };
+
+        let steps = match helpers::get_flag_value(args, "--steps")? {
+            None => DEFAULT_STEPS,
+            Some(v) => v
+                .parse::<u64>()
+                .ok()
+                .filter(|x| *x >= 2)
+                .ok_or(TsError::new(RuntimeError, format!("--steps must be a whole number >= 2, found {v}")))?,
+        };
+
+        Ok(Self {
+            strike,
+            expiry,
+            option_type,
+            tree_settings: TreeSettings {
+                exercise_style,
+                tree_type,
+                steps,
+                dividend_yield: config.dividend_yield,
+            },
+        })
+    }
+}
+
+pub fn price_option(args: &[String], config: &Config, run: &mut RunSummary) -> Result<(), TsError> {
+    let options = PriceOptionOptions::from_args(args, config).map_err(|e| e.context("Invalid arguments"))?;
+
+    let grid = run.step("Loading surface grid", || surface_file::load_surface_grid(config))?;
+    let slices = grid.get_slices();
+
+    let rate = constants::INTEREST_FREE_RATE;
+    let years_until_expiry = (options.expiry - helpers::get_now()).num_seconds() as f64 / 31556926.0;
+    let point = run.step("Querying surface", || analytics::interpolate_surface(&slices, options.strike, years_until_expiry))?;
+    let spot_price = point.forward_price * E.powf(-(rate - config.dividend_yield) * years_until_expiry);
+
+    let (greeks, black_scholes_price) = run.step("Pricing option", || {
+        let greeks = analytics::calculate_tree_greeks(
+            spot_price,
+            options.strike,
+            years_until_expiry,
+            rate,
+            point.implied_volatility,
+            options.option_type,
+            &options.tree_settings,
+        )?;
+        let black_scholes_price = analytics::calculate_black_scholes(
+            spot_price,
+            options.strike,
+            years_until_expiry,
+            rate,
+            config.dividend_yield,
+            point.implied_volatility,
+            options.option_type,
+        )?;
+
+        Ok((greeks, black_scholes_price))
+    })?;
+
+    println!("Strike:               {}", options.strike);
+    println!("Expiry:               {}", helpers::format_time(options.expiry));
+    println!("Type:                 {:?}", options.option_type);
+    println!("Exercise:             {:?}", options.tree_settings.exercise_style);
+    println!(
+        "Tree:                 {:?} ({} steps)",
+        options.tree_settings.tree_type, options.tree_settings.steps
+    );
+    println!("Spot price:           {}", spot_price.round_to_decimal_places(2));
+    println!("Dividend yield:       {}", config.dividend_yield);
+    println!("Implied volatility:   {}", point.implied_volatility.round_to_decimal_places(6));
+    println!("------------------------------");
+    println!("Price:                {}", greeks.price.round_to_decimal_places(4));
+    println!("Black-Scholes price:  {}", black_scholes_price.round_to_decimal_places(4));
+
+    if options.tree_settings.exercise_style == ExerciseStyle::American {
+        // Compare with the same tree so that the difference isn't affected by the tree's error.
+        let european_settings = TreeSettings {
+            exercise_style: ExerciseStyle::European,
+            ..options.tree_settings
+        };
+        let european_price = run.step("Pricing European option", || {
+            analytics::calculate_tree_price(
+                spot_price,
+                options.strike,
+                years_until_expiry,
+                rate,
+                point.implied_volatility,
+                options.option_type,
+                &european_settings,
+            )
+        })?;
+
+        println!("Early exercise value: {}", (greeks.price - european_price).round_to_decimal_places(4));
+    }
+
+    println!("Delta:                {}", greeks.delta.round_to_decimal_places(6));
+    println!("Gamma:                {}", greeks.gamma.round_to_decimal_places(8));
+    println!("Vega (per 1% vol):    {}", (greeks.vega / 100.0).round_to_decimal_places(4));
+    println!("Theta (per day):      {}", (greeks.theta / 365.0).round_to_decimal_places(4));
+    println!("------------------------------");
+    run.step("Calculating second-order greeks", || {
+        print_second_order_greeks(
+            spot_price,
+            options.strike,
+            years_until_expiry,
+            rate,
+            config.dividend_yield,
+            point.implied_volatility,
+            options.option_type,
+        )
+    })?;
+
+    if point.calendar_arbitrage.is_some() {
+        println!("Warning: the surface has calendar arbitrage at this strike, so total variance has been held flat");
+    }
+
+    Ok(())
+}
+
+/// Print the second-order greeks, which come from Black-Scholes rather than the tree, so they are for a European option.
+fn print_second_order_greeks(
+    spot_price: f64,
+    strike: f64,
+    years_until_expiry: f64,
+    rate: f64,
+    dividend_yield: f64,
+    volatility: f64,
+    option_type: OptionType,
+) -> Result<(), TsError> {
+    let vanna =
+        analytics::calculate_black_scholes_vanna(spot_price, strike, years_until_expiry, rate, dividend_yield, volatility)?;
+    let volga =
+        analytics::calculate_black_scholes_volga(spot_price, strike, years_until_expiry, rate, dividend_yield, volatility)?;
+    let charm = analytics::calculate_black_scholes_charm(
+        spot_price,
+        strike,
+        years_until_expiry,
+        rate,
+        dividend_yield,
+        volatility,
+        option_type,
+    )?;
+    let speed =
+        analytics::calculate_black_scholes_speed(spot_price, strike, years_until_expiry, rate, dividend_yield, volatility)?;
+    let color =
+        analytics::calculate_black_scholes_color(spot_price, strike, years_until_expiry, rate, dividend_yield, volatility)?;
+
+    println!("Second-order greeks (Black-Scholes):");
+    println!("Vanna (per 1% vol):   {}", (vanna / 100.0).round_to_decimal_places(6));
+    println!("Volga (per 1% vol):   {}", (volga / 10000.0).round_to_decimal_places(6));
+    println!("Charm (per day):      {}", (charm / 365.0).round_to_decimal_places(6));
+    println!("Speed:                {}", speed.round_to_decimal_places(12));
+    println!("Color (per day):      {}", (color / 365.0).round_to_decimal_places(12));
+
+    Ok(())
+}
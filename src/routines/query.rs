@@ -0,0 +1,88 @@
+use chrono::{DateTime, Utc};
+
+use crate::analytics::{self, OptionType};
+use crate::config::Config;
+use crate::helpers;
+use crate::helpers::F64Helpers;
+use crate::routines::{RunSummary, surface_file};
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+/// Where across the smile to read the surface.
+enum QueryPoint {
+    Strike(f64),
+    /// A forward delta, positive for calls and negative for puts.
+    Delta(f64),
+}
+
+/// The point on the surface to read, set via command line flags.
+struct QueryOptions {
+    point: QueryPoint,
+    expiry: DateTime<Utc>,
+}
+
+impl QueryOptions {
+    fn from_args(args: &[String]) -> Result<Self, TsError> {
+        let point = match (helpers::get_flag_value(args, "--strike")?, helpers::get_flag_value(args, "--delta")?) {
+            (Some(strike), None) => {
+                let strike = strike
+                    .parse::<f64>()
+                    .map_err(|e| TsError::new(RuntimeError, format!("Invalid strike {strike}: {e}")).with_source(e))?;
+                helpers::error_unless_positive_f64(strike, "strike")?;
+
+                QueryPoint::Strike(strike)
+            }
+            (None, Some(delta)) => QueryPoint::Delta(
+                delta
+                    .parse::<f64>()
+                    .map_err(|e| TsError::new(RuntimeError, format!("Invalid delta {delta}: {e}")).with_source(e))?,
+            ),
+            _ => return Err(TsError::new(RuntimeError, "Either --strike or --delta is required, but not both")),
+        };
+
+        let expiry = helpers::get_flag_expiry(args, "--expiry")?.ok_or(TsError::new(RuntimeError, "--expiry is required"))?;
+
+        Ok(Self { point, expiry })
+    }
+}
+
+pub fn query(args: &[String], config: &Config, run: &mut RunSummary) -> Result<(), TsError> {
+    let query_options = QueryOptions::from_args(args).map_err(|e| e.context("Invalid arguments"))?;
+
+    let grid = run.step("Loading surface grid", || surface_file::load_surface_grid(config))?;
+    let slices = grid.get_slices();
+
+    let years_until_expiry = (query_options.expiry - helpers::get_now()).num_seconds() as f64 / 31556926.0;
+    let point = run.step("Querying surface", || match query_options.point {
+        QueryPoint::Strike(strike) => analytics::interpolate_surface(&slices, strike, years_until_expiry),
+        QueryPoint::Delta(delta) => analytics::interpolate_surface_at_delta(&slices, delta, years_until_expiry),
+    })?;
+    let call_delta = run.step("Calculating delta", || {
+        analytics::calculate_forward_delta(point.log_moneyness, point.total_variance, OptionType::Call)
+    })?;
+
+    println!(
+        "Strike:             {}",
+        analytics::log_moneyness_to_strike(point.log_moneyness, point.forward_price).round_to_decimal_places(2)
+    );
+    println!("Expiry:             {}", helpers::format_time(query_options.expiry));
+    println!("Years until expiry: {}", years_until_expiry.round_to_decimal_places(6));
+    println!("Forward price:      {}", point.forward_price.round_to_decimal_places(2));
+    println!("Log moneyness:      {}", point.log_moneyness.round_to_decimal_places(6));
+    println!("Total variance:     {}", point.total_variance.round_to_decimal_places(6));
+    println!("Implied volatility: {}", point.implied_volatility.round_to_decimal_places(6));
+    println!("Call delta:         {}", call_delta.round_to_decimal_places(4));
+    println!("Put delta:          {}", (call_delta - 1.0).round_to_decimal_places(4));
+
+    if let Some(arbitrage) = point.calendar_arbitrage {
+        println!(
+            "Warning: total variance falls from {} at {} to {} at {}, so it has been held flat between them",
+            arbitrage.earlier_total_variance.round_to_decimal_places(6),
+            helpers::format_time(arbitrage.earlier_expiry),
+            arbitrage.later_total_variance.round_to_decimal_places(6),
+            helpers::format_time(arbitrage.later_expiry),
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Days, NaiveDate, Utc};
+
+#[cfg(feature = "network")]
+use crate::fileio::NdjsonWriter;
+use crate::fileio::{self, DataScope, SchemaVersioned};
+use crate::integrations::{self, DeribitDataContainer, DeribitOptionInstrument, DeribitTickerData};
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+/// The quote log is kept with the market's data rather than a snapshot's, since it builds up across days.
+const QUOTE_LOG_DIRECTORY: &str = "quote-log/";
+
+/// How many days before the requested time quotes are looked for. An instrument not quoted within this is left out.
+const QUOTE_LOG_LOOKBACK_DAYS: u64 = 1;
+
+/// One line of the quote log.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct QuoteLogEntry {
+    /// The instrument, without its ticker data.
+    instrument: DeribitOptionInstrument,
+    timestamp: DateTime<Utc>,
+    quote: DeribitTickerData,
+}
+
+/// Appends quotes to the quote log as they are fetched, one file per day (UTC) named after the day, e.g.
+/// quote-log/2026-03-01.ndjson. Files are only ever added to, so collection can carry on for as long as needed without
+/// rewriting what has been collected.
+#[cfg(feature = "network")]
+pub struct QuoteLogWriter {
+    day: Option<NaiveDate>,
+    writer: Option<NdjsonWriter>,
+}
+
+#[cfg(feature = "network")]
+impl QuoteLogWriter {
+    pub fn open() -> Result<Self, TsError> {
+        fileio::create_directory(&fileio::get_data_path(DataScope::Market, QUOTE_LOG_DIRECTORY))?;
+
+        Ok(Self { day: None, writer: None })
+    }
+
+    /// Append the option's quote to the file of the day it was quoted.
+    pub fn write(&mut self, option: &DeribitOptionInstrument) -> Result<(), TsError> {
+        let quote = option.ticker_data.clone().ok_or(TsError::new(
+            RuntimeError,
+            format!("{} has no ticker data to add to the quote log", option.instrument_name),
+        ))?;
+        let timestamp = DateTime::from_timestamp_millis(quote.timestamp as i64).ok_or(TsError::new(
+            RuntimeError,
+            format!("{} has an invalid ticker timestamp {}", option.instrument_name, quote.timestamp),
+        ))?;
+        let day = timestamp.date_naive();
+
+        if self.day != Some(day) {
+            self.writer = Some(NdjsonWriter::append(&get_quote_log_path(day))?);
+            self.day = Some(day);
+        }
+
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or(TsError::new(RuntimeError, "Quote log file isn't open"))?;
+
+        writer.write(&QuoteLogEntry {
+            instrument: DeribitOptionInstrument {
+                ticker_data: None,
+                ..option.clone()
+            },
+            timestamp,
+            quote,
+        })
+    }
+}
+
+fn get_quote_log_path(day: NaiveDate) -> String {
+    fileio::get_data_path(DataScope::Market, &format!("{QUOTE_LOG_DIRECTORY}{}.ndjson", day.format("%Y-%m-%d")))
+}
+
+/// Rebuild the market data as it was at the given time from the quote log, taking each instrument's latest quote at or
+/// before it. Quotes older than QUOTE_LOG_LOOKBACK_DAYS are left out. Like a downloaded snapshot, every quote is
+/// re-anchored to the latest one, since the quotes weren't all taken at once.
+pub fn load_latest_quotes(as_of: DateTime<Utc>) -> Result<DeribitDataContainer, TsError> {
+    println!("Reading the quote log as of {}...", as_of.to_rfc3339());
+
+    let oldest_allowed = as_of
+        .checked_sub_days(Days::new(QUOTE_LOG_LOOKBACK_DAYS))
+        .ok_or(TsError::new(RuntimeError, format!("Invalid quote log time {as_of}")))?;
+    let mut latest_entries = HashMap::<Box<str>, QuoteLogEntry>::new();
+    let mut quotes_read = 0;
+
+    for day in oldest_allowed
+        .date_naive()
+        .iter_days()
+        .take_while(|x| *x <= as_of.date_naive())
+    {
+        let path = get_quote_log_path(day);
+
+        if !fileio::file_exists(&path) {
+            continue;
+        }
+
+        println!("Reading {path}...");
+
+        for entry in fileio::read_ndjson_file::<QuoteLogEntry>(&path)? {
+            let entry = match entry {
+                Ok(v) => v,
+                Err(e) => {
+                    // Most likely the program died halfway through writing this line.
+                    println!("Ignoring unreadable quote log data: {}...", e.reason);
+                    continue;
+                }
+            };
+            quotes_read += 1;
+
+            if entry.timestamp > as_of || entry.timestamp < oldest_allowed {
+                continue;
+            }
+
+            let is_latest = latest_entries
+                .get(&entry.instrument.instrument_name)
+                .is_none_or(|x| x.timestamp <= entry.timestamp);
+
+            if is_latest {
+                latest_entries.insert(entry.instrument.instrument_name.clone(), entry);
+            }
+        }
+    }
+
+    if latest_entries.is_empty() {
+        return Err(TsError::new(RuntimeError, format!("The quote log has no quotes from {oldest_allowed} to {as_of}")));
+    }
+
+    let mut options = latest_entries
+        .into_values()
+        .map(|mut entry| {
+            entry.instrument.ticker_data = Some(entry.quote);
+            entry.instrument
+        })
+        .collect::<Vec<DeribitOptionInstrument>>();
+
+    integrations::sort_options(&mut options);
+    println!("Found the latest quotes of {} options among {quotes_read} quotes", options.len());
+    integrations::reanchor_options(&mut options)?;
+
+    let snapshot_time = integrations::get_snapshot_time(&options);
+
+    Ok(DeribitDataContainer {
+        schema_version: DeribitDataContainer::SCHEMA_VERSION,
+        options,
+        snapshot_time,
+        // Futures aren't logged, so forward prices come from the options' own underlying prices.
+        futures: Vec::new(),
+    })
+}
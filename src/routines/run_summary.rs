@@ -0,0 +1,203 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+
+use crate::fileio::{self, DataScope};
+use crate::helpers;
+use crate::types::TsError;
+
+const LAST_RUN_FILE_NAME: &str = "last-run.json";
+
+/// The exit code of a command that panicked rather than returning an error, the same as Rust's own.
+const PANIC_EXIT_CODE: i32 = 101;
+
+/// The exit code of a command stopped early by Ctrl-C, the same as a shell gives a process killed by SIGINT.
+const CANCELLED_EXIT_CODE: i32 = 130;
+
+#[derive(Clone, Copy, PartialEq, Debug, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Succeeded,
+    Failed,
+    /// Stopped early (e.g. by Ctrl-C), keeping the work finished by then.
+    Cancelled,
+}
+
+#[derive(serde::Serialize)]
+pub struct RunStep {
+    pub name: String,
+    pub status: RunStatus,
+    /// Whether the command stops if the step fails. Steps that only report on the results don't stop it.
+    pub required: bool,
+    pub duration_seconds: f64,
+    pub error: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct RunError {
+    /// The type of error (see TsErrorType), or "panic" if the command panicked.
+    pub category: String,
+    pub message: String,
+}
+
+/// What a command did and how it ended, saved to last-run.json in the data directory when it finishes so that scripts can react to
+/// failures (and partial failures) without reading its output.
+#[derive(serde::Serialize)]
+pub struct RunSummary {
+    pub command: String,
+    pub started_at: DateTime<Utc>,
+    pub duration_seconds: f64,
+    pub status: RunStatus,
+    /// See TsErrorType::exit_code().
+    pub exit_code: i32,
+    pub error: Option<RunError>,
+    /// How many of each thing the command worked with, e.g. options or fitted smiles. Only recorded by some commands.
+    pub counts: BTreeMap<&'static str, usize>,
+    /// How well the command did, e.g. the surface's average fit error. Only recorded by some commands.
+    pub measurements: BTreeMap<&'static str, f64>,
+    /// The steps the command got through, in order. Only recorded by some commands.
+    pub steps: Vec<RunStep>,
+    #[serde(skip)]
+    start: Instant,
+    #[serde(skip)]
+    cancelled: bool,
+}
+
+impl RunSummary {
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: command.to_string(),
+            started_at: helpers::get_now(),
+            duration_seconds: 0.0,
+            status: RunStatus::Succeeded,
+            exit_code: 0,
+            error: None,
+            counts: BTreeMap::new(),
+            measurements: BTreeMap::new(),
+            steps: Vec::new(),
+            start: Instant::now(),
+            cancelled: false,
+        }
+    }
+
+    /// Run a step that the command can't go on without. A failure is recorded and returned, with the step's name added
+    /// to it as context.
+    pub fn step<T>(&mut self, name: &str, f: impl FnOnce() -> Result<T, TsError>) -> Result<T, TsError> {
+        self.run_step(name, true, f)
+            .map_err(|e| e.context(format!("{name} failed")))
+    }
+
+    /// Run a step that only reports on the results, so shouldn't stop the command if it fails. A failure is recorded
+    /// and printed.
+    pub fn optional_step<T>(&mut self, name: &str, f: impl FnOnce() -> Result<T, TsError>) -> Option<T> {
+        self.run_step(name, false, f)
+            .inspect_err(|e| println!("{name} failed: {}...", e.reason))
+            .ok()
+    }
+
+    /// Like step(), but for a step that has to be awaited, such as a download.
+    pub async fn step_async<T>(&mut self, name: &str, f: impl Future<Output = Result<T, TsError>>) -> Result<T, TsError> {
+        let start = Instant::now();
+        let result = f.await;
+
+        self.record_step(name, true, start, &result);
+        result.map_err(|e| e.context(format!("{name} failed")))
+    }
+
+    fn run_step<T>(&mut self, name: &str, required: bool, f: impl FnOnce() -> Result<T, TsError>) -> Result<T, TsError> {
+        let start = Instant::now();
+        let result = f();
+
+        self.record_step(name, required, start, &result);
+        result
+    }
+
+    fn record_step<T>(&mut self, name: &str, required: bool, start: Instant, result: &Result<T, TsError>) {
+        self.steps.push(RunStep {
+            name: name.to_string(),
+            status: match result {
+                Ok(_) => RunStatus::Succeeded,
+                Err(_) => RunStatus::Failed,
+            },
+            required,
+            duration_seconds: start.elapsed().as_secs_f64(),
+            error: result.as_ref().err().map(|x| x.reason.clone()),
+        });
+    }
+
+    pub fn count(&mut self, name: &'static str, count: usize) {
+        self.counts.insert(name, count);
+    }
+
+    /// Add to a count, for things counted as they happen (e.g. the runs of a long-running command).
+    pub fn add_to_count(&mut self, name: &'static str, count: usize) {
+        *self.counts.entry(name).or_default() += count;
+    }
+
+    pub fn measure(&mut self, name: &'static str, value: f64) {
+        self.measurements.insert(name, value);
+    }
+
+    /// Record that the command was stopped early. Unless it also fails, it ends as cancelled rather than succeeded.
+    pub fn cancel(&mut self) {
+        self.cancelled = true;
+    }
+
+    /// Record how the command ended, save the summary (printing it too if asked), and return the exit code.
+    pub fn finish(mut self, result: std::thread::Result<Result<(), TsError>>, print_json: bool) -> i32 {
+        self.duration_seconds = self.start.elapsed().as_secs_f64();
+
+        let error = match result {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => {
+                println!("{} failed: {}", self.command, e.reason);
+                self.exit_code = e.error_type.exit_code();
+
+                Some(RunError {
+                    category: e.error_type.to_string(),
+                    message: e.reason,
+                })
+            }
+            // The panic has already been printed.
+            Err(payload) => {
+                self.exit_code = PANIC_EXIT_CODE;
+
+                Some(RunError {
+                    category: "panic".to_string(),
+                    message: get_panic_message(payload.as_ref()),
+                })
+            }
+        };
+
+        if error.is_some() {
+            self.status = RunStatus::Failed;
+        } else if self.cancelled {
+            println!("{} was cancelled, keeping the work finished before it stopped", self.command);
+            self.status = RunStatus::Cancelled;
+            self.exit_code = CANCELLED_EXIT_CODE;
+        }
+        self.error = error;
+
+        fileio::save_struct_to_file(&self, &fileio::get_data_path(DataScope::Global, LAST_RUN_FILE_NAME))
+            .unwrap_or_else(|e| println!("Failed saving run summary: {}...", e.reason));
+
+        if print_json {
+            match serde_json::to_string_pretty(&self) {
+                Ok(json) => println!("{json}"),
+                Err(e) => println!("Failed printing run summary: {e}..."),
+            }
+        }
+
+        self.exit_code
+    }
+}
+
+fn get_panic_message(payload: &(dyn Any + Send)) -> String {
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        (None, None) => "Unknown panic".to_string(),
+    }
+}
@@ -0,0 +1,153 @@
+use chrono::{DateTime, Utc};
+
+use crate::analytics::{self, OptionInstrument, QuoteArbitrage, QuoteArbitrageType};
+use crate::config::Config;
+use crate::fileio::DataScope;
+use crate::helpers::{Clock, F64Helpers};
+use crate::integrations::DeribitDataContainer;
+use crate::routines::RunSummary;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+use crate::{constants, fileio, helpers, integrations};
+
+/// Prefixed with the exchange and currency (see fileio::get_market_data_path()).
+const MARKET_DATA_FILE_NAME: &str = "market-data.json";
+const REPORT_FILE_NAME: &str = "quote-arbitrage-report.json";
+
+/// The arbitrages found in a snapshot's quotes.
+#[derive(serde::Serialize)]
+struct QuoteArbitrageReport {
+    snapshot_time: DateTime<Utc>,
+    scanned_options: usize,
+    /// Only those with at least --min-edge of edge.
+    arbitrages: Vec<QuoteArbitrage>,
+}
+
+pub fn scan_arbitrage(args: &[String], config: &Config, run: &mut RunSummary) -> Result<(), TsError> {
+    println!("===============================================================");
+    println!("===============================================================");
+    println!("Scanning the downloaded quotes for arbitrage");
+    println!("===============================================================");
+    println!("===============================================================");
+
+    let min_edge = get_min_edge(args).map_err(|e| e.context("Invalid arguments"))?;
+
+    let (options, snapshot_time) = run.step("Loading market data", || load_options(config))?;
+    println!("------------------------------");
+
+    let mut arbitrages = run.step("Scanning for arbitrage", || {
+        analytics::scan_quote_arbitrage(&options, constants::INTEREST_FREE_RATE)
+    })?;
+    arbitrages.retain(|x| x.edge >= min_edge);
+    run.count("arbitrages", arbitrages.len());
+
+    print_arbitrages(&arbitrages);
+    println!("------------------------------");
+
+    let report = QuoteArbitrageReport {
+        snapshot_time,
+        scanned_options: options.len(),
+        arbitrages,
+    };
+    let report_path = fileio::get_data_path(DataScope::Snapshot, REPORT_FILE_NAME);
+    println!("Saving {report_path}...");
+    run.step("Saving report", || fileio::save_struct_to_file(&report, &report_path))?;
+    println!("===============================================================");
+
+    Ok(())
+}
+
+/// The least edge (USD) an arbitrage needs to be reported, so that ones too small to trade after fees can be left out.
+fn get_min_edge(args: &[String]) -> Result<f64, TsError> {
+    match helpers::get_flag_value(args, "--min-edge")? {
+        None => Ok(0.0),
+        Some(v) => v
+            .parse::<f64>()
+            .ok()
+            .filter(|x| x.is_finite() && *x >= 0.0)
+            .ok_or(TsError::new(RuntimeError, format!("Invalid --min-edge {v}, expected a number of USD >= 0"))),
+    }
+}
+
+/// Load the snapshot's options with their raw quotes, named as Deribit names them and with times until expiry measured
+/// from when the snapshot was taken. Each option's forward price is Deribit's underlying price for its expiry. Options that
+/// can't be used are skipped.
+fn load_options(config: &Config) -> Result<(Vec<OptionInstrument>, DateTime<Utc>), TsError> {
+    let path = fileio::find_data_file(&fileio::get_market_data_path(DataScope::Snapshot, MARKET_DATA_FILE_NAME));
+    println!("Loading {path}...");
+
+    let data = fileio::load_versioned_struct_from_file::<DeribitDataContainer>(&path)?;
+    let snapshot_time = data
+        .snapshot_time
+        .or(integrations::get_snapshot_time(&data.options))
+        .ok_or(TsError::new(RuntimeError, "None of the options have ticker data"))?;
+    let mut options = Vec::with_capacity(data.options.len());
+    let mut skipped_options = 0;
+
+    for api_option in &data.options {
+        let mut option = match api_option.to_option(&config.deribit) {
+            Ok(v) => v,
+            Err(_) => {
+                skipped_options += 1;
+                continue;
+            }
+        };
+
+        option.instrument_id = api_option.instrument_name.clone();
+        option.set_clock(Clock::Fixed(snapshot_time));
+
+        if let Some(forward_price) = api_option.get_underlying_price() {
+            option.set_forward_price(forward_price);
+        }
+
+        options.push(option);
+    }
+
+    println!(
+        "Loaded {} options quoted at {} (skipped {skipped_options} unusable)",
+        options.len(),
+        helpers::format_time(snapshot_time)
+    );
+
+    Ok((options, snapshot_time))
+}
+
+fn print_arbitrages(arbitrages: &[QuoteArbitrage]) {
+    for arbitrage_type in [
+        QuoteArbitrageType::VerticalSpread,
+        QuoteArbitrageType::Butterfly,
+        QuoteArbitrageType::Box,
+        QuoteArbitrageType::Calendar,
+    ] {
+        let count = arbitrages
+            .iter()
+            .filter(|x| x.arbitrage_type == arbitrage_type)
+            .count();
+        println!("Found {count} {} arbitrages", arbitrage_type.name());
+    }
+
+    if arbitrages.is_empty() {
+        return;
+    }
+
+    println!("------------------------------");
+    println!("Type            | Edge (USD) | Trades");
+
+    for arbitrage in arbitrages {
+        let trades = arbitrage
+            .legs
+            .iter()
+            .map(|x| {
+                let side = if x.quantity > 0.0 { "buy" } else { "sell" };
+                format!("{side} {} {}", x.quantity.abs().round_to_decimal_places(4), x.instrument_id)
+            })
+            .collect::<Vec<String>>();
+
+        println!(
+            "{:<15} | {:<10} | {}",
+            arbitrage.arbitrage_type.name(),
+            arbitrage.edge.round_to_decimal_places(2),
+            trades.join(", ")
+        );
+    }
+}
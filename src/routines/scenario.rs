@@ -0,0 +1,265 @@
+use chrono::{DateTime, NaiveTime, Utc};
+
+use crate::analytics::{self, OptionType, PortfolioPosition, PositionValuation, ScenarioShock, SmileGraph, SpotMoveRule};
+use crate::config::Config;
+use crate::fileio::{CsvFormat, DataScope};
+use crate::helpers::F64Helpers;
+use crate::routines::{RunSummary, surface_file};
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+use crate::{fileio, helpers};
+
+const RESULTS_FILE_NAME: &str = "scenario-results.json";
+
+/// The default number of strikes the shocked surface is saved at per expiry.
+const DEFAULT_STRIKE_POINTS: u64 = 25;
+
+/// Deribit options expire at 08:00 UTC, so portfolio expiries are assumed to as well.
+const EXPIRY_TIME: NaiveTime = NaiveTime::from_hms_opt(8, 0, 0).expect("Expiry time should be valid");
+
+/// Options for running scenarios, set via command line flags.
+struct ScenarioOptions {
+    /// A CSV file of options held, whose P&L is calculated under each scenario.
+    portfolio_path: Option<String>,
+    csv_format: CsvFormat,
+    scenarios: Vec<ScenarioShock>,
+    /// The number of evenly spaced strikes the shocked surface is saved at per expiry, covering the observed strike range.
+    strike_points: u64,
+}
+
+impl ScenarioOptions {
+    fn from_args(args: &[String], config: &Config) -> Result<Self, TsError> {
+        let mut scenarios = config.scenarios.clone();
+
+        // Shocks given on the command line make one more scenario.
+        if ["--spot-move", "--vol-shift", "--skew-twist", "--days"]
+            .iter()
+            .any(|x| helpers::has_flag(args, x))
+        {
+            let scenario = ScenarioShock {
+                name: "Command line".to_string(),
+                volatility_shift: parse_f64_flag(args, "--vol-shift")?,
+                skew_twist: parse_f64_flag(args, "--skew-twist")?,
+                spot_move: parse_f64_flag(args, "--spot-move")?,
+                spot_move_rule: match helpers::has_flag(args, "--sticky-delta") {
+                    true => SpotMoveRule::StickyDelta,
+                    false => SpotMoveRule::StickyStrike,
+                },
+                days_passed: parse_f64_flag(args, "--days")?,
+            };
+            scenario.check_valid()?;
+            scenarios.push(scenario);
+        }
+
+        if let Some(name) = helpers::get_flag_value(args, "--name")? {
+            scenarios.retain(|x| x.name == name);
+
+            if scenarios.is_empty() {
+                return Err(TsError::new(RuntimeError, format!("No scenario named {name} is configured")));
+            }
+        }
+
+        if scenarios.is_empty() {
+            return Err(TsError::new(
+                RuntimeError,
+                "No scenarios to run, add some to the config's scenarios or pass shocks on the command line",
+            ));
+        }
+
+        Ok(Self {
+            portfolio_path: helpers::get_flag_value(args, "--portfolio")?.map(|x| x.to_string()),
+            csv_format: CsvFormat::from_args(args)?,
+            scenarios,
+            strike_points: match helpers::get_flag_value(args, "--strike-points")? {
+                None => DEFAULT_STRIKE_POINTS,
+                Some(v) => v
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|x| *x >= 2)
+                    .ok_or(TsError::new(RuntimeError, format!("--strike-points must be a whole number >= 2, found {v}")))?,
+            },
+        })
+    }
+}
+
+fn parse_f64_flag(args: &[String], flag: &str) -> Result<f64, TsError> {
+    match helpers::get_flag_value(args, flag)? {
+        None => Ok(0.0),
+        Some(v) => v
+            .parse::<f64>()
+            .map_err(|e| TsError::new(RuntimeError, format!("Invalid {flag} {v}: {e}")).with_source(e)),
+    }
+}
+
+/// The results of one scenario, as saved to file.
+#[derive(serde::Serialize)]
+struct ScenarioResult {
+    scenario: ScenarioShock,
+    /// Missing if no portfolio was given.
+    total_pnl: Option<f64>,
+    positions: Vec<PositionValuation>,
+    surface: Vec<ShockedSurfacePoint>,
+}
+
+/// The surface before and after a shock at one expiry and strike.
+#[derive(serde::Serialize)]
+struct ShockedSurfacePoint {
+    expiry: DateTime<Utc>,
+    strike: f64,
+    implied_volatility: f64,
+    /// Missing if the expiry passes during the scenario, or the shocks make the implied volatility negative.
+    shocked_implied_volatility: Option<f64>,
+}
+
+/// Apply each configured scenario's shocks to the saved surface, saving the shocked surface and printing the P&L of a
+/// portfolio of options under each.
+pub fn scenario(args: &[String], config: &Config, run: &mut RunSummary) -> Result<(), TsError> {
+    let scenario_options = ScenarioOptions::from_args(args, config).map_err(|e| e.context("Invalid arguments"))?;
+
+    println!("===============================================================");
+    println!("===============================================================");
+    println!("Running scenarios on the fitted surface");
+    println!("===============================================================");
+    println!("===============================================================");
+
+    println!("Loading surface data...");
+    let smile_graphs = run.step("Loading surface data", || Ok(surface_file::load_surface_grid(config)?.into_smile_graphs()))?;
+
+    let positions = match &scenario_options.portfolio_path {
+        Some(path) => run.step("Loading portfolio", || load_portfolio(path, scenario_options.csv_format))?,
+        None => Vec::new(),
+    };
+    println!("------------------------------");
+
+    let mut results = Vec::new();
+
+    for scenario in &scenario_options.scenarios {
+        let result = run.step(&format!("Running scenario {}", scenario.name), || {
+            run_scenario(
+                &smile_graphs,
+                &positions,
+                scenario,
+                scenario_options.portfolio_path.is_some(),
+                scenario_options.strike_points,
+            )
+        })?;
+
+        print_result(&result);
+        println!("------------------------------");
+        results.push(result);
+    }
+
+    let results_path = fileio::get_data_path(DataScope::Snapshot, RESULTS_FILE_NAME);
+    println!("Saving {results_path}...");
+    run.step("Saving results", || fileio::save_struct_to_file(&results, &results_path))?;
+    println!("===============================================================");
+
+    Ok(())
+}
+
+/// Load a portfolio from a CSV file with the columns strike, expiry, type (call or put) and quantity (negative if short).
+fn load_portfolio(path: &str, format: CsvFormat) -> Result<Vec<PortfolioPosition>, TsError> {
+    println!("Loading portfolio from {path}...");
+
+    let table = fileio::load_csv_file(path, format)?;
+    let positions = (0..table.rows_count())
+        .map(|row| {
+            let strike = table.get_f64(row, "strike")?;
+            helpers::error_unless_positive_f64(strike, "strike")?;
+
+            Ok(PortfolioPosition {
+                strike,
+                expiry: table
+                    .get_date(row, "expiry")?
+                    .and_time(EXPIRY_TIME)
+                    .and_utc(),
+                option_type: OptionType::try_from(table.get_str(row, "type")?)?,
+                quantity: table.get_f64(row, "quantity")?,
+            })
+        })
+        .collect::<Result<Vec<PortfolioPosition>, TsError>>()?;
+
+    println!("Found {} positions", positions.len());
+
+    Ok(positions)
+}
+
+fn run_scenario(
+    smile_graphs: &[SmileGraph],
+    positions: &[PortfolioPosition],
+    scenario: &ScenarioShock,
+    has_portfolio: bool,
+    strike_points: u64,
+) -> Result<ScenarioResult, TsError> {
+    let valuations = analytics::value_portfolio(smile_graphs, positions, scenario, helpers::get_now())?;
+    let unshocked = ScenarioShock::default();
+    let mut surface = Vec::new();
+
+    for graph in smile_graphs {
+        let years_until_expiry = graph.get_years_until_expiry()?;
+        let strike_range = graph.highest_observed_strike - graph.lowest_observed_strike;
+
+        for i in 0..strike_points {
+            let strike = graph.lowest_observed_strike + strike_range * (i as f64 / (strike_points - 1) as f64);
+
+            surface.push(ShockedSurfacePoint {
+                expiry: graph.get_expiration()?,
+                strike,
+                implied_volatility: unshocked
+                    .get_shocked_point(smile_graphs, strike, years_until_expiry)?
+                    .implied_volatility,
+                shocked_implied_volatility: scenario
+                    .get_shocked_point(smile_graphs, strike, years_until_expiry)
+                    .ok()
+                    .map(|x| x.implied_volatility),
+            });
+        }
+    }
+
+    Ok(ScenarioResult {
+        scenario: scenario.clone(),
+        total_pnl: has_portfolio.then(|| valuations.iter().map(|x| x.pnl).sum()),
+        positions: valuations,
+        surface,
+    })
+}
+
+fn print_result(result: &ScenarioResult) {
+    let scenario = &result.scenario;
+
+    println!("Scenario: {}", scenario.name);
+    println!(
+        "Spot move {}% ({:?}), volatility shift {} vol points, skew twist {}, {} days passed",
+        (scenario.spot_move * 100.0).round_to_decimal_places(2),
+        scenario.spot_move_rule,
+        (scenario.volatility_shift * 100.0).round_to_decimal_places(2),
+        scenario.skew_twist,
+        scenario.days_passed
+    );
+
+    let Some(total_pnl) = result.total_pnl else {
+        return;
+    };
+
+    println!("Expiry                     | Strike   | Type | Quantity | Base price | Shocked price | P&L");
+
+    for valuation in &result.positions {
+        let position = &valuation.position;
+
+        println!(
+            "{:<26} | {:<8} | {:<4} | {:<8} | {:<10} | {:<13} | {}",
+            helpers::format_time(position.expiry),
+            position.strike,
+            match position.option_type {
+                OptionType::Call => "call",
+                OptionType::Put => "put",
+            },
+            position.quantity,
+            valuation.base_price.round_to_decimal_places(2),
+            valuation.shocked_price.round_to_decimal_places(2),
+            valuation.pnl.round_to_decimal_places(2),
+        );
+    }
+
+    println!("Total P&L: {}", total_pnl.round_to_decimal_places(2));
+}
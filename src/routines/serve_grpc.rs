@@ -0,0 +1,610 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::fs;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use tokio::sync::watch;
+use tokio_stream::wrappers::{TcpListenerStream, WatchStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+use crate::analytics::{self, SurfaceGrid, WingExtrapolation};
+use crate::config::Config;
+use crate::fileio::{self, DataPaths, DataScope};
+use crate::helpers::{self, Clock};
+use crate::routines::{RunSummary, surface_file};
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+/// The service and messages generated from proto/theta_surface.proto by build.rs.
+mod proto {
+    tonic::include_proto!("theta_surface.v1");
+}
+
+/// The address the server listens on if --address isn't given. Only this machine can connect to it.
+const DEFAULT_ADDRESS: &str = "127.0.0.1:50051";
+
+/// How often the saved surface is checked for a new one if --poll-seconds isn't given.
+const DEFAULT_POLL_SECONDS: u64 = 5;
+
+/// The metrics file, in the Prometheus text format, as for the daemon's.
+const METRICS_FILE_NAME: &str = "grpc-metrics.prom";
+
+/// How many of the latest queries the latency percentiles are worked out from.
+const LATENCY_WINDOW: usize = 1000;
+
+/// Options for serving surfaces, set via command line flags.
+struct ServeGrpcOptions {
+    address: SocketAddr,
+    poll_interval: Duration,
+    /// Whether --snapshot-date was given, in which case only that snapshot's surface is served, rather than following
+    /// the latest snapshot as new ones are started.
+    fixed_snapshot: bool,
+    /// Load the latest surface before serving, and each new one as soon as it's saved, rather than when a client first
+    /// needs it.
+    preload: bool,
+}
+
+impl ServeGrpcOptions {
+    fn from_args(args: &[String]) -> Result<Self, TsError> {
+        let address = helpers::get_flag_value(args, "--address")?.unwrap_or(DEFAULT_ADDRESS);
+
+        Ok(Self {
+            address: address
+                .parse::<SocketAddr>()
+                .map_err(|e| TsError::new(RuntimeError, format!("Invalid --address {address}: {e}")).with_source(e))?,
+            poll_interval: Duration::from_secs(match helpers::get_flag_value(args, "--poll-seconds")? {
+                None => DEFAULT_POLL_SECONDS,
+                Some(v) => v
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|x| *x > 0)
+                    .ok_or(TsError::new(RuntimeError, format!("Invalid --poll-seconds {v}, expected a whole number above 0")))?,
+            }),
+            fixed_snapshot: helpers::get_flag_value(args, "--snapshot-date")?.is_some(),
+            preload: helpers::has_flag(args, "--preload"),
+        })
+    }
+}
+
+/// A snapshot's surface, loaded from its grid.
+struct LoadedSurface {
+    /// When the grid was last modified as of loading it, so that a newer one can be told apart.
+    modified: SystemTime,
+    /// With the snapshot date of the surface.
+    data_paths: DataPaths,
+    grid: SurfaceGrid,
+    /// What times until expiry are measured from, which the grid's clock is set to: when the surface was built, or when
+    /// it was loaded if it doesn't say. The real clock stays at when the server started, so it can't be used.
+    evaluation_time: DateTime<Utc>,
+}
+
+/// How long queries have taken to answer, for the metrics file.
+#[derive(Default)]
+struct QueryLatencies {
+    /// The latest LATENCY_WINDOW, in seconds, oldest first.
+    recent: VecDeque<f64>,
+    count: u64,
+    total_seconds: f64,
+}
+
+impl QueryLatencies {
+    fn record(&mut self, latency: Duration) {
+        if self.recent.len() == LATENCY_WINDOW {
+            self.recent.pop_front();
+        }
+
+        self.recent.push_back(latency.as_secs_f64());
+        self.count += 1;
+        self.total_seconds += latency.as_secs_f64();
+    }
+
+    /// The latencies in the Prometheus text exposition format, as a summary with the P50 and P99 of the latest queries.
+    fn to_prometheus_text(&self) -> String {
+        let mut text = String::new();
+        let mut sorted: Vec<f64> = self.recent.iter().copied().collect();
+
+        sorted.sort_by(f64::total_cmp);
+
+        let _ = writeln!(
+            text,
+            "# HELP theta_surface_grpc_query_latency_seconds How long QuerySurface took to answer, with quantiles over the \
+             latest {LATENCY_WINDOW} queries."
+        );
+        let _ = writeln!(text, "# TYPE theta_surface_grpc_query_latency_seconds summary");
+
+        if !sorted.is_empty() {
+            for quantile in [0.5, 0.99] {
+                let _ = writeln!(
+                    text,
+                    "theta_surface_grpc_query_latency_seconds{{quantile=\"{quantile}\"}} {}",
+                    analytics::get_quantile(&sorted, quantile)
+                );
+            }
+        }
+
+        let _ = writeln!(text, "theta_surface_grpc_query_latency_seconds_sum {}", self.total_seconds);
+        let _ = writeln!(text, "theta_surface_grpc_query_latency_seconds_count {}", self.count);
+
+        text
+    }
+}
+
+/// Streams the latest surface to every client watching, as it's replaced, and answers queries of any snapshot's surface.
+struct SurfaceFeedService {
+    /// As resolved when the server started, whose snapshot date is only kept if --snapshot-date gave it.
+    data_paths: DataPaths,
+    fixed_snapshot: bool,
+    wing_extrapolation: WingExtrapolation,
+    /// By the path of their surface data. Each is loaded the first time it's needed, and again once a newer one is saved.
+    surfaces: Mutex<HashMap<String, Arc<LoadedSurface>>>,
+    /// The latest surface, as sent to the clients watching. Only they subscribe to it, so its receivers are them.
+    latest: watch::Sender<Option<Arc<proto::Surface>>>,
+    query_latencies: Mutex<QueryLatencies>,
+}
+
+impl SurfaceFeedService {
+    fn new(data_paths: DataPaths, options: &ServeGrpcOptions, config: &Config) -> Self {
+        Self {
+            data_paths,
+            fixed_snapshot: options.fixed_snapshot,
+            wing_extrapolation: config.wing_extrapolation,
+            surfaces: Mutex::new(HashMap::new()),
+            latest: watch::Sender::new(None),
+            query_latencies: Mutex::new(QueryLatencies::default()),
+        }
+    }
+
+    /// The data paths of the given snapshot, or if none is given, of the latest (or the one given by --snapshot-date).
+    fn resolve_data_paths(&self, snapshot_date: Option<NaiveDate>) -> Result<DataPaths, TsError> {
+        let mut data_paths = self.data_paths.clone();
+
+        if snapshot_date.is_some() || !self.fixed_snapshot {
+            data_paths.snapshot_date = snapshot_date;
+            data_paths.resolve_snapshot_date(false)?;
+        }
+
+        Ok(data_paths)
+    }
+
+    /// The snapshot's surface, loading only it if it hasn't been loaded, or a newer one has been saved since. None if no
+    /// surface grid has been saved for it.
+    fn load_surface(&self, data_paths: DataPaths) -> Result<Option<Arc<LoadedSurface>>, TsError> {
+        let path = format!("{}/smile-graph-data.json", data_paths.get_directory(DataScope::Snapshot));
+
+        // Surfaces are only read from their grids, which are saved last, so that one isn't read part way through being
+        // saved.
+        let Ok(modified) = fs::metadata(surface_file::find_surface_grid_file(&path)).and_then(|x| x.modified()) else {
+            return Ok(None);
+        };
+
+        if let Some(surface) = self
+            .lock_surfaces()
+            .get(&path)
+            .filter(|x| x.modified == modified)
+        {
+            return Ok(Some(surface.clone()));
+        }
+
+        let mut grid = surface_file::load_surface_grid_at(&path, self.wing_extrapolation)?;
+        let evaluation_time = grid
+            .build_info
+            .as_ref()
+            .map_or(Utc::now(), |x| x.evaluation_time);
+
+        grid.set_clock(Clock::Fixed(evaluation_time));
+
+        let surface = Arc::new(LoadedSurface {
+            modified,
+            data_paths,
+            grid,
+            evaluation_time,
+        });
+
+        self.lock_surfaces().insert(path, surface.clone());
+
+        Ok(Some(surface))
+    }
+
+    /// Not held while a surface loads, so that queries of surfaces already loaded aren't held up by it.
+    fn lock_surfaces(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<LoadedSurface>>> {
+        self.surfaces.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_query_latencies(&self) -> std::sync::MutexGuard<'_, QueryLatencies> {
+        self.query_latencies
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn query(&self, request: &proto::QuerySurfaceRequest) -> Result<proto::QuerySurfaceResponse, Status> {
+        let snapshot_date = match request.snapshot_date.as_str() {
+            "" => None,
+            date => Some(
+                NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                    .map_err(|e| Status::invalid_argument(format!("Invalid snapshot date {date}: {e}")))?,
+            ),
+        };
+        let expiry = DateTime::<Utc>::from_timestamp(request.expiry, 0)
+            .ok_or(Status::invalid_argument(format!("Invalid expiry {}", request.expiry)))?;
+        let surface = self
+            .resolve_data_paths(snapshot_date)
+            .and_then(|x| self.load_surface(x))
+            .map_err(|e| Status::internal(format!("Failed loading the surface: {}", e.reason)))?
+            .ok_or(Status::not_found("No surface has been saved for the snapshot"))?;
+        let years_until_expiry = (expiry - surface.evaluation_time).num_seconds() as f64 / 31556926.0;
+        let point = analytics::interpolate_surface(&surface.grid.get_slices(), request.strike, years_until_expiry)
+            .map_err(|e| Status::invalid_argument(format!("Failed querying the surface: {}", e.reason)))?;
+
+        Ok(proto::QuerySurfaceResponse {
+            snapshot_date: format_snapshot_date(&surface.data_paths),
+            evaluation_time: surface.evaluation_time.timestamp(),
+            years_until_expiry,
+            forward_price: point.forward_price,
+            point: Some(proto::SurfacePoint {
+                log_moneyness: point.log_moneyness,
+                strike: request.strike,
+                total_variance: point.total_variance,
+                implied_volatility: point.implied_volatility,
+            }),
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl proto::surface_feed_server::SurfaceFeed for SurfaceFeedService {
+    type WatchSurfacesStream = Pin<Box<dyn Stream<Item = Result<proto::Surface, Status>> + Send>>;
+
+    /// A client that falls behind skips straight to the latest surface rather than being sent every one in between.
+    async fn watch_surfaces(
+        &self,
+        _request: Request<proto::WatchSurfacesRequest>,
+    ) -> Result<Response<Self::WatchSurfacesStream>, Status> {
+        let stream = WatchStream::new(self.latest.subscribe()).filter_map(|x| x.map(|surface| Ok(Arc::unwrap_or_clone(surface))));
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    /// The time taken, including loading the surface if it hasn't been, is recorded for the metrics file.
+    async fn query_surface(
+        &self,
+        request: Request<proto::QuerySurfaceRequest>,
+    ) -> Result<Response<proto::QuerySurfaceResponse>, Status> {
+        let started = Instant::now();
+        // Loading a surface reads files, which would otherwise hold up the other requests on this thread.
+        let response = tokio::task::block_in_place(|| self.query(request.get_ref()));
+
+        self.lock_query_latencies().record(started.elapsed());
+
+        response.map(Response::new)
+    }
+}
+
+/// Serve the currency's fitted surfaces over gRPC (see proto/theta_surface.proto): streaming each new one to the clients
+/// watching as soon as it's saved, and answering queries of any snapshot's. build-surface, import-vol-matrix and the
+/// daemon's runs each save the surface in their own process, so the saved surface grid is checked for a new one every few
+/// seconds, following the latest snapshot unless --snapshot-date is given.
+///
+/// Each snapshot's surface is only loaded once it's first needed, unless --preload is given, which loads the latest before
+/// serving. How long queries take is saved in the Prometheus text format, for monitoring as the daemon's metrics are.
+///
+/// It only returns if it fails, e.g. because the address is in use.
+pub fn serve_grpc(args: &[String], config: &Config, run: &mut RunSummary) -> Result<(), TsError> {
+    let options = ServeGrpcOptions::from_args(args).map_err(|e| e.context("Invalid arguments"))?;
+
+    println!("===============================================================");
+    println!("===============================================================");
+    println!("Serving {} surfaces over gRPC on {}", fileio::get_currency().get_name(), options.address);
+    println!("===============================================================");
+    println!("===============================================================");
+
+    let service = Arc::new(SurfaceFeedService::new(config.data_paths.clone(), &options, config));
+
+    if options.preload {
+        let surface = run.step("Preloading surface", || {
+            service
+                .resolve_data_paths(None)
+                .and_then(|x| service.load_surface(x))
+        })?;
+
+        if surface.is_none() {
+            println!("No surface has been saved yet, so there's nothing to preload...");
+        }
+    }
+
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed starting async runtime: {e}")).with_source(e))?;
+    let listener = run.step("Listening", || {
+        runtime
+            .block_on(tokio::net::TcpListener::bind(options.address))
+            .map_err(|e| TsError::new(RuntimeError, format!("Couldn't listen on {}: {e}", options.address)).with_source(e))
+    })?;
+    let server = runtime.spawn(serve(listener, service.clone()));
+    let mut served_surface: Option<Arc<LoadedSurface>> = None;
+    let mut saved_query_count = None;
+
+    loop {
+        if server.is_finished() {
+            return runtime
+                .block_on(server)
+                .map_err(|e| TsError::new(RuntimeError, format!("The gRPC server stopped: {e}")).with_source(e))?
+                .map_err(|e| TsError::new(RuntimeError, format!("The gRPC server stopped: {e}")).with_source(e));
+        }
+
+        // The latest surface is only loaded for the feed while a client is watching it, unless it's preloaded. One that
+        // can't be read yet, e.g. because it's still being saved, is tried again next time.
+        if options.preload || service.latest.receiver_count() > 0 {
+            match service
+                .resolve_data_paths(None)
+                .and_then(|x| service.load_surface(x))
+            {
+                Ok(Some(surface))
+                    if served_surface
+                        .as_ref()
+                        .is_none_or(|x| !Arc::ptr_eq(x, &surface)) =>
+                {
+                    match to_proto_surface(&surface.grid, &surface.data_paths, surface.evaluation_time) {
+                        Ok(message) => {
+                            println!("Serving the surface saved at {}...", helpers::format_time(surface.modified.into()));
+                            service.latest.send_replace(Some(Arc::new(message)));
+                            served_surface = Some(surface);
+                        }
+                        Err(e) => println!("Failed reading the saved surface, trying again: {}...", e.reason),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => println!("Failed reading the saved surface, trying again: {}...", e.reason),
+            }
+        }
+
+        save_metrics(&service, &mut saved_query_count);
+        thread::sleep(options.poll_interval);
+    }
+}
+
+/// Save the metrics file if any queries have been answered since it was last saved. The metrics are only for monitoring,
+/// so failing to save them doesn't stop the server.
+fn save_metrics(service: &SurfaceFeedService, saved_query_count: &mut Option<u64>) {
+    let query_latencies = service.lock_query_latencies();
+
+    if *saved_query_count == Some(query_latencies.count) {
+        return;
+    }
+
+    match fileio::save_text_to_file(
+        &query_latencies.to_prometheus_text(),
+        &fileio::get_data_path(DataScope::Global, METRICS_FILE_NAME),
+    ) {
+        Ok(_) => *saved_query_count = Some(query_latencies.count),
+        Err(e) => println!("Failed saving gRPC metrics: {}...", e.reason),
+    }
+}
+
+/// Serve the service to clients connecting to the listener, until the server fails.
+async fn serve(listener: tokio::net::TcpListener, service: Arc<SurfaceFeedService>) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(proto::surface_feed_server::SurfaceFeedServer::from_arc(service))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await
+}
+
+/// The snapshot date of the data paths as YYYY-MM-DD, or empty if snapshots aren't kept apart.
+fn format_snapshot_date(data_paths: &DataPaths) -> String {
+    data_paths
+        .snapshot_date
+        .map(|x| x.format("%Y-%m-%d").to_string())
+        .unwrap_or_default()
+}
+
+/// The surface's message, with times until expiry measured from the evaluation time, which the grid's clock must be set
+/// to.
+fn to_proto_surface(
+    grid: &SurfaceGrid,
+    data_paths: &DataPaths,
+    evaluation_time: DateTime<Utc>,
+) -> Result<proto::Surface, TsError> {
+    let mut smiles = Vec::with_capacity(grid.rows.len());
+
+    for row in &grid.rows {
+        let years_until_expiry = row.smile_graph.get_years_until_expiry()?;
+        let curve = &row.smile_graph.svi_curve_parameters;
+
+        smiles.push(proto::Smile {
+            expiry: row.expiry.timestamp(),
+            years_until_expiry,
+            forward_price: row.forward_price,
+            lowest_observed_strike: row.smile_graph.lowest_observed_strike,
+            highest_observed_strike: row.smile_graph.highest_observed_strike,
+            svi: Some(proto::SviParameters {
+                a: curve.get_a(),
+                b: curve.get_b(),
+                rho: curve.get_p(),
+                m: curve.get_m(),
+                sigma: curve.get_o(),
+            }),
+            points: grid
+                .log_moneyness
+                .iter()
+                .zip(&row.total_variance)
+                .map(|(log_moneyness, total_variance)| proto::SurfacePoint {
+                    log_moneyness: *log_moneyness,
+                    strike: analytics::log_moneyness_to_strike(*log_moneyness, row.forward_price),
+                    total_variance: *total_variance,
+                    implied_volatility: (total_variance / years_until_expiry).sqrt(),
+                })
+                .collect(),
+        });
+    }
+
+    Ok(proto::Surface {
+        currency: data_paths.currency.get_code().to_string(),
+        snapshot_date: format_snapshot_date(data_paths),
+        evaluation_time: evaluation_time.timestamp(),
+        smiles,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analytics::{OptionInstrument, OptionType, SVICurveParameters, SmileGraph};
+
+    fn get_evaluation_time() -> DateTime<Utc> {
+        DateTime::from_timestamp_secs(1_767_225_600).unwrap()
+    }
+
+    fn get_smile() -> Result<SmileGraph, TsError> {
+        let mut graph = SmileGraph::new();
+
+        graph.options.push(OptionInstrument::new(
+            1.0,
+            2_000_000_000,
+            100.0,
+            "100".into(),
+            OptionType::Call,
+            100.0,
+            Clock::Fixed(get_evaluation_time()),
+        ));
+        graph.lowest_observed_strike = 90.0;
+        graph.highest_observed_strike = 110.0;
+        graph.svi_curve_parameters = SVICurveParameters::new_from_values(0.01, 0.1, -0.3, 0.0, 0.1)?;
+
+        Ok(graph)
+    }
+
+    /// A service reading surfaces saved in the directory, with nothing loaded yet.
+    fn get_service(directory: &str) -> SurfaceFeedService {
+        SurfaceFeedService {
+            data_paths: DataPaths {
+                directory: directory.to_string(),
+                ..DataPaths::default()
+            },
+            fixed_snapshot: false,
+            wing_extrapolation: WingExtrapolation::default(),
+            surfaces: Mutex::new(HashMap::new()),
+            latest: watch::Sender::new(None),
+            query_latencies: Mutex::new(QueryLatencies::default()),
+        }
+    }
+
+    #[test]
+    fn test_to_proto_surface() -> Result<(), TsError> {
+        let grid = SurfaceGrid::new(&[get_smile()?], None)?;
+        let surface = to_proto_surface(&grid, &DataPaths::default(), get_evaluation_time())?;
+        let smile = &surface.smiles[0];
+        let svi = smile.svi.as_ref().unwrap();
+
+        assert_eq!(surface.currency, "BTC");
+        assert_eq!(smile.expiry, 2_000_000_000);
+        assert_eq!((svi.a, svi.b, svi.rho, svi.m, svi.sigma), (0.01, 0.1, -0.3, 0.0, 0.1));
+        assert_eq!(smile.points.len(), grid.log_moneyness.len());
+
+        // The middle column is at the money.
+        let point = &smile.points[smile.points.len() / 2];
+
+        assert!((point.strike - smile.forward_price).abs() < 0.000001);
+        assert!((point.implied_volatility - (point.total_variance / smile.years_until_expiry).sqrt()).abs() < 0.000001);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_surfaces() -> Result<(), TsError> {
+        let grid = SurfaceGrid::new(&[get_smile()?], None)?;
+        let surface = to_proto_surface(&grid, &DataPaths::default(), get_evaluation_time())?;
+        let service = Arc::new(get_service("./data"));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let address = listener.local_addr().unwrap();
+
+        service.latest.send_replace(Some(Arc::new(surface.clone())));
+        runtime.spawn(serve(listener, service.clone()));
+        runtime.block_on(async {
+            let mut client = proto::surface_feed_client::SurfaceFeedClient::connect(format!("http://{address}"))
+                .await
+                .unwrap();
+            let mut stream = client
+                .watch_surfaces(proto::WatchSurfacesRequest {})
+                .await
+                .unwrap()
+                .into_inner();
+
+            // The surface already saved is sent straight away, then each new one as it's saved.
+            assert_eq!(stream.message().await.unwrap(), Some(surface.clone()));
+            assert_eq!(service.latest.receiver_count(), 1);
+
+            let newer = proto::Surface {
+                snapshot_date: "2026-10-17".to_string(),
+                ..surface
+            };
+            service.latest.send_replace(Some(Arc::new(newer.clone())));
+
+            assert_eq!(stream.message().await.unwrap(), Some(newer));
+        });
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_surface() -> Result<(), TsError> {
+        let directory = std::env::temp_dir().join("theta-surface-test-grpc-query");
+        let directory = directory.to_str().unwrap();
+        let smile = get_smile()?;
+
+        fileio::create_directory(directory)?;
+        fileio::save_data_file(
+            &SurfaceGrid::new(std::slice::from_ref(&smile), None)?,
+            &format!("{directory}/surface-grid.json"),
+            false,
+        )?;
+
+        let service = get_service(directory);
+        let request = proto::QuerySurfaceRequest {
+            snapshot_date: String::new(),
+            strike: 95.0,
+            expiry: 2_000_000_000,
+        };
+
+        // The surface is only loaded by the first query.
+        assert!(service.lock_surfaces().is_empty());
+
+        let response = service.query(&request).unwrap();
+        let point = response.point.unwrap();
+        let expected = smile.get_total_variance_at_log_moneyness((95.0 / smile.get_underlying_forward_price()?).ln())?;
+
+        assert_eq!(service.lock_surfaces().len(), 1);
+        assert!((point.total_variance - expected).abs() < 0.0001);
+
+        let invalid = proto::QuerySurfaceRequest {
+            snapshot_date: "yesterday".to_string(),
+            ..request
+        };
+
+        assert_eq!(service.query(&invalid).unwrap_err().code(), tonic::Code::InvalidArgument);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_latencies() {
+        let mut latencies = QueryLatencies::default();
+
+        for milliseconds in 1..=LATENCY_WINDOW as u64 + 100 {
+            latencies.record(Duration::from_millis(milliseconds));
+        }
+
+        let text = latencies.to_prometheus_text();
+
+        // Only the latest queries count towards the percentiles, but all of them towards the count.
+        assert_eq!(latencies.recent.len(), LATENCY_WINDOW);
+        assert!(text.contains("theta_surface_grpc_query_latency_seconds{quantile=\"0.5\"} 0.6"));
+        assert!(text.contains(&format!("theta_surface_grpc_query_latency_seconds_count {}", LATENCY_WINDOW + 100)));
+    }
+}
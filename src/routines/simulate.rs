@@ -0,0 +1,185 @@
+use std::f64::consts::E;
+
+use chrono::{DateTime, Utc};
+
+use crate::analytics::{
+    self, BarrierType, OptionType, Payoff, SimulatedOption, SimulationSettings, SmileGraphsDataContainer, VolatilityModel,
+};
+use crate::config::Config;
+use crate::fileio::DataScope;
+use crate::helpers::F64Helpers;
+use crate::routines::RunSummary;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+use crate::{constants, fileio, helpers};
+
+const DEFAULT_PATHS: u64 = 100000;
+const DEFAULT_SEED: u64 = 1;
+
+/// The default number of time steps per year, about one per day.
+const DEFAULT_STEPS_PER_YEAR: f64 = 365.0;
+
+/// The option to simulate, set via command line flags.
+struct SimulateOptions {
+    expiry: DateTime<Utc>,
+    option_type: OptionType,
+    strike: f64,
+    payoff: Payoff,
+    volatility_model: VolatilityModel,
+    paths: u64,
+    steps: Option<u64>,
+    seed: u64,
+}
+
+impl SimulateOptions {
+    fn from_args(args: &[String]) -> Result<Self, TsError> {
+        let strike = parse_positive_f64(args, "--strike")?.ok_or(TsError::new(RuntimeError, "--strike is required"))?;
+        let expiry = helpers::get_flag_expiry(args, "--expiry")?.ok_or(TsError::new(RuntimeError, "--expiry is required"))?;
+
+        let option_type = OptionType::try_from(
+            helpers::get_flag_value(args, "--type")?.ok_or(TsError::new(RuntimeError, "--type is required"))?,
+        )?;
+
+        let payoff = match helpers::get_flag_value(args, "--payoff")?.unwrap_or("european") {
+            "european" => Payoff::European,
+            "asian" => Payoff::Asian,
+            "barrier" => Payoff::Barrier {
+                barrier: parse_positive_f64(args, "--barrier")?
+                    .ok_or(TsError::new(RuntimeError, "--barrier is required for barrier options"))?,
+                barrier_type: BarrierType::try_from(
+                    helpers::get_flag_value(args, "--barrier-type")?
+                        .ok_or(TsError::new(RuntimeError, "--barrier-type is required for barrier options"))?,
+                )?,
+            },
+            v => return Err(TsError::new(RuntimeError, format!("Invalid payoff {v}"))),
+        };
+
+        let volatility_model = match helpers::get_flag_value(args, "--model")? {
+            None => VolatilityModel::LocalVolatility,
+            Some(v) => VolatilityModel::try_from(v)?,
+        };
+
+        let paths = match helpers::get_flag_value(args, "--paths")? {
+            None => DEFAULT_PATHS,
+            Some(v) => v
+                .parse::<u64>()
+                .ok()
+                .filter(|x| *x >= 2)
+                .ok_or(TsError::new(RuntimeError, format!("--paths must be a whole number >= 2, found {v}")))?,
+        };
+
+        let steps = match helpers::get_flag_value(args, "--steps")? {
+            None => None,
+            Some(v) => Some(
+                v.parse::<u64>()
+                    .ok()
+                    .filter(|x| *x >= 1)
+                    .ok_or(TsError::new(RuntimeError, format!("--steps must be a whole number >= 1, found {v}")))?,
+            ),
+        };
+
+        let seed = match helpers::get_flag_value(args, "--seed")? {
+            None => DEFAULT_SEED,
+            Some(v) => v
+                .parse::<u64>()
+                .map_err(|e| TsError::new(RuntimeError, format!("Invalid seed {v}: {e}")).with_source(e))?,
+        };
+
+        Ok(Self {
+            expiry,
+            option_type,
+            strike,
+            payoff,
+            volatility_model,
+            paths,
+            steps,
+            seed,
+        })
+    }
+}
+
+fn parse_positive_f64(args: &[String], flag: &str) -> Result<Option<f64>, TsError> {
+    let Some(value) = helpers::get_flag_value(args, flag)? else {
+        return Ok(None);
+    };
+
+    let parsed = value
+        .parse::<f64>()
+        .map_err(|e| TsError::new(RuntimeError, format!("Invalid value {value} for {flag}: {e}")).with_source(e))?;
+    helpers::error_unless_positive_f64(parsed, flag)?;
+
+    Ok(Some(parsed))
+}
+
+pub fn simulate(args: &[String], config: &Config, run: &mut RunSummary) -> Result<(), TsError> {
+    let options = SimulateOptions::from_args(args).map_err(|e| e.context("Invalid arguments"))?;
+
+    let mut data = run.step("Loading surface data", || {
+        fileio::load_versioned_struct_from_file::<SmileGraphsDataContainer>(&fileio::find_data_file(&fileio::get_data_path(
+            DataScope::Snapshot,
+            "smile-graph-data.json",
+        )))
+    })?;
+
+    for graph in &mut data.smile_graphs {
+        graph.wing_extrapolation = config.wing_extrapolation;
+    }
+
+    let rate = constants::INTEREST_FREE_RATE;
+    let years_until_expiry = (options.expiry - helpers::get_now()).num_seconds() as f64 / 31556926.0;
+    let steps = options
+        .steps
+        .unwrap_or(((years_until_expiry * DEFAULT_STEPS_PER_YEAR).ceil() as u64).max(1));
+
+    let option = SimulatedOption {
+        strike: options.strike,
+        years_until_expiry,
+        option_type: options.option_type,
+        payoff: options.payoff,
+    };
+    let settings = SimulationSettings {
+        volatility_model: options.volatility_model,
+        paths: options.paths,
+        steps,
+        seed: options.seed,
+    };
+
+    println!("Simulating {} paths of {} steps...", settings.paths, settings.steps);
+
+    let result = run.step("Simulating option", || analytics::simulate_option_price(&data.smile_graphs, &option, &settings))?;
+
+    let point = run.step("Querying surface", || {
+        analytics::interpolate_surface(&data.smile_graphs, options.strike, years_until_expiry)
+    })?;
+    let spot_price = point.forward_price * E.powf(-(rate - config.dividend_yield) * years_until_expiry);
+    let black_scholes_price = run.step("Pricing option", || {
+        analytics::calculate_black_scholes(
+            spot_price,
+            options.strike,
+            years_until_expiry,
+            rate,
+            config.dividend_yield,
+            point.implied_volatility,
+            options.option_type,
+        )
+    })?;
+
+    println!("Strike:                         {}", options.strike);
+    println!("Expiry:                         {}", helpers::format_time(options.expiry));
+    println!("Type:                           {:?}", options.option_type);
+    println!("Payoff:                         {:?}", options.payoff);
+    println!("Volatility model:               {:?}", options.volatility_model);
+    println!("Seed:                           {}", options.seed);
+    println!("Spot price:                     {}", spot_price.round_to_decimal_places(2));
+    println!("------------------------------");
+    println!("Price:                          {}", result.price.round_to_decimal_places(4));
+    println!("Standard error:                 {}", result.standard_error.round_to_decimal_places(4));
+    println!(
+        "95% confidence interval:        {} to {}",
+        (result.price - 1.96 * result.standard_error).round_to_decimal_places(4),
+        (result.price + 1.96 * result.standard_error).round_to_decimal_places(4)
+    );
+    println!("European Black-Scholes price:   {}", black_scholes_price.round_to_decimal_places(4));
+
+    Ok(())
+}
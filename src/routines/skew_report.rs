@@ -0,0 +1,227 @@
+use std::f64::consts::E;
+
+use chrono::{DateTime, Utc};
+
+use crate::analytics::{self, SkewBeta, SmileGraphsDataContainer};
+use crate::config::Config;
+use crate::fileio::{DataScope, Table, TableColumn};
+use crate::helpers::{Clock, F64Helpers};
+use crate::routines::RunSummary;
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+use crate::{constants, fileio, helpers};
+
+const REPORT_FILE_NAME: &str = "skew-beta-report.csv";
+
+/// A historical surface, loaded so that at the money volatility can be read off it at any tenor.
+struct HistoricalSurface {
+    time: DateTime<Utc>,
+    data: SmileGraphsDataContainer,
+}
+
+pub fn skew_report(args: &[String], config: &Config, run: &mut RunSummary) -> Result<(), TsError> {
+    println!("===============================================================");
+    println!("===============================================================");
+    println!("Calculating at the money skew and skew beta for each expiry");
+    println!("===============================================================");
+    println!("===============================================================");
+
+    let history_paths = get_history_paths(args);
+
+    println!("Loading surface data...");
+    let mut data = run.step("Loading surface data", || {
+        fileio::load_versioned_struct_from_file::<SmileGraphsDataContainer>(&fileio::find_data_file(&fileio::get_data_path(
+            DataScope::Snapshot,
+            "smile-graph-data.json",
+        )))
+    })?;
+
+    for graph in &mut data.smile_graphs {
+        graph.wing_extrapolation = config.wing_extrapolation;
+    }
+
+    let mut skew_betas = run.step("Calculating skew", || {
+        data.smile_graphs
+            .iter()
+            .map(SkewBeta::calculate)
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+    println!("------------------------------");
+
+    if !history_paths.is_empty() {
+        let history = run.step("Loading historical surfaces", || load_history(&history_paths, config))?;
+        run.step("Calculating historical betas", || calibrate_historical_betas(&mut skew_betas, &history))?;
+        println!("------------------------------");
+    }
+
+    print_report(&skew_betas);
+    println!("------------------------------");
+
+    let report_path = fileio::get_data_path(DataScope::Snapshot, REPORT_FILE_NAME);
+    println!("Saving {report_path}...");
+    run.step("Saving report", || save_report(&skew_betas, &report_path))?;
+    println!("===============================================================");
+
+    Ok(())
+}
+
+/// Every argument after the command is the path of a historical surface, other than flags and their values.
+fn get_history_paths(args: &[String]) -> Vec<String> {
+    let mut paths = Vec::new();
+    let mut remaining_args = args.iter().skip_while(|a| *a != "skew-report").skip(1);
+
+    while let Some(arg) = remaining_args.next() {
+        match arg.as_str() {
+            "--config" | "--data-dir" | "--snapshot-date" => {
+                remaining_args.next();
+            }
+            flag if flag.starts_with("--") => {}
+            path => paths.push(path.to_string()),
+        }
+    }
+
+    paths
+}
+
+/// Load the historical surfaces, ordered by the time they were built. Only surfaces saved with a surface score can be
+/// used, since the score records when the surface was built.
+fn load_history(paths: &[String], config: &Config) -> Result<Vec<HistoricalSurface>, TsError> {
+    let mut history = Vec::new();
+
+    for path in paths {
+        println!("Reading {path}...");
+
+        let mut data = fileio::load_versioned_struct_from_file::<SmileGraphsDataContainer>(path)?;
+        let time = data
+            .surface_score
+            .as_ref()
+            .map(|x| x.calculated_at)
+            .ok_or(TsError::new(RuntimeError, format!("{path} has no surface score, so its build time is unknown")))?;
+
+        // Times until expiry are measured from when the surface was built.
+        for graph in &mut data.smile_graphs {
+            graph.wing_extrapolation = config.wing_extrapolation;
+            graph.set_clock(Clock::Fixed(time));
+        }
+
+        history.push(HistoricalSurface { time, data });
+    }
+
+    history.sort_by_key(|x| x.time);
+
+    Ok(history)
+}
+
+/// For each expiry's tenor, read the at the money volatility at that same tenor off each historical surface and regress
+/// its changes against the changes in spot.
+fn calibrate_historical_betas(skew_betas: &mut [SkewBeta], history: &[HistoricalSurface]) -> Result<(), TsError> {
+    println!("Calibrating historical betas from {} surfaces...", history.len());
+
+    let mut observations: Vec<Vec<(f64, f64)>> = vec![Vec::new(); skew_betas.len()];
+
+    for surface in history {
+        for (observations, observation) in observations
+            .iter_mut()
+            .zip(read_atm_observations(surface, skew_betas)?)
+        {
+            observations.push(observation);
+        }
+    }
+
+    for (skew_beta, observations) in skew_betas.iter_mut().zip(observations) {
+        skew_beta.historical_beta = analytics::calculate_historical_beta(&observations)
+            .inspect_err(|e| println!("No historical beta for {}: {}", helpers::format_time(skew_beta.expiry), e.reason))
+            .ok();
+    }
+
+    Ok(())
+}
+
+/// Read the spot price and the at the money volatility at each expiry's tenor off a historical surface.
+fn read_atm_observations(surface: &HistoricalSurface, skew_betas: &[SkewBeta]) -> Result<Vec<(f64, f64)>, TsError> {
+    let spot_price = surface
+        .data
+        .smile_graphs
+        .first()
+        .and_then(|x| x.options.first())
+        .ok_or(TsError::new(RuntimeError, "Historical surface has no options"))?
+        .spot_price;
+
+    skew_betas
+        .iter()
+        .map(|skew_beta| {
+            let years_until_expiry = skew_beta.years_until_expiry;
+            let forward_price = spot_price * E.powf(constants::INTEREST_FREE_RATE * years_until_expiry);
+            let point = analytics::interpolate_surface(&surface.data.smile_graphs, forward_price, years_until_expiry)?;
+
+            Ok((spot_price, point.implied_volatility))
+        })
+        .collect()
+}
+
+fn print_report(skew_betas: &[SkewBeta]) {
+    println!("Expiry                     | Days   | ATM vol | ATM skew | Hist. beta | Stickiness");
+
+    for skew_beta in skew_betas {
+        let format_optional = |x: Option<f64>| {
+            x.map(|x| x.round_to_decimal_places(4).to_string())
+                .unwrap_or("-".into())
+        };
+
+        println!(
+            "{:<26} | {:<6} | {:<7} | {:<8} | {:<10} | {}",
+            helpers::format_time(skew_beta.expiry),
+            (skew_beta.years_until_expiry * 365.0).round_to_decimal_places(1),
+            skew_beta.atm_implied_volatility.round_to_decimal_places(4),
+            skew_beta.atm_skew.round_to_decimal_places(4),
+            format_optional(skew_beta.historical_beta),
+            format_optional(skew_beta.get_skew_stickiness_ratio()),
+        );
+    }
+}
+
+/// Save the report as CSV. Missing historical betas are saved as NaN.
+fn save_report(skew_betas: &[SkewBeta], path: &str) -> Result<(), TsError> {
+    let mut table = Table::default();
+
+    table.add_column(
+        "expiry",
+        TableColumn::Timestamp(
+            skew_betas
+                .iter()
+                .map(|x| x.expiry.timestamp_millis())
+                .collect(),
+        ),
+    )?;
+    table.add_column("years_until_expiry", TableColumn::Float(skew_betas.iter().map(|x| x.years_until_expiry).collect()))?;
+    table.add_column(
+        "atm_implied_volatility",
+        TableColumn::Float(
+            skew_betas
+                .iter()
+                .map(|x| x.atm_implied_volatility)
+                .collect(),
+        ),
+    )?;
+    table.add_column("atm_skew", TableColumn::Float(skew_betas.iter().map(|x| x.atm_skew).collect()))?;
+    table.add_column(
+        "historical_beta",
+        TableColumn::Float(
+            skew_betas
+                .iter()
+                .map(|x| x.historical_beta.unwrap_or(f64::NAN))
+                .collect(),
+        ),
+    )?;
+    table.add_column(
+        "skew_stickiness_ratio",
+        TableColumn::Float(
+            skew_betas
+                .iter()
+                .map(|x| x.get_skew_stickiness_ratio().unwrap_or(f64::NAN))
+                .collect(),
+        ),
+    )?;
+
+    fileio::save_table_to_csv(&table, path)
+}
@@ -0,0 +1,121 @@
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
+
+use crate::analytics::{SmileGraphsDataContainer, SurfaceMetrics};
+use crate::fileio::{ExpiryMetricsRecord, QuoteRecord, SurfaceStore, SviFitRecord};
+use crate::integrations::{DeribitDataContainer, DeribitOptionInstrument};
+use crate::types::TsError;
+use crate::types::TsErrorType::{RuntimeError, UnusableAPIData};
+
+/// Save the quotes in a snapshot to the database at the given path, keyed by the snapshot's time. Returns the number of
+/// quotes saved.
+pub fn store_snapshot(path: &str, data: &DeribitDataContainer) -> Result<usize, TsError> {
+    let snapshot_time = data
+        .snapshot_time
+        .ok_or(TsError::new(RuntimeError, "The snapshot has no ticker data, so has no time to save it under"))?;
+    let quotes = data
+        .options
+        .iter()
+        .filter(|x| x.ticker_data.is_some())
+        .map(to_quote_record)
+        .collect::<Result<Vec<QuoteRecord>, TsError>>()?;
+
+    SurfaceStore::open(path)?.save_quotes(snapshot_time, &quotes)?;
+
+    Ok(quotes.len())
+}
+
+/// Save the SVI parameters of each fitted smile in a surface to the database at the given path, keyed by the time the
+/// surface was evaluated at (the snapshot's time, unless it was built with --as-of). Returns the number of smiles saved.
+pub fn store_surface(path: &str, data: &SmileGraphsDataContainer) -> Result<usize, TsError> {
+    let fits = data
+        .smile_graphs
+        .iter()
+        .map(|graph| {
+            Ok(SviFitRecord {
+                expiry: graph.get_expiration()?,
+                a: graph.svi_curve_parameters.get_a(),
+                b: graph.svi_curve_parameters.get_b(),
+                p: graph.svi_curve_parameters.get_p(),
+                m: graph.svi_curve_parameters.get_m(),
+                o: graph.svi_curve_parameters.get_o(),
+                forward_price: graph.get_underlying_forward_price()?,
+                option_count: graph.options.len(),
+            })
+        })
+        .collect::<Result<Vec<SviFitRecord>, TsError>>()?;
+
+    SurfaceStore::open(path)?.save_svi_fits(get_surface_snapshot_time(data)?, &fits)?;
+
+    Ok(fits.len())
+}
+
+/// Save the metrics calculated from a surface to the database at the given path, keyed like store_surface(). Returns the
+/// number of expiries saved.
+pub fn store_metrics(path: &str, data: &SmileGraphsDataContainer, metrics: &SurfaceMetrics) -> Result<usize, TsError> {
+    let records = metrics
+        .expiries
+        .iter()
+        .map(|x| ExpiryMetricsRecord {
+            expiry: x.expiry,
+            atm_implied_volatility: x.atm_implied_volatility,
+            risk_reversal_25_delta: x.risk_reversal_25_delta,
+            butterfly_25_delta: x.butterfly_25_delta,
+            atm_skew: x.atm_skew,
+            atm_forward_volatility: x.atm_forward_volatility,
+        })
+        .collect::<Vec<ExpiryMetricsRecord>>();
+
+    SurfaceStore::open(path)?.save_expiry_metrics(get_surface_snapshot_time(data)?, &records)?;
+
+    Ok(records.len())
+}
+
+fn get_surface_snapshot_time(data: &SmileGraphsDataContainer) -> Result<DateTime<Utc>, TsError> {
+    data.build_info
+        .as_ref()
+        .map(|x| x.evaluation_time)
+        .ok_or(TsError::new(
+            RuntimeError,
+            "The surface was saved by an older version without build info, so has no time to save it under",
+        ))
+}
+
+fn to_quote_record(option: &DeribitOptionInstrument) -> Result<QuoteRecord, TsError> {
+    let ticker_data = option
+        .ticker_data
+        .as_ref()
+        .ok_or(TsError::new(UnusableAPIData, "Failed getting ticker data"))?;
+    let to_f64 = |x: Decimal, name: &str| {
+        x.to_f64()
+            .ok_or(TsError::new(UnusableAPIData, format!("Failed converting {name} to f64")))
+    };
+    // A price of zero means there is no bid or ask.
+    let to_price = |x: Decimal, name: &str| Ok(Some(to_f64(x, name)?).filter(|x| *x > 0.0));
+
+    Ok(QuoteRecord {
+        instrument_name: option.instrument_name.to_string(),
+        expiry: DateTime::from_timestamp_millis(option.expiration_timestamp as i64)
+            .ok_or(TsError::new(UnusableAPIData, "Failed creating timestamp from expiration timestamp"))?,
+        strike: to_f64(option.strike, "strike price")?,
+        option_type: option.option_type.to_string(),
+        quote_currency: option.quote_currency.to_string(),
+        bid_price: to_price(ticker_data.best_bid_price, "best bid price")?,
+        ask_price: to_price(ticker_data.best_ask_price, "best ask price")?,
+        mark_price: to_price(ticker_data.mark_price, "mark price")?,
+        // Deribit quotes implied volatilities in percent.
+        mark_implied_volatility: ticker_data
+            .mark_iv
+            .map(|x| to_f64(x, "mark implied volatility"))
+            .transpose()?
+            .map(|x| x / 100.0),
+        index_price: to_f64(ticker_data.index_price, "index price")?,
+        underlying_price: ticker_data
+            .underlying_price
+            .map(|x| to_f64(x, "underlying price"))
+            .transpose()?,
+        quoted_at: DateTime::from_timestamp_millis(ticker_data.timestamp as i64)
+            .ok_or(TsError::new(UnusableAPIData, "Failed creating timestamp from ticker timestamp"))?,
+    })
+}
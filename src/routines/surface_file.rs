@@ -0,0 +1,64 @@
+use crate::analytics::{SmileGraphsDataContainer, SurfaceGrid, WingExtrapolation};
+use crate::config::Config;
+use crate::fileio::{self, DataScope};
+use crate::types::TsError;
+
+/// Save the surface data to the path, along with its grid (see analytics::SurfaceGrid) alongside it. Returns the path
+/// the surface data was saved to.
+pub fn save_surface(data: &SmileGraphsDataContainer, path: &str, compress: bool) -> Result<String, TsError> {
+    let grid_path = get_grid_path(path);
+
+    // The grid of the previous surface is deleted first, so that if saving either file fails, it can't be read in place
+    // of the new surface.
+    fileio::delete_data_file(&grid_path)?;
+
+    let saved_path = fileio::save_data_file(data, path, compress)?;
+    let grid = SurfaceGrid::new(&data.smile_graphs, data.build_info.clone())?;
+
+    fileio::save_data_file(&grid, &grid_path, compress)?;
+
+    Ok(saved_path)
+}
+
+/// Load the snapshot's surface grid for reading the surface. If the surface was built by a version that didn't save one,
+/// it's precomputed from the full surface data instead, and if it was extrapolated differently to the config's wing
+/// extrapolation, it's precomputed again from its smiles.
+pub fn load_surface_grid(config: &Config) -> Result<SurfaceGrid, TsError> {
+    load_surface_grid_at(&fileio::get_data_path(DataScope::Snapshot, "smile-graph-data.json"), config.wing_extrapolation)
+}
+
+/// Like load_surface_grid(), but for the surface data saved at the given path, e.g. another snapshot's.
+pub fn load_surface_grid_at(path: &str, wing_extrapolation: WingExtrapolation) -> Result<SurfaceGrid, TsError> {
+    let grid_path = find_surface_grid_file(path);
+
+    let mut grid = match fileio::file_exists(&grid_path) {
+        true => fileio::load_versioned_struct_from_file::<SurfaceGrid>(&grid_path)?,
+        false => {
+            let data = fileio::load_versioned_struct_from_file::<SmileGraphsDataContainer>(&fileio::find_data_file(path))?;
+
+            SurfaceGrid::new(&data.smile_graphs, data.build_info)?
+        }
+    };
+
+    if grid.wing_extrapolation != wing_extrapolation || grid.log_moneyness.is_empty() {
+        return grid.with_wing_extrapolation(wing_extrapolation);
+    }
+
+    // Wing extrapolation isn't saved with the smiles, so it's set again for reading beyond the grid's columns.
+    for row in &mut grid.rows {
+        row.smile_graph.wing_extrapolation = grid.wing_extrapolation;
+    }
+
+    Ok(grid)
+}
+
+/// The path of the grid saved alongside the surface data at the given path, whether or not it was saved compressed. It
+/// may not exist.
+pub fn find_surface_grid_file(path: &str) -> String {
+    fileio::find_data_file(&get_grid_path(path))
+}
+
+/// The path of the grid saved alongside the surface data at the given path.
+fn get_grid_path(path: &str) -> String {
+    path.replace("smile-graph-data", "surface-grid")
+}
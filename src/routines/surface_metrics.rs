@@ -0,0 +1,77 @@
+use crate::analytics::{SmileGraphsDataContainer, SurfaceMetrics, VolEvent};
+use crate::config::Config;
+use crate::fileio::DataScope;
+use crate::helpers::F64Helpers;
+use crate::routines::RunSummary;
+use crate::types::TsError;
+use crate::{fileio, helpers};
+
+const METRICS_FILE_NAME: &str = "surface-metrics.json";
+
+pub fn surface_metrics(args: &[String], config: &Config, run: &mut RunSummary) -> Result<(), TsError> {
+    println!("===============================================================");
+    println!("===============================================================");
+    println!("Calculating summary metrics for each expiry");
+    println!("===============================================================");
+    println!("===============================================================");
+
+    println!("Loading surface data...");
+    let mut data = run.step("Loading surface data", || {
+        fileio::load_versioned_struct_from_file::<SmileGraphsDataContainer>(&fileio::find_data_file(&fileio::get_data_path(
+            DataScope::Snapshot,
+            "smile-graph-data.json",
+        )))
+    })?;
+
+    for graph in &mut data.smile_graphs {
+        graph.wing_extrapolation = config.wing_extrapolation;
+    }
+
+    let events = run.step("Loading events", || VolEvent::load_from_args(args))?;
+
+    let metrics = run.step("Calculating metrics", || SurfaceMetrics::calculate(&data.smile_graphs, &events))?;
+    println!("------------------------------");
+
+    print_metrics(&metrics);
+    println!("------------------------------");
+
+    let metrics_path = fileio::get_data_path(DataScope::Snapshot, METRICS_FILE_NAME);
+    println!("Saving {metrics_path}...");
+    run.step("Saving metrics", || fileio::save_struct_to_file(&metrics, &metrics_path))?;
+
+    #[cfg(feature = "storage-sqlite")]
+    if let Some(db_path) = helpers::get_flag_value(args, "--db").map_err(|e| e.context("Invalid arguments"))? {
+        println!("Saving metrics to {db_path}...");
+        let count =
+            run.step("Saving metrics to database", || crate::routines::storage::store_metrics(db_path, &data, &metrics))?;
+        println!("Saved metrics for {count} expiries");
+    }
+    println!("===============================================================");
+
+    Ok(())
+}
+
+fn print_metrics(metrics: &SurfaceMetrics) {
+    println!("Expiry                     | Days   | ATM vol | 25d RR  | 25d BF  | ATM skew | Fwd vol | Last event");
+
+    for expiry in &metrics.expiries {
+        println!(
+            "{:<26} | {:<6} | {:<7} | {:<7} | {:<7} | {:<8} | {:<7} | {}",
+            helpers::format_time(expiry.expiry),
+            (expiry.years_until_expiry * 365.0).round_to_decimal_places(1),
+            expiry.atm_implied_volatility.round_to_decimal_places(4),
+            expiry.risk_reversal_25_delta.round_to_decimal_places(4),
+            expiry.butterfly_25_delta.round_to_decimal_places(4),
+            expiry.atm_skew.round_to_decimal_places(4),
+            expiry
+                .atm_forward_volatility
+                .map(|x| x.round_to_decimal_places(4).to_string())
+                .unwrap_or("-".into()),
+            expiry
+                .nearest_event
+                .as_ref()
+                .map(|x| format!("{} ({} days before)", x.label, x.days_before_expiry.round_to_decimal_places(1)))
+                .unwrap_or("-".into()),
+        );
+    }
+}
@@ -1,7 +1,12 @@
-#[derive(Clone, PartialEq, Debug)]
+use std::{error::Error, fmt, sync::Arc};
+
+/// An error from anywhere in the program. The reason is complete on its own, including the reasons of any errors that
+/// caused it, so it can be printed as it is. The error that caused it, if any, is kept as its source.
+#[derive(Clone, Debug)]
 pub struct TsError {
     pub reason: String,
     pub error_type: TsErrorType,
+    source: Option<Arc<dyn Error + Send + Sync>>,
 }
 
 impl TsError {
@@ -9,8 +14,44 @@ impl TsError {
         Self {
             reason: reason.into(),
             error_type,
+            source: None,
         }
     }
+
+    /// Keep the error that caused this one, such as an I/O or deserialisation error, as its source.
+    pub fn with_source(mut self, source: impl Error + Send + Sync + 'static) -> Self {
+        self.source = Some(Arc::new(source));
+        self
+    }
+
+    /// Say what was being done when this error happened. The result has the same type, a reason of
+    /// "{context}: {reason}", and this error as its source.
+    pub fn context(self, context: impl fmt::Display) -> Self {
+        Self {
+            reason: format!("{context}: {}", self.reason),
+            error_type: self.error_type.clone(),
+            source: Some(Arc::new(self)),
+        }
+    }
+}
+
+/// Errors are equal if they have the same type and reason, whatever their sources.
+impl PartialEq for TsError {
+    fn eq(&self, other: &Self) -> bool {
+        self.error_type == other.error_type && self.reason == other.reason
+    }
+}
+
+impl fmt::Display for TsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl Error for TsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_deref().map(|x| x as &(dyn Error + 'static))
+    }
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -21,4 +62,30 @@ pub enum TsErrorType {
     RuntimeError,
     /// The maths were unsolvable.
     UnsolvableError,
+    /// An API returned an error. The code is the error code given by the API.
+    ApiError { code: i64 },
+}
+
+impl TsErrorType {
+    /// The code the program exits with when a command fails with this type of error, so that scripts can tell the types
+    /// apart. 101 is used for panics.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            TsErrorType::RuntimeError => 1,
+            TsErrorType::UnusableAPIData => 2,
+            TsErrorType::UnsolvableError => 3,
+            TsErrorType::ApiError { .. } => 4,
+        }
+    }
+}
+
+impl fmt::Display for TsErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TsErrorType::UnusableAPIData => write!(f, "unusable API data"),
+            TsErrorType::RuntimeError => write!(f, "runtime error"),
+            TsErrorType::UnsolvableError => write!(f, "unsolvable"),
+            TsErrorType::ApiError { code } => write!(f, "API error {code}"),
+        }
+    }
 }
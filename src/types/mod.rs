@@ -1,4 +1,9 @@
 mod errors;
+mod schema;
 
 pub use errors::TsError;
 pub use errors::TsErrorType;
+pub use schema::SchemaVersionHeader;
+pub use schema::SchemaVersioned;
+pub use schema::load_versioned_struct_from_str;
+pub use schema::upgrade_versioned_json;
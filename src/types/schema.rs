@@ -0,0 +1,79 @@
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::types::TsError;
+use crate::types::TsErrorType::RuntimeError;
+
+/// A struct saved to file whose structure is versioned, so that files saved by older versions of the program can be
+/// upgraded when they are loaded, rather than failing to load or loading wrongly. The version is saved in a top level
+/// schema_version field.
+pub trait SchemaVersioned: DeserializeOwned {
+    /// The schema version saved by this version of the program. Increase it whenever a change to the struct would stop
+    /// older files from loading (or change what they mean), and add a step to migrate() that upgrades them.
+    const SCHEMA_VERSION: u32;
+
+    /// Upgrade the JSON of a file saved with the given schema version to the next version. Files saved before schema
+    /// versions were added have no schema_version, and are version 0.
+    fn migrate(data: &mut Value, from_version: u32) -> Result<(), TsError>;
+}
+
+/// Just the schema version of a versioned file. Every other field is skipped over without being kept, so the version can
+/// be found without loading the whole file.
+#[derive(serde::Deserialize)]
+pub struct SchemaVersionHeader {
+    schema_version: Option<Value>,
+}
+
+impl SchemaVersionHeader {
+    /// The version of the data, which is named in errors, checking that this version of the program can load it.
+    pub fn get_version<T: SchemaVersioned>(&self, name: &str) -> Result<u32, TsError> {
+        let version = match &self.schema_version {
+            None => 0,
+            Some(value) => value
+                .as_u64()
+                .and_then(|x| u32::try_from(x).ok())
+                .ok_or(TsError::new(RuntimeError, format!("{name} has an invalid schema_version {value}")))?,
+        };
+
+        if version > T::SCHEMA_VERSION {
+            return Err(TsError::new(
+                RuntimeError,
+                format!(
+                    "{name} was saved by a newer version of this program (schema version {version}, but only up to {} can \
+                     be loaded), please update",
+                    T::SCHEMA_VERSION
+                ),
+            ));
+        }
+
+        Ok(version)
+    }
+}
+
+/// Load the JSON of a versioned struct, e.g. a file's contents, upgrading it if it was saved with an older schema version
+/// (see SchemaVersioned). The name is used in errors.
+pub fn load_versioned_struct_from_str<T: SchemaVersioned>(json: &str, name: &str) -> Result<T, TsError> {
+    let data = serde_json::from_str::<Value>(json)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed deserialising object: {}", e)).with_source(e))?;
+    let header = serde_json::from_value::<SchemaVersionHeader>(data.clone())
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed deserialising object: {}", e)).with_source(e))?;
+
+    upgrade_versioned_json(data, header.get_version::<T>(name)?, name)
+}
+
+/// Upgrade the JSON of a versioned struct saved with the given schema version to the current one, and load it.
+pub fn upgrade_versioned_json<T: SchemaVersioned>(mut data: Value, mut version: u32, name: &str) -> Result<T, TsError> {
+    while version < T::SCHEMA_VERSION {
+        T::migrate(&mut data, version).map_err(|e| {
+            TsError::new(RuntimeError, format!("Failed upgrading {name} from schema version {version}: {}", e.reason))
+        })?;
+        version += 1;
+
+        data.as_object_mut()
+            .ok_or(TsError::new(RuntimeError, format!("{name} doesn't contain a JSON object")))?
+            .insert("schema_version".to_string(), version.into());
+    }
+
+    serde_json::from_value::<T>(data)
+        .map_err(|e| TsError::new(RuntimeError, format!("Failed deserialising object: {}", e)).with_source(e))
+}
@@ -0,0 +1,225 @@
+//! JavaScript bindings to the analytics, so that a web page can draw a saved surface without a server. Build them with
+//! `cargo rustc --release --lib --crate-type cdylib --target wasm32-unknown-unknown --no-default-features --features wasm`
+//! and then run wasm-bindgen on the module (see the readme). Times are passed to and from JavaScript as
+//! milliseconds since the Unix epoch, the same as Date.getTime().
+
+use chrono::{DateTime, Utc};
+use wasm_bindgen::prelude::*;
+
+use crate::analytics::{self, OptionType, SVICurveParameters, SmileGraph, SmileGraphsDataContainer};
+use crate::types::{TsError, load_versioned_struct_from_str};
+
+/// The total implied variance of an SVI curve at the given log moneyness.
+#[wasm_bindgen(js_name = sviVariance)]
+pub fn svi_variance(a: f64, b: f64, rho: f64, m: f64, sigma: f64, log_moneyness: f64) -> Result<f64, JsError> {
+    let params = SVICurveParameters::new_from_values(a, b, rho, m, sigma).map_err(to_js_error)?;
+
+    analytics::svi_variance(&params, log_moneyness).map_err(to_js_error)
+}
+
+/// The Black-Scholes price of a European option. The option type is "call" or "put", and the rates are continuously
+/// compounded and annualised, as in calculate_black_scholes().
+#[wasm_bindgen(js_name = blackScholesPrice)]
+pub fn black_scholes_price(
+    spot_price: f64,
+    strike: f64,
+    years_until_expiry: f64,
+    risk_free_interest_rate: f64,
+    dividend_yield: f64,
+    volatility: f64,
+    option_type: &str,
+) -> Result<f64, JsError> {
+    analytics::calculate_black_scholes(
+        spot_price,
+        strike,
+        years_until_expiry,
+        risk_free_interest_rate,
+        dividend_yield,
+        volatility,
+        OptionType::try_from(option_type).map_err(to_js_error)?,
+    )
+    .map_err(to_js_error)
+}
+
+/// A surface loaded from a saved smile-graph-data.json. Smiles are referred to by their index, in order of expiry.
+#[wasm_bindgen]
+pub struct Surface {
+    smile_graphs: Vec<SmileGraph>,
+}
+
+#[wasm_bindgen]
+impl Surface {
+    /// Load the surface from the contents of smile-graph-data.json. Times until expiry are measured from the given time if
+    /// there is one, and otherwise from the time the surface was built, which is only saved by newer versions.
+    #[wasm_bindgen(js_name = fromJson)]
+    pub fn from_json(json: &str, evaluation_time: Option<f64>) -> Result<Surface, JsError> {
+        let data = load_versioned_struct_from_str::<SmileGraphsDataContainer>(json, "The surface data")
+            .map_err(|e| JsError::new(&format!("Failed reading surface data: {}", e.reason)))?;
+
+        let evaluation_time = match evaluation_time {
+            Some(v) => Some(
+                DateTime::<Utc>::from_timestamp_millis(v as i64)
+                    .ok_or_else(|| JsError::new(&format!("Invalid evaluation time {v}")))?,
+            ),
+            None => None,
+        };
+        let smile_graphs = data
+            .into_smile_graphs_as_of(evaluation_time)
+            .map_err(to_js_error)?;
+
+        Ok(Surface { smile_graphs })
+    }
+
+    #[wasm_bindgen(js_name = smileCount)]
+    pub fn smile_count(&self) -> usize {
+        self.smile_graphs.len()
+    }
+
+    /// The expiry of the smile.
+    pub fn expiry(&self, smile: usize) -> Result<f64, JsError> {
+        Ok(self
+            .get_smile(smile)?
+            .get_expiration()
+            .map_err(to_js_error)?
+            .timestamp_millis() as f64)
+    }
+
+    #[wasm_bindgen(js_name = yearsUntilExpiry)]
+    pub fn years_until_expiry(&self, smile: usize) -> Result<f64, JsError> {
+        self.get_smile(smile)?
+            .get_years_until_expiry()
+            .map_err(to_js_error)
+    }
+
+    #[wasm_bindgen(js_name = forwardPrice)]
+    pub fn forward_price(&self, smile: usize) -> Result<f64, JsError> {
+        self.get_smile(smile)?
+            .get_underlying_forward_price()
+            .map_err(to_js_error)
+    }
+
+    /// The lowest and highest strikes the smile was fit to, which is the range its curve is most trustworthy over.
+    #[wasm_bindgen(js_name = observedStrikeRange)]
+    pub fn observed_strike_range(&self, smile: usize) -> Result<Vec<f64>, JsError> {
+        let graph = self.get_smile(smile)?;
+
+        Ok(vec![graph.lowest_observed_strike, graph.highest_observed_strike])
+    }
+
+    /// The strikes of the options the smile was fit to, in the same order as observedImpliedVolatilities().
+    #[wasm_bindgen(js_name = observedStrikes)]
+    pub fn observed_strikes(&self, smile: usize) -> Result<Vec<f64>, JsError> {
+        Ok(self
+            .get_smile(smile)?
+            .options
+            .iter()
+            .map(|x| x.strike)
+            .collect())
+    }
+
+    /// The implied volatilities of the options the smile was fit to.
+    #[wasm_bindgen(js_name = observedImpliedVolatilities)]
+    pub fn observed_implied_volatilities(&self, smile: usize) -> Result<Vec<f64>, JsError> {
+        self.get_smile(smile)?
+            .options
+            .iter()
+            .map(|x| x.get_implied_volatility())
+            .collect::<Result<Vec<f64>, TsError>>()
+            .map_err(to_js_error)
+    }
+
+    /// The implied volatility of the smile's fitted curve at the strike.
+    #[wasm_bindgen(js_name = smileImpliedVolatility)]
+    pub fn smile_implied_volatility(&self, smile: usize, strike: f64) -> Result<f64, JsError> {
+        self.get_smile(smile)?
+            .get_implied_volatility_at_strike(strike)
+            .map_err(to_js_error)
+    }
+
+    /// The implied volatility anywhere on the surface, interpolated between the smiles as interpolate_surface() does.
+    #[wasm_bindgen(js_name = impliedVolatility)]
+    pub fn implied_volatility(&self, strike: f64, years_until_expiry: f64) -> Result<f64, JsError> {
+        Ok(analytics::interpolate_surface(&self.smile_graphs, strike, years_until_expiry)
+            .map_err(to_js_error)?
+            .implied_volatility)
+    }
+}
+
+impl Surface {
+    fn get_smile(&self, smile: usize) -> Result<&SmileGraph, JsError> {
+        self.smile_graphs
+            .get(smile)
+            .ok_or_else(|| JsError::new(&format!("There is no smile {smile}, the surface has {}", self.smile_graphs.len())))
+    }
+}
+
+fn to_js_error(e: TsError) -> JsError {
+    JsError::new(&e.reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analytics::{
+        OptionInstrument, OptionType, SVICurveParameters, SmileGraph, SmileGraphsDataContainer, calculate_black_scholes,
+    };
+    use crate::helpers::Clock;
+    use crate::types::{SchemaVersioned, load_versioned_struct_from_str};
+
+    use super::Surface;
+
+    /// A surface saved before schema versions and build info were, with one smile.
+    fn get_old_surface_json() -> String {
+        let clock = Clock::Fixed(chrono::DateTime::from_timestamp_secs(1_767_225_600).unwrap());
+        let mut graph = SmileGraph::new();
+
+        let expiry_seconds = 1_769_904_000;
+        let years_until_expiry = (expiry_seconds - 1_767_225_600) as f64 / 31556926.0;
+
+        for strike in [80.0, 90.0, 100.0, 110.0, 120.0] {
+            let price = calculate_black_scholes(
+                100.0,
+                strike,
+                years_until_expiry,
+                crate::constants::INTEREST_FREE_RATE,
+                0.0,
+                0.5,
+                OptionType::Call,
+            )
+            .unwrap();
+            let option =
+                OptionInstrument::new(price, expiry_seconds, strike, strike.to_string().into(), OptionType::Call, 100.0, clock);
+            graph.try_insert_option(option).unwrap();
+        }
+        graph.svi_curve_parameters = SVICurveParameters::new_from_values(0.03, 0.08, -0.4, 0.05, 0.15).unwrap();
+
+        let data = SmileGraphsDataContainer {
+            schema_version: SmileGraphsDataContainer::SCHEMA_VERSION,
+            smile_graphs: vec![graph],
+            surface_score: None,
+            synthetic_smiles: Vec::new(),
+            build_info: None,
+        };
+        let mut json = serde_json::to_value(data).unwrap();
+        json.as_object_mut().unwrap().remove("schema_version");
+
+        json.to_string()
+    }
+
+    #[test]
+    fn test_surface_from_json() {
+        let json = get_old_surface_json();
+
+        // Surfaces from before schema versions are upgraded, as they are when loaded from file.
+        let surface = Surface::from_json(&json, Some(1_767_225_600_000.0)).unwrap_or_else(|_| panic!("Should load"));
+        assert_eq!(surface.smile_count(), 1);
+        assert_eq!(surface.smile_graphs[0].options.len(), 5);
+
+        // Surfaces from a newer version can't be loaded, rather than being loaded wrongly.
+        let mut newer = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+        newer["schema_version"] = (SmileGraphsDataContainer::SCHEMA_VERSION + 1).into();
+        let error = load_versioned_struct_from_str::<SmileGraphsDataContainer>(&newer.to_string(), "The surface data")
+            .err()
+            .expect("Should fail");
+        assert!(error.reason.contains("newer version"));
+    }
+}